@@ -4,6 +4,8 @@ use std::{
     process::{Command, exit},
 };
 
+/// The architectures the kernel builds for, the AArch64 and RISC-V ports in `kernel/src/arch`
+/// are not far enough along to be offered here yet
 enum Arch {
     X86_64,
 }
@@ -14,6 +16,18 @@ impl Arch {
             Arch::X86_64 => "x86_64",
         }
     }
+
+    /// The target the kernel is built for
+    fn rust_target(&self) -> String {
+        self.as_str().to_string() + "-unknown-none"
+    }
+
+    /// The names of the UEFI executables limine boots from on this architecture
+    fn efi_executables(&self) -> &'static [&'static str] {
+        match self {
+            Arch::X86_64 => &["BOOTX64.EFI", "BOOTIA32.EFI"],
+        }
+    }
 }
 
 fn exec<C>(command: C)
@@ -63,6 +77,9 @@ pub fn main() {
     let mut only_build = false;
     let mut iso = true;
     let mut bios = true;
+    let mut kasan = false;
+    let mut cet = false;
+    let mut kcfi = false;
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
@@ -90,6 +107,11 @@ pub fn main() {
                         arch = match arg.as_str() {
                             "x86_64" => Arch::X86_64,
 
+                            "aarch64" | "riscv64" => {
+                                eprintln!("the kernel does not build for {arg} yet");
+                                exit(1);
+                            }
+
                             _ => {
                                 eprintln!("unknown architecture: {arg}");
                                 exit(1);
@@ -107,6 +129,9 @@ pub fn main() {
                     "hdd" => iso = false,
                     "bios" => bios = true,
                     "uefi" => bios = false,
+                    "kasan" => kasan = true,
+                    "cet" => cet = true,
+                    "kcfi" => kcfi = true,
 
                     _ => {
                         eprintln!("unknown key: {key}");
@@ -145,7 +170,7 @@ pub fn main() {
         ));
     }
 
-    let rust_target = arch.as_str().to_string() + "-unknown-none";
+    let rust_target = arch.rust_target();
 
     let rust_profile_subdir = match rust_profile.as_str() {
         "dev" => "debug",
@@ -159,9 +184,42 @@ pub fn main() {
 
     let image_path = "fajr-".to_string() + arch.as_str() + if iso { ".iso" } else { ".hdd" };
 
+    let mut rust_flags = "-C relocation-model=pie -C force-frame-pointers=yes".to_string();
+    let mut rust_features = String::new();
+
+    if kasan {
+        // Checks are outlined so only the heap needs shadow memory, and the stack and globals are
+        // left unpoisoned for the same reason
+        rust_flags += " -Z sanitizer=kernel-address";
+        rust_flags += " -C llvm-args=-asan-instrumentation-with-call-threshold=0";
+        rust_flags += " -C llvm-args=-asan-stack=0";
+        rust_flags += " -C llvm-args=-asan-globals=0";
+
+        rust_features += " --features kasan";
+    }
+
+    if cet {
+        // Every function that can be branched to indirectly starts with an endbr64, so the kernel
+        // can turn on indirect branch tracking
+        rust_flags += " -Z cf-protection=full";
+
+        rust_features += " --features cet";
+    }
+
+    if kcfi {
+        // Integers are hashed by their size, as the types of the same function differ between
+        // crates otherwise
+        rust_flags += " -Z sanitizer=kcfi";
+        rust_flags += " -Z sanitizer-cfi-normalize-integers";
+
+        rust_features += " --features kcfi";
+    }
+
     exece(
-        format!("cargo build -p fajr_kernel --target {rust_target} --profile {rust_profile}"),
-        [("RUSTFLAGS", "-C relocation-model=static")].into_iter(),
+        format!(
+            "cargo build -p fajr_kernel --target {rust_target} --profile {rust_profile}{rust_features}"
+        ),
+        [("RUSTFLAGS", rust_flags)].into_iter(),
     );
 
     fs::copy(
@@ -197,8 +255,13 @@ pub fn main() {
             "iso_root/boot/limine/limine-uefi-cd.bin",
         )
         .unwrap();
-        fs::copy("limine/BOOTX64.EFI", "iso_root/EFI/BOOT/BOOTX64.EFI").unwrap();
-        fs::copy("limine/BOOTIA32.EFI", "iso_root/EFI/BOOT/BOOTIA32.EFI").unwrap();
+        for executable in arch.efi_executables() {
+            fs::copy(
+                format!("limine/{executable}"),
+                format!("iso_root/EFI/BOOT/{executable}"),
+            )
+            .unwrap();
+        }
 
         exec(format!(
             "xorriso -as mkisofs -b boot/limine/limine-bios-cd.bin --no-emul-boot -boot-load-size 4 -boot-info-table
@@ -228,29 +291,34 @@ pub fn main() {
         exec(format!(
             "mcopy -i {image_path}@@1M limine/limine-bios.sys ::/boot/limine"
         ));
-        exec(format!(
-            "mcopy -i {image_path}@@1M limine/BOOTX64.EFI ::/EFI/BOOT"
-        ));
-        exec(format!(
-            "mcopy -i {image_path}@@1M limine/BOOTIA32.EFI ::/EFI/BOOT"
-        ));
+        for executable in arch.efi_executables() {
+            exec(format!(
+                "mcopy -i {image_path}@@1M limine/{executable} ::/EFI/BOOT"
+            ));
+        }
         exec(format!("./limine/limine bios-install {image_path}"));
     }
 
     if !only_build {
         let qemu_program = "qemu-system-".to_string() + arch.as_str();
+        let qemu_machine = match arch {
+            Arch::X86_64 => "-M q35",
+        };
+        let qemu_devices = "-netdev user,id=net0 -device virtio-net-pci,netdev=net0 -device virtio-rng-pci -device qemu-xhci -device usb-kbd -device usb-mouse";
 
         if bios {
             if iso {
                 exec(format!(
-                    "{qemu_program} -m 4G -M q35 -cdrom {image_path} -boot d"
+                    "{qemu_program} -m 4G -M q35 -serial stdio {qemu_devices} -cdrom {image_path} -boot d"
                 ));
             } else {
-                exec(format!("{qemu_program} -m 4G -M q35 -hda {image_path}"));
+                exec(format!(
+                    "{qemu_program} -m 4G -M q35 -serial stdio {qemu_devices} -hda {image_path}"
+                ));
             }
         } else {
             exec(format!(
-                "{qemu_program} -M q35 -drive if=pflash,unit=0,format=raw,file=ovmf/{ovmf_code},readonly=on
+                "{qemu_program} {qemu_machine} -serial stdio {qemu_devices} -drive if=pflash,unit=0,format=raw,file=ovmf/{ovmf_code},readonly=on
                 -drive if=pflash,unit=1,format=raw,file=ovmf/{ovmf_vars} {} {image_path}",
                 if iso { "-cdrom" } else { "-hda" }
             ));
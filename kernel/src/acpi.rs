@@ -0,0 +1,74 @@
+//! Finding ACPI tables, without interpreting any AML
+
+use lazy_static::lazy_static;
+
+use crate::{mmio::Mmio, paging, requests::RSDP_REQUEST};
+
+/// The common start of every table
+const HEADER_SIZE: usize = 36;
+
+lazy_static! {
+    /// The root table's mapping and whether its entries are 64 bit, as in the XSDT
+    static ref ROOT: Option<(Mmio, bool)> = find_root();
+}
+
+fn checksum_ok(table: &Mmio) -> bool {
+    (0..table.len()).fold(0u8, |sum, offset| {
+        sum.wrapping_add(table.read::<u8>(offset))
+    }) == 0
+}
+
+/// Map a whole table given its physical address, once its header says how long it is
+pub fn map_table(address: u64) -> Option<Mmio> {
+    let len = Mmio::map(address, HEADER_SIZE).read::<u32>(4) as usize;
+
+    if len < HEADER_SIZE {
+        return None;
+    }
+
+    let table = Mmio::map(address, len);
+
+    checksum_ok(&table).then_some(table)
+}
+
+fn find_root() -> Option<(Mmio, bool)> {
+    let address = paging::phys_from_bootloader(RSDP_REQUEST.get_response()?.address() as u64);
+    let rsdp = Mmio::map(address, 36);
+
+    if rsdp.read::<[u8; 8]>(0) != *b"RSD PTR " {
+        return None;
+    }
+
+    // Revision 2 and later have the XSDT, which can point above 4 GiB
+    if rsdp.read::<u8>(15) >= 2 {
+        let xsdt = rsdp.read::<u64>(24);
+
+        if xsdt != 0
+            && let Some(table) = map_table(xsdt)
+        {
+            return Some((table, true));
+        }
+    }
+
+    map_table(rsdp.read::<u32>(16) as u64).map(|table| (table, false))
+}
+
+/// Find the first table with `signature`, mapped from its header to its end
+pub fn find_table(signature: &[u8; 4]) -> Option<Mmio> {
+    let (root, wide) = ROOT.as_ref()?;
+    let entry_size = if *wide { 8 } else { 4 };
+
+    (HEADER_SIZE..root.len())
+        .step_by(entry_size)
+        .filter(|offset| offset + entry_size <= root.len())
+        .map(|offset| {
+            // The entries of the XSDT are only 4 byte aligned
+            if *wide {
+                root.read::<u32>(offset) as u64 | (root.read::<u32>(offset + 4) as u64) << 32
+            } else {
+                root.read::<u32>(offset) as u64
+            }
+        })
+        .filter(|&address| Mmio::map(address, HEADER_SIZE).read::<[u8; 4]>(0) == *signature)
+        .find_map(map_table)
+}
@@ -5,7 +5,9 @@ use core::{
     ptr::NonNull,
 };
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
+
+use crate::sync::Mutex;
 
 pub struct BuddyAllocator {
     start: NonZero<usize>,
@@ -73,6 +75,11 @@ impl BuddyAllocator {
         }
     }
 
+    /// How much of the memory it was given it hands out, the largest power of two that fits
+    pub fn managed_bytes(&self) -> usize {
+        1 << (self.end.get() - self.start.get()).ilog2()
+    }
+
     pub fn calculate_free_bytes(&self) -> usize {
         let mut amount = 0;
 
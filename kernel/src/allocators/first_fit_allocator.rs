@@ -4,7 +4,9 @@ use core::{
     ptr::NonNull,
 };
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
+
+use crate::sync::Mutex;
 
 pub struct FirstFitAllocator {
     head: Option<NonNull<Header>>,
@@ -1,15 +1,156 @@
+//! What differs between the architectures the kernel runs on
+//!
+//! Each architecture has a module of its own, and what code shared between them needs of one is
+//! the [`Architecture`] trait, implemented for the one built for by [`Current`]. The registers
+//! kept of kernel stacks and of threads in user mode are behind the [`Context`] and [`TrapFrame`]
+//! traits. The modules of x86_64 are still used directly by most of the kernel, which has to
+//! stop before it can be built for another architecture
+
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::X86_64 as Current;
+
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::apic;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::backtrace;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::cpu;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::cstate;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::earlycon;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::extable;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::init;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::interrupts;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::kexec;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::microcode;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::paging;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::pcid;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::pic;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::pit;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::port;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::reboot;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::registers;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::serial;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::speaker;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::suspend;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::vdso;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::wakeup;
+
+/// The registers kept while a kernel stack is switched away from
+pub trait Context: Default + Send {
+    /// Make it start `entry` on the stack ending at `stack_top` when it is switched to
+    fn init(&mut self, entry: extern "C" fn() -> !, stack_top: u64);
+
+    /// Save the registers into `from` and go on with `to`, returns once `from` is switched to
+    ///
+    /// # Safety
+    ///
+    /// `to` has to be a context that was switched away from or set up by [`Context::init`], on a
+    /// stack that is still there
+    unsafe fn switch(from: *mut Self, to: *const Self);
+}
+
+/// What a thread has in its registers in user mode, kept from when it last left it for when it
+/// goes back
+pub trait TrapFrame: Clone + Copy + Default + Send {
+    /// What a thread starts with to run at `entry` on the stack at `stack`
+    fn starting_at(entry: u64, stack: u64) -> Self;
+
+    fn instruction_pointer(&self) -> u64;
+
+    fn set_instruction_pointer(&mut self, address: u64);
+
+    fn stack_pointer(&self) -> u64;
+
+    fn set_stack_pointer(&mut self, address: u64);
+
+    /// Set what the syscall it made returns
+    fn set_return_value(&mut self, value: u64);
+
+    /// Where its thread local storage is
+    fn thread_pointer(&self) -> u64;
+
+    fn set_thread_pointer(&mut self, address: u64);
+
+    /// Make it trap again after one instruction, for debuggers stepping through it
+    fn set_single_step(&mut self, single_step: bool);
+
+    /// Whether what a debugger changed can be run, in user mode with interrupts enabled
+    fn is_valid(&self) -> bool;
+}
+
+/// The registers of threads in user mode on the architecture built for
+pub type UserRegisters = <Current as Architecture>::TrapFrame;
+
+/// Interrupt control, switching stacks, the MMU and the timer of an architecture
+pub trait Architecture {
+    type Context: Context;
+
+    type TrapFrame: TrapFrame;
+
+    const PAGE_SIZE: u64;
+
+    /// Set up the processor to handle exceptions and interrupts
+    fn init();
+
+    /// Set up what needs memory to be allocated or timers, once there are both
+    fn init_late();
+
+    fn reboot() -> !;
+
+    fn disable_interrupts();
+
+    fn enable_interrupts();
+
+    fn interrupts_enabled() -> bool;
+
+    /// Stop the processor until the next interrupt
+    fn wait_for_interrupts();
+
+    /// The physical address of the page table translation starts at
+    fn root_table() -> u64;
+
+    /// Forget what was cached of the translation of `virt`
+    fn flush(virt: u64);
+
+    /// Where `virt` is mapped in the page tables at `root`, which are reached by `phys_to_virt`
+    fn translate(root: u64, virt: u64, phys_to_virt: impl Fn(u64) -> u64) -> Option<u64>;
+
+    /// The address the last page fault was for
+    fn fault_address() -> u64;
+
+    /// A counter that goes up at a constant rate
+    fn counter() -> u64;
+
+    /// How fast [`Architecture::counter`] goes in hertz, if the processor tells
+    fn counter_frequency() -> Option<u64>;
+}
 
 pub fn endless_loop() -> ! {
-    interrupts::disable();
+    Current::disable_interrupts();
 
     loop {
-        interrupts::wait_for_interrupts();
+        Current::wait_for_interrupts();
     }
 }
@@ -0,0 +1,166 @@
+//! The local APIC, only its timer, which wakes the processor up from idle
+//!
+//! Nothing else raises interrupts yet, so the timer's is the only one that has a vector, every
+//! other vector is reported as unhandled
+
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use super::cpu;
+use crate::{
+    irq::{self, Problem},
+    paging, time,
+};
+
+pub const WAKEUP_VECTOR: u8 = 0x30;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const MSR_APIC_BASE: u32 = 0x1B;
+const MSR_TSC_DEADLINE: u32 = 0x6E0;
+const MSR_X2APIC_BASE: u32 = 0x800;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+const REGISTER_EOI: u32 = 0xB0;
+const REGISTER_SPURIOUS: u32 = 0xF0;
+/// The first of eight registers of 32 bits each, with a bit for every vector being serviced
+const REGISTER_IN_SERVICE: u32 = 0x100;
+const REGISTER_LVT_TIMER: u32 = 0x320;
+const REGISTER_INITIAL_COUNT: u32 = 0x380;
+const REGISTER_CURRENT_COUNT: u32 = 0x390;
+const REGISTER_DIVIDE: u32 = 0x3E0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const TIMER_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const MODE_NONE: u8 = 0;
+const MODE_XAPIC: u8 = 1;
+const MODE_X2APIC: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(MODE_NONE);
+
+/// Where the registers are mapped when they are not model specific registers, which is not
+/// behind a lock as the interrupt handler needs it
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Ticks per second of the timer's count, zero when it fires at time stamps instead
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+fn read(register: u32) -> u32 {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_X2APIC => cpu::rdmsr(MSR_X2APIC_BASE + (register >> 4)) as u32,
+        MODE_XAPIC => unsafe {
+            ((XAPIC_BASE.load(Ordering::Relaxed) + register as u64) as *const u32).read_volatile()
+        },
+        _ => 0,
+    }
+}
+
+fn write(register: u32, value: u32) {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_X2APIC => cpu::wrmsr(MSR_X2APIC_BASE + (register >> 4), value as u64),
+        MODE_XAPIC => unsafe {
+            ((XAPIC_BASE.load(Ordering::Relaxed) + register as u64) as *mut u32)
+                .write_volatile(value)
+        },
+        _ => {}
+    }
+}
+
+pub fn is_enabled() -> bool {
+    MODE.load(Ordering::Relaxed) != MODE_NONE
+}
+
+pub fn is_in_service(vector: u8) -> bool {
+    read(REGISTER_IN_SERVICE + vector as u32 / 32 * 0x10) & 1 << (vector % 32) != 0
+}
+
+/// Tell the APIC `vector` was handled, unless it was not servicing it and would take it for the
+/// end of another
+pub fn eoi(vector: u8) {
+    if !is_in_service(vector) {
+        irq::report(vector, Problem::EoiMismatch);
+        return;
+    }
+
+    write(REGISTER_EOI, 0);
+}
+
+/// Raise the wakeup interrupt after `ns`, replacing any earlier wakeup
+pub fn arm_wakeup(ns: u64) {
+    match TIMER_FREQUENCY.load(Ordering::Relaxed) {
+        0 => {
+            let ticks = (ns as u128 * time::tsc_frequency() as u128
+                / time::NANOSECONDS_PER_SECOND as u128) as u64;
+
+            cpu::wrmsr(MSR_TSC_DEADLINE, cpu::rdtsc() + ticks.max(1));
+        }
+        frequency => {
+            let count = ns as u128 * frequency as u128 / time::NANOSECONDS_PER_SECOND as u128;
+
+            write(
+                REGISTER_INITIAL_COUNT,
+                count.clamp(1, u32::MAX as u128) as u32,
+            );
+        }
+    }
+}
+
+pub fn disarm_wakeup() {
+    if TIMER_FREQUENCY.load(Ordering::Relaxed) == 0 {
+        cpu::wrmsr(MSR_TSC_DEADLINE, 0);
+    } else {
+        write(REGISTER_INITIAL_COUNT, 0);
+    }
+}
+
+/// Count how fast the timer runs over 10 milliseconds of the time stamp counter
+fn calibrate_timer() -> u64 {
+    write(REGISTER_DIVIDE, DIVIDE_BY_16);
+    write(REGISTER_INITIAL_COUNT, u32::MAX);
+
+    time::delay_ns(10_000_000);
+
+    let elapsed = u32::MAX - read(REGISTER_CURRENT_COUNT);
+
+    write(REGISTER_INITIAL_COUNT, 0);
+
+    (elapsed as u64 * 100).max(1)
+}
+
+/// Enable the local APIC with its timer set up for wakeups, returns false if there is none
+pub fn init() -> bool {
+    if cpu::cpuid(1, 0).edx & (1 << 9) == 0 {
+        return false;
+    }
+
+    let base = cpu::rdmsr(MSR_APIC_BASE);
+
+    if cpu::has_x2apic() {
+        cpu::wrmsr(MSR_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        MODE.store(MODE_X2APIC, Ordering::Relaxed);
+    } else {
+        cpu::wrmsr(MSR_APIC_BASE, base | APIC_BASE_ENABLE);
+        XAPIC_BASE.store(
+            paging::map_mmio(base & 0x000F_FFFF_FFFF_F000, 0x1000),
+            Ordering::Relaxed,
+        );
+        MODE.store(MODE_XAPIC, Ordering::Relaxed);
+    }
+
+    write(REGISTER_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+
+    if cpu::has_tsc_deadline() {
+        write(
+            REGISTER_LVT_TIMER,
+            TIMER_MODE_TSC_DEADLINE | WAKEUP_VECTOR as u32,
+        );
+    } else {
+        // One shot, counting down from the initial count
+        write(REGISTER_LVT_TIMER, WAKEUP_VECTOR as u32);
+        TIMER_FREQUENCY.store(calibrate_timer(), Ordering::Relaxed);
+    }
+
+    true
+}
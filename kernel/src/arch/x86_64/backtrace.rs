@@ -0,0 +1,39 @@
+use core::arch::asm;
+
+const MAX_DEPTH: usize = 64;
+
+/// Walk the chain of frame pointers starting from the caller, calling `f` with each return address
+///
+/// This relies on the kernel being built with frame pointers, and stops at the first frame pointer
+/// that does not look like it points into the higher half
+#[inline(always)]
+pub fn walk(mut f: impl FnMut(u64)) {
+    let mut rbp: u64;
+
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    for _ in 0..MAX_DEPTH {
+        if rbp < 0xFFFF_8000_0000_0000 || !rbp.is_multiple_of(8) {
+            break;
+        }
+
+        let frame = rbp as *const u64;
+
+        let (previous_rbp, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+
+        if return_address == 0 {
+            break;
+        }
+
+        f(return_address);
+
+        // Frames must go up the stack, otherwise we would loop forever on a corrupted chain
+        if previous_rbp <= rbp {
+            break;
+        }
+
+        rbp = previous_rbp;
+    }
+}
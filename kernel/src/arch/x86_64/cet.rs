@@ -0,0 +1,361 @@
+//! Control-flow enforcement for the kernel, shadow stacks and indirect branch tracking
+//!
+//! With shadow stacks every call also pushes its return address to a stack ordinary stores can
+//! not write, and every return checks that the two agree, so a return address that was overwritten
+//! raises a control protection exception instead of being returned to. They are turned on late in
+//! boot from a call chain that is already running, so the shadow stack starts out with the return
+//! addresses of the frames above, found through the frame pointers. Each slot of the interrupt
+//! stack table gets a shadow stack of its own
+//!
+//! Indirect branch tracking makes every indirect call and jump land on an `endbr64`, which the
+//! compiler only puts in with `-Z cf-protection`, so it is only turned on in kernels built with
+//! the `cet` feature, `with cet` in the builder. `nocet` on the command line turns both off
+//!
+//! Switching between kernel stacks does not switch shadow stacks yet, as nothing switches them
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use super::{
+    backtrace, cpu, gdt,
+    paging::{PAGE_SIZE, PageTableFlags},
+};
+use crate::{cmdline, memory, paging};
+
+const MSR_S_CET: u32 = 0x6A2;
+/// Where the table of the shadow stacks of the interrupt stack table slots is
+const MSR_INTERRUPT_SSP_TABLE: u32 = 0x6A8;
+
+const CET_SHSTK_EN: u64 = 1 << 0;
+/// Lets `wrss` write to shadow stacks, which moving where an exception returns to needs
+const CET_WRSS_EN: u64 = 1 << 1;
+const CET_ENDBR_EN: u64 = 1 << 2;
+/// Lets branches with the `notrack` prefix, like those of jump tables, land anywhere
+const CET_NO_TRACK_EN: u64 = 1 << 4;
+
+const CR0_WP: u64 = 1 << 16;
+const CR4_CET: u64 = 1 << 23;
+
+/// Set in a restore token made in 64 bit mode
+const TOKEN_64_BIT: u64 = 1;
+
+/// A shadow stack only holds return addresses and the frames of exceptions, so it can be much
+/// smaller than its stack
+const BOOT_SHADOW_STACK_SIZE: usize = 16 * 1024;
+const INTERRUPT_SHADOW_STACK_SIZE: usize = 8 * 1024;
+
+/// How many frames above the one turning shadow stacks on are put on the shadow stack
+const MAX_FRAMES: usize = 64;
+
+/// How far up the shadow stack the frame of an exception is looked for
+const MAX_SCAN: usize = 512;
+
+/// The code segment the kernel runs in, pushed onto the shadow stack with the return address of
+/// an exception
+const KERNEL_CODE_SELECTOR: u64 = 0x08;
+
+#[repr(C, align(64))]
+struct InterruptSspTable([AtomicU64; 8]);
+
+/// The supervisor shadow stack token of each slot of the interrupt stack table, from the first
+/// slot in the second entry
+static INTERRUPT_SSP_TABLE: InterruptSspTable = InterruptSspTable([const { AtomicU64::new(0) }; 8]);
+
+/// A second token halfway down the shadow stack of NMIs, for one that starts while another is
+/// being handled, like [`gdt::point_interrupt_stack`] moves its stack
+static NESTED_NMI_TOKEN: AtomicU64 = AtomicU64::new(0);
+static NMI_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+static SHADOW_STACKS: AtomicBool = AtomicBool::new(false);
+static BRANCH_TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// Where the boot shadow stack starts, to start it over when waking up from sleep
+static BOOT_SHADOW_STACK: AtomicU64 = AtomicU64::new(0);
+
+/// What a control protection exception with `code` caught
+pub fn describe(code: u64) -> &'static str {
+    match code & 0x7FFF {
+        1 => "return address does not match the shadow stack",
+        2 => "far return or iret does not match the shadow stack",
+        3 => "indirect branch did not land on an endbr64",
+        4 => "bad shadow stack restore token",
+        5 => "bad supervisor shadow stack token",
+        _ => "unknown violation",
+    }
+}
+
+fn read_cr0() -> u64 {
+    let cr0: u64;
+
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    }
+
+    cr0
+}
+
+/// Make the pages of `len` bytes at `bottom` shadow stack pages, read only and dirty, or turn
+/// them back into ordinary writable pages
+fn set_shadow(bottom: u64, len: usize, shadow: bool) {
+    for page in (bottom..bottom + len as u64).step_by(PAGE_SIZE as usize) {
+        let Some(entry) = paging::read_entry(page) else {
+            continue;
+        };
+
+        let entry = if shadow {
+            entry & !PageTableFlags::WRITABLE.bits() | PageTableFlags::DIRTY.bits()
+        } else {
+            entry | PageTableFlags::WRITABLE.bits()
+        };
+
+        paging::write_entry(page, entry);
+    }
+}
+
+/// A shadow stack of `len` bytes in the vmalloc area, with a guard page above it, still writable
+fn allocate(len: usize) -> Option<u64> {
+    let buffer = memory::vmalloc(len).ok()?;
+    let bottom = buffer.as_ptr() as u64;
+
+    // In use until the processor is reset, like the stacks of the interrupt stack table
+    core::mem::forget(buffer);
+
+    Some(bottom)
+}
+
+/// Put a supervisor shadow stack token, which an exception switching to the shadow stack takes,
+/// at `address`
+fn write_token(address: u64) {
+    unsafe { (address as *mut u64).write_volatile(address) };
+}
+
+/// Give each slot of the interrupt stack table a shadow stack with a token at its top
+fn init_interrupt_shadow_stacks() -> bool {
+    for slot in 0..gdt::INTERRUPT_STACK_COUNT {
+        let Some(bottom) = allocate(INTERRUPT_SHADOW_STACK_SIZE) else {
+            return false;
+        };
+
+        let token = bottom + INTERRUPT_SHADOW_STACK_SIZE as u64 - 8;
+
+        write_token(token);
+
+        if slot as u16 == gdt::NMI_STACK {
+            let nested = bottom + INTERRUPT_SHADOW_STACK_SIZE as u64 / 2 - 8;
+
+            write_token(nested);
+
+            NMI_TOKEN.store(token, Ordering::Relaxed);
+            NESTED_NMI_TOKEN.store(nested, Ordering::Relaxed);
+        }
+
+        set_shadow(bottom, INTERRUPT_SHADOW_STACK_SIZE, true);
+
+        INTERRUPT_SSP_TABLE.0[slot + 1].store(token, Ordering::Relaxed);
+    }
+
+    true
+}
+
+/// Start the shadow stack of `len` bytes at `bottom` with the return addresses of the frames
+/// above this one, then turn on enforcement with `s_cet`
+///
+/// It must not be inlined, as its own return address is the first one put on the shadow stack
+#[inline(never)]
+fn start_shadow_stack(bottom: u64, len: usize, s_cet: u64) {
+    let mut addresses = [0; MAX_FRAMES];
+    let mut count = 0;
+
+    backtrace::walk(|address| {
+        if count < MAX_FRAMES {
+            addresses[count] = address;
+            count += 1;
+        }
+    });
+
+    // The restore token, then the return addresses with the one returned to first nearest to it
+    let token = bottom + len as u64 - 8 * (count as u64 + 1);
+
+    set_shadow(bottom, len, false);
+
+    unsafe {
+        let shadow = token as *mut u64;
+
+        shadow.write_volatile((token + 8) | TOKEN_64_BIT);
+
+        for (index, &address) in addresses[..count].iter().enumerate() {
+            shadow.add(index + 1).write_volatile(address);
+        }
+    }
+
+    set_shadow(bottom, len, true);
+
+    // Nothing may be called or returned from between turning shadow stacks on and moving to the
+    // one made here. The restore token is left behind on the shadow stack and popped
+    unsafe {
+        asm!(
+            "wrmsr",
+            "rstorssp [{token}]",
+            "incsspq {one}",
+            token = in(reg) token,
+            one = in(reg) 1u64,
+            in("ecx") MSR_S_CET,
+            in("eax") s_cet as u32,
+            in("edx") (s_cet >> 32) as u32,
+            options(nostack)
+        );
+    }
+}
+
+/// Turn on what the processor has of shadow stacks, and of indirect branch tracking if the
+/// kernel was built for it, unless `nocet` is on the command line
+///
+/// Called with interrupts disabled, after the stacks of the interrupt stack table were allocated
+pub fn init() {
+    let shadow_stacks = cpu::has_shadow_stacks();
+    let branch_tracking = cfg!(feature = "cet") && cpu::has_indirect_branch_tracking();
+
+    if !shadow_stacks && !branch_tracking {
+        return;
+    }
+
+    if cmdline::has("nocet") {
+        println!("cet: turned off on the command line");
+        return;
+    }
+
+    // Shadow stack pages are read only, which only keeps the kernel out with write protection
+    if read_cr0() & CR0_WP == 0 {
+        println!("cet: write protection is off");
+        return;
+    }
+
+    cpu::write_cr4(cpu::read_cr4() | CR4_CET);
+
+    let mut s_cet = 0;
+
+    if branch_tracking {
+        s_cet |= CET_ENDBR_EN | CET_NO_TRACK_EN;
+    }
+
+    let boot = allocate(BOOT_SHADOW_STACK_SIZE);
+
+    if shadow_stacks && let Some(boot) = boot.filter(|_| init_interrupt_shadow_stacks()) {
+        cpu::wrmsr(
+            MSR_INTERRUPT_SSP_TABLE,
+            (&raw const INTERRUPT_SSP_TABLE) as u64,
+        );
+
+        BOOT_SHADOW_STACK.store(boot, Ordering::Relaxed);
+        SHADOW_STACKS.store(true, Ordering::Relaxed);
+
+        start_shadow_stack(
+            boot,
+            BOOT_SHADOW_STACK_SIZE,
+            s_cet | CET_SHSTK_EN | CET_WRSS_EN,
+        );
+    } else {
+        if shadow_stacks {
+            println!("cet: out of memory for shadow stacks");
+        }
+
+        cpu::wrmsr(MSR_S_CET, s_cet);
+    }
+
+    BRANCH_TRACKING.store(branch_tracking, Ordering::Relaxed);
+
+    println!(
+        "cet: shadow stacks {}, indirect branch tracking {}",
+        if SHADOW_STACKS.load(Ordering::Relaxed) {
+            "on"
+        } else {
+            "off"
+        },
+        if branch_tracking { "on" } else { "off" }
+    );
+}
+
+/// Turn both on again after waking up from sleep, which turned them off, with the boot shadow
+/// stack started over
+pub fn resume() {
+    let mut s_cet = 0;
+
+    if BRANCH_TRACKING.load(Ordering::Relaxed) {
+        s_cet |= CET_ENDBR_EN | CET_NO_TRACK_EN;
+    }
+
+    if !SHADOW_STACKS.load(Ordering::Relaxed) {
+        if s_cet != 0 {
+            cpu::wrmsr(MSR_S_CET, s_cet);
+        }
+
+        return;
+    }
+
+    cpu::wrmsr(
+        MSR_INTERRUPT_SSP_TABLE,
+        (&raw const INTERRUPT_SSP_TABLE) as u64,
+    );
+
+    start_shadow_stack(
+        BOOT_SHADOW_STACK.load(Ordering::Relaxed),
+        BOOT_SHADOW_STACK_SIZE,
+        s_cet | CET_SHSTK_EN | CET_WRSS_EN,
+    );
+}
+
+/// Turn both off, for a kernel booted with kexec which may not expect them
+pub fn disable() {
+    if cpu::read_cr4() & CR4_CET == 0 {
+        return;
+    }
+
+    cpu::wrmsr(MSR_S_CET, 0);
+    cpu::write_cr4(cpu::read_cr4() & !CR4_CET);
+}
+
+/// Make the exception returning to `rip` return to `new_rip`, which the frame it pushed on the
+/// shadow stack has to say too
+pub fn move_return(rip: u64, new_rip: u64) {
+    if !SHADOW_STACKS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut ssp: u64;
+
+    unsafe {
+        asm!("rdsspq {}", out(reg) ssp, options(nomem, nostack, preserves_flags));
+    }
+
+    // The frame is the code segment above the return address above the shadow stack pointer,
+    // which only calls the handler made since pushed below
+    for _ in 0..MAX_SCAN {
+        let entry = ssp as *const u64;
+        let (address, selector) = unsafe { (entry.read_volatile(), entry.add(1).read_volatile()) };
+
+        if address == rip && selector == KERNEL_CODE_SELECTOR {
+            unsafe {
+                asm!("wrssq qword ptr [{}], {}", in(reg) ssp, in(reg) new_rip, options(nostack));
+            }
+
+            return;
+        }
+
+        ssp += 8;
+    }
+}
+
+/// Move the shadow stack of NMIs started from now on halfway down or back to its top, like
+/// [`gdt::point_interrupt_stack`]
+pub fn point_nmi_shadow_stack(nested: bool) {
+    let token = match nested {
+        true => NESTED_NMI_TOKEN.load(Ordering::Relaxed),
+        false => NMI_TOKEN.load(Ordering::Relaxed),
+    };
+
+    if token != 0 {
+        INTERRUPT_SSP_TABLE.0[gdt::NMI_STACK as usize + 1].store(token, Ordering::Relaxed);
+    }
+}
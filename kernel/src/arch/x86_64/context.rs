@@ -0,0 +1,62 @@
+//! Switching between kernel stacks, saving the registers a call has to keep on the stack left
+
+use core::arch::global_asm;
+
+/// Where a stack that was switched away from was left
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+}
+
+global_asm!(
+    r#"
+    .pushsection .text.context, "ax"
+    .global context_switch
+context_switch:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, (%rdi)
+    movq (%rsi), %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    ret
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    fn context_switch(from: *mut Context, to: *const Context);
+}
+
+impl crate::arch::Context for Context {
+    fn init(&mut self, entry: extern "C" fn() -> !, stack_top: u64) {
+        // What context_switch pops, the address it returns to and a return address for entry, so
+        // the stack is aligned as after a call
+        let frame = ((stack_top & !0xF) - 8 * size_of::<u64>() as u64) as *mut u64;
+
+        unsafe {
+            for register in 0..6 {
+                frame.add(register).write(0);
+            }
+
+            frame.add(6).write(entry as usize as u64);
+            frame.add(7).write(0);
+        }
+
+        self.rsp = frame as u64;
+    }
+
+    unsafe fn switch(from: *mut Self, to: *const Self) {
+        unsafe { context_switch(from, to) }
+    }
+}
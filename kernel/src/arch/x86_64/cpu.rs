@@ -0,0 +1,284 @@
+use core::arch::asm;
+
+const MSR_FS_BASE: u32 = 0xC000_0100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx);
+
+    unsafe {
+        // LLVM reserves rbx, so it has to be saved and restored around cpuid
+        asm!(
+            "mov {rbx:r}, rbx",
+            "cpuid",
+            "xchg {rbx:r}, rbx",
+            rbx = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+pub fn max_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+pub fn is_intel() -> bool {
+    let vendor = cpuid(0, 0);
+
+    (vendor.ebx, vendor.edx, vendor.ecx) == (0x756E_6547, 0x4965_6E69, 0x6C65_746E)
+}
+
+/// The family with the extended family added in, as it is for family 15 and above
+pub fn family() -> u32 {
+    let eax = cpuid(1, 0).eax;
+    let family = (eax >> 8) & 0xF;
+
+    if family == 0xF {
+        family + ((eax >> 20) & 0xFF)
+    } else {
+        family
+    }
+}
+
+pub fn has_rdrand() -> bool {
+    cpuid(1, 0).ecx & (1 << 30) != 0
+}
+
+pub fn has_rdseed() -> bool {
+    max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 18) != 0
+}
+
+pub fn has_mwait() -> bool {
+    cpuid(1, 0).ecx & (1 << 3) != 0
+}
+
+/// Whether the processor raises machine check exceptions
+pub fn has_mce() -> bool {
+    cpuid(1, 0).edx & (1 << 7) != 0
+}
+
+/// Whether it logs the errors of machine checks in banks of MSRs
+pub fn has_mca() -> bool {
+    cpuid(1, 0).edx & (1 << 14) != 0
+}
+
+/// Whether the kernel can have shadow stacks, which only hold return addresses
+pub fn has_shadow_stacks() -> bool {
+    max_leaf() >= 7 && cpuid(7, 0).ecx & (1 << 7) != 0
+}
+
+/// Whether indirect branches can be made to only land on `endbr64`
+pub fn has_indirect_branch_tracking() -> bool {
+    max_leaf() >= 7 && cpuid(7, 0).edx & (1 << 20) != 0
+}
+
+pub fn has_x2apic() -> bool {
+    cpuid(1, 0).ecx & (1 << 21) != 0
+}
+
+/// The running processor's APIC ID, the full 32 bits of it when the topology leaf has them
+pub fn apic_id() -> u32 {
+    if max_leaf() >= 0xB && cpuid(0xB, 0).ebx != 0 {
+        cpuid(0xB, 0).edx
+    } else {
+        cpuid(1, 0).ebx >> 24
+    }
+}
+
+/// Whether TLB entries can be tagged with the address space they belong to
+pub fn has_pcid() -> bool {
+    cpuid(1, 0).ecx & (1 << 17) != 0
+}
+
+pub fn has_invpcid() -> bool {
+    max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 10) != 0
+}
+
+/// Whether the local APIC timer can fire at a time stamp instead of after a count
+pub fn has_tsc_deadline() -> bool {
+    cpuid(1, 0).ecx & (1 << 24) != 0
+}
+
+pub fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+
+    ((high as u64) << 32) | low as u64
+}
+
+/// Read a model specific register that may not exist, which raises a protection fault
+pub fn rdmsr_safe(msr: u32) -> Option<u64> {
+    let (low, high): (u32, u32);
+    let faulted: u32;
+
+    unsafe {
+        asm!(
+            "xor {faulted:e}, {faulted:e}",
+            "2: rdmsr",
+            "jmp 4f",
+            "3: mov {faulted:e}, 1",
+            "4:",
+            crate::extable!("2b", "3b"),
+            faulted = out(reg) faulted,
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack)
+        );
+    }
+
+    (faulted == 0).then_some(((high as u64) << 32) | low as u64)
+}
+
+/// Write a model specific register that may not exist or may not take `value`, returns whether it
+/// did
+pub fn wrmsr_safe(msr: u32, value: u64) -> bool {
+    let faulted: u32;
+
+    unsafe {
+        asm!(
+            "xor {faulted:e}, {faulted:e}",
+            "2: wrmsr",
+            "jmp 4f",
+            "3: mov {faulted:e}, 1",
+            "4:",
+            crate::extable!("2b", "3b"),
+            faulted = out(reg) faulted,
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack)
+        );
+    }
+
+    faulted == 0
+}
+
+pub fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+    }
+}
+
+pub fn read_cr4() -> u64 {
+    let cr4: u64;
+
+    unsafe {
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+
+    cr4
+}
+
+pub fn write_cr4(cr4: u64) {
+    unsafe {
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+    }
+}
+
+/// Point the FS segment at `base`, which is where user threads keep their thread local storage
+pub fn set_fs_base(base: u64) {
+    wrmsr(MSR_FS_BASE, base);
+}
+
+pub fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).eax
+}
+
+/// Whether the time stamp counter runs at the same rate in every power and frequency state
+pub fn has_invariant_tsc() -> bool {
+    max_extended_leaf() >= 0x8000_0007 && cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+}
+
+/// The frequency of the time stamp counter in hertz as the processor reports it, exact when it
+/// gives the frequency of its crystal and rounded to megahertz when only its base frequency
+pub fn tsc_frequency() -> Option<(u64, bool)> {
+    if max_leaf() < 0x15 {
+        return None;
+    }
+
+    let ratio = cpuid(0x15, 0);
+
+    if ratio.eax == 0 || ratio.ebx == 0 {
+        return None;
+    }
+
+    if ratio.ecx != 0 {
+        return Some((ratio.ecx as u64 * ratio.ebx as u64 / ratio.eax as u64, true));
+    }
+
+    // No crystal frequency, but the base frequency is the nominal one of the counter
+    if max_leaf() >= 0x16 {
+        let base_mhz = cpuid(0x16, 0).eax & 0xFFFF;
+
+        if base_mhz != 0 {
+            return Some((base_mhz as u64 * 1_000_000, false));
+        }
+    }
+
+    None
+}
+
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+
+    ((high as u64) << 32) | low as u64
+}
+
+/// Get a random number from the hardware, retrying a few times as it may temporarily run out
+pub fn rdrand() -> Option<u64> {
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Get a random seed from the hardware, which unlike [`rdrand`] comes directly from the entropy
+/// source, it runs out more often so it is retried more
+pub fn rdseed() -> Option<u64> {
+    for _ in 0..100 {
+        let value: u64;
+        let ok: u8;
+
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+
+        if ok != 0 {
+            return Some(value);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    None
+}
@@ -0,0 +1,102 @@
+//! The processor's idle states, entered with mwait when it has it and with hlt otherwise
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use super::cpu;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CState {
+    pub name: &'static str,
+    /// The hint mwait is given, or `None` for hlt
+    hint: Option<u32>,
+    /// How long waking up takes
+    pub exit_latency_ns: u64,
+    /// How long the state has to last to save more than entering and leaving it costs
+    pub target_residency_ns: u64,
+}
+
+/// What each mwait C-state costs, the real numbers are in the ACPI `_CST` objects which would
+/// need AML, so these are on the careful side of what common processors have
+const MWAIT_STATES: [(&str, u64, u64); 7] = [
+    ("C1", 1_000, 1_000),
+    ("C2", 50_000, 150_000),
+    ("C3", 100_000, 400_000),
+    ("C4", 150_000, 800_000),
+    ("C5", 200_000, 1_200_000),
+    ("C6", 250_000, 1_600_000),
+    ("C7", 300_000, 2_000_000),
+];
+
+/// The states from shallowest to deepest, the first being hlt or mwait's C1
+pub fn states() -> Vec<CState> {
+    let halt = CState {
+        name: "HLT",
+        hint: None,
+        exit_latency_ns: 1_000,
+        target_residency_ns: 1_000,
+    };
+
+    if !cpu::has_mwait() || cpu::max_leaf() < 5 {
+        return Vec::from([halt]);
+    }
+
+    let leaf = cpu::cpuid(5, 0);
+
+    // Without enumeration of the sub states there is no knowing which hints are valid
+    if leaf.ecx & 1 == 0 {
+        return Vec::from([halt]);
+    }
+
+    // Each nibble of edx counts the sub states of a C-state, starting from C0
+    let mut states = Vec::new();
+
+    for (i, &(name, exit_latency_ns, target_residency_ns)) in MWAIT_STATES.iter().enumerate() {
+        if (leaf.edx >> (4 * (i + 1))) & 0xF == 0 {
+            continue;
+        }
+
+        states.push(CState {
+            name,
+            hint: Some((i as u32) << 4),
+            exit_latency_ns,
+            target_residency_ns,
+        });
+    }
+
+    if states.is_empty() {
+        states.push(halt);
+    }
+
+    states
+}
+
+/// A line for mwait to monitor, nothing writes to it so only interrupts wake it up
+#[repr(align(64))]
+struct MonitorLine;
+
+static MONITOR_LINE: MonitorLine = MonitorLine;
+
+/// Sleep in `state` until an interrupt, which is handled before returning with interrupts
+/// disabled again
+pub fn enter(state: &CState) {
+    // Interrupts are enabled right before going to sleep, and the one instruction after sti still
+    // runs with them disabled, so a wakeup can not slip in before the sleep
+    unsafe {
+        match state.hint {
+            None => asm!("sti", "hlt", "cli", options(nomem, nostack)),
+            Some(hint) => asm!(
+                "monitor",
+                "mov eax, {hint:e}",
+                "sti",
+                "mwait",
+                "cli",
+                hint = in(reg) hint,
+                inout("rax") &MONITOR_LINE as *const MonitorLine => _,
+                inout("ecx") 0 => _,
+                in("edx") 0,
+                options(nostack),
+            ),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Output that works from the first instruction of the kernel, before the console is set up
+//!
+//! What is written goes to the first serial port, set up the first time something is written,
+//! and to the debug port Bochs and QEMU print with `-debugcon`. Neither needs memory, statics
+//! initialized lazily or the framebuffer, so a crash early in the boot can still be seen
+
+use core::fmt::Write;
+
+use super::{
+    port,
+    serial::{COM1, SerialPort},
+};
+use crate::sync::Mutex;
+
+/// What is written to it shows up on the debug console of Bochs and QEMU, and nowhere elsewhere
+const DEBUG_PORT: u16 = 0xE9;
+
+/// The serial port, and whether it was set up yet
+static SERIAL: Mutex<(SerialPort, bool)> = Mutex::new((SerialPort::new(COM1), false));
+
+pub fn write_str(s: &str) {
+    // Only fails when something writing panicked, the panic message is lost here then
+    let Some(mut serial) = SERIAL.try_lock() else {
+        return;
+    };
+
+    let (serial, initialized) = &mut *serial;
+
+    if !*initialized {
+        serial.init();
+        *initialized = true;
+    }
+
+    let _ = serial.write_str(s);
+
+    for byte in s.bytes() {
+        port::outb(DEBUG_PORT, byte);
+    }
+}
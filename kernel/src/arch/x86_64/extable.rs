@@ -0,0 +1,107 @@
+//! Instructions that are allowed to fault, and where to continue when they do instead of
+//! panicking
+//!
+//! An instruction is listed with [`extable!`] in the same `asm!` block, from a label on it to a
+//! label on the code that handles the fault, which sees the registers as they were when it faulted
+
+use core::arch::asm;
+
+/// One instruction and where to go when it faults, both relative to where they are stored so the
+/// table needs no relocations
+#[repr(C)]
+struct Entry {
+    instruction: i32,
+    fixup: i32,
+}
+
+unsafe extern "C" {
+    static __extable_start: Entry;
+    static __extable_end: Entry;
+}
+
+/// The assembly that lists the instruction at the label `instruction` as allowed to fault, going
+/// to the label `fixup` when it does, like `extable!("2b", "3b")`
+#[macro_export]
+macro_rules! extable {
+    ($instruction:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection .extable, \"a\"\n",
+            ".balign 4\n",
+            ".long ",
+            $instruction,
+            " - .\n",
+            ".long ",
+            $fixup,
+            " - .\n",
+            ".popsection"
+        )
+    };
+}
+
+/// Where to continue after the instruction at `rip` faulted, if it is allowed to
+pub fn fixup(rip: u64) -> Option<u64> {
+    let start = &raw const __extable_start;
+    let end = &raw const __extable_end;
+
+    let entries = unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) };
+
+    entries.iter().find_map(|entry| {
+        let instruction =
+            (&raw const entry.instruction as u64).wrapping_add_signed(entry.instruction as i64);
+        let fixup = (&raw const entry.fixup as u64).wrapping_add_signed(entry.fixup as i64);
+
+        (instruction == rip).then_some(fixup)
+    })
+}
+
+/// The page fault or protection fault that one of the functions below ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+/// Read a `u32` from `address`, which may not be mapped or may not answer
+pub fn read_u32(address: u64) -> Result<u32, Fault> {
+    let value: u32;
+    let faulted: u32;
+
+    unsafe {
+        asm!(
+            "xor {faulted:e}, {faulted:e}",
+            "2: mov {value:e}, [{address}]",
+            "jmp 4f",
+            "3: mov {faulted:e}, 1",
+            "xor {value:e}, {value:e}",
+            "4:",
+            extable!("2b", "3b"),
+            address = in(reg) address,
+            value = out(reg) value,
+            faulted = out(reg) faulted,
+            options(nostack, readonly)
+        );
+    }
+
+    if faulted == 0 { Ok(value) } else { Err(Fault) }
+}
+
+/// Copy `len` bytes from `source` to `destination`, stopping at the first fault
+///
+/// # Safety
+///
+/// Whatever of `destination` is mapped must be fine to overwrite
+pub unsafe fn copy(destination: *mut u8, source: *const u8, len: usize) -> Result<(), Fault> {
+    let remaining: usize;
+
+    unsafe {
+        asm!(
+            "2: rep movsb",
+            "3:",
+            extable!("2b", "3b"),
+            inout("rdi") destination => _,
+            inout("rsi") source => _,
+            inout("rcx") len => remaining,
+            options(nostack)
+        );
+    }
+
+    // A fault leaves what was not copied yet in the count, a finished copy leaves zero
+    if remaining == 0 { Ok(()) } else { Err(Fault) }
+}
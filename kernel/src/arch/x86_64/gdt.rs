@@ -1,10 +1,18 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use bit_field::BitField;
 use bitflags::bitflags;
 use lazy_static::lazy_static;
+use spin::Once;
 
 use super::{DescriptorTableRegister, tss::TaskStateSegment};
+use crate::{
+    memory, ro_after_init,
+    stack::{self, Stack},
+};
 
 #[derive(Debug, PartialEq)]
 struct GlobalDescriptorTable<const MAX: usize = 8> {
@@ -168,22 +176,60 @@ impl Descriptor {
     }
 }
 
+/// The slots of the interrupt stack table, for the exceptions that can not trust the stack they
+/// interrupted to be usable
+pub const DOUBLE_FAULT_STACK: u16 = 0;
+pub const NMI_STACK: u16 = 1;
+pub const MACHINE_CHECK_STACK: u16 = 2;
+pub const PAGE_FAULT_STACK: u16 = 3;
+
+/// What each slot is called and how big its stack is once there is memory to allocate it from,
+/// page faults get more as they can read from files and swap
+const INTERRUPT_STACKS: [(&str, usize); 4] = [
+    ("double fault", 20 * 1024),
+    ("nmi", 20 * 1024),
+    ("machine check", 20 * 1024),
+    ("page fault", 64 * 1024),
+];
+
+/// The stacks the slots start with, without guard pages as boot is too early to map any
+const BOOT_STACK_SIZE: usize = 16 * 1024;
+
+pub const INTERRUPT_STACK_COUNT: usize = INTERRUPT_STACKS.len();
+
+static mut BOOT_STACKS: [[u8; BOOT_STACK_SIZE]; INTERRUPT_STACKS.len()] =
+    [[0; BOOT_STACK_SIZE]; INTERRUPT_STACKS.len()];
+
+/// The tops of the stacks allocated for the slots, zero for those still on their boot stack
+static INTERRUPT_STACK_TOPS: [AtomicU64; INTERRUPT_STACKS.len()] =
+    [const { AtomicU64::new(0) }; INTERRUPT_STACKS.len()];
+
+fn boot_stack_top(slot: usize) -> u64 {
+    ((&raw const BOOT_STACKS).addr() + (slot + 1) * BOOT_STACK_SIZE) as u64
+}
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
 
-        tss.interrupt_stack_table[0] = {
-            const IST_STACK_SIZE: usize = 20 * 1024;
-            static mut IST_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
-            ((&raw const IST_STACK).addr() + IST_STACK_SIZE) as u64
-        };
+        tss.interrupt_stack_table = core::array::from_fn(|slot| {
+            if slot >= INTERRUPT_STACKS.len() {
+                return 0;
+            }
+
+            boot_stack_top(slot)
+        });
 
         tss
     };
 }
 
-lazy_static! {
-    static ref GDT: GlobalDescriptorTable = {
+ro_after_init! {
+    static GDT: Once<GlobalDescriptorTable> = Once::new();
+}
+
+fn gdt() -> &'static GlobalDescriptorTable {
+    GDT.call_once(|| {
         let mut gdt = GlobalDescriptorTable::empty();
 
         gdt.push(Descriptor::kernel_code_segment()); // 0x08
@@ -193,16 +239,16 @@ lazy_static! {
         gdt.push(Descriptor::task_state_segment(&TSS)); // 0x28
 
         gdt
-    };
+    })
 }
 
 pub fn init() {
     unsafe {
-        asm!("lgdt [{}]", in(reg) &GDT.register(), options(readonly, nostack, preserves_flags));
+        asm!("lgdt [{}]", in(reg) &gdt().register(), options(readonly, nostack, preserves_flags));
 
         asm!(
             "push 0x08",
-            "lea rax, [{}]",
+            "lea rax, [rip + {}]",
             "push rax",
             "retfq",
             label {
@@ -223,3 +269,60 @@ pub fn init() {
         asm!("ltr {0:x}", in(reg) 0x28, options(readonly, nostack, preserves_flags));
     }
 }
+
+/// Load the table and the task register again after the processor lost them, like when it wakes
+/// up from sleep
+pub fn reload() {
+    // Loading the task register marks its descriptor busy, and a busy one can not be loaded, so
+    // the mark from before has to go, the processor writes to the table itself too
+    ro_after_init::with_writable(|| {
+        unsafe {
+            let tss = (&raw const gdt().table[5]).cast_mut();
+
+            tss.write_volatile(Entry(tss.read_volatile().0 & !(1 << 41)));
+        }
+
+        init();
+    });
+}
+
+/// Move each slot of the interrupt stack table to a stack of its own in the vmalloc area, where
+/// overflowing it hits an unmapped page instead of what is below it
+pub fn init_interrupt_stacks() {
+    for (slot, (name, size)) in INTERRUPT_STACKS.into_iter().enumerate() {
+        let Ok(buffer) = memory::vmalloc(size) else {
+            println!("gdt: out of memory, the {} stack has no guard page", name);
+            continue;
+        };
+
+        let bottom = buffer.as_ptr().addr();
+
+        // The processor reads the slot at every exception that uses it, so the stack is in use
+        // from here on and is never freed
+        core::mem::forget(buffer);
+
+        stack::register(Stack::new(name, bottom, size));
+
+        INTERRUPT_STACK_TOPS[slot].store((bottom + size) as u64, Ordering::Relaxed);
+        point_interrupt_stack(slot as u16, (bottom + size) as u64);
+    }
+}
+
+/// The top of the stack of `slot`, where the processor starts exceptions that use it unless
+/// [`point_interrupt_stack`] moved it
+pub fn interrupt_stack_top(slot: u16) -> u64 {
+    match INTERRUPT_STACK_TOPS[slot as usize].load(Ordering::Relaxed) {
+        0 => boot_stack_top(slot as usize),
+        top => top,
+    }
+}
+
+/// Make the processor start the exceptions that use `slot` at `top`
+pub fn point_interrupt_stack(slot: u16, top: u64) {
+    // The processor only reads the slot, at every exception that uses it
+    unsafe {
+        let entry = (&raw const TSS.interrupt_stack_table[slot as usize]).cast_mut();
+
+        entry.write_unaligned(top);
+    }
+}
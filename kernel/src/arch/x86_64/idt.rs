@@ -1,9 +1,24 @@
-use core::arch::asm;
+use core::{
+    arch::{asm, global_asm},
+    fmt,
+};
 
 use bit_field::BitField;
-use lazy_static::lazy_static;
-
-use super::DescriptorTableRegister;
+use spin::Once;
+
+use super::{
+    DescriptorTableRegister, apic, extable, gdt, kcfi,
+    paging::{self as arch_paging, PageTableEntry},
+    pic, wakeup,
+};
+use crate::{
+    console::Hexdump,
+    irq::{self, Problem},
+    paging, ro_after_init, symbols,
+};
+
+/// Set in the error code of a page fault caused by a write
+const PAGE_FAULT_WRITE: u64 = 1 << 1;
 
 #[derive(Debug, PartialEq)]
 #[repr(C, align(16))]
@@ -71,6 +86,10 @@ impl EntryOptions {
         }
     }
 
+    pub fn is_present(&self) -> bool {
+        self.bits.get_bit(15)
+    }
+
     pub fn set_present(&mut self, value: bool) -> &mut Self {
         self.bits.set_bit(15, value);
         self
@@ -97,30 +116,90 @@ struct InterruptStackFrame {
     ss: u64,
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
+impl fmt::Display for InterruptStackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rip={:#018x}", self.rip)?;
+
+        if let Some((symbol, offset)) = symbols::find(self.rip) {
+            write!(f, " {}+{:#x}", symbol.name(), offset)?;
+        }
+
+        write!(
+            f,
+            "\ncs={:#x} rflags={:#018x} rsp={:#018x} ss={:#x}",
+            self.cs, self.rflags, self.rsp, self.ss
+        )
+    }
+}
+
+/// How many bytes of code at where a fault happened are shown when it panics
+const FAULT_CODE_LEN: usize = 16;
+
+/// The page table entry for an address that faulted, if it can be read
+struct FaultEntry(u64);
+
+impl fmt::Display for FaultEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match paging::try_read_entry(self.0) {
+            Some(entry) => write!(f, "{}", PageTableEntry(entry)),
+            None => write!(f, "no page table entry, or the page tables are busy"),
+        }
+    }
+}
+
+/// The handlers of every vector from the sixteens in the list, as rows of sixteen
+macro_rules! unhandled_handlers {
+    ($($high:literal)*) => {
+        [$(unhandled_handlers!(@row $high 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)),*]
+    };
+    (@row $high:literal $($low:literal)*) => {
+        [$(handle_unhandled::<{ $high * 16 + $low }> as extern "x86-interrupt" fn(_)),*]
+    };
+}
+
+ro_after_init! {
+    static IDT: Once<InterruptDescriptorTable> = Once::new();
+}
+
+fn idt() -> &'static InterruptDescriptorTable {
+    IDT.call_once(|| {
         let mut idt = InterruptDescriptorTable::empty();
 
         idt.table[0].set_handler_address(handle_division_error as usize as u64);
         idt.table[1].set_handler_address(handle_debug as usize as u64);
+
+        idt.table[2]
+            .set_handler_address(handle_non_maskable_interrupt as usize as u64)
+            .set_stack_index(gdt::NMI_STACK);
+
         idt.table[3].set_handler_address(handle_breakpoint as usize as u64);
         idt.table[4].set_handler_address(handle_overflow as usize as u64);
         idt.table[5].set_handler_address(handle_bound_range_exceeded as usize as u64);
-        idt.table[6].set_handler_address(handle_invalid_opcode as usize as u64);
+        idt.table[6].set_handler_address((&raw const invalid_opcode_entry) as u64);
         idt.table[7].set_handler_address(handle_device_not_available as usize as u64);
 
         idt.table[8]
             .set_handler_address(handle_double_fault as usize as u64)
-            .set_stack_index(0);
+            .set_stack_index(gdt::DOUBLE_FAULT_STACK);
 
         idt.table[10].set_handler_address(handle_segmentation_fault as usize as u64);
         idt.table[11].set_handler_address(handle_segmentation_fault as usize as u64);
         idt.table[12].set_handler_address(handle_segmentation_fault as usize as u64);
         idt.table[13].set_handler_address(handle_general_protection_fault as usize as u64);
-        idt.table[14].set_handler_address(handle_page_fault as usize as u64);
+
+        // A page fault in the handler would start over at the top of the same stack, it only
+        // touches memory that is mapped
+        idt.table[14]
+            .set_handler_address(handle_page_fault as usize as u64)
+            .set_stack_index(gdt::PAGE_FAULT_STACK);
+
         idt.table[16].set_handler_address(handle_x87_floating_point_exception as usize as u64);
         idt.table[17].set_handler_address(handle_alignment_check as usize as u64);
-        idt.table[18].set_handler_address(handle_machine_check as usize as u64);
+
+        idt.table[18]
+            .set_handler_address(handle_machine_check as usize as u64)
+            .set_stack_index(gdt::MACHINE_CHECK_STACK);
+
         idt.table[19].set_handler_address(handle_simd_floating_point_exception as usize as u64);
         idt.table[20].set_handler_address(handle_virtualization_exception as usize as u64);
         idt.table[21].set_handler_address(handle_control_protection_exception as usize as u64);
@@ -128,25 +207,84 @@ lazy_static! {
         idt.table[29].set_handler_address(handle_vmm_communication_exception as usize as u64);
         idt.table[30].set_handler_address(handle_security_exception as usize as u64);
 
+        let pic_vector = |irq: u8| pic::vector(irq) as usize;
+
+        idt.table[pic_vector(wakeup::PIT_IRQ)].set_handler_address(handle_pit as usize as u64);
+        idt.table[pic_vector(pic::SPURIOUS_MASTER)]
+            .set_handler_address(handle_pic_spurious_master as usize as u64);
+        idt.table[pic_vector(pic::SPURIOUS_SLAVE)]
+            .set_handler_address(handle_pic_spurious_slave as usize as u64);
+
+        idt.table[apic::WAKEUP_VECTOR as usize].set_handler_address(handle_wakeup as usize as u64);
+        idt.table[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_address(handle_spurious as usize as u64);
+
+        // Past the exceptions, what nothing handles is still handled to say which vector it was
+        let unhandled = unhandled_handlers!(2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+        for (vector, handler) in unhandled.iter().flatten().enumerate() {
+            let entry = &mut idt.table[32 + vector];
+
+            if !entry.options.is_present() {
+                entry.set_handler_address(*handler as usize as u64);
+            }
+        }
+
         idt
-    };
+    })
 }
 
 pub fn init() {
     unsafe {
-        asm!("lidt [{}]", in(reg) &IDT.register(), options(readonly, nostack, preserves_flags));
+        asm!("lidt [{}]", in(reg) &idt().register(), options(readonly, nostack, preserves_flags));
     }
 }
 
+/// Continue at the fixup of the faulting instruction, if it has one
+fn resume_at_fixup(frame: &mut InterruptStackFrame) -> bool {
+    let Some(fixup) = extable::fixup(frame.rip) else {
+        return false;
+    };
+
+    super::cet::move_return(frame.rip, fixup);
+
+    // The frame is the one the processor pushed, which it returns through
+    unsafe { (&raw mut frame.rip).write_volatile(fixup) };
+
+    true
+}
+
 extern "x86-interrupt" fn handle_division_error(_: InterruptStackFrame) {
     panic!("division error");
 }
 
-extern "x86-interrupt" fn handle_debug(_: InterruptStackFrame) {
+/// Whether the trap came from user mode and stopped a traced thread there
+fn trap_traced(frame: &InterruptStackFrame) -> bool {
+    frame.cs & 3 == 3
+        && crate::ptrace::trap(|registers| {
+            registers.rip = frame.rip;
+            registers.rflags = frame.rflags;
+            registers.rsp = frame.rsp;
+        })
+}
+
+extern "x86-interrupt" fn handle_debug(frame: InterruptStackFrame) {
+    if trap_traced(&frame) {
+        return;
+    }
+
     println!("debug");
 }
 
-extern "x86-interrupt" fn handle_breakpoint(_: InterruptStackFrame) {
+extern "x86-interrupt" fn handle_non_maskable_interrupt(_: InterruptStackFrame) {
+    super::nmi::handle();
+}
+
+extern "x86-interrupt" fn handle_breakpoint(frame: InterruptStackFrame) {
+    if trap_traced(&frame) {
+        return;
+    }
+
     println!("breakpoint");
 }
 
@@ -158,8 +296,76 @@ extern "x86-interrupt" fn handle_bound_range_exceeded(_: InterruptStackFrame) {
     panic!("bound range exceeded");
 }
 
-extern "x86-interrupt" fn handle_invalid_opcode(_: InterruptStackFrame) {
-    panic!("invalid opcode");
+// Saves every register before the handler, which needs the one a failed check of an indirect
+// call went through. Nothing returns from it, so neither does this
+global_asm!(
+    r#"
+    .pushsection .text.invalid_opcode, "ax"
+    .global invalid_opcode_entry
+invalid_opcode_entry:
+    endbr64
+    pushq %r15
+    pushq %r14
+    pushq %r13
+    pushq %r12
+    pushq %r11
+    pushq %r10
+    pushq %r9
+    pushq %r8
+    pushq %rdi
+    pushq %rsi
+    pushq %rbp
+    pushq %rsp
+    pushq %rbx
+    pushq %rdx
+    pushq %rcx
+    pushq %rax
+    movq %rsp, %rdi
+    subq $8, %rsp
+    call {handler}
+    ud2
+    .popsection
+    "#,
+    handler = sym handle_invalid_opcode,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static invalid_opcode_entry: u8;
+}
+
+/// The registers numbered like in instructions from `rax` to `r15`, then what the processor
+/// pushed
+#[repr(C)]
+struct SavedRegisters {
+    registers: [u64; 16],
+    frame: InterruptStackFrame,
+}
+
+impl fmt::Display for SavedRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (name, value)) in kcfi::REGISTERS.iter().zip(self.registers).enumerate() {
+            match index % 4 {
+                0 if index != 0 => writeln!(f)?,
+                0 => {}
+                _ => write!(f, " ")?,
+            }
+
+            write!(f, "{:<3}={:#018x}", name, value)?;
+        }
+
+        write!(f, "\n{}", self.frame)
+    }
+}
+
+extern "C" fn handle_invalid_opcode(saved: &SavedRegisters) -> ! {
+    kcfi::check(saved.frame.rip, &saved.registers);
+
+    panic!(
+        "invalid opcode\n{}\n{}",
+        saved,
+        Hexdump::memory(saved.frame.rip, FAULT_CODE_LEN)
+    );
 }
 
 extern "x86-interrupt" fn handle_device_not_available(_: InterruptStackFrame) {
@@ -174,12 +380,40 @@ extern "x86-interrupt" fn handle_segmentation_fault(_: InterruptStackFrame, code
     panic!("segmentation fault: {}", code);
 }
 
-extern "x86-interrupt" fn handle_general_protection_fault(_: InterruptStackFrame, code: u64) {
-    panic!("general protection fault: {}", code);
+extern "x86-interrupt" fn handle_general_protection_fault(
+    mut frame: InterruptStackFrame,
+    code: u64,
+) {
+    if resume_at_fixup(&mut frame) {
+        return;
+    }
+
+    panic!(
+        "general protection fault: {}\n{}\n{}",
+        code,
+        frame,
+        Hexdump::memory(frame.rip, FAULT_CODE_LEN)
+    );
 }
 
-extern "x86-interrupt" fn handle_page_fault(_: InterruptStackFrame, code: u64) {
-    panic!("page fault: {}", code);
+extern "x86-interrupt" fn handle_page_fault(mut frame: InterruptStackFrame, code: u64) {
+    let address = arch_paging::fault_address();
+
+    if crate::swap::handle_fault(address)
+        || crate::mmap::handle_fault(address, code & PAGE_FAULT_WRITE != 0)
+        || resume_at_fixup(&mut frame)
+    {
+        return;
+    }
+
+    panic!(
+        "page fault: {} at {:#x}\nentry={}\n{}\n{}",
+        code,
+        address,
+        FaultEntry(address),
+        frame,
+        Hexdump::memory(frame.rip, FAULT_CODE_LEN)
+    );
 }
 
 extern "x86-interrupt" fn handle_x87_floating_point_exception(_: InterruptStackFrame) {
@@ -191,7 +425,7 @@ extern "x86-interrupt" fn handle_alignment_check(_: InterruptStackFrame, code: u
 }
 
 extern "x86-interrupt" fn handle_machine_check(_: InterruptStackFrame) {
-    panic!("machine check");
+    super::mce::handle();
 }
 
 extern "x86-interrupt" fn handle_simd_floating_point_exception(_: InterruptStackFrame) {
@@ -203,7 +437,11 @@ extern "x86-interrupt" fn handle_virtualization_exception(_: InterruptStackFrame
 }
 
 extern "x86-interrupt" fn handle_control_protection_exception(_: InterruptStackFrame, code: u64) {
-    panic!("control protection exception: {}", code);
+    panic!(
+        "control protection exception: {}: {}",
+        code,
+        super::cet::describe(code)
+    );
 }
 
 extern "x86-interrupt" fn handle_hypervisor_injection_exception(_: InterruptStackFrame) {
@@ -217,3 +455,53 @@ extern "x86-interrupt" fn handle_vmm_communication_exception(_: InterruptStackFr
 extern "x86-interrupt" fn handle_security_exception(_: InterruptStackFrame, code: u64) {
     panic!("security exception: {}", code);
 }
+
+extern "x86-interrupt" fn handle_wakeup(_: InterruptStackFrame) {
+    irq::raised(apic::WAKEUP_VECTOR);
+    apic::eoi(apic::WAKEUP_VECTOR);
+}
+
+/// Spurious interrupts are not really delivered, so they must not be acknowledged
+extern "x86-interrupt" fn handle_spurious(_: InterruptStackFrame) {
+    irq::raised(apic::SPURIOUS_VECTOR);
+    irq::report(apic::SPURIOUS_VECTOR, Problem::Spurious);
+}
+
+extern "x86-interrupt" fn handle_pit(_: InterruptStackFrame) {
+    irq::raised(pic::vector(wakeup::PIT_IRQ));
+    wakeup::handle_pit();
+}
+
+/// Nothing is on the last line of either PIC, so what comes there is only acknowledged if it was
+/// really raised
+fn pic_spurious(line: u8) {
+    let vector = pic::vector(line);
+
+    irq::raised(vector);
+
+    if pic::is_real(line) {
+        irq::report(vector, Problem::Unhandled);
+        pic::eoi(line);
+    } else {
+        irq::report(vector, Problem::Spurious);
+    }
+}
+
+extern "x86-interrupt" fn handle_pic_spurious_master(_: InterruptStackFrame) {
+    pic_spurious(pic::SPURIOUS_MASTER);
+}
+
+extern "x86-interrupt" fn handle_pic_spurious_slave(_: InterruptStackFrame) {
+    pic_spurious(pic::SPURIOUS_SLAVE);
+}
+
+/// Raised on a vector with no handler, acknowledged if the local APIC delivered it so it does not
+/// hold back every vector below it
+extern "x86-interrupt" fn handle_unhandled<const VECTOR: u8>(_: InterruptStackFrame) {
+    irq::raised(VECTOR);
+    irq::report(VECTOR, Problem::Unhandled);
+
+    if apic::is_enabled() && apic::is_in_service(VECTOR) {
+        apic::eoi(VECTOR);
+    }
+}
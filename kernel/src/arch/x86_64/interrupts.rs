@@ -12,6 +12,19 @@ pub fn enable() {
     }
 }
 
+/// The interrupt flag in rflags
+const INTERRUPT_FLAG: u64 = 1 << 9;
+
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+
+    rflags & INTERRUPT_FLAG != 0
+}
+
 pub fn wait_for_interrupts() {
     unsafe {
         asm!("hlt");
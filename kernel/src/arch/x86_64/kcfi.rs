@@ -0,0 +1,119 @@
+//! Reporting the checks of indirect calls that failed, in kernels built with `-Z sanitizer=kcfi`
+//!
+//! The compiler puts a hash of the type of every function that can be called indirectly right
+//! before it, and before every indirect call compares it with the hash of the type the call is
+//! made through, executing `ud2` when they differ. Each of those `ud2` is listed in the
+//! `.kcfi_traps` section, so the invalid opcode handler can tell a failed check from any other
+//! and say where the call was, where it went and what the types were
+//!
+//! The check before the trap always looks the same, which is how the register the call goes
+//! through is found:
+//!
+//! ```text
+//! mov $-type, %r10d
+//! add -4(%target), %r10d
+//! je 1f
+//! ud2
+//! 1: call *%target
+//! ```
+
+use crate::paging;
+
+/// The names of the registers numbered like in instructions
+pub(super) const REGISTERS: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+/// The length of the check before the trap
+const CHECK_LEN: usize = 12;
+
+unsafe extern "C" {
+    static __kcfi_traps_start: i32;
+    static __kcfi_traps_end: i32;
+}
+
+/// Whether the `ud2` at `address` is the trap of a check
+fn is_trap(address: u64) -> bool {
+    let start = &raw const __kcfi_traps_start;
+    let end = &raw const __kcfi_traps_end;
+
+    let len = (end.addr() - start.addr()) / size_of::<i32>();
+
+    // Each entry is where its trap is relative to the entry itself, so the table needs no
+    // relocations
+    (0..len).any(|index| unsafe {
+        let entry = start.add(index);
+
+        entry.addr().wrapping_add_signed(entry.read() as isize) as u64 == address
+    })
+}
+
+/// The type the check before the trap at `address` expected, and the register the call went
+/// through
+fn decode(address: u64) -> Option<(u32, usize)> {
+    let check = unsafe {
+        core::slice::from_raw_parts((address as usize - CHECK_LEN) as *const u8, CHECK_LEN)
+    };
+
+    let &[
+        0x41,
+        0xBA,
+        t0,
+        t1,
+        t2,
+        t3,
+        rex @ (0x44 | 0x45),
+        0x03,
+        modrm,
+        0xFC,
+        0x74,
+        0x02,
+    ] = check
+    else {
+        return None;
+    };
+
+    // A displacement of one byte and r10 as the destination, anything else is not a check
+    if modrm >> 6 != 0b01 || (modrm >> 3) & 0b111 != 0b010 {
+        return None;
+    }
+
+    let register = (modrm & 0b111) as usize | ((rex & 1) as usize) << 3;
+
+    Some((
+        u32::from_le_bytes([t0, t1, t2, t3]).wrapping_neg(),
+        register,
+    ))
+}
+
+/// Panic with what the check that trapped at `address` found, if a check trapped there, with
+/// the registers numbered like in instructions from `rax` to `r15`
+pub fn check(address: u64, registers: &[u64; 16]) {
+    if !is_trap(address) {
+        return;
+    }
+
+    let Some((expected, register)) = decode(address) else {
+        panic!(
+            "kcfi: indirect call at {:#x} to a function of another type",
+            address
+        );
+    };
+
+    let target = registers[register];
+
+    // The target can be anything a bug left in the register
+    let found = match paging::translate(target.wrapping_sub(4)) {
+        Some(_) => unsafe { ((target - 4) as *const u32).read_unaligned() },
+        None => panic!(
+            "kcfi: indirect call at {:#x} through {} to {:#x}, which is not mapped",
+            address, REGISTERS[register], target
+        ),
+    };
+
+    panic!(
+        "kcfi: indirect call at {:#x} through {} to {:#x} of type {:#010x}, expected type {:#010x}",
+        address, REGISTERS[register], target, found, expected
+    );
+}
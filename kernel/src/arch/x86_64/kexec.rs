@@ -0,0 +1,182 @@
+//! Jumping into another kernel, from a copy of a trampoline that switches to page tables mapping
+//! physical memory one to one, copies the new kernel's segments into place and jumps to its entry
+
+use core::arch::{asm, global_asm};
+
+use super::{
+    cet,
+    paging::{PAGE_SIZE, PageTableFlags},
+    pcid,
+};
+use crate::paging;
+
+/// What one huge page in the level 2 tables maps
+const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// One level 3 table is all there is, so nothing above this can be mapped
+pub const IDENTITY_LIMIT: u64 = 512 * GIB;
+
+const STACK_SIZE: u64 = 16 * 1024;
+
+/// The trampoline's page, then the arguments and segments' page, then the stack, then the tables
+const ARGUMENTS_OFFSET: u64 = PAGE_SIZE;
+const STACK_OFFSET: u64 = 2 * PAGE_SIZE;
+const TABLES_OFFSET: u64 = STACK_OFFSET + STACK_SIZE;
+
+/// As many segments as fit in the page after the arguments
+pub const MAX_SEGMENTS: usize =
+    (PAGE_SIZE as usize - size_of::<Arguments>()) / size_of::<Segment>();
+
+global_asm!(
+    r#"
+    .pushsection .text.kexec, "ax"
+    .global kexec_start
+kexec_start:
+    /* The arguments are in rdi, which the copies need */
+    movq %rdi, %r8
+    movq 0(%r8), %rax
+    movq %rax, %cr3
+    movq 8(%r8), %rsp
+    movq 16(%r8), %r9
+    movq 24(%r8), %r10
+
+1:
+    testq %r10, %r10
+    jz 2f
+    movq 0(%r9), %rdi
+    movq 8(%r9), %rsi
+    movq 16(%r9), %rcx
+    rep movsb
+    movq 24(%r9), %rcx
+    xorl %eax, %eax
+    rep stosb
+    addq $32, %r9
+    decq %r10
+    jmp 1b
+
+2:
+    movq 32(%r8), %rax
+    movq 40(%r8), %rdi
+    xorl %ebp, %ebp
+    jmp *%rax
+    .global kexec_end
+kexec_end:
+    .popsection
+"#,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static kexec_start: u8;
+    static kexec_end: u8;
+}
+
+/// Where to copy a part of the new kernel to, and how much to clear after it, all physical
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Segment {
+    pub destination: u64,
+    pub source: u64,
+    pub copy_len: u64,
+    pub zero_len: u64,
+}
+
+/// What the trampoline reads, where it expects it
+#[repr(C)]
+struct Arguments {
+    root: u64,
+    stack: u64,
+    segments: u64,
+    segment_count: u64,
+    entry: u64,
+    argument: u64,
+}
+
+/// The memory the trampoline needs to map physical memory up to `top`
+pub fn scratch_size(top: u64) -> u64 {
+    TABLES_OFFSET + (2 + top.div_ceil(GIB)) * PAGE_SIZE
+}
+
+/// Copy `segments` into place and jump to `entry` with `argument` in `rdi`, from `scratch` which
+/// is at least [`scratch_size`] of page aligned physical memory that no segment overlaps
+///
+/// Physical memory up to `top`, which has to cover the scratch memory and every segment, is mapped
+/// one to one once the new kernel runs, and interrupts must already be disabled
+pub fn jump(scratch: u64, segments: &[Segment], entry: u64, argument: u64, top: u64) -> ! {
+    assert!(segments.len() <= MAX_SEGMENTS && top <= IDENTITY_LIMIT);
+
+    let len = (&raw const kexec_end) as usize - (&raw const kexec_start) as usize;
+    let write = |offset: u64, value: u64| unsafe {
+        (paging::virt_from_phys(scratch + offset) as *mut u64).write(value);
+    };
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            &raw const kexec_start,
+            paging::virt_from_phys(scratch) as *mut u8,
+            len,
+        );
+    }
+
+    // A level 4 and a level 3 table, then one level 2 table of huge pages for each GiB
+    let level4 = scratch + TABLES_OFFSET;
+    let level3 = level4 + PAGE_SIZE;
+    let table_flags = (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits();
+    let page_flags = table_flags | PageTableFlags::HUGE_PAGE.bits();
+
+    write(TABLES_OFFSET, level3 | table_flags);
+
+    for gib in 0..top.div_ceil(GIB) {
+        let level2 = level3 + (gib + 1) * PAGE_SIZE;
+
+        write(level3 - scratch + gib * 8, level2 | table_flags);
+
+        for index in 0..512 {
+            write(
+                level2 - scratch + index * 8,
+                (gib * GIB + index * HUGE_PAGE_SIZE) | page_flags,
+            );
+        }
+    }
+
+    let arguments = scratch + ARGUMENTS_OFFSET;
+    let segments_address = arguments + size_of::<Arguments>() as u64;
+
+    for (index, segment) in segments.iter().enumerate() {
+        let offset = segments_address - scratch + (index * size_of::<Segment>()) as u64;
+
+        write(offset, segment.destination);
+        write(offset + 8, segment.source);
+        write(offset + 16, segment.copy_len);
+        write(offset + 24, segment.zero_len);
+    }
+
+    unsafe {
+        (paging::virt_from_phys(arguments) as *mut Arguments).write(Arguments {
+            root: level4,
+            stack: scratch + STACK_OFFSET + STACK_SIZE,
+            segments: segments_address,
+            segment_count: segments.len() as u64,
+            entry,
+            argument,
+        });
+    }
+
+    // The new kernel may not expect its entries to be tagged, or shadow stacks
+    pcid::disable();
+    cet::disable();
+
+    // The trampoline keeps running from its copy once it switches to the new tables
+    paging::map_identity(scratch, PAGE_SIZE as usize, PageTableFlags::empty());
+
+    unsafe {
+        asm!(
+            "jmp {}",
+            in(reg) scratch,
+            in("rdi") arguments,
+            options(noreturn)
+        );
+    }
+}
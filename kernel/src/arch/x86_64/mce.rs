@@ -0,0 +1,294 @@
+//! Machine checks, the processor's reports of errors in itself, its caches, its buses and memory
+//!
+//! Each bank of the machine check architecture logs the errors of one part of the hardware.
+//! Errors that were not corrected raise a machine check exception, which prints what the banks
+//! logged and panics, as nothing can be recovered yet. Corrected errors raise nothing, so the banks
+//! are polled for them and what they logged is printed and cleared, a machine with memory going
+//! bad says so long before it crashes
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use super::cpu;
+use crate::{time::NANOSECONDS_PER_SECOND, timer};
+
+const MSR_MCG_CAP: u32 = 0x179;
+const MSR_MCG_STATUS: u32 = 0x17A;
+const MSR_MCG_CTL: u32 = 0x17B;
+/// Where the MSRs of the banks start, the control, status, address and miscellaneous registers
+/// of each one after another
+const MSR_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xFF;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+/// The interrupted instruction can be restarted
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+
+/// The bank logged an error
+const STATUS_VAL: u64 = 1 << 63;
+/// Another error came before this one was cleared, and was lost
+const STATUS_OVER: u64 = 1 << 62;
+/// The error was not corrected
+const STATUS_UC: u64 = 1 << 61;
+/// The error raised a machine check exception
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+/// The processor may have been left corrupted by the error
+const STATUS_PCC: u64 = 1 << 57;
+
+const CR4_MCE: u64 = 1 << 6;
+
+const POLL_INTERVAL_NS: u64 = 5 * NANOSECONDS_PER_SECOND;
+
+static BANKS: AtomicU32 = AtomicU32::new(0);
+
+static CORRECTED: AtomicU64 = AtomicU64::new(0);
+
+fn msr(bank: u32, register: u32) -> u32 {
+    MSR_MC0_CTL + bank * 4 + register
+}
+
+fn status_msr(bank: u32) -> u32 {
+    msr(bank, 1)
+}
+
+/// The architectural part of the status of a bank, what kind of error it logged
+struct ErrorCode(u16);
+
+fn level(code: u16) -> &'static str {
+    ["level 0", "level 1", "level 2", "generic level"][(code & 0x3) as usize]
+}
+
+fn transaction(code: u16) -> &'static str {
+    ["instruction", "data", "generic", "reserved"][((code >> 2) & 0x3) as usize]
+}
+
+fn request(code: u16) -> &'static str {
+    match (code >> 4) & 0xF {
+        0 => "generic",
+        1 => "read",
+        2 => "write",
+        3 => "data read",
+        4 => "data write",
+        5 => "instruction fetch",
+        6 => "prefetch",
+        7 => "eviction",
+        8 => "snoop",
+        _ => "reserved",
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Whether corrected errors are filtered out of the interrupt for them, not what they are
+        let code = self.0 & !0x1000;
+
+        match code {
+            0x0000 => write!(f, "no error"),
+            0x0001 => write!(f, "unclassified error"),
+            0x0002 => write!(f, "microcode ROM parity error"),
+            0x0003 => write!(f, "external error"),
+            0x0004 => write!(f, "functional redundancy check error"),
+            0x0005 => write!(f, "internal parity error"),
+            0x0006 => write!(f, "SMM handler code access violation"),
+            0x0400 => write!(f, "internal timer error"),
+            _ if code & 0xFC00 == 0x0400 => write!(f, "internal unclassified error"),
+            _ if code & 0xF800 == 0x0800 => {
+                let participation = ["source", "responder", "observer", "generic"];
+                let space = ["memory", "reserved", "I/O", "other"];
+
+                write!(
+                    f,
+                    "bus error, {} {} of {} as {}{}",
+                    level(code),
+                    request(code),
+                    space[((code >> 2) & 0x3) as usize],
+                    participation[((code >> 9) & 0x3) as usize],
+                    if code & (1 << 8) != 0 {
+                        ", timed out"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            _ if code & 0xFF00 == 0x0100 => write!(
+                f,
+                "{} cache error, {} {}",
+                level(code),
+                transaction(code),
+                request(code)
+            ),
+            _ if code & 0xFF80 == 0x0080 => {
+                let operation = match (code >> 4) & 0x7 {
+                    0 => "generic",
+                    1 => "read",
+                    2 => "write",
+                    3 => "address or command",
+                    4 => "scrubbing",
+                    _ => "reserved",
+                };
+
+                match code & 0xF {
+                    0xF => write!(f, "memory controller {} error", operation),
+                    channel => write!(
+                        f,
+                        "memory controller {} error on channel {}",
+                        operation, channel
+                    ),
+                }
+            }
+            _ if code & 0xFFF0 == 0x0010 => {
+                write!(f, "{} TLB error, {}", level(code), transaction(code))
+            }
+            _ if code & 0xFFFC == 0x000C => write!(f, "{} cache hierarchy error", level(code)),
+            _ => write!(f, "unknown error {:#06x}", code),
+        }
+    }
+}
+
+/// Print what `bank` logged in `status`
+fn report(bank: u32, status: u64) {
+    println!(
+        "mce: bank {}: {} {}{}, status {:#018x}",
+        bank,
+        if status & STATUS_UC != 0 {
+            "uncorrected"
+        } else {
+            "corrected"
+        },
+        ErrorCode(status as u16),
+        if status & STATUS_OVER != 0 {
+            ", earlier errors lost"
+        } else {
+            ""
+        },
+        status
+    );
+
+    if status & STATUS_ADDRV != 0 {
+        println!(
+            "mce: bank {}: address {:#x}",
+            bank,
+            cpu::rdmsr(msr(bank, 2))
+        );
+    }
+
+    if status & STATUS_MISCV != 0 {
+        println!("mce: bank {}: misc {:#x}", bank, cpu::rdmsr(msr(bank, 3)));
+    }
+}
+
+/// Called on a machine check exception, panics unless every error can be gone on from
+pub fn handle() {
+    // Without banks there is nothing to say what went wrong or that it can be gone on from
+    if BANKS.load(Ordering::Relaxed) == 0 {
+        panic!("machine check");
+    }
+
+    let mut fatal = cpu::rdmsr(MSR_MCG_STATUS) & MCG_STATUS_RIPV == 0;
+
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        let status = cpu::rdmsr(status_msr(bank));
+
+        if status & STATUS_VAL == 0 {
+            continue;
+        }
+
+        report(bank, status);
+
+        // Nothing takes a page with an error out of use or kills what ran into it yet
+        if status & STATUS_PCC != 0 || status & (STATUS_UC | STATUS_EN) == STATUS_UC | STATUS_EN {
+            fatal = true;
+        }
+    }
+
+    if fatal {
+        panic!("machine check: uncorrected hardware error");
+    }
+
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        cpu::wrmsr(status_msr(bank), 0);
+    }
+
+    // Clears the machine check in progress, another one while it was set would shut down
+    cpu::wrmsr(MSR_MCG_STATUS, 0);
+}
+
+/// Print and clear the errors the banks logged without raising an exception
+fn poll() {
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        let status = cpu::rdmsr(status_msr(bank));
+
+        // One that raised an exception is left to the handler
+        if status & STATUS_VAL == 0 || status & STATUS_EN != 0 && status & STATUS_UC != 0 {
+            continue;
+        }
+
+        report(bank, status);
+        cpu::wrmsr(status_msr(bank), 0);
+
+        CORRECTED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn poll_periodically() {
+    poll();
+
+    timer::add(POLL_INTERVAL_NS, poll_periodically);
+}
+
+/// How many errors the banks logged that did not raise an exception
+pub fn corrected_errors() -> u64 {
+    CORRECTED.load(Ordering::Relaxed)
+}
+
+/// Turn on reporting in every bank and then machine check exceptions, printing what the banks
+/// still hold from before, which is often why the machine was reset
+pub fn init() {
+    if !cpu::has_mce() {
+        println!("mce: not supported");
+        return;
+    }
+
+    if cpu::has_mca() {
+        let capabilities = cpu::rdmsr(MSR_MCG_CAP);
+        let banks = (capabilities & MCG_CAP_COUNT) as u32;
+
+        if capabilities & MCG_CAP_CTL_P != 0 {
+            cpu::wrmsr(MSR_MCG_CTL, u64::MAX);
+        }
+
+        for bank in 0..banks {
+            let status = cpu::rdmsr(status_msr(bank));
+
+            if status & STATUS_VAL != 0 {
+                println!("mce: bank {} holds an error from before boot", bank);
+                report(bank, status);
+            }
+
+            // The first bank of older Intel processors is set up by the firmware only
+            if bank != 0 || !cpu::is_intel() || cpu::family() != 6 {
+                cpu::wrmsr(msr(bank, 0), u64::MAX);
+            }
+
+            cpu::wrmsr(status_msr(bank), 0);
+        }
+
+        BANKS.store(banks, Ordering::Relaxed);
+
+        println!("mce: {} banks", banks);
+    }
+
+    cpu::write_cr4(cpu::read_cr4() | CR4_MCE);
+}
+
+/// Start polling the banks for corrected errors, once there are timers
+pub fn init_polling() {
+    if BANKS.load(Ordering::Relaxed) != 0 {
+        timer::add(POLL_INTERVAL_NS, poll_periodically);
+    }
+}
@@ -0,0 +1,243 @@
+//! The revision of the processor's microcode, and loading a newer one from the bootloader
+//!
+//! The revision is printed at boot, as errata are fixed by revision. With
+//! `microcode=<path>` the bootloader module at that path is searched for updates in Intel's
+//! format, one after another like the files of intel-ucode put together, and the newest one for
+//! this processor is loaded if it is newer than what the firmware loaded. That is done before
+//! anything else is set up, and again after waking up from sleep which loses it
+//!
+//! Only the boot processor runs, so it is the only one updated. AMD's container format is not
+//! read yet
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::cpu;
+use crate::{cmdline, requests::MODULE_REQUEST};
+
+/// The revision of the loaded microcode on Intel, the patch level on AMD
+const MSR_BIOS_SIGN_ID: u32 = 0x8B;
+/// Loads the update at the address written to it
+const MSR_BIOS_UPDT_TRIG: u32 = 0x79;
+/// Which of the platforms an update can be for this one is, in bits 50 to 52
+const MSR_PLATFORM_ID: u32 = 0x17;
+
+const HEADER_SIZE: usize = 48;
+/// What the sizes in the header mean when they are zero, for the oldest updates
+const DEFAULT_DATA_SIZE: usize = 2000;
+const DEFAULT_TOTAL_SIZE: usize = 2048;
+
+/// How big the header of the table of other processors an update is for is, and each entry in it
+const EXTENDED_HEADER_SIZE: usize = 20;
+const EXTENDED_SIGNATURE_SIZE: usize = 12;
+
+/// Where the update that was loaded starts, zero if none was, and its revision
+static LOADED: AtomicU64 = AtomicU64::new(0);
+static LOADED_REVISION: AtomicU32 = AtomicU32::new(0);
+
+/// The revision of the microcode running now, zero if it can not be read like under some
+/// hypervisors
+pub fn revision() -> u32 {
+    if !cpu::is_intel() {
+        return cpu::rdmsr_safe(MSR_BIOS_SIGN_ID).unwrap_or(0) as u32;
+    }
+
+    // The register is only filled in by cpuid, after it was cleared
+    cpu::wrmsr_safe(MSR_BIOS_SIGN_ID, 0);
+    cpu::cpuid(1, 0);
+
+    (cpu::rdmsr_safe(MSR_BIOS_SIGN_ID).unwrap_or(0) >> 32) as u32
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// An update in Intel's format, with the header
+struct Update<'a> {
+    bytes: &'a [u8],
+    revision: u32,
+}
+
+impl Update<'_> {
+    fn data_size(&self) -> usize {
+        match read_u32(self.bytes, 28) {
+            Some(0) | None => DEFAULT_DATA_SIZE,
+            Some(size) => size as usize,
+        }
+    }
+
+    /// The words of the update, and of the table after it if there is one, each add up to zero
+    fn is_intact(&self) -> bool {
+        let sum = |bytes: &[u8]| {
+            bytes.as_chunks::<4>().0.iter().fold(0u32, |sum, word| {
+                sum.wrapping_add(u32::from_le_bytes(*word))
+            })
+        };
+
+        let end = HEADER_SIZE + self.data_size();
+        let Some(count) = read_u32(self.bytes, end) else {
+            return sum(&self.bytes[..end]) == 0;
+        };
+
+        let extended = &self.bytes[end..];
+        let extended_size = EXTENDED_HEADER_SIZE + count as usize * EXTENDED_SIGNATURE_SIZE;
+
+        sum(&self.bytes[..end]) == 0
+            && extended.len() >= extended_size
+            && sum(&extended[..extended_size]) == 0
+    }
+
+    /// Whether it is for the processor with `signature` on the platforms in `platform`
+    fn is_for(&self, signature: u32, platform: u32) -> bool {
+        let matches = |at: usize| {
+            read_u32(self.bytes, at) == Some(signature)
+                && read_u32(self.bytes, at + 8).is_some_and(|flags| flags & platform != 0)
+        };
+
+        if matches(12) {
+            return true;
+        }
+
+        let extended = HEADER_SIZE + self.data_size();
+        let count = read_u32(self.bytes, extended).unwrap_or(0) as usize;
+
+        (0..count).any(|index| {
+            let entry = extended + EXTENDED_HEADER_SIZE + index * EXTENDED_SIGNATURE_SIZE;
+
+            read_u32(self.bytes, entry) == Some(signature)
+                && read_u32(self.bytes, entry + 4).is_some_and(|flags| flags & platform != 0)
+        })
+    }
+}
+
+/// The updates one after another in `blob`, stopping at the first that does not make sense
+fn updates(blob: &[u8]) -> impl Iterator<Item = Update<'_>> {
+    let mut rest = blob;
+
+    core::iter::from_fn(move || {
+        let version = read_u32(rest, 0)?;
+        let loader = read_u32(rest, 20)?;
+
+        if version != 1 || loader != 1 {
+            return None;
+        }
+
+        let total = match read_u32(rest, 32)? {
+            0 => DEFAULT_TOTAL_SIZE,
+            size => size as usize,
+        };
+
+        if total < HEADER_SIZE || total > rest.len() || !total.is_multiple_of(4) {
+            return None;
+        }
+
+        let (bytes, next) = rest.split_at(total);
+
+        rest = next;
+
+        Some(Update {
+            bytes,
+            revision: read_u32(bytes, 4)?,
+        })
+    })
+    .filter(|update| HEADER_SIZE + update.data_size() <= update.bytes.len())
+}
+
+/// Load the update starting at `address`, returns whether the revision changed
+fn apply(address: u64) -> bool {
+    let before = revision();
+
+    // The data after the header, which the processor checks itself
+    cpu::wrmsr_safe(MSR_BIOS_UPDT_TRIG, address + HEADER_SIZE as u64) && revision() != before
+}
+
+/// Load the newest update in the module `path` for this processor, if it is newer
+fn load(path: &str) {
+    let Some(module) = MODULE_REQUEST.get_response().and_then(|response| {
+        response
+            .modules()
+            .iter()
+            .find(|module| module.path().to_bytes() == path.as_bytes())
+    }) else {
+        println!("microcode: no module at {}", path);
+        return;
+    };
+
+    if !cpu::is_intel() {
+        println!("microcode: only Intel updates can be loaded");
+        return;
+    }
+
+    let blob = unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) };
+
+    let signature = cpu::cpuid(1, 0).eax;
+    let platform = 1 << ((cpu::rdmsr(MSR_PLATFORM_ID) >> 50) & 0x7);
+    let current = revision();
+
+    let Some(update) = updates(blob)
+        .filter(|update| update.is_for(signature, platform) && update.is_intact())
+        .max_by_key(|update| update.revision)
+    else {
+        println!(
+            "microcode: no update in {} for signature {:#x}",
+            path, signature
+        );
+        return;
+    };
+
+    if update.revision <= current {
+        println!(
+            "microcode: {} has revision {:#x}, not newer than {:#x}",
+            path, update.revision, current
+        );
+        return;
+    }
+
+    // The update has to start on 16 bytes, the bootloader puts modules on pages
+    let address = update.bytes.as_ptr() as u64;
+
+    if !(address + HEADER_SIZE as u64).is_multiple_of(16) {
+        println!("microcode: the update in {} is not aligned", path);
+        return;
+    }
+
+    if apply(address) {
+        LOADED.store(address, Ordering::Relaxed);
+        LOADED_REVISION.store(revision(), Ordering::Relaxed);
+
+        println!(
+            "microcode: updated from {:#x} to {:#x}",
+            current,
+            revision()
+        );
+    } else {
+        println!(
+            "microcode: the processor refused revision {:#x}",
+            update.revision
+        );
+    }
+}
+
+/// Print the revision, and load a newer one from the module given with `microcode=<path>`
+pub fn init() {
+    println!("microcode: revision {:#x}", revision());
+
+    if let Some(path) = cmdline::value("microcode") {
+        load(path);
+    }
+}
+
+/// Load the update again after waking up from sleep, which went back to the firmware's
+pub fn resume() {
+    let address = LOADED.load(Ordering::Relaxed);
+
+    if address == 0 || revision() == LOADED_REVISION.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if !apply(address) {
+        println!("microcode: could not load the update again");
+    }
+}
@@ -1,7 +1,32 @@
+pub mod apic;
+pub mod backtrace;
+pub mod cet;
+pub mod context;
+pub mod cpu;
+pub mod cstate;
+pub mod earlycon;
+pub mod extable;
 pub mod gdt;
 pub mod idt;
 pub mod interrupts;
+pub mod kcfi;
+pub mod kexec;
+pub mod mce;
+pub mod microcode;
+pub mod nmi;
+pub mod paging;
+pub mod pcid;
+pub mod pic;
+pub mod pit;
+pub mod port;
+pub mod power;
+pub mod registers;
+pub mod serial;
+pub mod speaker;
+pub mod suspend;
 pub mod tss;
+pub mod vdso;
+pub mod wakeup;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed(2))]
@@ -10,7 +35,77 @@ struct DescriptorTableRegister {
     address: u64,
 }
 
+pub use power::reboot;
+
 pub fn init() {
     gdt::init();
     idt::init();
+    pic::init();
+    microcode::init();
+    mce::init();
+    pcid::init();
+}
+
+pub struct X86_64;
+
+impl super::Architecture for X86_64 {
+    type Context = context::Context;
+
+    type TrapFrame = registers::UserRegisters;
+
+    const PAGE_SIZE: u64 = paging::PAGE_SIZE;
+
+    fn init() {
+        init();
+    }
+
+    fn init_late() {
+        gdt::init_interrupt_stacks();
+        cet::init();
+        mce::init_polling();
+    }
+
+    fn reboot() -> ! {
+        reboot()
+    }
+
+    fn disable_interrupts() {
+        interrupts::disable();
+    }
+
+    fn enable_interrupts() {
+        interrupts::enable();
+    }
+
+    fn interrupts_enabled() -> bool {
+        interrupts::are_enabled()
+    }
+
+    fn wait_for_interrupts() {
+        interrupts::wait_for_interrupts();
+    }
+
+    fn root_table() -> u64 {
+        paging::root_table()
+    }
+
+    fn flush(virt: u64) {
+        paging::flush(virt);
+    }
+
+    fn translate(root: u64, virt: u64, phys_to_virt: impl Fn(u64) -> u64) -> Option<u64> {
+        paging::translate(root, virt, phys_to_virt)
+    }
+
+    fn fault_address() -> u64 {
+        paging::fault_address()
+    }
+
+    fn counter() -> u64 {
+        cpu::rdtsc()
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        cpu::tsc_frequency().map(|(frequency, _)| frequency)
+    }
 }
@@ -0,0 +1,223 @@
+//! Non-maskable interrupts, and the handlers that find out what raised them
+//!
+//! Nothing says where an NMI came from, so every handler registered for the source it could have
+//! come from is asked in turn: first the watchdog and the performance counters, then the errors
+//! the chipset reports on system control port B, then the handlers of unknown NMIs. Handlers run
+//! with everything else interrupted, so they must not take locks or allocate
+//!
+//! NMIs are blocked until the handler returns, unless something in it returns from an exception
+//! first. One that comes then is started further down the NMI stack instead of over the frame of
+//! the one being handled, and only marks that there was another, which is handled after it
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU64, Ordering};
+
+use super::{cet, gdt, port};
+use crate::sync::Mutex;
+
+const MAX_HANDLERS: usize = 16;
+
+/// The port the chipset reports memory parity and I/O channel errors on, the one the PIT's
+/// channel 2 is gated through
+const SYSTEM_CONTROL_B: u16 = 0x61;
+
+/// A parity error on memory or a device on the bus signalled a system error
+const SERR: u8 = 1 << 7;
+/// A device on the bus reported an error on its channel
+const IOCHK: u8 = 1 << 6;
+/// Masks and clears each of them
+const SERR_DISABLE: u8 = 1 << 2;
+const IOCHK_DISABLE: u8 = 1 << 3;
+
+/// How much of the NMI stack is left for the one being handled before where a nested one starts
+const NESTED_OFFSET: u64 = 12 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The hard lockup detector
+    Watchdog,
+    /// The performance counters, like those of the profiler
+    Perf,
+    /// Asked when nothing else raised it
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    TooManyHandlers,
+    NotRegistered,
+}
+
+/// Returns whether it found that its source raised the NMI
+pub type Handler = fn() -> bool;
+
+/// A registered handler, read without a lock from the NMI
+struct Slot {
+    /// One more than the [`Source`] it is asked for, zero when the slot is free
+    source: AtomicU8,
+    handler: AtomicPtr<()>,
+}
+
+static SLOTS: [Slot; MAX_HANDLERS] = [const {
+    Slot {
+        source: AtomicU8::new(0),
+        handler: AtomicPtr::new(core::ptr::null_mut()),
+    }
+}; MAX_HANDLERS];
+
+/// Taken by those registering, which the NMI does not
+static REGISTERING: Mutex<()> = Mutex::new(());
+
+static IN_NMI: AtomicBool = AtomicBool::new(false);
+/// Another NMI came while one was being handled
+static LATCHED: AtomicBool = AtomicBool::new(false);
+
+static COUNTS: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+static EXTERNAL: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// Ask `handler` about NMIs that could have come from `source`
+pub fn register(source: Source, handler: Handler) -> Result<(), Error> {
+    let _registering = REGISTERING.lock();
+
+    let slot = SLOTS
+        .iter()
+        .find(|slot| slot.source.load(Ordering::Relaxed) == 0)
+        .ok_or(Error::TooManyHandlers)?;
+
+    slot.handler
+        .store(handler as *const () as *mut (), Ordering::Relaxed);
+    slot.source.store(source as u8 + 1, Ordering::Release);
+
+    Ok(())
+}
+
+/// Stop asking `handler`, an NMI that already started may still ask it
+pub fn unregister(handler: Handler) -> Result<(), Error> {
+    let _registering = REGISTERING.lock();
+
+    let slot = SLOTS
+        .iter()
+        .find(|slot| {
+            slot.source.load(Ordering::Relaxed) != 0
+                && core::ptr::eq(slot.handler.load(Ordering::Relaxed), handler as *const ())
+        })
+        .ok_or(Error::NotRegistered)?;
+
+    slot.source.store(0, Ordering::Release);
+
+    Ok(())
+}
+
+/// Ask every handler of `source`, returns whether any of them raised it
+fn ask(source: Source) -> bool {
+    let mut handled = false;
+
+    for slot in &SLOTS {
+        if slot.source.load(Ordering::Acquire) != source as u8 + 1 {
+            continue;
+        }
+
+        let handler = slot.handler.load(Ordering::Relaxed);
+        let handler = unsafe { core::mem::transmute::<*mut (), Handler>(handler) };
+
+        // Every handler is asked, more than one source can raise the same NMI
+        handled |= handler();
+    }
+
+    if handled {
+        COUNTS[source as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    handled
+}
+
+/// Report and clear the errors of system control port B, returns whether there were any
+fn external() -> bool {
+    let reason = port::inb(SYSTEM_CONTROL_B);
+
+    if reason & (SERR | IOCHK) == 0 {
+        return false;
+    }
+
+    if reason & SERR != 0 {
+        crate::println_nowait!("nmi: system error, memory parity or a device on the bus");
+    }
+
+    if reason & IOCHK != 0 {
+        crate::println_nowait!("nmi: i/o channel check, a device on the bus reported an error");
+    }
+
+    // Only the low bits can be written, and setting the disable bits clears the errors
+    let control = reason & 0x0F;
+
+    port::outb(SYSTEM_CONTROL_B, control | SERR_DISABLE | IOCHK_DISABLE);
+    port::outb(SYSTEM_CONTROL_B, control & !(SERR_DISABLE | IOCHK_DISABLE));
+
+    EXTERNAL.fetch_add(1, Ordering::Relaxed);
+
+    true
+}
+
+fn dispatch() {
+    // The watchdog and the counters are asked both, they can overflow together
+    let local = ask(Source::Watchdog) | ask(Source::Perf);
+
+    if local || external() || ask(Source::Unknown) {
+        return;
+    }
+
+    UNHANDLED.fetch_add(1, Ordering::Relaxed);
+
+    crate::log_ratelimited!("nmi: unknown source, ignoring it");
+}
+
+/// Called on every NMI
+pub fn handle() {
+    if IN_NMI.swap(true, Ordering::Acquire) {
+        LATCHED.store(true, Ordering::Relaxed);
+        return;
+    }
+
+    let top = gdt::interrupt_stack_top(gdt::NMI_STACK);
+
+    gdt::point_interrupt_stack(gdt::NMI_STACK, top - NESTED_OFFSET);
+    cet::point_nmi_shadow_stack(true);
+
+    loop {
+        dispatch();
+
+        if !LATCHED.swap(false, Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    // One that comes between the two starts where a nested one would, but is handled in full
+    IN_NMI.store(false, Ordering::Release);
+    gdt::point_interrupt_stack(gdt::NMI_STACK, top);
+    cet::point_nmi_shadow_stack(false);
+}
+
+/// How many NMIs were raised by what
+#[derive(Debug, Clone, Copy)]
+pub struct Counts {
+    pub watchdog: u64,
+    pub perf: u64,
+    /// Those only a handler of unknown NMIs took
+    pub unknown: u64,
+    /// Those of errors on system control port B
+    pub external: u64,
+    /// Those nothing took
+    pub unhandled: u64,
+}
+
+pub fn counts() -> Counts {
+    let count = |source: Source| COUNTS[source as usize].load(Ordering::Relaxed);
+
+    Counts {
+        watchdog: count(Source::Watchdog),
+        perf: count(Source::Perf),
+        unknown: count(Source::Unknown),
+        external: EXTERNAL.load(Ordering::Relaxed),
+        unhandled: UNHANDLED.load(Ordering::Relaxed),
+    }
+}
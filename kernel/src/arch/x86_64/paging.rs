@@ -0,0 +1,313 @@
+use core::{arch::asm, fmt};
+
+use bitflags::bitflags;
+
+bitflags! {
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct PageTableFlags: u64 {
+        const PRESENT           = 1;
+        const WRITABLE          = 1 << 1;
+        const USER_ACCESSIBLE   = 1 << 2;
+        const WRITE_THROUGH     = 1 << 3;
+        const NO_CACHE          = 1 << 4;
+        const ACCESSED          = 1 << 5;
+        const DIRTY             = 1 << 6;
+        /// Set in a level 3 or 2 entry to map a 1 GiB or 2 MiB page instead of pointing to a table
+        const HUGE_PAGE         = 1 << 7;
+        const GLOBAL            = 1 << 8;
+        const NO_EXECUTE        = 1 << 63;
+    }
+}
+
+pub const PAGE_SIZE: u64 = 4096;
+
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The value of a page table entry, displayed with the address it maps and its flags decoded
+#[derive(Debug, Clone, Copy)]
+pub struct PageTableEntry(pub u64);
+
+impl fmt::Display for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = PageTableFlags::from_bits_truncate(self.0);
+
+        write!(f, "{:#018x}", self.0)?;
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return write!(f, " not present");
+        }
+
+        write!(f, " -> {:#x}", self.0 & ADDRESS_MASK)?;
+
+        // Lowercased a character at a time, as this is printed while panicking
+        for (name, _) in flags.iter_names() {
+            write!(f, " ")?;
+
+            for character in name.chars() {
+                write!(f, "{}", character.to_ascii_lowercase())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The physical address of the level 4 table in use
+pub fn root_table() -> u64 {
+    let cr3: u64;
+
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+
+    cr3 & ADDRESS_MASK
+}
+
+/// The address the last page fault was for
+pub fn fault_address() -> u64 {
+    let cr2: u64;
+
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+
+    cr2
+}
+
+pub fn flush(virt: u64) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+    }
+}
+
+fn index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) & 0x1FF) as usize
+}
+
+/// Map the 4 KiB page at `virt` to `phys`, returns false if `virt` was already mapped
+///
+/// Page tables are reached through `phys_to_virt`, and missing ones are created by `alloc_table`
+/// which must return the physical address of a zeroed page
+pub fn map(
+    root: u64,
+    virt: u64,
+    phys: u64,
+    flags: PageTableFlags,
+    phys_to_virt: impl Fn(u64) -> u64,
+    mut alloc_table: impl FnMut() -> u64,
+) -> bool {
+    let mut table = root;
+
+    for level in (2..=4).rev() {
+        let entry = (phys_to_virt(table) as *mut u64).wrapping_add(index(virt, level));
+        let value = unsafe { entry.read_volatile() };
+        let value_flags = PageTableFlags::from_bits_retain(value);
+
+        if !value_flags.contains(PageTableFlags::PRESENT) {
+            let next = alloc_table();
+
+            unsafe {
+                entry.write_volatile(
+                    next | (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits(),
+                );
+            }
+
+            table = next;
+        } else if value_flags.contains(PageTableFlags::HUGE_PAGE) {
+            return false;
+        } else {
+            table = value & ADDRESS_MASK;
+        }
+    }
+
+    let entry = (phys_to_virt(table) as *mut u64).wrapping_add(index(virt, 1));
+
+    unsafe {
+        if PageTableFlags::from_bits_retain(entry.read_volatile()).contains(PageTableFlags::PRESENT)
+        {
+            return false;
+        }
+
+        entry.write_volatile((phys & ADDRESS_MASK) | (flags | PageTableFlags::PRESENT).bits());
+    }
+
+    flush(virt);
+
+    true
+}
+
+/// Remove the mapping of the page at `virt`, returns whether there was one
+///
+/// Tables that end up empty are left in place, they are never freed
+pub fn unmap(root: u64, virt: u64, phys_to_virt: impl Fn(u64) -> u64) -> bool {
+    let mut table = root;
+
+    for level in (2..=4).rev() {
+        let entry = (phys_to_virt(table) as *const u64).wrapping_add(index(virt, level));
+        let value = unsafe { entry.read_volatile() };
+        let value_flags = PageTableFlags::from_bits_retain(value);
+
+        if !value_flags.contains(PageTableFlags::PRESENT)
+            || value_flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            return false;
+        }
+
+        table = value & ADDRESS_MASK;
+    }
+
+    let entry = (phys_to_virt(table) as *mut u64).wrapping_add(index(virt, 1));
+
+    unsafe {
+        if !PageTableFlags::from_bits_retain(entry.read_volatile())
+            .contains(PageTableFlags::PRESENT)
+        {
+            return false;
+        }
+
+        entry.write_volatile(0);
+    }
+
+    flush(virt);
+
+    true
+}
+
+/// The level 1 entry for the page at `virt`, present or not, if the tables above it exist
+pub fn entry(root: u64, virt: u64, phys_to_virt: impl Fn(u64) -> u64) -> Option<*mut u64> {
+    let mut table = root;
+
+    for level in (2..=4).rev() {
+        let entry = (phys_to_virt(table) as *const u64).wrapping_add(index(virt, level));
+        let value = unsafe { entry.read_volatile() };
+        let value_flags = PageTableFlags::from_bits_retain(value);
+
+        if !value_flags.contains(PageTableFlags::PRESENT)
+            || value_flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+
+        table = value & ADDRESS_MASK;
+    }
+
+    Some((phys_to_virt(table) as *mut u64).wrapping_add(index(virt, 1)))
+}
+
+/// Call `f` with the level and value of each entry translating `virt` goes through, from the
+/// level 4 one down to the one that maps it or is not present
+pub fn levels(
+    root: u64,
+    virt: u64,
+    phys_to_virt: impl Fn(u64) -> u64,
+    mut f: impl FnMut(u32, u64),
+) {
+    let mut table = root;
+
+    for level in (1..=4).rev() {
+        let value = unsafe {
+            (phys_to_virt(table) as *const u64)
+                .wrapping_add(index(virt, level))
+                .read_volatile()
+        };
+        let value_flags = PageTableFlags::from_bits_retain(value);
+
+        f(level, value);
+
+        if !value_flags.contains(PageTableFlags::PRESENT)
+            || value_flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            return;
+        }
+
+        table = value & ADDRESS_MASK;
+    }
+}
+
+/// Call `f` with the address and size of every page mapped under `table`, in order of address,
+/// and the flags it ends up with from every level above it: writable only if each of them allows
+/// it and executable unless one of them forbids it
+fn walk_table(
+    table: u64,
+    level: u32,
+    base: u64,
+    inherited: PageTableFlags,
+    phys_to_virt: &impl Fn(u64) -> u64,
+    f: &mut impl FnMut(u64, u64, PageTableFlags),
+) {
+    let page_size = 1 << (12 + 9 * (level - 1));
+
+    for index in 0..512 {
+        let entry = (phys_to_virt(table) as *const u64).wrapping_add(index);
+        let value = unsafe { entry.read_volatile() };
+        let value_flags = PageTableFlags::from_bits_retain(value);
+
+        if !value_flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let forbidden = (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE) - inherited;
+        let flags = (value_flags - forbidden) | (inherited & PageTableFlags::NO_EXECUTE);
+
+        let mut virt = base + index as u64 * page_size;
+
+        // The upper half of the addresses are sign extended from bit 47
+        if level == 4 && index >= 256 {
+            virt |= 0xFFFF_0000_0000_0000;
+        }
+
+        if level == 1 || value_flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(virt, page_size, flags);
+        } else {
+            walk_table(
+                value & ADDRESS_MASK,
+                level - 1,
+                virt,
+                flags,
+                phys_to_virt,
+                f,
+            );
+        }
+    }
+}
+
+/// Call `f` with the address, size and flags of every page mapped, like [`walk_table`]
+pub fn walk(
+    root: u64,
+    phys_to_virt: impl Fn(u64) -> u64,
+    mut f: impl FnMut(u64, u64, PageTableFlags),
+) {
+    walk_table(
+        root,
+        4,
+        0,
+        PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        &phys_to_virt,
+        &mut f,
+    );
+}
+
+/// Find the physical address `virt` is mapped to
+pub fn translate(root: u64, virt: u64, phys_to_virt: impl Fn(u64) -> u64) -> Option<u64> {
+    let mut table = root;
+
+    for level in (1..=4).rev() {
+        let entry = (phys_to_virt(table) as *const u64).wrapping_add(index(virt, level));
+        let value = unsafe { entry.read_volatile() };
+        let value_flags = PageTableFlags::from_bits_retain(value);
+
+        if !value_flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        if level == 1 || value_flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1 << (12 + 9 * (level - 1));
+
+            return Some((value & ADDRESS_MASK & !(page_size - 1)) + (virt & (page_size - 1)));
+        }
+
+        table = value & ADDRESS_MASK;
+    }
+
+    None
+}
@@ -0,0 +1,162 @@
+//! Process-context identifiers, which tag TLB entries with the address space they were filled from
+//! so switching to another address space does not have to flush them
+//!
+//! The kernel's address space is PCID 0, the others are handed out by [`allocate`] for address
+//! spaces to be switched to with [`switch`]
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{cpu, paging};
+use crate::sync::Mutex;
+
+const CR4_PGE: u64 = 1 << 7;
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// Set in the value written to CR3 to keep the entries of the PCID switched to
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+const PCID_COUNT: usize = 4096;
+
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+const INVPCID_ALL_CONTEXTS_AND_GLOBAL: u64 = 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HAS_INVPCID: AtomicBool = AtomicBool::new(false);
+
+/// A bit for each PCID in use, the kernel's is always
+static USED: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new({
+    let mut used = [0; PCID_COUNT / 64];
+    used[0] = 1;
+    used
+});
+
+fn current() -> u16 {
+    let cr3: u64;
+
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+
+    (cr3 & 0xFFF) as u16
+}
+
+fn invpcid(kind: u64, pcid: u16, address: u64) {
+    let descriptor: [u64; 2] = [pcid as u64, address];
+
+    unsafe {
+        asm!(
+            "invpcid {}, [{}]",
+            in(reg) kind,
+            in(reg) &descriptor,
+            options(readonly, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Flush every entry of every PCID by toggling global pages and back, for processors that can not
+/// flush them one at a time
+fn flush_by_cr4() {
+    let cr4 = cpu::read_cr4();
+
+    cpu::write_cr4(cr4 ^ CR4_PGE);
+    cpu::write_cr4(cr4);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A PCID for an address space, until given back with [`free`]
+pub fn allocate() -> Option<u16> {
+    if !is_enabled() {
+        return None;
+    }
+
+    let mut used = USED.lock();
+    let pcid = (1..PCID_COUNT).find(|&pcid| used[pcid / 64] & (1 << (pcid % 64)) == 0)?;
+
+    used[pcid / 64] |= 1 << (pcid % 64);
+
+    Some(pcid as u16)
+}
+
+/// Give back a PCID, whose entries are flushed so whoever gets it next starts clean
+pub fn free(pcid: u16) {
+    assert!(pcid != 0, "pcid: freeing the kernel's");
+
+    invalidate_all(pcid);
+
+    let pcid = pcid as usize;
+
+    USED.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+/// Switch to the page tables at `root`, keeping the entries of `pcid` from the last time it ran
+pub fn switch(root: u64, pcid: u16) {
+    let cr3 = match is_enabled() {
+        true => root | pcid as u64 | CR3_NO_FLUSH,
+        false => root,
+    };
+
+    unsafe {
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+    }
+}
+
+/// Flush the entry for `virt` of the address space tagged `pcid`, which may not be the one in use
+pub fn invalidate(pcid: u16, virt: u64) {
+    if HAS_INVPCID.load(Ordering::Relaxed) {
+        invpcid(INVPCID_ADDRESS, pcid, virt);
+    } else if pcid == current() {
+        paging::flush(virt);
+    } else {
+        flush_by_cr4();
+    }
+}
+
+/// Flush every entry of the address space tagged `pcid`, but the global ones
+pub fn invalidate_all(pcid: u16) {
+    if HAS_INVPCID.load(Ordering::Relaxed) {
+        invpcid(INVPCID_SINGLE_CONTEXT, pcid, 0);
+    } else {
+        flush_by_cr4();
+    }
+}
+
+/// Flush every entry of every address space, the global ones too
+pub fn flush_everything() {
+    if HAS_INVPCID.load(Ordering::Relaxed) {
+        invpcid(INVPCID_ALL_CONTEXTS_AND_GLOBAL, 0, 0);
+    } else {
+        flush_by_cr4();
+    }
+}
+
+/// Stop tagging entries, for code that does not expect it like another kernel
+pub fn disable() {
+    if !ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+
+    // It can only be turned off from PCID 0
+    switch(paging::root_table(), 0);
+    cpu::write_cr4(cpu::read_cr4() & !CR4_PCIDE);
+}
+
+/// Turn PCIDs on if the processor has them, while the kernel's tables with PCID 0 are in use
+pub fn init() {
+    // Turning them on needs the low bits of CR3 clear, which become the PCID
+    if !cpu::has_pcid() || current() != 0 {
+        return;
+    }
+
+    HAS_INVPCID.store(cpu::has_invpcid(), Ordering::Relaxed);
+
+    cpu::write_cr4(cpu::read_cr4() | CR4_PCIDE);
+
+    ENABLED.store(true, Ordering::Relaxed);
+}
@@ -0,0 +1,133 @@
+//! The two legacy 8259 interrupt controllers, cascaded with the second on line 2 of the first
+//!
+//! They start out raising the vectors of the exceptions, so they are moved past them at boot and
+//! left with every line masked for the APIC. Machines without a usable APIC use them for the timer
+//! that wakes up from idle instead
+
+use super::port;
+use crate::irq::{self, Problem};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// Where the lines of the first and the second controller are moved to
+pub const MASTER_VECTOR: u8 = 0x20;
+pub const SLAVE_VECTOR: u8 = 0x28;
+
+/// The line of the second controller on the first
+const CASCADE: u8 = 2;
+
+/// The lines where a request that went away before it was taken is raised
+pub const SPURIOUS_MASTER: u8 = 7;
+pub const SPURIOUS_SLAVE: u8 = 15;
+
+/// Initialisation, with the fourth word coming
+const ICW1_INIT: u8 = 0x11;
+/// Not the 8080 mode
+const ICW4_8086: u8 = 0x01;
+
+const OCW2_EOI: u8 = 0x20;
+/// Read the in-service register with the next read of the command port
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// The previous write has to settle on old hardware, writing to an unused port takes long enough
+fn io_wait() {
+    port::outb(0x80, 0);
+}
+
+/// Move the vectors past the exceptions and mask every line
+pub fn init() {
+    for (command, data, vector, wiring) in [
+        (MASTER_COMMAND, MASTER_DATA, MASTER_VECTOR, 1 << CASCADE),
+        (SLAVE_COMMAND, SLAVE_DATA, SLAVE_VECTOR, CASCADE),
+    ] {
+        port::outb(command, ICW1_INIT);
+        io_wait();
+        port::outb(data, vector);
+        io_wait();
+        port::outb(data, wiring);
+        io_wait();
+        port::outb(data, ICW4_8086);
+        io_wait();
+    }
+
+    port::outb(MASTER_DATA, 0xFF);
+    port::outb(SLAVE_DATA, 0xFF);
+}
+
+/// The vector `irq` raises
+pub fn vector(irq: u8) -> u8 {
+    match irq {
+        0..8 => MASTER_VECTOR + irq,
+        _ => SLAVE_VECTOR + irq - 8,
+    }
+}
+
+fn data_port(irq: u8) -> (u16, u8) {
+    match irq {
+        0..8 => (MASTER_DATA, irq),
+        _ => (SLAVE_DATA, irq - 8),
+    }
+}
+
+pub fn mask(irq: u8) {
+    let (port, line) = data_port(irq);
+
+    port::outb(port, port::inb(port) | 1 << line);
+}
+
+/// Let `irq` through, and the line of the second controller on the first for its lines
+pub fn unmask(irq: u8) {
+    let (port, line) = data_port(irq);
+
+    port::outb(port, port::inb(port) & !(1 << line));
+
+    if irq >= 8 {
+        unmask(CASCADE);
+    }
+}
+
+/// Tell the controllers `irq` was handled, unless they were not servicing it and would be told
+/// about another instead
+pub fn eoi(irq: u8) {
+    let (command, line) = match irq {
+        0..8 => (MASTER_COMMAND, irq),
+        _ => (SLAVE_COMMAND, irq - 8),
+    };
+
+    if in_service(command) & 1 << line == 0 {
+        irq::report(vector(irq), Problem::EoiMismatch);
+        return;
+    }
+
+    if irq >= 8 {
+        port::outb(SLAVE_COMMAND, OCW2_EOI);
+    }
+
+    port::outb(MASTER_COMMAND, OCW2_EOI);
+}
+
+fn in_service(command: u16) -> u8 {
+    port::outb(command, OCW3_READ_ISR);
+    port::inb(command)
+}
+
+/// Whether the `irq` just raised is really being serviced, one on the spurious lines that is not
+/// must not be acknowledged, except on the first controller for one from the second
+pub fn is_real(irq: u8) -> bool {
+    match irq {
+        SPURIOUS_MASTER => in_service(MASTER_COMMAND) & 1 << 7 != 0,
+        SPURIOUS_SLAVE => {
+            let real = in_service(SLAVE_COMMAND) & 1 << 7 != 0;
+
+            if !real {
+                port::outb(MASTER_COMMAND, OCW2_EOI);
+            }
+
+            real
+        }
+        _ => true,
+    }
+}
@@ -0,0 +1,40 @@
+//! The programmable interval timer, used to measure how fast the time stamp counter runs, to
+//! drive the PC speaker and to wake up from idle without an APIC
+
+use super::{cpu, port};
+
+pub const CHANNEL_0: u16 = 0x40;
+pub const CHANNEL_2: u16 = 0x42;
+pub const COMMAND: u16 = 0x43;
+/// The keyboard controller port that gates channel 2 and reads its output
+pub const GATE: u16 = 0x61;
+
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Measure the frequency of the time stamp counter in hertz against 10 milliseconds of the timer
+pub fn tsc_frequency() -> u64 {
+    let count = (FREQUENCY / 100) as u16;
+
+    // Enable the gate of channel 2 and keep the speaker off
+    let gate = port::inb(GATE) & !0x02;
+    port::outb(GATE, gate & !0x01);
+
+    // Channel 2, low then high byte, interrupt on terminal count
+    port::outb(COMMAND, 0b1011_0000);
+    port::outb(CHANNEL_2, count as u8);
+    port::outb(CHANNEL_2, (count >> 8) as u8);
+
+    port::outb(GATE, gate | 0x01);
+
+    let start = cpu::rdtsc();
+
+    while port::inb(GATE) & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+
+    let end = cpu::rdtsc();
+
+    port::outb(GATE, gate & !0x01);
+
+    (end - start) * FREQUENCY / count as u64
+}
@@ -0,0 +1,61 @@
+use core::arch::asm;
+
+pub fn inb(port: u16) -> u8 {
+    let value: u8;
+
+    unsafe {
+        asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    }
+
+    value
+}
+
+crate::export!(inb: fn(u16) -> u8);
+
+pub fn outb(port: u16, value: u8) {
+    unsafe {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+crate::export!(outb: fn(u16, u8));
+
+pub fn inw(port: u16) -> u16 {
+    let value: u16;
+
+    unsafe {
+        asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    }
+
+    value
+}
+
+crate::export!(inw: fn(u16) -> u16);
+
+pub fn outw(port: u16, value: u16) {
+    unsafe {
+        asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+crate::export!(outw: fn(u16, u16));
+
+pub fn inl(port: u16) -> u32 {
+    let value: u32;
+
+    unsafe {
+        asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    }
+
+    value
+}
+
+crate::export!(inl: fn(u16) -> u32);
+
+pub fn outl(port: u16, value: u32) {
+    unsafe {
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+crate::export!(outl: fn(u16, u32));
@@ -0,0 +1,28 @@
+use core::arch::asm;
+
+use super::{DescriptorTableRegister, interrupts, port};
+
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    // Wait for the input buffer of the keyboard controller to be empty, then ask it to pulse the
+    // reset line of the processor
+    while port::inb(0x64) & 0b10 != 0 {}
+
+    port::outb(0x64, 0xFE);
+
+    unsafe {
+        // If the keyboard controller did not reset us, load an empty interrupt descriptor table
+        // and raise an exception, which will cause a triple fault and therefore a reset
+        let empty = DescriptorTableRegister {
+            size: 0,
+            address: 0,
+        };
+
+        asm!("lidt [{}]", "int3", in(reg) &empty, options(readonly, nostack));
+    }
+
+    loop {
+        interrupts::wait_for_interrupts();
+    }
+}
@@ -0,0 +1,164 @@
+use core::{arch::asm, fmt};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rsp, rbp, rflags, cr0, cr2, cr3, cr4);
+
+        unsafe {
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+
+        Self {
+            rsp,
+            rbp,
+            rflags,
+            cr0,
+            cr2,
+            cr3,
+            cr4,
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rsp={:#018x} rbp={:#018x}", self.rsp, self.rbp)?;
+        writeln!(f, "rflags={:#018x}", self.rflags)?;
+        writeln!(f, "cr0={:#018x} cr2={:#018x}", self.cr0, self.cr2)?;
+        write!(f, "cr3={:#018x} cr4={:#018x}", self.cr3, self.cr4)
+    }
+}
+
+#[inline(always)]
+pub fn stack_pointer() -> u64 {
+    let rsp;
+
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+
+    rsp
+}
+
+/// The user code and data segments of the GDT, at the privilege of user mode
+const USER_CS: u64 = 0x18 | 3;
+const USER_SS: u64 = 0x20 | 3;
+
+/// Interrupts enabled, and the bit that is always set
+const USER_RFLAGS: u64 = 1 << 9 | 1 << 1;
+
+/// Lets user mode touch I/O ports, which it never can
+const IOPL: u64 = 3 << 12;
+
+/// Raises a debug exception after each instruction, for single stepping
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// What a thread has in its registers in user mode, laid out like the `user_regs_struct` of
+/// Linux debuggers read and write
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct UserRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The syscall number, which `rax` is replaced by what it returns
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+impl crate::arch::TrapFrame for UserRegisters {
+    fn starting_at(entry: u64, stack: u64) -> Self {
+        Self {
+            rip: entry,
+            cs: USER_CS,
+            rflags: USER_RFLAGS,
+            rsp: stack,
+            ss: USER_SS,
+            ds: USER_SS,
+            es: USER_SS,
+            ..Default::default()
+        }
+    }
+
+    fn instruction_pointer(&self) -> u64 {
+        self.rip
+    }
+
+    fn set_instruction_pointer(&mut self, address: u64) {
+        self.rip = address;
+    }
+
+    fn stack_pointer(&self) -> u64 {
+        self.rsp
+    }
+
+    fn set_stack_pointer(&mut self, address: u64) {
+        self.rsp = address;
+    }
+
+    fn set_return_value(&mut self, value: u64) {
+        self.rax = value;
+    }
+
+    fn thread_pointer(&self) -> u64 {
+        self.fs_base
+    }
+
+    fn set_thread_pointer(&mut self, address: u64) {
+        self.fs_base = address;
+    }
+
+    fn set_single_step(&mut self, single_step: bool) {
+        match single_step {
+            true => self.rflags |= TRAP_FLAG,
+            false => self.rflags &= !TRAP_FLAG,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.cs == USER_CS
+            && self.ss == USER_SS
+            && self.rflags & USER_RFLAGS == USER_RFLAGS
+            && self.rflags & IOPL == 0
+    }
+}
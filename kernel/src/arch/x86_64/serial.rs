@@ -0,0 +1,98 @@
+use core::fmt::{self, Write};
+
+use lazy_static::lazy_static;
+
+use super::port;
+use crate::sync::Mutex;
+
+pub const COM1: u16 = 0x3F8;
+
+/// A 16550 compatible UART, driven by polling
+pub struct SerialPort {
+    base: u16,
+    present: bool,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self {
+            base,
+            present: false,
+        }
+    }
+
+    pub fn init(&mut self) {
+        // Disable interrupts, we only poll the port
+        port::outb(self.base + 1, 0x00);
+
+        // Set the divisor to 1 (115200 baud) while the divisor latch is enabled
+        port::outb(self.base + 3, 0x80);
+        port::outb(self.base, 0x01);
+        port::outb(self.base + 1, 0x00);
+
+        // 8 bits, no parity, one stop bit
+        port::outb(self.base + 3, 0x03);
+
+        // Enable and clear the FIFOs, with a 14 bytes threshold
+        port::outb(self.base + 2, 0xC7);
+
+        // Check that the port exists by sending a byte to ourselves in loopback mode
+        port::outb(self.base + 4, 0x1E);
+        port::outb(self.base, 0xAE);
+
+        self.present = port::inb(self.base) == 0xAE;
+
+        // Leave loopback mode, and set DTR, RTS, OUT1 and OUT2
+        port::outb(self.base + 4, 0x0F);
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    fn line_status(&self) -> u8 {
+        port::inb(self.base + 5)
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+
+        while self.line_status() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        port::outb(self.base, byte);
+    }
+
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.present && self.line_status() & 0x01 != 0 {
+            Some(port::inb(self.base))
+        } else {
+            None
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL: Mutex<SerialPort> = {
+        let mut serial = SerialPort::new(COM1);
+        serial.init();
+        Mutex::new(serial)
+    };
+}
@@ -0,0 +1,49 @@
+//! The PC speaker, a square wave from channel 2 of the programmable interval timer
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{
+    pit::{self, CHANNEL_2, COMMAND, GATE},
+    port,
+};
+use crate::timer;
+
+/// The gate bit of channel 2 and the bit that connects its output to the speaker
+const GATE_SPEAKER: u8 = 0x03;
+
+/// Bumped by every beep, so the timer of an older beep does not cut a newer one short
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Play `frequency` until stopped
+pub fn start(frequency: u32) {
+    if frequency == 0 {
+        stop();
+        return;
+    }
+
+    let divisor = (pit::FREQUENCY / frequency as u64).clamp(1, u16::MAX as u64) as u16;
+
+    // Channel 2, low then high byte, square wave
+    port::outb(COMMAND, 0b1011_0110);
+    port::outb(CHANNEL_2, divisor as u8);
+    port::outb(CHANNEL_2, (divisor >> 8) as u8);
+
+    port::outb(GATE, port::inb(GATE) | GATE_SPEAKER);
+}
+
+pub fn stop() {
+    port::outb(GATE, port::inb(GATE) & !GATE_SPEAKER);
+}
+
+/// Beep for `duration_ms` without waiting for it to end, the timer softirq stops it
+pub fn beep(frequency: u32, duration_ms: u64) {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+
+    start(frequency);
+
+    timer::add(duration_ms * 1_000_000, move || {
+        if GENERATION.load(Ordering::Relaxed) == generation {
+            stop();
+        }
+    });
+}
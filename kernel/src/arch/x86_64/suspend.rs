@@ -0,0 +1,253 @@
+//! Saving the processor before it loses power, and getting it back to long mode when the firmware
+//! wakes it up in real mode at the waking vector, which has to be below 1 MiB
+
+use core::{
+    arch::{asm, global_asm},
+    ptr,
+};
+
+use super::{
+    cet, cpu, gdt, idt,
+    paging::{PAGE_SIZE, PageTableFlags, root_table},
+};
+use crate::{
+    dma::{self, Constraints},
+    paging,
+};
+
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_PAT: u32 = 0x277;
+
+// The trampoline is copied to the waking vector, so it only refers to itself relative to its
+// start, and the few absolute addresses it needs are filled in before sleeping
+global_asm!(
+    r#"
+    .pushsection .rodata.wake, "a"
+    .balign 16
+    .global wake_start
+wake_start:
+    .code16
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    lgdtl (wake_gdtr - wake_start)
+
+    movl %cr4, %eax
+    orl $0x20, %eax
+    movl %eax, %cr4
+
+    movl (wake_temporary_cr3 - wake_start), %eax
+    movl %eax, %cr3
+
+    /* Long mode, and no execute which the kernel's page tables use */
+    movl $0xC0000080, %ecx
+    rdmsr
+    orl $0x900, %eax
+    wrmsr
+
+    /* Paging, write protect and protected mode all at once */
+    movl %cr0, %eax
+    orl $0x80010001, %eax
+    movl %eax, %cr0
+
+    ljmpl *(wake_far - wake_start)
+
+    .code64
+    .global wake_long
+wake_long:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+
+    movq wake_context(%rip), %rdi
+    movq wake_resume(%rip), %rbx
+    movq wake_cr3(%rip), %rax
+    movq %rax, %cr3
+    jmp *%rbx
+
+    /* Already marked accessed, as the trampoline's page is not writable once paging is on */
+    .balign 8
+    .global wake_gdt
+wake_gdt:
+    .quad 0
+    .quad 0x00AF9B000000FFFF
+    .quad 0x00CF93000000FFFF
+    .global wake_gdtr
+wake_gdtr:
+    .word 23
+    .long 0
+    .global wake_far
+wake_far:
+    .long 0
+    .word 0x08
+
+    .balign 8
+    .global wake_temporary_cr3
+wake_temporary_cr3:
+    .quad 0
+    .global wake_cr3
+wake_cr3:
+    .quad 0
+    .global wake_context
+wake_context:
+    .quad 0
+    .global wake_resume
+wake_resume:
+    .quad 0
+    .global wake_end
+wake_end:
+    .popsection
+
+    .pushsection .text.suspend, "ax"
+    .global suspend_save
+suspend_save:
+    movq %rbx, 0(%rdi)
+    movq %rbp, 8(%rdi)
+    movq %r12, 16(%rdi)
+    movq %r13, 24(%rdi)
+    movq %r14, 32(%rdi)
+    movq %r15, 40(%rdi)
+    movq %rsp, 48(%rdi)
+    pushq %rdi
+    /* The caches are lost along with everything else */
+    wbinvd
+    call *%rsi
+    popq %rdi
+    xorl %eax, %eax
+    ret
+
+    .global suspend_resume
+suspend_resume:
+    movq 0(%rdi), %rbx
+    movq 8(%rdi), %rbp
+    movq 16(%rdi), %r12
+    movq 24(%rdi), %r13
+    movq 32(%rdi), %r14
+    movq 40(%rdi), %r15
+    movq 48(%rdi), %rsp
+    movl $1, %eax
+    ret
+    .popsection
+"#,
+    options(att_syntax)
+);
+
+/// What the functions above save and restore, where they expect it
+#[repr(C)]
+struct Context {
+    registers: [u64; 7],
+}
+
+unsafe extern "C" {
+    static wake_start: u8;
+    static wake_end: u8;
+    static wake_gdtr: u8;
+    static wake_far: u8;
+    static wake_temporary_cr3: u8;
+    static wake_cr3: u8;
+    static wake_context: u8;
+    static wake_resume: u8;
+    static wake_gdt: u8;
+    static wake_long: u8;
+
+    /// Returns 0 if `enter` returns, and 1 when woken up
+    fn suspend_save(context: *mut Context, enter: extern "C" fn()) -> u64;
+    fn suspend_resume();
+}
+
+static mut CONTEXT: Context = Context { registers: [0; 7] };
+
+/// How far `symbol` is from the start of the trampoline
+fn offset(symbol: *const u8) -> u64 {
+    symbol as u64 - (&raw const wake_start) as u64
+}
+
+/// Where `symbol` is in the copy of the trampoline at `copy`
+fn in_copy(copy: u64, symbol: *const u8) -> *mut u64 {
+    paging::virt_from_phys(copy + offset(symbol)) as *mut u64
+}
+
+/// Put the trampoline in `page`, save the processor and call `enter` which is expected to never
+/// return, as the firmware jumps to `page` on wake up
+///
+/// Returns whether the processor slept, and if it did, it is back as it was
+pub fn suspend(page: u64, enter: extern "C" fn()) -> bool {
+    let len = (&raw const wake_end) as usize - (&raw const wake_start) as usize;
+
+    assert!(page < 0x10_0000 && page.is_multiple_of(PAGE_SIZE) && len <= PAGE_SIZE as usize);
+
+    // The trampoline enables paging while it runs from where it was copied to, which it only
+    // reads from
+    paging::map_identity(page, PAGE_SIZE as usize, PageTableFlags::empty());
+
+    // Real mode can only load a page table below 4 GiB, which the kernel's may not be, so it
+    // starts on a copy with the same entries
+    let root = root_table();
+
+    let Ok(mut temporary) =
+        dma::alloc_coherent(PAGE_SIZE as usize, Constraints::new().below_4gib())
+    else {
+        return false;
+    };
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            paging::virt_from_phys(root) as *const u8,
+            temporary.as_mut_slice().as_mut_ptr(),
+            PAGE_SIZE as usize,
+        );
+
+        ptr::copy_nonoverlapping(
+            &raw const wake_start,
+            paging::virt_from_phys(page) as *mut u8,
+            len,
+        );
+
+        let gdt = page + offset(&raw const wake_gdt);
+        let long = page + offset(&raw const wake_long);
+
+        in_copy(page, &raw const wake_gdtr)
+            .byte_add(2)
+            .cast::<u32>()
+            .write_unaligned(gdt as u32);
+        in_copy(page, &raw const wake_far)
+            .cast::<u32>()
+            .write_unaligned(long as u32);
+        in_copy(page, &raw const wake_temporary_cr3).write(temporary.bus_address());
+        in_copy(page, &raw const wake_cr3).write(root);
+        in_copy(page, &raw const wake_context).write((&raw mut CONTEXT) as u64);
+        in_copy(page, &raw const wake_resume).write(suspend_resume as *const () as u64);
+    }
+
+    let (cr0, cr4): (u64, u64);
+
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+
+    let efer = cpu::rdmsr(MSR_EFER);
+    let pat = cpu::rdmsr(MSR_PAT);
+
+    if unsafe { suspend_save(&raw mut CONTEXT, enter) } == 0 {
+        return false;
+    }
+
+    cpu::wrmsr(MSR_EFER, efer);
+    cpu::wrmsr(MSR_PAT, pat);
+
+    unsafe {
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+    }
+
+    gdt::reload();
+    idt::init();
+    cet::resume();
+
+    true
+}
@@ -0,0 +1,150 @@
+//! The code of the vDSO, which reads the clock from the page the kernel keeps its parameters in,
+//! the page before the vDSO's own
+//!
+//! It finds that page relative to itself, so it has to be copied to [`TEXT_OFFSET`] in the vDSO's
+//! page. The parameters are read at the offsets `crate::vdso` lays them out at: a sequence that is
+//! odd while they change, then a time stamp, the clock then, the frequency of the time stamp
+//! counter and the wall clock at boot, each eight bytes
+
+use core::{arch::global_asm, slice};
+
+pub const MACHINE: u16 = 0x3E;
+
+/// Where the code goes in the vDSO's page, after its headers and tables
+pub const TEXT_OFFSET: u64 = 0x800;
+
+global_asm!(
+    r#"
+    .pushsection .text.vdso, "ax"
+    .balign 16
+    .global vdso_start
+vdso_start:
+    .set vdso_data, vdso_start - {text_offset} - 4096
+
+    /* The monotonic clock in rax, clobbers rcx, rdx, r8, r10 and r11 and leaves r8 at the data */
+vdso_monotonic_ns:
+    leaq vdso_data(%rip), %r8
+2:
+    movq 0(%r8), %r10
+    testq $1, %r10
+    jnz 4f
+    lfence
+    rdtsc
+    shlq $32, %rdx
+    orq %rdx, %rax
+    subq 8(%r8), %rax
+    jae 3f
+    xorl %eax, %eax
+3:
+    movq $1000000000, %rcx
+    mulq %rcx
+    divq 24(%r8)
+    addq 16(%r8), %rax
+    cmpq 0(%r8), %r10
+    jne 2b
+    ret
+4:
+    pause
+    jmp 2b
+
+    /* The wall clock in rax, with the carry flag set if the time is not known */
+vdso_realtime_ns:
+    call vdso_monotonic_ns
+    movq 32(%r8), %rcx
+    testq %rcx, %rcx
+    jz 5f
+    addq %rcx, %rax
+    clc
+    ret
+5:
+    stc
+    ret
+
+    .global vdso_clock_gettime
+vdso_clock_gettime:
+    movq %rsi, %r9
+    /* CLOCK_MONOTONIC */
+    cmpl $1, %edi
+    je 6f
+    /* CLOCK_REALTIME */
+    testl %edi, %edi
+    jnz 8f
+    call vdso_realtime_ns
+    jc 8f
+    jmp 7f
+6:
+    call vdso_monotonic_ns
+7:
+    xorl %edx, %edx
+    movq $1000000000, %rcx
+    divq %rcx
+    movq %rax, 0(%r9)
+    movq %rdx, 8(%r9)
+    xorl %eax, %eax
+    ret
+8:
+    /* Any other clock is up to the kernel */
+    movq %r9, %rsi
+    movl $228, %eax
+    syscall
+    ret
+
+    .global vdso_gettimeofday
+vdso_gettimeofday:
+    movq %rdi, %r9
+    testq %rsi, %rsi
+    jz 9f
+    movq $0, 0(%rsi)
+9:
+    testq %r9, %r9
+    jz 10f
+    call vdso_realtime_ns
+    jc 11f
+    xorl %edx, %edx
+    movq $1000000000, %rcx
+    divq %rcx
+    movq %rax, 0(%r9)
+    movq %rdx, %rax
+    xorl %edx, %edx
+    movq $1000, %rcx
+    divq %rcx
+    movq %rax, 8(%r9)
+10:
+    xorl %eax, %eax
+    ret
+11:
+    movq %r9, %rdi
+    movl $96, %eax
+    syscall
+    ret
+    .global vdso_end
+vdso_end:
+    .popsection
+"#,
+    text_offset = const TEXT_OFFSET,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static vdso_start: u8;
+    static vdso_clock_gettime: u8;
+    static vdso_gettimeofday: u8;
+    static vdso_end: u8;
+}
+
+pub fn text() -> &'static [u8] {
+    let start = &raw const vdso_start;
+    let end = &raw const vdso_end;
+
+    unsafe { slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+/// What the vDSO exports, and where each is in [`text`]
+pub fn symbols() -> [(&'static str, u64); 2] {
+    let offset = |symbol: *const u8| symbol as u64 - (&raw const vdso_start) as u64;
+
+    [
+        ("clock_gettime", offset(&raw const vdso_clock_gettime)),
+        ("gettimeofday", offset(&raw const vdso_gettimeofday)),
+    ]
+}
@@ -0,0 +1,81 @@
+//! The timer that wakes the processor up from idle: the local APIC's, or the PIT's first channel
+//! behind the legacy PICs on machines without a usable APIC or with `nolapic`
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::{apic, pic, pit, port};
+use crate::{cmdline, time};
+
+/// The line of the PIT on the first PIC
+pub const PIT_IRQ: u8 = 0;
+
+const MODE_NONE: u8 = 0;
+const MODE_APIC: u8 = 1;
+const MODE_PIC: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(MODE_NONE);
+
+/// Channel 0, low then high byte, interrupt on terminal count, which also stops it until the
+/// count is written
+const PIT_ONE_SHOT: u8 = 0b0011_0000;
+
+/// Raise the wakeup interrupt after `ns`, replacing any earlier wakeup
+pub fn arm(ns: u64) {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_APIC => apic::arm_wakeup(ns),
+        MODE_PIC => {
+            let count = ns as u128 * pit::FREQUENCY as u128 / time::NANOSECONDS_PER_SECOND as u128;
+            let count = count.clamp(1, u16::MAX as u128) as u16;
+
+            port::outb(pit::COMMAND, PIT_ONE_SHOT);
+            port::outb(pit::CHANNEL_0, count as u8);
+            port::outb(pit::CHANNEL_0, (count >> 8) as u8);
+        }
+        _ => {}
+    }
+}
+
+pub fn disarm() {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_APIC => apic::disarm_wakeup(),
+        MODE_PIC => port::outb(pit::COMMAND, PIT_ONE_SHOT),
+        _ => {}
+    }
+}
+
+/// Called on the PIT's interrupt
+pub fn handle_pit() {
+    pic::eoi(PIT_IRQ);
+}
+
+fn start() {
+    if !cmdline::has("nolapic") && apic::init() {
+        MODE.store(MODE_APIC, Ordering::Relaxed);
+        return;
+    }
+
+    // Stopped until it is armed, on the only line let through
+    port::outb(pit::COMMAND, PIT_ONE_SHOT);
+    pic::unmask(PIT_IRQ);
+
+    MODE.store(MODE_PIC, Ordering::Relaxed);
+}
+
+/// Set up the timer, returns what it is
+pub fn init() -> &'static str {
+    start();
+
+    match MODE.load(Ordering::Relaxed) {
+        MODE_APIC => "local apic",
+        _ => "pit",
+    }
+}
+
+/// Set the timer up again after the controllers lost their state, like when waking up from sleep
+pub fn resume() {
+    pic::init();
+
+    if MODE.load(Ordering::Relaxed) != MODE_NONE {
+        start();
+    }
+}
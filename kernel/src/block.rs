@@ -0,0 +1,84 @@
+//! Block devices, disks that are read and written in whole blocks
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The blocks are past the end of the device
+    OutOfRange,
+    /// The buffer is not a whole number of blocks
+    Misaligned,
+    /// The device failed the request
+    Io,
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks starting at `lba` into `buffer`, which is a whole number of blocks
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<(), Error>;
+
+    /// Get what was written out of the device's caches
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Check a request against the device, returning how many blocks it is
+pub fn check(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, Error> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(Error::Misaligned);
+    }
+
+    let count = (len / device.block_size()) as u64;
+
+    if lba
+        .checked_add(count)
+        .is_none_or(|end| end > device.block_count())
+    {
+        return Err(Error::OutOfRange);
+    }
+
+    Ok(count)
+}
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+pub fn register(name: String, device: Arc<dyn BlockDevice>) {
+    println!(
+        "block: {}: {} MiB in {} byte blocks",
+        name,
+        device.block_count() * device.block_size() as u64 / (1024 * 1024),
+        device.block_size()
+    );
+
+    DEVICES.lock().push((name, device));
+}
+
+pub fn unregister(name: &str) {
+    DEVICES
+        .lock()
+        .retain(|(device_name, _)| device_name != name);
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(device_name, _)| device_name == name)
+        .map(|(_, device)| device.clone())
+}
+
+pub fn names() -> Vec<String> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect()
+}
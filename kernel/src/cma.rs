@@ -0,0 +1,176 @@
+//! A physically contiguous area set aside at boot for devices that need large buffers
+//!
+//! With `cma=<MiB>` that much is taken from the frame zones while booting, before everything
+//! else allocating scatters what it leaves free, and below 4 GiB when it fits there so devices
+//! with 32-bit addressing can use it too. [`dma::alloc_contiguous`](crate::dma::alloc_contiguous)
+//! takes runs of pages from it for things like framebuffers and sound rings, which are given back
+//! to it when freed and never to the zones
+
+use alloc::vec::Vec;
+
+use crate::{
+    cmdline,
+    frame::{self, PAGE_SIZE, ZoneKind},
+    numa,
+    page::{self, Owner},
+    sync::Mutex,
+};
+
+/// The area, with a bit set for each page that is allocated
+struct Area {
+    base: u64,
+    pages: usize,
+    free: usize,
+    used: Vec<u64>,
+    failed: u64,
+}
+
+impl Area {
+    fn is_used(&self, page: usize) -> bool {
+        self.used[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_used(&mut self, pages: core::ops::Range<usize>, used: bool) {
+        for page in pages {
+            if used {
+                self.used[page / 64] |= 1 << (page % 64);
+            } else {
+                self.used[page / 64] &= !(1 << (page % 64));
+            }
+        }
+    }
+
+    /// The first run of `count` free pages whose address is aligned to `align` and that ends
+    /// below `limit`
+    fn allocate(&mut self, count: usize, align: u64, limit: u64) -> Option<u64> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        // The alignment is of the physical address, not of the page in the area
+        let first = self.base / PAGE_SIZE;
+        let align = align / PAGE_SIZE;
+        let aligned =
+            |page: usize| ((first + page as u64).next_multiple_of(align) - first) as usize;
+        let mut start = aligned(0);
+
+        while start + count <= self.pages {
+            if self.base + (start + count) as u64 * PAGE_SIZE > limit {
+                return None;
+            }
+
+            match (start..start + count)
+                .rev()
+                .find(|&page| self.is_used(page))
+            {
+                Some(used) => start = aligned(used + 1),
+                None => {
+                    self.set_used(start..start + count, true);
+                    self.free -= count;
+
+                    return Some(self.base + start as u64 * PAGE_SIZE);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn deallocate(&mut self, phys: u64, count: usize) {
+        let start = ((phys - self.base) / PAGE_SIZE) as usize;
+
+        assert!(
+            start + count <= self.pages && (start..start + count).all(|page| self.is_used(page)),
+            "cma: freeing pages at {phys:#x} that are not allocated"
+        );
+
+        self.set_used(start..start + count, false);
+        self.free += count;
+    }
+}
+
+static AREA: Mutex<Option<Area>> = Mutex::new(None);
+
+/// Allocate `count` contiguous pages at an address aligned to `align`, a power of two, ending
+/// at or below `limit`, returning the physical address of the first one
+pub fn alloc(count: usize, align: u64, limit: u64) -> Option<u64> {
+    let mut area = AREA.lock();
+    let area = area.as_mut()?;
+
+    let phys = area.allocate(count, align.max(PAGE_SIZE), limit);
+
+    if phys.is_none() {
+        area.failed += 1;
+    }
+
+    phys
+}
+
+/// Give back `count` pages from [`alloc`]
+pub fn free(phys: u64, count: usize) {
+    AREA.lock()
+        .as_mut()
+        .expect("cma: freeing pages without an area")
+        .deallocate(phys, count);
+}
+
+/// How many pages the area has, and how many of them are free
+pub fn pages() -> (usize, usize) {
+    AREA.lock()
+        .as_ref()
+        .map_or((0, 0), |area| (area.pages, area.free))
+}
+
+pub fn dump() {
+    let Some(area) = AREA.try_lock() else {
+        println!("cma: busy, try again later");
+        return;
+    };
+
+    match area.as_ref() {
+        Some(area) => println!(
+            "cma: {:#x}: {} of {} pages free, {} failed allocations",
+            area.base, area.free, area.pages, area.failed
+        ),
+        None => println!("cma: no area"),
+    }
+}
+
+/// Set aside the area from `cma=<MiB>`, once the frame zones and the pages are set up
+pub fn init() {
+    let Some(size) = cmdline::value("cma") else {
+        return;
+    };
+
+    let Some(pages) = size
+        .parse::<u64>()
+        .ok()
+        .and_then(|mib| mib.checked_mul(1024 * 1024 / PAGE_SIZE))
+        .filter(|&pages| pages > 0)
+    else {
+        crate::log_warn!("cma", "invalid size {}, expected MiB", size);
+        return;
+    };
+
+    let pages = pages as usize;
+    let node = numa::current_node();
+
+    let Some(base) = frame::alloc_in(ZoneKind::Dma32, node, pages)
+        .or_else(|| frame::alloc_in(ZoneKind::Normal, node, pages))
+    else {
+        crate::log_error!("cma", "could not reserve {} MiB", size);
+        return;
+    };
+
+    page::reset_range(base, pages, Owner::Cma, 1);
+
+    *AREA.lock() = Some(Area {
+        base,
+        pages,
+        free: pages,
+        used: alloc::vec![0; pages.div_ceil(64)],
+        failed: 0,
+    });
+
+    crate::log_ok!("cma", "reserved {} MiB at {:#x}", size, base);
+}
@@ -0,0 +1,38 @@
+use lazy_static::lazy_static;
+
+use crate::requests::EXECUTABLE_CMDLINE_REQUEST;
+
+lazy_static! {
+    static ref CMDLINE: &'static str = EXECUTABLE_CMDLINE_REQUEST
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+        .unwrap_or("");
+}
+
+/// The whole command line that the kernel was booted with
+pub fn get() -> &'static str {
+    &CMDLINE
+}
+
+/// Iterate over the options of the command line, an option is either `key` or `key=value`
+pub fn options() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    get()
+        .split_whitespace()
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        })
+}
+
+/// Whether `key` was passed on the command line, with or without a value
+pub fn has(key: &str) -> bool {
+    options().any(|(option, _)| option == key)
+}
+
+/// The value of the last `key=value` option on the command line
+pub fn value(key: &str) -> Option<&'static str> {
+    options()
+        .filter(|(option, _)| *option == key)
+        .filter_map(|(_, value)| value)
+        .last()
+}
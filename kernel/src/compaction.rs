@@ -0,0 +1,108 @@
+//! Moving pages out of the way when contiguous pages are asked for and are not free together
+//!
+//! Anonymous memory and the pages mapped files own are only reached through their mapping, so
+//! the frame they are in can change under them. When an allocation of more than a page finds
+//! enough free memory but not in one run, the run with the fewest pages in use where all of them
+//! can be moved is taken: its free pages are claimed first so nothing else lands there, each page
+//! in use is copied to a frame elsewhere and its mapping pointed at the copy, and the frame it
+//! left is claimed in turn
+//!
+//! Pages shared with a file or owned by anything else stay where they are, so a run with one of
+//! them in it is never picked. One that turns out not to move, like a page being written out to
+//! swap, gives the run up and the allocation fails like it would have
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    frame::{self, PAGE_SIZE, ZoneKind},
+    mmap,
+    numa::Node,
+    page::{self, Owner},
+    swap,
+};
+
+static COMPACTIONS: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+static MIGRATED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the page in the frame at `phys` is only reached through a mapping that can be
+/// pointed elsewhere
+fn is_movable(phys: u64) -> bool {
+    page::from_phys(phys).is_some_and(|page| {
+        matches!(page.owner(), Owner::Anonymous | Owner::Mapped) && page.refcount() == 1
+    })
+}
+
+/// Move the page in the frame at `phys` to another frame, returns whether it could be
+fn migrate(phys: u64) -> bool {
+    let Some(owner) = page::from_phys(phys).map(|page| page.owner()) else {
+        return false;
+    };
+
+    let Some(to) = frame::alloc(1) else {
+        return false;
+    };
+
+    let migrated = match owner {
+        Owner::Anonymous => swap::migrate(phys, to),
+        Owner::Mapped => mmap::migrate(phys, to),
+        _ => false,
+    };
+
+    if !migrated {
+        frame::free(to, 1);
+    }
+
+    migrated
+}
+
+/// Make `count` contiguous pages free for `kind` of memory on `node` by moving pages, and take
+/// them, returning the physical address of the first one
+pub fn compact(kind: ZoneKind, node: Node, count: usize) -> Option<u64> {
+    let start = frame::movable_window(kind, node, count, is_movable)?;
+
+    let mut claimed = Vec::with_capacity(count);
+    let mut in_use = Vec::new();
+    let mut moved = 0;
+
+    // The free pages are claimed first, so the frames pages are moved to are not among them
+    for phys in (0..count as u64).map(|page| start + page * PAGE_SIZE) {
+        match frame::claim(phys) {
+            true => claimed.push(phys),
+            false => in_use.push(phys),
+        }
+    }
+
+    for phys in in_use {
+        // What left the frame gave it back, and nothing else was allocated since
+        if !migrate(phys) || !frame::claim(phys) {
+            COMPACTIONS.fetch_add(1, Ordering::Relaxed);
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+            MIGRATED.fetch_add(moved, Ordering::Relaxed);
+
+            for phys in claimed {
+                frame::free(phys, 1);
+            }
+
+            return None;
+        }
+
+        claimed.push(phys);
+        moved += 1;
+    }
+
+    COMPACTIONS.fetch_add(1, Ordering::Relaxed);
+    MIGRATED.fetch_add(moved, Ordering::Relaxed);
+
+    Some(start)
+}
+
+pub fn dump() {
+    println!(
+        "compaction: {} runs, {} failed, {} pages moved",
+        COMPACTIONS.load(Ordering::Relaxed),
+        FAILURES.load(Ordering::Relaxed),
+        MIGRATED.load(Ordering::Relaxed)
+    );
+}
@@ -1,13 +1,181 @@
-use core::fmt::{self, Write};
+//! The console on the framebuffer, and the early console everything goes to before it is set up
+//!
+//! Until [`init`] sets it up everything printed goes to the log and to
+//! [`earlycon`](crate::arch::earlycon), which needs nothing the boot has to set up first. The
+//! log is a static buffer, so what it holds from then is drawn once the console is set up, and
+//! nothing printed early is lost on machines without a serial port
+//!
+//! Characters are drawn with the glyph the unicode table of the font gives them, or a
+//! replacement one when it has none, and take as many cells as on a terminal: combining marks
+//! are drawn over the character before them and wide characters take two, so the cursor stays
+//! where programs expect it whatever the font has
+//!
+//! The text on the screen is kept along with what is drawn, so SysRq `d` and crash dumps can
+//! write it to the serial port line by line as someone in front of the screen reads it, down to
+//! the panic screen
+//!
+//! Each pixel of the font is drawn as a square of `fbcon.scale=<n>` pixels, from one to
+//! [`MAX_SCALE`], or as big as fits a 1080p screen worth of text with `fbcon.scale=auto`, so the
+//! one font is still readable on a 4K framebuffer
+//!
+//! Records from [`log!`](crate::log) are drawn with the time since boot in grey and their level
+//! in its color, they are plain text in the log and on the early console
+//!
+//! [`Hexdump`] formats memory or bytes as rows of hex with their offset and an ASCII gutter, for
+//! panics and debugging alike
+
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use lazy_static::lazy_static;
-use spin::Mutex;
 
 use crate::{
+    arch::{earlycon, serial::SERIAL, speaker},
+    cmdline,
+    log::{self, Level, Line, LineHash},
+    paging,
     psf2::Psf2Font,
-    screen::{self, Color, FRAMEBUFFER},
+    screen::{self, Color},
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
 };
 
+const BELL: char = '\x07';
+const BELL_FREQUENCY: u32 = 750;
+const BELL_DURATION_MS: u64 = 125;
+/// Moves back a character without erasing it, like on a terminal
+const BACKSPACE: char = '\x08';
+
+/// The most each pixel of the font is scaled by
+pub const MAX_SCALE: usize = 4;
+
+/// The height of the screen `fbcon.scale=auto` scales the font to keep as much text on as
+const AUTO_SCALE_HEIGHT: usize = 1080;
+
+/// How much each pixel of the font is scaled by, from `fbcon.scale=`
+fn scale() -> usize {
+    match cmdline::value("fbcon.scale") {
+        Some("auto") => (screen::height() / AUTO_SCALE_HEIGHT).clamp(1, MAX_SCALE),
+        Some(scale) => match scale.parse() {
+            Ok(scale @ 1..=MAX_SCALE) => scale,
+            _ => {
+                // The console is being set up, so this goes to the early console
+                crate::log_warn!(
+                    "console",
+                    "invalid scale {}, expected 1 to {}",
+                    scale,
+                    MAX_SCALE
+                );
+                1
+            }
+        },
+        None => 1,
+    }
+}
+
+/// Tabs go to the next column that is a multiple of this from the left
+const TAB_WIDTH: usize = 8;
+
+/// How many cells `ch` takes: none for marks that combine with the character before them, and
+/// two for the wide characters of East Asian scripts and for emoji
+fn char_width(ch: char) -> usize {
+    match ch as u32 {
+        0x0300..=0x036F
+        | 0x0483..=0x0489
+        | 0x0591..=0x05BD
+        | 0x05BF
+        | 0x05C1..=0x05C2
+        | 0x05C4..=0x05C5
+        | 0x05C7
+        | 0x0610..=0x061A
+        | 0x064B..=0x065F
+        | 0x0670
+        | 0x06D6..=0x06DC
+        | 0x06DF..=0x06E4
+        | 0x06E7..=0x06E8
+        | 0x06EA..=0x06ED
+        | 0x1AB0..=0x1AFF
+        | 0x1DC0..=0x1DFF
+        | 0x200B..=0x200F
+        | 0x20D0..=0x20FF
+        | 0xFE00..=0xFE0F
+        | 0xFE20..=0xFE2F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Puts characters back together from UTF-8 that comes a byte at a time, what is not UTF-8
+/// comes out as the replacement character
+#[derive(Default)]
+struct Utf8Decoder {
+    bytes: [u8; 4],
+    len: usize,
+    /// How long the character being put together is
+    expected: usize,
+}
+
+impl Utf8Decoder {
+    fn push(&mut self, byte: u8, mut f: impl FnMut(char)) {
+        if self.len != 0 && byte & 0xC0 != 0x80 {
+            // The character was cut short, and the byte starts something else
+            self.len = 0;
+            f(char::REPLACEMENT_CHARACTER);
+        }
+
+        if self.len == 0 {
+            self.expected = match byte {
+                0x00..=0x7F => return f(byte as char),
+                0xC2..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF4 => 4,
+                _ => return f(char::REPLACEMENT_CHARACTER),
+            };
+        }
+
+        self.bytes[self.len] = byte;
+        self.len += 1;
+
+        if self.len == self.expected {
+            let ch = core::str::from_utf8(&self.bytes[..self.len])
+                .ok()
+                .and_then(|s| s.chars().next())
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+
+            self.len = 0;
+            f(ch);
+        }
+    }
+}
+
+const TIMESTAMP_COLOR: Color = Color::new(170, 170, 170);
+
+fn level_color(level: Level) -> Color {
+    match level {
+        Level::Error => Color::new(255, 85, 85),
+        Level::Warn => Color::new(255, 200, 0),
+        Level::Ok => Color::new(85, 255, 85),
+        Level::Info => Color::new(85, 170, 255),
+        Level::Debug => Color::new(150, 150, 150),
+    }
+}
+
 pub struct Console<'a> {
     pub font: Psf2Font<'a>,
     pub background: Color,
@@ -18,6 +186,12 @@ pub struct Console<'a> {
     pub y: usize,
     pub padding_x: usize,
     pub padding_y: usize,
+    /// How many pixels each pixel of the font is drawn as across and down
+    pub scale: usize,
+    decoder: Utf8Decoder,
+    /// The character in each cell, in rows as wide as the console, which stays empty when there
+    /// is no memory for it yet
+    text: Vec<char>,
 }
 
 impl Default for Console<'_> {
@@ -25,17 +199,21 @@ impl Default for Console<'_> {
         let font = Psf2Font::parse(include_bytes!("fonts/default8x16.psfu"));
         let padding_x = 2;
         let padding_y = 1;
+        let scale = scale();
 
         Console {
             font,
             background: Color::BLACK,
             foreground: Color::WHITE,
-            width: (FRAMEBUFFER.width() as usize / font.header.glyph_width as usize) - padding_x,
-            height: (FRAMEBUFFER.height() as usize / font.header.glyph_height as usize) - padding_y,
+            width: (screen::width() / (font.header.glyph_width as usize * scale)) - padding_x,
+            height: (screen::height() / (font.header.glyph_height as usize * scale)) - padding_y,
             x: padding_x,
             y: padding_y,
             padding_x,
             padding_y,
+            scale,
+            decoder: Utf8Decoder::default(),
+            text: Vec::new(),
         }
     }
 }
@@ -46,81 +224,335 @@ impl Console<'_> {
 
         self.x = self.padding_x;
         self.y = self.padding_y;
+
+        self.text.fill(' ');
+    }
+
+    /// Make room for the text of every cell, which is not done while panicking as the heap may
+    /// be locked by what panicked
+    fn allocate_text(&mut self) {
+        self.text = alloc::vec![' '; self.width * self.height];
+    }
+
+    /// Keep `ch` as the character in the cell at `x`, `y`
+    fn set_text(&mut self, x: usize, y: usize, ch: char) {
+        if let Some(cell) = self.text.get_mut(y * self.width + x) {
+            *cell = ch;
+        }
+    }
+
+    /// Write the text on the screen line by line, without the spaces at the end of them
+    pub fn write_text(&self, out: &mut impl Write) -> fmt::Result {
+        for row in self.text.chunks(self.width.max(1)).skip(self.padding_y) {
+            let row = &row[self.padding_x.min(row.len())..];
+            let len = row
+                .iter()
+                .rposition(|&ch| ch != ' ')
+                .map_or(0, |last| last + 1);
+
+            // The cells after the first one of a wide character hold nothing
+            for &ch in row[..len].iter().filter(|&&ch| ch != '\0') {
+                out.write_char(ch)?;
+            }
+
+            out.write_char('\n')?;
+        }
+
+        Ok(())
+    }
+
+    /// Fit the console to the size of the framebuffer again, clearing it
+    pub fn resize(&mut self) {
+        self.width = screen::width() / self.cell_width() - self.padding_x;
+        self.height = screen::height() / self.cell_height() - self.padding_y;
+
+        self.allocate_text();
+        self.clear();
+    }
+
+    /// The width of a character on the screen, in pixels
+    fn cell_width(&self) -> usize {
+        self.font.header.glyph_width as usize * self.scale
+    }
+
+    /// The height of a character on the screen, in pixels
+    fn cell_height(&self) -> usize {
+        self.font.header.glyph_height as usize * self.scale
     }
 
-    fn write_glyph(&self, glyph_bytes: &[u8]) {
-        let x = self.x * self.font.header.glyph_width as usize;
-        let y = self.y * self.font.header.glyph_height as usize;
+    /// Draw `glyph_bytes` in the cell at `x`, `y`, only where the glyph is set when `overlay`
+    fn write_glyph(&self, x: usize, y: usize, glyph_bytes: &[u8], overlay: bool) {
+        let x = x * self.cell_width();
+        let y = y * self.cell_height();
 
-        for dx in 0..self.font.header.glyph_width as usize {
-            for dy in 0..self.font.header.glyph_height as usize {
+        for dx in 0..self.cell_width() {
+            for dy in 0..self.cell_height() {
                 let font_bit = self.get_glyph_bit(
                     glyph_bytes,
-                    self.font.header.glyph_width as usize - 1 - dx,
-                    dy,
+                    self.font.header.glyph_width as usize - 1 - dx / self.scale,
+                    dy / self.scale,
                 );
 
                 if font_bit {
                     *screen::get_color(x + dx, y + dy) = self.foreground;
-                } else {
+                } else if !overlay {
                     *screen::get_color(x + dx, y + dy) = self.background;
                 }
             }
         }
     }
 
-    fn get_glyph_bytes(&self, index: usize) -> &[u8] {
-        &self.font.data[index..index + self.font.header.glyph_height as usize]
+    /// The glyph that draws `ch`, or the one for characters the font does not have
+    fn get_glyph_bytes(&self, ch: char) -> &[u8] {
+        [ch, char::REPLACEMENT_CHARACTER, '?']
+            .into_iter()
+            .find_map(|ch| self.font.glyph_index(ch))
+            .and_then(|index| self.font.glyph(index))
+            .unwrap_or(&self.font.data[..self.font.header.glyph_size as usize])
     }
 
     fn get_glyph_bit(&self, glyph_bytes: &[u8], x: usize, y: usize) -> bool {
         (glyph_bytes[y] & (1 << x)) != 0
     }
+
+    fn put_str(&mut self, s: &str) {
+        for ch in s.chars() {
+            self.put_char(ch);
+        }
+    }
+
+    /// Draw UTF-8 that may be cut anywhere, keeping what is left of a character for the next bytes
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let mut decoder = core::mem::take(&mut self.decoder);
+
+            decoder.push(byte, |ch| self.put_char(ch));
+
+            self.decoder = decoder;
+        }
+    }
+
+    fn put_char(&mut self, ch: char) {
+        if ch == BELL {
+            speaker::beep(BELL_FREQUENCY, BELL_DURATION_MS);
+            return;
+        }
+
+        if ch == BACKSPACE {
+            self.x = self.x.saturating_sub(1).max(self.padding_x);
+            return;
+        }
+
+        if ch == '\n' {
+            self.new_line();
+            return;
+        }
+
+        if ch == '\t' {
+            for _ in 0..TAB_WIDTH - (self.x - self.padding_x) % TAB_WIDTH {
+                self.put_char(' ');
+
+                // Wrapping to the next line ends the tab
+                if self.x == self.padding_x {
+                    break;
+                }
+            }
+
+            return;
+        }
+
+        let width = char_width(ch);
+
+        // A combining mark goes over the character before it, if it is still on the line
+        if width == 0 {
+            if self.x > self.padding_x {
+                self.write_glyph(self.x - 1, self.y, self.get_glyph_bytes(ch), true);
+            }
+
+            return;
+        }
+
+        self.set_text(self.x, self.y, ch);
+
+        // A wide character that does not fit at the end of the line goes on the next one
+        if self.x + width > self.width {
+            self.new_line();
+        }
+
+        self.write_glyph(self.x, self.y, self.get_glyph_bytes(ch), false);
+
+        // The font only has glyphs a cell wide, a wide one is drawn in the first of its cells
+        for x in self.x + 1..self.x + width {
+            self.write_glyph(x, self.y, self.get_glyph_bytes(' '), false);
+            self.set_text(x, self.y, '\0');
+        }
+
+        if self.x + width >= self.width {
+            self.new_line();
+        } else {
+            self.x += width;
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.x = self.padding_x;
+        self.y += 1;
+
+        if self.y >= self.height {
+            let colors = screen::get_colors();
+            let row_unit = screen::width() * self.cell_height();
+
+            for current_row in (self.padding_y..self.height).map(|i| i * row_unit) {
+                let previous_row = current_row - row_unit;
+                let next_row = current_row + row_unit;
+
+                colors.copy_within(current_row..next_row, previous_row);
+            }
+
+            colors[(self.height - 1) * row_unit..].fill(self.background);
+
+            if !self.text.is_empty() {
+                let width = self.width;
+
+                self.text.copy_within(width.., 0);
+                self.text[(self.height - 1) * width..].fill(' ');
+            }
+
+            self.y = self.height - 1;
+        }
+    }
 }
 
 impl Write for Console<'_> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for ch in s.chars() {
-            self.write_char(ch)?;
-        }
+        log::append(s);
+
+        self.put_str(s);
 
         Ok(())
     }
 
     fn write_char(&mut self, ch: char) -> fmt::Result {
-        if !ch.is_ascii() {
-            self.write_glyph(self.get_glyph_bytes(0));
-        } else if ch != '\n' {
-            self.write_glyph(
-                self.get_glyph_bytes(
-                    (ch as usize * self.font.header.glyph_height as usize)
-                        .rem_euclid(self.font.data.len()),
-                ),
-            );
-        }
-
-        if self.x + 1 >= self.width || ch == '\n' {
-            self.x = self.padding_x;
-            self.y += 1;
-
-            if self.y >= self.height {
-                let colors = screen::get_colors();
-                let row_unit =
-                    FRAMEBUFFER.width() as usize * self.font.header.glyph_height as usize;
-
-                for current_row in (self.padding_y..self.height).map(|i| i * row_unit) {
-                    let previous_row = current_row - row_unit;
-                    let next_row = current_row + row_unit;
-
-                    colors.copy_within(current_row..next_row, previous_row);
+        log::append(ch.encode_utf8(&mut [0; 4]));
+
+        self.put_char(ch);
+
+        Ok(())
+    }
+}
+
+/// How many bytes each row of a [`Hexdump`] shows
+const HEXDUMP_ROW: usize = 16;
+
+/// What a [`Hexdump`] shows
+enum Source<'a> {
+    /// Memory at an address, which may not all be mapped
+    Memory(u64),
+    /// Bytes numbered from an offset
+    Bytes(&'a [u8], u64),
+}
+
+/// Rows of hex, each starting with the address or offset of its first byte and ending with the
+/// bytes that are printable as ASCII, one per line when displayed
+pub struct Hexdump<'a> {
+    source: Source<'a>,
+    len: usize,
+}
+
+impl<'a> Hexdump<'a> {
+    /// Of `len` bytes of memory at `address`, with the rows in pages that are not mapped skipped
+    pub fn memory(address: u64, len: usize) -> Self {
+        Self {
+            source: Source::Memory(address),
+            len,
+        }
+    }
+
+    /// Of `bytes`, numbering the first one `offset`
+    pub fn bytes(bytes: &'a [u8], offset: u64) -> Self {
+        Self {
+            source: Source::Bytes(bytes, offset),
+            len: bytes.len(),
+        }
+    }
+
+    fn start(&self) -> u64 {
+        match self.source {
+            Source::Memory(address) => address,
+            Source::Bytes(_, offset) => offset,
+        }
+    }
+
+    /// The bytes of the row at `start`, if they can be read
+    fn row(&self, start: u64, row: &mut [u8]) -> bool {
+        match self.source {
+            Source::Memory(_) => {
+                if paging::translate(start).is_none()
+                    || paging::translate(start + row.len() as u64 - 1).is_none()
+                {
+                    return false;
                 }
 
-                colors[(self.height - 1) * row_unit..].fill(self.background);
+                for (i, byte) in row.iter_mut().enumerate() {
+                    *byte = unsafe { ((start + i as u64) as *const u8).read_volatile() };
+                }
+            }
+            Source::Bytes(bytes, offset) => {
+                let from = (start - offset) as usize;
 
-                self.y = self.height - 1;
+                row.copy_from_slice(&bytes[from..from + row.len()]);
             }
-        } else {
-            self.x += 1;
+        }
+
+        true
+    }
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = match self.source {
+            Source::Memory(_) => 16,
+            Source::Bytes(..) => 4,
+        };
+
+        let mut buffer = [0; HEXDUMP_ROW];
+
+        for (index, done) in (0..self.len).step_by(HEXDUMP_ROW).enumerate() {
+            let start = self.start() + done as u64;
+            let row = &mut buffer[..(self.len - done).min(HEXDUMP_ROW)];
+
+            if index != 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{:0width$x} ", start)?;
+
+            if !self.row(start, row) {
+                write!(f, " not mapped")?;
+                continue;
+            }
+
+            for column in 0..HEXDUMP_ROW {
+                if column == HEXDUMP_ROW / 2 {
+                    write!(f, " ")?;
+                }
+
+                match row.get(column) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+
+            write!(f, "  |")?;
+
+            for &byte in row.iter() {
+                match byte.is_ascii_graphic() || byte == b' ' {
+                    true => write!(f, "{}", byte as char)?,
+                    false => write!(f, ".")?,
+                }
+            }
+
+            write!(f, "|")?;
         }
 
         Ok(())
@@ -141,8 +573,15 @@ macro_rules! println {
     };
 
     ($($arg:tt)*) => {{
-        $crate::console::_print(core::format_args!($($arg)*));
-        $crate::print!("\n");
+        $crate::console::_println(core::format_args!($($arg)*));
+    }};
+}
+
+/// Like `println!`, but for interrupt and NMI handlers, which must not wait for the console
+#[macro_export]
+macro_rules! println_nowait {
+    ($($arg:tt)*) => {{
+        $crate::console::_println_nowait(core::format_args!($($arg)*));
     }};
 }
 
@@ -150,7 +589,200 @@ lazy_static! {
     pub static ref CONSOLE: Mutex<Console<'static>> = Mutex::new(Console::default());
 }
 
+/// Whether printing goes to the console on the framebuffer yet
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Where everything printed goes before the console is set up
+pub struct EarlyConsole;
+
+impl Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        log::append(s);
+        earlycon::write_str(s);
+
+        Ok(())
+    }
+}
+
+/// Draw what was printed before the console was set up, which is in the log already
+fn replay(console: &mut Console, end: u64) {
+    let mut buffer = [0; 1024];
+    let mut position = 0;
+
+    while position < end {
+        let len = (end - position).min(buffer.len() as u64) as usize;
+        let (start, len) = log::read(position, &mut buffer[..len]);
+
+        if start > position {
+            console.put_str("[older messages were overwritten]\n");
+        }
+
+        // A character cut in two by the end of the buffer is finished by the next one
+        console.put_bytes(&buffer[..len]);
+
+        position = start + len as u64;
+    }
+}
+
+/// Print to the console on the framebuffer from now on, instead of the early console
+pub fn init() {
+    let mut console = CONSOLE.lock();
+
+    console.allocate_text();
+
+    // What is printed once it is ready waits for the console to be let go, after the replay
+    replay(&mut console, log::written());
+
+    READY.store(true, Ordering::Release);
+
+    drop(console);
+
+    screen::flush();
+}
+
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Whether the line hashed into `hash` is printed, which it is unless it is the one printed last
+/// again, after saying how many times the last one was repeated if it was
+fn is_new_line(hash: LineHash) -> bool {
+    match log::note_line(hash.finish()) {
+        Line::Repeated => false,
+        Line::New { repeats: 0 } => true,
+        Line::New { repeats } => {
+            _print(format_args!("last message repeated {} times\n", repeats));
+            true
+        }
+    }
+}
+
+/// Print a line, unless it is the one printed last again
+pub fn _println(args: fmt::Arguments) {
+    let mut hash = LineHash::new();
+    let _ = hash.write_fmt(args);
+
+    if !is_new_line(hash) {
+        return;
+    }
+
+    _print(args);
+    _print(format_args!("\n"));
+}
+
+/// Print a line without waiting for the console, whoever holds it may be what was interrupted.
+/// When it is busy the line goes to the log and the early console, and the screen is only
+/// flushed by whatever is printed next
+pub fn _println_nowait(args: fmt::Arguments) {
+    let mut hash = LineHash::new();
+    let _ = hash.write_fmt(args);
+
+    let console = if is_ready() { CONSOLE.try_lock() } else { None };
+
+    let Some(mut console) = console else {
+        let _ = writeln!(EarlyConsole, "{}", args);
+        return;
+    };
+
+    match log::note_line(hash.finish()) {
+        Line::Repeated => return,
+        Line::New { repeats: 0 } => {}
+        Line::New { repeats } => {
+            let _ = writeln!(console, "last message repeated {} times", repeats);
+        }
+    }
+
+    let _ = writeln!(console, "{}", args);
+}
+
+pub fn _log(level: Level, subsystem: &str, args: fmt::Arguments) {
+    if !log::enabled(subsystem, level) {
+        return;
+    }
+
+    // Without the time, which differs every time the same record is printed again
+    let mut hash = LineHash::new();
+    let _ = write!(hash, "{} {}: {}", level.name(), subsystem, args);
+
+    if !is_new_line(hash) {
+        return;
+    }
+
+    // Reading the clock before it is calibrated would calibrate it, which does not belong here
+    let ns = if time::is_calibrated() {
+        time::monotonic_ns()
+    } else {
+        0
+    };
+
+    let timestamp = format_args!(
+        "[{:5}.{:06}]",
+        ns / NANOSECONDS_PER_SECOND,
+        ns % NANOSECONDS_PER_SECOND / 1000
+    );
+
+    if !is_ready() {
+        let _ = writeln!(
+            EarlyConsole,
+            "{} [{}] {}: {}",
+            timestamp,
+            level.name(),
+            subsystem,
+            args
+        );
+        return;
+    }
+
+    let mut console = CONSOLE.lock();
+    let foreground = console.foreground;
+
+    console.foreground = TIMESTAMP_COLOR;
+    let _ = write!(console, "{} ", timestamp);
+
+    console.foreground = level_color(level);
+    let _ = write!(console, "[{}]", level.name());
+
+    console.foreground = foreground;
+    let _ = writeln!(console, " {}: {}", subsystem, args);
+
+    drop(console);
+
+    screen::flush();
+}
+
 #[allow(static_mut_refs)]
 pub fn _print(args: fmt::Arguments) {
-    CONSOLE.lock().write_fmt(args).unwrap();
+    if !is_ready() {
+        let _ = EarlyConsole.write_fmt(args);
+        return;
+    }
+
+    let mut console = CONSOLE.lock();
+
+    console.write_fmt(args).unwrap();
+
+    screen::flush();
+}
+
+/// Write the text on the screen to the first serial port, delimited so it can be extracted from
+/// a log
+pub fn mirror_to_serial() {
+    // Printing would wait for the console, so being busy is only said on the port
+    let Some(mut serial) = SERIAL.try_lock() else {
+        return;
+    };
+
+    let Some(console) = CONSOLE.try_lock() else {
+        let _ = writeln!(serial, "console: busy, try again later");
+        return;
+    };
+
+    let _ = writeln!(serial, "-----BEGIN FAJR SCREEN-----");
+    let _ = console.write_text(&mut *serial);
+    let _ = writeln!(serial, "-----END FAJR SCREEN-----");
+}
+
+/// Print `len` bytes of memory at `address` as a [`Hexdump`]
+pub fn hexdump(address: u64, len: usize) {
+    println!("{}", Hexdump::memory(address, len));
 }
@@ -0,0 +1,105 @@
+//! Intel P-states, picked by the processor itself when it has hardware P-states and asked for
+//! through the performance control register otherwise
+
+use super::{Driver, Governor};
+use crate::arch::cpu;
+
+const MSR_PLATFORM_INFO: u32 = 0xCE;
+const MSR_PERF_STATUS: u32 = 0x198;
+const MSR_PERF_CTL: u32 = 0x199;
+const MSR_PM_ENABLE: u32 = 0x770;
+const MSR_HWP_CAPABILITIES: u32 = 0x771;
+const MSR_HWP_REQUEST: u32 = 0x774;
+
+/// What the ratios multiply, since Sandy Bridge
+const BUS_CLOCK_KHZ: u64 = 100_000;
+
+/// The energy performance preferences hardware P-states are asked for, from 0 which is all
+/// performance to 255 which is all saving power
+const EPP_PERFORMANCE: u64 = 0x00;
+const EPP_BALANCE_POWER: u64 = 0xC0;
+
+pub struct IntelPState {
+    /// Whether the processor picks the frequency itself within the limits it is given
+    hwp: bool,
+    lowest_ratio: u8,
+    highest_ratio: u8,
+}
+
+impl Driver for IntelPState {
+    fn name(&self) -> &'static str {
+        if self.hwp {
+            "intel hwp"
+        } else {
+            "intel p-state"
+        }
+    }
+
+    fn min_khz(&self) -> u64 {
+        self.lowest_ratio as u64 * BUS_CLOCK_KHZ
+    }
+
+    fn max_khz(&self) -> u64 {
+        self.highest_ratio as u64 * BUS_CLOCK_KHZ
+    }
+
+    fn current_khz(&self) -> u64 {
+        ((cpu::rdmsr(MSR_PERF_STATUS) >> 8) & 0xFF) * BUS_CLOCK_KHZ
+    }
+
+    fn apply(&self, governor: Governor) {
+        let lowest = self.lowest_ratio as u64;
+        let highest = self.highest_ratio as u64;
+
+        if self.hwp {
+            let (min, epp) = match governor {
+                Governor::Performance => (highest, EPP_PERFORMANCE),
+                Governor::Powersave => (lowest, EPP_BALANCE_POWER),
+            };
+
+            // Zero as the desired performance leaves it to the processor
+            cpu::wrmsr(MSR_HWP_REQUEST, min | highest << 8 | epp << 24);
+        } else {
+            let ratio = match governor {
+                Governor::Performance => highest,
+                Governor::Powersave => lowest,
+            };
+
+            cpu::wrmsr(MSR_PERF_CTL, ratio << 8);
+        }
+    }
+}
+
+/// Find the P-states of the processor, only Intel's family 6 is known to have these registers
+pub fn probe() -> Option<IntelPState> {
+    if !cpu::is_intel() || cpu::family() != 6 {
+        return None;
+    }
+
+    let hwp = cpu::max_leaf() >= 6 && cpu::cpuid(6, 0).eax & (1 << 7) != 0;
+    let speedstep = cpu::cpuid(1, 0).ecx & (1 << 7) != 0;
+
+    let (lowest_ratio, highest_ratio) = if hwp {
+        // Once enabled, hardware P-states stay enabled until reset, hypervisors that say they have
+        // them do not always have the registers
+        if !cpu::wrmsr_safe(MSR_PM_ENABLE, 1) {
+            return None;
+        }
+
+        let capabilities = cpu::rdmsr_safe(MSR_HWP_CAPABILITIES)?;
+
+        ((capabilities >> 24) as u8, capabilities as u8)
+    } else if speedstep {
+        let info = cpu::rdmsr_safe(MSR_PLATFORM_INFO)?;
+
+        ((info >> 40) as u8, (info >> 8) as u8)
+    } else {
+        return None;
+    };
+
+    (lowest_ratio != 0 && highest_ratio >= lowest_ratio).then_some(IntelPState {
+        hwp,
+        lowest_ratio,
+        highest_ratio,
+    })
+}
@@ -0,0 +1,132 @@
+//! Frequency scaling, so the processor runs as fast or as frugally as asked instead of however
+//! the firmware left it
+
+pub mod intel;
+
+use alloc::boxed::Box;
+
+use crate::{cmdline, sync::Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Governor {
+    /// As fast as it goes
+    Performance,
+    /// As slow as it goes, or whatever the hardware picks while leaning towards saving power
+    Powersave,
+}
+
+impl Governor {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "performance" => Some(Self::Performance),
+            "powersave" => Some(Self::Powersave),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Performance => "performance",
+            Self::Powersave => "powersave",
+        }
+    }
+}
+
+pub trait Driver: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The range the governors work within, in kilohertz
+    fn min_khz(&self) -> u64;
+    fn max_khz(&self) -> u64;
+
+    /// What the processor is running at right now, in kilohertz
+    fn current_khz(&self) -> u64;
+
+    fn apply(&self, governor: Governor);
+}
+
+struct Policy {
+    driver: Box<dyn Driver>,
+    governor: Governor,
+}
+
+static POLICY: Mutex<Option<Policy>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoDriver,
+}
+
+pub fn governor() -> Option<Governor> {
+    POLICY.lock().as_ref().map(|policy| policy.governor)
+}
+
+pub fn set_governor(governor: Governor) -> Result<(), Error> {
+    let mut policy = POLICY.lock();
+    let policy = policy.as_mut().ok_or(Error::NoDriver)?;
+
+    policy.driver.apply(governor);
+    policy.governor = governor;
+
+    Ok(())
+}
+
+/// Apply the governor again after the processor forgot it while asleep
+pub fn resume() {
+    if let Some(policy) = POLICY.lock().as_ref() {
+        policy.driver.apply(policy.governor);
+    }
+}
+
+pub fn current_khz() -> Option<u64> {
+    POLICY
+        .lock()
+        .as_ref()
+        .map(|policy| policy.driver.current_khz())
+}
+
+pub fn dump() {
+    let Some(policy) = POLICY.try_lock() else {
+        println!("cpufreq: busy, try again later");
+        return;
+    };
+
+    let Some(policy) = policy.as_ref() else {
+        println!("cpufreq: no frequency scaling");
+        return;
+    };
+
+    println!(
+        "cpufreq: {}, {} governor, {} mhz within {} to {} mhz",
+        policy.driver.name(),
+        policy.governor.name(),
+        policy.driver.current_khz() / 1000,
+        policy.driver.min_khz() / 1000,
+        policy.driver.max_khz() / 1000
+    );
+}
+
+/// Find a driver and apply the governor from `cpufreq=<governor>`, performance by default
+pub fn init() {
+    let Some(driver) = intel::probe() else {
+        println!("cpufreq: no frequency scaling");
+        return;
+    };
+
+    let governor = match cmdline::value("cpufreq") {
+        Some(name) => Governor::from_name(name).unwrap_or_else(|| {
+            println!("cpufreq: unknown governor {}, using performance", name);
+            Governor::Performance
+        }),
+        None => Governor::Performance,
+    };
+
+    driver.apply(governor);
+
+    *POLICY.lock() = Some(Policy {
+        driver: Box::new(driver),
+        governor,
+    });
+
+    dump();
+}
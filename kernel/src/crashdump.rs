@@ -0,0 +1,123 @@
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use crate::{
+    arch::{
+        backtrace,
+        registers::Registers,
+        serial::{COM1, SerialPort},
+    },
+    cmdline,
+    console::{self, CONSOLE, Hexdump},
+    memory::GLOBAL_BUDDY_ALLOCATOR,
+    paging,
+    requests::EXECUTABLE_ADDRESS_REQUEST,
+    stack, symbols,
+};
+
+/// How many bytes of the stack from where the panic was are in the dump
+const STACK_DUMP_LEN: usize = 512;
+
+/// Whether a crash dump was asked for, using `crashdump=serial` on the command line
+pub fn enabled() -> bool {
+    cmdline::value("crashdump") == Some("serial")
+}
+
+/// Write a minidump of the current state of the kernel to the first serial port, delimited so it
+/// can be extracted from a log by tooling on the other side
+pub fn write(info: &PanicInfo) {
+    let registers = Registers::capture();
+
+    // The serial port lock may be held by the code that panicked, so we take the port over
+    // directly, we are never going back to that code anyway
+    let mut serial = SerialPort::new(COM1);
+    serial.init();
+
+    let _ = writeln!(serial, "-----BEGIN FAJR CRASH DUMP-----");
+    let _ = writeln!(serial, "version: 1");
+    let _ = writeln!(serial, "cmdline: {}", cmdline::get());
+
+    // We must not panic again here, so the kernel address is not asked from paging unless it is
+    // known to be available
+    if EXECUTABLE_ADDRESS_REQUEST.get_response().is_some() {
+        let _ = writeln!(serial, "kernel slide: {:#x}", paging::kernel_slide());
+    }
+
+    if let Some(location) = info.location() {
+        let _ = writeln!(serial, "location: {}", location);
+    }
+
+    let _ = writeln!(serial, "message: {}", info.message());
+
+    let _ = writeln!(serial, "cpu 0 registers:");
+    let _ = writeln!(serial, "{}", registers);
+
+    let _ = writeln!(serial, "cpu 0 stack:");
+    let _ = writeln!(serial, "{}", Hexdump::memory(registers.rsp, STACK_DUMP_LEN));
+
+    let _ = writeln!(serial, "cpu 0 backtrace:");
+
+    let mut depth = 0;
+
+    backtrace::walk(|address| {
+        match symbols::find(address) {
+            Some((symbol, offset)) => {
+                let _ = writeln!(
+                    serial,
+                    "#{:<2} {:#018x} {}+{:#x}",
+                    depth,
+                    address,
+                    symbol.name(),
+                    offset
+                );
+            }
+
+            None => {
+                let _ = writeln!(serial, "#{:<2} {:#018x}", depth, address);
+            }
+        }
+
+        depth += 1;
+    });
+
+    match GLOBAL_BUDDY_ALLOCATOR.try_lock() {
+        Some(allocator) => {
+            let _ = writeln!(
+                serial,
+                "heap free bytes: {}",
+                allocator.calculate_free_bytes()
+            );
+        }
+
+        None => {
+            let _ = writeln!(serial, "heap free bytes: unavailable, allocator is locked");
+        }
+    }
+
+    if let Some(stacks) = stack::try_registered() {
+        let _ = writeln!(serial, "stacks:");
+
+        for stack in stacks.iter().flatten() {
+            let _ = writeln!(
+                serial,
+                "{}: {}/{} bytes, canary {}",
+                stack.name,
+                stack.high_water(),
+                stack.size,
+                if stack.is_canary_intact() {
+                    "intact"
+                } else {
+                    "smashed"
+                }
+            );
+        }
+    }
+
+    // What the panic handler drew is there too, unless it is still drawing as it panicked again
+    if let Some(console) = console::is_ready().then(|| CONSOLE.try_lock()).flatten() {
+        let _ = writeln!(serial, "screen:");
+        let _ = console.write_text(&mut serial);
+    }
+
+    let _ = writeln!(serial, "-----END FAJR CRASH DUMP-----");
+}
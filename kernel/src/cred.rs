@@ -0,0 +1,260 @@
+//! The users and groups processes run as, and what that lets them do to files
+//!
+//! A process has a real, an effective and a saved user and group id, inherited from its parent.
+//! The effective ones are checked against the owner, group and mode bits of a file, except for
+//! the superuser who can do anything but execute files no one can. The kernel itself, running
+//! without a current process, is never checked
+
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+
+use crate::process::{self, Error};
+
+pub type Uid = u32;
+pub type Gid = u32;
+
+/// What `-1` is given as to the `setres*id` calls, leaving an id as it is
+pub const UNCHANGED: u32 = u32::MAX;
+
+const MODE_EXECUTE_ANYONE: u32 = 0o111;
+
+bitflags! {
+    /// What is to be done to a file, as the bits of each class in its mode
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Access: u32 {
+        const READ = 4;
+        const WRITE = 2;
+        const EXECUTE = 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: Uid,
+    pub euid: Uid,
+    pub suid: Uid,
+    pub gid: Gid,
+    pub egid: Gid,
+    pub sgid: Gid,
+    /// The supplementary groups
+    pub groups: Vec<Gid>,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials {
+        uid: 0,
+        euid: 0,
+        suid: 0,
+        gid: 0,
+        egid: 0,
+        sgid: 0,
+        groups: Vec::new(),
+    };
+
+    pub fn is_superuser(&self) -> bool {
+        self.euid == 0
+    }
+
+    pub fn in_group(&self, gid: Gid) -> bool {
+        self.egid == gid || self.groups.contains(&gid)
+    }
+
+    /// Whether `access` is allowed to a file with `mode` owned by `owner` and `group`
+    pub fn may_access(&self, mode: u32, owner: Uid, group: Gid, access: Access) -> bool {
+        if self.is_superuser() {
+            return !access.contains(Access::EXECUTE) || mode & MODE_EXECUTE_ANYONE != 0;
+        }
+
+        // Only the first class that matches counts, an owner can have less than everyone else
+        let allowed = if self.euid == owner {
+            mode >> 6
+        } else if self.in_group(group) {
+            mode >> 3
+        } else {
+            mode
+        };
+
+        Access::from_bits_truncate(allowed).contains(access)
+    }
+}
+
+/// Whether the current process may do `access` to a file, which the kernel always may
+pub fn may_access(mode: u32, owner: Uid, group: Gid, access: Access) -> bool {
+    process::current().is_none_or(|current| {
+        current
+            .process()
+            .credentials()
+            .may_access(mode, owner, group, access)
+    })
+}
+
+/// Whether the current process runs as the superuser, which the kernel counts as
+pub fn is_superuser() -> bool {
+    process::current().is_none_or(|current| current.process().credentials().is_superuser())
+}
+
+/// Whether the current process may trace `target`, which has to run with its real user and group
+/// in every one of its ids unless the tracer is the superuser, like `ptrace_may_access`
+pub fn may_trace(target: &Credentials) -> bool {
+    let Some(current) = process::current() else {
+        return true;
+    };
+
+    let tracer = current.process().credentials();
+
+    tracer.is_superuser()
+        || [target.uid, target.euid, target.suid]
+            .iter()
+            .all(|&uid| uid == tracer.uid)
+            && [target.gid, target.egid, target.sgid]
+                .iter()
+                .all(|&gid| gid == tracer.gid)
+}
+
+fn current() -> Result<Credentials, Error> {
+    Ok(process::current()
+        .ok_or(Error::NoThread)?
+        .process()
+        .credentials())
+}
+
+fn update(change: impl FnOnce(&mut Credentials) -> Result<(), Error>) -> Result<(), Error> {
+    let current = process::current().ok_or(Error::NoThread)?;
+    let mut credentials = current.process().credentials();
+
+    change(&mut credentials)?;
+
+    current.process().set_credentials(credentials);
+
+    Ok(())
+}
+
+pub fn getuid() -> Result<Uid, Error> {
+    Ok(current()?.uid)
+}
+
+pub fn geteuid() -> Result<Uid, Error> {
+    Ok(current()?.euid)
+}
+
+pub fn getgid() -> Result<Gid, Error> {
+    Ok(current()?.gid)
+}
+
+pub fn getegid() -> Result<Gid, Error> {
+    Ok(current()?.egid)
+}
+
+pub fn getresuid() -> Result<(Uid, Uid, Uid), Error> {
+    let credentials = current()?;
+
+    Ok((credentials.uid, credentials.euid, credentials.suid))
+}
+
+pub fn getresgid() -> Result<(Gid, Gid, Gid), Error> {
+    let credentials = current()?;
+
+    Ok((credentials.gid, credentials.egid, credentials.sgid))
+}
+
+pub fn getgroups() -> Result<Vec<Gid>, Error> {
+    Ok(current()?.groups)
+}
+
+/// Set all three user ids as the superuser, otherwise only the effective one to the real or
+/// saved one
+pub fn setuid(uid: Uid) -> Result<(), Error> {
+    update(|credentials| {
+        if credentials.is_superuser() {
+            credentials.uid = uid;
+            credentials.suid = uid;
+        } else if uid != credentials.uid && uid != credentials.suid {
+            return Err(Error::PermissionDenied);
+        }
+
+        credentials.euid = uid;
+
+        Ok(())
+    })
+}
+
+/// Set all three group ids as the superuser, otherwise only the effective one to the real or
+/// saved one
+pub fn setgid(gid: Gid) -> Result<(), Error> {
+    update(|credentials| {
+        if credentials.is_superuser() {
+            credentials.gid = gid;
+            credentials.sgid = gid;
+        } else if gid != credentials.gid && gid != credentials.sgid {
+            return Err(Error::PermissionDenied);
+        }
+
+        credentials.egid = gid;
+
+        Ok(())
+    })
+}
+
+/// Change each of `ids` to what is wanted of it unless that is [`UNCHANGED`], which is one of the
+/// three unless done by the superuser
+fn set_ids(ids: [&mut u32; 3], wanted: [u32; 3], superuser: bool) -> Result<(), Error> {
+    let current = [*ids[0], *ids[1], *ids[2]];
+
+    let allowed = wanted
+        .iter()
+        .all(|&id| id == UNCHANGED || superuser || current.contains(&id));
+
+    if !allowed {
+        return Err(Error::PermissionDenied);
+    }
+
+    for (id, wanted) in ids.into_iter().zip(wanted) {
+        if wanted != UNCHANGED {
+            *id = wanted;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn setresuid(uid: Uid, euid: Uid, suid: Uid) -> Result<(), Error> {
+    update(|credentials| {
+        let superuser = credentials.is_superuser();
+        let Credentials {
+            uid: real,
+            euid: effective,
+            suid: saved,
+            ..
+        } = credentials;
+
+        set_ids([real, effective, saved], [uid, euid, suid], superuser)
+    })
+}
+
+pub fn setresgid(gid: Gid, egid: Gid, sgid: Gid) -> Result<(), Error> {
+    update(|credentials| {
+        let superuser = credentials.is_superuser();
+        let Credentials {
+            gid: real,
+            egid: effective,
+            sgid: saved,
+            ..
+        } = credentials;
+
+        set_ids([real, effective, saved], [gid, egid, sgid], superuser)
+    })
+}
+
+/// Set the supplementary groups, which only the superuser can
+pub fn setgroups(groups: &[Gid]) -> Result<(), Error> {
+    update(|credentials| {
+        if !credentials.is_superuser() {
+            return Err(Error::PermissionDenied);
+        }
+
+        credentials.groups = groups.to_vec();
+
+        Ok(())
+    })
+}
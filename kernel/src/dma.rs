@@ -0,0 +1,334 @@
+//! Memory that devices can access directly
+//!
+//! Memory on x86_64 stays cache coherent with device accesses, so the map and unmap helpers only
+//! need to keep the compiler and the processor from reordering accesses around the transfer
+
+use alloc::alloc::Global;
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::NonNull,
+    sync::atomic::{Ordering, fence},
+};
+
+use crate::{
+    cma,
+    frame::{self, ZoneKind},
+    memory::{self, DMA32_LIMIT, GLOBAL_DMA32_ALLOCATOR},
+    numa,
+    paging::{self, KERNEL_LINK_BASE},
+};
+
+pub const PAGE_SIZE: usize = crate::arch::paging::PAGE_SIZE as usize;
+
+/// What a device needs from the memory it accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Constraints {
+    /// The alignment of the bus address, must be a power of two
+    pub align: usize,
+    /// The bus address after the last one the device can reach
+    pub limit: u64,
+}
+
+impl Constraints {
+    /// Page aligned memory anywhere
+    pub const fn new() -> Self {
+        Self {
+            align: PAGE_SIZE,
+            limit: u64::MAX,
+        }
+    }
+
+    /// Restrict the memory to below 4 GiB for devices with 32-bit addressing
+    pub const fn below_4gib(self) -> Self {
+        Self {
+            limit: DMA32_LIMIT,
+            ..self
+        }
+    }
+
+    pub const fn aligned(self, align: usize) -> Self {
+        Self { align, ..self }
+    }
+
+    fn allows(&self, bus: u64, len: usize) -> bool {
+        bus.is_multiple_of(self.align as u64)
+            && bus
+                .checked_add(len as u64)
+                .is_some_and(|end| end <= self.limit)
+    }
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pool {
+    Heap,
+    Dma32,
+    /// Whole pages from the frame zones of that kind
+    Frames(ZoneKind),
+    /// Whole pages from the contiguous area, aligned like asked
+    Cma {
+        align: usize,
+        limit: u64,
+    },
+}
+
+impl Pool {
+    fn allocate_zeroed(self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self {
+            Pool::Heap => Global.allocate_zeroed(layout),
+            Pool::Dma32 => GLOBAL_DMA32_ALLOCATOR.allocate_zeroed(layout),
+            Pool::Frames(kind) => {
+                let pages = layout.size().div_ceil(PAGE_SIZE);
+                let phys = frame::alloc_in(kind, numa::current_node(), pages).ok_or(AllocError)?;
+                let virt = paging::virt_from_phys(phys) as *mut u8;
+
+                unsafe { virt.write_bytes(0, pages * PAGE_SIZE) };
+
+                Ok(NonNull::slice_from_raw_parts(
+                    unsafe { NonNull::new_unchecked(virt) },
+                    pages * PAGE_SIZE,
+                ))
+            }
+            Pool::Cma { align, limit } => {
+                let pages = layout.size().div_ceil(PAGE_SIZE);
+                let phys = cma::alloc(pages, align as u64, limit).ok_or(AllocError)?;
+                let virt = paging::virt_from_phys(phys) as *mut u8;
+
+                unsafe { virt.write_bytes(0, pages * PAGE_SIZE) };
+
+                Ok(NonNull::slice_from_raw_parts(
+                    unsafe { NonNull::new_unchecked(virt) },
+                    pages * PAGE_SIZE,
+                ))
+            }
+        }
+    }
+
+    unsafe fn deallocate(self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe {
+            match self {
+                Pool::Heap => Global.deallocate(ptr, layout),
+                Pool::Dma32 => GLOBAL_DMA32_ALLOCATOR.deallocate(ptr, layout),
+                Pool::Frames(_) => frame::free(
+                    paging::phys_from_virt(ptr.addr().get() as u64),
+                    layout.size().div_ceil(PAGE_SIZE),
+                ),
+                Pool::Cma { .. } => cma::free(
+                    paging::phys_from_virt(ptr.addr().get() as u64),
+                    layout.size().div_ceil(PAGE_SIZE),
+                ),
+            }
+        }
+    }
+}
+
+/// Physically contiguous memory shared with a device, freed on drop
+pub struct CoherentBuffer {
+    virt: NonNull<u8>,
+    bus: u64,
+    len: usize,
+    allocation: NonNull<u8>,
+    layout: Layout,
+    pool: Pool,
+}
+
+unsafe impl Send for CoherentBuffer {}
+unsafe impl Sync for CoherentBuffer {}
+
+impl CoherentBuffer {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt.as_ptr()
+    }
+
+    /// The address the device should be given
+    pub fn bus_address(&self) -> u64 {
+        self.bus
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for CoherentBuffer {
+    fn drop(&mut self) {
+        unsafe { self.pool.deallocate(self.allocation, self.layout) };
+    }
+}
+
+/// Allocate zeroed, physically contiguous memory the device can reach
+///
+/// The heap is used when it satisfies the constraints, otherwise memory is taken from the pool
+/// below 4 GiB, and then from the frame zones, below 4 GiB as well if the device needs it
+pub fn alloc_coherent(len: usize, constraints: Constraints) -> Result<CoherentBuffer, AllocError> {
+    if !constraints.align.is_power_of_two() {
+        return Err(AllocError);
+    }
+
+    if let Ok(buffer) = alloc_coherent_in(Pool::Heap, len, constraints) {
+        return Ok(buffer);
+    }
+
+    if constraints.limit < memory::heap_phys_range().end
+        && memory::has_dma32_memory()
+        && let Ok(buffer) = alloc_coherent_in(Pool::Dma32, len, constraints)
+    {
+        return Ok(buffer);
+    }
+
+    let kind = match constraints.limit <= DMA32_LIMIT {
+        true => ZoneKind::Dma32,
+        false => ZoneKind::Normal,
+    };
+
+    alloc_coherent_in(Pool::Frames(kind), len, constraints)
+}
+
+/// Allocate zeroed, physically contiguous memory the device can reach, for large buffers
+///
+/// They are taken from the contiguous area set aside with `cma=` when there is one with room,
+/// and otherwise allocated like [`alloc_coherent`] does
+pub fn alloc_contiguous(
+    len: usize,
+    constraints: Constraints,
+) -> Result<CoherentBuffer, AllocError> {
+    if !constraints.align.is_power_of_two() {
+        return Err(AllocError);
+    }
+
+    let pool = Pool::Cma {
+        align: constraints.align,
+        limit: constraints.limit,
+    };
+
+    alloc_coherent_in(pool, len, constraints).or_else(|_| alloc_coherent(len, constraints))
+}
+
+fn alloc_coherent_in(
+    pool: Pool,
+    len: usize,
+    constraints: Constraints,
+) -> Result<CoherentBuffer, AllocError> {
+    // The buddy allocator does not honor large alignments, so allocate enough to align ourselves,
+    // while frames are aligned to their size already and the area like asked
+    let padding = match pool {
+        Pool::Frames(_) | Pool::Cma { .. } => 0,
+        _ => constraints.align,
+    };
+
+    let layout = Layout::from_size_align(len.checked_add(padding).ok_or(AllocError)?, 16)
+        .map_err(|_| AllocError)?;
+
+    let allocation = pool.allocate_zeroed(layout)?.cast::<u8>();
+
+    let offset = allocation.as_ptr().align_offset(constraints.align);
+    let virt = unsafe { allocation.add(offset) };
+    let bus = paging::phys_from_virt(virt.addr().get() as u64);
+
+    if !constraints.allows(bus, len) {
+        unsafe { pool.deallocate(allocation, layout) };
+
+        return Err(AllocError);
+    }
+
+    Ok(CoherentBuffer {
+        virt,
+        bus,
+        len,
+        allocation,
+        layout,
+        pool,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The device reads the memory
+    ToDevice,
+    /// The device writes the memory
+    FromDevice,
+    Bidirectional,
+}
+
+/// A buffer handed to a device for a single transfer, must be given back to [`unmap`] once the
+/// device is done with it before the buffer is touched again
+#[derive(Debug)]
+#[must_use]
+pub struct Mapping {
+    bus: u64,
+    len: usize,
+    direction: Direction,
+}
+
+impl Mapping {
+    pub fn bus_address(&self) -> u64 {
+        self.bus
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+}
+
+/// Find the physical address of memory in the direct map or the kernel executable, both of which
+/// are physically contiguous
+fn bus_address_of(virt: u64) -> u64 {
+    if virt >= KERNEL_LINK_BASE {
+        paging::kernel_phys_from_virt(virt)
+    } else {
+        paging::phys_from_virt(virt)
+    }
+}
+
+/// Hand an existing buffer to a device, fails if the buffer does not satisfy the constraints
+pub fn map(buffer: &[u8], direction: Direction, constraints: Constraints) -> Option<Mapping> {
+    let bus = bus_address_of(buffer.as_ptr() as u64);
+
+    if !constraints.allows(bus, buffer.len()) {
+        return None;
+    }
+
+    if direction != Direction::FromDevice {
+        // Everything written to the buffer must be visible before the device is told about it
+        fence(Ordering::SeqCst);
+    }
+
+    Some(Mapping {
+        bus,
+        len: buffer.len(),
+        direction,
+    })
+}
+
+/// Take a buffer back from a device after the transfer completed
+pub fn unmap(mapping: Mapping) {
+    if mapping.direction != Direction::ToDevice {
+        // Nothing may be read from the buffer before the device finished writing it
+        fence(Ordering::SeqCst);
+    }
+}
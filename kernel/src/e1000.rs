@@ -0,0 +1,478 @@
+//! The Intel 8254x (e1000) and 8257x (e1000e) network controllers
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering, fence};
+
+use crate::{
+    dma::{self, CoherentBuffer, Constraints},
+    mmio::Mmio,
+    net::{self, MacAddress, NetDevice, PacketBuffer},
+    pci::{self, Bar},
+    sync::Mutex,
+    time,
+};
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+/// The models and whether they are the PCIe ones with the different EEPROM read register
+const DEVICES: &[(u16, bool)] = &[
+    (0x1004, false), // 82543GC
+    (0x100E, false), // 82540EM, what QEMU emulates as e1000
+    (0x100F, false), // 82545EM, what VMware emulates
+    (0x1015, false), // 82540EM LOM
+    (0x1019, false), // 82547EI
+    (0x101E, false), // 82540EP LP
+    (0x1026, false), // 82545GM
+    (0x1076, false), // 82541GI
+    (0x107C, false), // 82541PI
+    (0x105E, true),  // 82571EB
+    (0x107D, true),  // 82572EI
+    (0x108C, true),  // 82573E
+    (0x109A, true),  // 82573L
+    (0x10D3, true),  // 82574L, what QEMU emulates as e1000e
+    (0x10F6, true),  // 82574LA
+    (0x150C, true),  // 82583V
+];
+
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00C0;
+const REG_IMC: usize = 0x00D8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_LU: u32 = 1 << 1;
+
+const EERD_START: u32 = 1 << 0;
+
+const ICR_LSC: u32 = 1 << 2;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the CRC, so frames come up without it
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// The inter packet gaps recommended for copper
+const TIPG_DEFAULT: u32 = 10 | (8 << 10) | (6 << 20);
+
+const RAH_AV: u32 = 1 << 31;
+
+const DESCRIPTOR_STATUS_DD: u8 = 1 << 0;
+const DESCRIPTOR_STATUS_EOP: u8 = 1 << 1;
+
+const COMMAND_EOP: u8 = 1 << 0;
+const COMMAND_IFCS: u8 = 1 << 1;
+const COMMAND_RS: u8 = 1 << 3;
+
+/// A multiple of 8, the ring sizes must be multiples of 128 bytes
+const RING_SIZE: usize = 128;
+/// What `RCTL` asks for with a buffer size of zero
+const BUFFER_SIZE: usize = 2048;
+const DESCRIPTOR_SIZE: usize = 16;
+
+const MTU: usize = 1500;
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+const TIMEOUT_NS: u64 = 10_000_000;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct ReceiveDescriptor {
+    address: u64,
+    len: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct TransmitDescriptor {
+    address: u64,
+    len: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+/// A descriptor ring and one buffer per descriptor
+struct Ring {
+    descriptors: CoherentBuffer,
+    buffers: CoherentBuffer,
+    /// The next descriptor to look at, or to fill
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Option<Self> {
+        Some(Self {
+            descriptors: dma::alloc_coherent(RING_SIZE * DESCRIPTOR_SIZE, Constraints::new())
+                .ok()?,
+            buffers: dma::alloc_coherent(RING_SIZE * BUFFER_SIZE, Constraints::new()).ok()?,
+            next: 0,
+        })
+    }
+
+    fn read<T: Copy>(&self, index: usize) -> T {
+        unsafe {
+            (self.descriptors.as_ptr() as *const T)
+                .add(index)
+                .read_volatile()
+        }
+    }
+
+    fn write<T: Copy>(&mut self, index: usize, descriptor: T) {
+        unsafe {
+            (self.descriptors.as_ptr() as *mut T)
+                .add(index)
+                .write_volatile(descriptor)
+        }
+    }
+
+    fn buffer_address(&self, index: usize) -> u64 {
+        self.buffers.bus_address() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffers.as_mut_slice()[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE]
+    }
+}
+
+struct Inner {
+    registers: Mmio,
+    receive: Ring,
+    transmit: Ring,
+}
+
+pub struct E1000 {
+    inner: Mutex<Inner>,
+    mac: MacAddress,
+    link_up: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Register bar 0 is not a memory bar
+    MissingBar,
+    ResetTimedOut,
+    OutOfMemory,
+}
+
+/// Read a word of the EEPROM, the PCIe models moved the done bit and the address
+fn read_eeprom(registers: &Mmio, pcie: bool, word: u8) -> Option<u16> {
+    let (address_shift, done) = if pcie { (2, 1 << 1) } else { (8, 1 << 4) };
+
+    registers.write(REG_EERD, EERD_START | (word as u32) << address_shift);
+
+    let deadline = time::monotonic_ns() + TIMEOUT_NS;
+
+    loop {
+        let value = registers.read::<u32>(REG_EERD);
+
+        if value & done != 0 {
+            return Some((value >> 16) as u16);
+        }
+
+        if time::monotonic_ns() >= deadline {
+            return None;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// The address from the EEPROM, or the one the firmware left in the first receive address
+fn read_mac(registers: &Mmio, pcie: bool) -> MacAddress {
+    let words = [0, 1, 2].map(|word| read_eeprom(registers, pcie, word));
+
+    if let [Some(a), Some(b), Some(c)] = words {
+        let [a0, a1] = a.to_le_bytes();
+        let [b0, b1] = b.to_le_bytes();
+        let [c0, c1] = c.to_le_bytes();
+
+        return MacAddress([a0, a1, b0, b1, c0, c1]);
+    }
+
+    let low = registers.read::<u32>(REG_RAL).to_le_bytes();
+    let high = registers.read::<u32>(REG_RAH).to_le_bytes();
+
+    MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+}
+
+impl E1000 {
+    pub fn new(device: &pci::Device, pcie: bool) -> Result<Self, Error> {
+        let Some(Bar::Memory { address, size, .. }) = device.bar(0) else {
+            return Err(Error::MissingBar);
+        };
+
+        device.enable_bus_master();
+
+        let registers = Mmio::map(address, size as usize);
+
+        registers.write(REG_IMC, u32::MAX);
+        registers.write(REG_CTRL, registers.read::<u32>(REG_CTRL) | CTRL_RST);
+
+        let deadline = time::monotonic_ns() + TIMEOUT_NS;
+
+        // The datasheet asks for a microsecond before touching it again
+        time::delay_ns(1000);
+
+        while registers.read::<u32>(REG_CTRL) & CTRL_RST != 0 {
+            if time::monotonic_ns() >= deadline {
+                return Err(Error::ResetTimedOut);
+            }
+
+            core::hint::spin_loop();
+        }
+
+        // Interrupts are not routed yet, the driver is polled and reads the causes itself
+        registers.write(REG_IMC, u32::MAX);
+        registers.read::<u32>(REG_ICR);
+
+        registers.write(
+            REG_CTRL,
+            registers.read::<u32>(REG_CTRL) | CTRL_SLU | CTRL_ASDE,
+        );
+
+        let mac = read_mac(&registers, pcie);
+
+        let [m0, m1, m2, m3, m4, m5] = mac.0;
+
+        registers.write(REG_RAL, u32::from_le_bytes([m0, m1, m2, m3]));
+        registers.write(REG_RAH, u32::from_le_bytes([m4, m5, 0, 0]) | RAH_AV);
+
+        for i in 0..128 {
+            registers.write(REG_MTA + i * 4, 0u32);
+        }
+
+        let mut receive = Ring::new().ok_or(Error::OutOfMemory)?;
+        let mut transmit = Ring::new().ok_or(Error::OutOfMemory)?;
+
+        for i in 0..RING_SIZE {
+            let address = receive.buffer_address(i);
+
+            receive.write(
+                i,
+                ReceiveDescriptor {
+                    address,
+                    ..Default::default()
+                },
+            );
+
+            // Done from the start, so the first transmits find free descriptors
+            transmit.write(
+                i,
+                TransmitDescriptor {
+                    status: DESCRIPTOR_STATUS_DD,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let ring_len = (RING_SIZE * DESCRIPTOR_SIZE) as u32;
+
+        let receive_base = receive.descriptors.bus_address();
+
+        registers.write(REG_RDBAL, receive_base as u32);
+        registers.write(REG_RDBAH, (receive_base >> 32) as u32);
+        registers.write(REG_RDLEN, ring_len);
+        registers.write(REG_RDH, 0u32);
+        registers.write(REG_RDT, (RING_SIZE - 1) as u32);
+        registers.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let transmit_base = transmit.descriptors.bus_address();
+
+        registers.write(REG_TDBAL, transmit_base as u32);
+        registers.write(REG_TDBAH, (transmit_base >> 32) as u32);
+        registers.write(REG_TDLEN, ring_len);
+        registers.write(REG_TDH, 0u32);
+        registers.write(REG_TDT, 0u32);
+        registers.write(REG_TIPG, TIPG_DEFAULT);
+        registers.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        let link_up = registers.read::<u32>(REG_STATUS) & STATUS_LU != 0;
+
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                registers,
+                receive,
+                transmit,
+            }),
+            mac,
+            link_up: AtomicBool::new(link_up),
+        })
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::Relaxed)
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn transmit(&self, packet: &PacketBuffer) -> Result<(), net::Error> {
+        let frame = packet.data();
+
+        if frame.len() > ETHERNET_HEADER_SIZE + MTU {
+            return Err(net::Error::TooLarge);
+        }
+
+        let mut inner = self.inner.lock();
+        let Inner {
+            registers,
+            transmit,
+            ..
+        } = &mut *inner;
+
+        let index = transmit.next;
+
+        // The device has not sent what was in it the last time around the ring
+        if transmit.read::<TransmitDescriptor>(index).status & DESCRIPTOR_STATUS_DD == 0 {
+            return Err(net::Error::Busy);
+        }
+
+        transmit.buffer(index)[..frame.len()].copy_from_slice(frame);
+
+        let address = transmit.buffer_address(index);
+
+        transmit.write(
+            index,
+            TransmitDescriptor {
+                address,
+                len: frame.len() as u16,
+                command: COMMAND_EOP | COMMAND_IFCS | COMMAND_RS,
+                ..Default::default()
+            },
+        );
+
+        transmit.next = (index + 1) % RING_SIZE;
+
+        fence(Ordering::SeqCst);
+
+        registers.write(REG_TDT, transmit.next as u32);
+
+        Ok(())
+    }
+
+    fn poll(&self, rx: &mut dyn FnMut(PacketBuffer)) {
+        let mut inner = self.inner.lock();
+        let Inner {
+            registers, receive, ..
+        } = &mut *inner;
+
+        // Reading the causes clears them
+        if registers.read::<u32>(REG_ICR) & ICR_LSC != 0 {
+            let link_up = registers.read::<u32>(REG_STATUS) & STATUS_LU != 0;
+
+            if self.link_up.swap(link_up, Ordering::Relaxed) != link_up {
+                crate::log_info!(
+                    "e1000",
+                    "{} link {}",
+                    self.mac,
+                    if link_up { "up" } else { "down" }
+                );
+            }
+        }
+
+        let mut last = None;
+
+        loop {
+            let index = receive.next;
+            let descriptor = receive.read::<ReceiveDescriptor>(index);
+
+            if descriptor.status & DESCRIPTOR_STATUS_DD == 0 {
+                break;
+            }
+
+            fence(Ordering::SeqCst);
+
+            let len = (descriptor.len as usize).min(BUFFER_SIZE);
+
+            // Frames never span buffers with the maximum frame size below the buffer size
+            if descriptor.status & DESCRIPTOR_STATUS_EOP != 0 && descriptor.errors == 0 {
+                rx(PacketBuffer::from_slice(&receive.buffer(index)[..len]));
+            }
+
+            let address = receive.buffer_address(index);
+
+            receive.write(
+                index,
+                ReceiveDescriptor {
+                    address,
+                    ..Default::default()
+                },
+            );
+
+            last = Some(index);
+            receive.next = (index + 1) % RING_SIZE;
+        }
+
+        // Give the buffers back, the tail is the last descriptor that is ours
+        if let Some(last) = last {
+            fence(Ordering::SeqCst);
+
+            registers.write(REG_RDT, last as u32);
+        }
+    }
+}
+
+pub fn init() {
+    for device in pci::devices() {
+        if device.vendor_id != VENDOR_INTEL {
+            continue;
+        }
+
+        let Some(&(_, pcie)) = DEVICES.iter().find(|(id, _)| *id == device.device_id) else {
+            continue;
+        };
+
+        match E1000::new(device, pcie) {
+            Ok(driver) => {
+                crate::log_ok!(
+                    "e1000",
+                    "{} with mac {}, link {}",
+                    device.address,
+                    driver.mac,
+                    if driver.link_up() { "up" } else { "down" }
+                );
+
+                net::register(Arc::new(driver));
+            }
+            Err(error) => crate::log_error!("e1000", "{}: {:?}", device.address, error),
+        }
+    }
+}
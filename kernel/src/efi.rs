@@ -0,0 +1,460 @@
+//! EFI runtime services, the part of the firmware that stays around after boot
+//!
+//! The firmware is never told to move, so its runtime regions are mapped at their physical
+//! addresses and it is called with the kernel's page tables as they are
+
+use alloc::{string::String, vec, vec::Vec};
+use core::{fmt, ptr};
+
+use crate::{
+    arch::paging::PageTableFlags,
+    mmio::Mmio,
+    paging,
+    requests::{EFI_MEMORY_MAP_REQUEST, EFI_SYSTEM_TABLE_REQUEST},
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+const SYSTEM_TABLE_SIGNATURE: u64 = 0x5453_5953_2049_4249;
+const RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544E_5552;
+
+const MEMORY_RUNTIME_SERVICES_CODE: u32 = 5;
+const MEMORY_MAPPED_IO: u32 = 11;
+const MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+
+/// The attribute of memory the firmware still needs after boot
+const MEMORY_RUNTIME: u64 = 1 << 63;
+
+const STATUS_ERROR: usize = 1 << 63;
+const STATUS_UNSUPPORTED: usize = STATUS_ERROR | 3;
+const STATUS_BUFFER_TOO_SMALL: usize = STATUS_ERROR | 5;
+const STATUS_NOT_FOUND: usize = STATUS_ERROR | 14;
+
+/// The time zone of a time that does not say which, taken to be UTC
+const UNSPECIFIED_TIMEZONE: i16 = 0x07FF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not booted with EFI, or the runtime services could not be found
+    Unavailable,
+    Unsupported,
+    NotFound,
+    InvalidName,
+    /// Any other error status, without the error bit
+    Status(usize),
+}
+
+fn check(status: usize) -> Result<(), Error> {
+    match status {
+        status if status & STATUS_ERROR == 0 => Ok(()),
+        STATUS_UNSUPPORTED => Err(Error::Unsupported),
+        STATUS_NOT_FOUND => Err(Error::NotFound),
+        status => Err(Error::Status(status & !STATUS_ERROR)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    /// The variables defined by the EFI specification, like `BootOrder`
+    pub const GLOBAL_VARIABLE: Self = Self {
+        data1: 0x8BE4DF61,
+        data2: 0x93CA,
+        data3: 0x11D2,
+        data4: [0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C],
+    };
+
+    /// The kernel's own variables, kept across boots
+    pub const FAJR: Self = Self {
+        data1: 0x6D1E5C1F,
+        data2: 0x3B5A,
+        data3: 0x4F0E,
+        data4: [0x9A, 0x63, 0x2E, 0x71, 0xC4, 0x0B, 0xD8, 0x52],
+    };
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            self.data1, self.data2, self.data3, self.data4[0], self.data4[1]
+        )?;
+
+        for byte in &self.data4[2..] {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Attributes: u32 {
+        const NON_VOLATILE = 1;
+        const BOOTSERVICE_ACCESS = 1 << 1;
+        const RUNTIME_ACCESS = 1 << 2;
+    }
+}
+
+impl Attributes {
+    /// What a variable needs to be readable from the kernel and survive a reboot
+    pub const PERSISTENT: Self = Self::NON_VOLATILE
+        .union(Self::BOOTSERVICE_ACCESS)
+        .union(Self::RUNTIME_ACCESS);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    /// Minutes from UTC, so that local time is UTC plus this
+    pub timezone: i16,
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+impl Time {
+    /// Nanoseconds since the unix epoch
+    pub fn unix_ns(&self) -> u64 {
+        // Days since the epoch of the proleptic gregorian calendar, with years starting in march
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = (self.month as i64 + 9) % 12;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let mut seconds =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+
+        if self.timezone != UNSPECIFIED_TIMEZONE {
+            seconds -= self.timezone as i64 * 60;
+        }
+
+        seconds.max(0) as u64 * NANOSECONDS_PER_SECOND + self.nanosecond as u64
+    }
+
+    /// The UTC time `ns` nanoseconds after the unix epoch
+    pub fn from_unix_ns(ns: u64) -> Self {
+        let seconds = ns / NANOSECONDS_PER_SECOND;
+        let days = (seconds / 86400) as i64 + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let second_of_day = seconds % 86400;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (second_of_day / 3600) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            second: (second_of_day % 60) as u8,
+            nanosecond: (ns % NANOSECONDS_PER_SECOND) as u32,
+            timezone: 0,
+            ..Self::default()
+        }
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[repr(C)]
+struct MemoryDescriptor {
+    kind: u32,
+    physical_start: u64,
+    _virtual_start: u64,
+    pages: u64,
+    attribute: u64,
+}
+
+/// The start of the runtime services table, only as far as what is used
+#[repr(C)]
+struct RuntimeServices {
+    _header: [u64; 3],
+    /// Also takes where to write the clock's capabilities, which may be null
+    get_time: extern "efiapi" fn(*mut Time, *mut u8) -> usize,
+    set_time: extern "efiapi" fn(*const Time) -> usize,
+    /// The wakeup timer and the calls that move the firmware
+    _unused: [usize; 4],
+    get_variable:
+        extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> usize,
+    get_next_variable_name: extern "efiapi" fn(*mut usize, *mut u16, *mut Guid) -> usize,
+    set_variable: extern "efiapi" fn(*const u16, *const Guid, u32, usize, *const u8) -> usize,
+}
+
+/// The runtime services are not reentrant, so every call goes through this lock
+static RUNTIME: Mutex<Option<&'static RuntimeServices>> = Mutex::new(None);
+
+fn runtime<T>(f: impl FnOnce(&RuntimeServices) -> T) -> Result<T, Error> {
+    let runtime = RUNTIME.lock();
+
+    Ok(f((*runtime).ok_or(Error::Unavailable)?))
+}
+
+pub fn is_available() -> bool {
+    RUNTIME.lock().is_some()
+}
+
+pub fn get_time() -> Result<Time, Error> {
+    let mut time = Time::default();
+
+    runtime(|runtime| (runtime.get_time)(&mut time, ptr::null_mut())).and_then(check)?;
+
+    Ok(time)
+}
+
+/// Set the firmware's clock, which is kept in UTC, and the kernel's along with it
+pub fn set_time(time: &Time) -> Result<(), Error> {
+    let time = Time {
+        timezone: UNSPECIFIED_TIMEZONE,
+        ..Time::from_unix_ns(time.unix_ns())
+    };
+
+    runtime(|runtime| (runtime.set_time)(&time)).and_then(check)?;
+
+    time::set_realtime_ns(time.unix_ns());
+
+    Ok(())
+}
+
+fn encode_name(name: &str) -> Result<Vec<u16>, Error> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(Error::InvalidName);
+    }
+
+    Ok(name.encode_utf16().chain([0]).collect())
+}
+
+pub fn get_variable(name: &str, vendor: &Guid) -> Result<(Vec<u8>, Attributes), Error> {
+    let name = encode_name(name)?;
+    let mut data = vec![0; 256];
+
+    loop {
+        let mut attributes = 0;
+        let mut len = data.len();
+
+        let status = runtime(|runtime| {
+            (runtime.get_variable)(
+                name.as_ptr(),
+                vendor,
+                &mut attributes,
+                &mut len,
+                data.as_mut_ptr(),
+            )
+        })?;
+
+        if status == STATUS_BUFFER_TOO_SMALL {
+            data.resize(len, 0);
+            continue;
+        }
+
+        check(status)?;
+
+        data.truncate(len);
+
+        return Ok((data, Attributes::from_bits_retain(attributes)));
+    }
+}
+
+/// Create or replace a variable, an empty `data` deletes it
+pub fn set_variable(
+    name: &str,
+    vendor: &Guid,
+    attributes: Attributes,
+    data: &[u8],
+) -> Result<(), Error> {
+    let name = encode_name(name)?;
+
+    runtime(|runtime| {
+        (runtime.set_variable)(
+            name.as_ptr(),
+            vendor,
+            attributes.bits(),
+            data.len(),
+            data.as_ptr(),
+        )
+    })
+    .and_then(check)
+}
+
+pub fn delete_variable(name: &str, vendor: &Guid) -> Result<(), Error> {
+    set_variable(name, vendor, Attributes::empty(), &[])
+}
+
+/// The names and vendors of all variables visible at runtime
+pub fn variables() -> Result<Vec<(String, Guid)>, Error> {
+    let mut variables = Vec::new();
+    // Starts as the empty name, and is then replaced by each name in turn
+    let mut name = vec![0u16; 64];
+    let mut vendor = Guid::GLOBAL_VARIABLE;
+
+    loop {
+        let mut len = name.len() * 2;
+
+        let status = runtime(|runtime| {
+            (runtime.get_next_variable_name)(&mut len, name.as_mut_ptr(), &mut vendor)
+        })?;
+
+        match status {
+            STATUS_NOT_FOUND => return Ok(variables),
+            STATUS_BUFFER_TOO_SMALL => {
+                name.resize(len.div_ceil(2), 0);
+                continue;
+            }
+            status => check(status)?,
+        }
+
+        let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+        variables.push((String::from_utf16_lossy(&name[..end]), vendor));
+    }
+}
+
+/// Map the firmware's runtime regions where it expects them to be
+fn map_runtime_regions() -> Option<usize> {
+    let response = EFI_MEMORY_MAP_REQUEST.get_response()?;
+    let descriptor_size = response.desc_size() as usize;
+
+    if descriptor_size < size_of::<MemoryDescriptor>() {
+        return None;
+    }
+
+    let mut regions = 0;
+
+    for offset in (0..response.memmap_size() as usize).step_by(descriptor_size) {
+        let descriptor = unsafe {
+            response
+                .memmap()
+                .byte_add(offset)
+                .cast::<MemoryDescriptor>()
+                .read_unaligned()
+        };
+
+        if descriptor.attribute & MEMORY_RUNTIME == 0 {
+            continue;
+        }
+
+        let flags = match descriptor.kind {
+            MEMORY_RUNTIME_SERVICES_CODE => PageTableFlags::WRITABLE,
+            MEMORY_MAPPED_IO | MEMORY_MAPPED_IO_PORT_SPACE => {
+                PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH
+                    | PageTableFlags::NO_EXECUTE
+            }
+            _ => PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        };
+
+        let len = (descriptor.pages * 4096) as usize;
+
+        // Older firmware writes to variables in its code, so it stays writable like it was
+        if descriptor.kind == MEMORY_RUNTIME_SERVICES_CODE {
+            paging::map_identity_writable_executable(descriptor.physical_start, len, flags);
+        } else {
+            paging::map_identity(descriptor.physical_start, len, flags);
+        }
+
+        regions += 1;
+    }
+
+    Some(regions)
+}
+
+/// Find the runtime services table, given the physical address of the system table
+fn find_runtime_services(system_table: u64) -> Option<(String, u64)> {
+    let table = Mmio::map(system_table, 0x60);
+
+    if table.read::<u64>(0) != SYSTEM_TABLE_SIGNATURE {
+        return None;
+    }
+
+    let runtime_services = table.read::<u64>(0x58);
+
+    if runtime_services == 0
+        || Mmio::map(runtime_services, 8).read::<u64>(0) != RUNTIME_SERVICES_SIGNATURE
+    {
+        return None;
+    }
+
+    let mut vendor = String::new();
+    let vendor_address = table.read::<u64>(0x18);
+
+    if vendor_address != 0 {
+        let characters = Mmio::map(vendor_address, 128);
+
+        vendor = String::from_utf16_lossy(
+            &(0..64)
+                .map(|i| characters.read::<u16>(i * 2))
+                .take_while(|&c| c != 0)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    Some((vendor, runtime_services))
+}
+
+/// Find the runtime services and set the wall clock from the firmware's
+pub fn init() {
+    let Some(response) = EFI_SYSTEM_TABLE_REQUEST.get_response() else {
+        return;
+    };
+
+    let system_table = paging::phys_from_bootloader(response.address() as u64);
+
+    let Some((vendor, runtime_services)) = find_runtime_services(system_table) else {
+        println!("efi: no runtime services");
+        return;
+    };
+
+    let Some(regions) = map_runtime_regions() else {
+        println!("efi: no memory map, runtime services are unusable");
+        return;
+    };
+
+    // The table is in one of the runtime regions, so its physical address now works as is
+    *RUNTIME.lock() = Some(unsafe { &*(runtime_services as *const RuntimeServices) });
+
+    println!("efi: {}, {} runtime regions", vendor, regions);
+
+    match get_time() {
+        Ok(now) => {
+            time::set_realtime_ns(now.unix_ns());
+
+            println!("efi: the time is {} UTC", Time::from_unix_ns(now.unix_ns()));
+        }
+        Err(error) => println!("efi: could not get the time: {:?}", error),
+    }
+}
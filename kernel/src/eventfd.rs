@@ -0,0 +1,147 @@
+//! Eventfd, a counter that both the kernel and whoever holds the file can bump and wait on
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+
+use crate::{
+    file::{Error, Events, File},
+    sync::Mutex,
+    wait::{self, WaitQueue},
+};
+
+/// The counter never reaches `u64::MAX`, which writes can not add
+const MAX_COUNT: u64 = u64::MAX - 1;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct EventFdFlags: u32 {
+        /// Reads take one from the counter instead of all of it
+        const SEMAPHORE = 1 << 0;
+        const NONBLOCK = 0o4000;
+        const CLOEXEC = 0o2000000;
+    }
+}
+
+pub struct EventFd {
+    count: Mutex<u64>,
+    semaphore: bool,
+    nonblocking: AtomicBool,
+    wait_queue: WaitQueue,
+}
+
+impl EventFd {
+    pub fn new(initial: u32, flags: EventFdFlags) -> Self {
+        Self {
+            count: Mutex::new(initial as u64),
+            semaphore: flags.contains(EventFdFlags::SEMAPHORE),
+            nonblocking: AtomicBool::new(flags.contains(EventFdFlags::NONBLOCK)),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    /// Add to the counter from the kernel, saturating instead of waiting for room
+    pub fn signal(&self, value: u64) {
+        {
+            let mut count = self.count.lock();
+
+            *count = count.saturating_add(value).min(MAX_COUNT);
+        }
+
+        self.wait_queue.wake_all();
+    }
+
+    /// Wait until `ready` is true of the counter
+    fn wait_for(&self, ready: impl Fn(u64) -> bool) -> Result<(), Error> {
+        if self.nonblocking.load(Ordering::Relaxed) {
+            return Err(Error::WouldBlock);
+        }
+
+        wait::until(None, || ready(*self.count.lock()).then_some(()));
+
+        Ok(())
+    }
+}
+
+impl File for EventFd {
+    /// Read the counter as 8 native endian bytes, waiting for it to be above zero
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let buffer: &mut [u8; 8] = buffer
+            .get_mut(..8)
+            .and_then(|buffer| buffer.try_into().ok())
+            .ok_or(Error::InvalidArgument)?;
+
+        loop {
+            let mut count = self.count.lock();
+
+            if *count != 0 {
+                let value = if self.semaphore { 1 } else { *count };
+
+                *count -= value;
+                *buffer = value.to_ne_bytes();
+
+                drop(count);
+                self.wait_queue.wake_all();
+
+                return Ok(8);
+            }
+
+            drop(count);
+
+            self.wait_for(|count| count != 0)?;
+        }
+    }
+
+    /// Add 8 native endian bytes to the counter, waiting for room
+    fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        let value = data
+            .get(..8)
+            .map(|data| u64::from_ne_bytes(data.try_into().unwrap()))
+            .ok_or(Error::InvalidArgument)?;
+
+        if value == u64::MAX {
+            return Err(Error::InvalidArgument);
+        }
+
+        loop {
+            let mut count = self.count.lock();
+
+            if MAX_COUNT - *count >= value {
+                *count += value;
+
+                drop(count);
+                self.wait_queue.wake_all();
+
+                return Ok(8);
+            }
+
+            drop(count);
+
+            self.wait_for(|count| MAX_COUNT - count >= value)?;
+        }
+    }
+
+    fn poll(&self) -> Events {
+        let count = *self.count.lock();
+
+        let mut events = Events::empty();
+
+        if count != 0 {
+            events |= Events::READABLE;
+        }
+
+        if count < MAX_COUNT {
+            events |= Events::WRITABLE;
+        }
+
+        events
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.wait_queue)
+    }
+}
@@ -0,0 +1,869 @@
+//! Loading ELF executables, whose segments are mapped from the file and paged in as they are
+//! touched
+//!
+//! There are no processes or address spaces of their own yet, so only position independent
+//! executables can be loaded, wherever [`mmap::reserve`] finds room at random. Segments are mapped private
+//! from the file, so files made of pages share the text of every image loaded from them until it
+//! is written, and what is past the end of a segment's data is mapped from [`Zeroes`]. The program
+//! break starts right after the last segment, and [`Image::brk`] moves it. The stack is set up by
+//! [`Image::setup_stack`] the way the System V ABI has it, with the arguments, the environment and
+//! the auxiliary vector on it, and the vDSO is mapped next to it. [`execve`] puts the two together,
+//! and runs scripts starting with `#!` with the interpreter they name
+//!
+//! Dynamically linked programs are started at the entry of the interpreter their `PT_INTERP`
+//! names, which [`execve`] loads and which relocates the program. Static position
+//! independent executables are relocated here instead, which works while all their relocations
+//! are relative ones to writable segments
+//!
+//! [`execve`] holds the image to the stack and address space limits of the process calling it, as
+//! the process it will run in inherits them
+//!
+//! Whatever runs the image has to check that it was built for this kernel, nothing here can tell
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    arch::paging::PAGE_SIZE,
+    file,
+    mmap::{self, Mappable, Protection, Sharing, Zeroes},
+    rand,
+    rlimit::{self, Resource},
+    vdso,
+};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+const ELF_TYPE_SHARED: u16 = 3;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_DYNAMIC: u32 = 2;
+const SEGMENT_INTERPRETER: u32 = 3;
+
+const SEGMENT_EXECUTE: u32 = 1;
+const SEGMENT_WRITE: u32 = 1 << 1;
+const SEGMENT_READ: u32 = 1 << 2;
+
+const DYNAMIC_NULL: u64 = 0;
+const DYNAMIC_RELOCATIONS: u64 = 7;
+const DYNAMIC_RELOCATIONS_SIZE: u64 = 8;
+const DYNAMIC_RELOCATION_SIZE: u64 = 9;
+
+const RELOCATION_NONE: u32 = 0;
+const RELOCATION_RELATIVE: u32 = 8;
+const RELOCATION_SIZE: u64 = 24;
+
+/// How long the `#!` line of a script can be, and how many scripts can run each other in a row
+const INTERPRETER_LINE_MAX: usize = 256;
+const INTERPRETERS_MAX: usize = 4;
+
+/// How far the program break can go past the end of the image
+const BREAK_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The size of the stack until its limit is set, and how small and large that can make it
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+const STACK_SIZE_MIN: u64 = 128 * 1024;
+const STACK_SIZE_MAX: u64 = 256 * 1024 * 1024;
+
+const AUX_NULL: u64 = 0;
+const AUX_PROGRAM_HEADERS: u64 = 3;
+const AUX_PROGRAM_HEADER_SIZE: u64 = 4;
+const AUX_PROGRAM_HEADER_COUNT: u64 = 5;
+const AUX_PAGE_SIZE: u64 = 6;
+const AUX_BASE: u64 = 7;
+const AUX_FLAGS: u64 = 8;
+const AUX_ENTRY: u64 = 9;
+const AUX_SECURE: u64 = 23;
+const AUX_RANDOM: u64 = 25;
+const AUX_VDSO: u64 = 33;
+
+/// The most entries the auxiliary vector has, with the one ending it
+const AUXILIARY_MAX: usize = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not a 64 bit little endian x86_64 executable, or a broken one
+    NotExecutable,
+    /// Linked to run at a fixed address, which needs an address space of its own
+    NotPositionIndependent,
+    /// The arguments and the environment take more of the stack than they can
+    ArgumentsTooLong,
+    /// A static position independent executable needs a relocation that is not relative
+    UnsupportedRelocation(u32),
+    /// Scripts run by scripts more times in a row than they can be
+    TooManyInterpreters,
+    File(file::Error),
+}
+
+impl From<file::Error> for Error {
+    fn from(error: file::Error) -> Self {
+        Error::File(error)
+    }
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// Read `len` bytes of `file` from `offset`, a page at a time as that is all it can do, failing
+/// for what goes past the end of the file as the lengths come from the file itself
+fn read_file(file: &dyn Mappable, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let end = offset.checked_add(len as u64).ok_or(Error::NotExecutable)?;
+
+    if end > file.size()? {
+        return Err(Error::NotExecutable);
+    }
+
+    let mut data = Vec::with_capacity(len);
+    let mut page = vec![0; PAGE_SIZE as usize];
+
+    for index in offset / PAGE_SIZE..end.div_ceil(PAGE_SIZE) {
+        file.read_page(index, &mut page)?;
+
+        let page_start = index * PAGE_SIZE;
+        let from = offset.max(page_start) - page_start;
+        let to = end.min(page_start + PAGE_SIZE) - page_start;
+
+        data.extend_from_slice(&page[from as usize..to as usize]);
+    }
+
+    Ok(data)
+}
+
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+impl Segment {
+    fn protection(&self) -> Protection {
+        let mut protection = Protection::empty();
+
+        if self.flags & SEGMENT_READ != 0 {
+            protection |= Protection::READ;
+        }
+
+        if self.flags & SEGMENT_WRITE != 0 {
+            protection |= Protection::WRITE;
+        }
+
+        if self.flags & SEGMENT_EXECUTE != 0 {
+            protection |= Protection::EXECUTE;
+        }
+
+        protection
+    }
+}
+
+fn segments(file: &dyn Mappable, header: &[u8]) -> Result<Vec<Segment>, Error> {
+    let table = read_u64(header, 32).ok_or(Error::NotExecutable)?;
+    let entry_size = read_u16(header, 54).ok_or(Error::NotExecutable)? as usize;
+    let count = read_u16(header, 56).ok_or(Error::NotExecutable)? as usize;
+
+    if entry_size < PROGRAM_HEADER_SIZE {
+        return Err(Error::NotExecutable);
+    }
+
+    let headers = read_file(file, table, entry_size * count)?;
+
+    let mut segments = Vec::new();
+
+    for header in headers.chunks(entry_size) {
+        let field = |offset| read_u64(header, offset).ok_or(Error::NotExecutable);
+
+        let segment = Segment {
+            kind: read_u32(header, 0).ok_or(Error::NotExecutable)?,
+            flags: read_u32(header, 4).ok_or(Error::NotExecutable)?,
+            offset: field(8)?,
+            address: field(16)?,
+            file_size: field(32)?,
+            memory_size: field(40)?,
+        };
+
+        // Pages are mapped from the file as they are, so both have to be at the same offset in one
+        if segment.kind == SEGMENT_LOAD
+            && (segment.offset % PAGE_SIZE != segment.address % PAGE_SIZE
+                || segment.file_size > segment.memory_size
+                || segment.address.checked_add(segment.memory_size).is_none())
+        {
+            return Err(Error::NotExecutable);
+        }
+
+        segments.push(segment);
+    }
+
+    Ok(segments)
+}
+
+/// An executable mapped into memory, unmapped when dropped
+pub struct Image {
+    base: u64,
+    entry: u64,
+    /// Where the program break starts, right after the image, and how far it can go
+    break_start: u64,
+    break_limit: u64,
+    program_break: u64,
+    /// Where the program headers ended up, if a segment has them
+    program_headers: Option<u64>,
+    program_header_size: u16,
+    program_header_count: u16,
+    /// Where the stack was mapped and how large, and how large the next one is mapped
+    stack: Option<(u64, u64)>,
+    stack_size: u64,
+    /// How much of the address space it can take
+    address_space_limit: u64,
+    /// Where the vDSO's data was mapped, the vDSO is the page after
+    vdso: Option<u64>,
+    /// Where and how large what the program mapped itself is
+    mappings: Vec<(u64, u64)>,
+    /// What its `PT_INTERP` names, and the image of it once [`execve`] loaded it
+    interpreter_path: Option<String>,
+    interpreter: Option<Box<Image>>,
+}
+
+impl Image {
+    /// Where the image was loaded, what its addresses are relative to
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Where to start running it, which is in its interpreter if it has one
+    pub fn start_address(&self) -> u64 {
+        match &self.interpreter {
+            Some(interpreter) => interpreter.entry,
+            None => self.entry,
+        }
+    }
+
+    /// Move the program break to `address` like the `brk` system call, mapping memory that reads
+    /// as zeroes as it grows and unmapping it as it shrinks, returns where the break is now, which
+    /// is where it was if it can not go there
+    pub fn brk(&mut self, address: u64) -> u64 {
+        if !(self.break_start..=self.break_limit).contains(&address) {
+            return self.program_break;
+        }
+
+        let mapped = self.program_break.next_multiple_of(PAGE_SIZE);
+        let needed = address.next_multiple_of(PAGE_SIZE);
+
+        if needed > mapped {
+            if self.address_space_size() + (needed - mapped) > self.address_space_limit {
+                return self.program_break;
+            }
+
+            let grown = mmap::mmap_fixed(
+                mapped,
+                Arc::new(Zeroes),
+                0,
+                (needed - mapped) as usize,
+                Protection::READ | Protection::WRITE,
+                Sharing::Private,
+            );
+
+            if grown.is_err() {
+                return self.program_break;
+            }
+        } else if needed < mapped {
+            mmap::munmap_range(needed, (mapped - needed) as usize);
+        }
+
+        self.program_break = address;
+
+        self.program_break
+    }
+
+    /// How much of the address space it and its interpreter take, mapped or reserved
+    pub fn address_space_size(&self) -> u64 {
+        let image = self.break_start - self.base;
+        let program_break = self.program_break.next_multiple_of(PAGE_SIZE) - self.break_start;
+        let stack = self.stack.map_or(0, |(_, size)| size);
+        let vdso = self.vdso.map_or(0, |_| 2 * PAGE_SIZE);
+        let mappings: u64 = self.mappings.iter().map(|&(_, len)| len).sum();
+        let interpreter = self
+            .interpreter
+            .as_ref()
+            .map_or(0, |interpreter| interpreter.address_space_size());
+
+        image + program_break + stack + vdso + mappings + interpreter
+    }
+
+    /// Map `len` bytes of `file` from `offset` for the program like the `mmap` system call, held
+    /// to the address space limit and unmapped with the image
+    pub fn mmap(
+        &mut self,
+        file: Arc<dyn Mappable>,
+        offset: u64,
+        len: usize,
+        protection: Protection,
+        sharing: Sharing,
+    ) -> Result<u64, file::Error> {
+        let size = (len as u64)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(file::Error::OutOfMemory)?;
+
+        if self.address_space_size().saturating_add(size) > self.address_space_limit {
+            return Err(file::Error::OutOfMemory);
+        }
+
+        let start = mmap::mmap(file, offset, len, protection, sharing)?;
+
+        self.mappings.push((start, size));
+
+        Ok(start)
+    }
+
+    /// Unmap the pages between `start` and `start + len` like the `munmap` system call, keeping
+    /// what is left of the mappings from [`Image::mmap`] on either side, where nothing is mapped
+    /// is fine
+    pub fn munmap(&mut self, start: u64, len: usize) -> Result<(), file::Error> {
+        let end = (len as u64)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .and_then(|size| start.checked_add(size))
+            .filter(|_| len != 0 && start.is_multiple_of(PAGE_SIZE))
+            .ok_or(file::Error::InvalidArgument)?;
+
+        let mut kept = Vec::new();
+
+        self.mappings.retain(|&(mapped, size)| {
+            let mapped_end = mapped + size;
+
+            if mapped_end <= start || end <= mapped {
+                return true;
+            }
+
+            if mapped < start {
+                kept.push((mapped, start - mapped));
+            }
+
+            if end < mapped_end {
+                kept.push((end, mapped_end - end));
+            }
+
+            false
+        });
+
+        self.mappings.extend(kept);
+
+        mmap::munmap_range(start, (end - start) as usize);
+
+        Ok(())
+    }
+
+    /// Hold the program break and the stack to `limit`, what is mapped already stays
+    pub fn set_address_space_limit(&mut self, limit: u64) {
+        self.address_space_limit = limit;
+    }
+
+    /// Map the next stack with `size`, within what the stack can be
+    pub fn set_stack_size(&mut self, size: u64) {
+        self.stack_size = size
+            .clamp(STACK_SIZE_MIN, STACK_SIZE_MAX)
+            .next_multiple_of(PAGE_SIZE);
+    }
+
+    /// Map a stack with `arguments` and `environment` on it as the System V ABI lays them out for
+    /// a program that just started, returns the stack pointer to start it with, at its argument
+    /// count
+    pub fn setup_stack(&mut self, arguments: &[&str], environment: &[&str]) -> Result<u64, Error> {
+        // What the pointers point to goes at the top, 16 random bytes first for AT_RANDOM
+        let mut strings = vec![0; 16];
+
+        rand::fill(&mut strings);
+
+        let mut offsets = Vec::new();
+
+        for string in arguments.iter().chain(environment) {
+            offsets.push(strings.len() as u64);
+            strings.extend_from_slice(string.as_bytes());
+            strings.push(0);
+        }
+
+        let words = 1 + arguments.len() + 1 + environment.len() + 1 + 2 * AUXILIARY_MAX;
+
+        let size = self.stack_size;
+
+        // A quarter of the stack, as much as Linux lets them take
+        if strings.len() as u64 + words as u64 * 8 > size / 4 {
+            return Err(Error::ArgumentsTooLong);
+        }
+
+        if let Some((stack, _)) = self.stack.take() {
+            mmap::munmap(stack);
+        }
+
+        if self.address_space_size() + size > self.address_space_limit {
+            return Err(Error::File(file::Error::OutOfMemory));
+        }
+
+        // Reserved rather than mapped anywhere, for it to be placed at random like the image
+        let stack = mmap::reserve(size as usize)?;
+
+        mmap::mmap_fixed(
+            stack,
+            Arc::new(Zeroes),
+            0,
+            size as usize,
+            Protection::READ | Protection::WRITE,
+            Sharing::Private,
+        )
+        .inspect_err(|_| mmap::munmap(stack))?;
+
+        self.stack = Some((stack, size));
+
+        // Without it the program asks the kernel for the time
+        if self.vdso.is_none() {
+            self.vdso = vdso::map().ok();
+        }
+
+        let strings_start = (stack + size - strings.len() as u64) & !15;
+        let pointers = offsets.iter().map(|offset| strings_start + offset);
+
+        let mut vector = Vec::with_capacity(words);
+
+        vector.push(arguments.len() as u64);
+        vector.extend(pointers.clone().take(arguments.len()));
+        vector.push(0);
+        vector.extend(pointers.skip(arguments.len()));
+        vector.push(0);
+
+        let mut auxiliary = vec![
+            (
+                AUX_PROGRAM_HEADERS,
+                self.program_headers.unwrap_or_default(),
+            ),
+            (AUX_PROGRAM_HEADER_SIZE, self.program_header_size as u64),
+            (AUX_PROGRAM_HEADER_COUNT, self.program_header_count as u64),
+            (AUX_PAGE_SIZE, PAGE_SIZE),
+            (
+                AUX_BASE,
+                self.interpreter
+                    .as_ref()
+                    .map_or(0, |interpreter| interpreter.base),
+            ),
+            (AUX_FLAGS, 0),
+            (AUX_ENTRY, self.entry),
+            (AUX_SECURE, 0),
+            (AUX_RANDOM, strings_start),
+        ];
+
+        if let Some(vdso) = self.vdso {
+            auxiliary.push((AUX_VDSO, vdso + PAGE_SIZE));
+        }
+
+        auxiliary.push((AUX_NULL, 0));
+
+        for (key, value) in auxiliary {
+            vector.extend([key, value]);
+        }
+
+        // The argument count has to be 16 byte aligned when the program starts
+        let stack_pointer = (strings_start - vector.len() as u64 * 8) & !15;
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                strings.as_ptr(),
+                strings_start as *mut u8,
+                strings.len(),
+            );
+
+            core::ptr::copy_nonoverlapping(
+                vector.as_ptr(),
+                stack_pointer as *mut u64,
+                vector.len(),
+            );
+        }
+
+        Ok(stack_pointer)
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if let Some((stack, _)) = self.stack {
+            mmap::munmap(stack);
+        }
+
+        if let Some(vdso) = self.vdso {
+            mmap::munmap(vdso);
+        }
+
+        for &(start, _) in &self.mappings {
+            mmap::munmap(start);
+        }
+
+        mmap::munmap(self.base);
+    }
+}
+
+/// The offset in the file of the `len` bytes at `address`, if they are all in a segment's data
+fn file_offset(segments: &[Segment], address: u64, len: u64) -> Option<u64> {
+    segments
+        .iter()
+        .filter(|segment| segment.kind == SEGMENT_LOAD)
+        .find(|segment| {
+            segment.address <= address
+                && address.saturating_add(len) <= segment.address + segment.file_size
+        })
+        .map(|segment| segment.offset + (address - segment.address))
+}
+
+/// Apply the relocations of a static position independent executable loaded `bias` bytes from
+/// where it was linked to, which must all be relative ones
+fn relocate(
+    file: &dyn Mappable,
+    segments: &[Segment],
+    dynamic: &Segment,
+    bias: u64,
+) -> Result<(), Error> {
+    let entries = read_file(file, dynamic.offset, dynamic.file_size as usize)?;
+
+    let mut table = None;
+    let mut size = 0;
+    let mut entry_size = RELOCATION_SIZE;
+
+    for entry in entries.as_chunks::<16>().0 {
+        let tag = read_u64(entry, 0).ok_or(Error::NotExecutable)?;
+        let value = read_u64(entry, 8).ok_or(Error::NotExecutable)?;
+
+        match tag {
+            DYNAMIC_NULL => break,
+            DYNAMIC_RELOCATIONS => table = Some(value),
+            DYNAMIC_RELOCATIONS_SIZE => size = value,
+            DYNAMIC_RELOCATION_SIZE => entry_size = value,
+            _ => {}
+        }
+    }
+
+    let Some(table) = table else {
+        return Ok(());
+    };
+
+    if entry_size < RELOCATION_SIZE {
+        return Err(Error::NotExecutable);
+    }
+
+    let offset = file_offset(segments, table, size).ok_or(Error::NotExecutable)?;
+    let relocations = read_file(file, offset, size as usize)?;
+
+    for relocation in relocations.chunks_exact(entry_size as usize) {
+        let target = read_u64(relocation, 0).ok_or(Error::NotExecutable)?;
+        let info = read_u64(relocation, 8).ok_or(Error::NotExecutable)?;
+        let addend = read_u64(relocation, 16).ok_or(Error::NotExecutable)?;
+
+        match info as u32 {
+            RELOCATION_NONE => continue,
+            RELOCATION_RELATIVE => {}
+            kind => return Err(Error::UnsupportedRelocation(kind)),
+        }
+
+        // Anything else is text, which can not be written once mapped
+        let writable = segments.iter().any(|segment| {
+            segment.kind == SEGMENT_LOAD
+                && segment.flags & SEGMENT_WRITE != 0
+                && segment.address <= target
+                && target.saturating_add(8) <= segment.address + segment.memory_size
+        });
+
+        if !writable {
+            return Err(Error::NotExecutable);
+        }
+
+        unsafe {
+            ((bias.wrapping_add(target)) as *mut u64).write_unaligned(bias.wrapping_add(addend));
+        }
+    }
+
+    Ok(())
+}
+
+/// Map the segments of the executable in `file`, which are only read once touched, a dynamically
+/// linked one still needs its interpreter loaded like [`execve`] does
+pub fn load(file: Arc<dyn Mappable>) -> Result<Image, Error> {
+    let header = read_file(&*file, 0, HEADER_SIZE)?;
+
+    if read::<4>(&header, 0) != Some(ELF_MAGIC)
+        || header.get(4) != Some(&ELF_CLASS_64)
+        || header.get(5) != Some(&ELF_DATA_LITTLE_ENDIAN)
+        || read_u16(&header, 18) != Some(ELF_MACHINE_X86_64)
+    {
+        return Err(Error::NotExecutable);
+    }
+
+    match read_u16(&header, 16) {
+        Some(ELF_TYPE_SHARED) => {}
+        Some(ELF_TYPE_EXECUTABLE) => return Err(Error::NotPositionIndependent),
+        _ => return Err(Error::NotExecutable),
+    }
+
+    let segments = segments(&*file, &header)?;
+
+    let interpreter_path = match segments
+        .iter()
+        .find(|segment| segment.kind == SEGMENT_INTERPRETER)
+    {
+        Some(segment) => {
+            let path = read_file(&*file, segment.offset, segment.file_size as usize)?;
+            let len = path
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(path.len());
+
+            Some(String::from(
+                core::str::from_utf8(&path[..len]).map_err(|_| Error::NotExecutable)?,
+            ))
+        }
+        None => None,
+    };
+
+    let loads = || {
+        segments
+            .iter()
+            .filter(|segment| segment.kind == SEGMENT_LOAD)
+    };
+
+    let start = loads()
+        .map(|segment| segment.address)
+        .min()
+        .ok_or(Error::NotExecutable)?
+        & !(PAGE_SIZE - 1);
+
+    let mut end = None;
+
+    // Segments end where they can be rounded up to the end of their last page, which was only
+    // checked as far as their last byte
+    for segment in loads() {
+        let segment_end = (segment.address + segment.memory_size)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::NotExecutable)?;
+
+        end = end.max(Some(segment_end));
+    }
+
+    let end = end.ok_or(Error::NotExecutable)?;
+
+    let entry = read_u64(&header, 24).ok_or(Error::NotExecutable)?;
+
+    if !(start..end).contains(&entry) {
+        return Err(Error::NotExecutable);
+    }
+
+    let size = (end - start)
+        .checked_add(BREAK_SIZE)
+        .ok_or(Error::NotExecutable)?;
+
+    let base = mmap::reserve(size as usize)?;
+    let break_start = base + (end - start);
+
+    // Programs can be linked above where they are loaded, so this wraps around as in
+    // [`relocate`], what it is added to is always between `start` and `end`
+    let bias = base.wrapping_sub(start);
+
+    let program_header_offset = read_u64(&header, 32).ok_or(Error::NotExecutable)?;
+    let program_header_size = read_u16(&header, 54).ok_or(Error::NotExecutable)?;
+    let program_header_count = read_u16(&header, 56).ok_or(Error::NotExecutable)?;
+    let program_headers_end = program_header_offset
+        .saturating_add(program_header_size as u64 * program_header_count as u64);
+
+    // Dropped on the way out if a segment fails to map, which unmaps the rest
+    let image = Image {
+        base,
+        entry: bias.wrapping_add(entry),
+        break_start,
+        break_limit: break_start + BREAK_SIZE,
+        program_break: break_start,
+        program_headers: loads()
+            .find(|segment| {
+                segment.offset <= program_header_offset
+                    && program_headers_end <= segment.offset.saturating_add(segment.file_size)
+            })
+            .map(|segment| {
+                bias.wrapping_add(segment.address + (program_header_offset - segment.offset))
+            }),
+        program_header_size,
+        program_header_count,
+        stack: None,
+        stack_size: STACK_SIZE,
+        address_space_limit: rlimit::INFINITY,
+        vdso: None,
+        mappings: Vec::new(),
+        interpreter_path,
+        interpreter: None,
+    };
+
+    for segment in loads() {
+        let page = segment.address & !(PAGE_SIZE - 1);
+        let data_end = segment.address + segment.file_size;
+        let file_end = data_end.next_multiple_of(PAGE_SIZE);
+        let memory_end = (segment.address + segment.memory_size).next_multiple_of(PAGE_SIZE);
+
+        if segment.file_size != 0 {
+            mmap::mmap_fixed(
+                bias.wrapping_add(page),
+                file.clone(),
+                segment.offset & !(PAGE_SIZE - 1),
+                (file_end - page) as usize,
+                segment.protection(),
+                Sharing::Private,
+            )?;
+        }
+
+        if memory_end > file_end {
+            mmap::mmap_fixed(
+                bias.wrapping_add(file_end),
+                Arc::new(Zeroes),
+                0,
+                (memory_end - file_end) as usize,
+                segment.protection(),
+                Sharing::Private,
+            )?;
+        }
+
+        // The rest of the last page with data is whatever comes next in the file, which has to
+        // read as zeroes when it is part of the segment, copying the page. Segments that can not
+        // be written are left alone, they are not expected to have more than their data
+        let tail = (file_end - data_end) as usize;
+
+        if segment.file_size != 0
+            && tail != 0
+            && segment.memory_size > segment.file_size
+            && segment.protection().contains(Protection::WRITE)
+        {
+            unsafe {
+                (bias.wrapping_add(data_end) as *mut u8).write_bytes(0, tail);
+            }
+        }
+    }
+
+    // A dynamically linked program is relocated by its interpreter
+    if image.interpreter_path.is_none()
+        && let Some(dynamic) = segments
+            .iter()
+            .find(|segment| segment.kind == SEGMENT_DYNAMIC)
+    {
+        relocate(&*file, &segments, dynamic, bias)?;
+    }
+
+    Ok(image)
+}
+
+/// The interpreter a script names on its `#!` line and the argument to give it, if it has one
+fn script_interpreter(file: &dyn Mappable) -> Result<Option<(String, Option<String>)>, Error> {
+    let len = file.size()?.min(INTERPRETER_LINE_MAX as u64) as usize;
+    let line = read_file(file, 0, len)?;
+
+    let Some(line) = line.strip_prefix(b"#!") else {
+        return Ok(None);
+    };
+
+    let end = line
+        .iter()
+        .position(|&byte| byte == b'\n' || byte == 0)
+        .unwrap_or(line.len());
+
+    let line = core::str::from_utf8(&line[..end]).map_err(|_| Error::NotExecutable)?;
+    let line = line.trim_matches([' ', '\t']);
+
+    // Like Linux, everything after the interpreter is a single argument
+    let (path, argument) = match line.split_once([' ', '\t']) {
+        Some((path, argument)) => (path, Some(argument.trim_matches([' ', '\t']))),
+        None => (line, None),
+    };
+
+    if path.is_empty() {
+        return Err(Error::NotExecutable);
+    }
+
+    Ok(Some((
+        String::from(path),
+        argument
+            .filter(|argument| !argument.is_empty())
+            .map(String::from),
+    )))
+}
+
+/// Load the program at `path` with `arguments` and `environment` on its stack, returns it and the
+/// stack pointer to start it with at [`Image::start_address`]
+///
+/// Files are found by `open`, as there is no file system to look paths up in yet. A script is run
+/// by its interpreter instead, with the interpreter's argument if the script gives one and the
+/// script's path in front of the arguments but the first. The program that called this is only
+/// to be torn down once it succeeded, so it can still be told why it did not
+pub fn execve(
+    open: impl Fn(&str) -> Result<Arc<dyn Mappable>, file::Error>,
+    path: &str,
+    arguments: &[&str],
+    environment: &[&str],
+) -> Result<(Image, u64), Error> {
+    let mut path = String::from(path);
+    let mut arguments: Vec<String> = arguments.iter().map(|&argument| argument.into()).collect();
+
+    for _ in 0..=INTERPRETERS_MAX {
+        let file = open(&path)?;
+
+        let Some((interpreter, argument)) = script_interpreter(&*file)? else {
+            let mut image = load(file)?;
+
+            if let Some(path) = &image.interpreter_path {
+                let interpreter = load(open(path)?)?;
+
+                // The interpreter is what relocates the others, nothing can relocate it
+                if interpreter.interpreter_path.is_some() {
+                    return Err(Error::NotExecutable);
+                }
+
+                image.interpreter = Some(Box::new(interpreter));
+            }
+
+            // Held to the limits of the process calling, which it will run as
+            let address_space_limit = rlimit::get(Resource::AddressSpace).soft;
+
+            if image.address_space_size() > address_space_limit {
+                return Err(Error::File(file::Error::OutOfMemory));
+            }
+
+            image.set_address_space_limit(address_space_limit);
+            image.set_stack_size(rlimit::get(Resource::Stack).soft);
+
+            let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+            let stack_pointer = image.setup_stack(&arguments, environment)?;
+
+            return Ok((image, stack_pointer));
+        };
+
+        let script = core::mem::replace(&mut path, interpreter);
+        let rest = arguments.into_iter().skip(1);
+
+        arguments = core::iter::once(path.clone())
+            .chain(argument)
+            .chain(core::iter::once(script))
+            .chain(rest)
+            .collect();
+    }
+
+    Err(Error::TooManyInterpreters)
+}
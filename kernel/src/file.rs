@@ -0,0 +1,138 @@
+//! Open files, the kernel objects that can be read, written and waited on
+
+use alloc::string::String;
+
+use bitflags::bitflags;
+
+use crate::{net, sync::Mutex, wait::WaitQueue};
+
+bitflags! {
+    /// What a file is ready for, the `POLL*` bits of poll
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Events: u16 {
+        const READABLE = 1 << 0;
+        const WRITABLE = 1 << 2;
+        const ERROR = 1 << 3;
+        /// The other end is gone, reads see the end of the file once drained
+        const HANGUP = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// The `O_*` flags an open file is created with
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct OpenFlags: u32 {
+        /// Neither of the access modes opens for reading only
+        const WRITE_ONLY = 0o1;
+        const READ_WRITE = 0o2;
+        const CREATE = 0o100;
+        /// With `CREATE`, fail if the file already exists
+        const EXCLUSIVE = 0o200;
+        const TRUNCATE = 0o1000;
+        const NONBLOCK = 0o4000;
+        const CLOEXEC = 0o2000000;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The file is nonblocking and not ready
+    WouldBlock,
+    /// Writing with nobody left to read
+    BrokenPipe,
+    InvalidArgument,
+    NotSupported,
+    NotFound,
+    AlreadyExists,
+    NameTooLong,
+    OutOfMemory,
+    PermissionDenied,
+    TimedOut,
+    /// The message does not fit the buffer, or the queue's message size
+    MessageTooLong,
+    /// Whatever is behind the file failed
+    Io,
+    /// A signal was sent that would interrupt it
+    Interrupted,
+    Network(net::Error),
+}
+
+const NAME_MAX: usize = 255;
+
+/// Names of shared memory objects and message queues are a slash followed by a name without
+/// slashes, like `/buffer`
+pub fn validate_ipc_name(name: &str) -> Result<(), Error> {
+    let rest = name.strip_prefix('/').ok_or(Error::InvalidArgument)?;
+
+    if rest.is_empty() || rest.contains('/') || rest == "." || rest == ".." {
+        return Err(Error::InvalidArgument);
+    }
+
+    if rest.len() > NAME_MAX {
+        return Err(Error::NameTooLong);
+    }
+
+    Ok(())
+}
+
+impl From<net::Error> for Error {
+    fn from(error: net::Error) -> Self {
+        match error {
+            net::Error::WouldBlock => Error::WouldBlock,
+            error => Error::Network(error),
+        }
+    }
+}
+
+pub trait File: Send + Sync {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<usize, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// What the file is ready for right now
+    fn poll(&self) -> Events;
+
+    /// Woken whenever what `poll` returns may have changed, files without one are polled over
+    /// and over by whoever waits on them
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        None
+    }
+}
+
+/// Text made up when the file was opened, like the files in `/proc`, read from the start and
+/// never changing after
+pub struct Snapshot {
+    text: String,
+    position: Mutex<usize>,
+}
+
+impl Snapshot {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            position: Mutex::new(0),
+        }
+    }
+}
+
+impl File for Snapshot {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut position = self.position.lock();
+
+        let rest = &self.text.as_bytes()[*position..];
+        let len = rest.len().min(buffer.len());
+
+        buffer[..len].copy_from_slice(&rest[..len]);
+        *position += len;
+
+        Ok(len)
+    }
+
+    fn poll(&self) -> Events {
+        Events::READABLE
+    }
+}
@@ -0,0 +1,489 @@
+//! Whole physical pages, from the memory the heap and the pool below 4 GiB left over
+//!
+//! The memory is split in one zone for each part of it on a single node and on one side of 4 GiB,
+//! and allocations are taken from the node they are asked for first, then from the others by
+//! distance
+//!
+//! Memory below 4 GiB is kept for devices that can not reach above it: other allocations only
+//! fall back to it while that leaves the zone above its high watermark
+//!
+//! An allocation of more than a page that finds no run free has [`compaction`] move pages out of
+//! the way before it fails
+
+use alloc::vec::Vec;
+
+use crate::{
+    compaction,
+    memory::{self, DMA32_LIMIT},
+    numa::{self, Node},
+    page::{self, Owner},
+    sync::Mutex,
+};
+
+pub const PAGE_SIZE: u64 = crate::arch::paging::PAGE_SIZE;
+
+/// Which devices can reach the memory of a zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneKind {
+    /// Below 4 GiB, for devices with 32-bit addressing
+    Dma32,
+    Normal,
+}
+
+impl ZoneKind {
+    fn name(self) -> &'static str {
+        match self {
+            ZoneKind::Dma32 => "dma32",
+            ZoneKind::Normal => "normal",
+        }
+    }
+}
+
+/// Free page counts that change how a zone is allocated from
+#[derive(Debug, Clone, Copy)]
+struct Watermarks {
+    /// Below this the zone is short of memory
+    low: usize,
+    /// Allocations that could have been served from another kind of zone do not take the zone
+    /// below this
+    high: usize,
+}
+
+impl Watermarks {
+    fn new(pages: usize) -> Self {
+        let high = (pages / 32).clamp(32, 8192);
+
+        Self {
+            low: high / 2,
+            high,
+        }
+    }
+}
+
+/// Contiguous pages on one node, with a bit set for each page that is allocated
+struct Zone {
+    node: Node,
+    kind: ZoneKind,
+    base: u64,
+    pages: usize,
+    free: usize,
+    watermarks: Watermarks,
+    used: Vec<u64>,
+}
+
+impl Zone {
+    fn new(node: Node, base: u64, pages: usize) -> Self {
+        Self {
+            node,
+            kind: if base < DMA32_LIMIT {
+                ZoneKind::Dma32
+            } else {
+                ZoneKind::Normal
+            },
+            base,
+            pages,
+            free: pages,
+            watermarks: Watermarks::new(pages),
+            used: alloc::vec![0; pages.div_ceil(64)],
+        }
+    }
+
+    fn is_used(&self, page: usize) -> bool {
+        self.used[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_used(&mut self, pages: core::ops::Range<usize>, used: bool) {
+        for page in pages {
+            if used {
+                self.used[page / 64] |= 1 << (page % 64);
+            } else {
+                self.used[page / 64] &= !(1 << (page % 64));
+            }
+        }
+    }
+
+    fn contains(&self, phys: u64) -> bool {
+        (self.base..self.base + self.pages as u64 * PAGE_SIZE).contains(&phys)
+    }
+
+    /// The first page from `page` on where a run of `count` pages can start, aligned to `count`
+    /// rounded up to a power of two
+    fn aligned(&self, page: usize, count: usize) -> usize {
+        // The alignment is of the physical address, not of the page in the zone
+        let align = count.next_power_of_two().min(512);
+        let first = (self.base / PAGE_SIZE) as usize;
+
+        (first + page).next_multiple_of(align) - first
+    }
+
+    /// The first run of `count` free pages, aligned to `count` rounded up to a power of two,
+    /// leaving at least `reserve` pages free
+    fn allocate(&mut self, count: usize, reserve: usize) -> Option<u64> {
+        if count + reserve > self.free {
+            return None;
+        }
+
+        let mut start = self.aligned(0, count);
+
+        while start + count <= self.pages {
+            match (start..start + count)
+                .rev()
+                .find(|&page| self.is_used(page))
+            {
+                Some(used) => start = self.aligned(used + 1, count),
+                None => {
+                    self.set_used(start..start + count, true);
+                    self.free -= count;
+
+                    return Some(self.base + start as u64 * PAGE_SIZE);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The run of `count` pages, aligned like [`Zone::allocate`] aligns them, with the fewest
+    /// pages in use where every page in use is one `movable` says can be moved elsewhere, if
+    /// there is enough free memory in the zone besides
+    fn movable_window(
+        &self,
+        count: usize,
+        reserve: usize,
+        movable: &impl Fn(u64) -> bool,
+    ) -> Option<u64> {
+        if count + reserve > self.free {
+            return None;
+        }
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut start = self.aligned(0, count);
+
+        while start + count <= self.pages {
+            let mut used = 0;
+
+            let fits = (start..start + count).all(|page| {
+                if !self.is_used(page) {
+                    return true;
+                }
+
+                used += 1;
+
+                movable(self.base + page as u64 * PAGE_SIZE)
+            });
+
+            if fits && best.is_none_or(|(_, fewest)| used < fewest) {
+                best = Some((start, used));
+            }
+
+            start = self.aligned(start + count, count);
+        }
+
+        best.map(|(start, _)| self.base + start as u64 * PAGE_SIZE)
+    }
+
+    /// Take the page at `phys` if it is free, returns whether it was
+    fn claim(&mut self, phys: u64) -> bool {
+        let page = ((phys - self.base) / PAGE_SIZE) as usize;
+
+        if self.is_used(page) {
+            return false;
+        }
+
+        self.set_used(page..page + 1, true);
+        self.free -= 1;
+
+        true
+    }
+
+    fn deallocate(&mut self, phys: u64, count: usize) {
+        let start = ((phys - self.base) / PAGE_SIZE) as usize;
+
+        assert!(
+            start + count <= self.pages && (start..start + count).all(|page| self.is_used(page)),
+            "frame: freeing pages at {phys:#x} that are not allocated"
+        );
+
+        self.set_used(start..start + count, false);
+        self.free += count;
+    }
+}
+
+/// How allocations on a node went
+#[derive(Debug, Clone, Copy, Default)]
+struct Statistics {
+    /// Allocations asked for on the node and taken from it
+    local: u64,
+    /// Allocations asked for on the node and taken from another one
+    remote: u64,
+    failed: u64,
+}
+
+struct Frames {
+    zones: Vec<Zone>,
+    statistics: Vec<(Node, Statistics)>,
+}
+
+impl Frames {
+    /// The zones `kind` of memory can be taken from, in the order they are tried for `node`
+    fn order(&self, kind: ZoneKind, node: Node) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.zones.len())
+            .filter(|&index| kind == ZoneKind::Normal || self.zones[index].kind == kind)
+            .collect();
+
+        // Zones of the kind asked for come first, and the others only down to their high
+        // watermark
+        order.sort_by_key(|&index| {
+            let zone = &self.zones[index];
+
+            (zone.kind != kind, numa::distance(node, zone.node))
+        });
+
+        order
+    }
+
+    /// Try `allocate` on each zone in order with the number of pages it must leave free, until
+    /// it finds pages in one, returns where they are and on which node
+    fn allocate(
+        &mut self,
+        kind: ZoneKind,
+        node: Node,
+        mut allocate: impl FnMut(&mut Zone, usize) -> Option<u64>,
+    ) -> Option<(u64, Node)> {
+        self.order(kind, node).into_iter().find_map(|index| {
+            let zone = &mut self.zones[index];
+            let reserve = match zone.kind == kind {
+                true => 0,
+                false => zone.watermarks.high,
+            };
+
+            allocate(zone, reserve).map(|phys| (phys, zone.node))
+        })
+    }
+
+    fn node_pages(&self, node: Node) -> (usize, usize) {
+        self.zones
+            .iter()
+            .filter(|zone| zone.node == node)
+            .fold((0, 0), |(pages, free), zone| {
+                (pages + zone.pages, free + zone.free)
+            })
+    }
+}
+
+static FRAMES: Mutex<Option<Frames>> = Mutex::new(None);
+
+/// Allocate `count` contiguous pages, on the node this runs on if it has room, returning the
+/// physical address of the first one
+pub fn alloc(count: usize) -> Option<u64> {
+    alloc_on(numa::current_node(), count)
+}
+
+/// Allocate `count` contiguous pages, on `node` if it has room, otherwise on the closest node
+/// that does
+pub fn alloc_on(node: Node, count: usize) -> Option<u64> {
+    alloc_in(ZoneKind::Normal, node, count)
+}
+
+/// Allocate `count` contiguous pages that devices reaching only `kind` of memory can use, on
+/// `node` if it has room, otherwise on the closest node that does
+pub fn alloc_in(kind: ZoneKind, node: Node, count: usize) -> Option<u64> {
+    if count == 0 {
+        return None;
+    }
+
+    let mut found = FRAMES
+        .lock()
+        .as_mut()?
+        .allocate(kind, node, |zone, reserve| zone.allocate(count, reserve));
+
+    // A single page fits anywhere there is one free, more may not fit between what is allocated
+    if found.is_none() && count > 1 {
+        found = compaction::compact(kind, node, count).map(|phys| (phys, numa::node_of(phys)));
+    }
+
+    let mut frames = FRAMES.lock();
+    let frames = frames.as_mut()?;
+
+    let statistics = match frames.statistics.iter_mut().find(|(n, _)| *n == node) {
+        Some((_, statistics)) => statistics,
+        None => {
+            frames.statistics.push((node, Statistics::default()));
+            &mut frames.statistics.last_mut().unwrap().1
+        }
+    };
+
+    match found {
+        Some((_, from)) if from == node => statistics.local += 1,
+        Some(_) => statistics.remote += 1,
+        None => statistics.failed += 1,
+    }
+
+    let (phys, _) = found?;
+
+    page::reset_range(phys, count, Owner::Frame, 1);
+
+    Some(phys)
+}
+
+/// Where `count` pages, aligned like they are allocated, can be made free for `kind` of memory
+/// on `node` by moving the pages in use there elsewhere, if `movable` says each of them can be
+pub fn movable_window(
+    kind: ZoneKind,
+    node: Node,
+    count: usize,
+    movable: impl Fn(u64) -> bool,
+) -> Option<u64> {
+    FRAMES
+        .lock()
+        .as_mut()?
+        .allocate(kind, node, |zone, reserve| {
+            zone.movable_window(count, reserve, &movable)
+        })
+        .map(|(phys, _)| phys)
+}
+
+/// Take the page at `phys` if it is free, returns whether it was, so what is made free where
+/// pages are moved out of is not allocated again before they all are
+pub fn claim(phys: u64) -> bool {
+    FRAMES
+        .lock()
+        .as_mut()
+        .and_then(|frames| frames.zones.iter_mut().find(|zone| zone.contains(phys)))
+        .is_some_and(|zone| zone.claim(phys))
+}
+
+/// Give back `count` pages from [`alloc`] or [`alloc_on`]
+pub fn free(phys: u64, count: usize) {
+    let mut frames = FRAMES.lock();
+
+    let zone = frames
+        .as_mut()
+        .and_then(|frames| frames.zones.iter_mut().find(|zone| zone.contains(phys)))
+        .expect("frame: freeing pages that were never allocated");
+
+    zone.deallocate(phys, count);
+
+    page::reset_range(phys, count, Owner::Free, 0);
+}
+
+/// Drop a reference to the page at `phys`, freeing it with the last one
+pub fn put(phys: u64) {
+    if page::from_phys(phys).is_some_and(page::Page::put) {
+        free(phys & !(PAGE_SIZE - 1), 1);
+    }
+}
+
+/// Call `f` with the address of every page in a zone and whether it is allocated
+pub fn for_each_frame(mut f: impl FnMut(u64, bool)) {
+    let frames = FRAMES.lock();
+
+    for zone in frames.iter().flat_map(|frames| frames.zones.iter()) {
+        for index in 0..zone.pages {
+            f(zone.base + index as u64 * PAGE_SIZE, zone.is_used(index));
+        }
+    }
+}
+
+/// Whether a zone has less free memory than its low watermark
+pub fn is_short_of_memory() -> bool {
+    FRAMES.lock().as_ref().is_some_and(|frames| {
+        frames
+            .zones
+            .iter()
+            .any(|zone| zone.free < zone.watermarks.low)
+    })
+}
+
+/// The pages on `node`, and how many of them are free
+pub fn node_pages(node: Node) -> (usize, usize) {
+    FRAMES
+        .lock()
+        .as_ref()
+        .map_or((0, 0), |frames| frames.node_pages(node))
+}
+
+pub fn dump() {
+    let Some(frames) = FRAMES.try_lock() else {
+        println!("frame: busy, try again later");
+        return;
+    };
+
+    let Some(frames) = frames.as_ref() else {
+        println!("frame: no spare memory");
+        return;
+    };
+
+    for node in numa::nodes() {
+        let (pages, free) = frames.node_pages(node);
+
+        let statistics = frames
+            .statistics
+            .iter()
+            .find(|(n, _)| *n == node)
+            .map_or(Statistics::default(), |&(_, statistics)| statistics);
+
+        println!(
+            "frame: node {}: {} of {} pages free, {} local, {} remote, {} failed allocations",
+            node, free, pages, statistics.local, statistics.remote, statistics.failed
+        );
+
+        for zone in frames.zones.iter().filter(|zone| zone.node == node) {
+            println!(
+                "frame:     {} at {:#x}: {} of {} pages free, watermarks low {} high {}",
+                zone.kind.name(),
+                zone.base,
+                zone.free,
+                zone.pages,
+                zone.watermarks.low,
+                zone.watermarks.high
+            );
+        }
+    }
+}
+
+/// Split the spare memory in zones where its node changes and at 4 GiB
+pub fn init() {
+    let mut zones = Vec::new();
+
+    for range in memory::spare_ranges() {
+        let mut start = range.start;
+
+        while start < range.end {
+            let limit = match start < DMA32_LIMIT {
+                true => range.end.min(DMA32_LIMIT),
+                false => range.end,
+            };
+            let end = numa::next_boundary(start, limit) & !(PAGE_SIZE - 1);
+            let end = if end <= start { limit } else { end };
+
+            zones.push(Zone::new(
+                numa::node_of(start),
+                start,
+                ((end - start) / PAGE_SIZE) as usize,
+            ));
+
+            start = end;
+        }
+    }
+
+    let pages: usize = zones.iter().map(|zone| zone.pages).sum();
+
+    let dma32: usize = zones
+        .iter()
+        .filter(|zone| zone.kind == ZoneKind::Dma32)
+        .map(|zone| zone.pages)
+        .sum();
+
+    println!(
+        "frame: {} MiB in {} zones, {} MiB of it below 4 GiB",
+        pages as u64 * PAGE_SIZE / (1024 * 1024),
+        zones.len(),
+        dma32 as u64 * PAGE_SIZE / (1024 * 1024)
+    );
+
+    *FRAMES.lock() = Some(Frames {
+        zones,
+        statistics: Vec::new(),
+    });
+}
@@ -0,0 +1,101 @@
+//! Futexes, words in mapped memory that threads wait on until another thread wakes them
+//!
+//! Waiters are kept by the address of the word, which is the same for every thread as there is
+//! only one address space. Like everything else without a scheduler, waiting keeps the system
+//! going until the waiter is woken, so it is woken by whatever runs meanwhile
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{
+    mmap::{self, Protection},
+    sync::Mutex,
+    wait,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The word is not aligned
+    InvalidArgument,
+    /// The word is not mapped
+    Fault,
+    /// The word did not have the value expected
+    WouldBlock,
+    TimedOut,
+}
+
+/// Who waits on each word, in the order they started waiting, each with the flag set to wake them
+static WAITERS: Mutex<BTreeMap<u64, Vec<Arc<AtomicBool>>>> = Mutex::new(BTreeMap::new());
+
+fn word(address: u64, protection: Protection) -> Result<&'static AtomicU32, Error> {
+    if !address.is_multiple_of(4) {
+        return Err(Error::InvalidArgument);
+    }
+
+    if !mmap::is_mapped(address, 4, protection) {
+        return Err(Error::Fault);
+    }
+
+    Ok(unsafe { &*(address as *const AtomicU32) })
+}
+
+/// Wait on the word at `address` while it is `expected`, until woken by [`wake`] or the deadline
+pub fn wait(address: u64, expected: u32, deadline: Option<u64>) -> Result<(), Error> {
+    let word = word(address, Protection::READ)?;
+    let woken = Arc::new(AtomicBool::new(false));
+
+    // Paged in first, as that polls the devices which must not find the waiters locked
+    word.load(Ordering::Relaxed);
+
+    {
+        // Checked with the waiters locked, so a wake after the word changed can not be missed
+        let mut waiters = WAITERS.lock();
+
+        if word.load(Ordering::SeqCst) != expected {
+            return Err(Error::WouldBlock);
+        }
+
+        waiters.entry(address).or_default().push(woken.clone());
+    }
+
+    if wait::until(deadline, || woken.load(Ordering::Acquire).then_some(())).is_some() {
+        return Ok(());
+    }
+
+    let mut waiters = WAITERS.lock();
+
+    if let Some(queue) = waiters.get_mut(&address) {
+        queue.retain(|waiter| !Arc::ptr_eq(waiter, &woken));
+
+        if queue.is_empty() {
+            waiters.remove(&address);
+        }
+    }
+
+    // Woken right as the deadline passed
+    match woken.load(Ordering::Acquire) {
+        true => Ok(()),
+        false => Err(Error::TimedOut),
+    }
+}
+
+/// Wake up to `count` of the threads waiting on the word at `address`, returns how many were
+pub fn wake(address: u64, count: usize) -> usize {
+    let mut waiters = WAITERS.lock();
+
+    let Some(queue) = waiters.get_mut(&address) else {
+        return 0;
+    };
+
+    let woken = count.min(queue.len());
+
+    for waiter in queue.drain(..woken) {
+        waiter.store(true, Ordering::Release);
+    }
+
+    if queue.is_empty() {
+        waiters.remove(&address);
+    }
+
+    woken
+}
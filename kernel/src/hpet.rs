@@ -0,0 +1,96 @@
+//! The high precision event timer, only its main counter, which is a steady reference for the
+//! time stamp counter
+
+use lazy_static::lazy_static;
+
+use crate::{acpi, mmio::Mmio};
+
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xF0;
+
+const CAPABILITY_64_BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+pub struct Hpet {
+    registers: Mmio,
+    /// How long a tick is in femtoseconds
+    period_fs: u64,
+    wide: bool,
+}
+
+impl Hpet {
+    pub fn counter(&self) -> u64 {
+        if self.wide {
+            self.registers.read::<u64>(MAIN_COUNTER)
+        } else {
+            self.registers.read::<u32>(MAIN_COUNTER) as u64
+        }
+    }
+
+    /// Ticks from `earlier` to `later`, across one wrap of a 32 bit counter
+    pub fn elapsed(&self, earlier: u64, later: u64) -> u64 {
+        if self.wide {
+            later.wrapping_sub(earlier)
+        } else {
+            (later as u32).wrapping_sub(earlier as u32) as u64
+        }
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND as u128) as u64
+    }
+
+    /// Start the counter if it is not running, it stops whenever the system sleeps
+    pub fn enable(&self) {
+        let configuration = self.registers.read::<u64>(CONFIGURATION);
+
+        if configuration & CONFIGURATION_ENABLE == 0 {
+            self.registers
+                .write(CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+}
+
+fn find() -> Option<Hpet> {
+    let table = acpi::find_table(b"HPET")?;
+
+    // The base address is a generic address structure, which must be in memory space
+    if table.len() < 56 || table.read::<u8>(40) != 0 {
+        return None;
+    }
+
+    let address = table.read::<u32>(44) as u64 | (table.read::<u32>(48) as u64) << 32;
+    let registers = Mmio::map(address, 0x100);
+    let capabilities = registers.read::<u64>(CAPABILITIES);
+    let period_fs = capabilities >> 32;
+
+    // The specification allows at most 100 nanoseconds
+    if period_fs == 0 || period_fs > 100_000_000 {
+        return None;
+    }
+
+    let hpet = Hpet {
+        registers,
+        period_fs,
+        wide: capabilities & CAPABILITY_64_BIT != 0,
+    };
+
+    hpet.enable();
+
+    Some(hpet)
+}
+
+lazy_static! {
+    static ref HPET: Option<Hpet> = find();
+}
+
+pub fn get() -> Option<&'static Hpet> {
+    HPET.as_ref()
+}
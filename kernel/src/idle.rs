@@ -0,0 +1,182 @@
+//! Sleeping between rounds of polling, in the deepest C-state that pays off before the next timer
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    arch::{
+        cstate::{self, CState},
+        wakeup,
+    },
+    cmdline,
+    sync::Mutex,
+    time, timer,
+};
+
+/// Devices are polled, so they are never left alone for longer than this
+const MAX_IDLE_NS: u64 = 1_000_000;
+
+struct State {
+    cstate: CState,
+    usage: u64,
+    residency_ns: u64,
+}
+
+struct Governor {
+    states: Vec<State>,
+    /// Times the next timer was too close for any state, so the processor only spun
+    polls: u64,
+}
+
+static GOVERNOR: Mutex<Option<Governor>> = Mutex::new(None);
+
+/// The time spent in any of the states
+static IDLE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// How long the processor slept in C-states, not counting the moments it only spun
+pub fn idle_ns() -> u64 {
+    IDLE_NS.load(Ordering::Relaxed)
+}
+
+fn record(state: &mut State, start: u64) {
+    let ns = time::monotonic_ns() - start;
+
+    state.usage += 1;
+    state.residency_ns += ns;
+    IDLE_NS.fetch_add(ns, Ordering::Relaxed);
+}
+
+/// Sleep until there is something to do, or spin for a moment when sleeping does not pay off
+pub fn idle() {
+    let Some(mut governor) = GOVERNOR.try_lock() else {
+        core::hint::spin_loop();
+        return;
+    };
+
+    let Some(governor) = governor.as_mut() else {
+        core::hint::spin_loop();
+        return;
+    };
+
+    let start = time::monotonic_ns();
+    let predicted_ns = timer::next_expiry_ns()
+        .map_or(MAX_IDLE_NS, |expiry| expiry.saturating_sub(start))
+        .min(MAX_IDLE_NS);
+
+    let Some(state) = governor
+        .states
+        .iter_mut()
+        .rev()
+        .find(|state| state.cstate.target_residency_ns <= predicted_ns)
+    else {
+        governor.polls += 1;
+        core::hint::spin_loop();
+        return;
+    };
+
+    // Be awake again by the time the timer expires
+    wakeup::arm(predicted_ns.saturating_sub(state.cstate.exit_latency_ns));
+    cstate::enter(&state.cstate);
+    wakeup::disarm();
+
+    record(state, start);
+}
+
+/// Rest in the deepest state for `duration_ns` even if there is work to do, to cool down
+pub fn inject(duration_ns: u64) {
+    let Some(mut governor) = GOVERNOR.try_lock() else {
+        return;
+    };
+
+    let Some(state) = governor
+        .as_mut()
+        .and_then(|governor| governor.states.last_mut())
+    else {
+        return;
+    };
+
+    let start = time::monotonic_ns();
+    let end = start + duration_ns;
+
+    // Anything else that wakes the processor up early only means going back to sleep
+    loop {
+        let now = time::monotonic_ns();
+
+        if now >= end {
+            break;
+        }
+
+        wakeup::arm(end - now);
+        cstate::enter(&state.cstate);
+        wakeup::disarm();
+    }
+
+    record(state, start);
+}
+
+/// Print how often each state was entered and how long was spent in it
+pub fn dump() {
+    let Some(governor) = GOVERNOR.try_lock() else {
+        println!("idle: busy, try again later");
+        return;
+    };
+
+    let Some(governor) = governor.as_ref() else {
+        println!("idle: polling only");
+        return;
+    };
+
+    println!("idle: poll: {} times", governor.polls);
+
+    for state in &governor.states {
+        println!(
+            "idle: {}: {} times, {} us, exit latency {} us, target residency {} us",
+            state.cstate.name,
+            state.usage,
+            state.residency_ns / 1000,
+            state.cstate.exit_latency_ns / 1000,
+            state.cstate.target_residency_ns / 1000
+        );
+    }
+}
+
+/// Find the C-states and the timer to wake up from them, `idle=poll` keeps spinning and
+/// `idle=halt` only uses the shallowest state
+pub fn init() {
+    let mode = cmdline::value("idle");
+
+    if mode == Some("poll") {
+        println!("idle: polling only");
+        return;
+    }
+
+    let timer = wakeup::init();
+
+    let mut states = cstate::states();
+
+    if mode == Some("halt") {
+        states.truncate(1);
+    }
+
+    println!(
+        "idle: {}, woken by the {}",
+        states
+            .iter()
+            .map(|state| state.name)
+            .collect::<Vec<_>>()
+            .join(", "),
+        timer
+    );
+
+    *GOVERNOR.lock() = Some(Governor {
+        states: states
+            .into_iter()
+            .map(|cstate| State {
+                cstate,
+                usage: 0,
+                residency_ns: 0,
+            })
+            .collect(),
+        polls: 0,
+    });
+}
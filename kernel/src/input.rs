@@ -0,0 +1,137 @@
+//! Input events from keyboards and mice, whatever they are attached to
+//!
+//! Keys and buttons use the key codes of Linux's evdev, so the drivers translate into one set of
+//! codes and whoever reads the events does not care where they came from
+
+use alloc::collections::VecDeque;
+
+use crate::{sync::Mutex, sysrq, wait::WaitQueue};
+
+pub const KEY_ESC: u16 = 1;
+pub const KEY_BACKSPACE: u16 = 14;
+pub const KEY_TAB: u16 = 15;
+pub const KEY_ENTER: u16 = 28;
+pub const KEY_LEFTCTRL: u16 = 29;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_RIGHTSHIFT: u16 = 54;
+pub const KEY_LEFTALT: u16 = 56;
+pub const KEY_SYSRQ: u16 = 99;
+pub const KEY_RIGHTALT: u16 = 100;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_PAGEUP: u16 = 104;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_PAGEDOWN: u16 = 109;
+pub const KEY_INSERT: u16 = 110;
+pub const KEY_DELETE: u16 = 111;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+pub const BTN_SIDE: u16 = 0x113;
+pub const BTN_EXTRA: u16 = 0x114;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A key or a mouse button went down or up
+    Key { code: u16, pressed: bool },
+    /// The mouse moved, or its wheel turned
+    Motion { dx: i32, dy: i32, wheel: i32 },
+}
+
+/// Events past this many unread ones push out the oldest
+const QUEUE_LIMIT: usize = 256;
+
+struct State {
+    events: VecDeque<Event>,
+    /// How many of the alt keys are down
+    alt: u8,
+    sysrq: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    events: VecDeque::new(),
+    alt: 0,
+    sysrq: false,
+});
+
+static WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+/// The character a key types on a US layout, with shift held or not, for what reads keys as
+/// text without a keymap like the sysrq keys and the debug shell
+pub fn character(code: u16, shift: bool) -> Option<u8> {
+    let (first, row, shifted) = match code {
+        2..=13 => (2, b"1234567890-=".as_slice(), b"!@#$%^&*()_+".as_slice()),
+        KEY_BACKSPACE => return Some(0x08),
+        KEY_TAB => return Some(b'\t'),
+        16..=27 => (16, b"qwertyuiop[]".as_slice(), b"QWERTYUIOP{}".as_slice()),
+        KEY_ENTER => return Some(b'\n'),
+        30..=41 => (30, b"asdfghjkl;'`".as_slice(), b"ASDFGHJKL:\"~".as_slice()),
+        43..=53 => (43, b"\\zxcvbnm,./".as_slice(), b"|ZXCVBNM<>?".as_slice()),
+        57 => return Some(b' '),
+        _ => return None,
+    };
+
+    let index = (code - first) as usize;
+
+    Some(if shift { shifted[index] } else { row[index] })
+}
+
+/// The key that types `character` on a US layout, and whether shift has to be held for it
+pub fn key_for(character: u8) -> Option<(u16, bool)> {
+    (1..=57).find_map(|code| {
+        [false, true]
+            .into_iter()
+            .find(|&shift| self::character(code, shift) == Some(character))
+            .map(|shift| (code, shift))
+    })
+}
+
+/// The letters of the key codes, for picking the sysrq action
+fn letter(code: u16) -> Option<u8> {
+    character(code, false).filter(u8::is_ascii_lowercase)
+}
+
+/// Called by the drivers for every event, Alt+SysRq with a letter runs a sysrq action
+pub fn report(event: Event) {
+    let mut state = STATE.lock();
+
+    let mut action = None;
+
+    if let Event::Key { code, pressed } = event {
+        match code {
+            KEY_LEFTALT | KEY_RIGHTALT if pressed => state.alt += 1,
+            KEY_LEFTALT | KEY_RIGHTALT => state.alt = state.alt.saturating_sub(1),
+            KEY_SYSRQ => state.sysrq = pressed,
+            code if pressed && state.alt != 0 && state.sysrq => action = letter(code),
+            _ => {}
+        }
+    }
+
+    if state.events.len() == QUEUE_LIMIT {
+        state.events.pop_front();
+    }
+
+    state.events.push_back(event);
+
+    drop(state);
+
+    WAIT_QUEUE.wake_all();
+
+    if let Some(key) = action {
+        sysrq::handle(key);
+    }
+}
+
+/// Take the oldest unread event
+pub fn read() -> Option<Event> {
+    STATE.lock().events.pop_front()
+}
+
+/// Woken whenever there is a new event
+pub fn wait_queue() -> &'static WaitQueue {
+    &WAIT_QUEUE
+}
@@ -0,0 +1,86 @@
+//! Statistics of the interrupts raised, and of those that went wrong
+//!
+//! Every vector counts how often it was raised. Spurious interrupts, vectors raised with no
+//! handler and interrupts acknowledged that were not being serviced are counted apart and
+//! printed, so an interrupt that seems lost can be told from one that never came. A vector that
+//! keeps misfiring would flood the console, so only a few of those lines are printed in a while
+//! and the rest are counted as suppressed
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    log::{self, RateLimit},
+    rand,
+};
+
+pub const VECTORS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// Raised by a controller with nothing being requested any more
+    Spurious,
+    /// Raised on a vector nothing handles
+    Unhandled,
+    /// Acknowledged while the controller was not servicing it
+    EoiMismatch,
+}
+
+impl Problem {
+    fn describe(self) -> &'static str {
+        match self {
+            Problem::Spurious => "spurious interrupt",
+            Problem::Unhandled => "unhandled interrupt",
+            Problem::EoiMismatch => "end of interrupt that was not in service",
+        }
+    }
+}
+
+static COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+static PROBLEMS: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+
+static LIMIT: RateLimit = RateLimit::new(log::RATELIMIT_INTERVAL_NS, log::RATELIMIT_BURST);
+
+/// Count that `vector` was raised, and take when it was as entropy
+pub fn raised(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    rand::add_interrupt_entropy(vector);
+}
+
+/// Count and print that `vector` went wrong with `problem`
+pub fn report(vector: u8, problem: Problem) {
+    let count = PROBLEMS[problem as usize].fetch_add(1, Ordering::Relaxed) + 1;
+
+    if LIMIT.check("irq") {
+        crate::println_nowait!(
+            "irq: {} on vector {:#04x}, {} so far",
+            problem.describe(),
+            vector,
+            count
+        );
+    }
+}
+
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// How many interrupts went wrong with `problem`
+pub fn problems(problem: Problem) -> u64 {
+    PROBLEMS[problem as usize].load(Ordering::Relaxed)
+}
+
+pub fn dump() {
+    for (vector, count) in COUNTS.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+
+        if count != 0 {
+            println!("irq: vector {:#04x}: {} times", vector, count);
+        }
+    }
+
+    for problem in [Problem::Spurious, Problem::Unhandled, Problem::EoiMismatch] {
+        println!("irq: {}: {} times", problem.describe(), problems(problem));
+    }
+
+    println!("irq: {} messages suppressed", LIMIT.suppressed());
+}
@@ -0,0 +1,461 @@
+//! A lite version of KASAN, which detects out of bounds and use after free accesses to the heap
+//!
+//! The kernel is compiled with `-Zsanitizer=kernel-address` using outlined checks, so every memory
+//! access calls one of the `__asan_*` functions below. Only the heap has shadow memory, accesses
+//! anywhere else are always allowed
+
+use core::{
+    alloc::{Allocator, GlobalAlloc, Layout},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::arch::backtrace;
+
+/// Every byte of shadow memory describes 8 bytes of the heap (a granule)
+const GRANULE_SIZE: usize = 8;
+
+/// The bytes of the granule are all accessible, values from 1 to 7 mean only that many bytes are
+const ACCESSIBLE: u8 = 0x00;
+const LEFT_REDZONE: u8 = 0xFA;
+const RIGHT_REDZONE: u8 = 0xFB;
+const UNALLOCATED: u8 = 0xFC;
+const FREED: u8 = 0xFD;
+
+/// The left redzone of every allocation holds its metadata at its end, right before the
+/// allocation, and is larger for allocations aligned to more than this so they stay aligned
+const LEFT_REDZONE_SIZE: usize = 128;
+const RIGHT_REDZONE_SIZE: usize = 16;
+
+const TRACE_DEPTH: usize = 6;
+
+/// How many freed allocations are kept poisoned before really freeing them, the more there are the
+/// later a use after free can still be detected
+const QUARANTINE_SIZE: usize = 256;
+
+/// How far behind an invalid access we look for the metadata of the allocation it belongs to
+const MAX_METADATA_DISTANCE: usize = 64 * 1024;
+
+const METADATA_MAGIC: u64 = 0x4B41_5341_4E4D_4554;
+
+static SHADOW_START: AtomicUsize = AtomicUsize::new(0);
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Accesses are not checked while this is not zero, the allocator needs to touch memory which is
+/// poisoned on purpose, and generic functions it calls are instrumented even though it is not
+static SUPPRESSED: AtomicUsize = AtomicUsize::new(0);
+
+struct Suppressed;
+
+impl Suppressed {
+    fn new() -> Self {
+        SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Suppressed {
+    fn drop(&mut self) {
+        SUPPRESSED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Metadata {
+    magic: u64,
+    size: usize,
+    allocated_at: [u64; TRACE_DEPTH],
+    freed_at: [u64; TRACE_DEPTH],
+}
+
+const _: () = assert!(size_of::<Metadata>() <= LEFT_REDZONE_SIZE);
+
+/// Take the shadow memory for the heap out of the heap itself, returning what is left of the heap
+pub fn init(heap: &'static mut [u8]) -> &'static mut [u8] {
+    let shadow_size = heap.len().div_ceil(GRANULE_SIZE + 1);
+    let heap_size = heap.len() - shadow_size;
+
+    let (heap, shadow) = heap.split_at_mut(heap_size);
+
+    shadow.fill(UNALLOCATED);
+
+    SHADOW_START.store(shadow.as_ptr().addr(), Ordering::Relaxed);
+    HEAP_START.store(heap.as_ptr().addr(), Ordering::Relaxed);
+    HEAP_END.store(heap.as_ptr().addr() + heap.len(), Ordering::Relaxed);
+
+    heap
+}
+
+fn shadow_of(address: usize) -> *mut u8 {
+    let offset = (address - HEAP_START.load(Ordering::Relaxed)) / GRANULE_SIZE;
+
+    (SHADOW_START.load(Ordering::Relaxed) + offset) as *mut u8
+}
+
+fn in_heap(address: usize) -> bool {
+    address >= HEAP_START.load(Ordering::Relaxed) && address < HEAP_END.load(Ordering::Relaxed)
+}
+
+/// Set the shadow of `[start, start + size)`, where `start` must be aligned to a granule
+fn poison(start: usize, size: usize, value: u8) {
+    unsafe {
+        shadow_of(start).write_bytes(value, size.div_ceil(GRANULE_SIZE));
+    }
+}
+
+/// Make the first `size` bytes starting from `start` accessible, where `start` must be aligned to a
+/// granule
+fn unpoison(start: usize, size: usize) {
+    poison(start, size / GRANULE_SIZE * GRANULE_SIZE, ACCESSIBLE);
+
+    if !size.is_multiple_of(GRANULE_SIZE) {
+        unsafe {
+            shadow_of(start + size).write((size % GRANULE_SIZE) as u8);
+        }
+    }
+}
+
+fn is_accessible(address: usize) -> bool {
+    let shadow = unsafe { shadow_of(address).read() };
+
+    shadow == ACCESSIBLE
+        || (shadow < GRANULE_SIZE as u8 && address % GRANULE_SIZE < shadow as usize)
+}
+
+fn check(address: usize, size: usize, is_write: bool) {
+    if size == 0
+        || !in_heap(address)
+        || REPORTING.load(Ordering::Relaxed)
+        || SUPPRESSED.load(Ordering::Relaxed) != 0
+    {
+        return;
+    }
+
+    let last = (address + size).min(HEAP_END.load(Ordering::Relaxed)) - 1;
+
+    // A granule is accessible up to some byte, so it is enough to check the last byte we access in
+    // every granule
+    let mut granule = address / GRANULE_SIZE * GRANULE_SIZE;
+
+    while granule <= last {
+        let byte = (granule + GRANULE_SIZE - 1).min(last);
+
+        if !is_accessible(byte) {
+            report(address, size, is_write, byte);
+            return;
+        }
+
+        granule += GRANULE_SIZE;
+    }
+}
+
+fn capture_trace() -> [u64; TRACE_DEPTH] {
+    let mut trace = [0; TRACE_DEPTH];
+    let mut depth = 0;
+
+    backtrace::walk(|address| {
+        if depth < TRACE_DEPTH {
+            trace[depth] = address;
+            depth += 1;
+        }
+    });
+
+    trace
+}
+
+/// Find the metadata of the allocation that `address` belongs to, or is the closest to
+fn find_metadata(address: usize) -> Option<Metadata> {
+    let heap_start = HEAP_START.load(Ordering::Relaxed);
+    let heap_end = HEAP_END.load(Ordering::Relaxed);
+
+    let mut granule = address / GRANULE_SIZE * GRANULE_SIZE;
+
+    let read_shadow = |granule: usize| unsafe { shadow_of(granule).read() };
+
+    if read_shadow(granule) == LEFT_REDZONE {
+        // Accessing before an allocation, the metadata is in its left redzone which we are in
+        while granule + GRANULE_SIZE < heap_end
+            && read_shadow(granule + GRANULE_SIZE) == LEFT_REDZONE
+        {
+            granule += GRANULE_SIZE;
+        }
+    } else {
+        let lowest = address
+            .saturating_sub(MAX_METADATA_DISTANCE)
+            .max(heap_start);
+
+        while read_shadow(granule) != LEFT_REDZONE {
+            if granule < lowest + GRANULE_SIZE {
+                return None;
+            }
+
+            granule -= GRANULE_SIZE;
+        }
+    }
+
+    let metadata_address = (granule + GRANULE_SIZE).checked_sub(LEFT_REDZONE_SIZE)?;
+
+    if metadata_address < heap_start {
+        return None;
+    }
+
+    let metadata = unsafe { (metadata_address as *const Metadata).read() };
+
+    (metadata.magic == METADATA_MAGIC).then_some(metadata)
+}
+
+fn print_trace(trace: &[u64]) {
+    for (depth, address) in trace
+        .iter()
+        .take_while(|address| **address != 0)
+        .enumerate()
+    {
+        println!("kasan:     #{:<2} {:#018x}", depth, address);
+    }
+}
+
+fn report(address: usize, size: usize, is_write: bool, invalid: usize) {
+    if REPORTING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let kind = match unsafe { shadow_of(invalid).read() } {
+        LEFT_REDZONE | RIGHT_REDZONE => "heap out of bounds",
+        FREED => "use after free",
+        UNALLOCATED => "access to unallocated heap memory",
+        _ => "heap out of bounds",
+    };
+
+    println!(
+        "kasan: {} on a {} of size {} at {:#x}",
+        kind,
+        if is_write { "write" } else { "read" },
+        size,
+        address
+    );
+
+    println!("kasan: accessed at:");
+    print_trace(&capture_trace());
+
+    if let Some(metadata) = find_metadata(invalid) {
+        println!(
+            "kasan: the closest allocation has a size of {} bytes and was allocated at:",
+            metadata.size
+        );
+        print_trace(&metadata.allocated_at);
+
+        if metadata.freed_at[0] != 0 {
+            println!("kasan: and freed at:");
+            print_trace(&metadata.freed_at);
+        }
+    }
+
+    REPORTING.store(false, Ordering::Relaxed);
+}
+
+fn report_invalid_free(address: usize, reason: &str) {
+    if REPORTING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    println!("kasan: {} of {:#x}", reason, address);
+
+    println!("kasan: freed at:");
+    print_trace(&capture_trace());
+
+    if let Some(metadata) = find_metadata(address)
+        && metadata.freed_at[0] != 0
+    {
+        println!("kasan: already freed at:");
+        print_trace(&metadata.freed_at);
+    }
+
+    REPORTING.store(false, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy)]
+struct Quarantined {
+    address: usize,
+    layout: Layout,
+}
+
+struct Quarantine {
+    entries: [Option<Quarantined>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+/// Wraps an allocator to set up the shadow memory and redzones of each allocation
+pub struct KasanAllocator<A> {
+    inner: A,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl<A> KasanAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                entries: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+
+    fn left_redzone_size(layout: Layout) -> usize {
+        LEFT_REDZONE_SIZE.max(layout.align())
+    }
+
+    fn inner_layout(layout: Layout) -> Option<Layout> {
+        let size = Self::left_redzone_size(layout)
+            + layout.size().next_multiple_of(GRANULE_SIZE)
+            + RIGHT_REDZONE_SIZE;
+
+        Layout::from_size_align(size, layout.align()).ok()
+    }
+}
+
+unsafe impl<A: Allocator> GlobalAlloc for KasanAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _suppressed = Suppressed::new();
+
+        let Some(inner_layout) = Self::inner_layout(layout) else {
+            return core::ptr::null_mut();
+        };
+
+        let Ok(data) = self.inner.allocate(inner_layout) else {
+            return core::ptr::null_mut();
+        };
+
+        let left_redzone_size = Self::left_redzone_size(layout);
+        let start = data.as_ptr().cast::<u8>().addr();
+        let user_start = start + left_redzone_size;
+
+        unsafe {
+            ((user_start - LEFT_REDZONE_SIZE) as *mut Metadata).write(Metadata {
+                magic: METADATA_MAGIC,
+                size: layout.size(),
+                allocated_at: capture_trace(),
+                freed_at: [0; TRACE_DEPTH],
+            });
+        }
+
+        poison(start, left_redzone_size, LEFT_REDZONE);
+        poison(
+            user_start,
+            inner_layout.size() - left_redzone_size,
+            RIGHT_REDZONE,
+        );
+        unpoison(user_start, layout.size());
+
+        user_start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _suppressed = Suppressed::new();
+
+        let user_start = ptr.addr();
+
+        let Some(start) = user_start
+            .checked_sub(Self::left_redzone_size(layout))
+            .filter(|start| in_heap(*start))
+        else {
+            report_invalid_free(user_start, "free of a pointer outside of the heap");
+            return;
+        };
+
+        let metadata = (user_start - LEFT_REDZONE_SIZE) as *mut Metadata;
+
+        unsafe {
+            if (*metadata).magic != METADATA_MAGIC {
+                report_invalid_free(user_start, "free of a pointer that was not allocated");
+                return;
+            }
+
+            if (*metadata).freed_at[0] != 0 {
+                report_invalid_free(user_start, "double free");
+                return;
+            }
+
+            (*metadata).freed_at = capture_trace();
+        }
+
+        let Some(inner_layout) = Self::inner_layout(layout) else {
+            return;
+        };
+
+        poison(user_start, layout.size(), FREED);
+
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let next = quarantine.next;
+
+            quarantine.next = (next + 1) % QUARANTINE_SIZE;
+
+            quarantine.entries[next].replace(Quarantined {
+                address: start,
+                layout: inner_layout,
+            })
+        };
+
+        if let Some(evicted) = evicted {
+            let metadata =
+                evicted.address + Self::left_redzone_size(evicted.layout) - LEFT_REDZONE_SIZE;
+
+            poison(evicted.address, evicted.layout.size(), UNALLOCATED);
+
+            unsafe {
+                // Forget about the allocation, so a double free of it is not mistaken for a first one
+                (metadata as *mut Metadata).write_bytes(0, 1);
+
+                self.inner.deallocate(
+                    NonNull::new_unchecked(evicted.address as *mut u8),
+                    evicted.layout,
+                );
+            }
+        }
+    }
+}
+
+macro_rules! define_checks {
+    ($($load:ident, $store:ident => $size:literal),*) => {
+        $(
+            #[unsafe(no_mangle)]
+            extern "C" fn $load(address: usize) {
+                check(address, $size, false);
+            }
+
+            #[unsafe(no_mangle)]
+            extern "C" fn $store(address: usize) {
+                check(address, $size, true);
+            }
+        )*
+    };
+}
+
+define_checks! {
+    __asan_load1, __asan_store1 => 1,
+    __asan_load2, __asan_store2 => 2,
+    __asan_load4, __asan_store4 => 4,
+    __asan_load8, __asan_store8 => 8,
+    __asan_load16, __asan_store16 => 16
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn __asan_loadN(address: usize, size: usize) {
+    check(address, size, false);
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn __asan_storeN(address: usize, size: usize) {
+    check(address, size, true);
+}
+
+/// Called before functions that never return, we do not poison the stack so there is nothing to do
+#[unsafe(no_mangle)]
+extern "C" fn __asan_handle_no_return() {}
@@ -0,0 +1,621 @@
+//! A debug shell on the console and the first serial port, for looking around a running kernel
+//! without building it again
+//!
+//! SysRq `g` enters it, and so does `kdb` on the command line once boot is done. It runs the next
+//! time the devices are polled rather than from inside the driver the key came through. It keeps
+//! polling them while it waits for a line, so the keyboard and timers still work, but what was
+//! running waits until `exit` leaves it
+//!
+//! Lines are edited the same way on the keyboard and over the serial port, whose escape
+//! sequences [`serial_input`](crate::serial_input) turns into keys: the arrows, home and end move
+//! in the line, and backspace and delete take out a character either side of the cursor
+//!
+//! Numbers are decimal, or hexadecimal with `0x` in front. Memory is read and written through the
+//! page tables in use, and what faults is reported instead of panicking. `help` lists the commands
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::{cpu, extable, paging::PageTableEntry, serial::SERIAL},
+    cmdline,
+    console::{self, Hexdump},
+    input::{
+        self, Event, KEY_BACKSPACE, KEY_DELETE, KEY_END, KEY_ENTER, KEY_HOME, KEY_LEFT,
+        KEY_LEFTSHIFT, KEY_RIGHT, KEY_RIGHTSHIFT,
+    },
+    paging,
+    pci::{self, Address},
+    process::{self, Pid},
+    stat,
+    sync::Mutex,
+    time, wait,
+};
+
+/// How long a line can get, what is typed past it is dropped
+const LINE_LIMIT: usize = 128;
+
+/// How much `md` shows when not told, and the most it shows
+const DUMP_LEN: u64 = 64;
+const DUMP_LIMIT: u64 = 4096;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// What the processor time was the last time `top` ran, which the next one shows the use since
+struct Sample {
+    at: u64,
+    /// How long threads ran, the processor was busy otherwise and it was idle
+    times: (u64, u64, u64),
+    processes: BTreeMap<Pid, u64>,
+}
+
+static LAST_SAMPLE: Mutex<Sample> = Mutex::new(Sample {
+    at: 0,
+    times: (0, 0, 0),
+    processes: BTreeMap::new(),
+});
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    handler: fn(&mut Terminal, &[&str]) -> Result<(), Error>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "exit",
+        usage: "",
+        description: "leave the shell and go on",
+        handler: |_, _| Ok(()),
+    },
+    Command {
+        name: "help",
+        usage: "",
+        description: "show this help",
+        handler: show_help,
+    },
+    Command {
+        name: "md",
+        usage: "<address> [length]",
+        description: "dump memory",
+        handler: dump_memory,
+    },
+    Command {
+        name: "mw",
+        usage: "<address> <value>",
+        description: "write a 64-bit word to memory",
+        handler: write_memory,
+    },
+    Command {
+        name: "pci",
+        usage: "[bus:device.function [offset [value]]]",
+        description: "list devices, or show a header, or read or write a 32-bit register",
+        handler: pci_config,
+    },
+    Command {
+        name: "ps",
+        usage: "",
+        description: "list processes and their threads",
+        handler: list_processes,
+    },
+    Command {
+        name: "pt",
+        usage: "<address>",
+        description: "show the page table entries translating an address",
+        handler: page_tables,
+    },
+    Command {
+        name: "rdmsr",
+        usage: "<msr>",
+        description: "read a model specific register",
+        handler: read_msr,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        description: "reboot immediately without syncing",
+        handler: |_, _| crate::arch::reboot(),
+    },
+    Command {
+        name: "top",
+        usage: "",
+        description: "show processor use since the last time, and each process's state and memory",
+        handler: top,
+    },
+    Command {
+        name: "wrmsr",
+        usage: "<msr> <value>",
+        description: "write a model specific register",
+        handler: write_msr,
+    },
+];
+
+#[derive(Debug)]
+enum Error {
+    /// The arguments are not what the command takes
+    Usage,
+    UnknownCommand,
+    Fault,
+    NoSuchDevice,
+    /// The page tables are being changed by what the shell interrupted
+    Busy,
+}
+
+impl From<extable::Fault> for Error {
+    fn from(_: extable::Fault) -> Self {
+        Error::Fault
+    }
+}
+
+/// Both the console and the first serial port
+struct Terminal {
+    /// The shift keys that are down
+    shift: u8,
+}
+
+impl Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        console::_print(format_args!("{}", s));
+
+        if let Some(mut serial) = SERIAL.try_lock() {
+            let _ = serial.write_str(s);
+        }
+
+        Ok(())
+    }
+}
+
+/// What a key does to the line being typed
+enum Key {
+    Character(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+}
+
+impl Terminal {
+    /// The next key pressed on the keyboard or typed on the serial port, if there is one
+    fn read(&mut self) -> Option<Key> {
+        while let Some(event) = input::read() {
+            let Event::Key { code, pressed } = event else {
+                continue;
+            };
+
+            let key = match code {
+                KEY_LEFTSHIFT | KEY_RIGHTSHIFT if pressed => {
+                    self.shift += 1;
+                    continue;
+                }
+                KEY_LEFTSHIFT | KEY_RIGHTSHIFT => {
+                    self.shift = self.shift.saturating_sub(1);
+                    continue;
+                }
+                _ if !pressed => continue,
+                KEY_ENTER => Key::Enter,
+                KEY_BACKSPACE => Key::Backspace,
+                KEY_DELETE => Key::Delete,
+                KEY_LEFT => Key::Left,
+                KEY_RIGHT => Key::Right,
+                KEY_HOME => Key::Home,
+                KEY_END => Key::End,
+                code => match input::character(code, self.shift != 0) {
+                    Some(character) => Key::Character(character),
+                    None => continue,
+                },
+            };
+
+            return Some(key);
+        }
+
+        None
+    }
+
+    /// Move the cursor back `count` characters
+    fn back(&mut self, count: usize) {
+        for _ in 0..count {
+            let _ = write!(self, "\x08");
+        }
+    }
+
+    /// Read a line, echoing it as it is typed and letting the cursor move in it
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        let mut cursor = 0;
+
+        loop {
+            let Some(key) = self.read() else {
+                wait::poll();
+                core::hint::spin_loop();
+                continue;
+            };
+
+            match key {
+                Key::Enter => {
+                    let _ = writeln!(self, "{}", &line[cursor..]);
+                    return line;
+                }
+                Key::Left if cursor > 0 => {
+                    cursor -= 1;
+                    self.back(1);
+                }
+                Key::Right if cursor < line.len() => {
+                    let _ = write!(self, "{}", &line[cursor..cursor + 1]);
+                    cursor += 1;
+                }
+                Key::Home => {
+                    self.back(cursor);
+                    cursor = 0;
+                }
+                Key::End => {
+                    let _ = write!(self, "{}", &line[cursor..]);
+                    cursor = line.len();
+                }
+                Key::Backspace | Key::Delete => {
+                    if matches!(key, Key::Backspace) {
+                        if cursor == 0 {
+                            continue;
+                        }
+
+                        cursor -= 1;
+                        self.back(1);
+                    } else if cursor == line.len() {
+                        continue;
+                    }
+
+                    line.remove(cursor);
+
+                    // What was after it moves over, and the last cell is blanked
+                    let _ = write!(self, "{} ", &line[cursor..]);
+                    self.back(line.len() - cursor + 1);
+                }
+                Key::Character(byte)
+                    if (byte.is_ascii_graphic() || byte == b' ') && line.len() < LINE_LIMIT =>
+                {
+                    line.insert(cursor, byte as char);
+
+                    let _ = write!(self, "{}", &line[cursor..]);
+                    cursor += 1;
+                    self.back(line.len() - cursor);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A number in decimal, or in hexadecimal with `0x` in front
+fn number(text: &str) -> Result<u64, Error> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| Error::Usage)
+}
+
+/// A PCI address written like `00:1f.3`, all in hexadecimal
+fn pci_address(text: &str) -> Result<Address, Error> {
+    let (bus, rest) = text.split_once(':').ok_or(Error::Usage)?;
+    let (device, function) = rest.split_once('.').ok_or(Error::Usage)?;
+
+    let parse = |text: &str| u8::from_str_radix(text, 16).map_err(|_| Error::Usage);
+    let (bus, device, function) = (parse(bus)?, parse(device)?, parse(function)?);
+
+    if device >= 32 || function >= 8 {
+        return Err(Error::Usage);
+    }
+
+    Ok(Address::new(bus, device, function))
+}
+
+fn show_help(terminal: &mut Terminal, _: &[&str]) -> Result<(), Error> {
+    for command in COMMANDS {
+        let _ = writeln!(
+            terminal,
+            "{} {}: {}",
+            command.name, command.usage, command.description
+        );
+    }
+
+    Ok(())
+}
+
+fn dump_memory(terminal: &mut Terminal, arguments: &[&str]) -> Result<(), Error> {
+    let (address, len) = match arguments {
+        [address] => (number(address)?, DUMP_LEN),
+        [address, len] => (number(address)?, number(len)?.min(DUMP_LIMIT)),
+        _ => return Err(Error::Usage),
+    };
+
+    let _ = writeln!(terminal, "{}", Hexdump::memory(address, len as usize));
+
+    Ok(())
+}
+
+fn write_memory(_: &mut Terminal, arguments: &[&str]) -> Result<(), Error> {
+    let [address, value] = arguments else {
+        return Err(Error::Usage);
+    };
+
+    let (address, value) = (number(address)?, number(value)?.to_le_bytes());
+
+    // Whatever is there was asked to be overwritten, and a fault stops the copy
+    unsafe { extable::copy(address as *mut u8, value.as_ptr(), value.len())? };
+
+    Ok(())
+}
+
+fn pci_config(terminal: &mut Terminal, arguments: &[&str]) -> Result<(), Error> {
+    let Some((address, rest)) = arguments.split_first() else {
+        for device in pci::devices() {
+            let _ = writeln!(terminal, "{}", device);
+        }
+
+        return Ok(());
+    };
+
+    let address = pci_address(address)?;
+    let device = pci::devices()
+        .iter()
+        .find(|device| device.address == address)
+        .ok_or(Error::NoSuchDevice)?;
+
+    let offset = |text: &str| {
+        number(text)
+            .ok()
+            .filter(|offset| *offset < 256 && offset.is_multiple_of(4))
+            .map(|offset| offset as u8)
+            .ok_or(Error::Usage)
+    };
+
+    match rest {
+        [] => {
+            let _ = writeln!(terminal, "{}", device.header());
+        }
+        [register] => {
+            let _ = writeln!(terminal, "{:#010x}", address.read_u32(offset(register)?));
+        }
+        [register, value] => {
+            let value = u32::try_from(number(value)?).map_err(|_| Error::Usage)?;
+
+            address.write_u32(offset(register)?, value);
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn list_processes(terminal: &mut Terminal, _: &[&str]) -> Result<(), Error> {
+    let current = process::current().map(|thread| thread.tid());
+
+    for process in process::all() {
+        let _ = write!(
+            terminal,
+            "{} parent {} group {} session {} cpu {} ms",
+            process.pid(),
+            process.parent(),
+            process.pgid(),
+            process.sid(),
+            process.cpu_time_ns() / 1_000_000
+        );
+
+        match process.exit_status() {
+            Some(status) => {
+                let _ = write!(terminal, " exited with {}", status);
+            }
+            None => {
+                let _ = write!(terminal, " threads");
+
+                for tid in process.threads() {
+                    let marker = if Some(tid) == current { "*" } else { "" };
+
+                    let _ = write!(terminal, " {}{}", tid, marker);
+                }
+            }
+        }
+
+        let _ = writeln!(terminal);
+    }
+
+    Ok(())
+}
+
+/// `part` of `whole` in tenths of a percent
+fn permille(part: u64, whole: u64) -> u64 {
+    (part as u128 * 1000 / whole.max(1) as u128) as u64
+}
+
+fn top(terminal: &mut Terminal, _: &[&str]) -> Result<(), Error> {
+    let now = time::monotonic_ns();
+    let times = stat::cpu_times();
+    let processes = process::all();
+
+    let mut last = LAST_SAMPLE.lock();
+    let elapsed = now - last.at;
+
+    let percent = |part: u64| {
+        let permille = permille(part, elapsed);
+
+        (permille / 10, permille % 10)
+    };
+
+    let (user, system, idle) = (
+        percent(times.0.saturating_sub(last.times.0)),
+        percent(times.1.saturating_sub(last.times.1)),
+        percent(times.2.saturating_sub(last.times.2)),
+    );
+
+    let _ = writeln!(
+        terminal,
+        "cpu: {}.{}% threads, {}.{}% system, {}.{}% idle over {} ms",
+        user.0,
+        user.1,
+        system.0,
+        system.1,
+        idle.0,
+        idle.1,
+        elapsed / 1_000_000
+    );
+    let _ = writeln!(
+        terminal,
+        "{:>6} s {:>6} {:>10} {:>12} threads",
+        "pid", "cpu%", "cpu ms", "memory KiB"
+    );
+
+    let mut sample = BTreeMap::new();
+
+    for process in processes {
+        let cpu_time = process.cpu_time_ns();
+        let before = last.processes.get(&process.pid()).copied().unwrap_or(0);
+        let cpu = percent(cpu_time - before);
+
+        // What was interrupted may be changing the image
+        let memory = match process.memory() {
+            Some(memory) => match memory.try_lock() {
+                Some(image) => format!("{}", image.address_space_size() / 1024),
+                None => String::from("busy"),
+            },
+            None => String::from("-"),
+        };
+
+        let _ = writeln!(
+            terminal,
+            "{:>6} {} {:>4}.{} {:>10} {:>12} {}",
+            process.pid(),
+            process.state().letter(),
+            cpu.0,
+            cpu.1,
+            cpu_time / 1_000_000,
+            memory,
+            process.threads().len()
+        );
+
+        sample.insert(process.pid(), cpu_time);
+    }
+
+    *last = Sample {
+        at: now,
+        times,
+        processes: sample,
+    };
+
+    Ok(())
+}
+
+fn page_tables(terminal: &mut Terminal, arguments: &[&str]) -> Result<(), Error> {
+    let [address] = arguments else {
+        return Err(Error::Usage);
+    };
+
+    let address = number(address)?;
+
+    // Printing allocates, which may change the page tables, so the entries are kept first
+    let mut entries = Vec::with_capacity(4);
+
+    if !paging::try_levels(address, |level, entry| entries.push((level, entry))) {
+        return Err(Error::Busy);
+    }
+
+    for (level, entry) in entries {
+        let _ = writeln!(terminal, "level {}: {}", level, PageTableEntry(entry));
+    }
+
+    Ok(())
+}
+
+fn read_msr(terminal: &mut Terminal, arguments: &[&str]) -> Result<(), Error> {
+    let [msr] = arguments else {
+        return Err(Error::Usage);
+    };
+
+    let msr = u32::try_from(number(msr)?).map_err(|_| Error::Usage)?;
+    let value = cpu::rdmsr_safe(msr).ok_or(Error::Fault)?;
+
+    let _ = writeln!(terminal, "{:#018x}", value);
+
+    Ok(())
+}
+
+fn write_msr(_: &mut Terminal, arguments: &[&str]) -> Result<(), Error> {
+    let [msr, value] = arguments else {
+        return Err(Error::Usage);
+    };
+
+    let msr = u32::try_from(number(msr)?).map_err(|_| Error::Usage)?;
+
+    match cpu::wrmsr_safe(msr, number(value)?) {
+        true => Ok(()),
+        false => Err(Error::Fault),
+    }
+}
+
+/// Run the shell until `exit`
+fn run() {
+    let mut terminal = Terminal { shift: 0 };
+
+    let _ = writeln!(terminal, "kdb: entered, type help for the commands");
+
+    loop {
+        let _ = write!(terminal, "kdb> ");
+
+        let line = terminal.read_line();
+        let words = line.split_whitespace().collect::<Vec<_>>();
+
+        let Some((name, arguments)) = words.split_first() else {
+            continue;
+        };
+
+        let Some(command) = COMMANDS.iter().find(|command| command.name == *name) else {
+            let _ = writeln!(terminal, "kdb: {}: {:?}", name, Error::UnknownCommand);
+            continue;
+        };
+
+        match (command.handler)(&mut terminal, arguments) {
+            Err(Error::Usage) => {
+                let _ = writeln!(terminal, "usage: {} {}", command.name, command.usage);
+            }
+            Err(error) => {
+                let _ = writeln!(terminal, "kdb: {}: {:?}", name, error);
+            }
+            Ok(()) if command.name == "exit" => break,
+            Ok(()) => {}
+        }
+    }
+
+    let _ = writeln!(terminal, "kdb: left");
+}
+
+/// Enter the shell the next time the devices are polled
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Run the shell if it was asked for, called from where the devices are polled so it never runs
+/// inside a driver
+pub fn poll() {
+    if !REQUESTED.load(Ordering::Relaxed) || ACTIVE.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    REQUESTED.store(false, Ordering::Relaxed);
+
+    run();
+
+    ACTIVE.store(false, Ordering::Release);
+}
+
+/// Enter the shell once boot is done if the command line asks for it
+pub fn init() {
+    if cmdline::has("kdb") {
+        request();
+    }
+}
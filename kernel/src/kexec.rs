@@ -0,0 +1,389 @@
+//! Booting another kernel straight from this one, without going back through the firmware
+//!
+//! Memory for the new kernel is reserved at boot with `kexec=<MiB>`, and an ELF executable is
+//! loaded into it, either by [`load`] or from the bootloader module named by `kexec.image=<path>`
+//! with the module's string as its command line. Its segments are copied to their physical
+//! addresses once every device is quiesced, and its entry is jumped to in long mode with interrupts
+//! disabled, physical memory mapped one to one and `rdi` pointing at a [`BootInfo`]
+//!
+//! The boot information, the command line and the memory map are in memory that the map says is
+//! usable, so the new kernel has to read them before it allocates anything
+
+use alloc::vec::Vec;
+
+use limine::memory_map::EntryType as MemoryEntryType;
+
+use crate::{
+    arch::{
+        interrupts,
+        kexec::{self, IDENTITY_LIMIT, MAX_SEGMENTS, Segment},
+        paging::PAGE_SIZE,
+        wakeup,
+    },
+    cmdline,
+    dma::{self, CoherentBuffer, Constraints},
+    paging, pci,
+    requests::{MEMORY_MAP_REQUEST, MODULE_REQUEST, RSDP_REQUEST},
+    suspend,
+    sync::Mutex,
+};
+
+/// Which the new kernel can check `rdi` against before trusting it
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"FAJRKEXC");
+
+/// What the new kernel is handed, all addresses are physical
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub command_line: u64,
+    pub command_line_len: u64,
+    /// An array of [`MemoryRegion`]
+    pub memory_map: u64,
+    pub memory_map_len: u64,
+    /// Zero if there is none
+    pub rsdp: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub length: u64,
+    /// The type of the entry in the limine boot protocol's memory map
+    pub kind: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Nothing was reserved with `kexec=<MiB>`, or the reservation failed
+    NotReserved,
+    /// Not a 64 bit little endian x86_64 executable
+    NotElf,
+    /// The image does not fit in the reserved memory, or has too many segments
+    TooLarge,
+    /// A segment would land on the reserved memory, or where physical memory can not be mapped
+    BadAddress,
+    NotLoaded,
+}
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+const PROGRAM_LOAD: u32 = 1;
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// A loadable segment of the executable, its data is `offset..offset + file_len` in the file
+struct ProgramHeader {
+    offset: u64,
+    virt: u64,
+    phys: u64,
+    file_len: u64,
+    memory_len: u64,
+}
+
+/// The loadable segments and the virtual entry of an executable
+fn parse(elf: &[u8]) -> Option<(Vec<ProgramHeader>, u64)> {
+    if read::<4>(elf, 0)? != ELF_MAGIC
+        || elf.get(4) != Some(&ELF_CLASS_64)
+        || elf.get(5) != Some(&ELF_DATA_LITTLE_ENDIAN)
+        || read_u16(elf, 16)? != ELF_TYPE_EXECUTABLE
+        || read_u16(elf, 18)? != ELF_MACHINE_X86_64
+    {
+        return None;
+    }
+
+    let entry = read_u64(elf, 24)?;
+    let table = read_u64(elf, 32)? as usize;
+    let entry_size = read_u16(elf, 54)? as usize;
+    let count = read_u16(elf, 56)? as usize;
+
+    let mut headers = Vec::new();
+
+    for index in 0..count {
+        let header = table.checked_add(index.checked_mul(entry_size)?)?;
+
+        if read_u32(elf, header)? != PROGRAM_LOAD {
+            continue;
+        }
+
+        let header = ProgramHeader {
+            offset: read_u64(elf, header + 8)?,
+            virt: read_u64(elf, header + 16)?,
+            phys: read_u64(elf, header + 24)?,
+            file_len: read_u64(elf, header + 32)?,
+            memory_len: read_u64(elf, header + 40)?,
+        };
+
+        if header.file_len > header.memory_len
+            || header.offset.checked_add(header.file_len)? > elf.len() as u64
+        {
+            return None;
+        }
+
+        headers.push(header);
+    }
+
+    Some((headers, entry))
+}
+
+/// A kernel ready to be jumped to, in the reserved memory
+struct Image {
+    segments: Vec<Segment>,
+    entry: u64,
+    boot_info: u64,
+    top: u64,
+}
+
+static RESERVED: Mutex<Option<CoherentBuffer>> = Mutex::new(None);
+static IMAGE: Mutex<Option<Image>> = Mutex::new(None);
+
+/// Copies things into the reserved memory one after the other
+struct Placer<'a> {
+    memory: &'a mut CoherentBuffer,
+    used: usize,
+}
+
+impl Placer<'_> {
+    /// Put `bytes` at the next `align`ed offset and return their physical address
+    fn place(&mut self, bytes: &[u8], align: usize) -> Result<u64, Error> {
+        let offset = self.used.next_multiple_of(align);
+        let end = offset.checked_add(bytes.len()).ok_or(Error::TooLarge)?;
+
+        self.memory
+            .as_mut_slice()
+            .get_mut(offset..end)
+            .ok_or(Error::TooLarge)?
+            .copy_from_slice(bytes);
+        self.used = end;
+
+        Ok(self.memory.bus_address() + offset as u64)
+    }
+}
+
+/// The number limine gives each type of memory, which the type itself does not let out
+fn kind(entry_type: MemoryEntryType) -> u64 {
+    [
+        MemoryEntryType::USABLE,
+        MemoryEntryType::RESERVED,
+        MemoryEntryType::ACPI_RECLAIMABLE,
+        MemoryEntryType::ACPI_NVS,
+        MemoryEntryType::BAD_MEMORY,
+        MemoryEntryType::BOOTLOADER_RECLAIMABLE,
+        MemoryEntryType::EXECUTABLE_AND_MODULES,
+        MemoryEntryType::FRAMEBUFFER,
+    ]
+    .iter()
+    .position(|&known| known == entry_type)
+    .map_or(1, |kind| kind as u64)
+}
+
+fn as_bytes<T>(values: &[T]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(values.as_ptr() as *const u8, size_of_val(values)) }
+}
+
+/// Load `elf` into the reserved memory to be started by [`exec`] with `command_line`, replacing
+/// whatever was loaded before
+pub fn load(elf: &[u8], command_line: &str) -> Result<(), Error> {
+    let (headers, virtual_entry) = parse(elf).ok_or(Error::NotElf)?;
+
+    if headers.is_empty() || headers.len() > MAX_SEGMENTS {
+        return Err(Error::TooLarge);
+    }
+
+    let mut reserved = RESERVED.lock();
+    let memory = reserved.as_mut().ok_or(Error::NotReserved)?;
+    let reserved_range = memory.bus_address()..memory.bus_address() + memory.len() as u64;
+
+    // The entry is given as a virtual address, the new kernel starts with paging one to one
+    let entry = headers
+        .iter()
+        .find(|header| (header.virt..header.virt + header.memory_len).contains(&virtual_entry))
+        .map(|header| header.phys + (virtual_entry - header.virt))
+        .ok_or(Error::NotElf)?;
+
+    let mut top = reserved_range.end;
+
+    for header in &headers {
+        let end = header
+            .phys
+            .checked_add(header.memory_len)
+            .ok_or(Error::BadAddress)?;
+
+        if header.phys < reserved_range.end && reserved_range.start < end {
+            return Err(Error::BadAddress);
+        }
+
+        top = top.max(end);
+    }
+
+    if top > IDENTITY_LIMIT {
+        return Err(Error::BadAddress);
+    }
+
+    *IMAGE.lock() = None;
+
+    // The trampoline's memory comes first, then what the new kernel is handed, then its segments
+    let mut placer = Placer {
+        memory,
+        used: kexec::scratch_size(top) as usize,
+    };
+
+    let memory_map: Vec<MemoryRegion> = MEMORY_MAP_REQUEST
+        .get_response()
+        .map(|response| {
+            response
+                .entries()
+                .iter()
+                .map(|entry| MemoryRegion {
+                    base: entry.base,
+                    length: entry.length,
+                    kind: kind(entry.entry_type),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let command_line_address = placer.place(command_line.as_bytes(), 8)?;
+    let memory_map_address = placer.place(as_bytes(&memory_map), 8)?;
+
+    let boot_info = BootInfo {
+        magic: BOOT_INFO_MAGIC,
+        command_line: command_line_address,
+        command_line_len: command_line.len() as u64,
+        memory_map: memory_map_address,
+        memory_map_len: memory_map.len() as u64,
+        rsdp: RSDP_REQUEST.get_response().map_or(0, |response| {
+            paging::phys_from_bootloader(response.address() as u64)
+        }),
+    };
+
+    let boot_info = placer.place(as_bytes(&[boot_info]), 8)?;
+
+    let segments = headers
+        .iter()
+        .map(|header| {
+            let data = &elf[header.offset as usize..(header.offset + header.file_len) as usize];
+
+            Ok(Segment {
+                destination: header.phys,
+                source: placer.place(data, PAGE_SIZE as usize)?,
+                copy_len: header.file_len,
+                zero_len: header.memory_len - header.file_len,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    println!(
+        "kexec: loaded {} segments, {} KiB of {} KiB reserved, entry at {:#x}",
+        segments.len(),
+        placer.used / 1024,
+        reserved_range.end.saturating_sub(reserved_range.start) / 1024,
+        entry
+    );
+
+    *IMAGE.lock() = Some(Image {
+        segments,
+        entry,
+        boot_info,
+        top,
+    });
+
+    Ok(())
+}
+
+/// Quiesce the devices and start the loaded kernel, which only returns if nothing was loaded
+pub fn exec() -> Error {
+    let memory = RESERVED.lock();
+    let image = IMAGE.lock();
+
+    let (Some(memory), Some(image)) = (memory.as_ref(), image.as_ref()) else {
+        return Error::NotLoaded;
+    };
+
+    println!("kexec: starting the new kernel");
+
+    suspend::suspend_devices();
+
+    // Devices still doing DMA would write over the new kernel
+    for device in pci::devices() {
+        device.set_command(device.command() & !pci::COMMAND_BUS_MASTER);
+    }
+
+    interrupts::disable();
+    wakeup::disarm();
+
+    kexec::jump(
+        memory.bus_address(),
+        &image.segments,
+        image.entry,
+        image.boot_info,
+        image.top,
+    )
+}
+
+/// Reserve memory from `kexec=<MiB>`, and load the module from `kexec.image=<path>` into it
+pub fn init() {
+    let Some(size) = cmdline::value("kexec") else {
+        return;
+    };
+
+    let Some(size) = size
+        .parse::<usize>()
+        .ok()
+        .and_then(|mib| mib.checked_mul(1024 * 1024))
+    else {
+        println!("kexec: invalid size {}, expected MiB", size);
+        return;
+    };
+
+    let Ok(memory) = dma::alloc_coherent(size, Constraints::new()) else {
+        println!("kexec: could not reserve {} MiB", size / 1024 / 1024);
+        return;
+    };
+
+    println!(
+        "kexec: reserved {} MiB at {:#x}",
+        size / 1024 / 1024,
+        memory.bus_address()
+    );
+
+    *RESERVED.lock() = Some(memory);
+
+    let Some(path) = cmdline::value("kexec.image") else {
+        return;
+    };
+
+    let Some(module) = MODULE_REQUEST.get_response().and_then(|response| {
+        response
+            .modules()
+            .iter()
+            .find(|module| module.path().to_bytes() == path.as_bytes())
+    }) else {
+        println!("kexec: no module at {}", path);
+        return;
+    };
+
+    let image = unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) };
+
+    if let Err(error) = load(image, module.string().to_str().unwrap_or("")) {
+        println!("kexec: could not load {}: {:?}", path, error);
+    }
+}
@@ -0,0 +1,461 @@
+//! Running programs built for Linux, with its x86_64 syscall numbers and structures
+//!
+//! Processes with the Linux [`Personality`](crate::process::Personality) have their syscalls
+//! handled by [`syscall`], which takes the arguments the way Linux lays them out, calls what the
+//! kernel has for them and returns what Linux would, a negative error number on failure. Only
+//! what a static musl program needs to start, allocate, do its I/O and exit is there, anything
+//! else fails with `ENOSYS`. The image of a program still has to be position independent for
+//! [`exec`](crate::exec) to load it
+//!
+//! There is no VFS yet, so paths are looked up from the root of what the `9p` option on the
+//! command line names, and only anonymous memory can be mapped as descriptors do not remember
+//! whether their file could be
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    arch::paging::PAGE_SIZE,
+    cmdline,
+    file::{self, OpenFlags, Snapshot},
+    meminfo,
+    mmap::{self, Protection, Sharing, Zeroes},
+    process::{self, Thread},
+    rlimit::{self, Resource},
+    stat, strace, time,
+    virtio::ninep::{self, Node},
+};
+
+const SYS_READ: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_CLOSE: u64 = 3;
+const SYS_MMAP: u64 = 9;
+const SYS_MUNMAP: u64 = 11;
+const SYS_BRK: u64 = 12;
+const SYS_WRITEV: u64 = 20;
+const SYS_EXIT: u64 = 60;
+const SYS_ARCH_PRCTL: u64 = 158;
+const SYS_SET_TID_ADDRESS: u64 = 218;
+const SYS_CLOCK_GETTIME: u64 = 228;
+const SYS_EXIT_GROUP: u64 = 231;
+const SYS_OPENAT: u64 = 257;
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const ESRCH: i64 = 3;
+const EINTR: i64 = 4;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EAGAIN: i64 = 11;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const ENODEV: i64 = 19;
+const EINVAL: i64 = 22;
+const EMFILE: i64 = 24;
+const EPIPE: i64 = 32;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+const EMSGSIZE: i64 = 90;
+const EOPNOTSUPP: i64 = 95;
+const ETIMEDOUT: i64 = 110;
+
+const MAP_SHARED: u64 = 0x1;
+const MAP_PRIVATE: u64 = 0x2;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+const CLOCK_MONOTONIC_RAW: u64 = 4;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+const CLOCK_MONOTONIC_COARSE: u64 = 6;
+const CLOCK_BOOTTIME: u64 = 7;
+
+/// The directory relative paths are looked up from
+const AT_FDCWD: i32 = -100;
+
+const PATH_MAX: usize = 4096;
+const IOV_MAX: usize = 1024;
+
+/// The size of a `struct iovec` and a `struct timespec`
+const IOVEC_SIZE: usize = 16;
+const TIMESPEC_SIZE: usize = 16;
+
+/// An error number of Linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Errno(i64);
+
+impl From<file::Error> for Errno {
+    fn from(error: file::Error) -> Self {
+        Errno(match error {
+            file::Error::WouldBlock => EAGAIN,
+            file::Error::BrokenPipe => EPIPE,
+            file::Error::InvalidArgument => EINVAL,
+            file::Error::NotSupported => EOPNOTSUPP,
+            file::Error::NotFound => ENOENT,
+            file::Error::AlreadyExists => EEXIST,
+            file::Error::NameTooLong => ENAMETOOLONG,
+            file::Error::OutOfMemory => ENOMEM,
+            file::Error::PermissionDenied => EACCES,
+            file::Error::TimedOut => ETIMEDOUT,
+            file::Error::MessageTooLong => EMSGSIZE,
+            file::Error::Io | file::Error::Network(_) => EIO,
+            file::Error::Interrupted => EINTR,
+        })
+    }
+}
+
+impl From<process::Error> for Errno {
+    fn from(error: process::Error) -> Self {
+        Errno(match error {
+            process::Error::NoThread | process::Error::NotFound => ESRCH,
+            process::Error::InvalidArgument => EINVAL,
+            process::Error::Fault => EFAULT,
+            process::Error::NotSupported => ENOSYS,
+            process::Error::PermissionDenied => EPERM,
+            process::Error::Interrupted => EINTR,
+            process::Error::TooManyFiles => EMFILE,
+        })
+    }
+}
+
+impl From<ninep::Error> for Errno {
+    fn from(error: ninep::Error) -> Self {
+        file::Error::from(error).into()
+    }
+}
+
+/// The `len` bytes at `address` in the caller's memory, which have to be mapped
+fn user_bytes(address: u64, len: usize) -> Result<&'static [u8], Errno> {
+    // A null or dangling address is fine with no bytes, but not for `from_raw_parts`
+    if len == 0 {
+        return Ok(&[]);
+    }
+
+    if !mmap::is_mapped(address, len, Protection::READ) {
+        return Err(Errno(EFAULT));
+    }
+
+    Ok(unsafe { core::slice::from_raw_parts(address as *const u8, len) })
+}
+
+/// The `len` bytes at `address` in the caller's memory, which have to be mapped writable
+fn user_bytes_mut(address: u64, len: usize) -> Result<&'static mut [u8], Errno> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+
+    if !mmap::is_mapped(address, len, Protection::WRITE) {
+        return Err(Errno(EFAULT));
+    }
+
+    Ok(unsafe { core::slice::from_raw_parts_mut(address as *mut u8, len) })
+}
+
+/// The string ending in a zero byte at `address`
+fn user_string(address: u64) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+
+    loop {
+        if bytes.len() == PATH_MAX {
+            return Err(Errno(ENAMETOOLONG));
+        }
+
+        let at = address.wrapping_add(bytes.len() as u64);
+
+        if (bytes.is_empty() || at.is_multiple_of(PAGE_SIZE))
+            && !mmap::is_mapped(at, 1, Protection::READ)
+        {
+            return Err(Errno(EFAULT));
+        }
+
+        match unsafe { *(at as *const u8) } {
+            0 => break,
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| Errno(EINVAL))
+}
+
+fn current() -> Result<Arc<Thread>, Errno> {
+    process::current().ok_or(Errno(ESRCH))
+}
+
+fn file(fd: u64) -> Result<Arc<dyn file::File>, Errno> {
+    current()?
+        .process()
+        .files()
+        .and_then(|files| files.get(fd as i32 as usize))
+        .ok_or(Errno(EBADF))
+}
+
+fn read(fd: u64, buffer: u64, count: u64) -> Result<u64, Errno> {
+    let buffer = user_bytes_mut(buffer, count as usize)?;
+
+    Ok(file(fd)?.read(buffer)? as u64)
+}
+
+fn write(fd: u64, data: u64, count: u64) -> Result<u64, Errno> {
+    let data = user_bytes(data, count as usize)?;
+
+    Ok(file(fd)?.write(data)? as u64)
+}
+
+/// Write what each `struct iovec` points at in turn, stopping at the first short write
+fn writev(fd: u64, iov: u64, count: u64) -> Result<u64, Errno> {
+    let count = count as usize;
+
+    if count > IOV_MAX {
+        return Err(Errno(EINVAL));
+    }
+
+    let file = file(fd)?;
+    let vectors = user_bytes(iov, count * IOVEC_SIZE)?;
+    let mut written = 0;
+
+    for vector in vectors.as_chunks::<IOVEC_SIZE>().0 {
+        let base = u64::from_le_bytes(vector[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(vector[8..].try_into().unwrap()) as usize;
+
+        // What was written already is what is returned, the error is seen on the next call
+        let result = user_bytes(base, len).and_then(|data| file.write(data).map_err(Errno::from));
+
+        match result {
+            Ok(done) => {
+                written += done;
+
+                if done < len {
+                    break;
+                }
+            }
+            Err(error) if written == 0 => return Err(error),
+            Err(_) => break,
+        }
+    }
+
+    Ok(written as u64)
+}
+
+fn close(fd: u64) -> Result<u64, Errno> {
+    current()?
+        .process()
+        .files()
+        .and_then(|files| files.close(fd as i32 as usize))
+        .ok_or(Errno(EBADF))?;
+
+    Ok(0)
+}
+
+/// The root of the shared directory paths are looked up in
+fn root() -> Result<Node, Errno> {
+    let tag = cmdline::value("9p").ok_or(Errno(ENOENT))?;
+
+    Ok(ninep::mount(tag)?)
+}
+
+/// What is in the file at `path` under `/proc`, if it is one of those made up
+fn proc_file(path: &str) -> Result<Option<String>, Errno> {
+    let Some(rest) = path.strip_prefix("/proc/") else {
+        return Ok(None);
+    };
+
+    let text = match rest.split_once('/') {
+        None if rest == "meminfo" => meminfo::contents(),
+        None if rest == "stat" => stat::contents(),
+        Some((pid, "stat")) => {
+            let process = match pid {
+                "self" => current()?.process().clone(),
+                pid => pid
+                    .parse()
+                    .ok()
+                    .and_then(process::get)
+                    .ok_or(Errno(ENOENT))?,
+            };
+
+            stat::process_contents(&process)
+        }
+        Some((tid, "sched")) => {
+            let thread = match tid {
+                "self" => current()?,
+                tid => tid
+                    .parse()
+                    .ok()
+                    .and_then(process::thread)
+                    .ok_or(Errno(ENOENT))?,
+            };
+
+            stat::sched_contents(&thread)
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(text))
+}
+
+fn openat(directory: u64, path: u64, flags: u64, mode: u64) -> Result<u64, Errno> {
+    let path = user_string(path)?;
+    let flags = OpenFlags::from_bits_truncate(flags as u32);
+
+    // Without a working directory or directory descriptors, a relative path is from the root
+    if !path.starts_with('/') && directory as i32 != AT_FDCWD {
+        return Err(Errno(EBADF));
+    }
+
+    let files = current()?.process().files().ok_or(Errno(ESRCH))?;
+    let limit = rlimit::get(Resource::OpenFiles).soft;
+
+    // There is no procfs, the files in it that are asked for the most are made up here
+    if let Some(text) = proc_file(&path)? {
+        return Ok(files.install(Arc::new(Snapshot::new(text)), limit)? as u64);
+    }
+
+    let root = root()?;
+
+    let file = match root.walk(&path) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(Errno(EEXIST));
+        }
+        Ok(node) => node.open(flags)?,
+        Err(error) if flags.contains(OpenFlags::CREATE) => {
+            if file::Error::from(error) != file::Error::NotFound {
+                return Err(error.into());
+            }
+
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+
+            root.walk(parent)?
+                .create(name, flags, mode as u32 & 0o7777)?
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    Ok(files.install(Arc::new(file), limit)? as u64)
+}
+
+fn mmap(
+    address: u64,
+    len: u64,
+    protection: u64,
+    flags: u64,
+    _fd: u64,
+    offset: u64,
+) -> Result<u64, Errno> {
+    let protection = Protection::from_bits(protection as u32).ok_or(Errno(EINVAL))?;
+
+    let sharing = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => Sharing::Shared,
+        MAP_PRIVATE => Sharing::Private,
+        _ => return Err(Errno(EINVAL)),
+    };
+
+    // Where the program asks for is only a hint, and one it insists on can not be given
+    if flags & MAP_FIXED != 0 && address != 0 {
+        return Err(Errno(EINVAL));
+    }
+
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno(ENODEV));
+    }
+
+    let memory = current()?.process().memory().ok_or(Errno(ESRCH))?;
+
+    let start = memory
+        .lock()
+        .mmap(Arc::new(Zeroes), offset, len as usize, protection, sharing)?;
+
+    Ok(start)
+}
+
+fn munmap(address: u64, len: u64) -> Result<u64, Errno> {
+    let memory = current()?.process().memory().ok_or(Errno(ESRCH))?;
+
+    memory.lock().munmap(address, len as usize)?;
+
+    Ok(0)
+}
+
+fn brk(address: u64) -> Result<u64, Errno> {
+    let memory = current()?.process().memory().ok_or(Errno(ESRCH))?;
+
+    Ok(memory.lock().brk(address))
+}
+
+fn arch_prctl(code: u64, address: u64) -> Result<u64, Errno> {
+    let current = current()?;
+
+    match code {
+        ARCH_SET_FS => current.set_thread_pointer(address),
+        ARCH_GET_FS => {
+            let pointer = current.thread_pointer().to_le_bytes();
+
+            user_bytes_mut(address, pointer.len())?.copy_from_slice(&pointer);
+        }
+        _ => return Err(Errno(EINVAL)),
+    }
+
+    Ok(0)
+}
+
+/// Write the time of `clock` as a `struct timespec`
+fn clock_gettime(clock: u64, timespec: u64) -> Result<u64, Errno> {
+    let ns = match clock {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => time::boot_realtime_ns() + time::monotonic_ns(),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            time::monotonic_ns()
+        }
+        _ => return Err(Errno(EINVAL)),
+    };
+
+    let buffer = user_bytes_mut(timespec, TIMESPEC_SIZE)?;
+
+    buffer[..8].copy_from_slice(&(ns / time::NANOSECONDS_PER_SECOND).to_le_bytes());
+    buffer[8..].copy_from_slice(&(ns % time::NANOSECONDS_PER_SECOND).to_le_bytes());
+
+    Ok(0)
+}
+
+fn dispatch(number: u64, [a, b, c, d, e, f]: [u64; 6]) -> Result<u64, Errno> {
+    match number {
+        SYS_READ => read(a, b, c),
+        SYS_WRITE => write(a, b, c),
+        SYS_CLOSE => close(a),
+        SYS_MMAP => mmap(a, b, c, d, e, f),
+        SYS_MUNMAP => munmap(a, b),
+        SYS_BRK => brk(a),
+        SYS_WRITEV => writev(a, b, c),
+        SYS_EXIT => {
+            process::exit(a as i32);
+            Ok(0)
+        }
+        SYS_ARCH_PRCTL => arch_prctl(a, b),
+        SYS_SET_TID_ADDRESS => Ok(process::set_tid_address(a)? as u64),
+        SYS_CLOCK_GETTIME => clock_gettime(a, b),
+        SYS_EXIT_GROUP => {
+            process::exit_group(a as i32);
+            Ok(0)
+        }
+        SYS_OPENAT => openat(a, b, c, d),
+        _ => Err(Errno(ENOSYS)),
+    }
+}
+
+/// Handle the Linux syscall `number` of the current thread, returns what goes back in `rax`
+pub fn syscall(number: u64, arguments: [u64; 6]) -> i64 {
+    let call = strace::enter(number, arguments);
+
+    let result = match dispatch(number, arguments) {
+        Ok(value) => value as i64,
+        Err(Errno(errno)) => -errno,
+    };
+
+    if let Some(call) = call {
+        strace::exit(call, result);
+    }
+
+    result
+}
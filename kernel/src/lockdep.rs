@@ -0,0 +1,200 @@
+//! A lite version of lockdep, which tracks the order in which lock classes are acquired and
+//! reports the first acquisition that inverts a previously observed order
+//!
+//! Since we can not unwind the stack yet, the locations of the acquisitions are reported instead
+//! of full backtraces. Nothing here may allocate or use a tracked lock, as those would recurse
+//! back into us
+
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::console::CONSOLE;
+
+const MAX_CLASSES: usize = 64;
+const MAX_HELD: usize = 16;
+
+type Site = &'static Location<'static>;
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    site: Site,
+}
+
+/// An observed ordering, the lock of class `before` was acquired at `before_site` and was still
+/// held while acquiring a lock of class `after` at `after_site`
+#[derive(Clone, Copy)]
+struct Dependency {
+    before_site: Site,
+    after_site: Site,
+}
+
+struct State {
+    classes: [Option<Site>; MAX_CLASSES],
+    held: [Option<Held>; MAX_HELD],
+    held_len: usize,
+    dependencies: [[Option<Dependency>; MAX_CLASSES]; MAX_CLASSES],
+}
+
+struct Inversion {
+    held: Site,
+    acquired: Site,
+    previous: Dependency,
+    current: Dependency,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    classes: [None; MAX_CLASSES],
+    held: [None; MAX_HELD],
+    held_len: 0,
+    dependencies: [[None; MAX_CLASSES]; MAX_CLASSES],
+});
+
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+impl State {
+    fn class_index(&mut self, class: Site) -> Option<usize> {
+        for (index, slot) in self.classes.iter_mut().enumerate() {
+            match slot {
+                Some(registered) if core::ptr::eq(*registered, class) => return Some(index),
+                Some(_) => continue,
+                None => {
+                    *slot = Some(class);
+                    return Some(index);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+pub fn acquire(class: Site, site: Site, try_lock: bool) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut inversion = None;
+
+    {
+        let mut state = STATE.lock();
+
+        let Some(class_index) = state.class_index(class) else {
+            disable("too many lock classes");
+            return;
+        };
+
+        if !try_lock {
+            let (held_locks, held_len) = (state.held, state.held_len);
+
+            for held in held_locks[..held_len].iter().flatten().copied() {
+                if held.class == class_index {
+                    continue;
+                }
+
+                let current = Dependency {
+                    before_site: held.site,
+                    after_site: site,
+                };
+
+                if let Some(previous) = state.dependencies[class_index][held.class] {
+                    if inversion.is_none() {
+                        inversion = Some(Inversion {
+                            held: state.classes[held.class].unwrap(),
+                            acquired: class,
+                            previous,
+                            current,
+                        });
+                    }
+                } else {
+                    state.dependencies[held.class][class_index].get_or_insert(current);
+                }
+            }
+        }
+
+        if state.held_len == MAX_HELD {
+            disable("too many locks held at once");
+            return;
+        }
+
+        let held_len = state.held_len;
+        state.held[held_len] = Some(Held {
+            class: class_index,
+            site,
+        });
+        state.held_len += 1;
+    }
+
+    if let Some(inversion) = inversion {
+        report(inversion);
+    }
+}
+
+pub fn release(class: Site) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut state = STATE.lock();
+
+    let Some(class_index) = state.classes.iter().position(|registered| {
+        registered.is_some_and(|registered| core::ptr::eq(registered, class))
+    }) else {
+        return;
+    };
+
+    let held_len = state.held_len;
+
+    // Locks are not necessarily released in the reverse order of acquiring them, so we remove the
+    // most recent acquisition of this class wherever it is
+    let Some(position) = state.held[..held_len]
+        .iter()
+        .rposition(|held| held.is_some_and(|held| held.class == class_index))
+    else {
+        return;
+    };
+
+    state.held.copy_within(position + 1..held_len, position);
+    state.held[held_len - 1] = None;
+    state.held_len -= 1;
+}
+
+fn disable(reason: &str) {
+    DISABLED.store(true, Ordering::Relaxed);
+
+    if !CONSOLE.is_locked() {
+        println!("lockdep: turning off lock dependency checking: {}", reason);
+    }
+}
+
+fn report(inversion: Inversion) {
+    if REPORTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    // Printing requires the console, which we would never get if we are the ones holding it
+    if CONSOLE.is_locked() {
+        return;
+    }
+
+    println!("lockdep: possible deadlock, lock ordering inversion detected");
+    println!(
+        "lockdep: lock class {} is being acquired while holding lock class {}",
+        inversion.acquired, inversion.held
+    );
+    println!(
+        "lockdep:     held since {}, acquiring at {}",
+        inversion.current.before_site, inversion.current.after_site
+    );
+    println!("lockdep: but the opposite order was observed before");
+    println!(
+        "lockdep:     held since {}, acquired at {}",
+        inversion.previous.before_site, inversion.previous.after_site
+    );
+}
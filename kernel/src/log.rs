@@ -0,0 +1,334 @@
+//! The kernel log, a ring buffer of everything written to the console
+//!
+//! A line printed again right after itself is only counted, and `last message repeated N times`
+//! is printed before the next different one. What may be printed often, like from an interrupt
+//! handler that keeps misfiring, goes through [`log_ratelimited!`](crate::log_ratelimited) or a
+//! [`RateLimit`] of its own so it can not flood the console
+//!
+//! Records from [`log!`](crate::log) and the macros for each [`Level`] carry the subsystem they
+//! are from, and are printed after the time since boot and the level, which the console draws in
+//! the level's color. What is less important than the level set for the subsystem is dropped,
+//! which is info unless the command line says otherwise like `log=debug,net:debug,pci:warn`,
+//! where a level on its own applies to every subsystem not named
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use crate::{
+    cmdline,
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+pub const LOG_SIZE: usize = 64 * 1024;
+
+/// How many lines [`log_ratelimited!`](crate::log_ratelimited) prints in each interval from the
+/// same place before the rest are suppressed
+pub const RATELIMIT_BURST: u32 = 10;
+pub const RATELIMIT_INTERVAL_NS: u64 = 5 * NANOSECONDS_PER_SECOND;
+
+struct Log {
+    buffer: [u8; LOG_SIZE],
+    /// How many bytes were ever written, the position of a byte in the log never changes
+    written: u64,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    buffer: [0; LOG_SIZE],
+    written: 0,
+});
+
+/// The line printed last, by a hash of it, and how many times it was printed again after
+struct Last {
+    hash: u64,
+    repeats: u64,
+}
+
+static LAST: Mutex<Last> = Mutex::new(Last {
+    hash: 0,
+    repeats: 0,
+});
+
+/// Hashes what is formatted into it with FNV-1a, to tell whether a line is the last one again
+/// without keeping it
+pub struct LineHash(u64);
+
+impl LineHash {
+    pub const fn new() -> Self {
+        Self(0xCBF2_9CE4_8422_2325)
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for LineHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for LineHash {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01B3);
+        }
+
+        Ok(())
+    }
+}
+
+/// What to do with a line about to be printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line {
+    /// It is the last one again, and was only counted
+    Repeated,
+    /// It is another one, after the last one was repeated that many times
+    New { repeats: u64 },
+}
+
+/// Compare the line with the hash `hash` with the one printed last
+pub fn note_line(hash: u64) -> Line {
+    // Only fails when something printing a line panicked, which is printed whatever it says
+    let Some(mut last) = LAST.try_lock() else {
+        return Line::New { repeats: 0 };
+    };
+
+    if last.hash == hash {
+        last.repeats += 1;
+
+        return Line::Repeated;
+    }
+
+    let repeats = last.repeats;
+
+    *last = Last { hash, repeats: 0 };
+
+    Line::New { repeats }
+}
+
+/// Lets a few lines through in each interval and counts the rest, without locks so it can be
+/// used from any handler
+pub struct RateLimit {
+    interval_ns: u64,
+    burst: u32,
+    window_ns: AtomicU64,
+    printed: AtomicU32,
+    suppressed: AtomicU64,
+}
+
+impl RateLimit {
+    pub const fn new(interval_ns: u64, burst: u32) -> Self {
+        Self {
+            interval_ns,
+            burst,
+            window_ns: AtomicU64::new(0),
+            printed: AtomicU32::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether another line may be printed now, printing how many were suppressed as `what`
+    /// when a new interval starts
+    pub fn check(&self, what: &str) -> bool {
+        let now = time::monotonic_ns();
+        let window = self.window_ns.load(Ordering::Relaxed);
+
+        if now.saturating_sub(window) >= self.interval_ns
+            && self
+                .window_ns
+                .compare_exchange(window, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.printed.store(0, Ordering::Relaxed);
+
+            let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+
+            if suppressed != 0 {
+                crate::println_nowait!("{}: {} messages suppressed", what, suppressed);
+            }
+        }
+
+        if self.printed.fetch_add(1, Ordering::Relaxed) < self.burst {
+            return true;
+        }
+
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+
+        false
+    }
+
+    /// How many lines were suppressed in this interval so far
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
+
+/// Like `println_nowait!`, but only a few times in a while from the same place, counting the rest
+/// as suppressed
+#[macro_export]
+macro_rules! log_ratelimited {
+    ($($arg:tt)*) => {{
+        static LIMIT: $crate::log::RateLimit = $crate::log::RateLimit::new(
+            $crate::log::RATELIMIT_INTERVAL_NS,
+            $crate::log::RATELIMIT_BURST,
+        );
+
+        if LIMIT.check(core::concat!(core::file!(), ":", core::line!())) {
+            $crate::println_nowait!($($arg)*);
+        }
+    }};
+}
+
+/// How important a record is, from the most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    /// Something that was set up or done as it should, as important as info
+    Ok,
+    Info,
+    Debug,
+}
+
+impl Level {
+    const DEFAULT: Level = Level::Info;
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Ok => "ok",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Ok,
+            Level::Info,
+            Level::Debug,
+        ]
+        .into_iter()
+        .find(|level| level.name() == name)
+    }
+
+    /// Ok is only a level of its own to be drawn apart from info
+    fn importance(self) -> Level {
+        match self {
+            Level::Ok => Level::Info,
+            level => level,
+        }
+    }
+}
+
+/// The level set for `subsystem` by `log=` on the command line
+fn threshold(subsystem: &str) -> Level {
+    let mut default = Level::DEFAULT;
+
+    for setting in cmdline::value("log")
+        .into_iter()
+        .flat_map(|value| value.split(','))
+    {
+        match setting.split_once(':') {
+            Some((name, level)) if name == subsystem => {
+                if let Some(level) = Level::from_name(level) {
+                    return level;
+                }
+            }
+            Some(_) => {}
+            None => default = Level::from_name(setting).unwrap_or(default),
+        }
+    }
+
+    default
+}
+
+/// Whether a record of `level` from `subsystem` is printed
+pub fn enabled(subsystem: &str, level: Level) -> bool {
+    level.importance() <= threshold(subsystem).importance()
+}
+
+/// Print a record from a subsystem at a [`Level`], like `log!(Level::Warn, "net", "...")`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $subsystem:expr, $($arg:tt)*) => {{
+        $crate::console::_log($level, $subsystem, core::format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($subsystem:expr, $($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Error, $subsystem, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($subsystem:expr, $($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Warn, $subsystem, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_ok {
+    ($subsystem:expr, $($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Ok, $subsystem, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($subsystem:expr, $($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Info, $subsystem, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($subsystem:expr, $($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Debug, $subsystem, $($arg)*)
+    };
+}
+
+/// Add to the log, overwriting the oldest bytes once it is full
+pub fn append(s: &str) {
+    // Only fails when something appending panicked, the panic message is lost to the log then
+    let Some(mut log) = LOG.try_lock() else {
+        return;
+    };
+
+    for &byte in s.as_bytes() {
+        let index = (log.written % LOG_SIZE as u64) as usize;
+
+        log.buffer[index] = byte;
+        log.written += 1;
+    }
+}
+
+/// How many bytes were ever written, the position the next one goes to
+pub fn written() -> u64 {
+    LOG.lock().written
+}
+
+/// Copy the log starting at `position` into `buffer`, returning where the copy starts and how
+/// much was copied, positions already overwritten are skipped
+pub fn read(position: u64, buffer: &mut [u8]) -> (u64, usize) {
+    let log = LOG.lock();
+
+    let start = position.max(log.written.saturating_sub(LOG_SIZE as u64));
+    let len = (log.written.saturating_sub(start) as usize).min(buffer.len());
+
+    for (i, byte) in buffer[..len].iter_mut().enumerate() {
+        *byte = log.buffer[((start + i as u64) % LOG_SIZE as u64) as usize];
+    }
+
+    (start, len)
+}
@@ -1,4 +1,5 @@
 #![feature(abi_x86_interrupt, allocator_api, alloc_layout_extra)]
+#![cfg_attr(any(feature = "kasan", feature = "kcfi"), feature(sanitize))]
 #![no_std]
 #![no_main]
 
@@ -7,14 +8,88 @@ extern crate alloc;
 #[macro_use]
 pub mod console;
 
+pub mod acpi;
+// The allocators manage the heap behind the back of the sanitizer, and must not be checked by it
+#[cfg_attr(feature = "kasan", sanitize(address = "off"))]
 pub mod allocators;
 pub mod arch;
+pub mod block;
+pub mod cma;
+pub mod cmdline;
+pub mod compaction;
+pub mod cpufreq;
+pub mod crashdump;
+pub mod cred;
+pub mod dma;
+pub mod e1000;
+pub mod efi;
+pub mod eventfd;
+pub mod exec;
+pub mod file;
+pub mod frame;
+pub mod futex;
+pub mod hpet;
+pub mod idle;
+pub mod input;
+pub mod irq;
+#[cfg(feature = "kasan")]
+#[sanitize(address = "off")]
+pub mod kasan;
+pub mod kdb;
+pub mod kexec;
+pub mod linux;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod log;
+pub mod meminfo;
 pub mod memory;
+pub mod memtest;
+pub mod mmap;
+pub mod mmio;
+pub mod module;
+pub mod mqueue;
+pub mod net;
+pub mod numa;
+pub mod oom;
+pub mod page;
 pub mod paging;
 pub mod panic;
+pub mod pci;
+pub mod pipe;
+pub mod poll;
+pub mod process;
 pub mod psf2;
+pub mod ptrace;
+pub mod rand;
 pub mod requests;
+pub mod rlimit;
+pub mod ro_after_init;
 pub mod screen;
+pub mod serial_input;
+pub mod shm;
+pub mod signal;
+pub mod softirq;
+pub mod sound;
+pub mod stack;
+pub mod stat;
+pub mod strace;
+pub mod suspend;
+pub mod swap;
+pub mod symbols;
+pub mod sync;
+pub mod sysinfo;
+pub mod sysrq;
+pub mod thermal;
+pub mod time;
+pub mod timer;
+pub mod timerfd;
+pub mod tty;
+pub mod usb;
+pub mod vdso;
+pub mod virtio;
+pub mod wait;
+
+use arch::{Architecture, Current};
 
 #[unsafe(no_mangle)]
 extern "C" fn entry() -> ! {
@@ -22,9 +97,51 @@ extern "C" fn entry() -> ! {
         panic!("limine bootloader does not support our requested base revision");
     }
 
-    arch::interrupts::disable();
+    Current::disable_interrupts();
+
+    // Before anything allocates, which would put the heap in memory about to be overwritten
+    memtest::run();
+
+    stack::register(stack::Stack::boot());
 
-    arch::init();
+    Current::init();
+    symbols::init();
+    numa::init();
+    frame::init();
+    page::init();
+    console::init();
+    memtest::report();
+    cma::init();
 
-    arch::endless_loop();
+    time::init();
+    vdso::init();
+    timer::init();
+    Current::init_late();
+    idle::init();
+    cpufreq::init();
+    thermal::init();
+    efi::init();
+    sysinfo::init();
+    kexec::init();
+    pci::init();
+    net::init();
+    virtio::init();
+    e1000::init();
+    usb::init();
+    sound::init();
+    swap::init();
+    module::init();
+    net::configure();
+    net::netconsole::init();
+    net::ping_from_cmdline();
+    ro_after_init::protect();
+    paging::audit();
+    kdb::init();
+
+    // Interrupts are not routed yet, so devices are polled
+    loop {
+        wait::poll();
+
+        idle::idle();
+    }
 }
@@ -0,0 +1,223 @@
+//! Where the memory went, counted by what it is used for
+//!
+//! What is allocated for one of the [`Category`] uses is counted where it is allocated and freed,
+//! and the heap and the pool below 4 GiB are asked how much of them is in use. The heap is what
+//! slab caches would be elsewhere, everything the kernel allocates with the global allocator,
+//! which includes the network buffers and page tables counted on their own
+//!
+//! The breakdown is read from `/proc/meminfo` in Linux's format along with how much of the
+//! contiguous area for devices is free, and printed by SysRq `m`
+
+use alloc::{format, string::String};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    cma,
+    frame::{self, PAGE_SIZE},
+    memory::{self, GLOBAL_BUDDY_ALLOCATOR, GLOBAL_DMA32_ALLOCATOR},
+    numa,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Tables of the kernel's page tables, which are never freed
+    PageTables,
+    /// Pages of mapped files and shared memory objects
+    PageCache,
+    /// Anonymous memory that may be swapped out
+    Anonymous,
+    /// What [`memory::vmalloc`] mapped
+    Vmalloc,
+    /// What packets are held in while they are built, queued and received
+    Network,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [
+        Category::PageTables,
+        Category::PageCache,
+        Category::Anonymous,
+        Category::Vmalloc,
+        Category::Network,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::PageTables => "page tables",
+            Category::PageCache => "page cache",
+            Category::Anonymous => "anonymous",
+            Category::Vmalloc => "vmalloc",
+            Category::Network => "network buffers",
+        }
+    }
+
+    /// What the line for it is called in `/proc/meminfo`
+    fn field(self) -> &'static str {
+        match self {
+            Category::PageTables => "PageTables",
+            Category::PageCache => "Cached",
+            Category::Anonymous => "AnonPages",
+            Category::Vmalloc => "VmallocUsed",
+            Category::Network => "NetBuffers",
+        }
+    }
+}
+
+static BYTES: [AtomicU64; Category::ALL.len()] = [const { AtomicU64::new(0) }; Category::ALL.len()];
+
+/// Count `bytes` more used for `category`
+pub fn charge(category: Category, bytes: usize) {
+    BYTES[category as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Count `bytes` of `category` given back
+pub fn uncharge(category: Category, bytes: usize) {
+    BYTES[category as usize].fetch_sub(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn bytes(category: Category) -> u64 {
+    BYTES[category as usize].load(Ordering::Relaxed)
+}
+
+/// How much memory there is and how much of it is in use, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub total: u64,
+    pub free: u64,
+    pub heap_total: u64,
+    pub heap_free: u64,
+    pub dma32_total: u64,
+    pub dma32_free: u64,
+    pub frames_total: u64,
+    pub frames_free: u64,
+}
+
+impl Usage {
+    pub fn read() -> Self {
+        // Each lock is let go before the next one is taken, and before anything allocates
+        let heap = {
+            let heap = GLOBAL_BUDDY_ALLOCATOR.lock();
+
+            (heap.managed_bytes(), heap.calculate_free_bytes())
+        };
+
+        let dma32 = memory::has_dma32_memory().then(|| {
+            let dma32 = GLOBAL_DMA32_ALLOCATOR.lock();
+
+            (dma32.managed_bytes(), dma32.calculate_free_bytes())
+        });
+
+        Self::new(heap, dma32)
+    }
+
+    /// Like [`Usage::read`], but gives up if an allocator is busy
+    pub fn try_read() -> Option<Self> {
+        let heap = {
+            let heap = GLOBAL_BUDDY_ALLOCATOR.try_lock()?;
+
+            (heap.managed_bytes(), heap.calculate_free_bytes())
+        };
+
+        let dma32 = match memory::has_dma32_memory() {
+            true => {
+                let dma32 = GLOBAL_DMA32_ALLOCATOR.try_lock()?;
+
+                Some((dma32.managed_bytes(), dma32.calculate_free_bytes()))
+            }
+            false => None,
+        };
+
+        Some(Self::new(heap, dma32))
+    }
+
+    /// From how big the heap and the pool below 4 GiB are and how much of them is free
+    fn new(heap: (usize, usize), dma32: Option<(usize, usize)>) -> Self {
+        let (heap_total, heap_free) = (heap.0 as u64, heap.1 as u64);
+        let (dma32_total, dma32_free) =
+            dma32.map_or((0, 0), |(total, free)| (total as u64, free as u64));
+
+        let (frames_total, frames_free) = numa::nodes().into_iter().map(frame::node_pages).fold(
+            (0, 0),
+            |(total, free), (pages, free_pages)| {
+                (
+                    total + pages as u64 * PAGE_SIZE,
+                    free + free_pages as u64 * PAGE_SIZE,
+                )
+            },
+        );
+
+        Self {
+            total: heap_total + dma32_total + frames_total,
+            free: heap_free + dma32_free + frames_free,
+            heap_total,
+            heap_free,
+            dma32_total,
+            dma32_free,
+            frames_total,
+            frames_free,
+        }
+    }
+}
+
+/// The contents of `/proc/meminfo`
+pub fn contents() -> String {
+    let usage = Usage::read();
+
+    let mut text = format!(
+        "MemTotal:       {:>8} kB\nMemFree:        {:>8} kB\nSlab:           {:>8} kB\n",
+        usage.total / 1024,
+        usage.free / 1024,
+        (usage.heap_total - usage.heap_free) / 1024
+    );
+
+    for category in Category::ALL {
+        let field = format!("{}:", category.field());
+
+        text += &format!("{:<16}{:>8} kB\n", field, bytes(category) / 1024);
+    }
+
+    let (cma_pages, cma_free) = cma::pages();
+
+    text += &format!(
+        "CmaTotal:       {:>8} kB\nCmaFree:        {:>8} kB\n",
+        cma_pages as u64 * PAGE_SIZE / 1024,
+        cma_free as u64 * PAGE_SIZE / 1024
+    );
+
+    text
+}
+
+/// Print the breakdown, unless an allocator is busy as whatever was interrupted may hold it
+pub fn dump() {
+    let Some(usage) = Usage::try_read() else {
+        println!("meminfo: an allocator is busy, try again later");
+        return;
+    };
+
+    let kib = |bytes: u64| bytes / 1024;
+
+    println!(
+        "meminfo: {} of {} KiB free",
+        kib(usage.free),
+        kib(usage.total)
+    );
+    println!(
+        "meminfo: heap: {} of {} KiB in use",
+        kib(usage.heap_total - usage.heap_free),
+        kib(usage.heap_total)
+    );
+    println!(
+        "meminfo: dma32 pool: {} of {} KiB in use",
+        kib(usage.dma32_total - usage.dma32_free),
+        kib(usage.dma32_total)
+    );
+    println!(
+        "meminfo: frames: {} of {} KiB in use",
+        kib(usage.frames_total - usage.frames_free),
+        kib(usage.frames_total)
+    );
+
+    for category in Category::ALL {
+        println!("meminfo: {}: {} KiB", category.name(), kib(bytes(category)));
+    }
+}
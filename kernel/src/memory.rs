@@ -1,39 +1,98 @@
-use core::ptr::NonNull;
+use alloc::vec::Vec;
+use core::{alloc::AllocError, ops::Range, ptr::NonNull};
 
 use lazy_static::lazy_static;
 use limine::memory_map::EntryType as MemoryEntryType;
-use spin::{lazy::Lazy, mutex::Mutex};
+use spin::lazy::Lazy;
 
+#[cfg(feature = "kasan")]
+use crate::kasan::{self, KasanAllocator};
 use crate::{
     allocators::{
         buddy_allocator::{BuddyAllocator, LockedBuddyAllocator},
         first_fit_allocator::{FirstFitAllocator, LockedFirstFitAllocator},
     },
-    paging::virt_from_phys,
+    arch::paging::{PAGE_SIZE, PageTableFlags},
+    dma::{self, CoherentBuffer, Constraints},
+    frame,
+    meminfo::{self, Category},
+    memtest, paging,
+    paging::{phys_from_virt, virt_from_phys},
     requests::MEMORY_MAP_REQUEST,
+    sync::Mutex,
 };
 
-lazy_static! {
-    static ref HEAP: Mutex<&'static mut [u8]> = Mutex::new(unsafe {
-        let heap = **MEMORY_MAP_REQUEST
-            .get_response()
-            .expect("could not ask limine to get the memory map")
-            .entries()
-            .iter()
-            .filter(|a| a.entry_type == MemoryEntryType::USABLE)
-            .max_by(|a, b| a.length.cmp(&b.length))
-            .expect("could not find a usable memory entry");
+/// Real mode can only reach memory below this
+pub const LOW_MEMORY_LIMIT: u64 = 0x10_0000;
+
+/// Devices with 32-bit addressing can only reach memory below this
+pub const DMA32_LIMIT: u64 = 1 << 32;
 
+/// The usable entries of the memory map, split where [`memtest`] found bad pages
+fn usable_ranges() -> impl Iterator<Item = Range<u64>> {
+    MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("could not ask limine to get the memory map")
+        .entries()
+        .iter()
+        .filter(|entry| entry.entry_type == MemoryEntryType::USABLE)
+        .flat_map(|entry| memtest::without_bad_pages(entry.base..entry.base + entry.length))
+}
+
+unsafe fn range_as_slice(range: Range<u64>) -> &'static mut [u8] {
+    unsafe {
         core::ptr::slice_from_raw_parts_mut(
-            virt_from_phys(heap.base) as *mut u8,
-            heap.length as usize,
+            virt_from_phys(range.start) as *mut u8,
+            (range.end - range.start) as usize,
         )
         .as_mut()
         .unwrap_unchecked()
+    }
+}
+
+lazy_static! {
+    static ref HEAP: Mutex<&'static mut [u8]> = Mutex::new(unsafe {
+        let heap = range_as_slice(heap_phys_range());
+
+        #[cfg(feature = "kasan")]
+        let heap = kasan::init(heap);
+
+        heap
     });
 }
 
-#[global_allocator]
+lazy_static! {
+    /// The largest usable range below 4 GiB that the heap did not take, reserved for devices that
+    /// can not address more than that
+    static ref DMA32: Mutex<Option<&'static mut [u8]>> = Mutex::new(unsafe {
+        let heap = heap_phys_range();
+
+        usable_ranges()
+            .filter(|range| range.start != heap.start && range.end <= DMA32_LIMIT)
+            .max_by_key(|range| range.end - range.start)
+            .map(|range| range_as_slice(range))
+    });
+}
+
+lazy_static! {
+    /// A page below 1 MiB that nothing else uses, for code the processor runs in real mode
+    static ref LOW_PAGE: Option<u64> = {
+        let heap = heap_phys_range();
+        let dma32 = DMA32.lock().as_ref().map(|dma32| dma32.as_ptr() as u64);
+
+        usable_ranges()
+            .filter(|range| range.start != heap.start && Some(virt_from_phys(range.start)) != dma32)
+            .filter_map(|range| {
+                let top = (range.end.min(LOW_MEMORY_LIMIT) & !0xFFF).checked_sub(0x1000)?;
+
+                // The first page holds the real mode interrupt table
+                (top >= range.start && top != 0).then_some(top)
+            })
+            .next()
+    };
+}
+
+#[cfg_attr(not(feature = "kasan"), global_allocator)]
 pub static GLOBAL_BUDDY_ALLOCATOR: LockedBuddyAllocator = LockedBuddyAllocator(Lazy::new(|| {
     Mutex::new(unsafe {
         let mut heap = HEAP.lock();
@@ -45,6 +104,11 @@ pub static GLOBAL_BUDDY_ALLOCATOR: LockedBuddyAllocator = LockedBuddyAllocator(L
     })
 }));
 
+#[cfg(feature = "kasan")]
+#[global_allocator]
+static GLOBAL_KASAN_ALLOCATOR: KasanAllocator<&LockedBuddyAllocator> =
+    KasanAllocator::new(&GLOBAL_BUDDY_ALLOCATOR);
+
 pub static GLOBAL_FIRST_FIT_ALLOCATOR: LockedFirstFitAllocator =
     LockedFirstFitAllocator(Lazy::new(|| {
         Mutex::new(unsafe {
@@ -56,3 +120,206 @@ pub static GLOBAL_FIRST_FIT_ALLOCATOR: LockedFirstFitAllocator =
             )
         })
     }));
+
+/// Only usable when [`has_dma32_memory`] is true
+pub static GLOBAL_DMA32_ALLOCATOR: LockedBuddyAllocator = LockedBuddyAllocator(Lazy::new(|| {
+    Mutex::new(unsafe {
+        let mut dma32 = DMA32.lock();
+        let dma32 = dma32
+            .as_mut()
+            .expect("could not find a usable memory entry below 4 GiB");
+
+        BuddyAllocator::new(
+            NonNull::new(dma32.as_mut_ptr()).unwrap_unchecked(),
+            dma32.len(),
+        )
+    })
+}));
+
+pub fn has_dma32_memory() -> bool {
+    DMA32.lock().is_some()
+}
+
+/// The physical range the heap lives in
+pub fn heap_phys_range() -> Range<u64> {
+    usable_ranges()
+        .max_by_key(|range| range.end - range.start)
+        .expect("could not find a usable memory entry")
+}
+
+/// The physical range the pool below 4 GiB lives in, if there is one
+pub fn dma32_phys_range() -> Option<Range<u64>> {
+    DMA32.lock().as_ref().map(|dma32| {
+        let base = phys_from_virt(dma32.as_ptr() as u64);
+
+        base..base + dma32.len() as u64
+    })
+}
+
+pub fn low_page() -> Option<u64> {
+    *LOW_PAGE
+}
+
+/// The usable memory that neither the heap nor the pool below 4 GiB took, above 1 MiB so the low
+/// page stays out of it, page aligned
+pub fn spare_ranges() -> impl Iterator<Item = Range<u64>> {
+    let heap = heap_phys_range();
+    let dma32 = DMA32.lock().as_ref().map(|dma32| dma32.as_ptr() as u64);
+
+    usable_ranges()
+        .filter(move |range| {
+            range.start != heap.start && Some(virt_from_phys(range.start)) != dma32
+        })
+        .map(|range| range.start.max(LOW_MEMORY_LIMIT).next_multiple_of(0x1000)..range.end & !0xFFF)
+        .filter(|range| range.start < range.end)
+}
+
+/// Where [`vmalloc`] maps its memory, far above the higher half direct map
+const VMALLOC_BASE: u64 = 0xFFFF_D000_0000_0000;
+const VMALLOC_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// The virtual ranges given out by [`vmalloc`], sorted, each followed by an unmapped guard page
+static VMALLOC_AREAS: Mutex<Vec<Range<u64>>> = Mutex::new(Vec::new());
+
+/// A page of a [`VirtualBuffer`], from the frame zones or from the heap when they have none
+enum Backing {
+    Frame(u64),
+    Buffer(CoherentBuffer),
+}
+
+impl Backing {
+    fn alloc() -> Result<Self, AllocError> {
+        let backing = match frame::alloc(1) {
+            Some(phys) => {
+                unsafe {
+                    (virt_from_phys(phys) as *mut u8).write_bytes(0, PAGE_SIZE as usize);
+                }
+
+                Backing::Frame(phys)
+            }
+            None => {
+                dma::alloc_coherent(PAGE_SIZE as usize, Constraints::new()).map(Backing::Buffer)?
+            }
+        };
+
+        meminfo::charge(Category::Vmalloc, PAGE_SIZE as usize);
+
+        Ok(backing)
+    }
+
+    fn phys(&self) -> u64 {
+        match self {
+            Backing::Frame(phys) => *phys,
+            Backing::Buffer(buffer) => buffer.bus_address(),
+        }
+    }
+}
+
+impl Drop for Backing {
+    fn drop(&mut self) {
+        meminfo::uncharge(Category::Vmalloc, PAGE_SIZE as usize);
+
+        if let Backing::Frame(phys) = self {
+            frame::free(*phys, 1);
+        }
+    }
+}
+
+/// Zeroed memory that is contiguous in virtual memory only, freed on drop
+pub struct VirtualBuffer {
+    virt: NonNull<u8>,
+    len: usize,
+    pages: Vec<Backing>,
+}
+
+unsafe impl Send for VirtualBuffer {}
+unsafe impl Sync for VirtualBuffer {}
+
+impl VirtualBuffer {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt.as_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_ptr(), self.len) }
+    }
+
+    /// The physical address of each page, in order
+    pub fn pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.pages.iter().map(Backing::phys)
+    }
+}
+
+impl Drop for VirtualBuffer {
+    fn drop(&mut self) {
+        let virt = self.virt.addr().get() as u64;
+
+        paging::unmap(virt, self.pages.len() * PAGE_SIZE as usize);
+
+        VMALLOC_AREAS.lock().retain(|area| area.start != virt);
+    }
+}
+
+/// Find room for `len` bytes and a guard page in the vmalloc area, the first that fits
+fn reserve_vmalloc_area(len: u64) -> Option<u64> {
+    let mut areas = VMALLOC_AREAS.lock();
+
+    let mut start = VMALLOC_BASE;
+    let mut index = 0;
+
+    for area in areas.iter() {
+        if start.checked_add(len + PAGE_SIZE)? <= area.start {
+            break;
+        }
+
+        start = area.end + PAGE_SIZE;
+        index += 1;
+    }
+
+    if start.checked_add(len + PAGE_SIZE)? > VMALLOC_BASE + VMALLOC_SIZE {
+        return None;
+    }
+
+    areas.insert(index, start..start + len);
+
+    Some(start)
+}
+
+/// Allocate `len` bytes of zeroed memory from pages that do not have to be next to each other,
+/// mapped one after the other in a dedicated range of virtual memory
+pub fn vmalloc(len: usize) -> Result<VirtualBuffer, AllocError> {
+    let pages = len.div_ceil(PAGE_SIZE as usize).max(1);
+
+    let backing = (0..pages)
+        .map(|_| Backing::alloc())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let virt = reserve_vmalloc_area(pages as u64 * PAGE_SIZE).ok_or(AllocError)?;
+
+    for (index, page) in backing.iter().enumerate() {
+        paging::map(
+            virt + index as u64 * PAGE_SIZE,
+            page.phys(),
+            PAGE_SIZE as usize,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        );
+    }
+
+    Ok(VirtualBuffer {
+        virt: unsafe { NonNull::new_unchecked(virt as *mut u8) },
+        len,
+        pages: backing,
+    })
+}
@@ -0,0 +1,205 @@
+//! Testing the usable memory with patterns at boot, for machines that crash in ways bad memory
+//! would explain
+//!
+//! With `memtest` on the command line, or `memtest=<passes>`, every usable entry of the memory
+//! map is written with a pattern and read back before the heap or anything else takes memory
+//! from them, first with fixed patterns and then with every word holding its own address so
+//! address lines that are stuck show up too. The kernel and its modules are not in usable
+//! entries, so they are never overwritten
+//!
+//! The pages where a word did not read back what was written are left out of the ranges the
+//! heap, the pool below 4 GiB and the frame zones are made of, so they stay reserved. Nothing can
+//! be printed while testing as the console allocates, so what was found is reported once the
+//! memory is set up
+
+use core::ops::Range;
+
+use limine::memory_map::EntryType as MemoryEntryType;
+use spin::Once;
+
+use crate::{
+    arch::paging::PAGE_SIZE, cmdline, log::Level, paging, requests::MEMORY_MAP_REQUEST,
+    ro_after_init,
+};
+
+const PATTERNS: [u64; 4] = [0, u64::MAX, 0xAAAA_AAAA_AAAA_AAAA, 0x5555_5555_5555_5555];
+
+/// How many runs of bad pages are kept out of use, those found after are reported but used
+const MAX_BAD_RANGES: usize = 64;
+
+struct Report {
+    passes: usize,
+    tested: u64,
+    /// Sorted, and merged where they touch
+    bad: [Range<u64>; MAX_BAD_RANGES],
+    len: usize,
+    /// More runs of bad pages were found than fit
+    overflowed: bool,
+}
+
+impl Report {
+    fn bad_ranges(&self) -> &[Range<u64>] {
+        &self.bad[..self.len]
+    }
+
+    /// Keep the page at `page` out of use
+    fn record(&mut self, page: u64) {
+        let index = self.bad_ranges().partition_point(|range| range.end < page);
+        let page_range = page..page + PAGE_SIZE;
+
+        if let Some(range) = self.bad[..self.len].get_mut(index)
+            && range.start <= page_range.end
+        {
+            range.start = range.start.min(page_range.start);
+            range.end = range.end.max(page_range.end);
+
+            // It may have grown up to the next one
+            if index + 1 < self.len && self.bad[index + 1].start <= self.bad[index].end {
+                self.bad[index].end = self.bad[index + 1].end;
+                self.bad[index + 1..self.len].rotate_left(1);
+                self.len -= 1;
+            }
+
+            return;
+        }
+
+        if self.len == MAX_BAD_RANGES {
+            self.overflowed = true;
+            return;
+        }
+
+        self.bad[index..=self.len].rotate_right(1);
+        self.bad[index] = page_range;
+        self.len += 1;
+    }
+}
+
+ro_after_init! {
+    static REPORT: Once<Report> = Once::new();
+}
+
+/// Write `value` of each word in `range` and read them all back, recording the pages where one
+/// did not read back what was written
+fn test(report: &mut Report, range: Range<u64>, value: impl Fn(u64) -> u64) {
+    let words = (range.start..range.end).step_by(size_of::<u64>());
+
+    for phys in words.clone() {
+        unsafe { (paging::virt_from_phys(phys) as *mut u64).write_volatile(value(phys)) };
+    }
+
+    for phys in words {
+        let read = unsafe { (paging::virt_from_phys(phys) as *const u64).read_volatile() };
+
+        if read != value(phys) {
+            report.record(phys & !(PAGE_SIZE - 1));
+        }
+    }
+}
+
+/// Test the usable memory if the command line asks for it, which must come before anything
+/// allocates
+pub fn run() {
+    let passes = match cmdline::value("memtest") {
+        Some(passes) => passes.parse().unwrap_or(1),
+        None if cmdline::has("memtest") => 1,
+        None => return,
+    };
+
+    let mut report = Report {
+        passes,
+        tested: 0,
+        bad: [const { 0..0 }; MAX_BAD_RANGES],
+        len: 0,
+        overflowed: false,
+    };
+
+    let entries = MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("could not ask limine to get the memory map")
+        .entries()
+        .iter()
+        .filter(|entry| entry.entry_type == MemoryEntryType::USABLE);
+
+    for entry in entries.clone() {
+        report.tested += entry.length;
+    }
+
+    for _ in 0..passes {
+        for entry in entries.clone() {
+            let range = entry.base..entry.base + entry.length;
+
+            for pattern in PATTERNS {
+                test(&mut report, range.clone(), |_| pattern);
+            }
+
+            test(&mut report, range.clone(), |phys| phys);
+            test(&mut report, range, |phys| !phys);
+        }
+    }
+
+    REPORT.call_once(|| report);
+}
+
+/// The parts of `range` that have no bad pages in them
+pub fn without_bad_pages(range: Range<u64>) -> impl Iterator<Item = Range<u64>> {
+    let bad = REPORT.get().map_or(&[][..], Report::bad_ranges);
+    let Range {
+        start: mut from,
+        end,
+    } = range;
+    let mut bad = bad
+        .iter()
+        .filter(move |bad| bad.start < end && from < bad.end);
+
+    // Each part runs up to the next bad range, and the one after starts where it ends
+    core::iter::from_fn(move || {
+        while from < end {
+            let (until, next) = bad
+                .next()
+                .map_or((end, end), |bad| (bad.start.max(from), bad.end.min(end)));
+
+            let part = from..until;
+
+            from = next;
+
+            if !part.is_empty() {
+                return Some(part);
+            }
+        }
+
+        None
+    })
+}
+
+/// Print what the tests found, once there is a console to print it on
+pub fn report() {
+    let Some(report) = REPORT.get() else {
+        return;
+    };
+
+    for range in report.bad_ranges() {
+        crate::log_error!("memtest", "{:#x}-{:#x} is bad", range.start, range.end);
+    }
+
+    let bad = report
+        .bad_ranges()
+        .iter()
+        .map(|range| (range.end - range.start) / PAGE_SIZE)
+        .sum::<u64>();
+
+    crate::log!(
+        if bad == 0 { Level::Ok } else { Level::Warn },
+        "memtest",
+        "{} MiB tested {} times, {} bad pages kept out of use",
+        report.tested / (1024 * 1024),
+        report.passes,
+        bad
+    );
+
+    if report.overflowed {
+        crate::log_error!(
+            "memtest",
+            "there were more bad pages, which could not be kept out of use"
+        );
+    }
+}
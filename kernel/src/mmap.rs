@@ -0,0 +1,704 @@
+//! Mapping files into memory, so reading and writing them is reading and writing memory
+//!
+//! There are no processes or VFS yet, so what can be mapped is anything that implements
+//! [`Mappable`], and it is mapped where the kernel can reach it by [`mmap`]. Pages are only
+//! brought in once touched: files made of pages like shared memory objects have theirs mapped,
+//! others are read into a frame of the mapping's own. Those are written back by [`msync`] and
+//! when unmapped if the mapping is shared, and private mappings copy a page the first time it is
+//! written so the file never sees it
+//!
+//! Files are read and written by polling their device, so mapped memory must not be touched with
+//! a lock held that polling the devices takes
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bitflags::bitflags;
+
+use crate::{
+    arch::paging::{PAGE_SIZE, PageTableFlags},
+    file::Error,
+    frame, oom,
+    page::{self, Owner},
+    paging, rand,
+    sync::Mutex,
+};
+
+/// Where files are mapped, after anonymous memory
+const MAPPED_BASE: u64 = 0xFFFF_E800_0000_0000;
+const MAPPED_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+/// How many pages further than the next free one a range from [`reserve`] can start, at random
+const RANDOM_PAGES: u64 = 256;
+
+bitflags! {
+    /// The `PROT_*` flags of a mapping, readable is implied by the others as the page tables
+    /// can not tell them apart
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Protection: u32 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+/// Whether writes go to the file, `MAP_SHARED`, or only to the mapping, `MAP_PRIVATE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharing {
+    Shared,
+    Private,
+}
+
+/// Something that can be mapped into memory a page at a time
+pub trait Mappable: Send + Sync {
+    /// How long it is, pages past the end can not be touched
+    fn size(&self) -> Result<u64, Error>;
+
+    /// Read the page at `index` into `buffer`, with zeroes past the end
+    fn read_page(&self, index: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Write `data` back to the page at `index`, which never goes past the end
+    fn write_page(&self, index: u64, data: &[u8]) -> Result<(), Error>;
+
+    /// The frame the page at `index` lives in, for files made of pages which are mapped instead
+    /// of copied
+    fn frame(&self, _index: u64) -> Option<u64> {
+        None
+    }
+}
+
+struct Mapping {
+    start: u64,
+    len: u64,
+    file: Arc<dyn Mappable>,
+    /// Where in the file it starts, in pages
+    offset: u64,
+    protection: Protection,
+    sharing: Sharing,
+    /// The frames it owns by the address they are mapped at, read from the file or copied on write
+    frames: BTreeMap<u64, u64>,
+}
+
+impl Mapping {
+    fn range(&self) -> Range<u64> {
+        self.start..self.start + self.len
+    }
+
+    fn index(&self, virt: u64) -> u64 {
+        self.offset + (virt - self.start) / PAGE_SIZE
+    }
+
+    /// Cut the mapping in two at `at`, a page boundary inside it, returning the part after
+    fn split_off(&mut self, at: u64) -> Mapping {
+        let after = Mapping {
+            start: at,
+            len: self.start + self.len - at,
+            file: self.file.clone(),
+            offset: self.index(at),
+            protection: self.protection,
+            sharing: self.sharing,
+            frames: self.frames.split_off(&at),
+        };
+
+        self.len = at - self.start;
+
+        after
+    }
+
+    fn flags(&self, writable: bool) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+
+        if !self.protection.contains(Protection::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
+
+    /// Write the pages in `range` that were written since they were read back to the file
+    fn write_back(&self, range: Range<u64>) -> Result<(), Error> {
+        if self.sharing != Sharing::Shared {
+            return Ok(());
+        }
+
+        let size = self.file.size()?;
+
+        for (&virt, &phys) in self.frames.range(range) {
+            let Some(entry) = paging::read_entry(virt) else {
+                continue;
+            };
+
+            if !PageTableFlags::from_bits_retain(entry).contains(PageTableFlags::DIRTY) {
+                continue;
+            }
+
+            let index = self.index(virt);
+            let len = size.saturating_sub(index * PAGE_SIZE).min(PAGE_SIZE) as usize;
+
+            // Cleaned first so writes that land while it is written out dirty it again
+            paging::write_entry(virt, entry & !PageTableFlags::DIRTY.bits());
+
+            let data = unsafe {
+                core::slice::from_raw_parts(paging::virt_from_phys(phys) as *const u8, len)
+            };
+
+            if let Err(error) = self.file.write_page(index, data) {
+                paging::write_entry(virt, entry);
+
+                return Err(error);
+            }
+
+            WRITE_BACKS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads as zeroes and drops what is written, for memory that is not backed by anything
+pub struct Zeroes;
+
+impl Mappable for Zeroes {
+    fn size(&self) -> Result<u64, Error> {
+        Ok(u64::MAX)
+    }
+
+    fn read_page(&self, _index: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        buffer.fill(0);
+
+        Ok(())
+    }
+
+    fn write_page(&self, _index: u64, _data: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Mappings {
+    mappings: Vec<Mapping>,
+    /// Ranges from [`reserve`], which [`mmap_fixed`] maps into
+    reservations: Vec<Range<u64>>,
+    next: u64,
+}
+
+impl Mappings {
+    /// Find room for `len` bytes, which must be page aligned, `gap` bytes after the next free ones
+    fn allocate(&mut self, len: u64, gap: u64) -> Result<u64, Error> {
+        let start = self.next.checked_add(gap).ok_or(Error::OutOfMemory)?;
+
+        // A page is left unmapped after each range to catch overflows
+        let end = start
+            .checked_add(len)
+            .and_then(|end| end.checked_add(PAGE_SIZE))
+            .filter(|&end| end <= MAPPED_BASE + MAPPED_SIZE)
+            .ok_or(Error::OutOfMemory)?;
+
+        self.next = end;
+
+        Ok(start)
+    }
+}
+
+static MAPPINGS: Mutex<Mappings> = Mutex::new(Mappings {
+    mappings: Vec::new(),
+    reservations: Vec::new(),
+    next: MAPPED_BASE,
+});
+
+static READS: AtomicU64 = AtomicU64::new(0);
+static COPIES: AtomicU64 = AtomicU64::new(0);
+static WRITE_BACKS: AtomicU64 = AtomicU64::new(0);
+
+/// Map `len` bytes of `file` from `offset`, which must be page aligned, returns where
+///
+/// Mappings can not be both writable and executable, as they are kernel memory
+pub fn mmap(
+    file: Arc<dyn Mappable>,
+    offset: u64,
+    len: usize,
+    protection: Protection,
+    sharing: Sharing,
+) -> Result<u64, Error> {
+    if len == 0 || !offset.is_multiple_of(PAGE_SIZE) {
+        return Err(Error::InvalidArgument);
+    }
+
+    if protection.contains(Protection::WRITE | Protection::EXECUTE) {
+        return Err(Error::PermissionDenied);
+    }
+
+    let len = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Error::OutOfMemory)?;
+    let mut mappings = MAPPINGS.lock();
+
+    let start = mappings.allocate(len, 0)?;
+
+    mappings.mappings.push(Mapping {
+        start,
+        len,
+        file,
+        offset: offset / PAGE_SIZE,
+        protection,
+        sharing,
+        frames: BTreeMap::new(),
+    });
+
+    Ok(start)
+}
+
+/// Set aside `len` bytes for [`mmap_fixed`] to map parts of, for what has to be laid out like an
+/// executable, given back with everything mapped in it by [`munmap`]
+///
+/// Where it starts is moved up by a random number of pages, so programs can not count on where
+/// their image and stack are. Every process takes room from the one window, which is never
+/// handed out again, so that is only up to [`RANDOM_PAGES`] pages for it to last
+pub fn reserve(len: usize) -> Result<u64, Error> {
+    if len == 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let len = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Error::OutOfMemory)?;
+    let gap = rand::u64() % RANDOM_PAGES * PAGE_SIZE;
+    let mut mappings = MAPPINGS.lock();
+
+    let start = mappings.allocate(len, gap)?;
+
+    mappings.reservations.push(start..start + len);
+
+    Ok(start)
+}
+
+/// Map `len` bytes of `file` from `offset` at `address`, which must be page aligned and in a range
+/// from [`reserve`] that nothing is mapped at yet
+pub fn mmap_fixed(
+    address: u64,
+    file: Arc<dyn Mappable>,
+    offset: u64,
+    len: usize,
+    protection: Protection,
+    sharing: Sharing,
+) -> Result<(), Error> {
+    if len == 0 || !offset.is_multiple_of(PAGE_SIZE) || !address.is_multiple_of(PAGE_SIZE) {
+        return Err(Error::InvalidArgument);
+    }
+
+    if protection.contains(Protection::WRITE | Protection::EXECUTE) {
+        return Err(Error::PermissionDenied);
+    }
+
+    let len = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Error::InvalidArgument)?;
+    let range = address..address.checked_add(len).ok_or(Error::InvalidArgument)?;
+
+    let mut mappings = MAPPINGS.lock();
+
+    let reserved = mappings
+        .reservations
+        .iter()
+        .any(|reservation| reservation.start <= range.start && range.end <= reservation.end);
+
+    let taken = mappings.mappings.iter().any(|mapping| {
+        let mapped = mapping.range();
+
+        mapped.start < range.end && range.start < mapped.end
+    });
+
+    if !reserved || taken {
+        return Err(Error::InvalidArgument);
+    }
+
+    mappings.mappings.push(Mapping {
+        start: address,
+        len,
+        file,
+        offset: offset / PAGE_SIZE,
+        protection,
+        sharing,
+        frames: BTreeMap::new(),
+    });
+
+    Ok(())
+}
+
+/// Write back what was written to the shared mappings between `start` and `start + len`
+pub fn msync(start: u64, len: usize) -> Result<(), Error> {
+    let range = start..start.saturating_add(len as u64);
+
+    for mapping in MAPPINGS.lock().mappings.iter() {
+        let mapped = mapping.range();
+
+        if mapped.start < range.end && range.start < mapped.end {
+            mapping.write_back(range.start.max(mapped.start)..range.end.min(mapped.end))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Give back a mapping from [`mmap`] or a range from [`reserve`] with everything mapped in it,
+/// writing what is shared back first
+pub fn munmap(start: u64) {
+    let removed = {
+        let mut mappings = MAPPINGS.lock();
+
+        let reservation = mappings
+            .reservations
+            .iter()
+            .position(|reservation| reservation.start == start)
+            .map(|index| mappings.reservations.swap_remove(index));
+
+        mappings
+            .mappings
+            .extract_if(.., |mapping| match &reservation {
+                Some(reservation) => reservation.contains(&mapping.start),
+                None => mapping.start == start,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for mapping in removed {
+        release(mapping);
+    }
+}
+
+/// Unmap the pages between `start` and `start + len`, which must be page aligned, from whatever is
+/// mapped there, keeping the rest of those mappings and the ranges they were reserved in
+pub fn munmap_range(start: u64, len: usize) {
+    let len = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .unwrap_or(u64::MAX);
+    let range = start..start.saturating_add(len);
+
+    let removed = {
+        let mut mappings = MAPPINGS.lock();
+
+        let overlapping = mappings
+            .mappings
+            .extract_if(.., |mapping| {
+                mapping.start < range.end && range.start < mapping.start + mapping.len
+            })
+            .collect::<Vec<_>>();
+
+        let mut removed = Vec::new();
+
+        for mut mapping in overlapping {
+            if mapping.start < range.start {
+                let inside = mapping.split_off(range.start);
+
+                mappings.mappings.push(mapping);
+                mapping = inside;
+            }
+
+            if range.end < mapping.start + mapping.len {
+                let after = mapping.split_off(range.end);
+
+                mappings.mappings.push(after);
+            }
+
+            removed.push(mapping);
+        }
+
+        removed
+    };
+
+    for mapping in removed {
+        release(mapping);
+    }
+}
+
+/// Whether all of `address` to `address + len` is mapped with at least `protection`, for addresses
+/// programs hand the kernel to be checked before they are touched
+pub fn is_mapped(address: u64, len: usize, protection: Protection) -> bool {
+    let Some(end) = address.checked_add(len as u64) else {
+        return false;
+    };
+
+    let mappings = MAPPINGS.lock();
+    let mut at = address;
+
+    while at < end {
+        let Some(mapping) = mappings
+            .mappings
+            .iter()
+            .find(|mapping| mapping.range().contains(&at))
+        else {
+            return false;
+        };
+
+        if !mapping.protection.contains(protection) {
+            return false;
+        }
+
+        at = mapping.range().end;
+    }
+
+    true
+}
+
+fn release(mapping: Mapping) {
+    if let Err(error) = mapping.write_back(mapping.range()) {
+        println!("mmap: writing back {:#x}: {:?}", mapping.start, error);
+    }
+
+    for virt in mapping.range().step_by(PAGE_SIZE as usize) {
+        if paging::translate(virt).is_some() {
+            paging::unmap(virt, PAGE_SIZE as usize);
+        }
+    }
+
+    for &phys in mapping.frames.values() {
+        frame::put(phys);
+    }
+}
+
+fn copy_page(from: u64, to: u64) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            paging::virt_from_phys(from) as *const u8,
+            paging::virt_from_phys(to) as *mut u8,
+            PAGE_SIZE as usize,
+        );
+    }
+}
+
+/// Bring in the page of a mapped file at `address`, or copy it if it is private and `write` is
+/// the first to it, returns false if the address is not mapped or the page could not be brought in
+pub fn handle_fault(address: u64, write: bool) -> bool {
+    if !(MAPPED_BASE..MAPPED_BASE + MAPPED_SIZE).contains(&address) {
+        return false;
+    }
+
+    let virt = address & !(PAGE_SIZE - 1);
+
+    // Touching mapped memory while reading a file would otherwise wait on ourselves forever
+    let Some(mut mappings) = MAPPINGS.try_lock() else {
+        return false;
+    };
+
+    let Some(mapping) = mappings
+        .mappings
+        .iter_mut()
+        .find(|mapping| mapping.range().contains(&virt))
+    else {
+        return false;
+    };
+
+    if mapping.protection.is_empty() || (write && !mapping.protection.contains(Protection::WRITE)) {
+        return false;
+    }
+
+    let index = mapping.index(virt);
+
+    // Past the end of the file, which is a bus error rather than a page to bring in
+    if mapping
+        .file
+        .size()
+        .is_ok_and(|size| index >= size.div_ceil(PAGE_SIZE))
+    {
+        return false;
+    }
+
+    let present = paging::translate(virt);
+    let shared_frame = mapping.file.frame(index);
+
+    // The file's own page can be mapped unless a private mapping is writing to it
+    if present.is_none()
+        && let Some(phys) = shared_frame
+        && (mapping.sharing == Sharing::Shared || !write)
+    {
+        let writable =
+            mapping.sharing == Sharing::Shared && mapping.protection.contains(Protection::WRITE);
+
+        paging::map(virt, phys, PAGE_SIZE as usize, mapping.flags(writable));
+
+        return true;
+    }
+
+    // Anything else that is there is a fault the page tables can not fix, but the first write
+    // to the file's page in a private mapping
+    if present.is_some()
+        && (!write || mapping.sharing != Sharing::Private || mapping.frames.contains_key(&virt))
+    {
+        return false;
+    }
+
+    match copy_in(mapping, virt, present, shared_frame) {
+        Ok(_) => true,
+        Err(Error::OutOfMemory) => {
+            // Whatever gets killed may give its memory back
+            drop(mappings);
+
+            oom::out_of_memory("a mapped page", 1) && handle_fault(address, write)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Give the page of a private mapping at `virt` a frame of the mapping's own, with a copy of
+/// what is `present` there or of the file's page, or read from the file, returns the frame
+fn copy_in(
+    mapping: &mut Mapping,
+    virt: u64,
+    present: Option<u64>,
+    shared_frame: Option<u64>,
+) -> Result<u64, Error> {
+    let index = mapping.index(virt);
+    let phys = frame::alloc(1).ok_or(Error::OutOfMemory)?;
+
+    match present.or(shared_frame) {
+        Some(from) => {
+            copy_page(from, phys);
+
+            COPIES.fetch_add(1, Ordering::Relaxed);
+        }
+        None => {
+            let buffer = unsafe {
+                core::slice::from_raw_parts_mut(
+                    paging::virt_from_phys(phys) as *mut u8,
+                    PAGE_SIZE as usize,
+                )
+            };
+
+            if let Err(error) = mapping.file.read_page(index, buffer) {
+                println!("mmap: reading page {} at {:#x}: {:?}", index, virt, error);
+
+                frame::free(phys, 1);
+
+                return Err(error);
+            }
+
+            READS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if let Some(page) = page::from_phys(phys) {
+        page.set_owner(Owner::Mapped);
+    }
+
+    if present.is_some() {
+        paging::unmap(virt, PAGE_SIZE as usize);
+    }
+
+    let writable = mapping.protection.contains(Protection::WRITE);
+
+    paging::map(virt, phys, PAGE_SIZE as usize, mapping.flags(writable));
+
+    mapping.frames.insert(virt, phys);
+
+    Ok(phys)
+}
+
+/// Move the page of a mapping in the frame at `from` to the frame at `to`, returns false if no
+/// mapping owns it or the mappings are busy
+pub fn migrate(from: u64, to: u64) -> bool {
+    // Compaction can be asked for with anything held, even this
+    let Some(mut mappings) = MAPPINGS.try_lock() else {
+        return false;
+    };
+
+    let Some((&virt, phys)) = mappings
+        .mappings
+        .iter_mut()
+        .find_map(|mapping| mapping.frames.iter_mut().find(|(_, phys)| **phys == from))
+    else {
+        return false;
+    };
+
+    copy_page(from, to);
+
+    if let Some(page) = page::from_phys(to) {
+        page.set_owner(Owner::Mapped);
+    }
+
+    // The address is all of the entry that is not its flags, and the flags stay as they were
+    if paging::translate(virt) == Some(from)
+        && let Some(entry) = paging::read_entry(virt)
+    {
+        paging::write_entry(virt, entry ^ from | to);
+    }
+
+    *phys = to;
+
+    frame::put(from);
+
+    true
+}
+
+/// Write `data` at `address` into private mappings whatever their protection, copying pages like
+/// a first write to them does, for debuggers putting breakpoints into text
+pub fn write_private(address: u64, data: &[u8]) -> Result<(), Error> {
+    let mut written = 0;
+
+    while written < data.len() {
+        let at = address + written as u64;
+        let virt = at & !(PAGE_SIZE - 1);
+        let offset = (at - virt) as usize;
+        let len = (PAGE_SIZE as usize - offset).min(data.len() - written);
+
+        let phys = {
+            let mut mappings = MAPPINGS.lock();
+
+            let mapping = mappings
+                .mappings
+                .iter_mut()
+                .find(|mapping| mapping.range().contains(&virt))
+                .ok_or(Error::InvalidArgument)?;
+
+            if mapping.sharing != Sharing::Private {
+                return Err(Error::NotSupported);
+            }
+
+            match mapping.frames.get(&virt) {
+                Some(&phys) => phys,
+                None => {
+                    let shared_frame = mapping.file.frame(mapping.index(virt));
+
+                    copy_in(mapping, virt, paging::translate(virt), shared_frame)?
+                }
+            }
+        };
+
+        // Through where every frame is mapped, as the page may not be writable
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data[written..].as_ptr(),
+                (paging::virt_from_phys(phys) + offset as u64) as *mut u8,
+                len,
+            );
+        }
+
+        written += len;
+    }
+
+    Ok(())
+}
+
+pub fn dump() {
+    let Some(mappings) = MAPPINGS.try_lock() else {
+        println!("mmap: busy, try again later");
+        return;
+    };
+
+    println!(
+        "mmap: {} mappings holding {} pages, {} reads, {} copies on write, {} write backs",
+        mappings.mappings.len(),
+        mappings
+            .mappings
+            .iter()
+            .map(|mapping| mapping.frames.len())
+            .sum::<usize>(),
+        READS.load(Ordering::Relaxed),
+        COPIES.load(Ordering::Relaxed),
+        WRITE_BACKS.load(Ordering::Relaxed)
+    );
+}
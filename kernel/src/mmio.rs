@@ -0,0 +1,51 @@
+/// A mapped range of device registers
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    base: u64,
+    len: usize,
+}
+
+impl Mmio {
+    pub fn map(phys: u64, len: usize) -> Self {
+        Self {
+            base: crate::paging::map_mmio(phys, len),
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// A part of this range starting at `offset`
+    pub fn subrange(&self, offset: usize, len: usize) -> Self {
+        assert!(offset + len <= self.len, "mmio subrange out of bounds");
+
+        Self {
+            base: self.base + offset as u64,
+            len,
+        }
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(
+            offset + size_of::<T>() <= self.len,
+            "mmio read out of bounds"
+        );
+
+        unsafe { ((self.base as usize + offset) as *const T).read_volatile() }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(
+            offset + size_of::<T>() <= self.len,
+            "mmio write out of bounds"
+        );
+
+        unsafe { ((self.base as usize + offset) as *mut T).write_volatile(value) }
+    }
+}
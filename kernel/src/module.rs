@@ -0,0 +1,667 @@
+//! Loading drivers built as separate relocatable objects at runtime
+//!
+//! A module is an x86_64 ELF relocatable object built with the kernel code model. Its undefined
+//! symbols are resolved against the kernel's exported symbols and those of the modules loaded
+//! before it, which it then depends on. It is started by calling its `module_init`, which returns
+//! zero on success, and `module_exit` is called when it is unloaded, if it has one. Its own exports
+//! go in a `.symbols` section, using the same [`Symbol`] layout as the kernel. A module built
+//! against a particular [`symbols::abi_version`] says so in a `u64` called `module_abi_version`,
+//! and is refused by any other kernel.
+//!
+//! With no file system yet, the bootloader's modules whose paths end in `.ko` are loaded at boot.
+
+use alloc::{
+    alloc::{Layout, alloc, dealloc},
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    arch::paging::{PAGE_SIZE, PageTableFlags},
+    export,
+    memory::{self, VirtualBuffer},
+    paging,
+    requests::MODULE_REQUEST,
+    symbols::{self, Symbol},
+    sync::Mutex,
+};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_RELOCATABLE: u16 = 1;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+
+const SECTION_SYMBOLS: u32 = 2;
+const SECTION_RELOCATIONS: u32 = 4;
+const SECTION_NO_BITS: u32 = 8;
+
+const SECTION_ALLOCATE: u64 = 1 << 1;
+const SECTION_EXECUTE: u64 = 1 << 2;
+
+const SYMBOL_UNDEFINED: u16 = 0;
+const SYMBOL_ABSOLUTE: u16 = 0xFFF1;
+const SYMBOL_COMMON: u16 = 0xFFF2;
+const BINDING_WEAK: u8 = 2;
+
+const RELOCATION_64: u32 = 1;
+const RELOCATION_PC32: u32 = 2;
+const RELOCATION_PLT32: u32 = 4;
+const RELOCATION_32: u32 = 10;
+const RELOCATION_32S: u32 = 11;
+const RELOCATION_PC64: u32 = 24;
+
+/// How much virtual memory after the kernel is set aside for modules
+const AREA_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Not a 64 bit little endian x86_64 relocatable object, or a broken one
+    NotObject,
+    /// A module with the same name is loaded
+    AlreadyLoaded,
+    NotLoaded,
+    /// Neither the kernel nor a loaded module exports this symbol
+    UndefinedSymbol(String),
+    /// Needs a global offset table or another relocation that is not supported
+    UnsupportedRelocation(u32),
+    /// The module is too far from what it refers to for a 32 bit relocation
+    RelocationOverflow,
+    OutOfMemory,
+    /// `module_init` returned this instead of zero
+    InitFailed(i32),
+    /// Other modules use these symbols
+    InUse(Vec<String>),
+    /// The module was built against another version of the kernel's exports
+    AbiMismatch {
+        expected: u64,
+        found: u64,
+    },
+}
+
+struct Module {
+    name: String,
+    base: u64,
+    len: usize,
+    /// Where it is mapped from, freed once it is unloaded
+    memory: VirtualBuffer,
+    exports: Vec<(String, u64)>,
+    /// The modules it uses symbols of
+    dependencies: Vec<String>,
+    exit: Option<u64>,
+}
+
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+/// The virtual memory after the kernel that is not given to a module yet, only given back by
+/// modules that failed to load while nothing was given out after them
+static NEXT_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+unsafe extern "C" {
+    static __kernel_end: u8;
+}
+
+fn reserve_address(len: u64) -> Option<u64> {
+    let area_start = ((&raw const __kernel_end) as u64).next_multiple_of(PAGE_SIZE);
+    let area_end = area_start.checked_add(AREA_SIZE)?;
+
+    let _ = NEXT_ADDRESS.compare_exchange(0, area_start, Ordering::Relaxed, Ordering::Relaxed);
+
+    let base = NEXT_ADDRESS.fetch_add(len, Ordering::Relaxed);
+
+    if base.checked_add(len).is_none_or(|end| end > area_end) {
+        drop(Reservation { base, len });
+        return None;
+    }
+
+    Some(base)
+}
+
+/// Addresses from [`reserve_address`], given back when dropped unless the module got loaded
+struct Reservation {
+    base: u64,
+    len: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let _ = NEXT_ADDRESS.compare_exchange(
+            self.base.wrapping_add(self.len),
+            self.base,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+}
+
+impl Section {
+    fn data<'a>(&self, object: &'a [u8]) -> Option<&'a [u8]> {
+        object.get(self.offset as usize..self.offset.checked_add(self.size)? as usize)
+    }
+}
+
+fn sections(object: &[u8]) -> Option<Vec<Section>> {
+    if read::<4>(object, 0)? != ELF_MAGIC
+        || object.get(4) != Some(&ELF_CLASS_64)
+        || object.get(5) != Some(&ELF_DATA_LITTLE_ENDIAN)
+        || read_u16(object, 16)? != ELF_TYPE_RELOCATABLE
+        || read_u16(object, 18)? != ELF_MACHINE_X86_64
+    {
+        return None;
+    }
+
+    let table = read_u64(object, 40)? as usize;
+    let entry_size = read_u16(object, 58)? as usize;
+    let count = read_u16(object, 60)? as usize;
+
+    (0..count)
+        .map(|index| {
+            let header = table.checked_add(index.checked_mul(entry_size)?)?;
+
+            Some(Section {
+                name: read_u32(object, header)?,
+                kind: read_u32(object, header + 4)?,
+                flags: read_u64(object, header + 8)?,
+                offset: read_u64(object, header + 24)?,
+                size: read_u64(object, header + 32)?,
+                link: read_u32(object, header + 40)?,
+                info: read_u32(object, header + 44)?,
+                align: read_u64(object, header + 48)?.max(1),
+            })
+        })
+        .collect()
+}
+
+/// The nul terminated string at `offset` in a string table
+fn string(table: &[u8], offset: u32) -> Option<&str> {
+    let bytes = table.get(offset as usize..)?;
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Where every allocated section goes, executable ones first so they can share pages that are not
+/// writable, and how long each part is
+fn layout(sections: &[Section]) -> (Vec<Option<u64>>, u64, u64) {
+    let mut offsets = vec![None; sections.len()];
+    let mut end = 0u64;
+    let mut text_len = 0;
+
+    for executable in [true, false] {
+        for (index, section) in sections.iter().enumerate() {
+            if section.flags & SECTION_ALLOCATE == 0
+                || (section.flags & SECTION_EXECUTE != 0) != executable
+            {
+                continue;
+            }
+
+            let offset = end.next_multiple_of(section.align);
+
+            offsets[index] = Some(offset);
+            end = offset + section.size;
+        }
+
+        if executable {
+            end = end.next_multiple_of(PAGE_SIZE);
+            text_len = end;
+        }
+    }
+
+    (
+        offsets,
+        text_len,
+        end.next_multiple_of(PAGE_SIZE).max(PAGE_SIZE),
+    )
+}
+
+/// Find what an undefined symbol refers to, in the kernel or in a loaded module
+fn resolve(name: &str, modules: &[Module], dependencies: &mut Vec<String>) -> Option<u64> {
+    if let Some(address) = symbols::resolve(name) {
+        return Some(address);
+    }
+
+    let (module, address) = modules.iter().find_map(|module| {
+        module
+            .exports
+            .iter()
+            .find(|(export, _)| export == name)
+            .map(|(_, address)| (module, *address))
+    })?;
+
+    if !dependencies.contains(&module.name) {
+        dependencies.push(module.name.clone());
+    }
+
+    Some(address)
+}
+
+/// Apply every relocation in `relocations` to the section at `target`, whose copy of `size` bytes
+/// is at `copy`
+fn relocate(
+    relocations: &[u8],
+    symbols: &[u64],
+    target: u64,
+    copy: *mut u8,
+    size: u64,
+) -> Result<(), Error> {
+    for relocation in relocations.as_chunks::<24>().0 {
+        let offset = read_u64(relocation, 0).ok_or(Error::NotObject)?;
+        let info = read_u64(relocation, 8).ok_or(Error::NotObject)?;
+        let addend = read_u64(relocation, 16).ok_or(Error::NotObject)?;
+
+        let symbol = *symbols.get((info >> 32) as usize).ok_or(Error::NotObject)?;
+        let kind = info as u32;
+
+        let width = match kind {
+            RELOCATION_64 | RELOCATION_PC64 => 8,
+            RELOCATION_PC32 | RELOCATION_PLT32 | RELOCATION_32 | RELOCATION_32S => 4,
+            _ => return Err(Error::UnsupportedRelocation(kind)),
+        };
+
+        // What is written has to be in the section, or it would land in whatever follows it
+        if offset.checked_add(width).is_none_or(|end| end > size) {
+            return Err(Error::NotObject);
+        }
+
+        let place = target + offset;
+        let value = symbol.wrapping_add(addend);
+        let relative = value.wrapping_sub(place);
+        let copy = copy.wrapping_add(offset as usize);
+
+        unsafe {
+            match kind {
+                RELOCATION_64 => copy.cast::<u64>().write_unaligned(value),
+                RELOCATION_PC64 => copy.cast::<u64>().write_unaligned(relative),
+                RELOCATION_PC32 | RELOCATION_PLT32 => copy.cast::<i32>().write_unaligned(
+                    i32::try_from(relative as i64).map_err(|_| Error::RelocationOverflow)?,
+                ),
+                RELOCATION_32 => copy
+                    .cast::<u32>()
+                    .write_unaligned(u32::try_from(value).map_err(|_| Error::RelocationOverflow)?),
+                RELOCATION_32S => copy.cast::<i32>().write_unaligned(
+                    i32::try_from(value as i64).map_err(|_| Error::RelocationOverflow)?,
+                ),
+                _ => return Err(Error::UnsupportedRelocation(kind)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy `object` into memory after the kernel, link it and call its `module_init`
+pub fn load(name: &str, object: &[u8]) -> Result<(), Error> {
+    let sections = sections(object).ok_or(Error::NotObject)?;
+
+    let section_names = sections
+        .get(read_u16(object, 62).ok_or(Error::NotObject)? as usize)
+        .and_then(|section| section.data(object))
+        .ok_or(Error::NotObject)?;
+
+    let (offsets, text_len, len) = layout(&sections);
+
+    let mut memory = memory::vmalloc(len as usize).map_err(|_| Error::OutOfMemory)?;
+    let base = reserve_address(len).ok_or(Error::OutOfMemory)?;
+    let reservation = Reservation { base, len };
+    let copy = memory.as_mut_slice().as_mut_ptr();
+
+    for (section, offset) in sections.iter().zip(&offsets) {
+        if let Some(offset) = offset
+            && section.kind != SECTION_NO_BITS
+        {
+            let data = section.data(object).ok_or(Error::NotObject)?;
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data.as_ptr(),
+                    copy.add(*offset as usize),
+                    data.len(),
+                )
+            };
+        }
+    }
+
+    let modules = MODULES.lock();
+
+    if modules.iter().any(|module| module.name == name) {
+        return Err(Error::AlreadyLoaded);
+    }
+
+    // The address of every symbol, by its index in the symbol table
+    let symbol_table = sections
+        .iter()
+        .find(|section| section.kind == SECTION_SYMBOLS)
+        .ok_or(Error::NotObject)?;
+    let symbol_names = sections
+        .get(symbol_table.link as usize)
+        .and_then(|section| section.data(object))
+        .ok_or(Error::NotObject)?;
+
+    let mut dependencies = Vec::new();
+    let mut addresses = Vec::new();
+    let mut init = None;
+    let mut exit = None;
+    let mut abi_version = None;
+
+    for symbol in symbol_table
+        .data(object)
+        .ok_or(Error::NotObject)?
+        .as_chunks::<24>()
+        .0
+    {
+        let symbol_name = string(symbol_names, read_u32(symbol, 0).ok_or(Error::NotObject)?)
+            .ok_or(Error::NotObject)?;
+        let binding = symbol[4] >> 4;
+        let index = read_u16(symbol, 6).ok_or(Error::NotObject)?;
+        let value = read_u64(symbol, 8).ok_or(Error::NotObject)?;
+
+        let address = match index {
+            // The first symbol is always the undefined one with no name
+            SYMBOL_UNDEFINED if symbol_name.is_empty() => 0,
+            SYMBOL_UNDEFINED => match resolve(symbol_name, &modules, &mut dependencies) {
+                Some(address) => address,
+                None if binding == BINDING_WEAK => 0,
+                None => return Err(Error::UndefinedSymbol(symbol_name.to_string())),
+            },
+            SYMBOL_ABSOLUTE => value,
+            SYMBOL_COMMON => return Err(Error::NotObject),
+            index => match offsets.get(index as usize) {
+                Some(Some(offset)) => base + offset + value,
+                // Symbols in sections that are not loaded, like debug information
+                _ => 0,
+            },
+        };
+
+        match symbol_name {
+            "module_init" => init = Some(address),
+            "module_abi_version" => abi_version = Some(address),
+            "module_exit" => exit = Some(address),
+            _ => {}
+        }
+
+        addresses.push(address);
+    }
+
+    for section in sections
+        .iter()
+        .filter(|section| section.kind == SECTION_RELOCATIONS)
+    {
+        let Some(Some(target)) = offsets.get(section.info as usize) else {
+            continue;
+        };
+
+        relocate(
+            section.data(object).ok_or(Error::NotObject)?,
+            &addresses,
+            base + target,
+            copy.wrapping_add(*target as usize),
+            sections[section.info as usize].size,
+        )?;
+    }
+
+    if let Some(address) = abi_version {
+        let found = address
+            .checked_sub(base)
+            .filter(|&offset| offset + 8 <= len)
+            .map(|offset| unsafe { copy.add(offset as usize).cast::<u64>().read_unaligned() })
+            .ok_or(Error::NotObject)?;
+        let expected = symbols::abi_version();
+
+        if found != expected {
+            return Err(Error::AbiMismatch { expected, found });
+        }
+    }
+
+    // The module's own exports, now that the pointers in them are relocated
+    let exports = sections
+        .iter()
+        .zip(&offsets)
+        .filter(|(section, _)| string(section_names, section.name) == Some(".symbols"))
+        .filter_map(|(section, offset)| Some((section, (*offset)?)))
+        .flat_map(|(section, offset)| {
+            let start = copy.wrapping_add(offset as usize) as *const Symbol;
+
+            (0..section.size as usize / size_of::<Symbol>())
+                .map(move |index| unsafe { &*start.add(index) })
+        })
+        .map(|symbol| {
+            // The name points at where the module runs, which is not mapped yet
+            let name_offset = symbol.name().as_ptr() as u64 - base;
+            let name = unsafe {
+                core::str::from_utf8(core::slice::from_raw_parts(
+                    copy.wrapping_add(name_offset as usize),
+                    symbol.name().len(),
+                ))
+            };
+
+            name.map(|name| (name.to_string(), symbol.address()))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::NotObject)?;
+
+    drop(modules);
+
+    // The pages are only contiguous where vmalloc mapped them, so they are mapped one at a time
+    for (offset, phys) in (0..len).step_by(PAGE_SIZE as usize).zip(memory.pages()) {
+        let flags = match offset < text_len {
+            true => PageTableFlags::GLOBAL,
+            false => PageTableFlags::GLOBAL | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        };
+
+        paging::map(base + offset, phys, PAGE_SIZE as usize, flags);
+    }
+
+    if let Some(init) = init {
+        let result = unsafe { call_init(init) };
+
+        if result != 0 {
+            paging::unmap(base, len as usize);
+            return Err(Error::InitFailed(result));
+        }
+    }
+
+    println!(
+        "module: loaded {} at {:#x}{}{}",
+        name,
+        base,
+        if dependencies.is_empty() {
+            ""
+        } else {
+            ", using "
+        },
+        dependencies.join(", ")
+    );
+
+    core::mem::forget(reservation);
+
+    MODULES.lock().push(Module {
+        name: name.to_string(),
+        base,
+        len: len as usize,
+        memory,
+        exports,
+        dependencies,
+        exit,
+    });
+
+    Ok(())
+}
+
+/// Call the `module_init` at `address`, which may be built without the checks of indirect calls
+/// and so without the type they check
+///
+/// # Safety
+///
+/// `address` must be the `module_init` of a loaded module
+#[cfg_attr(feature = "kcfi", sanitize(kcfi = "off"))]
+unsafe fn call_init(address: u64) -> i32 {
+    let init: extern "C" fn() -> i32 = unsafe { core::mem::transmute(address as usize) };
+
+    init()
+}
+
+/// Call the `module_exit` at `address`, like [`call_init`]
+///
+/// # Safety
+///
+/// `address` must be the `module_exit` of a loaded module
+#[cfg_attr(feature = "kcfi", sanitize(kcfi = "off"))]
+unsafe fn call_exit(address: u64) {
+    let exit: extern "C" fn() = unsafe { core::mem::transmute(address as usize) };
+
+    exit();
+}
+
+/// Call the module's `module_exit` and free it, as long as no other module uses it
+pub fn unload(name: &str) -> Result<(), Error> {
+    let mut modules = MODULES.lock();
+
+    let users: Vec<String> = modules
+        .iter()
+        .filter(|module| {
+            module
+                .dependencies
+                .iter()
+                .any(|dependency| dependency == name)
+        })
+        .map(|module| module.name.clone())
+        .collect();
+
+    if !users.is_empty() {
+        return Err(Error::InUse(users));
+    }
+
+    let index = modules
+        .iter()
+        .position(|module| module.name == name)
+        .ok_or(Error::NotLoaded)?;
+    let module = modules.remove(index);
+
+    drop(modules);
+
+    if let Some(exit) = module.exit {
+        unsafe { call_exit(exit) };
+    }
+
+    paging::unmap(module.base, module.len);
+
+    println!("module: unloaded {}", module.name);
+
+    drop(module.memory);
+
+    Ok(())
+}
+
+pub fn dump() {
+    let Some(modules) = MODULES.try_lock() else {
+        println!("module: busy, try again later");
+        return;
+    };
+
+    if modules.is_empty() {
+        println!("module: none loaded");
+    }
+
+    for module in modules.iter() {
+        println!(
+            "module: {} at {:#x}, {} KiB, {} exports, using [{}]",
+            module.name,
+            module.base,
+            module.len / 1024,
+            module.exports.len(),
+            module.dependencies.join(", ")
+        );
+    }
+}
+
+/// For modules to print, they can not use the kernel's formatting machinery
+///
+/// # Safety
+///
+/// `message` must point at `len` readable bytes
+pub unsafe extern "C" fn module_print(message: *const u8, len: usize) {
+    let message = unsafe { core::slice::from_raw_parts(message, len) };
+
+    print!("{}", String::from_utf8_lossy(message));
+}
+
+export!(module_print: unsafe extern "C" fn(*const u8, usize));
+
+/// For modules to allocate from the kernel's heap, returns null when out of memory
+pub extern "C" fn module_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size.max(1), align) {
+        Ok(layout) => unsafe { alloc(layout) },
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+export!(module_alloc: extern "C" fn(usize, usize) -> *mut u8);
+
+/// Free what [`module_alloc`] gave
+///
+/// # Safety
+///
+/// `pointer` must come from [`module_alloc`] with the same size and alignment
+pub unsafe extern "C" fn module_free(pointer: *mut u8, size: usize, align: usize) {
+    if let Ok(layout) = Layout::from_size_align(size.max(1), align) {
+        unsafe { dealloc(pointer, layout) };
+    }
+}
+
+export!(module_free: unsafe extern "C" fn(*mut u8, usize, usize));
+
+/// Load the bootloader's modules whose paths end in `.ko`, named after their file names
+pub fn init() {
+    let Some(response) = MODULE_REQUEST.get_response() else {
+        return;
+    };
+
+    for file in response.modules() {
+        let Ok(path) = file.path().to_str() else {
+            continue;
+        };
+
+        let Some(name) = path
+            .rsplit('/')
+            .next()
+            .and_then(|file| file.strip_suffix(".ko"))
+        else {
+            continue;
+        };
+
+        let object = unsafe { core::slice::from_raw_parts(file.addr(), file.size() as usize) };
+
+        if let Err(error) = load(name, object) {
+            println!("module: could not load {}: {:?}", name, error);
+        }
+    }
+}
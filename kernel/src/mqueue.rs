@@ -0,0 +1,265 @@
+//! POSIX message queues, named queues of messages received highest priority first
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    file::{self, Error, Events, File, OpenFlags},
+    sync::Mutex,
+    time,
+    wait::{self, WaitQueue},
+};
+
+/// Priorities go from zero up to, but not including, this
+pub const PRIORITY_MAX: u32 = 32768;
+
+/// The limits of a queue, fixed once it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub max_messages: usize,
+    pub message_size: usize,
+}
+
+impl Attributes {
+    /// The limits of `attributes` are capped at these
+    pub const LIMIT: Self = Self {
+        max_messages: 1024,
+        message_size: 64 * 1024,
+    };
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Self {
+            max_messages: 10,
+            message_size: 8192,
+        }
+    }
+}
+
+struct Messages {
+    /// Oldest first within each priority
+    queues: BTreeMap<u32, VecDeque<Vec<u8>>>,
+    count: usize,
+}
+
+pub struct MessageQueue {
+    attributes: Attributes,
+    messages: Mutex<Messages>,
+    wait_queue: WaitQueue,
+}
+
+impl MessageQueue {
+    fn new(attributes: Attributes) -> Self {
+        Self {
+            attributes,
+            messages: Mutex::new(Messages {
+                queues: BTreeMap::new(),
+                count: 0,
+            }),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    /// How many messages are waiting to be received
+    pub fn len(&self) -> usize {
+        self.messages.lock().count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn try_send(&self, data: &[u8], priority: u32) -> bool {
+        let mut messages = self.messages.lock();
+
+        if messages.count == self.attributes.max_messages {
+            return false;
+        }
+
+        messages
+            .queues
+            .entry(priority)
+            .or_default()
+            .push_back(data.into());
+        messages.count += 1;
+
+        true
+    }
+
+    fn try_receive(&self, buffer: &mut [u8]) -> Option<(usize, u32)> {
+        let mut messages = self.messages.lock();
+
+        let mut entry = messages.queues.last_entry()?;
+        let priority = *entry.key();
+        let message = entry.get_mut().pop_front().unwrap();
+
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+
+        messages.count -= 1;
+
+        buffer[..message.len()].copy_from_slice(&message);
+
+        Some((message.len(), priority))
+    }
+}
+
+static QUEUES: Mutex<BTreeMap<String, Arc<MessageQueue>>> = Mutex::new(BTreeMap::new());
+
+/// An open message queue, whether it blocks is per descriptor like for any other file
+pub struct MessageQueueFile {
+    queue: Arc<MessageQueue>,
+    nonblocking: AtomicBool,
+}
+
+impl MessageQueueFile {
+    pub fn queue(&self) -> &MessageQueue {
+        &self.queue
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    /// Wait for `f` until the deadline, or not at all when nonblocking
+    fn wait_for<T>(
+        &self,
+        timeout_ns: Option<u64>,
+        f: impl FnMut() -> Option<T>,
+    ) -> Result<T, Error> {
+        let deadline = if self.nonblocking.load(Ordering::Relaxed) {
+            Some(0)
+        } else {
+            timeout_ns.map(|timeout| time::monotonic_ns() + timeout)
+        };
+
+        wait::until(deadline, f).ok_or(if deadline == Some(0) {
+            Error::WouldBlock
+        } else {
+            Error::TimedOut
+        })
+    }
+
+    /// Queue a message, waiting for room as long as `timeout_ns` allows, `mq_timedsend`
+    pub fn send(&self, data: &[u8], priority: u32, timeout_ns: Option<u64>) -> Result<(), Error> {
+        if data.len() > self.queue.attributes.message_size {
+            return Err(Error::MessageTooLong);
+        }
+
+        if priority >= PRIORITY_MAX {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.wait_for(timeout_ns, || {
+            self.queue.try_send(data, priority).then_some(())
+        })?;
+
+        self.queue.wait_queue.wake_all();
+
+        Ok(())
+    }
+
+    /// Take the oldest message of the highest priority, returning its length and priority, the
+    /// buffer must fit any message the queue allows, `mq_timedreceive`
+    pub fn receive(
+        &self,
+        buffer: &mut [u8],
+        timeout_ns: Option<u64>,
+    ) -> Result<(usize, u32), Error> {
+        if buffer.len() < self.queue.attributes.message_size {
+            return Err(Error::MessageTooLong);
+        }
+
+        let received = self.wait_for(timeout_ns, || self.queue.try_receive(buffer))?;
+
+        self.queue.wait_queue.wake_all();
+
+        Ok(received)
+    }
+}
+
+impl File for MessageQueueFile {
+    fn poll(&self) -> Events {
+        let count = self.queue.len();
+
+        let mut events = Events::empty();
+
+        if count != 0 {
+            events |= Events::READABLE;
+        }
+
+        if count < self.queue.attributes.max_messages {
+            events |= Events::WRITABLE;
+        }
+
+        events
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.queue.wait_queue)
+    }
+}
+
+/// Open the queue with `name`, creating it with `attributes` or the defaults with
+/// `OpenFlags::CREATE`
+pub fn mq_open(
+    name: &str,
+    flags: OpenFlags,
+    attributes: Option<Attributes>,
+) -> Result<MessageQueueFile, Error> {
+    file::validate_ipc_name(name)?;
+
+    let attributes = attributes.unwrap_or_default();
+
+    if attributes.max_messages == 0
+        || attributes.message_size == 0
+        || attributes.max_messages > Attributes::LIMIT.max_messages
+        || attributes.message_size > Attributes::LIMIT.message_size
+    {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut queues = QUEUES.lock();
+
+    let queue = match queues.get(name) {
+        Some(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(Error::AlreadyExists);
+        }
+        Some(queue) => queue.clone(),
+        None if flags.contains(OpenFlags::CREATE) => {
+            let queue = Arc::new(MessageQueue::new(attributes));
+
+            queues.insert(name.into(), queue.clone());
+
+            queue
+        }
+        None => return Err(Error::NotFound),
+    };
+
+    Ok(MessageQueueFile {
+        queue,
+        nonblocking: AtomicBool::new(flags.contains(OpenFlags::NONBLOCK)),
+    })
+}
+
+/// Remove the name, the queue and its messages live on until the last open file is dropped
+pub fn mq_unlink(name: &str) -> Result<(), Error> {
+    file::validate_ipc_name(name)?;
+
+    QUEUES
+        .lock()
+        .remove(name)
+        .map(|_| ())
+        .ok_or(Error::NotFound)
+}
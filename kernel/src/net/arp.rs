@@ -0,0 +1,340 @@
+//! Resolving the ethernet addresses of neighbors on the same network
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use super::{
+    Interface, MacAddress, PacketBuffer,
+    buffer::DEFAULT_HEADROOM,
+    ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4},
+    ipv4::Ipv4Address,
+};
+use crate::{
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+const PACKET_SIZE: usize = 28;
+
+const HARDWARE_ETHERNET: u16 = 1;
+
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+/// How long a resolved entry is trusted before it is resolved again
+const ENTRY_LIFETIME_NS: u64 = 60 * NANOSECONDS_PER_SECOND;
+/// How long to wait for a reply before asking again
+const RETRY_INTERVAL_NS: u64 = NANOSECONDS_PER_SECOND;
+/// How many requests are sent before giving up and dropping the waiting packets
+const MAX_REQUESTS: u8 = 3;
+/// How many packets can wait for a single address to be resolved
+const MAX_PENDING: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    operation: u16,
+    sender_mac: MacAddress,
+    sender_ip: Ipv4Address,
+    target_mac: MacAddress,
+    target_ip: Ipv4Address,
+}
+
+impl Packet {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let data: &[u8; PACKET_SIZE] = data.get(..PACKET_SIZE)?.try_into().ok()?;
+
+        let hardware = u16::from_be_bytes([data[0], data[1]]);
+        let protocol = u16::from_be_bytes([data[2], data[3]]);
+
+        if hardware != HARDWARE_ETHERNET
+            || protocol != ETHERTYPE_IPV4
+            || data[4] != 6
+            || data[5] != 4
+        {
+            return None;
+        }
+
+        Some(Self {
+            operation: u16::from_be_bytes([data[6], data[7]]),
+            sender_mac: MacAddress(data[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Address(data[14..18].try_into().unwrap()),
+            target_mac: MacAddress(data[18..24].try_into().unwrap()),
+            target_ip: Ipv4Address(data[24..28].try_into().unwrap()),
+        })
+    }
+
+    fn to_buffer(self) -> PacketBuffer {
+        let mut packet = PacketBuffer::new(DEFAULT_HEADROOM, PACKET_SIZE);
+        let data = packet.data_mut();
+
+        data[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        data[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        data[4] = 6;
+        data[5] = 4;
+        data[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        data[8..14].copy_from_slice(&self.sender_mac.0);
+        data[14..18].copy_from_slice(&self.sender_ip.0);
+        data[18..24].copy_from_slice(&self.target_mac.0);
+        data[24..28].copy_from_slice(&self.target_ip.0);
+
+        packet
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Resolved {
+        mac: MacAddress,
+        updated_at: u64,
+    },
+    Incomplete {
+        requested_at: u64,
+        requests: u8,
+        /// IPv4 packets waiting for the address
+        pending: Vec<PacketBuffer>,
+    },
+}
+
+/// Entries are keyed by the index of the interface and the address of the neighbor
+static CACHE: Mutex<BTreeMap<(usize, Ipv4Address), State>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Neighbor {
+    pub interface: usize,
+    pub ip: Ipv4Address,
+    pub mac: MacAddress,
+    /// Nanoseconds until the entry expires
+    pub expires_in_ns: u64,
+}
+
+/// Every resolved entry that did not expire yet
+pub fn neighbors() -> Vec<Neighbor> {
+    let now = time::monotonic_ns();
+
+    CACHE
+        .lock()
+        .iter()
+        .filter_map(|(&(interface, ip), state)| match *state {
+            State::Resolved { mac, updated_at } if now - updated_at < ENTRY_LIFETIME_NS => {
+                Some(Neighbor {
+                    interface,
+                    ip,
+                    mac,
+                    expires_in_ns: ENTRY_LIFETIME_NS - (now - updated_at),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Forget the entries that expired and the requests that were never answered, and ask again for
+/// the ones that can still be answered
+pub fn expire() {
+    let now = time::monotonic_ns();
+    let mut retries = Vec::new();
+
+    CACHE.lock().retain(|&key, state| match state {
+        State::Resolved { updated_at, .. } => now - *updated_at < ENTRY_LIFETIME_NS,
+        State::Incomplete {
+            requested_at,
+            requests,
+            ..
+        } => {
+            if now - *requested_at < RETRY_INTERVAL_NS {
+                return true;
+            }
+
+            if *requests >= MAX_REQUESTS {
+                return false;
+            }
+
+            *requested_at = now;
+            *requests += 1;
+            retries.push(key);
+
+            true
+        }
+    });
+
+    for (index, ip) in retries {
+        if let Some(interface) = super::interface(index) {
+            request(&interface, ip);
+        }
+    }
+}
+
+pub fn lookup(interface: &Interface, ip: Ipv4Address) -> Option<MacAddress> {
+    match CACHE.lock().get(&(interface.index, ip)) {
+        Some(&State::Resolved { mac, updated_at })
+            if time::monotonic_ns() - updated_at < ENTRY_LIFETIME_NS =>
+        {
+            Some(mac)
+        }
+        _ => None,
+    }
+}
+
+fn send(interface: &Interface, destination: MacAddress, packet: Packet) {
+    let _ = ethernet::transmit(interface, destination, ETHERTYPE_ARP, packet.to_buffer());
+}
+
+fn request(interface: &Interface, ip: Ipv4Address) {
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+
+    send(
+        interface,
+        MacAddress::BROADCAST,
+        Packet {
+            operation: OPERATION_REQUEST,
+            sender_mac: interface.mac(),
+            sender_ip: config.address,
+            target_mac: MacAddress::default(),
+            target_ip: ip,
+        },
+    );
+}
+
+/// Announce our address so neighbors update their caches, sent when an interface comes up
+pub fn announce(interface: &Interface) {
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+
+    if interface.device.is_loopback() {
+        return;
+    }
+
+    send(
+        interface,
+        MacAddress::BROADCAST,
+        Packet {
+            operation: OPERATION_REQUEST,
+            sender_mac: interface.mac(),
+            sender_ip: config.address,
+            target_mac: MacAddress::default(),
+            target_ip: config.address,
+        },
+    );
+}
+
+/// Send an IPv4 packet to a neighbor, resolving its address first if needed
+pub fn transmit_ipv4(interface: &Interface, next_hop: Ipv4Address, packet: PacketBuffer) {
+    if next_hop.is_broadcast() {
+        let _ = ethernet::transmit(interface, MacAddress::BROADCAST, ETHERTYPE_IPV4, packet);
+
+        return;
+    }
+
+    if interface.device.is_loopback() {
+        let _ = ethernet::transmit(interface, interface.mac(), ETHERTYPE_IPV4, packet);
+
+        return;
+    }
+
+    let now = time::monotonic_ns();
+    let mut cache = CACHE.lock();
+
+    let state = cache
+        .entry((interface.index, next_hop))
+        .or_insert(State::Incomplete {
+            requested_at: 0,
+            requests: 0,
+            pending: Vec::new(),
+        });
+
+    match state {
+        State::Resolved { mac, updated_at } if now - *updated_at < ENTRY_LIFETIME_NS => {
+            let mac = *mac;
+
+            drop(cache);
+
+            let _ = ethernet::transmit(interface, mac, ETHERTYPE_IPV4, packet);
+        }
+        State::Resolved { .. } => {
+            *state = State::Incomplete {
+                requested_at: now,
+                requests: 1,
+                pending: alloc::vec![packet],
+            };
+
+            drop(cache);
+
+            request(interface, next_hop);
+        }
+        State::Incomplete {
+            requested_at,
+            requests,
+            pending,
+        } => {
+            if pending.len() < MAX_PENDING {
+                pending.push(packet);
+            }
+
+            let retry = now - *requested_at >= RETRY_INTERVAL_NS;
+
+            if retry {
+                *requested_at = now;
+                *requests = requests.saturating_add(1);
+            }
+
+            drop(cache);
+
+            if retry {
+                request(interface, next_hop);
+            }
+        }
+    }
+}
+
+/// Remember the address of a neighbor and send the packets that waited for it
+fn update(interface: &Interface, ip: Ipv4Address, mac: MacAddress) {
+    let previous = CACHE.lock().insert(
+        (interface.index, ip),
+        State::Resolved {
+            mac,
+            updated_at: time::monotonic_ns(),
+        },
+    );
+
+    if let Some(State::Incomplete { pending, .. }) = previous {
+        for packet in pending {
+            let _ = ethernet::transmit(interface, mac, ETHERTYPE_IPV4, packet);
+        }
+    }
+}
+
+pub fn receive(interface: &Arc<Interface>, packet: PacketBuffer) {
+    let Some(packet) = Packet::parse(packet.data()) else {
+        return;
+    };
+
+    let config = interface.ipv4();
+
+    let for_us = config.is_some_and(|config| config.address == packet.target_ip);
+    let known = CACHE
+        .lock()
+        .contains_key(&(interface.index, packet.sender_ip));
+
+    if !packet.sender_ip.is_unspecified() && (for_us || known) {
+        update(interface, packet.sender_ip, packet.sender_mac);
+    }
+
+    if packet.operation == OPERATION_REQUEST
+        && for_us
+        && let Some(config) = config
+    {
+        send(
+            interface,
+            packet.sender_mac,
+            Packet {
+                operation: OPERATION_REPLY,
+                sender_mac: interface.mac(),
+                sender_ip: config.address,
+                target_mac: packet.sender_mac,
+                target_ip: packet.sender_ip,
+            },
+        );
+    }
+}
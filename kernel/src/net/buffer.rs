@@ -0,0 +1,132 @@
+use alloc::{vec, vec::Vec};
+
+use crate::meminfo::{self, Category};
+
+/// Room left in front of the data of a new buffer, enough for the headers of every layer
+pub const DEFAULT_HEADROOM: usize = 128;
+
+/// A packet with room in front of it, so each layer can put its header in front of the data
+/// without copying it
+#[derive(Debug, PartialEq, Eq)]
+pub struct PacketBuffer {
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBuffer {
+    /// A zeroed packet of `len` bytes with `headroom` bytes free before it
+    pub fn new(headroom: usize, len: usize) -> Self {
+        let buffer = vec![0; headroom + len];
+
+        meminfo::charge(Category::Network, buffer.capacity());
+
+        Self {
+            buffer,
+            start: headroom,
+            end: headroom + len,
+        }
+    }
+
+    /// Count what the buffer grew or shrank by since it had `capacity`
+    fn account(&self, capacity: usize) {
+        let now = self.buffer.capacity();
+
+        if now > capacity {
+            meminfo::charge(Category::Network, now - capacity);
+        } else {
+            meminfo::uncharge(Category::Network, capacity - now);
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut packet = Self::new(DEFAULT_HEADROOM, 0);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.start..self.end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Make room for a header of `len` bytes in front of the data and return it, the buffer is
+    /// moved if there is not enough headroom
+    pub fn push_header(&mut self, len: usize) -> &mut [u8] {
+        if len > self.start {
+            let grow = len - self.start + DEFAULT_HEADROOM;
+            let capacity = self.buffer.capacity();
+
+            self.buffer.splice(0..0, core::iter::repeat_n(0, grow));
+            self.start += grow;
+            self.end += grow;
+
+            self.account(capacity);
+        }
+
+        self.start -= len;
+
+        &mut self.buffer[self.start..self.start + len]
+    }
+
+    /// Remove a header of `len` bytes from the front of the data and return it
+    pub fn pull_header(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+
+        self.start += len;
+
+        Some(&self.buffer[self.start - len..self.start])
+    }
+
+    /// Drop everything after the first `len` bytes, like padding after the payload
+    pub fn truncate(&mut self, len: usize) {
+        self.end = self.start + len.min(self.len());
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        let capacity = self.buffer.capacity();
+
+        self.buffer.truncate(self.end);
+        self.buffer.extend_from_slice(data);
+        self.end += data.len();
+
+        self.account(capacity);
+    }
+}
+
+impl Clone for PacketBuffer {
+    fn clone(&self) -> Self {
+        let buffer = self.buffer.clone();
+
+        meminfo::charge(Category::Network, buffer.capacity());
+
+        Self {
+            buffer,
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        meminfo::uncharge(Category::Network, self.buffer.capacity());
+    }
+}
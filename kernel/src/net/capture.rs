@@ -0,0 +1,183 @@
+//! Packet capture, copying every frame received and sent into a ring that reads out as pcap
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::serial::SERIAL,
+    cmdline,
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+/// Enough for a whole frame of every device we drive
+pub const DEFAULT_SNAP_LEN: usize = 65535;
+
+/// How much captured data is kept before the oldest records are dropped
+const RING_BYTES: usize = 1024 * 1024;
+
+/// The magic of pcap files with nanosecond timestamps
+const PCAP_MAGIC: u32 = 0xA1B2_3C4D;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+
+struct Record {
+    timestamp_ns: u64,
+    original_len: u32,
+    data: Vec<u8>,
+}
+
+struct Capture {
+    snap_len: usize,
+    records: VecDeque<Record>,
+    bytes: usize,
+    dropped: u64,
+}
+
+/// Checked before taking the lock, so the tap costs nothing while nothing is captured
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+/// Start capturing, keeping at most `snap_len` bytes of every frame, this drops what the last
+/// capture left
+pub fn start(snap_len: usize) {
+    *CAPTURE.lock() = Some(Capture {
+        snap_len,
+        records: VecDeque::new(),
+        bytes: 0,
+        dropped: 0,
+    });
+
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop capturing, what was captured stays readable
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Called by the stack with every whole ethernet frame it receives or sends
+pub fn tap(frame: &[u8]) {
+    if !is_enabled() {
+        return;
+    }
+
+    let mut capture = CAPTURE.lock();
+
+    let Some(capture) = capture.as_mut() else {
+        return;
+    };
+
+    let data = frame[..frame.len().min(capture.snap_len)].to_vec();
+
+    capture.bytes += data.len();
+
+    capture.records.push_back(Record {
+        timestamp_ns: time::monotonic_ns(),
+        original_len: frame.len() as u32,
+        data,
+    });
+
+    while capture.bytes > RING_BYTES
+        && let Some(record) = capture.records.pop_front()
+    {
+        capture.bytes -= record.data.len();
+        capture.dropped += 1;
+    }
+}
+
+/// The captured frames as a pcap file, timestamps count from boot as there is no wall clock
+pub fn pcap() -> Vec<u8> {
+    let capture = CAPTURE.lock();
+
+    let Some(capture) = capture.as_ref() else {
+        return Vec::new();
+    };
+
+    let mut file = Vec::with_capacity(
+        PCAP_HEADER_SIZE + capture.bytes + capture.records.len() * PCAP_RECORD_HEADER_SIZE,
+    );
+
+    file.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    file.extend_from_slice(&PCAP_VERSION.0.to_le_bytes());
+    file.extend_from_slice(&PCAP_VERSION.1.to_le_bytes());
+    file.extend_from_slice(&0i32.to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    file.extend_from_slice(&(capture.snap_len as u32).to_le_bytes());
+    file.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+
+    for record in &capture.records {
+        let seconds = (record.timestamp_ns / NANOSECONDS_PER_SECOND) as u32;
+        let nanoseconds = (record.timestamp_ns % NANOSECONDS_PER_SECOND) as u32;
+
+        file.extend_from_slice(&seconds.to_le_bytes());
+        file.extend_from_slice(&nanoseconds.to_le_bytes());
+        file.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+        file.extend_from_slice(&record.original_len.to_le_bytes());
+        file.extend_from_slice(&record.data);
+    }
+
+    file
+}
+
+/// Write the capture to the serial port as hex between markers, for `xxd -r -p` on the other side
+pub fn dump() {
+    // Interrupted code may hold either lock, waiting on them would wedge the system
+    if CAPTURE.is_locked() {
+        println!("pcap: the capture is busy, try again later");
+        return;
+    }
+
+    let (records, dropped) = CAPTURE
+        .lock()
+        .as_ref()
+        .map_or((0, 0), |capture| (capture.records.len(), capture.dropped));
+
+    let file = pcap();
+
+    let Some(mut serial) = SERIAL.try_lock() else {
+        println!("pcap: the serial port is busy, try again later");
+        return;
+    };
+
+    let _ = writeln!(serial, "-----BEGIN FAJR PCAP-----");
+
+    for line in file.chunks(32) {
+        for byte in line {
+            let _ = write!(serial, "{byte:02x}");
+        }
+
+        let _ = writeln!(serial);
+    }
+
+    let _ = writeln!(serial, "-----END FAJR PCAP-----");
+
+    drop(serial);
+
+    println!("pcap: wrote {records} packets to serial, {dropped} dropped for lack of room");
+}
+
+/// Start capturing at boot with `pcap` or `pcap=<snap length>` on the command line
+pub fn init() {
+    if !cmdline::has("pcap") {
+        return;
+    }
+
+    let snap_len = cmdline::value("pcap")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_SNAP_LEN);
+
+    crate::log_info!("pcap", "capturing up to {snap_len} bytes of every frame");
+
+    start(snap_len);
+}
@@ -0,0 +1,59 @@
+//! The internet checksum, the ones' complement of the ones' complement sum of 16-bit words
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Checksum {
+    sum: u32,
+    /// A byte left over from an odd sized slice, paired with the first byte of the next one
+    odd: Option<u8>,
+}
+
+impl Checksum {
+    pub const fn new() -> Self {
+        Self { sum: 0, odd: None }
+    }
+
+    pub fn add(&mut self, mut data: &[u8]) -> &mut Self {
+        if let Some(high) = self.odd.take()
+            && let Some((&low, rest)) = data.split_first()
+        {
+            self.add_u16(u16::from_be_bytes([high, low]));
+            data = rest;
+        }
+
+        let (words, rest) = data.as_chunks::<2>();
+
+        for word in words {
+            self.add_u16(u16::from_be_bytes(*word));
+        }
+
+        if let Some(&last) = rest.first() {
+            self.odd = Some(last);
+        }
+
+        self
+    }
+
+    pub fn add_u16(&mut self, value: u16) -> &mut Self {
+        self.sum += value as u32;
+        self.sum = (self.sum & 0xFFFF) + (self.sum >> 16);
+        self
+    }
+
+    pub fn finish(&self) -> u16 {
+        let mut sum = self.sum;
+
+        if let Some(high) = self.odd {
+            sum += (high as u32) << 8;
+        }
+
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    Checksum::new().add(data).finish()
+}
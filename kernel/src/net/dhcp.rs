@@ -0,0 +1,428 @@
+//! A DHCP client that configures one interface and keeps renewing its lease
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{
+    Interface, MacAddress,
+    ipv4::{Ipv4Address, Ipv4Config},
+    route::{self, Route},
+    udp::{Datagram, UdpSocket},
+};
+use crate::{
+    rand,
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Asks the server to broadcast its replies, we can not receive unicast before having an address
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// The size of the fixed part of a message, up to and including the magic cookie
+const FIXED_SIZE: usize = 240;
+
+const INITIAL_RETRANSMIT_NS: u64 = 4 * NANOSECONDS_PER_SECOND;
+const MAX_RETRANSMIT_NS: u64 = 64 * NANOSECONDS_PER_SECOND;
+/// Requests sent for an offer before starting over with a discover
+const MAX_REQUESTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+#[derive(Debug, Default)]
+struct Reply {
+    message_type: u8,
+    address: Ipv4Address,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns_servers: Vec<Ipv4Address>,
+    server: Option<Ipv4Address>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl Reply {
+    fn parse(data: &[u8], xid: u32, mac: MacAddress) -> Option<Self> {
+        if data.len() < FIXED_SIZE
+            || data[0] != OP_REPLY
+            || data[4..8] != xid.to_be_bytes()
+            || data[28..34] != mac.0
+            || data[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut reply = Self {
+            address: Ipv4Address(data[16..20].try_into().unwrap()),
+            ..Default::default()
+        };
+
+        let mut options = &data[FIXED_SIZE..];
+
+        while let Some(&code) = options.first() {
+            match code {
+                OPTION_PAD => {
+                    options = &options[1..];
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+
+            let len = *options.get(1)? as usize;
+            let value = options.get(2..2 + len)?;
+
+            let address = || value.get(..4).map(|v| Ipv4Address(v.try_into().unwrap()));
+            let seconds = || {
+                value
+                    .get(..4)
+                    .map(|v| u32::from_be_bytes(v.try_into().unwrap()))
+            };
+
+            match code {
+                OPTION_MESSAGE_TYPE => reply.message_type = *value.first()?,
+                OPTION_SUBNET_MASK => reply.subnet_mask = address(),
+                OPTION_ROUTER => reply.router = address(),
+                OPTION_DNS_SERVERS => {
+                    reply.dns_servers = value
+                        .as_chunks::<4>()
+                        .0
+                        .iter()
+                        .map(|chunk| Ipv4Address(*chunk))
+                        .collect()
+                }
+                OPTION_SERVER_IDENTIFIER => reply.server = address(),
+                OPTION_LEASE_TIME => reply.lease_time = seconds(),
+                OPTION_RENEWAL_TIME => reply.renewal_time = seconds(),
+                OPTION_REBINDING_TIME => reply.rebinding_time = seconds(),
+                _ => {}
+            }
+
+            options = &options[2 + len..];
+        }
+
+        Some(reply)
+    }
+}
+
+struct Client {
+    interface: Arc<Interface>,
+    socket: UdpSocket,
+    state: State,
+    xid: u32,
+    /// The address offered or leased, and the server that did it
+    offered: Option<(Ipv4Address, Ipv4Address)>,
+    retransmit_at: u64,
+    retransmit_interval: u64,
+    requests: u32,
+    renew_at: u64,
+    rebind_at: u64,
+    expire_at: u64,
+}
+
+impl Client {
+    fn build(&self, message_type: u8, client_address: Ipv4Address) -> Vec<u8> {
+        let mut message = alloc::vec![0; FIXED_SIZE];
+
+        message[0] = OP_REQUEST;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&self.xid.to_be_bytes());
+
+        if client_address.is_unspecified() {
+            message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+        }
+
+        message[12..16].copy_from_slice(&client_address.0);
+        message[28..34].copy_from_slice(&self.interface.mac().0);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+        message.extend_from_slice(&[
+            OPTION_PARAMETER_LIST,
+            6,
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_DNS_SERVERS,
+            OPTION_LEASE_TIME,
+            OPTION_RENEWAL_TIME,
+            OPTION_REBINDING_TIME,
+        ]);
+
+        // Requests for an offer name the address and the server, renewals only use ciaddr
+        if message_type == DHCPREQUEST
+            && self.state == State::Requesting
+            && let Some((address, server)) = self.offered
+        {
+            message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+            message.extend_from_slice(&address.0);
+            message.extend_from_slice(&[OPTION_SERVER_IDENTIFIER, 4]);
+            message.extend_from_slice(&server.0);
+        }
+
+        message.push(OPTION_END);
+
+        message
+    }
+
+    fn broadcast(&self, message: &[u8], source: Ipv4Address) {
+        let route = Route {
+            destination: Ipv4Address::BROADCAST,
+            prefix_len: 32,
+            gateway: None,
+            interface: self.interface.index,
+            metric: 0,
+        };
+
+        let _ = self.socket.send_via(
+            &self.interface,
+            &route,
+            source,
+            message,
+            Ipv4Address::BROADCAST,
+            SERVER_PORT,
+        );
+    }
+
+    fn leased_address(&self) -> Ipv4Address {
+        self.offered
+            .map_or(Ipv4Address::UNSPECIFIED, |(address, _)| address)
+    }
+
+    fn transmit(&mut self) {
+        let now = time::monotonic_ns();
+
+        match self.state {
+            State::Init | State::Bound => return,
+            State::Selecting => {
+                self.broadcast(
+                    &self.build(DHCPDISCOVER, Ipv4Address::UNSPECIFIED),
+                    Ipv4Address::UNSPECIFIED,
+                );
+            }
+            State::Requesting => {
+                self.broadcast(
+                    &self.build(DHCPREQUEST, Ipv4Address::UNSPECIFIED),
+                    Ipv4Address::UNSPECIFIED,
+                );
+            }
+            State::Renewing => {
+                let address = self.leased_address();
+
+                if let Some((_, server)) = self.offered {
+                    let _ =
+                        self.socket
+                            .send_to(&self.build(DHCPREQUEST, address), server, SERVER_PORT);
+                }
+            }
+            State::Rebinding => {
+                let address = self.leased_address();
+
+                self.broadcast(&self.build(DHCPREQUEST, address), address);
+            }
+        }
+
+        self.retransmit_at = now + self.retransmit_interval;
+        self.retransmit_interval = (self.retransmit_interval * 2).min(MAX_RETRANSMIT_NS);
+    }
+
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        self.retransmit_interval = INITIAL_RETRANSMIT_NS;
+
+        if state == State::Selecting {
+            self.xid = rand::u64() as u32;
+            self.offered = None;
+            self.requests = 0;
+        }
+
+        self.transmit();
+    }
+
+    /// Lose the address, after a NAK or when the lease ran out
+    fn unconfigure(&mut self) {
+        crate::log_warn!("dhcp", "{} lost its lease", self.interface.name);
+
+        self.interface.set_ipv4(None);
+        route::remove(Ipv4Address::UNSPECIFIED, 0);
+
+        self.enter(State::Selecting);
+    }
+
+    fn bind(&mut self, reply: &Reply) {
+        let now = time::monotonic_ns();
+
+        let prefix_len = reply
+            .subnet_mask
+            .map_or(24, |mask| mask.to_u32().leading_ones() as u8);
+
+        let lease = reply.lease_time.unwrap_or(3600) as u64;
+        let renewal = reply.renewal_time.map_or(lease / 2, |time| time as u64);
+        let rebinding = reply
+            .rebinding_time
+            .map_or(lease * 7 / 8, |time| time as u64);
+
+        self.renew_at = now + renewal * NANOSECONDS_PER_SECOND;
+        self.rebind_at = now + rebinding * NANOSECONDS_PER_SECOND;
+        self.expire_at = now + lease * NANOSECONDS_PER_SECOND;
+
+        let config = Ipv4Config {
+            address: reply.address,
+            prefix_len,
+        };
+
+        if self.interface.ipv4() != Some(config) {
+            self.interface.set_ipv4(Some(config));
+        }
+
+        if let Some(router) = reply.router {
+            route::set_default_gateway(self.interface.index, router);
+        }
+
+        if !reply.dns_servers.is_empty() {
+            super::set_dns_servers(reply.dns_servers.clone());
+        }
+
+        if self.state != State::Renewing && self.state != State::Rebinding {
+            crate::log_ok!(
+                "dhcp",
+                "{} leased {} for {}s, gateway {}",
+                self.interface.name,
+                config,
+                lease,
+                reply.router.unwrap_or(Ipv4Address::UNSPECIFIED)
+            );
+        }
+
+        self.state = State::Bound;
+    }
+
+    fn handle(&mut self, datagram: &Datagram) {
+        let Some(reply) = Reply::parse(&datagram.data, self.xid, self.interface.mac()) else {
+            return;
+        };
+
+        match (self.state, reply.message_type) {
+            (State::Selecting, DHCPOFFER) => {
+                let server = reply.server.unwrap_or(datagram.source);
+
+                self.offered = Some((reply.address, server));
+                self.enter(State::Requesting);
+            }
+            (State::Requesting | State::Renewing | State::Rebinding, DHCPACK) => {
+                if let Some(server) = reply.server {
+                    self.offered = Some((reply.address, server));
+                }
+
+                self.bind(&reply);
+            }
+            (State::Requesting | State::Renewing | State::Rebinding, DHCPNAK) => {
+                self.unconfigure();
+            }
+            _ => {}
+        }
+    }
+
+    fn poll(&mut self) {
+        while let Some(datagram) = self.socket.try_recv() {
+            self.handle(&datagram);
+        }
+
+        let now = time::monotonic_ns();
+
+        match self.state {
+            State::Init => self.enter(State::Selecting),
+            State::Bound if now >= self.renew_at => self.enter(State::Renewing),
+            State::Renewing if now >= self.rebind_at => self.enter(State::Rebinding),
+            State::Renewing | State::Rebinding if now >= self.expire_at => self.unconfigure(),
+            State::Requesting if now >= self.retransmit_at => {
+                self.requests += 1;
+
+                if self.requests >= MAX_REQUESTS {
+                    self.enter(State::Selecting);
+                } else {
+                    self.transmit();
+                }
+            }
+            State::Selecting | State::Renewing | State::Rebinding if now >= self.retransmit_at => {
+                self.transmit();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Only one interface can be configured, the client owns the DHCP client port
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+/// Start configuring the interface, it is done once `state` returns `Bound`
+pub fn start(interface: Arc<Interface>) -> Result<(), super::Error> {
+    let mut client = CLIENT.lock();
+
+    if client.is_some() {
+        return Err(super::Error::AddressInUse);
+    }
+
+    *client = Some(Client {
+        interface,
+        socket: UdpSocket::bind(CLIENT_PORT)?,
+        state: State::Init,
+        xid: 0,
+        offered: None,
+        retransmit_at: 0,
+        retransmit_interval: INITIAL_RETRANSMIT_NS,
+        requests: 0,
+        renew_at: 0,
+        rebind_at: 0,
+        expire_at: 0,
+    });
+
+    Ok(())
+}
+
+pub fn state() -> Option<State> {
+    CLIENT.lock().as_ref().map(|client| client.state)
+}
+
+/// Drive the state machine, called from `net::poll`
+pub fn poll() {
+    // Whoever holds the client is already driving it
+    if let Some(mut client) = CLIENT.try_lock()
+        && let Some(client) = client.as_mut()
+    {
+        client.poll();
+    }
+}
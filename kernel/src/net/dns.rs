@@ -0,0 +1,246 @@
+//! A stub resolver that asks the configured name servers over UDP
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{Error, ipv4::Ipv4Address, udp::UdpSocket};
+use crate::{
+    rand,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+const SERVER_PORT: u16 = 53;
+
+const HEADER_SIZE: usize = 12;
+/// Replies over UDP are never larger without EDNS
+const MAX_MESSAGE_SIZE: usize = 512;
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE_MASK: u16 = 0xF;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const CLASS_IN: u16 = 1;
+
+const TIMEOUT_NS: u64 = 2 * NANOSECONDS_PER_SECOND;
+/// Queries sent to each server before trying the next one
+const ATTEMPTS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A = 1,
+    Aaaa = 28,
+}
+
+/// There is no IPv6 in the stack, AAAA answers are only handed back as bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    V4(Ipv4Address),
+    V6([u8; 16]),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::V4(address) => write!(f, "{address}"),
+            Address::V6(address) => {
+                for (i, group) in address.as_chunks::<2>().0.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ":")?;
+                    }
+
+                    write!(f, "{:x}", u16::from_be_bytes(*group))?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+fn build_query(id: u16, name: &str, ty: RecordType) -> Option<Vec<u8>> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return None;
+    }
+
+    let mut query = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return None;
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+
+    query.push(0);
+    query.extend_from_slice(&(ty as u16).to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Some(query)
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        message.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+/// Return the offset right after the name at `offset`, a compression pointer ends a name
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xC0 == 0xC0 => return Some(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+/// Parse a reply to the query with `id`, `None` means it is not one
+fn parse_reply(message: &[u8], id: u16, ty: RecordType) -> Option<Result<Vec<Address>, Error>> {
+    if read_u16(message, 0)? != id {
+        return None;
+    }
+
+    let flags = read_u16(message, 2)?;
+
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Some(Err(Error::NameNotFound)),
+        _ => return Some(Err(Error::NameServerFailure)),
+    }
+
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut offset = HEADER_SIZE;
+
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+
+    // Follows CNAME chains for free, the server puts the records of the target in the answers
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+
+        let record_type = read_u16(message, offset)?;
+        let class = read_u16(message, offset + 2)?;
+        let len = read_u16(message, offset + 8)? as usize;
+        let data = message.get(offset + 10..offset + 10 + len)?;
+
+        offset += 10 + len;
+
+        if class != CLASS_IN || record_type != ty as u16 {
+            continue;
+        }
+
+        match ty {
+            RecordType::A if len == 4 => {
+                addresses.push(Address::V4(Ipv4Address(data.try_into().unwrap())))
+            }
+            RecordType::Aaaa if len == 16 => addresses.push(Address::V6(data.try_into().unwrap())),
+            _ => {}
+        }
+    }
+
+    if addresses.is_empty() {
+        return Some(Err(Error::NameNotFound));
+    }
+
+    Some(Ok(addresses))
+}
+
+fn query_server(
+    socket: &mut UdpSocket,
+    server: Ipv4Address,
+    name: &str,
+    ty: RecordType,
+) -> Result<Vec<Address>, Error> {
+    let id = rand::u64() as u16;
+    let query = build_query(id, name, ty).ok_or(Error::InvalidName)?;
+
+    socket.send_to(&query, server, SERVER_PORT)?;
+
+    let deadline = time::monotonic_ns() + TIMEOUT_NS;
+    let mut buffer = [0; MAX_MESSAGE_SIZE];
+
+    loop {
+        let now = time::monotonic_ns();
+
+        if now >= deadline {
+            return Err(Error::TimedOut);
+        }
+
+        socket.set_read_timeout(Some(deadline - now));
+
+        let (len, source, port) = socket.recv_from(&mut buffer)?;
+
+        if source == server
+            && port == SERVER_PORT
+            && let Some(result) = parse_reply(&buffer[..len], id, ty)
+        {
+            return result;
+        }
+    }
+}
+
+/// Look up the records of `name`, asking each configured server in turn
+pub fn query(name: &str, ty: RecordType) -> Result<Vec<Address>, Error> {
+    let servers = super::dns_servers();
+
+    if servers.is_empty() {
+        return Err(Error::NoNameServer);
+    }
+
+    let mut socket = UdpSocket::bind(0)?;
+
+    let mut last_error = Error::TimedOut;
+
+    for server in servers {
+        for _ in 0..ATTEMPTS {
+            match query_server(&mut socket, server, name, ty) {
+                Err(Error::TimedOut) => last_error = Error::TimedOut,
+                // Another server would not know the name either
+                Err(Error::NameNotFound) => return Err(Error::NameNotFound),
+                Err(error) => {
+                    last_error = error;
+                    break;
+                }
+                Ok(addresses) => return Ok(addresses),
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Resolve a name, or parse it if it is already an address, to the first IPv4 address it has
+pub fn gethostbyname(name: &str) -> Result<Ipv4Address, Error> {
+    if let Some(address) = Ipv4Address::parse(name) {
+        return Ok(address);
+    }
+
+    match query(name, RecordType::A)?.first() {
+        Some(Address::V4(address)) => Ok(*address),
+        _ => Err(Error::NameNotFound),
+    }
+}
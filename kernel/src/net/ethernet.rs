@@ -0,0 +1,87 @@
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
+
+use super::{Error, Interface, MacAddress, PacketBuffer, arp, ipv4};
+
+pub const HEADER_SIZE: usize = 14;
+/// Frames shorter than this, without the frame check sequence, must be padded
+pub const MINIMUM_FRAME_SIZE: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data: &[u8; HEADER_SIZE] = data.get(..HEADER_SIZE)?.try_into().ok()?;
+
+        Some(Self {
+            destination: MacAddress(data[0..6].try_into().unwrap()),
+            source: MacAddress(data[6..12].try_into().unwrap()),
+            ethertype: u16::from_be_bytes([data[12], data[13]]),
+        })
+    }
+
+    pub fn write(&self, data: &mut [u8]) {
+        data[0..6].copy_from_slice(&self.destination.0);
+        data[6..12].copy_from_slice(&self.source.0);
+        data[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
+
+pub fn receive(interface: &Arc<Interface>, mut packet: PacketBuffer) {
+    let Some(header) = Header::parse(packet.data()) else {
+        interface
+            .statistics
+            .rx_dropped
+            .fetch_add(1, Ordering::Relaxed);
+
+        return;
+    };
+
+    if header.destination != interface.mac() && !header.destination.is_multicast() {
+        return;
+    }
+
+    packet.pull_header(HEADER_SIZE);
+
+    match header.ethertype {
+        ETHERTYPE_ARP => arp::receive(interface, packet),
+        ETHERTYPE_IPV4 => ipv4::receive(interface, packet),
+        _ => {
+            interface
+                .statistics
+                .rx_dropped
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Put an ethernet header in front of the packet and send it
+pub fn transmit(
+    interface: &Interface,
+    destination: MacAddress,
+    ethertype: u16,
+    mut packet: PacketBuffer,
+) -> Result<(), Error> {
+    Header {
+        destination,
+        source: interface.mac(),
+        ethertype,
+    }
+    .write(packet.push_header(HEADER_SIZE));
+
+    if packet.len() < MINIMUM_FRAME_SIZE {
+        let padding = MINIMUM_FRAME_SIZE - packet.len();
+
+        packet.extend_from_slice(&[0; MINIMUM_FRAME_SIZE][..padding]);
+    }
+
+    interface.transmit(&packet)
+}
@@ -0,0 +1,178 @@
+//! The internet control message protocol, echo and the errors the IPv4 layer reports
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{
+    Error, Interface, PacketBuffer,
+    buffer::DEFAULT_HEADROOM,
+    checksum::checksum,
+    ipv4::{
+        self, HEADER_SIZE as IPV4_HEADER_SIZE, Header as Ipv4Header, Ipv4Address, PROTOCOL_ICMP,
+    },
+};
+use crate::{sync::Mutex, time, wait};
+
+const HEADER_SIZE: usize = 8;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_TTL_EXCEEDED: u8 = 0;
+
+/// The payload sent in echo requests, the size `ping` uses by default
+const ECHO_PAYLOAD_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoReply {
+    pub from: Ipv4Address,
+    pub identifier: u16,
+    pub sequence: u16,
+    pub ttl: u8,
+    pub len: usize,
+    pub received_at: u64,
+}
+
+/// Replies not yet claimed by a `ping`, bounded so unsolicited replies can not pile up
+static REPLIES: Mutex<Vec<EchoReply>> = Mutex::new(Vec::new());
+
+const MAX_REPLIES: usize = 16;
+
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+fn build(kind: u8, code: u8, rest: [u8; 4], payload: &[u8]) -> PacketBuffer {
+    let mut packet = PacketBuffer::new(DEFAULT_HEADROOM, HEADER_SIZE);
+    packet.extend_from_slice(payload);
+
+    let data = packet.data_mut();
+    data[0] = kind;
+    data[1] = code;
+    data[4..8].copy_from_slice(&rest);
+
+    let checksum = checksum(data);
+    data[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+pub fn receive(_interface: &Arc<Interface>, header: &Ipv4Header, packet: PacketBuffer) {
+    let data = packet.data();
+
+    if data.len() < HEADER_SIZE || checksum(data) != 0 {
+        return;
+    }
+
+    let rest: [u8; 4] = data[4..8].try_into().unwrap();
+
+    match data[0] {
+        TYPE_ECHO_REQUEST => {
+            // Nobody answers pings sent to a broadcast address, that would flood the sender
+            if header.destination.is_broadcast() || header.destination.is_multicast() {
+                return;
+            }
+
+            let reply = build(TYPE_ECHO_REPLY, 0, rest, &data[HEADER_SIZE..]);
+            let _ = ipv4::send(header.source, PROTOCOL_ICMP, reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let mut replies = REPLIES.lock();
+
+            if replies.len() >= MAX_REPLIES {
+                replies.remove(0);
+            }
+
+            replies.push(EchoReply {
+                from: header.source,
+                identifier: u16::from_be_bytes([rest[0], rest[1]]),
+                sequence: u16::from_be_bytes([rest[2], rest[3]]),
+                ttl: header.ttl,
+                len: data.len() - HEADER_SIZE,
+                received_at: time::monotonic_ns(),
+            });
+        }
+        _ => {}
+    }
+}
+
+/// Tell the sender of a packet why it could not be delivered, `payload` is what followed its
+/// header
+pub fn send_error(kind: u8, code: u8, header: &Ipv4Header, payload: &[u8]) {
+    // Errors are never sent about errors, or about anything that was not for a single host
+    if header.protocol == PROTOCOL_ICMP
+        || header.fragment_offset != 0
+        || header.destination.is_broadcast()
+        || header.destination.is_multicast()
+        || header.source.is_unspecified()
+    {
+        return;
+    }
+
+    // The original header and the first 8 bytes of its payload, enough to find the connection
+    let mut quoted = [0; IPV4_HEADER_SIZE + 8];
+    let payload = &payload[..payload.len().min(8)];
+
+    Ipv4Header {
+        header_len: IPV4_HEADER_SIZE,
+        ..*header
+    }
+    .write(&mut quoted);
+    quoted[IPV4_HEADER_SIZE..IPV4_HEADER_SIZE + payload.len()].copy_from_slice(payload);
+
+    let packet = build(
+        kind,
+        code,
+        [0; 4],
+        &quoted[..IPV4_HEADER_SIZE + payload.len()],
+    );
+
+    let _ = ipv4::send(header.source, PROTOCOL_ICMP, packet);
+}
+
+pub fn send_echo_request(
+    destination: Ipv4Address,
+    identifier: u16,
+    sequence: u16,
+) -> Result<(), Error> {
+    let mut payload = [0; ECHO_PAYLOAD_SIZE];
+
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let mut rest = [0; 4];
+    rest[0..2].copy_from_slice(&identifier.to_be_bytes());
+    rest[2..4].copy_from_slice(&sequence.to_be_bytes());
+
+    ipv4::send(
+        destination,
+        PROTOCOL_ICMP,
+        build(TYPE_ECHO_REQUEST, 0, rest, &payload),
+    )
+}
+
+/// Send an echo request and wait for its reply, returning it and the round trip time
+pub fn ping(
+    destination: Ipv4Address,
+    sequence: u16,
+    timeout_ns: u64,
+) -> Result<Option<(EchoReply, u64)>, Error> {
+    let identifier = NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    let sent_at = time::monotonic_ns();
+
+    send_echo_request(destination, identifier, sequence)?;
+
+    let reply = wait::until(Some(sent_at + timeout_ns), || {
+        let mut replies = REPLIES.lock();
+        let index = replies
+            .iter()
+            .position(|reply| reply.identifier == identifier && reply.sequence == sequence)?;
+
+        Some(replies.remove(index))
+    });
+
+    Ok(reply.map(|reply| (reply, reply.received_at - sent_at)))
+}
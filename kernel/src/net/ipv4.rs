@@ -0,0 +1,569 @@
+//! The internet protocol, version 4
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+};
+
+use super::{
+    Error, Interface, PacketBuffer, arp,
+    buffer::DEFAULT_HEADROOM,
+    checksum::checksum,
+    icmp::{
+        self, CODE_PROTOCOL_UNREACHABLE, CODE_TTL_EXCEEDED, TYPE_DESTINATION_UNREACHABLE,
+        TYPE_TIME_EXCEEDED,
+    },
+    route::{self, Route},
+    tcp, udp,
+};
+use crate::{
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+pub const HEADER_SIZE: usize = 20;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const DEFAULT_TTL: u8 = 64;
+
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+/// The largest datagram that is reassembled
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// How long the fragments of a datagram are kept waiting for the rest
+const REASSEMBLY_TIMEOUT_NS: u64 = 30 * NANOSECONDS_PER_SECOND;
+/// How many datagrams can be reassembled at the same time
+const MAX_REASSEMBLIES: usize = 16;
+/// How many fragments a datagram can arrive in, more would hold on to the heap until it expires
+const MAX_FRAGMENTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+    pub const BROADCAST: Self = Self([0xFF; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    pub const fn from_u32(value: u32) -> Self {
+        Self(value.to_be_bytes())
+    }
+
+    pub const fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.0[0] == 127
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xF0 == 0xE0
+    }
+
+    /// Parse dotted decimal notation like `10.0.2.15`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut octets = [0; 4];
+        let mut parts = text.split('.');
+
+        for octet in &mut octets {
+            *octet = parts.next()?.parse().ok()?;
+        }
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self(octets))
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+/// The address of an interface and the size of the network it is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Address,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Config {
+    pub fn netmask(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(
+            u32::MAX
+                .checked_shl(32 - self.prefix_len as u32)
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn network(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() & self.netmask().to_u32())
+    }
+
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() | !self.netmask().to_u32())
+    }
+
+    /// Whether `address` is on the network, so it can be reached without a gateway
+    pub fn contains(&self, address: Ipv4Address) -> bool {
+        address.to_u32() & self.netmask().to_u32() == self.network().to_u32()
+    }
+}
+
+impl fmt::Display for Ipv4Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub tos: u8,
+    pub total_len: u16,
+    pub identification: u16,
+    pub dont_fragment: bool,
+    pub more_fragments: bool,
+    /// In bytes, always a multiple of 8
+    pub fragment_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    /// The length of the header with its options
+    pub header_len: usize,
+}
+
+impl Header {
+    /// Parse and validate the header, including its checksum
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let fixed = data.get(..HEADER_SIZE)?;
+
+        let version = fixed[0] >> 4;
+        let header_len = (fixed[0] & 0xF) as usize * 4;
+
+        if version != 4 || header_len < HEADER_SIZE || data.len() < header_len {
+            return None;
+        }
+
+        if checksum(&data[..header_len]) != 0 {
+            return None;
+        }
+
+        let total_len = u16::from_be_bytes([fixed[2], fixed[3]]);
+
+        if (total_len as usize) < header_len || total_len as usize > data.len() {
+            return None;
+        }
+
+        let flags = u16::from_be_bytes([fixed[6], fixed[7]]);
+
+        Some(Self {
+            tos: fixed[1],
+            total_len,
+            identification: u16::from_be_bytes([fixed[4], fixed[5]]),
+            dont_fragment: flags & FLAG_DONT_FRAGMENT != 0,
+            more_fragments: flags & FLAG_MORE_FRAGMENTS != 0,
+            fragment_offset: (flags & FRAGMENT_OFFSET_MASK) * 8,
+            ttl: fixed[8],
+            protocol: fixed[9],
+            source: Ipv4Address(fixed[12..16].try_into().unwrap()),
+            destination: Ipv4Address(fixed[16..20].try_into().unwrap()),
+            header_len,
+        })
+    }
+
+    /// Write the header without options and fill in its checksum
+    pub fn write(&self, data: &mut [u8]) {
+        let mut flags = self.fragment_offset / 8;
+
+        if self.dont_fragment {
+            flags |= FLAG_DONT_FRAGMENT;
+        }
+
+        if self.more_fragments {
+            flags |= FLAG_MORE_FRAGMENTS;
+        }
+
+        data[0] = 0x45;
+        data[1] = self.tos;
+        data[2..4].copy_from_slice(&self.total_len.to_be_bytes());
+        data[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        data[6..8].copy_from_slice(&flags.to_be_bytes());
+        data[8] = self.ttl;
+        data[9] = self.protocol;
+        data[10..12].fill(0);
+        data[12..16].copy_from_slice(&self.source.0);
+        data[16..20].copy_from_slice(&self.destination.0);
+
+        let checksum = checksum(&data[..HEADER_SIZE]);
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    pub fn payload_len(&self) -> usize {
+        self.total_len as usize - self.header_len
+    }
+}
+
+/// Whether packets for other hosts are routed instead of dropped
+static FORWARDING: AtomicBool = AtomicBool::new(false);
+
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+pub fn set_forwarding(enabled: bool) {
+    FORWARDING.store(enabled, Ordering::Release);
+}
+
+fn is_for_us(interface: &Interface, destination: Ipv4Address) -> bool {
+    if destination.is_broadcast() || destination.is_multicast() {
+        return true;
+    }
+
+    // Packets to the other addresses of the host are looped back too
+    if interface.device.is_loopback() {
+        return super::interfaces().iter().any(|interface| {
+            interface.ipv4().is_some_and(|config| {
+                if interface.device.is_loopback() {
+                    config.contains(destination)
+                } else {
+                    config.address == destination
+                }
+            })
+        });
+    }
+
+    match interface.ipv4() {
+        Some(config) => destination == config.address || destination == config.broadcast(),
+        // Before it has an address the interface takes anything, like DHCP offers
+        None => true,
+    }
+}
+
+pub fn receive(interface: &Arc<Interface>, mut packet: PacketBuffer) {
+    let Some(header) = Header::parse(packet.data()) else {
+        interface
+            .statistics
+            .rx_dropped
+            .fetch_add(1, Ordering::Relaxed);
+
+        return;
+    };
+
+    // Ethernet pads short frames
+    packet.truncate(header.total_len as usize);
+
+    if !is_for_us(interface, header.destination) {
+        forward(header, packet);
+
+        return;
+    }
+
+    packet.pull_header(header.header_len);
+
+    let packet = if header.more_fragments || header.fragment_offset != 0 {
+        match reassemble(&header, packet) {
+            Some(packet) => packet,
+            None => return,
+        }
+    } else {
+        packet
+    };
+
+    deliver(interface, &header, packet);
+}
+
+fn deliver(interface: &Arc<Interface>, header: &Header, packet: PacketBuffer) {
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::receive(interface, header, packet),
+        PROTOCOL_TCP => tcp::receive(interface, header, packet),
+        PROTOCOL_UDP => udp::receive(interface, header, packet),
+        _ => {
+            interface
+                .statistics
+                .rx_dropped
+                .fetch_add(1, Ordering::Relaxed);
+
+            if !header.destination.is_broadcast() && !header.destination.is_multicast() {
+                icmp::send_error(
+                    TYPE_DESTINATION_UNREACHABLE,
+                    CODE_PROTOCOL_UNREACHABLE,
+                    header,
+                    packet.data(),
+                );
+            }
+        }
+    }
+}
+
+fn forward(mut header: Header, mut packet: PacketBuffer) {
+    // Loopback addresses never come from or leave through a real network
+    if !FORWARDING.load(Ordering::Acquire)
+        || header.source.is_loopback()
+        || header.destination.is_loopback()
+    {
+        return;
+    }
+
+    if header.ttl <= 1 {
+        icmp::send_error(
+            TYPE_TIME_EXCEEDED,
+            CODE_TTL_EXCEEDED,
+            &header,
+            &packet.data()[header.header_len..],
+        );
+
+        return;
+    }
+
+    let Some(route) = route::lookup(header.destination) else {
+        return;
+    };
+
+    let Some(interface) = super::interface(route.interface) else {
+        return;
+    };
+
+    header.ttl -= 1;
+
+    // Options are dropped, so the header is rewritten without them
+    let payload_len = header.payload_len();
+    packet.pull_header(header.header_len);
+    header.header_len = HEADER_SIZE;
+    header.total_len = (HEADER_SIZE + payload_len) as u16;
+
+    let _ = output(&interface, &route, header, packet);
+}
+
+struct Reassembly {
+    key: (Ipv4Address, Ipv4Address, u8, u16),
+    started_at: u64,
+    /// The offsets and contents of the fragments received so far
+    fragments: Vec<(usize, PacketBuffer)>,
+    /// Known once the last fragment arrived
+    total_len: Option<usize>,
+}
+
+static REASSEMBLIES: Mutex<Vec<Reassembly>> = Mutex::new(Vec::new());
+
+/// Collect a fragment and return the whole payload once every fragment arrived
+fn reassemble(header: &Header, packet: PacketBuffer) -> Option<PacketBuffer> {
+    let key = (
+        header.source,
+        header.destination,
+        header.protocol,
+        header.identification,
+    );
+    let offset = header.fragment_offset as usize;
+
+    if offset + packet.len() > MAX_DATAGRAM_SIZE {
+        return None;
+    }
+
+    let mut reassemblies = REASSEMBLIES.lock();
+
+    let index = match reassemblies
+        .iter()
+        .position(|reassembly| reassembly.key == key)
+    {
+        Some(index) => index,
+        None => {
+            if reassemblies.len() >= MAX_REASSEMBLIES {
+                reassemblies.remove(0);
+            }
+
+            reassemblies.push(Reassembly {
+                key,
+                started_at: time::monotonic_ns(),
+                fragments: Vec::new(),
+                total_len: None,
+            });
+
+            reassemblies.len() - 1
+        }
+    };
+
+    let reassembly = &mut reassemblies[index];
+    let end = offset + packet.len();
+
+    if !header.more_fragments {
+        reassembly.total_len = Some(end);
+    }
+
+    // A datagram whose fragments go past where its last one ends is malformed, or forged to
+    // overlap, so it is dropped along with what was received of it
+    if let Some(total_len) = reassembly.total_len
+        && (end > total_len
+            || reassembly
+                .fragments
+                .iter()
+                .any(|(offset, fragment)| offset + fragment.len() > total_len))
+    {
+        reassemblies.remove(index);
+        return None;
+    }
+
+    if !reassembly
+        .fragments
+        .iter()
+        .any(|(existing, _)| *existing == offset)
+    {
+        if reassembly.fragments.len() >= MAX_FRAGMENTS {
+            reassemblies.remove(index);
+            return None;
+        }
+
+        reassembly.fragments.push((offset, packet));
+    }
+
+    let total_len = reassembly.total_len?;
+
+    reassembly.fragments.sort_by_key(|(offset, _)| *offset);
+
+    // Every byte must be covered before the datagram is complete
+    let mut covered = 0;
+
+    for (offset, fragment) in &reassembly.fragments {
+        if *offset > covered {
+            return None;
+        }
+
+        covered = covered.max(offset + fragment.len());
+    }
+
+    if covered < total_len {
+        return None;
+    }
+
+    let reassembly = reassemblies.remove(index);
+
+    let mut payload = PacketBuffer::new(DEFAULT_HEADROOM, total_len);
+
+    // None of them go past the end, which was checked as they arrived
+    for (offset, fragment) in reassembly.fragments {
+        payload.data_mut()[offset..offset + fragment.len()].copy_from_slice(fragment.data());
+    }
+
+    Some(payload)
+}
+
+/// Drop the datagrams whose fragments did not all arrive in time
+pub fn expire() {
+    let now = time::monotonic_ns();
+
+    REASSEMBLIES
+        .lock()
+        .retain(|reassembly| now - reassembly.started_at < REASSEMBLY_TIMEOUT_NS);
+}
+
+/// Send the packet on the route, fragmenting it if it does not fit the interface
+fn output(
+    interface: &Interface,
+    route: &Route,
+    header: Header,
+    packet: PacketBuffer,
+) -> Result<(), Error> {
+    let next_hop = route.next_hop(header.destination);
+
+    if HEADER_SIZE + packet.len() <= interface.mtu() {
+        let mut packet = packet;
+
+        header.write(packet.push_header(HEADER_SIZE));
+        arp::transmit_ipv4(interface, next_hop, packet);
+
+        return Ok(());
+    }
+
+    if header.dont_fragment {
+        return Err(Error::TooLarge);
+    }
+
+    // Every fragment but the last carries a multiple of 8 bytes
+    let chunk = (interface.mtu() - HEADER_SIZE) & !7;
+    let data = packet.data();
+
+    for (i, part) in data.chunks(chunk).enumerate() {
+        let offset = header.fragment_offset as usize + i * chunk;
+        let last = (i + 1) * chunk >= data.len();
+
+        let mut fragment = PacketBuffer::new(DEFAULT_HEADROOM, 0);
+        fragment.extend_from_slice(part);
+
+        Header {
+            total_len: (HEADER_SIZE + part.len()) as u16,
+            more_fragments: !last || header.more_fragments,
+            fragment_offset: offset as u16,
+            ..header
+        }
+        .write(fragment.push_header(HEADER_SIZE));
+
+        arp::transmit_ipv4(interface, next_hop, fragment);
+    }
+
+    Ok(())
+}
+
+/// Send a packet carrying `protocol` to `destination`, from the address of the interface the route
+/// goes through
+pub fn send(destination: Ipv4Address, protocol: u8, packet: PacketBuffer) -> Result<(), Error> {
+    let route = route::lookup(destination).ok_or(Error::NoRoute)?;
+    let interface = super::interface(route.interface).ok_or(Error::NoRoute)?;
+
+    let source = interface
+        .ipv4()
+        .map_or(Ipv4Address::UNSPECIFIED, |config| config.address);
+
+    send_via(&interface, &route, source, destination, protocol, packet)
+}
+
+/// Send a packet out of a chosen interface and source address, like the broadcasts of a client
+/// that has no address yet
+pub fn send_via(
+    interface: &Interface,
+    route: &Route,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    packet: PacketBuffer,
+) -> Result<(), Error> {
+    if HEADER_SIZE + packet.len() > MAX_DATAGRAM_SIZE {
+        return Err(Error::TooLarge);
+    }
+
+    let header = Header {
+        tos: 0,
+        total_len: (HEADER_SIZE + packet.len()) as u16,
+        identification: NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: 0,
+        ttl: DEFAULT_TTL,
+        protocol,
+        source,
+        destination,
+        header_len: HEADER_SIZE,
+    };
+
+    output(interface, route, header, packet)
+}
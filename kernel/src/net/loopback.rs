@@ -0,0 +1,70 @@
+//! The loopback device, which receives everything it transmits
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::{
+    Error, MacAddress, NetDevice, PacketBuffer,
+    ipv4::{Ipv4Address, Ipv4Config},
+};
+use crate::sync::Mutex;
+
+pub const ADDRESS: Ipv4Address = Ipv4Address::new(127, 0, 0, 1);
+
+const MTU: usize = 65536;
+
+/// Frames sent but not received yet, like a device ring a full queue drops new frames
+const QUEUE_LIMIT: usize = 1024;
+
+pub struct Loopback {
+    queue: Mutex<VecDeque<PacketBuffer>>,
+}
+
+impl NetDevice for Loopback {
+    fn mac(&self) -> MacAddress {
+        MacAddress::default()
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+
+    fn transmit(&self, packet: &PacketBuffer) -> Result<(), Error> {
+        let mut queue = self.queue.lock();
+
+        if queue.len() >= QUEUE_LIMIT {
+            return Err(Error::Busy);
+        }
+
+        queue.push_back(packet.clone());
+
+        Ok(())
+    }
+
+    fn poll(&self, rx: &mut dyn FnMut(PacketBuffer)) {
+        // Frames looped back while handing these out wait for the next poll
+        let pending = core::mem::take(&mut *self.queue.lock());
+
+        for packet in pending {
+            rx(packet);
+        }
+    }
+}
+
+/// Register `lo` with 127.0.0.1/8
+pub fn init() {
+    let interface = super::register_named(
+        "lo".into(),
+        Arc::new(Loopback {
+            queue: Mutex::new(VecDeque::new()),
+        }),
+    );
+
+    interface.set_ipv4(Some(Ipv4Config {
+        address: ADDRESS,
+        prefix_len: 8,
+    }));
+}
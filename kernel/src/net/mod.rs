@@ -0,0 +1,484 @@
+//! The network stack
+
+pub mod arp;
+pub mod buffer;
+pub mod capture;
+pub mod checksum;
+pub mod dhcp;
+pub mod dns;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod loopback;
+pub mod netconsole;
+pub mod route;
+pub mod tcp;
+pub mod udp;
+
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    cmdline,
+    softirq::{self, Softirq},
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+    wait,
+};
+
+pub use buffer::PacketBuffer;
+use ipv4::{Ipv4Address, Ipv4Config};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+
+    /// Whether the address is for a group, which includes broadcast
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The frame is larger than the device can send
+    TooLarge,
+    /// The device has no room for another frame right now
+    Busy,
+    Down,
+    /// There is no route to the destination
+    NoRoute,
+    AddressInUse,
+    /// The socket is nonblocking and nothing is ready
+    WouldBlock,
+    TimedOut,
+    ConnectionRefused,
+    ConnectionReset,
+    NotConnected,
+    /// The name can not be put in a DNS query
+    InvalidName,
+    NameNotFound,
+    NameServerFailure,
+    NoNameServer,
+}
+
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> MacAddress;
+
+    /// The largest payload of an ethernet frame the device takes
+    fn mtu(&self) -> usize;
+
+    /// Loopback devices need no neighbor resolution
+    fn is_loopback(&self) -> bool {
+        false
+    }
+
+    /// Send a whole ethernet frame
+    fn transmit(&self, packet: &PacketBuffer) -> Result<(), Error>;
+
+    /// Hand every frame received since the last poll to `rx`, which must not call back into the
+    /// device
+    fn poll(&self, rx: &mut dyn FnMut(PacketBuffer));
+}
+
+#[derive(Debug, Default)]
+pub struct Statistics {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub rx_dropped: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_errors: AtomicU64,
+}
+
+pub struct Interface {
+    pub index: usize,
+    pub name: String,
+    pub device: Arc<dyn NetDevice>,
+    pub statistics: Statistics,
+    up: AtomicBool,
+    ipv4: Mutex<Option<Ipv4Config>>,
+}
+
+impl Interface {
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Acquire)
+    }
+
+    pub fn set_up(&self, up: bool) {
+        let was_up = self.up.swap(up, Ordering::AcqRel);
+
+        if up && !was_up {
+            crate::log_info!("net", "{} is up", self.name);
+
+            arp::announce(self);
+        }
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4Config> {
+        *self.ipv4.lock()
+    }
+
+    pub fn set_ipv4(&self, config: Option<Ipv4Config>) {
+        *self.ipv4.lock() = config;
+
+        route::set_connected(self.index, config);
+
+        if let Some(config) = config {
+            crate::log_info!("net", "{} has address {}", self.name, config);
+
+            if self.is_up() {
+                arp::announce(self);
+            }
+        }
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.device.mac()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+
+    pub fn transmit(&self, packet: &PacketBuffer) -> Result<(), Error> {
+        if !self.is_up() {
+            self.statistics.tx_errors.fetch_add(1, Ordering::Relaxed);
+
+            return Err(Error::Down);
+        }
+
+        let result = self.device.transmit(packet);
+
+        if result.is_ok() {
+            capture::tap(packet.data());
+
+            self.statistics.tx_packets.fetch_add(1, Ordering::Relaxed);
+            self.statistics
+                .tx_bytes
+                .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        } else {
+            self.statistics.tx_errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+
+/// Frames received but not processed yet, a full queue drops new frames
+const RX_QUEUE_LIMIT: usize = 1024;
+
+static RX_QUEUE: Mutex<VecDeque<(Arc<Interface>, PacketBuffer)>> = Mutex::new(VecDeque::new());
+
+/// Add a device to the stack as the next `ethN` interface
+pub fn register(device: Arc<dyn NetDevice>) -> Arc<Interface> {
+    let ethernet_devices = INTERFACES
+        .lock()
+        .iter()
+        .filter(|interface| !interface.device.is_loopback())
+        .count();
+
+    register_named(format!("eth{ethernet_devices}"), device)
+}
+
+pub fn register_named(name: String, device: Arc<dyn NetDevice>) -> Arc<Interface> {
+    let mut interfaces = INTERFACES.lock();
+
+    let interface = Arc::new(Interface {
+        index: interfaces.len(),
+        name,
+        device,
+        statistics: Statistics::default(),
+        up: AtomicBool::new(false),
+        ipv4: Mutex::new(None),
+    });
+
+    crate::log_ok!(
+        "net",
+        "{} registered with mac {} and mtu {}",
+        interface.name,
+        interface.mac(),
+        interface.mtu()
+    );
+
+    interfaces.push(interface.clone());
+
+    drop(interfaces);
+
+    interface.set_up(true);
+
+    interface
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+pub fn interface(index: usize) -> Option<Arc<Interface>> {
+    INTERFACES.lock().get(index).cloned()
+}
+
+pub fn find(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .cloned()
+}
+
+static DNS_SERVERS: Mutex<Vec<Ipv4Address>> = Mutex::new(Vec::new());
+
+/// The name servers to resolve with, learned from DHCP or given with `dns=`
+pub fn dns_servers() -> Vec<Ipv4Address> {
+    DNS_SERVERS.lock().clone()
+}
+
+pub fn set_dns_servers(servers: Vec<Ipv4Address>) {
+    *DNS_SERVERS.lock() = servers;
+}
+
+/// Queue a received frame for processing outside of the driver
+pub fn receive(interface: &Arc<Interface>, packet: PacketBuffer) {
+    let statistics = &interface.statistics;
+
+    let mut queue = RX_QUEUE.lock();
+
+    if queue.len() >= RX_QUEUE_LIMIT {
+        statistics.rx_dropped.fetch_add(1, Ordering::Relaxed);
+
+        return;
+    }
+
+    capture::tap(packet.data());
+
+    statistics.rx_packets.fetch_add(1, Ordering::Relaxed);
+    statistics
+        .rx_bytes
+        .fetch_add(packet.len() as u64, Ordering::Relaxed);
+
+    queue.push_back((interface.clone(), packet));
+
+    softirq::raise(Softirq::NetRx);
+}
+
+/// How often the periodic work of the stack runs
+const TICK_NS: u64 = NANOSECONDS_PER_SECOND;
+
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Collect the received frames of every interface, and run the periodic work when it is due
+pub fn poll() {
+    for interface in interfaces() {
+        if interface.is_up() {
+            interface
+                .device
+                .poll(&mut |packet| receive(&interface, packet));
+        }
+    }
+
+    let now = time::monotonic_ns();
+    let last = LAST_TICK.load(Ordering::Relaxed);
+
+    if now - last >= TICK_NS
+        && LAST_TICK
+            .compare_exchange(last, now, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    {
+        arp::expire();
+        ipv4::expire();
+    }
+
+    dhcp::poll();
+    netconsole::flush();
+}
+
+fn process_rx() {
+    loop {
+        let Some((interface, packet)) = RX_QUEUE.lock().pop_front() else {
+            break;
+        };
+
+        ethernet::receive(&interface, packet);
+    }
+}
+
+pub fn init() {
+    softirq::register(Softirq::NetRx, process_rx);
+
+    capture::init();
+    loopback::init();
+}
+
+/// Apply `ip=<address>/<prefix>[,<gateway>]` from the command line to eth0
+pub fn configure() {
+    if let Some(value) = cmdline::value("dns") {
+        match value.split(',').map(Ipv4Address::parse).collect() {
+            Some(servers) => set_dns_servers(servers),
+            None => crate::log_warn!("net", "could not parse dns={value}"),
+        }
+    }
+
+    if cmdline::has("dhcp") {
+        configure_dhcp();
+        return;
+    }
+
+    let Some(value) = cmdline::value("ip") else {
+        return;
+    };
+
+    let Some(interface) = find("eth0") else {
+        crate::log_warn!("net", "ignoring ip={value}, there is no interface");
+        return;
+    };
+
+    let (config, gateway) = value.split_once(',').unwrap_or((value, ""));
+
+    let config = config.split_once('/').and_then(|(address, prefix_len)| {
+        Some(Ipv4Config {
+            address: Ipv4Address::parse(address)?,
+            prefix_len: prefix_len.parse().ok().filter(|&len| len <= 32)?,
+        })
+    });
+
+    let Some(config) = config else {
+        crate::log_warn!("net", "could not parse ip={value}");
+        return;
+    };
+
+    interface.set_ipv4(Some(config));
+
+    if let Some(gateway) = Ipv4Address::parse(gateway) {
+        route::set_default_gateway(interface.index, gateway);
+    }
+}
+
+/// How long boot waits for a lease before going on without an address
+const DHCP_TIMEOUT_NS: u64 = 10 * NANOSECONDS_PER_SECOND;
+
+fn configure_dhcp() {
+    let Some(interface) = find("eth0") else {
+        crate::log_warn!("net", "ignoring dhcp, there is no interface");
+        return;
+    };
+
+    if let Err(error) = dhcp::start(interface.clone()) {
+        crate::log_error!("net", "could not start dhcp: {error:?}");
+        return;
+    }
+
+    let deadline = time::monotonic_ns() + DHCP_TIMEOUT_NS;
+
+    if wait::until(Some(deadline), || {
+        (dhcp::state() == Some(dhcp::State::Bound)).then_some(())
+    })
+    .is_none()
+    {
+        crate::log_warn!(
+            "net",
+            "no dhcp lease for {} yet, continuing",
+            interface.name
+        );
+    }
+}
+
+/// Ping the address given by `ping=<address>` on the command line a few times, to check the
+/// network works right after boot
+pub fn ping_from_cmdline() {
+    let Some(destination) = cmdline::value("ping") else {
+        return;
+    };
+
+    let destination = match dns::gethostbyname(destination) {
+        Ok(address) => address,
+        Err(error) => {
+            crate::log_error!("net", "could not resolve ping={destination}: {error:?}");
+            return;
+        }
+    };
+
+    for sequence in 0..4 {
+        match icmp::ping(destination, sequence, NANOSECONDS_PER_SECOND) {
+            Ok(Some((reply, rtt_ns))) => crate::log_info!(
+                "ping",
+                "{} bytes from {}: icmp_seq={} ttl={} time={}.{:03} ms",
+                reply.len,
+                reply.from,
+                reply.sequence,
+                reply.ttl,
+                rtt_ns / 1_000_000,
+                rtt_ns / 1000 % 1000
+            ),
+            Ok(None) => crate::log_warn!(
+                "ping",
+                "no reply from {destination} for icmp_seq={sequence}"
+            ),
+            Err(error) => crate::log_error!("ping", "could not ping {destination}: {error:?}"),
+        }
+    }
+}
+
+/// Print the interfaces, the routing table and the neighbors like `ip addr`, `ip route` and
+/// `ip neigh` would
+pub fn dump() {
+    for interface in interfaces() {
+        let statistics = &interface.statistics;
+
+        println!(
+            "{}: {} mac {} mtu {}",
+            interface.name,
+            if interface.is_up() { "up" } else { "down" },
+            interface.mac(),
+            interface.mtu()
+        );
+
+        if let Some(config) = interface.ipv4() {
+            println!("    inet {}", config);
+        }
+
+        println!(
+            "    rx {} packets {} bytes {} dropped, tx {} packets {} bytes {} errors",
+            statistics.rx_packets.load(Ordering::Relaxed),
+            statistics.rx_bytes.load(Ordering::Relaxed),
+            statistics.rx_dropped.load(Ordering::Relaxed),
+            statistics.tx_packets.load(Ordering::Relaxed),
+            statistics.tx_bytes.load(Ordering::Relaxed),
+            statistics.tx_errors.load(Ordering::Relaxed)
+        );
+    }
+
+    for route in route::routes() {
+        println!("{}", route);
+    }
+
+    for server in dns_servers() {
+        println!("nameserver {}", server);
+    }
+
+    for neighbor in arp::neighbors() {
+        let name = interface(neighbor.interface).map(|interface| interface.name.clone());
+
+        println!(
+            "{} dev {} lladdr {} expires in {}s",
+            neighbor.ip,
+            name.as_deref().unwrap_or("?"),
+            neighbor.mac,
+            neighbor.expires_in_ns / NANOSECONDS_PER_SECOND
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Netconsole, which sends the kernel log to another machine as UDP datagrams
+
+use super::{arp, dns, ipv4::Ipv4Address, route, udp::UdpSocket};
+use crate::{
+    cmdline, log,
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+const LOCAL_PORT: u16 = 6665;
+const DEFAULT_PORT: u16 = 6666;
+
+/// Small enough to never be fragmented on ethernet
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// How often a message is sent while the destination is not resolved, each one is held by ARP
+const RESOLVE_INTERVAL_NS: u64 = NANOSECONDS_PER_SECOND;
+
+struct Netconsole {
+    socket: UdpSocket,
+    destination: Ipv4Address,
+    port: u16,
+    /// Where in the log the next message starts
+    position: u64,
+    /// When a message was last sent without the destination being resolved
+    unresolved_at: Option<u64>,
+}
+
+impl Netconsole {
+    /// Whether a datagram sent now goes out right away instead of waiting on ARP, which only
+    /// keeps a few packets around while resolving
+    fn ready(&self) -> bool {
+        let Some(route) = route::lookup(self.destination) else {
+            return false;
+        };
+
+        let Some(interface) = super::interface(route.interface) else {
+            return false;
+        };
+
+        let next_hop = route.next_hop(self.destination);
+
+        interface.device.is_loopback()
+            || next_hop.is_broadcast()
+            || arp::lookup(&interface, next_hop).is_some()
+    }
+}
+
+static NETCONSOLE: Mutex<Option<Netconsole>> = Mutex::new(None);
+
+/// Start sending the log to `netconsole=<host>[:<port>]` from the command line, beginning with
+/// what was logged before the network came up
+pub fn init() {
+    let Some(value) = cmdline::value("netconsole") else {
+        return;
+    };
+
+    let (host, port) = match value.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()),
+        None => (value, Some(DEFAULT_PORT)),
+    };
+
+    let (Ok(destination), Some(port)) = (dns::gethostbyname(host), port) else {
+        crate::log_warn!("netconsole", "could not parse netconsole={value}");
+        return;
+    };
+
+    let socket = match UdpSocket::bind(LOCAL_PORT) {
+        Ok(socket) => socket,
+        Err(error) => {
+            crate::log_error!("netconsole", "could not bind port {LOCAL_PORT}: {error:?}");
+            return;
+        }
+    };
+
+    crate::log_ok!("netconsole", "logging to {destination}:{port}");
+
+    *NETCONSOLE.lock() = Some(Netconsole {
+        socket,
+        destination,
+        port,
+        position: 0,
+        unresolved_at: None,
+    });
+
+    flush();
+}
+
+/// Send what was logged since the last flush, called from `net::poll`
+pub fn flush() {
+    // Sending may log, which must not come back here
+    let Some(mut netconsole) = NETCONSOLE.try_lock() else {
+        return;
+    };
+
+    let Some(netconsole) = netconsole.as_mut() else {
+        return;
+    };
+
+    let mut buffer = [0; MAX_MESSAGE_SIZE];
+
+    loop {
+        let ready = netconsole.ready();
+        let now = time::monotonic_ns();
+
+        if !ready
+            && netconsole
+                .unresolved_at
+                .is_some_and(|sent_at| now - sent_at < RESOLVE_INTERVAL_NS)
+        {
+            break;
+        }
+
+        let (start, len) = log::read(netconsole.position, &mut buffer);
+
+        // Only whole lines are sent, unless a line does not fit in a message
+        let end = match buffer[..len].iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => newline + 1,
+            None if len == MAX_MESSAGE_SIZE => len,
+            None => break,
+        };
+
+        if netconsole
+            .socket
+            .send_to(&buffer[..end], netconsole.destination, netconsole.port)
+            .is_err()
+        {
+            break;
+        }
+
+        netconsole.position = start + end as u64;
+
+        // The message starts the neighbor resolution, the rest wait for it to finish
+        netconsole.unresolved_at = (!ready).then_some(now);
+    }
+}
@@ -0,0 +1,157 @@
+//! The IPv4 routing table, picking the interface and the next hop for a destination
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::ipv4::{Ipv4Address, Ipv4Config};
+use crate::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Ipv4Address,
+    pub prefix_len: u8,
+    /// Where to send the packets, none for networks the interface is connected to
+    pub gateway: Option<Ipv4Address>,
+    pub interface: usize,
+    pub metric: u32,
+}
+
+impl Route {
+    fn netmask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn matches(&self, address: Ipv4Address) -> bool {
+        address.to_u32() & self.netmask() == self.destination.to_u32() & self.netmask()
+    }
+
+    /// The neighbor the packet must be handed to
+    pub fn next_hop(&self, destination: Ipv4Address) -> Ipv4Address {
+        self.gateway.unwrap_or(destination)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix_len == 0 {
+            write!(f, "default")?;
+        } else {
+            write!(f, "{}/{}", self.destination, self.prefix_len)?;
+        }
+
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+
+        let name = super::interface(self.interface).map(|interface| interface.name.clone());
+
+        write!(f, " dev {}", name.as_deref().unwrap_or("?"))?;
+
+        if self.gateway.is_none() {
+            write!(f, " scope link")?;
+        }
+
+        write!(f, " metric {}", self.metric)
+    }
+}
+
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+pub fn add(route: Route) {
+    let mut routes = ROUTES.lock();
+
+    routes.retain(|existing| {
+        (
+            existing.destination,
+            existing.prefix_len,
+            existing.interface,
+        ) != (route.destination, route.prefix_len, route.interface)
+    });
+
+    routes.push(route);
+}
+
+pub fn remove(destination: Ipv4Address, prefix_len: u8) {
+    ROUTES
+        .lock()
+        .retain(|route| (route.destination, route.prefix_len) != (destination, prefix_len));
+}
+
+/// Replace the route to the network of the interface after its address changed
+pub fn set_connected(interface: usize, config: Option<Ipv4Config>) {
+    ROUTES
+        .lock()
+        .retain(|route| route.interface != interface || route.gateway.is_some());
+
+    if let Some(config) = config {
+        add(Route {
+            destination: config.network(),
+            prefix_len: config.prefix_len,
+            gateway: None,
+            interface,
+            metric: 0,
+        });
+    }
+}
+
+pub fn set_default_gateway(interface: usize, gateway: Ipv4Address) {
+    add(Route {
+        destination: Ipv4Address::UNSPECIFIED,
+        prefix_len: 0,
+        gateway: Some(gateway),
+        interface,
+        metric: 100,
+    });
+}
+
+/// The route through the loopback interface to an address of this host
+fn local(destination: Ipv4Address) -> Option<Route> {
+    let interfaces = super::interfaces();
+
+    let is_local = interfaces
+        .iter()
+        .filter(|interface| !interface.device.is_loopback())
+        .any(|interface| {
+            interface
+                .ipv4()
+                .is_some_and(|config| config.address == destination)
+        });
+
+    if !is_local {
+        return None;
+    }
+
+    let loopback = interfaces
+        .iter()
+        .find(|interface| interface.device.is_loopback())?;
+
+    Some(Route {
+        destination,
+        prefix_len: 32,
+        gateway: None,
+        interface: loopback.index,
+        metric: 0,
+    })
+}
+
+/// The most specific route to the destination, the one with the lowest metric among equals
+///
+/// Addresses of the host itself always go through the loopback interface
+pub fn lookup(destination: Ipv4Address) -> Option<Route> {
+    if let Some(route) = local(destination) {
+        return Some(route);
+    }
+
+    ROUTES
+        .lock()
+        .iter()
+        .filter(|route| route.matches(destination))
+        .min_by_key(|route| (u8::MAX - route.prefix_len, route.metric))
+        .copied()
+}
@@ -0,0 +1,1083 @@
+//! The transmission control protocol, a simplified implementation that favors being correct over
+//! being fast: out of order segments are dropped and there is no congestion control
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use super::{
+    Error, Interface, PacketBuffer,
+    buffer::DEFAULT_HEADROOM,
+    checksum::Checksum,
+    ipv4::{self, Header as Ipv4Header, Ipv4Address, PROTOCOL_TCP},
+    route,
+};
+use crate::{
+    file::{self, Events, File},
+    rand,
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+    timer::{self, TimerId},
+    wait,
+};
+
+const HEADER_SIZE: usize = 20;
+
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The segment size assumed when the peer does not announce one
+const DEFAULT_MSS: usize = 536;
+
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+const RECEIVE_BUFFER_SIZE: usize = 64 * 1024 - 1;
+
+const INITIAL_RTO_NS: u64 = NANOSECONDS_PER_SECOND;
+const MIN_RTO_NS: u64 = NANOSECONDS_PER_SECOND / 5;
+const MAX_RTO_NS: u64 = 60 * NANOSECONDS_PER_SECOND;
+/// Retransmissions of the same segment before the connection is given up
+const MAX_RETRANSMITS: u32 = 8;
+
+/// Twice the maximum segment lifetime
+const TIME_WAIT_NS: u64 = 60 * NANOSECONDS_PER_SECOND;
+
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+const DEFAULT_BACKLOG: usize = 16;
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    local: (Ipv4Address, u16),
+    remote: (Ipv4Address, u16),
+}
+
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(header: &Ipv4Header, data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let data_offset = (data[12] >> 4) as usize * 4;
+
+        if data_offset < HEADER_SIZE || data_offset > data.len() {
+            return None;
+        }
+
+        if pseudo_header_checksum(header.source, header.destination, data.len())
+            .add(data)
+            .finish()
+            != 0
+        {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &data[HEADER_SIZE..data_offset];
+
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+
+                    if len < 2 || len > options.len() {
+                        break;
+                    }
+
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(Self {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            seq: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            ack: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            payload: &data[data_offset..],
+        })
+    }
+
+    /// How much sequence space the segment takes, SYN and FIN count as one each
+    fn len(&self) -> u32 {
+        self.payload.len() as u32
+            + (self.flags & FLAG_SYN != 0) as u32
+            + (self.flags & FLAG_FIN != 0) as u32
+    }
+}
+
+fn pseudo_header_checksum(source: Ipv4Address, destination: Ipv4Address, len: usize) -> Checksum {
+    let mut checksum = Checksum::new();
+
+    checksum
+        .add(&source.0)
+        .add(&destination.0)
+        .add_u16(PROTOCOL_TCP as u16)
+        .add_u16(len as u16);
+
+    checksum
+}
+
+fn transmit(
+    key: Key,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &[u8],
+) {
+    let options_len = if mss.is_some() { 4 } else { 0 };
+    let header_len = HEADER_SIZE + options_len;
+
+    let mut packet = PacketBuffer::new(DEFAULT_HEADROOM, header_len);
+    packet.extend_from_slice(payload);
+
+    let data = packet.data_mut();
+    data[0..2].copy_from_slice(&key.local.1.to_be_bytes());
+    data[2..4].copy_from_slice(&key.remote.1.to_be_bytes());
+    data[4..8].copy_from_slice(&seq.to_be_bytes());
+    data[8..12].copy_from_slice(&ack.to_be_bytes());
+    data[12] = ((header_len / 4) as u8) << 4;
+    data[13] = flags;
+    data[14..16].copy_from_slice(&window.to_be_bytes());
+
+    if let Some(mss) = mss {
+        data[20] = OPTION_MSS;
+        data[21] = 4;
+        data[22..24].copy_from_slice(&mss.to_be_bytes());
+    }
+
+    let checksum = pseudo_header_checksum(key.local.0, key.remote.0, data.len())
+        .add(data)
+        .finish();
+    data[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    let Some(route) = route::lookup(key.remote.0) else {
+        return;
+    };
+
+    let Some(interface) = super::interface(route.interface) else {
+        return;
+    };
+
+    let _ = ipv4::send_via(
+        &interface,
+        &route,
+        key.local.0,
+        key.remote.0,
+        PROTOCOL_TCP,
+        packet,
+    );
+}
+
+/// The transmission control block, everything known about a connection
+struct Tcb {
+    key: Key,
+    state: State,
+    this: Weak<Mutex<Tcb>>,
+    /// The port of the listener the connection is accepted by once it is established
+    listener: Option<u16>,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    rcv_nxt: u32,
+    mss: usize,
+
+    /// Data from `snd_una` on, both sent and not yet sent
+    send_buffer: VecDeque<u8>,
+    receive_buffer: VecDeque<u8>,
+
+    /// The application is done sending, FIN goes out once the send buffer drains
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_received: bool,
+    nodelay: bool,
+
+    rto_ns: u64,
+    srtt_ns: Option<u64>,
+    rttvar_ns: u64,
+    /// A sequence number being timed and when it was sent, for measuring the round trip time
+    rtt_sample: Option<(u32, u64)>,
+    retransmits: u32,
+    timer: Option<TimerId>,
+
+    error: Option<Error>,
+}
+
+impl Tcb {
+    fn new(key: Key, state: State, local_mss: usize) -> Arc<Mutex<Self>> {
+        let iss = rand::u64() as u32;
+
+        Arc::new_cyclic(|this| {
+            Mutex::new(Self {
+                key,
+                state,
+                this: this.clone(),
+                listener: None,
+                iss,
+                snd_una: iss,
+                snd_nxt: iss,
+                snd_wnd: 0,
+                rcv_nxt: 0,
+                mss: local_mss,
+                send_buffer: VecDeque::new(),
+                receive_buffer: VecDeque::new(),
+                fin_queued: false,
+                fin_sent: false,
+                fin_received: false,
+                nodelay: false,
+                rto_ns: INITIAL_RTO_NS,
+                srtt_ns: None,
+                rttvar_ns: 0,
+                rtt_sample: None,
+                retransmits: 0,
+                timer: None,
+                error: None,
+            })
+        })
+    }
+
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER_SIZE - self.receive_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn syn_unacked(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynReceived)
+    }
+
+    /// The sequence number of the first byte in the send buffer
+    fn data_seq(&self) -> u32 {
+        self.snd_una.wrapping_add(self.syn_unacked() as u32)
+    }
+
+    /// How many bytes of the send buffer were sent at least once
+    fn data_in_flight(&self) -> usize {
+        let sent = self.snd_nxt.wrapping_sub(self.data_seq()) as usize;
+
+        sent.saturating_sub(self.fin_sent as usize)
+            .min(self.send_buffer.len())
+    }
+
+    fn send_control(&self, seq: u32, flags: u8) {
+        let mss = if flags & FLAG_SYN != 0 {
+            Some(self.mss as u16)
+        } else {
+            None
+        };
+
+        transmit(self.key, seq, self.rcv_nxt, flags, self.window(), mss, &[]);
+    }
+
+    fn send_ack(&self) {
+        self.send_control(self.snd_nxt, FLAG_ACK);
+    }
+
+    fn send_data(&self, seq: u32, offset: usize, len: usize) {
+        let (front, back) = self.send_buffer.as_slices();
+
+        let payload: Vec<u8> = front
+            .iter()
+            .chain(back)
+            .skip(offset)
+            .take(len)
+            .copied()
+            .collect();
+
+        transmit(
+            self.key,
+            seq,
+            self.rcv_nxt,
+            FLAG_ACK | FLAG_PSH,
+            self.window(),
+            None,
+            &payload,
+        );
+    }
+
+    fn arm_timer(&mut self, delay_ns: u64) {
+        if let Some(timer) = self.timer.take() {
+            timer::cancel(timer);
+        }
+
+        let this = self.this.clone();
+
+        self.timer = Some(timer::add(delay_ns, move || {
+            if let Some(tcb) = this.upgrade() {
+                tcb.lock().on_timer();
+            }
+        }));
+    }
+
+    fn disarm_timer(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer::cancel(timer);
+        }
+    }
+
+    /// Send whatever the window, Nagle's algorithm and the state allow
+    fn output(&mut self) {
+        if !matches!(self.state, State::Established | State::CloseWait) {
+            return;
+        }
+
+        loop {
+            let in_flight = self.data_in_flight();
+            let available = self.send_buffer.len() - in_flight;
+
+            let window_end = self.snd_una.wrapping_add(self.snd_wnd);
+            let window_left = if seq_lt(self.snd_nxt, window_end) {
+                window_end.wrapping_sub(self.snd_nxt) as usize
+            } else {
+                0
+            };
+
+            let len = available.min(self.mss).min(window_left);
+
+            if len == 0 {
+                break;
+            }
+
+            // Small segments wait until everything sent so far is acknowledged
+            if !self.nodelay && len < self.mss && self.snd_nxt != self.snd_una {
+                break;
+            }
+
+            self.send_data(self.snd_nxt, in_flight, len);
+
+            if self.rtt_sample.is_none() {
+                self.rtt_sample =
+                    Some((self.snd_nxt.wrapping_add(len as u32), time::monotonic_ns()));
+            }
+
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+
+            if self.timer.is_none() {
+                self.arm_timer(self.rto_ns);
+            }
+        }
+
+        if self.fin_queued && !self.fin_sent && self.data_in_flight() == self.send_buffer.len() {
+            self.send_control(self.snd_nxt, FLAG_FIN | FLAG_ACK);
+
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = match self.state {
+                State::Established => State::FinWait1,
+                _ => State::LastAck,
+            };
+
+            if self.timer.is_none() {
+                self.arm_timer(self.rto_ns);
+            }
+        }
+    }
+
+    fn on_timer(&mut self) {
+        self.timer = None;
+
+        if self.state == State::TimeWait {
+            self.close(None);
+
+            return;
+        }
+
+        if self.snd_una == self.snd_nxt {
+            return;
+        }
+
+        self.retransmits += 1;
+
+        if self.retransmits > MAX_RETRANSMITS {
+            self.close(Some(Error::TimedOut));
+
+            return;
+        }
+
+        // Karn's algorithm, retransmitted segments are not timed and the timeout backs off
+        self.rtt_sample = None;
+        self.rto_ns = (self.rto_ns * 2).min(MAX_RTO_NS);
+
+        match self.state {
+            State::SynSent => self.send_control(self.iss, FLAG_SYN),
+            State::SynReceived => self.send_control(self.iss, FLAG_SYN | FLAG_ACK),
+            _ => {
+                let in_flight = self.data_in_flight();
+
+                if in_flight > 0 {
+                    // This also probes a zero window, it ignores the window on purpose
+                    self.send_data(self.snd_una, 0, in_flight.min(self.mss));
+                } else if self.fin_sent {
+                    self.send_control(self.snd_nxt.wrapping_sub(1), FLAG_FIN | FLAG_ACK);
+                }
+            }
+        }
+
+        self.arm_timer(self.rto_ns);
+    }
+
+    fn update_rtt(&mut self, rtt_ns: u64) {
+        // RFC 6298
+        match self.srtt_ns {
+            None => {
+                self.srtt_ns = Some(rtt_ns);
+                self.rttvar_ns = rtt_ns / 2;
+            }
+            Some(srtt) => {
+                self.rttvar_ns = (3 * self.rttvar_ns + srtt.abs_diff(rtt_ns)) / 4;
+                self.srtt_ns = Some((7 * srtt + rtt_ns) / 8);
+            }
+        }
+
+        self.rto_ns = (self.srtt_ns.unwrap() + (4 * self.rttvar_ns).max(timer::TICK_NS))
+            .clamp(MIN_RTO_NS, MAX_RTO_NS);
+    }
+
+    fn close(&mut self, error: Option<Error>) {
+        self.disarm_timer();
+
+        self.state = State::Closed;
+
+        if self.error.is_none() {
+            self.error = error;
+        }
+
+        CONNECTIONS.lock().remove(&self.key);
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.arm_timer(TIME_WAIT_NS);
+    }
+
+    fn on_segment(&mut self, segment: &Segment) {
+        if self.state == State::SynSent {
+            self.on_segment_syn_sent(segment);
+
+            return;
+        }
+
+        if segment.seq != self.rcv_nxt {
+            // Out of order or duplicate, ask for what we expect instead
+            if segment.flags & FLAG_RST == 0 && segment.len() > 0 {
+                self.send_ack();
+            }
+
+            return;
+        }
+
+        if segment.flags & FLAG_RST != 0 {
+            let error = match self.state {
+                State::SynReceived => Error::ConnectionRefused,
+                _ => Error::ConnectionReset,
+            };
+
+            self.close(Some(error));
+
+            return;
+        }
+
+        if segment.flags & FLAG_SYN != 0 {
+            self.send_control(self.snd_nxt, FLAG_RST);
+            self.close(Some(Error::ConnectionReset));
+
+            return;
+        }
+
+        if segment.flags & FLAG_ACK == 0 {
+            return;
+        }
+
+        if self.state == State::SynReceived {
+            if segment.ack != self.snd_nxt {
+                transmit(self.key, segment.ack, 0, FLAG_RST, 0, None, &[]);
+
+                return;
+            }
+
+            self.snd_una = segment.ack;
+            self.snd_wnd = segment.window as u32;
+            self.retransmits = 0;
+            self.disarm_timer();
+            self.state = State::Established;
+
+            let listener = self
+                .listener
+                .and_then(|port| LISTENERS.lock().get(&port).cloned());
+
+            // Only what a listener answered waits to be accepted, not simultaneous opens
+            let mut accepted = self.listener.is_none();
+
+            if let Some(listener) = listener
+                && let Some(this) = self.this.upgrade()
+            {
+                let mut queue = listener.queue.lock();
+
+                if queue.len() < listener.backlog {
+                    queue.push_back(this);
+                    accepted = true;
+                }
+            }
+
+            // Nothing would ever accept it, the listener is gone or its queue filled up since
+            // the SYN was answered
+            if !accepted {
+                self.send_control(self.snd_nxt, FLAG_RST);
+                self.close(Some(Error::ConnectionReset));
+
+                return;
+            }
+        }
+
+        self.on_ack(segment);
+
+        if self.state == State::Closed {
+            return;
+        }
+
+        let mut need_ack = false;
+
+        if !segment.payload.is_empty()
+            && matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            let room = RECEIVE_BUFFER_SIZE - self.receive_buffer.len();
+            let taken = segment.payload.len().min(room);
+
+            self.receive_buffer.extend(&segment.payload[..taken]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(taken as u32);
+
+            need_ack = true;
+        }
+
+        let fin_seq = segment.seq.wrapping_add(segment.payload.len() as u32);
+
+        if segment.flags & FLAG_FIN != 0 && fin_seq == self.rcv_nxt && !self.fin_received {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(),
+                _ => {}
+            }
+
+            need_ack = true;
+        }
+
+        if need_ack {
+            self.send_ack();
+        }
+
+        self.output();
+    }
+
+    fn on_segment_syn_sent(&mut self, segment: &Segment) {
+        let ack_acceptable = segment.flags & FLAG_ACK != 0 && segment.ack == self.snd_nxt;
+
+        if segment.flags & FLAG_ACK != 0 && !ack_acceptable {
+            if segment.flags & FLAG_RST == 0 {
+                transmit(self.key, segment.ack, 0, FLAG_RST, 0, None, &[]);
+            }
+
+            return;
+        }
+
+        if segment.flags & FLAG_RST != 0 {
+            if ack_acceptable {
+                self.close(Some(Error::ConnectionRefused));
+            }
+
+            return;
+        }
+
+        if segment.flags & FLAG_SYN == 0 {
+            return;
+        }
+
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.mss = self
+            .mss
+            .min(segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize));
+        self.snd_wnd = segment.window as u32;
+
+        if ack_acceptable {
+            self.snd_una = segment.ack;
+            self.retransmits = 0;
+            self.disarm_timer();
+            self.state = State::Established;
+
+            self.send_ack();
+            self.output();
+        } else {
+            // Both sides opened at the same time
+            self.state = State::SynReceived;
+            self.send_control(self.iss, FLAG_SYN | FLAG_ACK);
+        }
+    }
+
+    fn on_ack(&mut self, segment: &Segment) {
+        let ack = segment.ack;
+
+        if !seq_le(ack, self.snd_nxt) {
+            // Acknowledges something we never sent
+            self.send_ack();
+
+            return;
+        }
+
+        if seq_lt(self.snd_una, ack) {
+            let mut acked = ack.wrapping_sub(self.snd_una) as usize;
+
+            let fin_acked = self.fin_sent && ack == self.snd_nxt;
+
+            if fin_acked {
+                acked -= 1;
+            }
+
+            self.send_buffer.drain(..acked.min(self.send_buffer.len()));
+            self.snd_una = ack;
+            self.retransmits = 0;
+
+            if let Some((seq, sent_at)) = self.rtt_sample
+                && seq_le(seq, ack)
+            {
+                self.rtt_sample = None;
+                self.update_rtt(time::monotonic_ns() - sent_at);
+            }
+
+            if self.snd_una == self.snd_nxt {
+                self.disarm_timer();
+            } else {
+                self.arm_timer(self.rto_ns);
+            }
+
+            if fin_acked {
+                match self.state {
+                    State::FinWait1 => self.state = State::FinWait2,
+                    State::Closing => self.enter_time_wait(),
+                    State::LastAck => {
+                        self.close(None);
+
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if seq_le(self.snd_una, ack) {
+            self.snd_wnd = segment.window as u32;
+        }
+    }
+}
+
+struct Listener {
+    queue: Mutex<VecDeque<Arc<Mutex<Tcb>>>>,
+    backlog: usize,
+}
+
+static CONNECTIONS: Mutex<BTreeMap<Key, Arc<Mutex<Tcb>>>> = Mutex::new(BTreeMap::new());
+static LISTENERS: Mutex<BTreeMap<u16, Arc<Listener>>> = Mutex::new(BTreeMap::new());
+
+/// Answer a segment that belongs to no connection
+fn reset(key: Key, segment: &Segment) {
+    if segment.flags & FLAG_RST != 0 {
+        return;
+    }
+
+    if segment.flags & FLAG_ACK != 0 {
+        transmit(key, segment.ack, 0, FLAG_RST, 0, None, &[]);
+    } else {
+        let ack = segment.seq.wrapping_add(segment.len());
+
+        transmit(key, 0, ack, FLAG_RST | FLAG_ACK, 0, None, &[]);
+    }
+}
+
+fn local_mss(interface: &Interface) -> usize {
+    interface.mtu() - ipv4::HEADER_SIZE - HEADER_SIZE
+}
+
+pub fn receive(interface: &Arc<Interface>, header: &Ipv4Header, packet: PacketBuffer) {
+    let Some(segment) = Segment::parse(header, packet.data()) else {
+        return;
+    };
+
+    if header.destination.is_broadcast() || header.destination.is_multicast() {
+        return;
+    }
+
+    let key = Key {
+        local: (header.destination, segment.destination_port),
+        remote: (header.source, segment.source_port),
+    };
+
+    let tcb = CONNECTIONS.lock().get(&key).cloned();
+
+    if let Some(tcb) = tcb {
+        tcb.lock().on_segment(&segment);
+
+        return;
+    }
+
+    let listener = LISTENERS.lock().get(&segment.destination_port).cloned();
+
+    let Some(listener) = listener else {
+        reset(key, &segment);
+
+        return;
+    };
+
+    if segment.flags & FLAG_RST != 0 {
+        return;
+    }
+
+    if segment.flags & FLAG_ACK != 0 || segment.flags & FLAG_SYN == 0 {
+        reset(key, &segment);
+
+        return;
+    }
+
+    if listener.queue.lock().len() >= listener.backlog {
+        return;
+    }
+
+    let tcb = Tcb::new(key, State::SynReceived, local_mss(interface));
+
+    {
+        let mut tcb = tcb.lock();
+
+        tcb.listener = Some(segment.destination_port);
+        tcb.rcv_nxt = segment.seq.wrapping_add(1);
+        tcb.snd_wnd = segment.window as u32;
+        tcb.mss = tcb
+            .mss
+            .min(segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize));
+
+        tcb.send_control(tcb.iss, FLAG_SYN | FLAG_ACK);
+        tcb.snd_nxt = tcb.iss.wrapping_add(1);
+
+        let rto = tcb.rto_ns;
+        tcb.arm_timer(rto);
+    }
+
+    CONNECTIONS.lock().insert(key, tcb);
+}
+
+fn wait<T>(timeout_ns: Option<u64>, f: impl FnMut() -> Option<T>) -> Result<T, Error> {
+    let deadline = timeout_ns.map(|timeout| time::monotonic_ns() + timeout);
+
+    wait::until(deadline, f).ok_or(Error::TimedOut)
+}
+
+pub struct TcpStream {
+    tcb: Arc<Mutex<Tcb>>,
+    timeout_ns: Option<u64>,
+}
+
+impl TcpStream {
+    /// Open a connection, waiting until the handshake finishes
+    pub fn connect(address: Ipv4Address, port: u16) -> Result<Self, Error> {
+        let route = route::lookup(address).ok_or(Error::NoRoute)?;
+        let interface = super::interface(route.interface).ok_or(Error::NoRoute)?;
+        let local_address = interface.ipv4().ok_or(Error::NoRoute)?.address;
+
+        let tcb = {
+            let mut connections = CONNECTIONS.lock();
+
+            let local_port = EPHEMERAL_PORTS
+                .clone()
+                .find(|&port| {
+                    !LISTENERS.lock().contains_key(&port)
+                        && !connections.keys().any(|key| key.local.1 == port)
+                })
+                .ok_or(Error::AddressInUse)?;
+
+            let key = Key {
+                local: (local_address, local_port),
+                remote: (address, port),
+            };
+
+            let tcb = Tcb::new(key, State::SynSent, local_mss(&interface));
+            connections.insert(key, tcb.clone());
+
+            tcb
+        };
+
+        {
+            let mut tcb = tcb.lock();
+
+            tcb.send_control(tcb.iss, FLAG_SYN);
+            tcb.snd_nxt = tcb.iss.wrapping_add(1);
+
+            let rto = tcb.rto_ns;
+            tcb.arm_timer(rto);
+        }
+
+        wait(None, || {
+            let tcb = tcb.lock();
+
+            match tcb.state {
+                State::SynSent | State::SynReceived => None,
+                State::Closed => Some(Err(tcb.error.unwrap_or(Error::ConnectionRefused))),
+                _ => Some(Ok(())),
+            }
+        })??;
+
+        Ok(Self {
+            tcb,
+            timeout_ns: None,
+        })
+    }
+
+    /// Make sends and receives fail with `TimedOut` after waiting this long
+    pub fn set_timeout(&mut self, timeout_ns: Option<u64>) {
+        self.timeout_ns = timeout_ns;
+    }
+
+    /// Disable Nagle's algorithm, sending small segments right away
+    pub fn set_nodelay(&self, nodelay: bool) {
+        let mut tcb = self.tcb.lock();
+
+        tcb.nodelay = nodelay;
+        tcb.output();
+    }
+
+    pub fn state(&self) -> State {
+        self.tcb.lock().state
+    }
+
+    pub fn local_address(&self) -> (Ipv4Address, u16) {
+        self.tcb.lock().key.local
+    }
+
+    pub fn peer_address(&self) -> (Ipv4Address, u16) {
+        self.tcb.lock().key.remote
+    }
+
+    /// Queue as much of `data` as fits in the send buffer, waiting for room if there is none
+    pub fn send(&self, data: &[u8]) -> Result<usize, Error> {
+        wait(self.timeout_ns, || {
+            let mut tcb = self.tcb.lock();
+
+            if let Some(error) = tcb.error {
+                return Some(Err(error));
+            }
+
+            if tcb.fin_queued || !matches!(tcb.state, State::Established | State::CloseWait) {
+                return Some(Err(Error::NotConnected));
+            }
+
+            let room = SEND_BUFFER_SIZE - tcb.send_buffer.len();
+
+            if room == 0 {
+                return None;
+            }
+
+            let len = data.len().min(room);
+
+            tcb.send_buffer.extend(&data[..len]);
+            tcb.output();
+
+            Some(Ok(len))
+        })?
+    }
+
+    /// Read what was received, waiting for something to arrive, returns 0 once the peer closed
+    pub fn recv(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        wait(self.timeout_ns, || {
+            let mut tcb = self.tcb.lock();
+
+            if !tcb.receive_buffer.is_empty() {
+                let window_was_closed = tcb.window() == 0;
+                let len = buffer.len().min(tcb.receive_buffer.len());
+
+                for (byte, received) in buffer.iter_mut().zip(tcb.receive_buffer.drain(..len)) {
+                    *byte = received;
+                }
+
+                // Tell the peer it can send again
+                if window_was_closed {
+                    tcb.send_ack();
+                }
+
+                return Some(Ok(len));
+            }
+
+            if let Some(error) = tcb.error {
+                return Some(Err(error));
+            }
+
+            if tcb.fin_received || tcb.state == State::Closed {
+                return Some(Ok(0));
+            }
+
+            None
+        })?
+    }
+
+    /// Send FIN after the data queued so far, the peer can still send
+    pub fn shutdown(&self) {
+        let mut tcb = self.tcb.lock();
+
+        if matches!(tcb.state, State::Established | State::CloseWait) {
+            tcb.fin_queued = true;
+            tcb.output();
+        }
+    }
+}
+
+impl File for TcpStream {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, file::Error> {
+        Ok(self.recv(buffer)?)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, file::Error> {
+        Ok(self.send(data)?)
+    }
+
+    fn poll(&self) -> Events {
+        let tcb = self.tcb.lock();
+
+        let mut events = Events::empty();
+
+        if !tcb.receive_buffer.is_empty() || tcb.fin_received || tcb.state == State::Closed {
+            events |= Events::READABLE;
+        }
+
+        if !tcb.fin_queued
+            && matches!(tcb.state, State::Established | State::CloseWait)
+            && tcb.send_buffer.len() < SEND_BUFFER_SIZE
+        {
+            events |= Events::WRITABLE;
+        }
+
+        if tcb.fin_received && tcb.fin_queued || tcb.state == State::Closed {
+            events |= Events::HANGUP;
+        }
+
+        if tcb.error.is_some() {
+            events |= Events::ERROR;
+        }
+
+        events
+    }
+}
+
+impl Drop for TcpStream {
+    /// The connection keeps closing in the background
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+pub struct TcpListener {
+    port: u16,
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<Self, Error> {
+        let mut listeners = LISTENERS.lock();
+
+        if listeners.contains_key(&port) {
+            return Err(Error::AddressInUse);
+        }
+
+        let listener = Arc::new(Listener {
+            queue: Mutex::new(VecDeque::new()),
+            backlog: DEFAULT_BACKLOG,
+        });
+
+        listeners.insert(port, listener.clone());
+
+        Ok(Self { port, listener })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for a connection that finished its handshake
+    pub fn accept(&self) -> Result<TcpStream, Error> {
+        let tcb = wait(None, || self.listener.queue.lock().pop_front())?;
+
+        Ok(TcpStream {
+            tcb,
+            timeout_ns: None,
+        })
+    }
+}
+
+impl File for TcpListener {
+    /// Readable when `accept` would not wait
+    fn poll(&self) -> Events {
+        if self.listener.queue.lock().is_empty() {
+            Events::empty()
+        } else {
+            Events::READABLE
+        }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.port);
+    }
+}
@@ -0,0 +1,267 @@
+//! The user datagram protocol and its sockets
+
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc, vec::Vec};
+
+use super::{
+    Error, Interface, PacketBuffer,
+    buffer::DEFAULT_HEADROOM,
+    checksum::Checksum,
+    icmp::{self, CODE_PORT_UNREACHABLE, TYPE_DESTINATION_UNREACHABLE},
+    ipv4::{self, Header as Ipv4Header, Ipv4Address, PROTOCOL_UDP},
+    route::Route,
+};
+use crate::{
+    file::{self, Events, File},
+    sync::Mutex,
+    time, wait,
+};
+
+pub const HEADER_SIZE: usize = 8;
+
+/// Ports handed out to sockets bound to port 0
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+/// Datagrams a socket holds before new ones are dropped
+const RECEIVE_QUEUE_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub source: Ipv4Address,
+    pub source_port: u16,
+    pub destination: Ipv4Address,
+    pub data: Vec<u8>,
+}
+
+type Queue = Arc<Mutex<VecDeque<Datagram>>>;
+
+static SOCKETS: Mutex<BTreeMap<u16, Queue>> = Mutex::new(BTreeMap::new());
+
+fn pseudo_header_checksum(source: Ipv4Address, destination: Ipv4Address, len: usize) -> Checksum {
+    let mut checksum = Checksum::new();
+
+    checksum
+        .add(&source.0)
+        .add(&destination.0)
+        .add_u16(PROTOCOL_UDP as u16)
+        .add_u16(len as u16);
+
+    checksum
+}
+
+pub fn receive(_interface: &Arc<Interface>, header: &Ipv4Header, packet: PacketBuffer) {
+    let data = packet.data();
+
+    if data.len() < HEADER_SIZE {
+        return;
+    }
+
+    let source_port = u16::from_be_bytes([data[0], data[1]]);
+    let destination_port = u16::from_be_bytes([data[2], data[3]]);
+    let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    let checksum = u16::from_be_bytes([data[6], data[7]]);
+
+    if len < HEADER_SIZE || len > data.len() {
+        return;
+    }
+
+    let data = &data[..len];
+
+    // A zero checksum means the sender did not calculate one
+    if checksum != 0
+        && pseudo_header_checksum(header.source, header.destination, len)
+            .add(data)
+            .finish()
+            != 0
+    {
+        return;
+    }
+
+    let Some(queue) = SOCKETS.lock().get(&destination_port).cloned() else {
+        icmp::send_error(
+            TYPE_DESTINATION_UNREACHABLE,
+            CODE_PORT_UNREACHABLE,
+            header,
+            data,
+        );
+
+        return;
+    };
+
+    let mut queue = queue.lock();
+
+    if queue.len() < RECEIVE_QUEUE_LIMIT {
+        queue.push_back(Datagram {
+            source: header.source,
+            source_port,
+            destination: header.destination,
+            data: data[HEADER_SIZE..].to_vec(),
+        });
+    }
+}
+
+fn build(
+    source: Ipv4Address,
+    source_port: u16,
+    destination: Ipv4Address,
+    destination_port: u16,
+    data: &[u8],
+) -> Result<PacketBuffer, Error> {
+    let len = HEADER_SIZE + data.len();
+
+    if len > u16::MAX as usize {
+        return Err(Error::TooLarge);
+    }
+
+    let mut packet = PacketBuffer::new(DEFAULT_HEADROOM, HEADER_SIZE);
+    packet.extend_from_slice(data);
+
+    let header = packet.data_mut();
+    header[0..2].copy_from_slice(&source_port.to_be_bytes());
+    header[2..4].copy_from_slice(&destination_port.to_be_bytes());
+    header[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+
+    let checksum = match pseudo_header_checksum(source, destination, len)
+        .add(packet.data())
+        .finish()
+    {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+
+    packet.data_mut()[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    Ok(packet)
+}
+
+pub struct UdpSocket {
+    port: u16,
+    queue: Queue,
+    nonblocking: bool,
+    read_timeout_ns: Option<u64>,
+}
+
+impl UdpSocket {
+    /// Bind a socket to `port` on every address, port 0 picks a free ephemeral port
+    pub fn bind(port: u16) -> Result<Self, Error> {
+        let mut sockets = SOCKETS.lock();
+
+        let port = if port == 0 {
+            EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(Error::AddressInUse)?
+        } else if sockets.contains_key(&port) {
+            return Err(Error::AddressInUse);
+        } else {
+            port
+        };
+
+        let queue: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        sockets.insert(port, queue.clone());
+
+        Ok(Self {
+            port,
+            queue,
+            nonblocking: false,
+            read_timeout_ns: None,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Make reads fail with `WouldBlock` instead of waiting for a datagram
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Make blocking reads fail with `TimedOut` after waiting this long
+    pub fn set_read_timeout(&mut self, timeout_ns: Option<u64>) {
+        self.read_timeout_ns = timeout_ns;
+    }
+
+    pub fn send_to(
+        &self,
+        data: &[u8],
+        destination: Ipv4Address,
+        port: u16,
+    ) -> Result<usize, Error> {
+        let route = super::route::lookup(destination).ok_or(Error::NoRoute)?;
+        let interface = super::interface(route.interface).ok_or(Error::NoRoute)?;
+
+        let source = interface
+            .ipv4()
+            .map_or(Ipv4Address::UNSPECIFIED, |config| config.address);
+
+        self.send_via(&interface, &route, source, data, destination, port)
+    }
+
+    /// Send out of a chosen interface with a chosen source address, for protocols like DHCP that
+    /// run before the interface has an address
+    pub fn send_via(
+        &self,
+        interface: &Interface,
+        route: &Route,
+        source: Ipv4Address,
+        data: &[u8],
+        destination: Ipv4Address,
+        port: u16,
+    ) -> Result<usize, Error> {
+        let packet = build(source, self.port, destination, port, data)?;
+
+        ipv4::send_via(interface, route, source, destination, PROTOCOL_UDP, packet)?;
+
+        Ok(data.len())
+    }
+
+    /// Take the next datagram without waiting
+    pub fn try_recv(&self) -> Option<Datagram> {
+        self.queue.lock().pop_front()
+    }
+
+    /// Take the next datagram, waiting for one unless the socket is nonblocking
+    pub fn recv(&self) -> Result<Datagram, Error> {
+        if self.nonblocking {
+            return self.try_recv().ok_or(Error::WouldBlock);
+        }
+
+        let deadline = self
+            .read_timeout_ns
+            .map(|timeout| time::monotonic_ns() + timeout);
+
+        wait::until(deadline, || self.try_recv()).ok_or(Error::TimedOut)
+    }
+
+    /// Read the next datagram into `buffer`, dropping what does not fit, and return its size and
+    /// where it came from
+    pub fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, Ipv4Address, u16), Error> {
+        let datagram = self.recv()?;
+        let len = datagram.data.len().min(buffer.len());
+
+        buffer[..len].copy_from_slice(&datagram.data[..len]);
+
+        Ok((len, datagram.source, datagram.source_port))
+    }
+}
+
+impl File for UdpSocket {
+    /// Read the next datagram, dropping what does not fit
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, file::Error> {
+        Ok(self.recv_from(buffer)?.0)
+    }
+
+    /// Always writable, a datagram that can not be sent is dropped like on the wire
+    fn poll(&self) -> Events {
+        if self.queue.lock().is_empty() {
+            Events::WRITABLE
+        } else {
+            Events::READABLE | Events::WRITABLE
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
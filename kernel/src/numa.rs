@@ -0,0 +1,228 @@
+//! Which memory and processors are close to each other, from the SRAT and SLIT tables
+//!
+//! Without the tables everything is on node 0
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use lazy_static::lazy_static;
+
+use crate::{acpi, arch::cpu, mmio::Mmio};
+
+/// The SRAT starts its entries after the header and 12 reserved bytes
+const SRAT_ENTRIES_OFFSET: usize = 48;
+
+const SRAT_PROCESSOR: u8 = 0;
+const SRAT_MEMORY: u8 = 1;
+const SRAT_X2APIC: u8 = 2;
+
+const SRAT_ENABLED: u32 = 1 << 0;
+
+/// How the SLIT says a node is from itself, and what a remote node is taken to be without it
+pub const LOCAL_DISTANCE: u8 = 10;
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// A proximity domain, as the firmware numbers them
+pub type Node = u32;
+
+#[derive(Debug, Clone)]
+struct MemoryAffinity {
+    node: Node,
+    range: Range<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Topology {
+    memory: Vec<MemoryAffinity>,
+    /// APIC IDs and their nodes
+    processors: Vec<(u32, Node)>,
+    /// The SLIT's nodes and its matrix of distances between them, row by row
+    localities: usize,
+    distances: Vec<u8>,
+}
+
+lazy_static! {
+    static ref TOPOLOGY: Topology = {
+        let mut topology = Topology::default();
+
+        if let Some(srat) = acpi::find_table(b"SRAT") {
+            parse_srat(&srat, &mut topology);
+        }
+
+        if let Some(slit) = acpi::find_table(b"SLIT") {
+            parse_slit(&slit, &mut topology);
+        }
+
+        topology
+    };
+}
+
+fn parse_srat(srat: &Mmio, topology: &mut Topology) {
+    let mut offset = SRAT_ENTRIES_OFFSET;
+
+    while offset + 2 <= srat.len() {
+        let kind = srat.read::<u8>(offset);
+        let len = srat.read::<u8>(offset + 1) as usize;
+
+        if len < 2 || offset + len > srat.len() {
+            break;
+        }
+
+        match kind {
+            SRAT_PROCESSOR if len >= 16 && srat.read::<u32>(offset + 4) & SRAT_ENABLED != 0 => {
+                // The domain is split in a low byte and three high ones
+                let node = srat.read::<u8>(offset + 2) as u32
+                    | (srat.read::<u8>(offset + 9) as u32) << 8
+                    | (srat.read::<u8>(offset + 10) as u32) << 16
+                    | (srat.read::<u8>(offset + 11) as u32) << 24;
+
+                topology
+                    .processors
+                    .push((srat.read::<u8>(offset + 3) as u32, node));
+            }
+            SRAT_MEMORY if len >= 40 && srat.read::<u32>(offset + 28) & SRAT_ENABLED != 0 => {
+                let read_u64 = |at: usize| {
+                    srat.read::<u32>(at) as u64 | (srat.read::<u32>(at + 4) as u64) << 32
+                };
+
+                let base = read_u64(offset + 8);
+                let length = read_u64(offset + 16);
+
+                if length != 0 {
+                    topology.memory.push(MemoryAffinity {
+                        node: srat.read::<u32>(offset + 2),
+                        range: base..base.saturating_add(length),
+                    });
+                }
+            }
+            SRAT_X2APIC if len >= 24 && srat.read::<u32>(offset + 12) & SRAT_ENABLED != 0 => {
+                topology
+                    .processors
+                    .push((srat.read::<u32>(offset + 8), srat.read::<u32>(offset + 4)));
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+}
+
+fn parse_slit(slit: &Mmio, topology: &mut Topology) {
+    if slit.len() < 44 {
+        return;
+    }
+
+    let localities = slit.read::<u32>(36) as usize;
+
+    if localities == 0 || 44 + localities * localities > slit.len() {
+        return;
+    }
+
+    topology.localities = localities;
+    topology.distances = (0..localities * localities)
+        .map(|index| slit.read::<u8>(44 + index))
+        .collect();
+}
+
+/// The node `phys` is on
+pub fn node_of(phys: u64) -> Node {
+    TOPOLOGY
+        .memory
+        .iter()
+        .find(|affinity| affinity.range.contains(&phys))
+        .map_or(0, |affinity| affinity.node)
+}
+
+/// Where the node of the memory from `phys` changes next, if it does before `end`
+pub fn next_boundary(phys: u64, end: u64) -> u64 {
+    TOPOLOGY
+        .memory
+        .iter()
+        .flat_map(|affinity| [affinity.range.start, affinity.range.end])
+        .filter(|&boundary| boundary > phys && boundary < end)
+        .min()
+        .unwrap_or(end)
+}
+
+/// The node of the processor this runs on
+pub fn current_node() -> Node {
+    let apic_id = cpu::apic_id();
+
+    TOPOLOGY
+        .processors
+        .iter()
+        .find(|&&(id, _)| id == apic_id)
+        .map_or(0, |&(_, node)| node)
+}
+
+/// How far memory on `to` is from processors on `from`, relative to [`LOCAL_DISTANCE`]
+pub fn distance(from: Node, to: Node) -> u8 {
+    let (from, to) = (from as usize, to as usize);
+    let localities = TOPOLOGY.localities;
+
+    if from < localities && to < localities {
+        TOPOLOGY.distances[from * localities + to]
+    } else if from == to {
+        LOCAL_DISTANCE
+    } else {
+        REMOTE_DISTANCE
+    }
+}
+
+/// Every node with memory or processors, and at least node 0
+pub fn nodes() -> Vec<Node> {
+    let mut nodes: Vec<Node> = TOPOLOGY
+        .memory
+        .iter()
+        .map(|affinity| affinity.node)
+        .chain(TOPOLOGY.processors.iter().map(|&(_, node)| node))
+        .collect();
+
+    if nodes.is_empty() {
+        nodes.push(0);
+    }
+
+    nodes.sort_unstable();
+    nodes.dedup();
+    nodes
+}
+
+pub fn dump() {
+    let nodes = nodes();
+
+    for &node in &nodes {
+        let memory: u64 = TOPOLOGY
+            .memory
+            .iter()
+            .filter(|affinity| affinity.node == node)
+            .map(|affinity| affinity.range.end - affinity.range.start)
+            .sum();
+
+        let processors = TOPOLOGY
+            .processors
+            .iter()
+            .filter(|&&(_, processor_node)| processor_node == node)
+            .count();
+
+        let distances: Vec<u8> = nodes.iter().map(|&to| distance(node, to)).collect();
+
+        println!(
+            "numa: node {}: {} MiB, {} processors, distances {:?}",
+            node,
+            memory / (1024 * 1024),
+            processors,
+            distances
+        );
+    }
+}
+
+pub fn init() {
+    if TOPOLOGY.memory.is_empty() && TOPOLOGY.processors.is_empty() {
+        println!("numa: no srat, all memory is on node 0");
+        return;
+    }
+
+    dump();
+
+    println!("numa: running on node {}", current_node());
+}
@@ -0,0 +1,134 @@
+//! What happens when memory runs out and reclaiming it did not help
+//!
+//! Whatever holds memory it can give up on demand registers as a victim, and the one with the
+//! highest score is killed, its size adjusted by its priority. With nothing to kill or with
+//! `panic_on_oom` on the command line, running out of memory panics
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{cmdline, sync::Mutex};
+
+/// The priority of a victim that must never be killed
+pub const NEVER_KILL: i32 = -1000;
+pub const MAX_PRIORITY: i32 = 1000;
+
+pub trait Victim: Send + Sync {
+    fn name(&self) -> String;
+
+    /// How many pages killing it would give back
+    fn pages(&self) -> usize;
+
+    /// From [`NEVER_KILL`] to [`MAX_PRIORITY`], added to its share of memory in thousandths so
+    /// the higher it is the sooner it is killed
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Give back everything, never to be asked again
+    fn kill(&self);
+}
+
+static VICTIMS: Mutex<Vec<(u64, Arc<dyn Victim>)>> = Mutex::new(Vec::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static KILLS: AtomicU64 = AtomicU64::new(0);
+
+/// Let `victim` be killed when memory runs out, until unregistered with the id returned
+pub fn register(victim: Arc<dyn Victim>) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    VICTIMS.lock().push((id, victim));
+
+    id
+}
+
+pub fn unregister(id: u64) {
+    VICTIMS.lock().retain(|(victim_id, _)| *victim_id != id);
+}
+
+/// How much killing `victim` is worth, out of every page there is, `None` if it must not be
+fn score(victim: &dyn Victim, total: usize) -> Option<i64> {
+    let priority = victim.priority().clamp(NEVER_KILL, MAX_PRIORITY);
+
+    if priority == NEVER_KILL {
+        return None;
+    }
+
+    let share = victim.pages() as i64 * 1000 / total.max(1) as i64;
+
+    Some((share + priority as i64).max(1))
+}
+
+/// Called when `pages` could not be allocated for `context` even after reclaiming, returns
+/// whether a victim was killed so the allocation can be tried again
+pub fn out_of_memory(context: &str, pages: usize) -> bool {
+    println!("oom: {} needs {} pages and none are left", context, pages);
+
+    if cmdline::has("panic_on_oom") {
+        panic!(
+            "oom: out of memory for {}, and panic_on_oom is set",
+            context
+        );
+    }
+
+    // Killed outside of the lock, as the victim may register or unregister others as it goes
+    let victim = {
+        let victims = VICTIMS.lock();
+        let total = victims.iter().map(|(_, victim)| victim.pages()).sum();
+
+        victims
+            .iter()
+            .filter_map(|(id, victim)| Some((*id, victim.clone(), score(&**victim, total)?)))
+            .max_by_key(|&(_, _, score)| score)
+    };
+
+    let Some((id, victim, score)) = victim else {
+        panic!("oom: out of memory for {}, and nothing to kill", context);
+    };
+
+    println!(
+        "oom: killing {} with {} pages, score {}",
+        victim.name(),
+        victim.pages(),
+        score
+    );
+
+    unregister(id);
+    victim.kill();
+
+    KILLS.fetch_add(1, Ordering::Relaxed);
+
+    true
+}
+
+pub fn dump() {
+    let Some(victims) = VICTIMS.try_lock() else {
+        println!("oom: busy, try again later");
+        return;
+    };
+
+    let total = victims.iter().map(|(_, victim)| victim.pages()).sum();
+
+    println!(
+        "oom: {} killed so far, {} victims",
+        KILLS.load(Ordering::Relaxed),
+        victims.len()
+    );
+
+    for (_, victim) in victims.iter() {
+        match score(&**victim, total) {
+            Some(score) => println!(
+                "oom:     {}: {} pages, score {}",
+                victim.name(),
+                victim.pages(),
+                score
+            ),
+            None => println!(
+                "oom:     {}: {} pages, never killed",
+                victim.name(),
+                victim.pages()
+            ),
+        }
+    }
+}
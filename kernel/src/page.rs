@@ -0,0 +1,331 @@
+//! What is known about every physical page of memory, in an array indexed by page frame number
+//!
+//! The array covers memory up to the end of the last entry of the memory map that is RAM, and
+//! every entry starts out as reserved until the part of the memory map it is in says otherwise
+
+use alloc::vec::Vec;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU8, AtomicU16, AtomicU32, Ordering},
+};
+
+use bitflags::bitflags;
+use limine::memory_map::EntryType as MemoryEntryType;
+use spin::Once;
+
+use crate::{
+    frame::{self, PAGE_SIZE},
+    meminfo::{self, Category},
+    memory, paging,
+    requests::MEMORY_MAP_REQUEST,
+};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct PageFlags: u16 {
+        /// Someone is reading or writing the page, and everyone else must wait for them
+        const LOCKED = 1;
+        /// The page was written since it was last written back
+        const DIRTY = 1 << 1;
+        /// The page was used recently
+        const REFERENCED = 1 << 2;
+    }
+}
+
+/// What a page is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Owner {
+    /// Firmware, devices, holes in the memory map, and usable memory nothing manages
+    Reserved,
+    /// In a frame zone and not allocated
+    Free,
+    /// The kernel and the modules the bootloader loaded
+    Kernel,
+    /// The bootloader's, until it is reclaimed
+    Bootloader,
+    Heap,
+    /// The pool below 4 GiB
+    Dma32,
+    /// Allocated from a frame zone
+    Frame,
+    /// Backing anonymous memory that may be swapped out
+    Anonymous,
+    /// Holding a page of a mapped file
+    Mapped,
+    /// In the contiguous area set aside for devices
+    Cma,
+}
+
+impl Owner {
+    const ALL: [Owner; 10] = [
+        Owner::Reserved,
+        Owner::Free,
+        Owner::Kernel,
+        Owner::Bootloader,
+        Owner::Heap,
+        Owner::Dma32,
+        Owner::Frame,
+        Owner::Anonymous,
+        Owner::Mapped,
+        Owner::Cma,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Owner::Reserved => "reserved",
+            Owner::Free => "free",
+            Owner::Kernel => "kernel",
+            Owner::Bootloader => "bootloader",
+            Owner::Heap => "heap",
+            Owner::Dma32 => "dma32",
+            Owner::Frame => "frame",
+            Owner::Anonymous => "anonymous",
+            Owner::Mapped => "mapped",
+            Owner::Cma => "cma",
+        }
+    }
+}
+
+/// One physical page
+#[derive(Debug)]
+pub struct Page {
+    refcount: AtomicU32,
+    flags: AtomicU16,
+    owner: AtomicU8,
+}
+
+impl Page {
+    const fn new(owner: Owner) -> Self {
+        Self {
+            refcount: AtomicU32::new(0),
+            flags: AtomicU16::new(0),
+            owner: AtomicU8::new(owner as u8),
+        }
+    }
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Take another reference to the page
+    pub fn get(&self) {
+        let previous = self.refcount.fetch_add(1, Ordering::AcqRel);
+
+        assert!(previous != 0, "page: taking a reference to a free page");
+    }
+
+    /// Drop a reference to the page, returns whether it was the last one
+    pub fn put(&self) -> bool {
+        let previous = self.refcount.fetch_sub(1, Ordering::AcqRel);
+
+        assert!(previous != 0, "page: dropping a reference to a free page");
+
+        previous == 1
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_retain(self.flags.load(Ordering::Acquire))
+    }
+
+    pub fn set_flags(&self, flags: PageFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::AcqRel);
+    }
+
+    pub fn clear_flags(&self, flags: PageFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::AcqRel);
+    }
+
+    /// Set `flags`, returning whether none of them were set already
+    pub fn test_and_set_flags(&self, flags: PageFlags) -> bool {
+        self.flags.fetch_or(flags.bits(), Ordering::AcqRel) & flags.bits() == 0
+    }
+
+    pub fn owner(&self) -> Owner {
+        Owner::ALL[self.owner.load(Ordering::Acquire) as usize]
+    }
+
+    pub fn set_owner(&self, owner: Owner) {
+        let previous = self.owner.swap(owner as u8, Ordering::AcqRel);
+
+        account(Owner::ALL[previous as usize], owner);
+    }
+
+    fn reset(&self, owner: Owner, refcount: u32) {
+        self.refcount.store(refcount, Ordering::Release);
+        self.flags.store(0, Ordering::Release);
+
+        let previous = self.owner.swap(owner as u8, Ordering::AcqRel);
+
+        account(Owner::ALL[previous as usize], owner);
+    }
+}
+
+/// Count a page that went from `previous` to `owner` in the breakdown of what memory is used for
+fn account(previous: Owner, owner: Owner) {
+    let category = |owner: Owner| match owner {
+        Owner::Anonymous => Some(Category::Anonymous),
+        Owner::Mapped => Some(Category::PageCache),
+        _ => None,
+    };
+
+    if previous == owner {
+        return;
+    }
+
+    if let Some(category) = category(previous) {
+        meminfo::uncharge(category, PAGE_SIZE as usize);
+    }
+
+    if let Some(category) = category(owner) {
+        meminfo::charge(category, PAGE_SIZE as usize);
+    }
+}
+
+static PAGES: Once<&'static [Page]> = Once::new();
+
+pub fn pfn_from_phys(phys: u64) -> usize {
+    (phys / PAGE_SIZE) as usize
+}
+
+pub fn phys_from_pfn(pfn: usize) -> u64 {
+    pfn as u64 * PAGE_SIZE
+}
+
+/// The page with frame number `pfn`, once the array is set up and if it covers it
+pub fn from_pfn(pfn: usize) -> Option<&'static Page> {
+    PAGES.get()?.get(pfn)
+}
+
+/// The page `phys` is in
+pub fn from_phys(phys: u64) -> Option<&'static Page> {
+    from_pfn(pfn_from_phys(phys))
+}
+
+/// Reset the pages of `count` frames from `phys` as given to `owner` with `refcount` references,
+/// called by the frame allocator as it hands them out and takes them back
+pub fn reset_range(phys: u64, count: usize, owner: Owner, refcount: u32) {
+    let Some(pages) = PAGES.get() else {
+        return;
+    };
+
+    let start = pfn_from_phys(phys).min(pages.len());
+    let end = (start + count).min(pages.len());
+
+    for page in &pages[start..end] {
+        page.reset(owner, refcount);
+    }
+}
+
+fn pfns(range: Range<u64>) -> Range<usize> {
+    pfn_from_phys(range.start)..pfn_from_phys(range.end.next_multiple_of(PAGE_SIZE))
+}
+
+pub fn dump() {
+    let Some(pages) = PAGES.get() else {
+        println!("page: no metadata");
+        return;
+    };
+
+    let mut counts = [0usize; Owner::ALL.len()];
+    let mut shared = 0;
+
+    for page in pages.iter() {
+        counts[page.owner() as usize] += 1;
+
+        if page.refcount() > 1 {
+            shared += 1;
+        }
+    }
+
+    for owner in Owner::ALL {
+        println!("page: {}: {} pages", owner.name(), counts[owner as usize]);
+    }
+
+    println!("page: {} pages with more than one reference", shared);
+}
+
+/// Allocate the array, from the frame zones if they have room for it, and fill it in from the
+/// memory map, the heap, the pool below 4 GiB and the frame zones
+pub fn init() {
+    let entries = MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("could not ask limine to get the memory map")
+        .entries();
+
+    let is_ram = |kind: MemoryEntryType| {
+        kind == MemoryEntryType::USABLE
+            || kind == MemoryEntryType::BOOTLOADER_RECLAIMABLE
+            || kind == MemoryEntryType::EXECUTABLE_AND_MODULES
+            || kind == MemoryEntryType::ACPI_RECLAIMABLE
+    };
+
+    let count = entries
+        .iter()
+        .filter(|entry| is_ram(entry.entry_type))
+        .map(|entry| pfn_from_phys((entry.base + entry.length).next_multiple_of(PAGE_SIZE)))
+        .max()
+        .unwrap_or(0);
+
+    let len = count * size_of::<Page>();
+    let frames = len.div_ceil(PAGE_SIZE as usize);
+
+    let pages: &'static mut [Page] = match frame::alloc(frames) {
+        Some(phys) => unsafe {
+            let pages = paging::virt_from_phys(phys) as *mut Page;
+
+            for pfn in 0..count {
+                pages.add(pfn).write(Page::new(Owner::Reserved));
+            }
+
+            core::slice::from_raw_parts_mut(pages, count)
+        },
+        None => {
+            let mut pages = Vec::with_capacity(count);
+
+            pages.resize_with(count, || Page::new(Owner::Reserved));
+            pages.leak()
+        }
+    };
+
+    for entry in entries.iter() {
+        let owner = match entry.entry_type {
+            MemoryEntryType::BOOTLOADER_RECLAIMABLE => Owner::Bootloader,
+            MemoryEntryType::EXECUTABLE_AND_MODULES => Owner::Kernel,
+            _ => continue,
+        };
+
+        for pfn in pfns(entry.base..entry.base + entry.length) {
+            if let Some(page) = pages.get(pfn) {
+                page.set_owner(owner);
+            }
+        }
+    }
+
+    let pools = [
+        Some((memory::heap_phys_range(), Owner::Heap)),
+        memory::dma32_phys_range().map(|range| (range, Owner::Dma32)),
+    ];
+
+    for (range, owner) in pools.into_iter().flatten() {
+        for pfn in pfns(range) {
+            if let Some(page) = pages.get(pfn) {
+                page.reset(owner, 1);
+            }
+        }
+    }
+
+    // What the frame zones already handed out, which this array may be part of
+    frame::for_each_frame(|phys, allocated| {
+        if let Some(page) = pages.get(pfn_from_phys(phys)) {
+            match allocated {
+                true => page.reset(Owner::Frame, 1),
+                false => page.reset(Owner::Free, 0),
+            }
+        }
+    });
+
+    PAGES.call_once(|| pages);
+
+    println!("page: {} pages described in {} KiB", count, len / 1024);
+}
@@ -1,6 +1,28 @@
+//! The kernel's page tables, and the addresses the bootloader mapped things at
+//!
+//! Nothing the kernel maps may be both writable and executable, as what is written there could be
+//! run. [`map`] and [`map_identity`] panic when asked to, only firmware that can not be trusted to
+//! keep its code and data apart is mapped so with [`map_identity_writable_executable`], and
+//! [`audit`] looks at the end of boot for anything else mapped so, like by the bootloader
+//!
+//! [`audit`] only prints what it finds, `panic_on_wx` on the kernel command line makes it panic
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
 use lazy_static::lazy_static;
 
-use crate::requests::HHDM_REQUEST;
+use crate::{
+    arch::paging::{self as arch_paging, PAGE_SIZE, PageTableFlags},
+    cmdline,
+    dma::{self, Constraints},
+    meminfo::{self, Category},
+    requests::{EXECUTABLE_ADDRESS_REQUEST, HHDM_REQUEST},
+    sync::Mutex,
+};
+
+/// The virtual address the kernel is linked at, the bootloader may load it anywhere else
+pub const KERNEL_LINK_BASE: u64 = 0xFFFF_FFFF_8000_0000;
 
 lazy_static! {
     static ref HHDM_OFFSET: u64 = HHDM_REQUEST
@@ -9,6 +31,285 @@ lazy_static! {
         .offset();
 }
 
+lazy_static! {
+    static ref KERNEL_BASE: (u64, u64) = {
+        let response = EXECUTABLE_ADDRESS_REQUEST
+            .get_response()
+            .expect("could not ask limine to get the kernel address");
+
+        (response.virtual_base(), response.physical_base())
+    };
+}
+
 pub fn virt_from_phys(phys: u64) -> u64 {
     phys + *HHDM_OFFSET
 }
+
+/// Translate an address in the higher half direct map back to its physical address
+pub fn phys_from_virt(virt: u64) -> u64 {
+    virt - *HHDM_OFFSET
+}
+
+/// Firmware table addresses from the bootloader are physical since base revision 3, but were in
+/// the higher half direct map before and may still be with older bootloaders
+pub fn phys_from_bootloader(address: u64) -> u64 {
+    if address >= *HHDM_OFFSET {
+        phys_from_virt(address)
+    } else {
+        address
+    }
+}
+
+pub fn kernel_virt_base() -> u64 {
+    KERNEL_BASE.0
+}
+
+pub fn kernel_phys_base() -> u64 {
+    KERNEL_BASE.1
+}
+
+/// How far the kernel was moved from where it is linked, which must be subtracted from addresses
+/// in the kernel (like the ones in backtraces) before looking them up in the kernel executable
+pub fn kernel_slide() -> u64 {
+    kernel_virt_base().wrapping_sub(KERNEL_LINK_BASE)
+}
+
+/// Translate the address of something in the kernel executable to its physical address
+pub fn kernel_phys_from_virt(virt: u64) -> u64 {
+    virt - kernel_virt_base() + kernel_phys_base()
+}
+
+/// Serializes changes to the page tables
+static PAGE_TABLES: Mutex<()> = Mutex::new(());
+
+/// What was mapped writable and executable on purpose, which [`audit`] leaves alone
+static WRITABLE_EXECUTABLE: Mutex<Vec<Range<u64>>> = Mutex::new(Vec::new());
+
+fn is_writable_executable(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+fn forbid_writable_executable(virt: u64, flags: PageTableFlags) {
+    assert!(
+        !is_writable_executable(flags),
+        "paging: refusing to map {:#x} writable and executable",
+        virt
+    );
+}
+
+fn alloc_table() -> u64 {
+    let table = dma::alloc_coherent(PAGE_SIZE as usize, Constraints::new())
+        .expect("could not allocate a page table");
+    let phys = table.bus_address();
+
+    // Page tables are never freed
+    core::mem::forget(table);
+
+    meminfo::charge(Category::PageTables, PAGE_SIZE as usize);
+
+    phys
+}
+
+/// Map device memory uncached into the higher half direct map, which only covers ordinary memory,
+/// and return its virtual address
+pub fn map_mmio(phys: u64, len: usize) -> u64 {
+    let _guard = PAGE_TABLES.lock();
+
+    let root = arch_paging::root_table();
+    let first = phys & !(PAGE_SIZE - 1);
+
+    for page in (first..phys + len as u64).step_by(PAGE_SIZE as usize) {
+        arch_paging::map(
+            root,
+            virt_from_phys(page),
+            page,
+            PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::WRITE_THROUGH
+                | PageTableFlags::NO_EXECUTE,
+            virt_from_phys,
+            alloc_table,
+        );
+    }
+
+    virt_from_phys(phys)
+}
+
+crate::export!(map_mmio: fn(u64, usize) -> u64);
+
+fn map_identity_unchecked(phys: u64, len: usize, flags: PageTableFlags) {
+    let _guard = PAGE_TABLES.lock();
+
+    let root = arch_paging::root_table();
+    let first = phys & !(PAGE_SIZE - 1);
+
+    for page in (first..phys + len as u64).step_by(PAGE_SIZE as usize) {
+        arch_paging::map(root, page, page, flags, virt_from_phys, alloc_table);
+    }
+}
+
+/// Map physical memory at the same virtual address, for firmware that was never told about any
+/// other address, and leave whatever is already mapped there alone
+pub fn map_identity(phys: u64, len: usize, flags: PageTableFlags) {
+    forbid_writable_executable(phys, flags);
+
+    map_identity_unchecked(phys, len, flags);
+}
+
+/// Like [`map_identity`], but allowed to be writable and executable, for firmware code that
+/// writes to itself
+pub fn map_identity_writable_executable(phys: u64, len: usize, flags: PageTableFlags) {
+    let first = phys & !(PAGE_SIZE - 1);
+
+    WRITABLE_EXECUTABLE
+        .lock()
+        .push(first..(phys + len as u64).next_multiple_of(PAGE_SIZE));
+
+    map_identity_unchecked(phys, len, flags);
+}
+
+/// Map physical memory at `virt`, which must be page aligned and not mapped yet
+pub fn map(virt: u64, phys: u64, len: usize, flags: PageTableFlags) {
+    forbid_writable_executable(virt, flags);
+
+    let _guard = PAGE_TABLES.lock();
+
+    let root = arch_paging::root_table();
+
+    for offset in (0..len as u64).step_by(PAGE_SIZE as usize) {
+        arch_paging::map(
+            root,
+            virt + offset,
+            phys + offset,
+            flags,
+            virt_from_phys,
+            alloc_table,
+        );
+    }
+}
+
+/// Remove what [`map`] mapped at `virt`
+pub fn unmap(virt: u64, len: usize) {
+    let _guard = PAGE_TABLES.lock();
+
+    let root = arch_paging::root_table();
+
+    for offset in (0..len as u64).step_by(PAGE_SIZE as usize) {
+        arch_paging::unmap(root, virt + offset, virt_from_phys);
+    }
+}
+
+/// Find the physical address `virt` is mapped to in the page tables in use
+pub fn translate(virt: u64) -> Option<u64> {
+    arch_paging::translate(arch_paging::root_table(), virt, virt_from_phys)
+}
+
+/// The level 1 entry for the page at `virt`, which may hold something other than a mapping when
+/// it is not present
+pub fn read_entry(virt: u64) -> Option<u64> {
+    let _guard = PAGE_TABLES.lock();
+
+    arch_paging::entry(arch_paging::root_table(), virt, virt_from_phys)
+        .map(|entry| unsafe { entry.read_volatile() })
+}
+
+/// Like [`read_entry`], but gives up if the page tables are being changed, for faults and panics
+/// that may have interrupted whatever was changing them
+pub fn try_read_entry(virt: u64) -> Option<u64> {
+    let _guard = PAGE_TABLES.try_lock()?;
+
+    arch_paging::entry(arch_paging::root_table(), virt, virt_from_phys)
+        .map(|entry| unsafe { entry.read_volatile() })
+}
+
+/// Call `f` with the level and value of each entry translating `virt` goes through, returns
+/// false without calling it if the page tables are being changed
+pub fn try_levels(virt: u64, f: impl FnMut(u32, u64)) -> bool {
+    let Some(_guard) = PAGE_TABLES.try_lock() else {
+        return false;
+    };
+
+    arch_paging::levels(arch_paging::root_table(), virt, virt_from_phys, f);
+
+    true
+}
+
+/// Replace the level 1 entry for the page at `virt`, returns false if there are no tables for it
+pub fn write_entry(virt: u64, value: u64) -> bool {
+    let flags = PageTableFlags::from_bits_retain(value);
+
+    if flags.contains(PageTableFlags::PRESENT) {
+        forbid_writable_executable(virt, flags);
+    }
+
+    let _guard = PAGE_TABLES.lock();
+
+    let Some(entry) = arch_paging::entry(arch_paging::root_table(), virt, virt_from_phys) else {
+        return false;
+    };
+
+    unsafe { entry.write_volatile(value) };
+
+    arch_paging::flush(virt);
+
+    true
+}
+
+/// Print every range of kernel memory that is writable and executable but was not mapped so on
+/// purpose, and panic if there were any with `panic_on_wx`
+pub fn audit() {
+    let allowed = WRITABLE_EXECUTABLE.lock();
+    let _guard = PAGE_TABLES.lock();
+
+    let mut found = 0;
+    let mut current: Option<(u64, u64)> = None;
+
+    let mut report = |start: u64, len: u64| {
+        crate::log_warn!(
+            "paging",
+            "{:#x}-{:#x} is writable and executable",
+            start,
+            start.wrapping_add(len - 1)
+        );
+
+        found += 1;
+    };
+
+    // Ranges are printed once the first page after them is not part of them, so nothing is
+    // allocated with the page tables locked
+    arch_paging::walk(
+        arch_paging::root_table(),
+        virt_from_phys,
+        |virt, size, flags| {
+            let is_allowed = allowed
+                .iter()
+                .any(|range| range.start <= virt && virt.wrapping_add(size) <= range.end);
+
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                || !is_writable_executable(flags)
+                || is_allowed
+            {
+                return;
+            }
+
+            match &mut current {
+                Some((start, len)) if start.wrapping_add(*len) == virt => *len += size,
+                _ => {
+                    if let Some((start, len)) = current {
+                        report(start, len);
+                    }
+
+                    current = Some((virt, size));
+                }
+            }
+        },
+    );
+
+    if let Some((start, len)) = current {
+        report(start, len);
+    }
+
+    if found != 0 && cmdline::has("panic_on_wx") {
+        panic!("paging: {} ranges are writable and executable", found);
+    }
+}
@@ -1,12 +1,29 @@
 use core::fmt::Write;
 
-use crate::arch::endless_loop;
-use crate::console::CONSOLE;
+use crate::arch::{endless_loop, registers, speaker};
+use crate::console::{self, CONSOLE, EarlyConsole};
+use crate::crashdump;
+use crate::net::netconsole;
 use crate::requests::FRAMEBUFFER_REQUEST;
-use crate::screen::Color;
+use crate::screen::{self, Color};
+use crate::time;
+
+const BEEP_FREQUENCY: u32 = 1000;
+const BEEP_DURATION_NS: u64 = 250_000_000;
+
+/// How many bytes of the stack from where the panic was are shown
+const STACK_DUMP_LEN: usize = 128;
 
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    // The framebuffer may not be there, only the early console is sure to be
+    if !console::is_ready() {
+        let _ = match info.location() {
+            Some(location) => writeln!(EarlyConsole, "panic at {}: {}", location, info.message()),
+            None => writeln!(EarlyConsole, "panic: {}", info.message()),
+        };
+    }
+
     // We print panic info only if screen can be initialized, otherwise that would make a
     // stack overflow, because if screen can not be initialized, it will panic, therefore
     // calling the panic handler again
@@ -40,7 +57,25 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
         );
 
         let _ = writeln!(&mut console, "Panic message: {}", info.message());
+
+        screen::flush();
     }
 
+    println!("stack:");
+    console::hexdump(registers::stack_pointer(), STACK_DUMP_LEN);
+
+    if crashdump::enabled() {
+        crashdump::write(info);
+    }
+
+    // For machines where nothing else shows the panic, waited out as timers no longer run
+    speaker::start(BEEP_FREQUENCY);
+    time::delay_ns(BEEP_DURATION_NS);
+    speaker::stop();
+
+    // Last, as it may spin on a lock the code that panicked held, which is no worse than the
+    // endless loop after it
+    netconsole::flush();
+
     endless_loop();
 }
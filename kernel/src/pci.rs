@@ -0,0 +1,425 @@
+//! PCI devices found through the legacy configuration ports
+//!
+//! The standard header of each device can be printed decoded and as a hexdump, by SysRq `v`
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use lazy_static::lazy_static;
+
+use crate::{arch::port, console::Hexdump, sync::Mutex};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The bits of the command register with a name, in the order they are printed
+const COMMAND_NAMES: [(u16, &str); 4] = [
+    (COMMAND_IO_SPACE, "io"),
+    (COMMAND_MEMORY_SPACE, "memory"),
+    (COMMAND_BUS_MASTER, "bus-master"),
+    (COMMAND_INTERRUPT_DISABLE, "interrupt-disable"),
+];
+
+/// How long the standard header is, in bytes
+const HEADER_LEN: usize = 64;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn select(&self, offset: u8) {
+        port::outl(
+            CONFIG_ADDRESS,
+            1 << 31
+                | (self.bus as u32) << 16
+                | (self.device as u32) << 11
+                | (self.function as u32) << 8
+                | (offset & 0xFC) as u32,
+        );
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        self.select(offset);
+        port::inl(CONFIG_DATA)
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        self.select(offset);
+        port::outl(CONFIG_DATA, value);
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+
+        // The status register next to the command one clears the error bits written as ones
+        let old = if offset == 0x04 {
+            0
+        } else {
+            self.read_u32(offset) & !(0xFFFF << shift)
+        };
+
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+}
+
+impl Device {
+    fn probe(address: Address) -> Option<Self> {
+        let vendor_id = address.read_u16(0x00);
+
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+
+        Some(Self {
+            address,
+            vendor_id,
+            device_id: address.read_u16(0x02),
+            class: address.read_u8(0x0B),
+            subclass: address.read_u8(0x0A),
+            prog_if: address.read_u8(0x09),
+            header_type: address.read_u8(0x0E) & 0x7F,
+        })
+    }
+
+    pub fn command(&self) -> u16 {
+        self.address.read_u16(0x04)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.address.write_u16(0x04, command);
+    }
+
+    /// Let the device decode memory accesses to its bars and do DMA
+    pub fn enable_bus_master(&self) {
+        self.set_command(self.command() | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    }
+
+    pub fn interrupt_line(&self) -> u8 {
+        self.address.read_u8(0x3C)
+    }
+
+    /// Decode a base address register, sizing it by writing all ones and restoring it after
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if self.header_type != 0 || index >= 6 {
+            return None;
+        }
+
+        // While it holds all ones the device would decode accesses anywhere, so it decodes none
+        let command = self.command();
+
+        self.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        let bar = self.size_bar(index);
+
+        self.set_command(command);
+
+        bar
+    }
+
+    fn size_bar(&self, index: u8) -> Option<Bar> {
+        let offset = 0x10 + index * 4;
+        let low = self.address.read_u32(offset);
+
+        if low & 1 == 1 {
+            self.address.write_u32(offset, u32::MAX);
+            let size = !(self.address.read_u32(offset) & !0x3) as u16;
+            self.address.write_u32(offset, low);
+
+            return Some(Bar::Io {
+                port: (low & !0x3) as u16,
+                size: size.wrapping_add(1),
+            });
+        }
+
+        let is_64_bit = (low >> 1) & 0x3 == 0x2;
+
+        let mut address = (low & !0xF) as u64;
+        let mut mask = {
+            self.address.write_u32(offset, u32::MAX);
+            let mask = self.address.read_u32(offset) & !0xF;
+            self.address.write_u32(offset, low);
+            mask as u64
+        };
+
+        if is_64_bit {
+            let high = self.address.read_u32(offset + 4);
+
+            self.address.write_u32(offset + 4, u32::MAX);
+            let high_mask = self.address.read_u32(offset + 4);
+            self.address.write_u32(offset + 4, high);
+
+            address |= (high as u64) << 32;
+            mask |= (high_mask as u64) << 32;
+        } else {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+
+        if mask == 0xFFFF_FFFF_0000_0000 || mask == 0 {
+            return None;
+        }
+
+        Some(Bar::Memory {
+            address,
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & 0x8 != 0,
+        })
+    }
+
+    /// Read the standard header, without sizing the bars as that would write to them
+    pub fn header(&self) -> Header {
+        let mut bytes = [0; HEADER_LEN];
+
+        for (offset, word) in bytes.as_chunks_mut::<4>().0.iter_mut().enumerate() {
+            *word = self.address.read_u32(offset as u8 * 4).to_le_bytes();
+        }
+
+        Header {
+            device: *self,
+            bytes,
+        }
+    }
+
+    /// The id and configuration space offset of every capability
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        let mut next = if self.address.read_u16(0x06) & STATUS_CAPABILITIES != 0 {
+            self.address.read_u8(0x34) & 0xFC
+        } else {
+            0
+        };
+
+        let mut remaining = 48;
+
+        core::iter::from_fn(move || {
+            if next == 0 || remaining == 0 {
+                return None;
+            }
+
+            remaining -= 1;
+
+            let offset = next;
+            let id = self.address.read_u8(offset);
+            next = self.address.read_u8(offset + 1) & 0xFC;
+
+            Some((id, offset))
+        })
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+/// The standard header of a device as it was read, displayed decoded and then as a hexdump
+pub struct Header {
+    device: Device,
+    bytes: [u8; HEADER_LEN],
+}
+
+impl Header {
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = self.u16(0x04);
+        let header_type = self.bytes[0x0E];
+
+        writeln!(
+            f,
+            "{} revision {:02x} header type {:02x}{}",
+            self.device,
+            self.bytes[0x08],
+            header_type & 0x7F,
+            if header_type & 0x80 != 0 {
+                " multifunction"
+            } else {
+                ""
+            }
+        )?;
+
+        write!(f, "command {:#06x}", command)?;
+
+        for (bit, name) in COMMAND_NAMES {
+            if command & bit != 0 {
+                write!(f, " {}", name)?;
+            }
+        }
+
+        writeln!(f, " status {:#06x}", self.u16(0x06))?;
+
+        // Bridges only have two bars, what follows them is about the buses behind
+        let bars = match header_type & 0x7F {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+
+        for index in 0..bars {
+            writeln!(f, "bar {} {:#010x}", index, self.u32(0x10 + index * 4))?;
+        }
+
+        // The pins are numbered from one, no pin means no interrupt
+        match self.bytes[0x3D] {
+            0 => writeln!(f, "no interrupt pin")?,
+            pin @ 1..=4 => writeln!(
+                f,
+                "interrupt line {} pin {}",
+                self.bytes[0x3C],
+                (b'a' + pin - 1) as char
+            )?,
+            pin => writeln!(f, "interrupt line {} pin {}", self.bytes[0x3C], pin)?,
+        }
+
+        write!(f, "{}", Hexdump::bytes(&self.bytes, 0))
+    }
+}
+
+lazy_static! {
+    static ref DEVICES: Vec<Device> =
+        {
+            let mut devices = Vec::new();
+
+            for bus in 0..=255 {
+                for device in 0..32 {
+                    let Some(first) = Device::probe(Address::new(bus, device, 0)) else {
+                        continue;
+                    };
+
+                    let multifunction = first.address.read_u8(0x0E) & 0x80 != 0;
+
+                    devices.push(first);
+
+                    if multifunction {
+                        devices.extend((1..8).filter_map(|function| {
+                            Device::probe(Address::new(bus, device, function))
+                        }));
+                    }
+                }
+            }
+
+            devices
+        };
+}
+
+pub fn devices() -> &'static [Device] {
+    &DEVICES
+}
+
+pub fn find(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static Device> {
+    devices()
+        .iter()
+        .filter(move |device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// The standard header of every device, from before the system went to sleep
+static SAVED_HEADERS: Mutex<Vec<[u32; 16]>> = Mutex::new(Vec::new());
+
+/// Save the header of every device, which the firmware may not restore on wake up
+pub fn suspend() {
+    *SAVED_HEADERS.lock() = devices()
+        .iter()
+        .map(|device| core::array::from_fn(|index| device.address.read_u32(index as u8 * 4)))
+        .collect();
+}
+
+/// Restore the saved headers, backwards so the bars are back before the command register
+/// enables decoding them, and leaving out the read only identifiers
+pub fn resume() {
+    let saved = core::mem::take(&mut *SAVED_HEADERS.lock());
+
+    for (device, header) in devices().iter().zip(saved) {
+        for index in (1..16).rev() {
+            if device.address.read_u32(index as u8 * 4) != header[index] {
+                device.address.write_u32(index as u8 * 4, header[index]);
+            }
+        }
+    }
+}
+
+/// Print the standard header of every device
+pub fn dump() {
+    for device in devices() {
+        println!("pci: {}", device.header());
+    }
+}
+
+pub fn init() {
+    for device in devices() {
+        crate::log_info!("pci", "{}", device);
+    }
+
+    crate::suspend::register("pci", suspend, resume);
+}
@@ -0,0 +1,254 @@
+//! Anonymous pipes, a ring buffer with a reading and a writing end
+
+use alloc::{boxed::Box, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    file::{Error, Events, File, OpenFlags},
+    sync::Mutex,
+    wait::{self, WaitQueue},
+};
+
+pub const PIPE_SIZE: usize = 64 * 1024;
+/// Writes up to this size are never interleaved with other writes
+pub const PIPE_BUF: usize = 4096;
+
+struct Ring {
+    buffer: Box<[u8]>,
+    start: usize,
+    len: usize,
+    readers: usize,
+    writers: usize,
+}
+
+impl Ring {
+    fn space(&self) -> usize {
+        PIPE_SIZE - self.len
+    }
+
+    fn pop(&mut self, buffer: &mut [u8]) -> usize {
+        let len = buffer.len().min(self.len);
+
+        for byte in &mut buffer[..len] {
+            *byte = self.buffer[self.start];
+            self.start = (self.start + 1) % PIPE_SIZE;
+        }
+
+        self.len -= len;
+
+        len
+    }
+
+    fn push(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(self.space());
+
+        for &byte in &data[..len] {
+            self.buffer[(self.start + self.len) % PIPE_SIZE] = byte;
+            self.len += 1;
+        }
+
+        len
+    }
+}
+
+struct Pipe {
+    ring: Mutex<Ring>,
+    wait_queue: WaitQueue,
+}
+
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+    nonblocking: AtomicBool,
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+    nonblocking: AtomicBool,
+}
+
+impl File for PipeReader {
+    /// Block until there is something to read, zero means every writer is gone
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let mut ring = self.pipe.ring.lock();
+
+            if ring.len != 0 {
+                let len = ring.pop(buffer);
+
+                drop(ring);
+                self.pipe.wait_queue.wake_all();
+
+                return Ok(len);
+            }
+
+            if ring.writers == 0 {
+                return Ok(0);
+            }
+
+            drop(ring);
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(Error::WouldBlock);
+            }
+
+            wait::until(None, || (!self.poll().is_empty()).then_some(()));
+        }
+    }
+
+    fn poll(&self) -> Events {
+        let ring = self.pipe.ring.lock();
+
+        let mut events = Events::empty();
+
+        if ring.len != 0 {
+            events |= Events::READABLE;
+        }
+
+        if ring.writers == 0 {
+            events |= Events::HANGUP;
+        }
+
+        events
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.pipe.wait_queue)
+    }
+}
+
+impl File for PipeWriter {
+    /// Block until everything is written, writes up to `PIPE_BUF` go in whole
+    fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        let nonblocking = self.nonblocking.load(Ordering::Relaxed);
+        let mut written = 0;
+
+        while written < data.len() {
+            let mut ring = self.pipe.ring.lock();
+
+            if ring.readers == 0 {
+                return if written == 0 {
+                    Err(Error::BrokenPipe)
+                } else {
+                    Ok(written)
+                };
+            }
+
+            let remaining = &data[written..];
+
+            let pushed = if remaining.len() > PIPE_BUF || ring.space() >= remaining.len() {
+                ring.push(remaining)
+            } else {
+                0
+            };
+
+            drop(ring);
+
+            if pushed != 0 {
+                written += pushed;
+
+                self.pipe.wait_queue.wake_all();
+
+                if written == data.len() {
+                    break;
+                }
+            }
+
+            if nonblocking {
+                return if written == 0 {
+                    Err(Error::WouldBlock)
+                } else {
+                    Ok(written)
+                };
+            }
+
+            wait::until(None, || {
+                self.poll()
+                    .intersects(Events::WRITABLE | Events::ERROR)
+                    .then_some(())
+            });
+        }
+
+        Ok(written)
+    }
+
+    fn poll(&self) -> Events {
+        let ring = self.pipe.ring.lock();
+
+        let mut events = Events::empty();
+
+        if ring.space() >= PIPE_BUF {
+            events |= Events::WRITABLE;
+        }
+
+        if ring.readers == 0 {
+            events |= Events::ERROR;
+        }
+
+        events
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.pipe.wait_queue)
+    }
+}
+
+impl PipeReader {
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+}
+
+impl PipeWriter {
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.ring.lock().readers -= 1;
+        self.pipe.wait_queue.wake_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.ring.lock().writers -= 1;
+        self.pipe.wait_queue.wake_all();
+    }
+}
+
+/// Create a pipe, `pipe2` without the file descriptors as there are no processes to hold them yet
+pub fn pipe(flags: OpenFlags) -> Result<(PipeReader, PipeWriter), Error> {
+    if !(OpenFlags::NONBLOCK | OpenFlags::CLOEXEC).contains(flags) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let pipe = Arc::new(Pipe {
+        ring: Mutex::new(Ring {
+            buffer: alloc::vec![0; PIPE_SIZE].into_boxed_slice(),
+            start: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        }),
+        wait_queue: WaitQueue::new(),
+    });
+
+    let nonblocking = flags.contains(OpenFlags::NONBLOCK);
+
+    Ok((
+        PipeReader {
+            pipe: pipe.clone(),
+            nonblocking: AtomicBool::new(nonblocking),
+        },
+        PipeWriter {
+            pipe,
+            nonblocking: AtomicBool::new(nonblocking),
+        },
+    ))
+}
@@ -0,0 +1,294 @@
+//! Waiting until one of many files is ready, with `poll` and with `epoll`
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
+
+use bitflags::bitflags;
+
+use crate::{
+    file::{Error, Events, File},
+    sync::Mutex,
+    time, wait,
+};
+
+/// A file to wait on and, once `poll` returns, what it is ready for
+pub struct PollFd<'a> {
+    pub file: &'a dyn File,
+    pub events: Events,
+    pub revents: Events,
+}
+
+/// Errors and hangups are reported whether they were asked for or not
+const ALWAYS_REPORTED: Events = Events::ERROR.union(Events::HANGUP);
+
+/// Wait until one of the files is ready for what was asked, returning how many are, zero means
+/// the timeout passed first
+pub fn poll(fds: &mut [PollFd], timeout_ns: Option<u64>) -> usize {
+    let deadline = timeout_ns.map(|timeout| time::monotonic_ns() + timeout);
+
+    wait::until(deadline, || {
+        let mut ready = 0;
+
+        for fd in fds.iter_mut() {
+            fd.revents = fd.file.poll() & (fd.events | ALWAYS_REPORTED);
+
+            if !fd.revents.is_empty() {
+                ready += 1;
+            }
+        }
+
+        (ready != 0).then_some(ready)
+    })
+    .unwrap_or(0)
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct EpollFlags: u32 {
+        /// Report a file once each time it becomes ready, instead of as long as it is
+        const EDGE_TRIGGERED = 1 << 31;
+        /// Stop reporting a file after it was reported once, until it is modified
+        const ONE_SHOT = 1 << 30;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EpollEvent {
+    pub events: Events,
+    pub data: u64,
+}
+
+struct Interest {
+    file: Arc<dyn File>,
+    events: Events,
+    flags: EpollFlags,
+    data: u64,
+    /// Files without a wait queue are looked at on every round
+    waker: Option<u64>,
+    disabled: bool,
+}
+
+/// The files an epoll instance watches, keyed by the address of the file like the kernel would
+/// key them by descriptor
+type Key = usize;
+
+fn key(file: &Arc<dyn File>) -> Key {
+    Arc::as_ptr(file) as *const () as Key
+}
+
+/// A set of files to wait on, only looking at those their wait queue said may be ready
+pub struct Epoll {
+    interests: Mutex<BTreeMap<Key, Interest>>,
+    ready: Arc<Mutex<BTreeSet<Key>>>,
+}
+
+impl Epoll {
+    pub fn new() -> Self {
+        Self {
+            interests: Mutex::new(BTreeMap::new()),
+            ready: Arc::new(Mutex::new(BTreeSet::new())),
+        }
+    }
+
+    pub fn add(
+        &self,
+        file: Arc<dyn File>,
+        events: Events,
+        flags: EpollFlags,
+        data: u64,
+    ) -> Result<(), Error> {
+        let key = key(&file);
+        let mut interests = self.interests.lock();
+
+        if interests.contains_key(&key) {
+            return Err(Error::InvalidArgument);
+        }
+
+        let waker = file.wait_queue().map(|wait_queue| {
+            let ready = self.ready.clone();
+
+            wait_queue.subscribe(Arc::new(move || {
+                ready.lock().insert(key);
+            }))
+        });
+
+        interests.insert(
+            key,
+            Interest {
+                file,
+                events,
+                flags,
+                data,
+                waker,
+                disabled: false,
+            },
+        );
+
+        drop(interests);
+
+        // It may be ready already
+        self.ready.lock().insert(key);
+
+        Ok(())
+    }
+
+    /// Change what is waited for, which also rearms a one shot file
+    pub fn modify(
+        &self,
+        file: &Arc<dyn File>,
+        events: Events,
+        flags: EpollFlags,
+        data: u64,
+    ) -> Result<(), Error> {
+        let key = key(file);
+
+        let mut interests = self.interests.lock();
+        let interest = interests.get_mut(&key).ok_or(Error::InvalidArgument)?;
+
+        interest.events = events;
+        interest.flags = flags;
+        interest.data = data;
+        interest.disabled = false;
+
+        drop(interests);
+
+        self.ready.lock().insert(key);
+
+        Ok(())
+    }
+
+    pub fn remove(&self, file: &Arc<dyn File>) -> Result<(), Error> {
+        let key = key(file);
+
+        let interest = self
+            .interests
+            .lock()
+            .remove(&key)
+            .ok_or(Error::InvalidArgument)?;
+
+        if let (Some(wait_queue), Some(waker)) = (interest.file.wait_queue(), interest.waker) {
+            wait_queue.unsubscribe(waker);
+        }
+
+        self.ready.lock().remove(&key);
+
+        Ok(())
+    }
+
+    /// Look at the files that may be ready, filling `events` with those that are
+    fn collect(&self, events: &mut [EpollEvent]) -> usize {
+        let mut interests = self.interests.lock();
+
+        let mut candidates: Vec<Key> = core::mem::take(&mut *self.ready.lock())
+            .into_iter()
+            .collect();
+
+        candidates.extend(
+            interests
+                .iter()
+                .filter(|(_, interest)| interest.waker.is_none())
+                .map(|(&key, _)| key),
+        );
+
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let mut count = 0;
+        let mut still_ready = Vec::new();
+
+        for key in candidates {
+            let Some(interest) = interests.get_mut(&key) else {
+                continue;
+            };
+
+            // Out of room, it waits for the next call
+            if count == events.len() {
+                still_ready.push(key);
+                continue;
+            }
+
+            if interest.disabled {
+                continue;
+            }
+
+            let revents = interest.file.poll() & (interest.events | ALWAYS_REPORTED);
+
+            if revents.is_empty() {
+                continue;
+            }
+
+            events[count] = EpollEvent {
+                events: revents,
+                data: interest.data,
+            };
+            count += 1;
+
+            if interest.flags.contains(EpollFlags::ONE_SHOT) {
+                interest.disabled = true;
+            } else if !interest.flags.contains(EpollFlags::EDGE_TRIGGERED) {
+                still_ready.push(key);
+            }
+        }
+
+        self.ready.lock().extend(still_ready);
+
+        count
+    }
+
+    /// Wait until a file is ready, returning how many were put in `events`, zero means the
+    /// timeout passed first
+    pub fn wait(&self, events: &mut [EpollEvent], timeout_ns: Option<u64>) -> usize {
+        if events.is_empty() {
+            return 0;
+        }
+
+        let deadline = timeout_ns.map(|timeout| time::monotonic_ns() + timeout);
+
+        wait::until(deadline, || {
+            let count = self.collect(events);
+
+            (count != 0).then_some(count)
+        })
+        .unwrap_or(0)
+    }
+}
+
+impl Default for Epoll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        for interest in self.interests.lock().values() {
+            if let (Some(wait_queue), Some(waker)) = (interest.file.wait_queue(), interest.waker) {
+                wait_queue.unsubscribe(waker);
+            }
+        }
+    }
+}
+
+impl File for Epoll {
+    /// Readable when one of the files is, so epoll instances nest
+    fn poll(&self) -> Events {
+        let interests = self.interests.lock();
+
+        let ready = interests.values().any(|interest| {
+            !interest.disabled
+                && interest
+                    .file
+                    .poll()
+                    .intersects(interest.events | ALWAYS_REPORTED)
+        });
+
+        if ready {
+            Events::READABLE
+        } else {
+            Events::empty()
+        }
+    }
+}
@@ -0,0 +1,849 @@
+//! Processes and the threads running in them
+//!
+//! There is no user mode, scheduler or syscall entry yet, so nothing runs a thread. These are the
+//! objects the syscalls will work on, and [`clone`], [`set_tid_address`] and [`exit`] do what the
+//! syscalls of the same name do for the thread [`switch_to`] made current. A process has the
+//! image its threads run and a table of the files it has open, which `CLONE_VM` and `CLONE_FILES`
+//! share with what is created instead of copying them
+//!
+//! Processes are in a process group, and the group in a session, which they inherit from the
+//! process that created them until they move with [`setpgid`] or [`setsid`]. A session can have a
+//! controlling terminal, hung up when the process leading the session exits
+//!
+//! A thread is charged with the processor time from when [`switch_to`] made it current until it
+//! stopped being current, which is what `/proc/stat` and `/proc/<pid>/stat` report. Nothing
+//! blocks a thread without a scheduler, so one that is not current is ready to run, and how long
+//! it waited for that is kept along with how often it was made current for `/proc/<pid>/sched`
+//!
+//! There is only one address space, so an image can not be copied into a new one like fork does.
+//! New processes either come from [`spawn`] with an image of their own, or share the image of the
+//! process that cloned them
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use bitflags::bitflags;
+
+use crate::{
+    arch::{TrapFrame, UserRegisters, cpu},
+    cred::Credentials,
+    exec::Image,
+    file::File,
+    futex,
+    mmap::{self, Protection},
+    ptrace,
+    rlimit::{self, Limit, Resource},
+    signal::{self, Signal},
+    strace,
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+    tty::Tty,
+    wait,
+};
+
+/// Process and thread ids, which are the same for a process and its first thread
+pub type Pid = u32;
+
+bitflags! {
+    /// The `CLONE_*` flags of clone
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct CloneFlags: u64 {
+        /// Share the image, which every clone has to as there is only one address space
+        const VM = 0x100;
+        const FILES = 0x400;
+        /// Create a thread in the same process instead of a new process
+        const THREAD = 0x10000;
+        /// Start the new thread with `tls` as its thread pointer
+        const SETTLS = 0x80000;
+        /// Write the new thread's id to `parent_tid`
+        const PARENT_SETTID = 0x100000;
+        /// Clear the word at `child_tid` and wake a futex on it when the new thread exits
+        const CHILD_CLEARTID = 0x200000;
+        /// Write the new thread's id to `child_tid`
+        const CHILD_SETTID = 0x1000000;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no current thread to do it for
+    NoThread,
+    NotFound,
+    InvalidArgument,
+    /// An address passed is not mapped for what it is used for
+    Fault,
+    /// It needs an address space of its own
+    NotSupported,
+    PermissionDenied,
+    /// A signal was sent that would interrupt it
+    Interrupted,
+    /// No descriptor is free below the limit of open files
+    TooManyFiles,
+}
+
+/// The files a process has open, by descriptor
+pub struct Files {
+    table: Mutex<Vec<Option<Arc<dyn File>>>>,
+}
+
+impl Files {
+    pub const fn new() -> Self {
+        Self {
+            table: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Open `file` at the lowest descriptor free below `limit`, which is returned
+    pub fn install(&self, file: Arc<dyn File>, limit: u64) -> Result<usize, Error> {
+        let mut table = self.table.lock();
+
+        let fd = table
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(table.len());
+
+        if fd as u64 >= limit {
+            return Err(Error::TooManyFiles);
+        }
+
+        match table.get_mut(fd) {
+            Some(slot) => *slot = Some(file),
+            None => table.push(Some(file)),
+        }
+
+        Ok(fd)
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.table.lock().get(fd)?.clone()
+    }
+
+    /// Returns the file that was open at `fd`
+    pub fn close(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.table.lock().get_mut(fd)?.take()
+    }
+
+    /// A table with the same files open at the same descriptors
+    fn duplicate(&self) -> Files {
+        Files {
+            table: Mutex::new(self.table.lock().clone()),
+        }
+    }
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which system calls a process makes, by their numbers and the layout of what they are given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Personality {
+    Native,
+    /// Those of x86_64 Linux, handled by [`linux::syscall`](crate::linux::syscall)
+    Linux,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Sleeping,
+    Zombie,
+}
+
+impl State {
+    /// The letter `ps` shows it as
+    pub fn letter(self) -> char {
+        match self {
+            State::Running => 'R',
+            State::Sleeping => 'S',
+            State::Zombie => 'Z',
+        }
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    /// Zero for processes spawned without a current thread
+    parent: Pid,
+    pgid: AtomicU32,
+    sid: AtomicU32,
+    credentials: Mutex<Credentials>,
+    personality: Mutex<Personality>,
+    tty: Mutex<Option<Arc<Tty>>>,
+    /// The signals sent to it, a bit for each
+    pending_signals: AtomicU64,
+    limits: Mutex<[Limit; Resource::ALL.len()]>,
+    /// The processor time its threads were charged with
+    cpu_time_ns: AtomicU64,
+    started_ns: u64,
+    /// What its threads run and have open, given up when the last of them exits
+    memory: Mutex<Option<Arc<Mutex<Image>>>>,
+    files: Mutex<Option<Arc<Files>>>,
+    threads: Mutex<Vec<Pid>>,
+    exit_status: Mutex<Option<i32>>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Pid {
+        self.parent
+    }
+
+    pub fn memory(&self) -> Option<Arc<Mutex<Image>>> {
+        self.memory.lock().clone()
+    }
+
+    pub fn files(&self) -> Option<Arc<Files>> {
+        self.files.lock().clone()
+    }
+
+    /// The ids of the threads it has left
+    pub fn threads(&self) -> Vec<Pid> {
+        self.threads.lock().clone()
+    }
+
+    /// When it was created, in nanoseconds since boot
+    pub fn started_ns(&self) -> u64 {
+        self.started_ns
+    }
+
+    /// Running while one of its threads is current, a zombie once it exited until it is waited
+    /// for, and sleeping otherwise
+    pub fn state(&self) -> State {
+        if self.exit_status().is_some() {
+            return State::Zombie;
+        }
+
+        match current() {
+            Some(current) if current.process.pid == self.pid => State::Running,
+            _ => State::Sleeping,
+        }
+    }
+
+    /// What it exited with, once its last thread did
+    pub fn exit_status(&self) -> Option<i32> {
+        *self.exit_status.lock()
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        self.credentials.lock().clone()
+    }
+
+    pub fn set_credentials(&self, credentials: Credentials) {
+        *self.credentials.lock() = credentials;
+    }
+
+    pub fn personality(&self) -> Personality {
+        *self.personality.lock()
+    }
+
+    pub fn set_personality(&self, personality: Personality) {
+        *self.personality.lock() = personality;
+    }
+
+    pub fn pgid(&self) -> Pid {
+        self.pgid.load(Ordering::Relaxed)
+    }
+
+    pub fn sid(&self) -> Pid {
+        self.sid.load(Ordering::Relaxed)
+    }
+
+    /// The controlling terminal, if it still is the one of the process's session
+    pub fn tty(&self) -> Option<Arc<Tty>> {
+        self.tty
+            .lock()
+            .clone()
+            .filter(|tty| tty.session() == self.sid())
+    }
+
+    pub fn set_tty(&self, tty: Option<Arc<Tty>>) {
+        *self.tty.lock() = tty;
+    }
+
+    pub fn send_signal(&self, signal: Signal) {
+        self.pending_signals
+            .fetch_or(signal.bit(), Ordering::Relaxed);
+    }
+
+    pub fn pending_signals(&self) -> u64 {
+        self.pending_signals.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self, resource: Resource) -> Limit {
+        self.limits.lock()[resource.index()]
+    }
+
+    /// Set a limit without checking it, which the address space is held to right away and the
+    /// stack from the next image
+    pub fn set_limit(&self, resource: Resource, limit: Limit) {
+        self.limits.lock()[resource.index()] = limit;
+
+        if resource == Resource::AddressSpace
+            && let Some(memory) = self.memory()
+        {
+            memory.lock().set_address_space_limit(limit.soft);
+        }
+    }
+
+    pub fn cpu_time_ns(&self) -> u64 {
+        self.cpu_time_ns.load(Ordering::Relaxed)
+    }
+
+    /// Charge the process with `ns` of processor time, sending it `SIGXCPU` every second it runs
+    /// past its soft limit and `SIGKILL` once past its hard one
+    pub fn charge_cpu_time(&self, ns: u64) {
+        let before = self.cpu_time_ns.fetch_add(ns, Ordering::Relaxed);
+        let seconds = (before + ns) / NANOSECONDS_PER_SECOND;
+        let limit = self.limit(Resource::Cpu);
+
+        if limit.hard != rlimit::INFINITY && seconds >= limit.hard {
+            self.send_signal(Signal::Kill);
+        } else if limit.soft != rlimit::INFINITY
+            && seconds >= limit.soft
+            && seconds > before / NANOSECONDS_PER_SECOND
+        {
+            self.send_signal(Signal::CpuTimeExceeded);
+        }
+    }
+
+    /// Take the lowest numbered of the signals pending
+    pub fn take_signal(&self) -> Option<Signal> {
+        let signal = Signal::ALL
+            .into_iter()
+            .find(|signal| self.pending_signals() & signal.bit() != 0)?;
+
+        self.pending_signals
+            .fetch_and(!signal.bit(), Ordering::Relaxed);
+
+        Some(signal)
+    }
+}
+
+/// How a thread was run, with the delays between it being ready to run and being made current
+#[derive(Debug, Clone, Copy)]
+pub struct SchedStats {
+    pub runtime_ns: u64,
+    /// Times it was made current
+    pub switches: u64,
+    pub max_delay_ns: u64,
+    pub total_delay_ns: u64,
+}
+
+impl SchedStats {
+    pub fn average_delay_ns(&self) -> u64 {
+        self.total_delay_ns / self.switches.max(1)
+    }
+}
+
+pub struct Thread {
+    tid: Pid,
+    process: Arc<Process>,
+    /// What the FS base is while the thread runs, pointing at its thread local storage
+    thread_pointer: AtomicU64,
+    /// Where a word is cleared and a futex on it woken when the thread exits, zero for nowhere
+    clear_child_tid: AtomicU64,
+    /// The stack pointer it starts with, zero to keep the one of the thread it was cloned from
+    stack: u64,
+    /// What it had in its registers when it last left user mode
+    registers: Mutex<UserRegisters>,
+    cpu_time_ns: AtomicU64,
+    /// When it was created or last stopped being current
+    ready_at: AtomicU64,
+    switches: AtomicU64,
+    max_delay_ns: AtomicU64,
+    total_delay_ns: AtomicU64,
+}
+
+impl Thread {
+    pub fn tid(&self) -> Pid {
+        self.tid
+    }
+
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    pub fn thread_pointer(&self) -> u64 {
+        self.thread_pointer.load(Ordering::Relaxed)
+    }
+
+    pub fn set_thread_pointer(&self, thread_pointer: u64) {
+        self.thread_pointer.store(thread_pointer, Ordering::Relaxed);
+
+        if current().is_some_and(|current| current.tid == self.tid) {
+            cpu::set_fs_base(thread_pointer);
+        }
+    }
+
+    pub fn stack(&self) -> u64 {
+        self.stack
+    }
+
+    /// The processor time it was charged with, up to the last time [`account`] ran
+    pub fn cpu_time_ns(&self) -> u64 {
+        self.cpu_time_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn sched_stats(&self) -> SchedStats {
+        SchedStats {
+            runtime_ns: self.cpu_time_ns(),
+            switches: self.switches.load(Ordering::Relaxed),
+            max_delay_ns: self.max_delay_ns.load(Ordering::Relaxed),
+            total_delay_ns: self.total_delay_ns.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn registers(&self) -> UserRegisters {
+        let mut registers = *self.registers.lock();
+
+        registers.set_thread_pointer(self.thread_pointer());
+
+        registers
+    }
+
+    /// Change the registers it goes back to user mode with, which is also its thread pointer
+    pub fn set_registers(&self, registers: UserRegisters) {
+        *self.registers.lock() = registers;
+
+        self.set_thread_pointer(registers.thread_pointer());
+    }
+}
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+static THREADS: Mutex<BTreeMap<Pid, Arc<Thread>>> = Mutex::new(BTreeMap::new());
+
+static CURRENT: Mutex<Option<Arc<Thread>>> = Mutex::new(None);
+
+/// When the current thread was last charged with the time it ran
+static CHARGED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// The processor time every thread was charged with, and how many times the current thread
+/// changed
+static CPU_TIME_NS: AtomicU64 = AtomicU64::new(0);
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+pub fn all() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+pub fn thread(tid: Pid) -> Option<Arc<Thread>> {
+    THREADS.lock().get(&tid).cloned()
+}
+
+/// The thread whose syscalls are being handled
+pub fn current() -> Option<Arc<Thread>> {
+    CURRENT.lock().clone()
+}
+
+/// Charge the thread in `current` and its process with the time since it last was
+fn charge(current: &Option<Arc<Thread>>) {
+    let now = time::monotonic_ns();
+    let ns = now.saturating_sub(CHARGED_AT.swap(now, Ordering::Relaxed));
+
+    if let Some(thread) = current {
+        thread.cpu_time_ns.fetch_add(ns, Ordering::Relaxed);
+        thread.process.charge_cpu_time(ns);
+        CPU_TIME_NS.fetch_add(ns, Ordering::Relaxed);
+    }
+}
+
+/// Bring the processor time of the current thread up to date, for those about to read it, unless
+/// what was interrupted is changing the current thread
+pub fn account() {
+    if let Some(current) = CURRENT.try_lock() {
+        charge(&current);
+    }
+}
+
+/// The processor time every thread was charged with
+pub fn cpu_time_ns() -> u64 {
+    CPU_TIME_NS.load(Ordering::Relaxed)
+}
+
+/// How many times [`switch_to`] changed the current thread
+pub fn switches() -> u64 {
+    SWITCHES.load(Ordering::Relaxed)
+}
+
+/// How many processes and threads were ever created
+pub fn created() -> u64 {
+    NEXT_ID.load(Ordering::Relaxed) as u64 - 1
+}
+
+/// Make `thread` the current thread, with its thread pointer in the FS base, charging the one
+/// before with the time it ran
+pub fn switch_to(thread: Option<Arc<Thread>>) {
+    cpu::set_fs_base(thread.as_ref().map_or(0, |thread| thread.thread_pointer()));
+
+    let mut current = CURRENT.lock();
+
+    charge(&current);
+
+    if current.as_ref().map(|current| current.tid) != thread.as_ref().map(|thread| thread.tid) {
+        let now = time::monotonic_ns();
+
+        SWITCHES.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(before) = current.as_ref() {
+            before.ready_at.store(now, Ordering::Relaxed);
+        }
+
+        if let Some(thread) = thread.as_ref() {
+            let delay = now.saturating_sub(thread.ready_at.load(Ordering::Relaxed));
+
+            thread.switches.fetch_add(1, Ordering::Relaxed);
+            thread.max_delay_ns.fetch_max(delay, Ordering::Relaxed);
+            thread.total_delay_ns.fetch_add(delay, Ordering::Relaxed);
+        }
+    }
+
+    *current = thread;
+}
+
+fn add_thread(
+    process: &Arc<Process>,
+    tid: Pid,
+    registers: UserRegisters,
+    stack: u64,
+) -> Arc<Thread> {
+    let thread = Arc::new(Thread {
+        tid,
+        process: process.clone(),
+        thread_pointer: AtomicU64::new(registers.thread_pointer()),
+        clear_child_tid: AtomicU64::new(0),
+        stack,
+        registers: Mutex::new(registers),
+        cpu_time_ns: AtomicU64::new(0),
+        ready_at: AtomicU64::new(time::monotonic_ns()),
+        switches: AtomicU64::new(0),
+        max_delay_ns: AtomicU64::new(0),
+        total_delay_ns: AtomicU64::new(0),
+    });
+
+    process.threads.lock().push(tid);
+    THREADS.lock().insert(tid, thread.clone());
+
+    thread
+}
+
+/// A process in the group and session of its parent and with its credentials, or leading a new
+/// session of its own as the superuser
+fn add_process(
+    pid: Pid,
+    parent: Option<&Process>,
+    memory: Arc<Mutex<Image>>,
+    files: Arc<Files>,
+) -> Arc<Process> {
+    let process = Arc::new(Process {
+        pid,
+        parent: parent.map_or(0, |parent| parent.pid),
+        pgid: AtomicU32::new(parent.map_or(pid, |parent| parent.pgid())),
+        sid: AtomicU32::new(parent.map_or(pid, |parent| parent.sid())),
+        credentials: Mutex::new(parent.map_or(Credentials::ROOT, |parent| parent.credentials())),
+        personality: Mutex::new(parent.map_or(Personality::Native, |parent| parent.personality())),
+        tty: Mutex::new(parent.and_then(|parent| parent.tty())),
+        pending_signals: AtomicU64::new(0),
+        limits: Mutex::new(parent.map_or(rlimit::DEFAULTS, |parent| *parent.limits.lock())),
+        cpu_time_ns: AtomicU64::new(0),
+        started_ns: time::monotonic_ns(),
+        memory: Mutex::new(Some(memory)),
+        files: Mutex::new(Some(files)),
+        threads: Mutex::new(Vec::new()),
+        exit_status: Mutex::new(None),
+    });
+
+    PROCESSES.lock().insert(pid, process.clone());
+
+    process
+}
+
+/// A new process running `image`, a child of the current one if there is one, returns its first
+/// thread to start at `stack`
+pub fn spawn(image: Image, stack: u64) -> Arc<Thread> {
+    let pid = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let current = current();
+    let registers = UserRegisters::starting_at(image.start_address(), stack);
+
+    let process = add_process(
+        pid,
+        current.as_ref().map(|current| &*current.process),
+        Arc::new(Mutex::new(image)),
+        Arc::new(Files::new()),
+    );
+
+    add_thread(&process, pid, registers, stack)
+}
+
+fn word(address: u64) -> Result<&'static AtomicU32, Error> {
+    if !address.is_multiple_of(4) || !mmap::is_mapped(address, 4, Protection::WRITE) {
+        return Err(Error::Fault);
+    }
+
+    Ok(unsafe { &*(address as *const AtomicU32) })
+}
+
+/// Create a thread like the current one that starts at `stack`, in the current process with
+/// `CloneFlags::THREAD` and in a new one otherwise, returns its id
+pub fn clone(
+    flags: CloneFlags,
+    stack: u64,
+    parent_tid: u64,
+    child_tid: u64,
+    tls: u64,
+) -> Result<Pid, Error> {
+    let current = current().ok_or(Error::NoThread)?;
+
+    if !flags.contains(CloneFlags::VM) {
+        return Err(Error::NotSupported);
+    }
+
+    // Files are per process, so threads share them
+    if flags.contains(CloneFlags::THREAD) && !flags.contains(CloneFlags::FILES) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let parent_tid = match flags.contains(CloneFlags::PARENT_SETTID) {
+        true => Some(word(parent_tid)?),
+        false => None,
+    };
+
+    let child_tid_word = match flags.contains(CloneFlags::CHILD_SETTID) {
+        true => Some(word(child_tid)?),
+        false => None,
+    };
+
+    let tid = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let process = match flags.contains(CloneFlags::THREAD) {
+        true => current.process.clone(),
+        false => {
+            let memory = current.process.memory().ok_or(Error::NoThread)?;
+            let files = current.process.files().ok_or(Error::NoThread)?;
+
+            let files = match flags.contains(CloneFlags::FILES) {
+                true => files,
+                false => Arc::new(files.duplicate()),
+            };
+
+            add_process(tid, Some(&current.process), memory, files)
+        }
+    };
+
+    // The child returns zero from clone where the current thread is, on its own stack if given
+    let mut registers = current.registers();
+
+    registers.set_return_value(0);
+
+    if stack != 0 {
+        registers.set_stack_pointer(stack);
+    }
+
+    if flags.contains(CloneFlags::SETTLS) {
+        registers.set_thread_pointer(tls);
+    }
+
+    let thread = add_thread(&process, tid, registers, stack);
+
+    if flags.contains(CloneFlags::CHILD_CLEARTID) {
+        thread.clear_child_tid.store(child_tid, Ordering::Relaxed);
+    }
+
+    for word in parent_tid.into_iter().chain(child_tid_word) {
+        word.store(tid, Ordering::SeqCst);
+    }
+
+    Ok(tid)
+}
+
+/// Have the word at `address` cleared and a futex on it woken when the current thread exits,
+/// returns its id
+pub fn set_tid_address(address: u64) -> Result<Pid, Error> {
+    let current = current().ok_or(Error::NoThread)?;
+
+    current.clear_child_tid.store(address, Ordering::Relaxed);
+
+    Ok(current.tid)
+}
+
+/// Take `thread` out of its process, returns whether it was the last thread in it
+fn remove_thread(thread: &Thread) -> bool {
+    // This is how thread libraries find out a thread they wait to join is gone
+    let address = thread.clear_child_tid.load(Ordering::Relaxed);
+
+    if address != 0
+        && let Ok(word) = word(address)
+    {
+        word.store(0, Ordering::SeqCst);
+        futex::wake(address, 1);
+    }
+
+    THREADS.lock().remove(&thread.tid);
+    ptrace::thread_exited(thread.tid);
+
+    let mut threads = thread.process.threads.lock();
+
+    threads.retain(|&tid| tid != thread.tid);
+    threads.is_empty()
+}
+
+/// End every thread of the current process and the process with `status`, like `exit_group`
+pub fn exit_group(status: i32) {
+    let Some(current) = current() else {
+        return;
+    };
+
+    let others: Vec<Arc<Thread>> = current
+        .process
+        .threads
+        .lock()
+        .iter()
+        .filter(|&&tid| tid != current.tid)
+        .filter_map(|&tid| thread(tid))
+        .collect();
+
+    for other in others {
+        remove_thread(&other);
+    }
+
+    exit(status);
+}
+
+/// End the current thread, and its process with `status` if it was the last thread in it
+pub fn exit(status: i32) {
+    let thread = {
+        let mut current = CURRENT.lock();
+
+        charge(&current);
+        current.take()
+    };
+
+    let Some(thread) = thread else {
+        return;
+    };
+
+    cpu::set_fs_base(0);
+
+    let process = &thread.process;
+
+    if !remove_thread(&thread) {
+        return;
+    }
+
+    // Let go of outside the locks, unmapping the image writes its shared mappings back
+    let memory = process.memory.lock().take();
+    let files = process.files.lock().take();
+
+    drop(memory);
+    drop(files);
+
+    if process.sid() == process.pid()
+        && let Some(tty) = process.tty()
+    {
+        tty.hangup();
+    }
+
+    process.set_tty(None);
+    strace::untrace(process.pid);
+    ptrace::tracer_exited(process.pid);
+
+    *process.exit_status.lock() = Some(status);
+
+    if process.parent != 0 {
+        let _ = signal::send(process.parent, Signal::Child);
+    }
+}
+
+/// Wait for the child `pid` of the current process to exit, returns what it exited with once it
+/// is gone
+pub fn wait(pid: Pid) -> Result<i32, Error> {
+    let current = current().ok_or(Error::NoThread)?;
+    let process = get(pid).ok_or(Error::NotFound)?;
+
+    if process.parent != current.process.pid {
+        return Err(Error::NotFound);
+    }
+
+    let status = wait::until(None, || process.exit_status()).ok_or(Error::NotFound)?;
+
+    PROCESSES.lock().remove(&pid);
+
+    Ok(status)
+}
+
+/// The process `pid` or the current one for zero
+fn process_or_current(pid: Pid) -> Result<Arc<Process>, Error> {
+    match pid {
+        0 => Ok(current().ok_or(Error::NoThread)?.process.clone()),
+        pid => get(pid).ok_or(Error::NotFound),
+    }
+}
+
+pub fn getpgid(pid: Pid) -> Result<Pid, Error> {
+    Ok(process_or_current(pid)?.pgid())
+}
+
+pub fn getsid(pid: Pid) -> Result<Pid, Error> {
+    Ok(process_or_current(pid)?.sid())
+}
+
+/// Move the process `pid`, the current one or a child of it, to the group `pgid` of the same
+/// session, which is created if it is `pid`, zero for either is the process itself
+pub fn setpgid(pid: Pid, pgid: Pid) -> Result<(), Error> {
+    let current = current().ok_or(Error::NoThread)?.process.clone();
+    let process = process_or_current(pid)?;
+
+    let pgid = match pgid {
+        0 => process.pid,
+        pgid => pgid,
+    };
+
+    if process.pid != current.pid && process.parent != current.pid {
+        return Err(Error::NotFound);
+    }
+
+    // Session leaders stay in the group they lead
+    if process.sid() != current.sid() || process.sid() == process.pid {
+        return Err(Error::PermissionDenied);
+    }
+
+    let group_exists = all()
+        .iter()
+        .any(|other| other.pgid() == pgid && other.sid() == current.sid());
+
+    if pgid != process.pid && !group_exists {
+        return Err(Error::PermissionDenied);
+    }
+
+    process.pgid.store(pgid, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Start a new session without a controlling terminal with the current process leading it and a
+/// new group, returns the session's id
+pub fn setsid() -> Result<Pid, Error> {
+    let current = current().ok_or(Error::NoThread)?.process.clone();
+
+    // Its group would end up in two sessions
+    if all().iter().any(|process| process.pgid() == current.pid) {
+        return Err(Error::PermissionDenied);
+    }
+
+    current.pgid.store(current.pid, Ordering::Relaxed);
+    current.sid.store(current.pid, Ordering::Relaxed);
+    current.set_tty(None);
+
+    Ok(current.pid)
+}
@@ -1,5 +1,16 @@
 pub const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
 
+/// Set in the flags when the glyphs are followed by the characters each of them draws
+pub const PSF2_HAS_UNICODE_TABLE: u32 = 1;
+
+/// Ends the characters of a glyph in the unicode table
+const PSF2_SEPARATOR: u8 = 0xFF;
+/// Starts a sequence of characters drawn together by a glyph, like a letter and its accent
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+/// Marks an ASCII character no glyph draws
+const NO_GLYPH: u16 = u16::MAX;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Psf2Header {
     pub magic: [u8; 4],
@@ -23,6 +34,8 @@ pub struct Psf2Font<'a> {
     pub header: Psf2Header,
     /// Data without the header
     pub data: &'a [u8],
+    /// The glyph of each ASCII character, looked up once as they are drawn the most
+    ascii: [u16; 128],
 }
 
 impl Psf2Font<'_> {
@@ -44,9 +57,63 @@ impl Psf2Font<'_> {
 
         assert_eq!(header.magic, PSF2_MAGIC);
 
-        Psf2Font {
+        let mut font = Psf2Font {
             header,
             data: &data[32..data.len()],
+            ascii: [NO_GLYPH; 128],
+        };
+
+        for ch in 0..128u8 {
+            if let Some(index) = font.find_glyph(ch as char) {
+                font.ascii[ch as usize] = index as u16;
+            }
         }
+
+        font
+    }
+
+    /// The bitmap of the glyph at `index`
+    pub fn glyph(&self, index: u32) -> Option<&[u8]> {
+        let size = self.header.glyph_size as usize;
+        let start = index as usize * size;
+
+        (index < self.header.glyph_count).then(|| self.data.get(start..start + size))?
+    }
+
+    /// The index of the glyph that draws `ch`
+    pub fn glyph_index(&self, ch: char) -> Option<u32> {
+        match self.ascii.get(ch as usize) {
+            Some(&NO_GLYPH) => None,
+            Some(&index) => Some(index as u32),
+            None => self.find_glyph(ch),
+        }
+    }
+
+    /// Look `ch` up in the unicode table, or take it as the index of its glyph without one
+    fn find_glyph(&self, ch: char) -> Option<u32> {
+        if self.header.flags & PSF2_HAS_UNICODE_TABLE == 0 {
+            return ((ch as u32) < self.header.glyph_count).then_some(ch as u32);
+        }
+
+        let start = self.header.glyph_count as usize * self.header.glyph_size as usize;
+        let table = self.data.get(start..)?;
+
+        // Each glyph has its characters in UTF-8, then sequences it draws, then a separator
+        for (index, entry) in table.split(|&byte| byte == PSF2_SEPARATOR).enumerate() {
+            let characters = entry
+                .split(|&byte| byte == PSF2_START_SEQUENCE)
+                .next()
+                .unwrap_or_default();
+
+            let found = characters
+                .utf8_chunks()
+                .any(|chunk| chunk.valid().chars().any(|character| character == ch));
+
+            if found {
+                return Some(index as u32);
+            }
+        }
+
+        None
     }
 }
@@ -0,0 +1,240 @@
+//! Tracing threads for debuggers, the part of ptrace a simple debugger needs
+//!
+//! A process attaches to a thread of a process it may trace, which stops it. While it is stopped
+//! the tracer can read and write its memory and registers, and let it go on until it next traps
+//! or for one instruction with the trap flag set. Breakpoints and single steps in user mode stop
+//! the thread again with `SIGTRAP` and tell the tracer with `SIGCHLD`, then [`wait_stop`] says so
+//!
+//! There is no user mode or scheduler yet, so no thread traps or runs at all: whatever runs
+//! threads has to leave those that [`is_stopped`] says are stopped alone. Breakpoints are written
+//! into text that can not be written through a private copy of the page, like Linux does
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    arch::{TrapFrame, UserRegisters},
+    cred,
+    mmap::{self, Protection},
+    process::{self, Error, Pid, Thread},
+    signal::{self, Signal},
+    sync::Mutex,
+    wait,
+};
+
+struct Tracee {
+    tracer: Pid,
+    /// What it is stopped by, nothing while it runs
+    stop: Option<Signal>,
+    /// Whether [`wait_stop`] already returned the stop
+    reported: bool,
+}
+
+static TRACEES: Mutex<BTreeMap<Pid, Tracee>> = Mutex::new(BTreeMap::new());
+
+fn tracer() -> Result<Pid, Error> {
+    Ok(process::current().ok_or(Error::NoThread)?.process().pid())
+}
+
+/// Start tracing the thread `tid`, stopping it with `SIGSTOP` like `PTRACE_ATTACH`
+pub fn attach(tid: Pid) -> Result<(), Error> {
+    let tracer = tracer()?;
+    let thread = process::thread(tid).ok_or(Error::NotFound)?;
+
+    if thread.process().pid() == tracer || !cred::may_trace(&thread.process().credentials()) {
+        return Err(Error::PermissionDenied);
+    }
+
+    let mut tracees = TRACEES.lock();
+
+    if tracees.contains_key(&tid) {
+        return Err(Error::PermissionDenied);
+    }
+
+    tracees.insert(
+        tid,
+        Tracee {
+            tracer,
+            stop: Some(Signal::Stop),
+            reported: false,
+        },
+    );
+
+    Ok(())
+}
+
+/// The thread `tid` if the current process traces it and it is stopped
+fn stopped(tid: Pid) -> Result<Arc<Thread>, Error> {
+    let tracer = tracer()?;
+
+    let is_stopped = TRACEES
+        .lock()
+        .get(&tid)
+        .is_some_and(|tracee| tracee.tracer == tracer && tracee.stop.is_some());
+
+    match is_stopped {
+        true => process::thread(tid).ok_or(Error::NotFound),
+        false => Err(Error::NotFound),
+    }
+}
+
+/// Let the stopped thread `tid` go on, one instruction at a time with `single_step`, after
+/// `signal` is sent to its process unless it is `None`
+fn resume(tid: Pid, single_step: bool, signal: Option<Signal>) -> Result<(), Error> {
+    let thread = stopped(tid)?;
+    let mut registers = thread.registers();
+
+    registers.set_single_step(single_step);
+
+    thread.set_registers(registers);
+
+    if let Some(signal) = signal {
+        thread.process().send_signal(signal);
+    }
+
+    if let Some(tracee) = TRACEES.lock().get_mut(&tid) {
+        tracee.stop = None;
+        tracee.reported = false;
+    }
+
+    Ok(())
+}
+
+/// Let the stopped thread `tid` run until it next traps, like `PTRACE_CONT`
+pub fn cont(tid: Pid, signal: Option<Signal>) -> Result<(), Error> {
+    resume(tid, false, signal)
+}
+
+/// Let the stopped thread `tid` run one instruction, like `PTRACE_SINGLESTEP`
+pub fn single_step(tid: Pid, signal: Option<Signal>) -> Result<(), Error> {
+    resume(tid, true, signal)
+}
+
+/// Stop tracing the stopped thread `tid` and let it go on, like `PTRACE_DETACH`
+pub fn detach(tid: Pid, signal: Option<Signal>) -> Result<(), Error> {
+    cont(tid, signal)?;
+
+    TRACEES.lock().remove(&tid);
+
+    Ok(())
+}
+
+/// Read the word at `address` in the memory of the stopped thread `tid`, like `PTRACE_PEEKDATA`
+pub fn peek(tid: Pid, address: u64) -> Result<u64, Error> {
+    stopped(tid)?;
+
+    if !mmap::is_mapped(address, 8, Protection::READ) {
+        return Err(Error::Fault);
+    }
+
+    Ok(unsafe { (address as *const u64).read_unaligned() })
+}
+
+/// Write `value` to the word at `address` in the memory of the stopped thread `tid`, even where
+/// it can not write itself like its text, like `PTRACE_POKEDATA`
+pub fn poke(tid: Pid, address: u64, value: u64) -> Result<(), Error> {
+    stopped(tid)?;
+
+    if mmap::is_mapped(address, 8, Protection::WRITE) {
+        unsafe { (address as *mut u64).write_unaligned(value) };
+
+        return Ok(());
+    }
+
+    if !mmap::is_mapped(address, 8, Protection::READ) {
+        return Err(Error::Fault);
+    }
+
+    mmap::write_private(address, &value.to_le_bytes()).map_err(|_| Error::Fault)
+}
+
+pub fn get_registers(tid: Pid) -> Result<UserRegisters, Error> {
+    Ok(stopped(tid)?.registers())
+}
+
+/// Change the registers of the stopped thread `tid`, which have to stay in user mode
+pub fn set_registers(tid: Pid, registers: UserRegisters) -> Result<(), Error> {
+    if !registers.is_valid() {
+        return Err(Error::InvalidArgument);
+    }
+
+    stopped(tid)?.set_registers(registers);
+
+    Ok(())
+}
+
+/// Wait for the thread `tid` traced by the current process to stop, returns what stopped it
+pub fn wait_stop(tid: Pid) -> Result<Signal, Error> {
+    let tracer = tracer()?;
+
+    wait::until(None, || {
+        let mut tracees = TRACEES.lock();
+
+        let Some(tracee) = tracees
+            .get_mut(&tid)
+            .filter(|tracee| tracee.tracer == tracer)
+        else {
+            return Some(Err(Error::NotFound));
+        };
+
+        match tracee.stop {
+            Some(signal) if !tracee.reported => {
+                tracee.reported = true;
+
+                Some(Ok(signal))
+            }
+            _ => None,
+        }
+    })
+    .unwrap_or(Err(Error::NotFound))
+}
+
+/// Whether the thread `tid` is stopped by its tracer, and must not run
+pub fn is_stopped(tid: Pid) -> bool {
+    TRACEES
+        .lock()
+        .get(&tid)
+        .is_some_and(|tracee| tracee.stop.is_some())
+}
+
+/// Called on a breakpoint or single step trap in user mode, stops the current thread if it is
+/// traced and tells its tracer, returns whether it was. `saved` puts what the trap saved of the
+/// registers into the ones the tracer sees
+pub fn trap(saved: impl FnOnce(&mut UserRegisters)) -> bool {
+    let Some(thread) = process::current() else {
+        return false;
+    };
+
+    let Some(mut tracees) = TRACEES.try_lock() else {
+        return false;
+    };
+
+    let Some(tracee) = tracees.get_mut(&thread.tid()) else {
+        return false;
+    };
+
+    let mut registers = thread.registers();
+
+    saved(&mut registers);
+    thread.set_registers(registers);
+
+    tracee.stop = Some(Signal::Trap);
+    tracee.reported = false;
+
+    let tracer = tracee.tracer;
+
+    drop(tracees);
+
+    let _ = signal::send(tracer, Signal::Child);
+
+    true
+}
+
+/// Called as the thread `tid` exits, which is no longer traced
+pub fn thread_exited(tid: Pid) {
+    TRACEES.lock().remove(&tid);
+}
+
+/// Called as the process `pid` exits, which lets go of the threads it traced
+pub fn tracer_exited(pid: Pid) {
+    TRACEES.lock().retain(|_, tracee| tracee.tracer != pid);
+}
@@ -0,0 +1,247 @@
+//! A cryptographically secure random number generator, based on ChaCha20 and seeded from the
+//! hardware random number generator (when there is one) and timing entropy
+//!
+//! Every time random bytes are generated, the key is replaced with the first bytes of the stream,
+//! so output that was given out before can not be recovered from the current state
+//!
+//! When each interrupt came is mixed into a word of its own without taking a lock, as the
+//! interrupt may have come while the generator was in use, and the word is taken into the pool
+//! the next time bytes are generated. The block function is checked against the test vector of
+//! RFC 7539 before the generator is first seeded
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::{arch::cpu, sync::Mutex};
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+const BLOCK_SIZE: usize = 64;
+const KEY_WORDS: usize = 8;
+
+struct Generator {
+    key: [u32; KEY_WORDS],
+    pool: [u32; KEY_WORDS],
+    pool_index: usize,
+    pool_dirty: bool,
+}
+
+lazy_static! {
+    static ref GENERATOR: Mutex<Generator> = Mutex::new(Generator::seeded());
+}
+
+/// The times of the interrupts since bytes were last generated, mixed together
+static INTERRUPT_ENTROPY: AtomicU64 = AtomicU64::new(0);
+
+/// The key, the counter and nonce in the layout of this block function, and the block of RFC 7539
+/// section 2.3.2, whose 32-bit counter is 1 and 96-bit nonce is `00:00:00:09:00:00:00:4a:00:00:00:00`
+const TEST_COUNTER: u64 = 0x0900_0000_0000_0001;
+const TEST_NONCE: u64 = 0x4A00_0000;
+const TEST_BLOCK: [u8; BLOCK_SIZE] = [
+    0x10, 0xF1, 0xE7, 0xE4, 0xD1, 0x3B, 0x59, 0x15, 0x50, 0x0F, 0xDD, 0x1F, 0xA3, 0x20, 0x71, 0xC4,
+    0xC7, 0xD1, 0xF4, 0xC7, 0x33, 0xC0, 0x68, 0x03, 0x04, 0x22, 0xAA, 0x9A, 0xC3, 0xD4, 0x6C, 0x4E,
+    0xD2, 0x82, 0x64, 0x46, 0x07, 0x9F, 0xAA, 0x09, 0x14, 0xC2, 0xD7, 0x05, 0xD9, 0x8B, 0x02, 0xA2,
+    0xB5, 0x12, 0x9C, 0xD1, 0xDE, 0x16, 0x4E, 0xB9, 0xCB, 0xD0, 0x83, 0xE8, 0xA2, 0x50, 0x3C, 0x4E,
+];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function, with a 64-bit block counter and a 64-bit nonce
+fn chacha20_block(key: &[u32; KEY_WORDS], counter: u64, nonce: u64) -> [u8; BLOCK_SIZE] {
+    let mut state = [0; 16];
+
+    state[..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    state[14] = nonce as u32;
+    state[15] = (nonce >> 32) as u32;
+
+    let initial = state;
+
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0; BLOCK_SIZE];
+
+    for (i, word) in state.iter().enumerate() {
+        let word = word.wrapping_add(initial[i]);
+        block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+
+    block
+}
+
+/// Whether the block function gives the block of the test vector, its key is the bytes from 0 to 31
+fn self_test() -> bool {
+    let key: [u8; KEY_WORDS * 4] = core::array::from_fn(|index| index as u8);
+
+    chacha20_block(&words_from_bytes(&key), TEST_COUNTER, TEST_NONCE) == TEST_BLOCK
+}
+
+fn words_from_bytes(bytes: &[u8]) -> [u32; KEY_WORDS] {
+    let mut words = [0; KEY_WORDS];
+
+    for (word, bytes) in words.iter_mut().zip(bytes.as_chunks::<4>().0) {
+        *word = u32::from_le_bytes(*bytes);
+    }
+
+    words
+}
+
+impl Generator {
+    fn seeded() -> Self {
+        // Output from a broken block function would look random while being anything but
+        assert!(
+            self_test(),
+            "rand: ChaCha20 does not give the block of the RFC 7539 test vector"
+        );
+
+        let mut generator = Self {
+            key: [0; KEY_WORDS],
+            pool: [0; KEY_WORDS],
+            pool_index: 0,
+            pool_dirty: false,
+        };
+
+        let has_rdseed = cpu::has_rdseed();
+        let has_rdrand = cpu::has_rdrand();
+
+        if !has_rdseed && !has_rdrand {
+            println!("rand: no hardware random number generator, randomness relies on timing only");
+        }
+
+        for _ in 0..KEY_WORDS {
+            if has_rdseed && let Some(seed) = cpu::rdseed() {
+                generator.add_entropy(seed);
+            } else if has_rdrand && let Some(random) = cpu::rdrand() {
+                generator.add_entropy(random);
+            }
+
+            generator.add_entropy(cpu::rdtsc());
+        }
+
+        generator.reseed();
+
+        generator
+    }
+
+    fn add_entropy(&mut self, value: u64) {
+        for half in [value as u32, (value >> 32) as u32] {
+            let word = &mut self.pool[self.pool_index];
+            *word = word.rotate_left(7) ^ half;
+
+            self.pool_index = (self.pool_index + 1) % KEY_WORDS;
+        }
+
+        self.pool_dirty = true;
+    }
+
+    /// Mix the pool into the key, the pool is hashed together with the current key so entropy is
+    /// never lost even if the pool was controlled by an attacker
+    fn reseed(&mut self) {
+        let mut mixed = self.key;
+
+        for (key, pool) in mixed.iter_mut().zip(self.pool) {
+            *key ^= pool;
+        }
+
+        self.key = words_from_bytes(&chacha20_block(&mixed, 0, u64::MAX)[..KEY_WORDS * 4]);
+        self.pool = [0; KEY_WORDS];
+        self.pool_dirty = false;
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if cpu::has_rdrand()
+            && let Some(random) = cpu::rdrand()
+        {
+            self.add_entropy(random);
+        }
+
+        self.add_entropy(cpu::rdtsc());
+
+        let interrupts = INTERRUPT_ENTROPY.swap(0, Ordering::Relaxed);
+
+        if interrupts != 0 {
+            self.add_entropy(interrupts);
+        }
+
+        if self.pool_dirty {
+            self.reseed();
+        }
+
+        // The first half of the first block becomes the next key, the rest of the stream is output
+        let first = chacha20_block(&self.key, 0, 0);
+        let next_key = words_from_bytes(&first[..KEY_WORDS * 4]);
+
+        let (head, rest) = buf.split_at_mut(buf.len().min(BLOCK_SIZE - KEY_WORDS * 4));
+        head.copy_from_slice(&first[KEY_WORDS * 4..KEY_WORDS * 4 + head.len()]);
+
+        for (counter, chunk) in rest.chunks_mut(BLOCK_SIZE).enumerate() {
+            let block = chacha20_block(&self.key, counter as u64 + 1, 0);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+
+        self.key = next_key;
+    }
+}
+
+/// Mix a value that is hard to predict into the entropy pool
+pub fn add_entropy(value: u64) {
+    GENERATOR.lock().add_entropy(value);
+}
+
+/// Mix in when an interrupt on `vector` came, which is safe from interrupt handlers as it never
+/// waits for the generator
+pub fn add_interrupt_entropy(vector: u8) {
+    let value = cpu::rdtsc() ^ (vector as u64).rotate_right(8);
+    let mixed = INTERRUPT_ENTROPY.load(Ordering::Relaxed).rotate_left(7) ^ value;
+
+    // Another interrupt in between only means its time is lost
+    INTERRUPT_ENTROPY.store(mixed, Ordering::Relaxed);
+}
+
+/// Mix in bytes from a hardware random number generator, which are trusted to be random enough that
+/// the key is reseeded from them right away
+pub fn add_hardware_entropy(bytes: &[u8]) {
+    let mut generator = GENERATOR.lock();
+
+    for chunk in bytes.chunks(8) {
+        let mut value = [0; 8];
+        value[..chunk.len()].copy_from_slice(chunk);
+
+        generator.add_entropy(u64::from_le_bytes(value));
+    }
+
+    generator.reseed();
+}
+
+/// Fill `buf` with cryptographically secure random bytes
+pub fn fill(buf: &mut [u8]) {
+    GENERATOR.lock().fill(buf);
+}
+
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,107 @@
+//! Resource limits, how much of the system a process can take
+//!
+//! Each process has a soft limit it is held to and a hard limit the soft one can be raised to,
+//! both inherited from its parent. They are enforced where the resource is taken: the size of the
+//! stack and of the address space when [`exec`](crate::exec) maps an image and moves its break,
+//! open files when descriptors are installed and processor time as it is charged to the process
+//! by whatever runs its threads, sending `SIGXCPU` past the soft limit and `SIGKILL` past the hard
+
+use crate::process::{self, Error};
+
+/// No limit
+pub const INFINITY: u64 = u64::MAX;
+
+/// The `RLIMIT_*` resources that are limited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Processor time in seconds
+    Cpu = 0,
+    /// The size of the stack in bytes
+    Stack = 3,
+    /// One more than the highest file descriptor
+    OpenFiles = 7,
+    /// The size of the address space in bytes
+    AddressSpace = 9,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 4] = [
+        Resource::Cpu,
+        Resource::Stack,
+        Resource::OpenFiles,
+        Resource::AddressSpace,
+    ];
+
+    pub fn from_number(number: u32) -> Option<Resource> {
+        Self::ALL
+            .into_iter()
+            .find(|&resource| resource as u32 == number)
+    }
+
+    /// Where it is in a process's limits
+    pub fn index(self) -> usize {
+        Self::ALL
+            .iter()
+            .position(|&resource| resource == self)
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// What processes start with, the defaults of Linux
+pub const DEFAULTS: [Limit; 4] = [
+    Limit {
+        soft: INFINITY,
+        hard: INFINITY,
+    },
+    Limit {
+        soft: 8 * 1024 * 1024,
+        hard: INFINITY,
+    },
+    Limit {
+        soft: 1024,
+        hard: 4096,
+    },
+    Limit {
+        soft: INFINITY,
+        hard: INFINITY,
+    },
+];
+
+/// The limit of the current process, or the default one without a current process
+pub fn get(resource: Resource) -> Limit {
+    match process::current() {
+        Some(current) => current.process().limit(resource),
+        None => DEFAULTS[resource.index()],
+    }
+}
+
+pub fn getrlimit(resource: Resource) -> Result<Limit, Error> {
+    let current = process::current().ok_or(Error::NoThread)?;
+
+    Ok(current.process().limit(resource))
+}
+
+/// Change a limit of the current process, the soft one can not go past the hard one which only
+/// the superuser can raise
+pub fn setrlimit(resource: Resource, limit: Limit) -> Result<(), Error> {
+    let current = process::current().ok_or(Error::NoThread)?;
+    let process = current.process();
+
+    if limit.soft > limit.hard {
+        return Err(Error::InvalidArgument);
+    }
+
+    if limit.hard > process.limit(resource).hard && !process.credentials().is_superuser() {
+        return Err(Error::PermissionDenied);
+    }
+
+    process.set_limit(resource, limit);
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Data that is only written while booting, remapped read-only once boot is done
+//!
+//! The linker keeps it in pages of its own: what is declared with [`ro_after_init!`] like the
+//! descriptor tables, the table of exported symbols which is sorted at boot, and the tables of
+//! pointers the compiler puts in `.data.rel.ro` and the linker in `.got` as the bootloader has to
+//! relocate them, like vtables. Once [`protect`] ran a stray write to any of them faults instead
+//! of changing where the kernel jumps, and what still has to write to one goes through
+//! [`with_writable`]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    arch::paging::{PAGE_SIZE, PageTableFlags},
+    paging,
+};
+
+unsafe extern "C" {
+    static __ro_after_init_start: u8;
+    static __ro_after_init_end: u8;
+}
+
+static PROTECTED: AtomicBool = AtomicBool::new(false);
+
+/// Declare a static that is only written while booting, which must not have anything it
+/// changes after like atomics
+#[macro_export]
+macro_rules! ro_after_init {
+    ($(#[$attribute:meta])* $visibility:vis static $name:ident: $type:ty = $value:expr;) => {
+        $(#[$attribute])*
+        #[unsafe(link_section = ".data.ro_after_init")]
+        $visibility static $name: $type = $value;
+    };
+}
+
+fn pages() -> impl Iterator<Item = u64> {
+    let start = (&raw const __ro_after_init_start) as u64;
+    let end = (&raw const __ro_after_init_end) as u64;
+
+    (start..end).step_by(PAGE_SIZE as usize)
+}
+
+/// Make every page writable or read-only, returns how many of them could be
+fn set_writable(writable: bool) -> usize {
+    let mut changed = 0;
+
+    for page in pages() {
+        // Pages the bootloader mapped as part of a larger one have no entry of their own
+        let Some(entry) = paging::read_entry(page) else {
+            continue;
+        };
+
+        let entry = match writable {
+            true => entry | PageTableFlags::WRITABLE.bits(),
+            false => entry & !PageTableFlags::WRITABLE.bits(),
+        };
+
+        paging::write_entry(page, entry);
+
+        changed += 1;
+    }
+
+    changed
+}
+
+/// Remap everything only written while booting read-only, at the end of boot
+pub fn protect() {
+    let total = pages().count();
+    let protected = set_writable(false);
+
+    PROTECTED.store(true, Ordering::Relaxed);
+
+    if protected == total {
+        crate::log_ok!(
+            "ro_after_init",
+            "{} KiB made read-only",
+            total as u64 * PAGE_SIZE / 1024
+        );
+    } else {
+        crate::log_warn!(
+            "ro_after_init",
+            "only {} of {} pages could be made read-only",
+            protected,
+            total
+        );
+    }
+}
+
+/// Run `f`, which writes to something only written while booting, with it writable again
+pub fn with_writable<R>(f: impl FnOnce() -> R) -> R {
+    if !PROTECTED.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    set_writable(true);
+
+    let result = f();
+
+    set_writable(false);
+
+    result
+}
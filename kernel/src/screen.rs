@@ -1,7 +1,12 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
 use lazy_static::lazy_static;
 use limine::framebuffer::Framebuffer;
 
-use crate::requests::FRAMEBUFFER_REQUEST;
+use crate::{requests::FRAMEBUFFER_REQUEST, sync::Mutex};
 
 lazy_static! {
     pub static ref FRAMEBUFFER: Framebuffer<'static> = FRAMEBUFFER_REQUEST
@@ -35,15 +40,61 @@ impl Color {
     }
 }
 
-pub fn get_colors() -> &'static mut [Color] {
-    unsafe {
-        core::slice::from_raw_parts_mut(
-            FRAMEBUFFER.addr().cast::<Color>(),
-            (FRAMEBUFFER.width() * FRAMEBUFFER.height()) as usize,
-        )
+/// The framebuffer a display driver took over with, null while we draw to the one of the bootloader
+static ADDRESS: AtomicPtr<Color> = AtomicPtr::new(ptr::null_mut());
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+static HEIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets what was drawn onto the display, for framebuffers the display does not scan out itself
+static FLUSH: Mutex<Option<fn()>> = Mutex::new(None);
+
+pub fn width() -> usize {
+    if ADDRESS.load(Ordering::Relaxed).is_null() {
+        FRAMEBUFFER.width() as usize
+    } else {
+        WIDTH.load(Ordering::Relaxed)
     }
 }
 
+pub fn height() -> usize {
+    if ADDRESS.load(Ordering::Relaxed).is_null() {
+        FRAMEBUFFER.height() as usize
+    } else {
+        HEIGHT.load(Ordering::Relaxed)
+    }
+}
+
+pub fn get_colors() -> &'static mut [Color] {
+    let address = match ADDRESS.load(Ordering::Relaxed) {
+        address if address.is_null() => FRAMEBUFFER.addr().cast::<Color>(),
+        address => address,
+    };
+
+    unsafe { core::slice::from_raw_parts_mut(address, width() * height()) }
+}
+
 pub fn get_color(x: usize, y: usize) -> &'static mut Color {
-    &mut get_colors()[x + y * FRAMEBUFFER.width() as usize]
+    &mut get_colors()[x + y * width()]
+}
+
+/// Draw to another framebuffer from now on, the console must be resized after
+///
+/// # Safety
+///
+/// `address` must point to `width * height` colors that stay valid until the next switch
+pub unsafe fn set_framebuffer(address: *mut Color, width: usize, height: usize, flush: fn()) {
+    WIDTH.store(width, Ordering::Relaxed);
+    HEIGHT.store(height, Ordering::Relaxed);
+    ADDRESS.store(address, Ordering::Relaxed);
+
+    *FLUSH.lock() = Some(flush);
+}
+
+/// Make what was drawn visible
+pub fn flush() {
+    let flush = *FLUSH.lock();
+
+    if let Some(flush) = flush {
+        flush();
+    }
 }
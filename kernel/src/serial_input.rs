@@ -0,0 +1,244 @@
+//! Key events from what is typed on a terminal at the other end of the first serial port
+//!
+//! Terminals send characters and escape sequences rather than keys, so they are turned back into
+//! the [`input`] events a keyboard driver reports, shift and ctrl included, and everything that
+//! reads keys edits lines the same way over the serial port as on a local keyboard. Arrows, home,
+//! end, insert, delete and page up and down are decoded from both the `ESC [` and `ESC O` forms
+//! terminals send, and an escape that nothing follows for a while is the escape key
+//!
+//! The port is polled with the other devices
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::serial::SERIAL,
+    input::{
+        self, Event, KEY_BACKSPACE, KEY_DELETE, KEY_DOWN, KEY_END, KEY_ENTER, KEY_ESC, KEY_HOME,
+        KEY_INSERT, KEY_LEFT, KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_PAGEDOWN, KEY_PAGEUP, KEY_RIGHT,
+        KEY_TAB, KEY_UP,
+    },
+    sync::Mutex,
+    time,
+};
+
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+
+/// How long after an escape the rest of a sequence has to come, or it was the escape key
+const ESCAPE_TIMEOUT_NS: u64 = 50_000_000;
+
+/// How many bytes are taken from the port each time it is polled
+const POLL_LIMIT: usize = 64;
+
+/// A key to press and let go, with the modifiers to hold while it is
+#[derive(Debug, Clone, Copy)]
+struct Key {
+    code: u16,
+    shift: bool,
+    ctrl: bool,
+}
+
+impl Key {
+    const fn plain(code: u16) -> Self {
+        Self {
+            code,
+            shift: false,
+            ctrl: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Ground,
+    /// An escape came at this time
+    Escape(u64),
+    /// `ESC [` and the first number of the sequence so far
+    Csi(u16),
+    /// `ESC O`
+    Ss3,
+}
+
+struct Decoder {
+    state: State,
+    /// The last byte was a carriage return, which terminals may follow with a line feed
+    after_return: bool,
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder {
+    state: State::Ground,
+    after_return: false,
+});
+
+/// The key the final byte of an `ESC [` or `ESC O` sequence without a number stands for
+fn cursor_key(byte: u8) -> Option<u16> {
+    Some(match byte {
+        b'A' => KEY_UP,
+        b'B' => KEY_DOWN,
+        b'C' => KEY_RIGHT,
+        b'D' => KEY_LEFT,
+        b'H' => KEY_HOME,
+        b'F' => KEY_END,
+        _ => return None,
+    })
+}
+
+/// The key `ESC [ <number> ~` stands for
+fn tilde_key(number: u16) -> Option<u16> {
+    Some(match number {
+        1 | 7 => KEY_HOME,
+        2 => KEY_INSERT,
+        3 => KEY_DELETE,
+        4 | 8 => KEY_END,
+        5 => KEY_PAGEUP,
+        6 => KEY_PAGEDOWN,
+        _ => return None,
+    })
+}
+
+impl Decoder {
+    /// Take in a byte from the port, adding the keys it finished to `keys`
+    fn feed(&mut self, byte: u8, now: u64, keys: &mut Vec<Key>) {
+        let after_return = core::mem::replace(&mut self.after_return, false);
+
+        match self.state {
+            State::Ground => self.ground(byte, now, after_return, keys),
+            State::Escape(_) => match byte {
+                b'[' => self.state = State::Csi(0),
+                b'O' => self.state = State::Ss3,
+                _ => {
+                    // Not a sequence, so the escape was a key of its own
+                    keys.push(Key::plain(KEY_ESC));
+                    self.state = State::Ground;
+                    self.ground(byte, now, false, keys);
+                }
+            },
+            State::Csi(number) => match byte {
+                b'0'..=b'9' => {
+                    self.state = State::Csi(
+                        number
+                            .saturating_mul(10)
+                            .saturating_add((byte - b'0') as u16),
+                    );
+                }
+                // Parameters after the first one are modifiers, which are not kept
+                b';' => {}
+                b'~' => {
+                    keys.extend(tilde_key(number).map(Key::plain));
+                    self.state = State::Ground;
+                }
+                0x40..=0x7E => {
+                    keys.extend(cursor_key(byte).map(Key::plain));
+                    self.state = State::Ground;
+                }
+                _ => self.state = State::Ground,
+            },
+            State::Ss3 => {
+                keys.extend(cursor_key(byte).map(Key::plain));
+                self.state = State::Ground;
+            }
+        }
+    }
+
+    fn ground(&mut self, byte: u8, now: u64, after_return: bool, keys: &mut Vec<Key>) {
+        let key = match byte {
+            ESCAPE => {
+                self.state = State::Escape(now);
+                return;
+            }
+            b'\r' => {
+                self.after_return = true;
+                Key::plain(KEY_ENTER)
+            }
+            b'\n' if after_return => return,
+            b'\n' => Key::plain(KEY_ENTER),
+            DELETE | 0x08 => Key::plain(KEY_BACKSPACE),
+            b'\t' => Key::plain(KEY_TAB),
+            // Ctrl with a letter sends where the letter is in the alphabet
+            0x01..=0x1A => match input::key_for(b'a' + byte - 1) {
+                Some((code, _)) => Key {
+                    code,
+                    shift: false,
+                    ctrl: true,
+                },
+                None => return,
+            },
+            byte => match input::key_for(byte) {
+                Some((code, shift)) => Key {
+                    code,
+                    shift,
+                    ctrl: false,
+                },
+                None => return,
+            },
+        };
+
+        keys.push(key);
+    }
+
+    /// Take an escape nothing followed for long enough as the escape key
+    fn expire(&mut self, now: u64, keys: &mut Vec<Key>) {
+        if let State::Escape(since) = self.state
+            && now.saturating_sub(since) >= ESCAPE_TIMEOUT_NS
+        {
+            keys.push(Key::plain(KEY_ESC));
+            self.state = State::Ground;
+        }
+    }
+}
+
+/// Report `key` going down and up, with its modifiers held around it
+fn tap(key: Key) {
+    let modifiers = [(key.ctrl, KEY_LEFTCTRL), (key.shift, KEY_LEFTSHIFT)];
+
+    for (_, code) in modifiers.iter().filter(|(held, _)| *held) {
+        input::report(Event::Key {
+            code: *code,
+            pressed: true,
+        });
+    }
+
+    for pressed in [true, false] {
+        input::report(Event::Key {
+            code: key.code,
+            pressed,
+        });
+    }
+
+    for (_, code) in modifiers.iter().rev().filter(|(held, _)| *held) {
+        input::report(Event::Key {
+            code: *code,
+            pressed: false,
+        });
+    }
+}
+
+/// Turn what came in on the port into key events
+pub fn poll() {
+    let mut keys = Vec::new();
+
+    // Both are let go before the events are reported, as whoever reads them may use the port
+    {
+        let Some(mut decoder) = DECODER.try_lock() else {
+            return;
+        };
+
+        let now = time::monotonic_ns();
+
+        if let Some(mut serial) = SERIAL.try_lock() {
+            for _ in 0..POLL_LIMIT {
+                let Some(byte) = serial.try_read_byte() else {
+                    break;
+                };
+
+                decoder.feed(byte, now, &mut keys);
+            }
+        }
+
+        decoder.expire(now, &mut keys);
+    }
+
+    for key in keys {
+        tap(key);
+    }
+}
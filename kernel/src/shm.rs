@@ -0,0 +1,187 @@
+//! POSIX shared memory, named objects made of pages that everyone opening the name shares
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    dma::{self, CoherentBuffer, Constraints, PAGE_SIZE},
+    file::{self, Error, OpenFlags},
+    meminfo::{self, Category},
+    mmap::Mappable,
+    sync::Mutex,
+};
+
+struct Pages {
+    pages: Vec<CoherentBuffer>,
+    len: usize,
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        meminfo::uncharge(Category::PageCache, self.pages.len() * PAGE_SIZE);
+    }
+}
+
+/// A shared memory object, its pages are freed once it is unlinked and the last user is gone
+pub struct SharedMemory {
+    pages: Mutex<Pages>,
+}
+
+impl SharedMemory {
+    fn new() -> Self {
+        Self {
+            pages: Mutex::new(Pages {
+                pages: Vec::new(),
+                len: 0,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Grow with zeroed pages or shrink, like `ftruncate`
+    pub fn truncate(&self, len: usize) -> Result<(), Error> {
+        let mut pages = self.pages.lock();
+
+        let count = len.div_ceil(PAGE_SIZE);
+
+        while pages.pages.len() < count {
+            let page = dma::alloc_coherent(PAGE_SIZE, Constraints::new())
+                .map_err(|_| Error::OutOfMemory)?;
+
+            pages.pages.push(page);
+
+            meminfo::charge(Category::PageCache, PAGE_SIZE);
+        }
+
+        if pages.pages.len() > count {
+            meminfo::uncharge(Category::PageCache, (pages.pages.len() - count) * PAGE_SIZE);
+        }
+
+        pages.pages.truncate(count);
+
+        // What is past the end must read as zeroes if it grows again
+        if let Some(last) = pages.pages.last_mut()
+            && !len.is_multiple_of(PAGE_SIZE)
+        {
+            last.as_mut_slice()[len % PAGE_SIZE..].fill(0);
+        }
+
+        pages.len = len;
+
+        Ok(())
+    }
+
+    /// Copy from the object at `offset`, returning how much was there to copy
+    pub fn read_at(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let pages = self.pages.lock();
+
+        let len = buffer.len().min(pages.len.saturating_sub(offset));
+
+        for (i, byte) in buffer[..len].iter_mut().enumerate() {
+            let position = offset + i;
+
+            *byte = pages.pages[position / PAGE_SIZE].as_slice()[position % PAGE_SIZE];
+        }
+
+        len
+    }
+
+    /// Copy into the object at `offset`, which never grows it
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> usize {
+        let mut pages = self.pages.lock();
+
+        let len = data.len().min(pages.len.saturating_sub(offset));
+
+        for (i, &byte) in data[..len].iter().enumerate() {
+            let position = offset + i;
+
+            pages.pages[position / PAGE_SIZE].as_mut_slice()[position % PAGE_SIZE] = byte;
+        }
+
+        len
+    }
+
+    /// The physical address of a page, for mapping it into an address space
+    pub fn frame(&self, page: usize) -> Option<u64> {
+        self.pages
+            .lock()
+            .pages
+            .get(page)
+            .map(|page| page.bus_address())
+    }
+}
+
+// Its pages are mapped as they are, which truncating it while it is mapped pulls out from under
+// the mapping
+impl Mappable for SharedMemory {
+    fn size(&self) -> Result<u64, Error> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_page(&self, index: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let len = self.read_at(index as usize * PAGE_SIZE, buffer);
+
+        buffer[len..].fill(0);
+
+        Ok(())
+    }
+
+    fn write_page(&self, index: u64, data: &[u8]) -> Result<(), Error> {
+        self.write_at(index as usize * PAGE_SIZE, data);
+
+        Ok(())
+    }
+
+    fn frame(&self, index: u64) -> Option<u64> {
+        self.frame(index as usize)
+    }
+}
+
+static OBJECTS: Mutex<BTreeMap<String, Arc<SharedMemory>>> = Mutex::new(BTreeMap::new());
+
+/// Open the object with `name`, creating it empty with `OpenFlags::CREATE`
+pub fn shm_open(name: &str, flags: OpenFlags) -> Result<Arc<SharedMemory>, Error> {
+    file::validate_ipc_name(name)?;
+
+    let mut objects = OBJECTS.lock();
+
+    let object = match objects.get(name) {
+        Some(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(Error::AlreadyExists);
+        }
+        Some(object) => object.clone(),
+        None if flags.contains(OpenFlags::CREATE) => {
+            let object = Arc::new(SharedMemory::new());
+
+            objects.insert(name.into(), object.clone());
+
+            object
+        }
+        None => return Err(Error::NotFound),
+    };
+
+    drop(objects);
+
+    if flags.contains(OpenFlags::TRUNCATE) {
+        object.truncate(0)?;
+    }
+
+    Ok(object)
+}
+
+/// Remove the name, the object lives on until nobody uses it anymore
+pub fn shm_unlink(name: &str) -> Result<(), Error> {
+    file::validate_ipc_name(name)?;
+
+    OBJECTS
+        .lock()
+        .remove(name)
+        .map(|_| ())
+        .ok_or(Error::NotFound)
+}
@@ -0,0 +1,80 @@
+//! Signals sent to processes
+//!
+//! There is no user mode to run handlers in yet, so nothing delivers them or carries out what
+//! they do by default: a signal sent is kept pending on the process until whatever delivers
+//! signals takes it with [`Process::take_signal`](crate::process::Process::take_signal)
+
+use crate::process::{self, Error, Pid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Hangup = 1,
+    Interrupt = 2,
+    Quit = 3,
+    /// A breakpoint or single step of a traced thread
+    Trap = 5,
+    Kill = 9,
+    Terminate = 15,
+    Child = 17,
+    Continue = 18,
+    Stop = 19,
+    /// Stop from the terminal, the suspend character
+    TerminalStop = 20,
+    /// Reading from the terminal in the background
+    TerminalInput = 21,
+    /// Writing to the terminal in the background
+    TerminalOutput = 22,
+    /// Running past the soft limit of processor time
+    CpuTimeExceeded = 24,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 13] = [
+        Signal::Hangup,
+        Signal::Interrupt,
+        Signal::Quit,
+        Signal::Trap,
+        Signal::Kill,
+        Signal::Terminate,
+        Signal::Child,
+        Signal::Continue,
+        Signal::Stop,
+        Signal::TerminalStop,
+        Signal::TerminalInput,
+        Signal::TerminalOutput,
+        Signal::CpuTimeExceeded,
+    ];
+
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_number(number: u8) -> Option<Signal> {
+        Self::ALL
+            .into_iter()
+            .find(|signal| signal.number() == number)
+    }
+
+    /// The bit of the signal in a set of them
+    pub fn bit(self) -> u64 {
+        1 << (self.number() - 1)
+    }
+}
+
+/// Send `signal` to the process `pid`
+pub fn send(pid: Pid, signal: Signal) -> Result<(), Error> {
+    process::get(pid)
+        .ok_or(Error::NotFound)?
+        .send_signal(signal);
+
+    Ok(())
+}
+
+/// Send `signal` to every process in the group `pgid`, returns how many there were
+pub fn send_group(pgid: Pid, signal: Signal) -> usize {
+    process::all()
+        .into_iter()
+        .filter(|process| process.pgid() == pgid && process.exit_status().is_none())
+        .inspect(|process| process.send_signal(signal))
+        .count()
+}
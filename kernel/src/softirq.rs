@@ -0,0 +1,49 @@
+//! Work deferred out of the paths that notice it, run from the main loop
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Softirq {
+    NetRx,
+    Timer,
+}
+
+pub type Handler = fn();
+
+const COUNT: usize = 2;
+
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+static HANDLERS: Mutex<[Option<Handler>; COUNT]> = Mutex::new([None; COUNT]);
+
+pub fn register(softirq: Softirq, handler: Handler) {
+    HANDLERS.lock()[softirq as usize] = Some(handler);
+}
+
+/// Mark the softirq as pending, it runs the next time pending softirqs are run
+pub fn raise(softirq: Softirq) {
+    PENDING.fetch_or(1 << softirq as u32, Ordering::AcqRel);
+}
+
+pub fn run_pending() {
+    loop {
+        let pending = PENDING.swap(0, Ordering::AcqRel);
+
+        if pending == 0 {
+            break;
+        }
+
+        let handlers = *HANDLERS.lock();
+
+        for (i, handler) in handlers.iter().enumerate() {
+            if pending & (1 << i) != 0
+                && let Some(handler) = handler
+            {
+                handler();
+            }
+        }
+    }
+}
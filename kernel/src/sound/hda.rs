@@ -0,0 +1,727 @@
+//! Intel High Definition Audio controllers and the codecs behind them, playing PCM on the first
+//! output stream
+//!
+//! Codecs are talked to through the CORB and RIRB rings, and the output pins are wired to a
+//! converter by walking their connection lists
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{Format, PcmDevice};
+use crate::{
+    dma::{self, CoherentBuffer, Constraints},
+    mmio::Mmio,
+    pci::{self, Bar},
+    sync::Mutex,
+    time,
+};
+
+const CLASS_MULTIMEDIA: u8 = 0x04;
+const SUBCLASS_HDA: u8 = 0x03;
+
+const REG_GCAP: usize = 0x00;
+const REG_GCTL: usize = 0x08;
+const REG_STATESTS: usize = 0x0E;
+const REG_INTCTL: usize = 0x20;
+const REG_CORBLBASE: usize = 0x40;
+const REG_CORBUBASE: usize = 0x44;
+const REG_CORBWP: usize = 0x48;
+const REG_CORBRP: usize = 0x4A;
+const REG_CORBCTL: usize = 0x4C;
+const REG_CORBSIZE: usize = 0x4E;
+const REG_RIRBLBASE: usize = 0x50;
+const REG_RIRBUBASE: usize = 0x54;
+const REG_RIRBWP: usize = 0x58;
+const REG_RINTCNT: usize = 0x5A;
+const REG_RIRBCTL: usize = 0x5C;
+const REG_RIRBSTS: usize = 0x5D;
+const REG_RIRBSIZE: usize = 0x5E;
+
+const GCAP_64OK: u16 = 1 << 0;
+const GCTL_CRST: u32 = 1 << 0;
+
+const CORBRP_RESET: u16 = 1 << 15;
+const RIRBWP_RESET: u16 = 1 << 15;
+const RING_DMA_RUN: u8 = 1 << 1;
+/// The size select bits, and the bit of the capability for 256 entries
+const RING_SIZE_256: u8 = 2;
+const RING_SIZE_CAP_256: u8 = 1 << 6;
+const RING_ENTRIES: usize = 256;
+
+/// The stream descriptors, input ones first and then the output ones
+const STREAM_BASE: usize = 0x80;
+const STREAM_SIZE: usize = 0x20;
+
+const SD_CTL: usize = 0x00;
+const SD_STS: usize = 0x03;
+const SD_LPIB: usize = 0x04;
+const SD_CBL: usize = 0x08;
+const SD_LVI: usize = 0x0C;
+const SD_FMT: usize = 0x12;
+const SD_BDPL: usize = 0x18;
+const SD_BDPU: usize = 0x1C;
+
+const SD_CTL_SRST: u8 = 1 << 0;
+const SD_CTL_RUN: u8 = 1 << 1;
+
+/// The stream number the converters listen to, zero means none
+const STREAM_TAG: u8 = 1;
+
+const VERB_GET_PARAMETER: u32 = 0xF00;
+const VERB_GET_CONNECTION_LIST: u32 = 0xF02;
+const VERB_GET_CONFIG_DEFAULT: u32 = 0xF1C;
+const VERB_SET_CONNECTION_SELECT: u32 = 0x701;
+const VERB_SET_POWER_STATE: u32 = 0x705;
+const VERB_SET_CHANNEL_STREAM_ID: u32 = 0x706;
+const VERB_SET_PIN_WIDGET_CONTROL: u32 = 0x707;
+const VERB_SET_EAPD: u32 = 0x70C;
+/// The verbs with a 16 bit payload
+const VERB_SET_CONVERTER_FORMAT: u32 = 0x2;
+const VERB_SET_AMP_GAIN_MUTE: u32 = 0x3;
+
+const PARAM_NODE_COUNT: u8 = 0x04;
+const PARAM_FUNCTION_GROUP_TYPE: u8 = 0x05;
+const PARAM_AUDIO_WIDGET_CAP: u8 = 0x09;
+const PARAM_PIN_CAP: u8 = 0x0C;
+const PARAM_IN_AMP_CAP: u8 = 0x0D;
+const PARAM_CONNECTION_LIST_LEN: u8 = 0x0E;
+const PARAM_OUT_AMP_CAP: u8 = 0x12;
+
+const FUNCTION_GROUP_AUDIO: u32 = 1;
+
+const WIDGET_OUTPUT: u32 = 0;
+const WIDGET_MIXER: u32 = 2;
+const WIDGET_PIN: u32 = 4;
+
+const WIDGET_CAP_IN_AMP: u32 = 1 << 1;
+const WIDGET_CAP_OUT_AMP: u32 = 1 << 2;
+const WIDGET_CAP_AMP_OVERRIDE: u32 = 1 << 3;
+
+const PIN_CAP_OUTPUT: u32 = 1 << 4;
+const PIN_CAP_EAPD: u32 = 1 << 16;
+
+const PIN_CONTROL_OUT: u32 = 1 << 6;
+const PIN_CONTROL_HEADPHONE: u32 = 1 << 7;
+
+const EAPD_ENABLE: u32 = 1 << 1;
+
+const AMP_OUTPUT: u32 = 1 << 15;
+const AMP_INPUT: u32 = 1 << 14;
+const AMP_LEFT: u32 = 1 << 13;
+const AMP_RIGHT: u32 = 1 << 12;
+
+/// The port connectivity of the default configuration, where nothing is attached
+const CONNECTIVITY_NONE: u32 = 1;
+
+const DEVICE_LINE_OUT: u32 = 0;
+const DEVICE_SPEAKER: u32 = 1;
+const DEVICE_HEADPHONE: u32 = 2;
+
+/// How far a pin may be from its converter
+const MAX_PATH_LEN: usize = 5;
+
+const FORMAT: Format = Format {
+    rate: 48000,
+    channels: 2,
+    bits: 16,
+};
+
+/// 48 kHz, 16 bits per sample, and the channels minus one
+const FORMAT_BITS_16: u16 = 1 << 4;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const BUFFER_ENTRIES: usize = 4;
+
+/// How far ahead of the playing position new samples go after an underrun, so they are not
+/// written where the controller already fetched
+const LEAD: usize = 1024;
+
+const COMMAND_TIMEOUT_NS: u64 = 10_000_000;
+const RESET_TIMEOUT_NS: u64 = 100_000_000;
+/// How long codecs take to ask for an address after the link came out of reset
+const CODEC_WAKE_NS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    MissingBar,
+    ResetTimedOut,
+    OutOfMemory,
+    NoOutputStream,
+    /// A codec did not answer a verb
+    CommandTimedOut,
+    /// No codec has an output we can drive
+    NoOutput,
+    /// The rings can not have 256 entries
+    Unsupported,
+}
+
+#[derive(Debug, Clone)]
+struct Widget {
+    nid: u8,
+    caps: u32,
+    connections: Vec<u8>,
+}
+
+impl Widget {
+    fn ty(&self) -> u32 {
+        self.caps >> 20 & 0xF
+    }
+}
+
+struct Inner {
+    registers: Mmio,
+    stream: Mmio,
+    corb: CoherentBuffer,
+    rirb: CoherentBuffer,
+    /// Where the last response we read is in the RIRB
+    rirb_read: u16,
+    buffer: CoherentBuffer,
+    /// Where the next samples go in the buffer
+    write: usize,
+    /// How much of the buffer is ahead of the playing position
+    queued: usize,
+    /// The playing position at the last poll
+    played: usize,
+}
+
+impl Inner {
+    /// Send a verb to a node of a codec and wait for its response
+    fn command(&mut self, codec: u8, nid: u8, verb: u32, payload: u32) -> Result<u32, Error> {
+        // The 4 bit verbs take a 16 bit payload, the 12 bit ones an 8 bit one
+        let verb = if verb <= 0xF {
+            verb << 16 | payload & 0xFFFF
+        } else {
+            verb << 8 | payload & 0xFF
+        };
+
+        let command = (codec as u32) << 28 | (nid as u32) << 20 | verb;
+
+        let write = (self.registers.read::<u16>(REG_CORBWP) as usize + 1) % RING_ENTRIES;
+
+        unsafe {
+            self.corb
+                .as_ptr()
+                .cast::<u32>()
+                .add(write)
+                .write_volatile(command)
+        };
+
+        self.registers.write::<u16>(REG_CORBWP, write as u16);
+
+        let deadline = time::monotonic_ns() + COMMAND_TIMEOUT_NS;
+
+        loop {
+            let written = self.registers.read::<u16>(REG_RIRBWP) & 0xFF;
+
+            while self.rirb_read != written {
+                self.rirb_read = (self.rirb_read + 1) % RING_ENTRIES as u16;
+
+                let entry = unsafe {
+                    self.rirb
+                        .as_ptr()
+                        .cast::<[u32; 2]>()
+                        .add(self.rirb_read as usize)
+                        .read_volatile()
+                };
+
+                // Unsolicited responses, like a jack being plugged in, are not asked for
+                if entry[1] & 1 << 4 == 0 && (entry[1] & 0xF) as u8 == codec {
+                    self.registers.write::<u8>(REG_RIRBSTS, 0xFF);
+
+                    return Ok(entry[0]);
+                }
+            }
+
+            if time::monotonic_ns() >= deadline {
+                return Err(Error::CommandTimedOut);
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    fn parameter(&mut self, codec: u8, nid: u8, parameter: u8) -> Result<u32, Error> {
+        self.command(codec, nid, VERB_GET_PARAMETER, parameter as u32)
+    }
+
+    fn connections(&mut self, codec: u8, nid: u8) -> Result<Vec<u8>, Error> {
+        let len = self.parameter(codec, nid, PARAM_CONNECTION_LIST_LEN)?;
+
+        // Long form lists are for nodes past 127, which codecs with outputs we drive do not have
+        if len & 1 << 7 != 0 {
+            return Ok(Vec::new());
+        }
+
+        let len = (len & 0x7F) as usize;
+        let mut connections = Vec::with_capacity(len);
+
+        for index in (0..len).step_by(4) {
+            let entries = self.command(codec, nid, VERB_GET_CONNECTION_LIST, index as u32)?;
+
+            for entry in entries.to_le_bytes().into_iter().take(len - index) {
+                // A range from the last entry up to this one
+                if entry & 1 << 7 != 0
+                    && let Some(&start) = connections.last()
+                {
+                    connections.extend(start + 1..=entry & 0x7F);
+                } else {
+                    connections.push(entry);
+                }
+            }
+        }
+
+        Ok(connections)
+    }
+
+    fn widgets(&mut self, codec: u8, group: u8) -> Result<Vec<Widget>, Error> {
+        let count = self.parameter(codec, group, PARAM_NODE_COUNT)?;
+        let (start, count) = ((count >> 16 & 0xFF) as u8, (count & 0xFF) as u8);
+
+        let mut widgets = Vec::new();
+
+        for nid in start..start.saturating_add(count) {
+            let caps = self.parameter(codec, nid, PARAM_AUDIO_WIDGET_CAP)?;
+            let connections = self.connections(codec, nid)?;
+
+            widgets.push(Widget {
+                nid,
+                caps,
+                connections,
+            });
+        }
+
+        Ok(widgets)
+    }
+
+    /// Unmute an amplifier and set it to 0 dB, the caps of widgets without their own come from
+    /// the function group
+    fn unmute(
+        &mut self,
+        codec: u8,
+        group: u8,
+        widget: &Widget,
+        output: bool,
+        index: usize,
+    ) -> Result<(), Error> {
+        let (present, parameter, direction) = if output {
+            (WIDGET_CAP_OUT_AMP, PARAM_OUT_AMP_CAP, AMP_OUTPUT)
+        } else {
+            (WIDGET_CAP_IN_AMP, PARAM_IN_AMP_CAP, AMP_INPUT)
+        };
+
+        if widget.caps & present == 0 {
+            return Ok(());
+        }
+
+        let owner = if widget.caps & WIDGET_CAP_AMP_OVERRIDE != 0 {
+            widget.nid
+        } else {
+            group
+        };
+
+        let offset = self.parameter(codec, owner, parameter)? & 0x7F;
+
+        self.command(
+            codec,
+            widget.nid,
+            VERB_SET_AMP_GAIN_MUTE,
+            direction | AMP_LEFT | AMP_RIGHT | (index as u32) << 8 | offset,
+        )?;
+
+        Ok(())
+    }
+
+    /// Wire up the path from a pin to its converter, the path starts at the pin
+    fn configure_path(
+        &mut self,
+        codec: u8,
+        group: u8,
+        widgets: &[Widget],
+        path: &[(u8, usize)],
+        pin_control: u32,
+        pin_caps: u32,
+    ) -> Result<(), Error> {
+        let widget = |nid: u8| widgets.iter().find(|widget| widget.nid == nid).unwrap();
+
+        for &(nid, index) in path {
+            let node = widget(nid);
+
+            self.command(codec, nid, VERB_SET_POWER_STATE, 0)?;
+
+            if node.ty() == WIDGET_OUTPUT {
+                let format = FORMAT_BITS_16 | (FORMAT.channels as u16 - 1);
+
+                self.command(codec, nid, VERB_SET_CONVERTER_FORMAT, format as u32)?;
+                self.command(
+                    codec,
+                    nid,
+                    VERB_SET_CHANNEL_STREAM_ID,
+                    (STREAM_TAG as u32) << 4,
+                )?;
+                self.unmute(codec, group, node, true, 0)?;
+
+                continue;
+            }
+
+            // Mixers take every input at once, the others pick one
+            if node.ty() == WIDGET_MIXER {
+                self.unmute(codec, group, node, false, index)?;
+            } else if node.connections.len() > 1 {
+                self.command(codec, nid, VERB_SET_CONNECTION_SELECT, index as u32)?;
+            }
+
+            self.unmute(codec, group, node, true, 0)?;
+
+            if node.ty() == WIDGET_PIN {
+                self.command(codec, nid, VERB_SET_PIN_WIDGET_CONTROL, pin_control)?;
+
+                if pin_caps & PIN_CAP_EAPD != 0 {
+                    self.command(codec, nid, VERB_SET_EAPD, EAPD_ENABLE)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wire every output pin of the codec with something attached to a converter, returning how
+    /// many were
+    fn configure_codec(&mut self, codec: u8) -> Result<usize, Error> {
+        let count = self.parameter(codec, 0, PARAM_NODE_COUNT)?;
+        let (start, count) = ((count >> 16 & 0xFF) as u8, (count & 0xFF) as u8);
+
+        let mut configured = 0;
+
+        for group in start..start.saturating_add(count) {
+            if self.parameter(codec, group, PARAM_FUNCTION_GROUP_TYPE)? & 0xFF
+                != FUNCTION_GROUP_AUDIO
+            {
+                continue;
+            }
+
+            self.command(codec, group, VERB_SET_POWER_STATE, 0)?;
+
+            let widgets = self.widgets(codec, group)?;
+
+            for pin in widgets.iter().filter(|widget| widget.ty() == WIDGET_PIN) {
+                let pin_caps = self.parameter(codec, pin.nid, PARAM_PIN_CAP)?;
+                let config = self.command(codec, pin.nid, VERB_GET_CONFIG_DEFAULT, 0)?;
+
+                let pin_control = match config >> 20 & 0xF {
+                    DEVICE_LINE_OUT | DEVICE_SPEAKER => PIN_CONTROL_OUT,
+                    DEVICE_HEADPHONE => PIN_CONTROL_OUT | PIN_CONTROL_HEADPHONE,
+                    _ => continue,
+                };
+
+                if pin_caps & PIN_CAP_OUTPUT == 0 || config >> 30 == CONNECTIVITY_NONE {
+                    continue;
+                }
+
+                let mut path = Vec::new();
+
+                if !find_path(&widgets, pin.nid, &mut path) {
+                    continue;
+                }
+
+                self.configure_path(codec, group, &widgets, &path, pin_control, pin_caps)?;
+
+                configured += 1;
+            }
+        }
+
+        Ok(configured)
+    }
+
+    fn position(&self) -> usize {
+        self.stream.read::<u32>(SD_LPIB) as usize % BUFFER_SIZE
+    }
+
+    /// Silence what was played, so it is not played again once the buffer comes around
+    fn update(&mut self) {
+        let position = self.position();
+        let played = (position + BUFFER_SIZE - self.played) % BUFFER_SIZE;
+
+        let buffer = self.buffer.as_mut_slice();
+
+        if self.played <= position {
+            buffer[self.played..position].fill(0);
+        } else {
+            buffer[self.played..].fill(0);
+            buffer[..position].fill(0);
+        }
+
+        self.played = position;
+
+        if played >= self.queued {
+            // It ran dry, start again just ahead of it
+            self.queued = 0;
+            self.write = (position + LEAD) % BUFFER_SIZE;
+        } else {
+            self.queued -= played;
+        }
+    }
+
+    fn space(&self) -> usize {
+        BUFFER_SIZE.saturating_sub(self.queued + 2 * LEAD)
+    }
+}
+
+/// Find a converter behind `nid`, pushing every node on the way and which of its connections
+/// leads on
+fn find_path(widgets: &[Widget], nid: u8, path: &mut Vec<(u8, usize)>) -> bool {
+    let Some(widget) = widgets.iter().find(|widget| widget.nid == nid) else {
+        return false;
+    };
+
+    if widget.ty() == WIDGET_OUTPUT {
+        path.push((nid, 0));
+
+        return true;
+    }
+
+    if path.len() >= MAX_PATH_LEN || path.iter().any(|&(visited, _)| visited == nid) {
+        return false;
+    }
+
+    for (index, &connection) in widget.connections.iter().enumerate() {
+        path.push((nid, index));
+
+        if find_path(widgets, connection, path) {
+            return true;
+        }
+
+        path.pop();
+    }
+
+    false
+}
+
+pub struct Hda {
+    pub address: pci::Address,
+    inner: Mutex<Inner>,
+}
+
+fn wait_for(mut f: impl FnMut() -> bool) -> Result<(), Error> {
+    let deadline = time::monotonic_ns() + RESET_TIMEOUT_NS;
+
+    while !f() {
+        if time::monotonic_ns() >= deadline {
+            return Err(Error::ResetTimedOut);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+impl Hda {
+    pub fn new(device: &pci::Device) -> Result<Self, Error> {
+        let Some(Bar::Memory { address, size, .. }) = device.bar(0) else {
+            return Err(Error::MissingBar);
+        };
+
+        device.enable_bus_master();
+
+        let registers = Mmio::map(address, size as usize);
+
+        let gcap = registers.read::<u16>(REG_GCAP);
+        let (outputs, inputs) = ((gcap >> 12 & 0xF) as usize, (gcap >> 8 & 0xF) as usize);
+
+        if outputs == 0 {
+            return Err(Error::NoOutputStream);
+        }
+
+        let constraints = if gcap & GCAP_64OK != 0 {
+            Constraints::new().aligned(128)
+        } else {
+            Constraints::new().aligned(128).below_4gib()
+        };
+
+        // Reset the link, the codecs ask for an address once it is out of reset
+        registers.write::<u32>(REG_GCTL, registers.read::<u32>(REG_GCTL) & !GCTL_CRST);
+        wait_for(|| registers.read::<u32>(REG_GCTL) & GCTL_CRST == 0)?;
+
+        registers.write::<u32>(REG_GCTL, registers.read::<u32>(REG_GCTL) | GCTL_CRST);
+        wait_for(|| registers.read::<u32>(REG_GCTL) & GCTL_CRST != 0)?;
+
+        time::delay_ns(CODEC_WAKE_NS);
+
+        // Interrupts are not routed, everything is polled
+        registers.write::<u32>(REG_INTCTL, 0);
+
+        let alloc = |len| dma::alloc_contiguous(len, constraints).map_err(|_| Error::OutOfMemory);
+
+        let corb = alloc(RING_ENTRIES * 4)?;
+        let rirb = alloc(RING_ENTRIES * 8)?;
+
+        registers.write::<u8>(REG_CORBCTL, 0);
+        wait_for(|| registers.read::<u8>(REG_CORBCTL) & RING_DMA_RUN == 0)?;
+        registers.write::<u8>(REG_RIRBCTL, 0);
+        wait_for(|| registers.read::<u8>(REG_RIRBCTL) & RING_DMA_RUN == 0)?;
+
+        // Only controllers that can do 256 entries are driven, which is all of them in practice
+        if registers.read::<u8>(REG_CORBSIZE) & RING_SIZE_CAP_256 == 0
+            || registers.read::<u8>(REG_RIRBSIZE) & RING_SIZE_CAP_256 == 0
+        {
+            return Err(Error::Unsupported);
+        }
+
+        registers.write::<u8>(REG_CORBSIZE, RING_SIZE_256);
+        registers.write::<u32>(REG_CORBLBASE, corb.bus_address() as u32);
+        registers.write::<u32>(REG_CORBUBASE, (corb.bus_address() >> 32) as u32);
+
+        // Some controllers, QEMU's among them, reset the read pointer without ever showing the bit
+        registers.write::<u16>(REG_CORBRP, CORBRP_RESET);
+        let _ = wait_for(|| registers.read::<u16>(REG_CORBRP) & CORBRP_RESET != 0);
+        registers.write::<u16>(REG_CORBRP, 0);
+        wait_for(|| registers.read::<u16>(REG_CORBRP) & CORBRP_RESET == 0)?;
+        registers.write::<u16>(REG_CORBWP, 0);
+
+        registers.write::<u8>(REG_RIRBSIZE, RING_SIZE_256);
+        registers.write::<u32>(REG_RIRBLBASE, rirb.bus_address() as u32);
+        registers.write::<u32>(REG_RIRBUBASE, (rirb.bus_address() >> 32) as u32);
+        registers.write::<u16>(REG_RIRBWP, RIRBWP_RESET);
+        registers.write::<u16>(REG_RINTCNT, 1);
+
+        registers.write::<u8>(REG_CORBCTL, RING_DMA_RUN);
+        registers.write::<u8>(REG_RIRBCTL, RING_DMA_RUN);
+
+        let stream = registers.subrange(STREAM_BASE + inputs * STREAM_SIZE, STREAM_SIZE);
+
+        let buffer = alloc(BUFFER_SIZE)?;
+        let descriptors = alloc(BUFFER_ENTRIES * 16)?;
+
+        let mut inner = Inner {
+            registers,
+            stream,
+            corb,
+            rirb,
+            rirb_read: 0,
+            buffer,
+            write: LEAD,
+            queued: 0,
+            played: 0,
+        };
+
+        let codecs = registers.read::<u16>(REG_STATESTS);
+        let mut outputs = 0;
+
+        for codec in (0..15).filter(|codec| codecs & 1 << codec != 0) {
+            match inner.configure_codec(codec) {
+                Ok(configured) => outputs += configured,
+                Err(error) => {
+                    crate::log_error!("hda", "{} codec {}: {:?}", device.address, codec, error)
+                }
+            }
+        }
+
+        if outputs == 0 {
+            return Err(Error::NoOutput);
+        }
+
+        // Reset the stream, then point it at the buffer cut into equal entries that loop
+        stream.write::<u8>(SD_CTL, SD_CTL_SRST);
+        wait_for(|| stream.read::<u8>(SD_CTL) & SD_CTL_SRST != 0)?;
+        stream.write::<u8>(SD_CTL, 0);
+        wait_for(|| stream.read::<u8>(SD_CTL) & SD_CTL_SRST == 0)?;
+
+        let entry_size = BUFFER_SIZE / BUFFER_ENTRIES;
+
+        for i in 0..BUFFER_ENTRIES {
+            let entry = [
+                inner.buffer.bus_address() + (i * entry_size) as u64,
+                entry_size as u64,
+            ];
+
+            unsafe {
+                descriptors
+                    .as_ptr()
+                    .cast::<[u64; 2]>()
+                    .add(i)
+                    .write_volatile(entry)
+            };
+        }
+
+        stream.write::<u32>(SD_CBL, BUFFER_SIZE as u32);
+        stream.write::<u16>(SD_LVI, BUFFER_ENTRIES as u16 - 1);
+        stream.write::<u16>(SD_FMT, FORMAT_BITS_16 | (FORMAT.channels as u16 - 1));
+        stream.write::<u32>(SD_BDPL, descriptors.bus_address() as u32);
+        stream.write::<u32>(SD_BDPU, (descriptors.bus_address() >> 32) as u32);
+        stream.write::<u8>(SD_STS, 0xFF);
+        stream.write::<u8>(SD_CTL + 2, STREAM_TAG << 4);
+        stream.write::<u8>(SD_CTL, SD_CTL_RUN);
+
+        // The controller reads the descriptors for as long as the stream runs
+        core::mem::forget(descriptors);
+
+        crate::log_ok!(
+            "hda",
+            "{} with {} outputs, {} Hz {} channels",
+            device.address,
+            outputs,
+            FORMAT.rate,
+            FORMAT.channels
+        );
+
+        Ok(Self {
+            address: device.address,
+            inner: Mutex::new(inner),
+        })
+    }
+}
+
+impl PcmDevice for Hda {
+    fn format(&self) -> Format {
+        FORMAT
+    }
+
+    fn write(&self, data: &[u8]) -> usize {
+        let mut inner = self.inner.lock();
+
+        inner.update();
+
+        // Whole frames only, so the channels stay in step
+        let len = data.len().min(inner.space());
+        let len = len - len % FORMAT.frame_size();
+
+        let write = inner.write;
+        let first = len.min(BUFFER_SIZE - write);
+
+        let buffer = inner.buffer.as_mut_slice();
+
+        buffer[write..write + first].copy_from_slice(&data[..first]);
+        buffer[..len - first].copy_from_slice(&data[first..len]);
+
+        inner.write = (write + len) % BUFFER_SIZE;
+        inner.queued += len;
+
+        len
+    }
+
+    fn space(&self) -> usize {
+        let mut inner = self.inner.lock();
+
+        inner.update();
+
+        inner.space()
+    }
+
+    fn poll(&self) {
+        if let Some(mut inner) = self.inner.try_lock() {
+            inner.update();
+        }
+    }
+}
+
+pub fn init() {
+    for device in pci::devices() {
+        if (device.class, device.subclass) != (CLASS_MULTIMEDIA, SUBCLASS_HDA) {
+            continue;
+        }
+
+        match Hda::new(device) {
+            Ok(hda) => super::register(Arc::new(hda)),
+            Err(error) => crate::log_error!("hda", "{}: {:?}", device.address, error),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Sound, PCM playback devices and the `/dev/dsp` style file that writes to them
+
+pub mod hda;
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    cmdline,
+    file::{self, Events, File, OpenFlags},
+    sync::Mutex,
+    wait,
+};
+
+/// How samples are laid out, always signed little endian with the channels interleaved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub rate: u32,
+    pub channels: u8,
+    pub bits: u8,
+}
+
+impl Format {
+    pub const fn frame_size(&self) -> usize {
+        self.channels as usize * self.bits as usize / 8
+    }
+}
+
+pub trait PcmDevice: Send + Sync {
+    fn format(&self) -> Format;
+
+    /// Queue samples to be played, returning how much fit
+    fn write(&self, data: &[u8]) -> usize;
+
+    /// How much can be queued right now
+    fn space(&self) -> usize;
+
+    /// Keep up with what was played, called over and over
+    fn poll(&self) {}
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn PcmDevice>>> = Mutex::new(Vec::new());
+
+pub fn register(device: Arc<dyn PcmDevice>) {
+    DEVICES.lock().push(device);
+}
+
+/// The first device, which `/dev/dsp` plays on
+pub fn default_device() -> Option<Arc<dyn PcmDevice>> {
+    DEVICES.lock().first().cloned()
+}
+
+pub fn poll() {
+    let Some(devices) = DEVICES.try_lock().map(|devices| devices.clone()) else {
+        return;
+    };
+
+    for device in devices {
+        device.poll();
+    }
+}
+
+/// Open the default device, writes are samples in its format
+pub fn open_dsp(flags: OpenFlags) -> Result<Dsp, file::Error> {
+    Ok(Dsp {
+        device: default_device().ok_or(file::Error::NotFound)?,
+        nonblocking: AtomicBool::new(flags.contains(OpenFlags::NONBLOCK)),
+    })
+}
+
+pub struct Dsp {
+    device: Arc<dyn PcmDevice>,
+    nonblocking: AtomicBool,
+}
+
+impl Dsp {
+    pub fn format(&self) -> Format {
+        self.device.format()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+}
+
+impl File for Dsp {
+    /// Block until everything is queued, which is ahead of the playing by no more than the
+    /// device's buffer
+    fn write(&self, data: &[u8]) -> Result<usize, file::Error> {
+        let mut written = 0;
+
+        while written < data.len() {
+            let queued = self.device.write(&data[written..]);
+
+            written += queued;
+
+            if queued != 0 {
+                continue;
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return if written == 0 {
+                    Err(file::Error::WouldBlock)
+                } else {
+                    Ok(written)
+                };
+            }
+
+            wait::poll();
+        }
+
+        Ok(written)
+    }
+
+    fn poll(&self) -> Events {
+        if self.device.space() != 0 {
+            Events::WRITABLE
+        } else {
+            Events::empty()
+        }
+    }
+}
+
+/// Two short notes, quiet enough to not startle anyone
+fn chime(device: &dyn PcmDevice) {
+    const NOTES: [u32; 2] = [880, 1320];
+    const NOTE_MS: u32 = 150;
+    const AMPLITUDE: i32 = 4096;
+
+    let format = device.format();
+
+    if format.bits != 16 {
+        return;
+    }
+
+    let frames = format.rate * NOTE_MS / 1000;
+    let mut samples = Vec::with_capacity(NOTES.len() * frames as usize * format.frame_size());
+
+    for frequency in NOTES {
+        let period = format.rate / frequency;
+
+        for frame in 0..frames {
+            // A triangle wave, fading out over the note
+            let phase = (frame % period) as i32 * 4 * AMPLITUDE / period as i32;
+            let triangle = if phase < 2 * AMPLITUDE {
+                phase - AMPLITUDE
+            } else {
+                3 * AMPLITUDE - phase
+            };
+            let sample = (triangle * (frames - frame) as i32 / frames as i32) as i16;
+
+            for _ in 0..format.channels {
+                samples.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+    }
+
+    let mut written = 0;
+
+    while written < samples.len() {
+        let queued = device.write(&samples[written..]);
+
+        written += queued;
+
+        if queued == 0 {
+            wait::poll();
+        }
+    }
+}
+
+pub fn init() {
+    hda::init();
+
+    if cmdline::has("chime")
+        && let Some(device) = default_device()
+    {
+        chime(&*device);
+    }
+}
@@ -0,0 +1,129 @@
+use core::cmp::Reverse;
+
+use crate::{arch::registers, requests::STACK_SIZE_REQUEST, sync::Mutex};
+
+const MAX_STACKS: usize = 16;
+
+const CANARY: u64 = 0x57AC_CA4A_2DEA_DD1E;
+const PAINT: u64 = 0xA5A5_A5A5_A5A5_A5A5;
+
+/// How much below the current stack pointer is left unpainted, for the frames of the functions
+/// doing the painting
+const PAINT_MARGIN: usize = 512;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    pub name: &'static str,
+    pub bottom: usize,
+    pub size: usize,
+}
+
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+impl Stack {
+    pub const fn new(name: &'static str, bottom: usize, size: usize) -> Self {
+        Self { name, bottom, size }
+    }
+
+    /// The stack that the bootloader gave us, must be called directly from the entry point
+    #[inline(always)]
+    pub fn boot() -> Self {
+        let size = STACK_SIZE_REQUEST.size() as usize;
+
+        // We do not know exactly where the stack starts, but it can not be far above the current
+        // stack pointer, and underestimating the size by a page is safer than painting below the
+        // stack
+        let top = registers::stack_pointer() as usize;
+
+        Self::new("boot", top + 4096 - size, size - 4096)
+    }
+
+    pub fn top(&self) -> usize {
+        self.bottom + self.size
+    }
+
+    fn words(&self) -> *mut u64 {
+        self.bottom as *mut u64
+    }
+
+    /// Fill the unused part of the stack with a pattern, and put a canary at its bottom
+    pub fn paint(&self) {
+        let stack_pointer = registers::stack_pointer() as usize;
+
+        let end = if (self.bottom..self.top()).contains(&stack_pointer) {
+            stack_pointer - PAINT_MARGIN
+        } else {
+            self.top()
+        };
+
+        let words = (end - self.bottom) / size_of::<u64>();
+
+        unsafe {
+            for i in 1..words {
+                self.words().add(i).write_volatile(PAINT);
+            }
+
+            self.words().write_volatile(CANARY);
+        }
+    }
+
+    pub fn is_canary_intact(&self) -> bool {
+        unsafe { self.words().read_volatile() == CANARY }
+    }
+
+    /// The most bytes this stack has ever used, found by looking for the lowest word that does not
+    /// hold the pattern anymore
+    pub fn high_water(&self) -> usize {
+        if !self.is_canary_intact() {
+            return self.size;
+        }
+
+        let words = self.size / size_of::<u64>();
+
+        let untouched = (1..words)
+            .take_while(|&i| unsafe { self.words().add(i).read_volatile() } == PAINT)
+            .count();
+
+        self.size - (untouched + 1) * size_of::<u64>()
+    }
+}
+
+/// Paint the stack and keep track of its usage
+pub fn register(stack: Stack) {
+    stack.paint();
+
+    let mut stacks = STACKS.lock();
+
+    if let Some(slot) = stacks.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(stack);
+    }
+}
+
+/// The registered stacks, or nothing if they are being registered right now
+pub fn try_registered() -> Option<[Option<Stack>; MAX_STACKS]> {
+    STACKS.try_lock().map(|stacks| *stacks)
+}
+
+/// Print the usage of every registered stack, the ones using the most first
+pub fn dump() {
+    let Some(mut stacks) = try_registered() else {
+        println!("stack: the stacks are being registered, try again later");
+        return;
+    };
+
+    stacks.sort_unstable_by_key(|stack| Reverse(stack.map(|stack| stack.high_water())));
+
+    for stack in stacks.iter().flatten() {
+        println!(
+            "stack: {}: {} of {} bytes used at most, canary {}",
+            stack.name,
+            stack.high_water(),
+            stack.size,
+            if stack.is_canary_intact() {
+                "intact"
+            } else {
+                "smashed"
+            }
+        );
+    }
+}
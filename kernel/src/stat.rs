@@ -0,0 +1,143 @@
+//! Where the processor time went, for the whole system in `/proc/stat` and for each process in
+//! `/proc/<pid>/stat`, in Linux's formats so that `top` and `ps` can read them, and how each
+//! thread was run in `/proc/<tid>/sched`
+//!
+//! Time is counted in the 100 ticks a second Linux reports it in. What threads were charged with
+//! while current is user time, as there is no user mode to tell it apart from the syscalls they
+//! make, the time spent in C-states is idle time, and the rest is system time. Processes have no
+//! names yet, so the name of each is its id
+
+use alloc::{format, string::String};
+
+use crate::{
+    idle, irq,
+    process::{self, Process, Thread},
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+/// What time is counted in, `USER_HZ`
+const TICKS_PER_SECOND: u64 = 100;
+
+fn ticks(ns: u64) -> u64 {
+    ns / (NANOSECONDS_PER_SECOND / TICKS_PER_SECOND)
+}
+
+/// Since boot, how long threads ran, how long the processor was busy otherwise, and how long it
+/// was idle, in nanoseconds
+pub fn cpu_times() -> (u64, u64, u64) {
+    process::account();
+
+    let user = process::cpu_time_ns();
+    let idle = idle::idle_ns();
+    let system = time::monotonic_ns().saturating_sub(user + idle);
+
+    (user, system, idle)
+}
+
+/// The contents of `/proc/stat`
+pub fn contents() -> String {
+    let (user, system, idle) = cpu_times();
+
+    // The fields are user, nice, system, idle, iowait, irq, softirq, steal, guest and guest_nice
+    let times = format!(
+        "{} 0 {} {} 0 0 0 0 0 0",
+        ticks(user),
+        ticks(system),
+        ticks(idle)
+    );
+
+    let mut text = format!("cpu  {}\ncpu0 {}\n", times, times);
+
+    let counts = (0..=u8::MAX).map(irq::count);
+
+    text += &format!("intr {}", counts.clone().sum::<u64>());
+
+    for count in counts {
+        text += &format!(" {}", count);
+    }
+
+    let running = usize::from(process::current().is_some());
+
+    text += &format!(
+        "\nctxt {}\nbtime {}\nprocesses {}\nprocs_running {}\nprocs_blocked 0\n",
+        process::switches(),
+        time::boot_realtime_ns() / NANOSECONDS_PER_SECOND,
+        process::created(),
+        running
+    );
+
+    text
+}
+
+/// The contents of `/proc/<pid>/stat` for `process`
+pub fn process_contents(process: &Process) -> String {
+    process::account();
+
+    let tty = process.tty();
+    let foreground = tty.as_ref().map_or(-1, |tty| tty.foreground() as i64);
+    let size = process
+        .memory()
+        .map_or(0, |memory| memory.lock().address_space_size());
+
+    let mut text = format!(
+        "{pid} ({pid}) {} {} {} {} 0 {} 0 0 0 0 0 {} 0 0 0 20 0 {} 0 {} {} 0",
+        process.state().letter(),
+        process.parent(),
+        process.pgid(),
+        process.sid(),
+        foreground,
+        ticks(process.cpu_time_ns()),
+        process.threads().len(),
+        ticks(process.started_ns()),
+        size,
+        pid = process.pid(),
+    );
+
+    // What is not known of the 52 fields is zero, from the limit on resident memory on
+    for _ in 25..=52 {
+        text += " 0";
+    }
+
+    text.push('\n');
+
+    text
+}
+
+/// `ns` as milliseconds with six decimals
+fn milliseconds(ns: u64) -> String {
+    format!("{}.{:06}", ns / 1_000_000, ns % 1_000_000)
+}
+
+/// The contents of `/proc/<tid>/sched` for `thread`, its runtime, how many times it was made
+/// current and how long it waited for that once ready to run, in milliseconds
+pub fn sched_contents(thread: &Thread) -> String {
+    process::account();
+
+    let stats = thread.sched_stats();
+
+    let mut text = format!(
+        "{} ({}, #threads: {})\n{}\n",
+        thread.tid(),
+        thread.tid(),
+        thread.process().threads().len(),
+        "-".repeat(59)
+    );
+
+    let fields = [
+        ("se.sum_exec_runtime", milliseconds(stats.runtime_ns)),
+        ("nr_switches", format!("{}", stats.switches)),
+        ("se.statistics.wait_max", milliseconds(stats.max_delay_ns)),
+        ("se.statistics.wait_sum", milliseconds(stats.total_delay_ns)),
+        ("se.statistics.wait_count", format!("{}", stats.switches)),
+        (
+            "se.statistics.wait_avg",
+            milliseconds(stats.average_delay_ns()),
+        ),
+    ];
+
+    for (name, value) in fields {
+        text += &format!("{:<43}:{:>21}\n", name, value);
+    }
+
+    text
+}
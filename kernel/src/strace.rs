@@ -0,0 +1,313 @@
+//! Tracing the syscalls of processes, like strace
+//!
+//! Every syscall a traced process makes is written out as it starts, with the arguments decoded,
+//! and again as it returns with what it returned and how long it took. The lines go either to a
+//! ring that [`read`] reads like the kernel log, or to a file like a pipe a tracing process reads
+//! from. The syscall entry calls [`enter`] and [`exit`] around every syscall, which costs nothing
+//! while no process is traced
+
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::paging::PAGE_SIZE,
+    cred,
+    file::File,
+    mmap::{self, Protection},
+    process::{self, Error, Pid},
+    sync::Mutex,
+    time::{self, NANOSECONDS_PER_SECOND},
+};
+
+/// How much of the ring is kept before the oldest lines are overwritten
+const RING_SIZE: usize = 64 * 1024;
+
+/// How much of a string or buffer is shown
+const STRING_MAX: usize = 32;
+
+/// How arguments are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Argument {
+    Int,
+    Hex,
+    Fd,
+    /// A string ending in a zero byte
+    Path,
+    /// Data the size of the argument with that index
+    Buffer(usize),
+}
+
+/// What the tracer knows of a syscall
+pub struct Description {
+    pub number: u64,
+    pub name: &'static str,
+    pub arguments: &'static [Argument],
+}
+
+macro_rules! syscalls {
+    ($($number:literal $name:ident($($argument:expr),*)),* $(,)?) => {
+        &[$(Description {
+            number: $number,
+            name: stringify!($name),
+            arguments: &[$($argument),*],
+        }),*]
+    };
+}
+
+use Argument::{Buffer, Fd, Hex, Int, Path};
+
+/// The x86_64 Linux syscalls of the things the kernel has, the others are shown by number
+pub static SYSCALLS: &[Description] = syscalls![
+    0 read(Fd, Hex, Int),
+    1 write(Fd, Buffer(2), Int),
+    2 open(Path, Hex, Hex),
+    3 close(Fd),
+    7 poll(Hex, Int, Int),
+    9 mmap(Hex, Int, Hex, Hex, Fd, Hex),
+    11 munmap(Hex, Int),
+    12 brk(Hex),
+    20 writev(Fd, Hex, Int),
+    22 pipe(Hex),
+    26 msync(Hex, Int, Hex),
+    39 getpid(),
+    56 clone(Hex, Hex, Hex, Hex, Hex),
+    59 execve(Path, Hex, Hex),
+    60 exit(Int),
+    61 wait4(Int, Hex, Hex, Hex),
+    62 kill(Int, Int),
+    96 gettimeofday(Hex, Hex),
+    97 getrlimit(Int, Hex),
+    102 getuid(),
+    104 getgid(),
+    105 setuid(Int),
+    106 setgid(Int),
+    107 geteuid(),
+    108 getegid(),
+    109 setpgid(Int, Int),
+    110 getppid(),
+    112 setsid(),
+    115 getgroups(Int, Hex),
+    116 setgroups(Int, Hex),
+    117 setresuid(Int, Int, Int),
+    118 getresuid(Hex, Hex, Hex),
+    119 setresgid(Int, Int, Int),
+    120 getresgid(Hex, Hex, Hex),
+    121 getpgid(Int),
+    124 getsid(Int),
+    158 arch_prctl(Hex, Hex),
+    160 setrlimit(Int, Hex),
+    186 gettid(),
+    202 futex(Hex, Int, Int, Hex, Hex, Int),
+    218 set_tid_address(Hex),
+    228 clock_gettime(Int, Hex),
+    231 exit_group(Int),
+    240 mq_open(Path, Hex, Hex, Hex),
+    241 mq_unlink(Path),
+    257 openat(Fd, Path, Hex, Hex),
+    283 timerfd_create(Int, Hex),
+    290 eventfd2(Int, Hex),
+    291 epoll_create1(Hex),
+    293 pipe2(Hex, Hex),
+];
+
+pub fn describe(number: u64) -> Option<&'static Description> {
+    SYSCALLS
+        .iter()
+        .find(|description| description.number == number)
+}
+
+/// Where the lines of a traced process go
+#[derive(Clone)]
+pub enum Sink {
+    Ring,
+    File(Arc<dyn File>),
+}
+
+struct Ring {
+    buffer: [u8; RING_SIZE],
+    /// How many bytes were ever written, like the kernel log
+    written: u64,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    buffer: [0; RING_SIZE],
+    written: 0,
+});
+
+/// Checked before taking the lock, so syscalls cost nothing more while nothing is traced
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static TRACED: Mutex<BTreeMap<Pid, Sink>> = Mutex::new(BTreeMap::new());
+
+/// Start tracing the process `pid` into `sink`, which the current process has to be allowed to
+pub fn trace(pid: Pid, sink: Sink) -> Result<(), Error> {
+    let process = process::get(pid).ok_or(Error::NotFound)?;
+
+    if !cred::may_trace(&process.credentials()) {
+        return Err(Error::PermissionDenied);
+    }
+
+    TRACED.lock().insert(pid, sink);
+    ENABLED.store(true, Ordering::Release);
+
+    Ok(())
+}
+
+pub fn untrace(pid: Pid) {
+    let mut traced = TRACED.lock();
+
+    traced.remove(&pid);
+    ENABLED.store(!traced.is_empty(), Ordering::Release);
+}
+
+/// Copy the ring starting at `position` into `buffer`, returning where the copy starts and how
+/// much was copied, positions already overwritten are skipped
+pub fn read(position: u64, buffer: &mut [u8]) -> (u64, usize) {
+    let ring = RING.lock();
+
+    let start = position.max(ring.written.saturating_sub(RING_SIZE as u64));
+    let len = (ring.written.saturating_sub(start) as usize).min(buffer.len());
+
+    for (i, byte) in buffer[..len].iter_mut().enumerate() {
+        *byte = ring.buffer[((start + i as u64) % RING_SIZE as u64) as usize];
+    }
+
+    (start, len)
+}
+
+fn emit(sink: &Sink, line: &str) {
+    match sink {
+        Sink::Ring => {
+            let mut ring = RING.lock();
+
+            for &byte in line.as_bytes() {
+                let index = (ring.written % RING_SIZE as u64) as usize;
+
+                ring.buffer[index] = byte;
+                ring.written += 1;
+            }
+        }
+        Sink::File(file) => {
+            // A tracer that went away stops nothing
+            let _ = file.write(line.as_bytes());
+        }
+    }
+}
+
+/// Quote up to [`STRING_MAX`] of the `len` bytes at `address` that are mapped, stopping at a zero
+/// byte for strings, with dots after if there were more
+fn read_bytes(address: u64, len: usize, string: bool, out: &mut String) {
+    out.push('"');
+
+    let mut index = 0;
+
+    while index < len.min(STRING_MAX) {
+        let at = address.wrapping_add(index as u64);
+
+        if (index == 0 || at.is_multiple_of(PAGE_SIZE)) && !mmap::is_mapped(at, 1, Protection::READ)
+        {
+            break;
+        }
+
+        let byte = unsafe { *(at as *const u8) };
+
+        if string && byte == 0 {
+            break;
+        }
+
+        let _ = write!(out, "{}", byte.escape_ascii());
+
+        index += 1;
+    }
+
+    out.push('"');
+
+    if index == STRING_MAX && len > STRING_MAX {
+        out.push_str("...");
+    }
+}
+
+/// The syscall of a traced process being made, to be given to [`exit`]
+pub struct Call {
+    pid: Pid,
+    number: u64,
+    start_ns: u64,
+    sink: Sink,
+}
+
+fn name(number: u64) -> String {
+    match describe(number) {
+        Some(description) => description.name.into(),
+        None => format!("syscall_{}", number),
+    }
+}
+
+/// Called as the current process makes the syscall `number`, returns what to give to [`exit`] if
+/// it is traced
+pub fn enter(number: u64, arguments: [u64; 6]) -> Option<Call> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let pid = process::current()?.process().pid();
+    let sink = TRACED.lock().get(&pid)?.clone();
+
+    let mut line = format!("[pid {}] {}(", pid, name(number));
+
+    let kinds = describe(number).map_or(&[Hex; 6][..], |description| description.arguments);
+
+    for (index, (&kind, &value)) in kinds.iter().zip(&arguments).enumerate() {
+        if index != 0 {
+            line.push_str(", ");
+        }
+
+        let _ = match kind {
+            Int => write!(line, "{}", value as i64),
+            Hex => write!(line, "{:#x}", value),
+            Fd => write!(line, "{}", value as i32),
+            Path => {
+                read_bytes(value, usize::MAX, true, &mut line);
+                Ok(())
+            }
+            Buffer(len) => {
+                read_bytes(value, arguments[len] as usize, false, &mut line);
+                Ok(())
+            }
+        };
+    }
+
+    line.push_str(") ...\n");
+
+    emit(&sink, &line);
+
+    Some(Call {
+        pid,
+        number,
+        start_ns: time::monotonic_ns(),
+        sink,
+    })
+}
+
+/// Called as a traced syscall returns `result`, negative for an error number
+pub fn exit(call: Call, result: i64) {
+    let elapsed = time::monotonic_ns().saturating_sub(call.start_ns);
+
+    let mut line = format!("[pid {}] ... {} = ", call.pid, name(call.number));
+
+    let _ = match result {
+        -4095..=-1 => write!(line, "-1 (errno {})", -result),
+        result => write!(line, "{}", result),
+    };
+
+    let _ = writeln!(
+        line,
+        " <{}.{:06}>",
+        elapsed / NANOSECONDS_PER_SECOND,
+        elapsed % NANOSECONDS_PER_SECOND / 1000
+    );
+
+    emit(&call.sink, &line);
+}
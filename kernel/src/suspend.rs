@@ -0,0 +1,271 @@
+//! Suspend to RAM, the S3 sleep state, where everything but memory loses power until the firmware
+//! wakes the processor up at the waking vector
+//!
+//! Without an AML interpreter the `_PTS` and `_WAK` methods are never run, and only the sleep type
+//! is taken from the `_S3_` package in the DSDT
+
+use alloc::vec::Vec;
+
+use crate::{
+    acpi,
+    arch::{interrupts, microcode, port, suspend, wakeup},
+    cpufreq, efi, hpet, memory,
+    mmio::Mmio,
+    sync::Mutex,
+    time,
+};
+
+const PM1_STATUS_WAKE: u16 = 1 << 15;
+
+const PM1_CONTROL_SCI_ENABLE: u16 = 1 << 0;
+const PM1_CONTROL_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_CONTROL_SLEEP_TYPE_MASK: u16 = 0b111 << PM1_CONTROL_SLEEP_TYPE_SHIFT;
+const PM1_CONTROL_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// How long to wait for the system to go to sleep before giving up
+const SLEEP_TIMEOUT_NS: u64 = time::NANOSECONDS_PER_SECOND;
+
+/// What a device does before the system goes to sleep and after it wakes up
+pub struct Hook {
+    pub name: &'static str,
+    pub suspend: fn(),
+    pub resume: fn(),
+}
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+/// Register what a device does around sleeping, and before another kernel is started
+pub fn register(name: &'static str, suspend: fn(), resume: fn()) {
+    HOOKS.lock().push(Hook {
+        name,
+        suspend,
+        resume,
+    });
+}
+
+/// Quiesce every device with a hook, in the opposite order they were registered in
+pub fn suspend_devices() {
+    for hook in HOOKS.lock().iter().rev() {
+        (hook.suspend)();
+    }
+}
+
+/// Bring every device with a hook back, in the order they were registered in
+pub fn resume_devices() {
+    for hook in HOOKS.lock().iter() {
+        (hook.resume)();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Some table or package that sleeping needs is missing
+    Unsupported,
+    /// There is no memory below 1 MiB for the waking vector
+    NoLowMemory,
+    /// The firmware did not put the system to sleep
+    Failed,
+}
+
+/// The fixed hardware registers from the FADT that sleeping goes through
+#[derive(Debug, Clone, Copy)]
+struct Pm1 {
+    status_a: u16,
+    status_b: u16,
+    control_a: u16,
+    control_b: u16,
+    sleep_type_a: u16,
+    sleep_type_b: u16,
+}
+
+/// Where the sleep goes through, found once before sleeping so the function that enters the
+/// state does not have to look
+static PM1: Mutex<Option<Pm1>> = Mutex::new(None);
+
+/// Read a 64 bit address from the FADT, which may only be 4 byte aligned
+fn read_address(table: &Mmio, offset: usize) -> u64 {
+    table.read::<u32>(offset) as u64 | (table.read::<u32>(offset + 4) as u64) << 32
+}
+
+/// The 64 bit address at `wide` if the table is long enough for it and it is set, otherwise the
+/// 32 bit one at `narrow`
+fn address(fadt: &Mmio, narrow: usize, wide: usize) -> u64 {
+    match fadt.len() >= wide + 8 {
+        true if read_address(fadt, wide) != 0 => read_address(fadt, wide),
+        _ => fadt.read::<u32>(narrow) as u64,
+    }
+}
+
+/// The sleep types in `Name (_S3_, Package () { a, b, ... })`, found by looking for the bytes of
+/// the name instead of interpreting the table
+fn sleep_types(dsdt: &Mmio) -> Option<(u16, u16)> {
+    let bytes: Vec<u8> = (0..dsdt.len())
+        .map(|offset| dsdt.read::<u8>(offset))
+        .collect();
+
+    let start = bytes.windows(6).position(|window| {
+        window[1..5] == *b"_S3_" && (window[0] == 0x08 || window[0] == b'\\') && window[5] == 0x12
+    })? + 6;
+
+    // The package length's lead byte says how many more bytes it has, then comes the count
+    let length_bytes = (*bytes.get(start)? >> 6) as usize;
+    let mut elements = bytes.get(start + 1 + length_bytes + 1..)?.iter();
+
+    let mut element = || match *elements.next()? {
+        // ZeroOp and OneOp
+        0x00 => Some(0),
+        0x01 => Some(1),
+        // BytePrefix
+        0x0A => elements.next().map(|&value| value as u16),
+        _ => None,
+    };
+
+    Some((element()?, element()?))
+}
+
+fn find_pm1() -> Option<Pm1> {
+    let fadt = acpi::find_table(b"FACP")?;
+
+    if fadt.len() < 116 {
+        return None;
+    }
+
+    let dsdt = acpi::map_table(address(&fadt, 40, 140))?;
+    let (sleep_type_a, sleep_type_b) = sleep_types(&dsdt)?;
+
+    let pm1 = Pm1 {
+        status_a: fadt.read::<u32>(56) as u16,
+        status_b: fadt.read::<u32>(60) as u16,
+        control_a: fadt.read::<u32>(64) as u16,
+        control_b: fadt.read::<u32>(68) as u16,
+        sleep_type_a,
+        sleep_type_b,
+    };
+
+    if pm1.status_a == 0 || pm1.control_a == 0 {
+        return None;
+    }
+
+    // The firmware handles the registers itself until it is asked to hand them over
+    if port::inw(pm1.control_a) & PM1_CONTROL_SCI_ENABLE == 0 {
+        let command = fadt.read::<u32>(48) as u16;
+        let enable = fadt.read::<u8>(52);
+
+        if command == 0 || enable == 0 {
+            return None;
+        }
+
+        port::outb(command, enable);
+
+        let start = time::monotonic_ns();
+
+        while port::inw(pm1.control_a) & PM1_CONTROL_SCI_ENABLE == 0 {
+            if time::monotonic_ns() - start > SLEEP_TIMEOUT_NS {
+                return None;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    Some(pm1)
+}
+
+/// Point the firmware at where to jump on wake up
+fn set_waking_vector(fadt: &Mmio, page: u64) -> Option<()> {
+    let facs = Mmio::map(address(fadt, 36, 132), 32);
+
+    if facs.read::<[u8; 4]>(0) != *b"FACS" {
+        return None;
+    }
+
+    facs.write::<u32>(12, page as u32);
+
+    // Otherwise the firmware may jump there in protected or long mode
+    if facs.read::<u32>(4) >= 32 {
+        facs.write::<u64>(24, 0);
+    }
+
+    Some(())
+}
+
+fn write_sleep_type(control: u16, sleep_type: u16) {
+    if control == 0 {
+        return;
+    }
+
+    let value = port::inw(control) & !(PM1_CONTROL_SLEEP_TYPE_MASK | PM1_CONTROL_SLEEP_ENABLE)
+        | sleep_type << PM1_CONTROL_SLEEP_TYPE_SHIFT;
+
+    port::outw(control, value);
+    port::outw(control, value | PM1_CONTROL_SLEEP_ENABLE);
+}
+
+/// Write the sleep type, which powers off everything but memory, and give up if the system is
+/// still running after a while
+extern "C" fn enter() {
+    let Some(pm1) = *PM1.lock() else {
+        return;
+    };
+
+    port::outw(pm1.status_a, PM1_STATUS_WAKE);
+
+    if pm1.status_b != 0 {
+        port::outw(pm1.status_b, PM1_STATUS_WAKE);
+    }
+
+    write_sleep_type(pm1.control_a, pm1.sleep_type_a);
+    write_sleep_type(pm1.control_b, pm1.sleep_type_b);
+
+    time::delay_ns(SLEEP_TIMEOUT_NS);
+}
+
+/// Put the system to sleep until something wakes it up, and bring it back as it was
+pub fn suspend_to_ram() -> Result<(), Error> {
+    let fadt = acpi::find_table(b"FACP").ok_or(Error::Unsupported)?;
+    let pm1 = find_pm1().ok_or(Error::Unsupported)?;
+    let page = memory::low_page().ok_or(Error::NoLowMemory)?;
+
+    set_waking_vector(&fadt, page).ok_or(Error::Unsupported)?;
+
+    *PM1.lock() = Some(pm1);
+
+    println!("suspend: going to sleep");
+
+    suspend_devices();
+
+    time::suspend();
+    interrupts::disable();
+
+    let slept = suspend::suspend(page, enter);
+
+    // The processor's own state first, the devices may need the clock
+    if slept {
+        microcode::resume();
+
+        if let Some(hpet) = hpet::get() {
+            hpet.enable();
+        }
+
+        time::resume();
+
+        wakeup::resume();
+
+        cpufreq::resume();
+    }
+
+    resume_devices();
+
+    // The time asleep does not count on the monotonic clock, but the wall clock has to catch up
+    if slept && let Ok(now) = efi::get_time() {
+        time::set_realtime_ns(now.unix_ns());
+    }
+
+    if slept {
+        println!("suspend: woke up");
+        Ok(())
+    } else {
+        println!("suspend: the firmware did not put the system to sleep");
+        Err(Error::Failed)
+    }
+}
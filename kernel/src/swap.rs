@@ -0,0 +1,526 @@
+//! Swapping anonymous memory out to a block device when the frame zones run short
+//!
+//! There are no processes yet, so what can be swapped is the memory the kernel maps with
+//! [`map_anonymous`]. Its pages only get frames once touched, are written to a slot of the swap
+//! device and unmapped by the page-out pass when a zone falls below its low watermark, and are read
+//! back by the page fault handler when touched again
+//!
+//! The device has to be formatted by mkswap, and is read and written by polling it, so anonymous
+//! memory must not be touched with a lock held that polling the devices takes
+
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    arch::paging::{PAGE_SIZE, PageTableFlags},
+    block::{self, BlockDevice},
+    cmdline, frame, oom,
+    page::{self, Owner},
+    paging,
+    sync::Mutex,
+    time,
+};
+
+/// Where anonymous memory is mapped, far above the higher half direct map
+const ANONYMOUS_BASE: u64 = 0xFFFF_E000_0000_0000;
+const ANONYMOUS_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// Set in an entry that is not present and holds the slot its page was written to
+const SWAP_ENTRY: u64 = 1 << 9;
+const SLOT_SHIFT: u64 = 12;
+
+/// Where mkswap puts its signature and what it says about the device, in the first page
+const MAGIC_OFFSET: usize = 4086;
+const MAGIC: &[u8; 10] = b"SWAPSPACE2";
+const VERSION_OFFSET: usize = 1024;
+const LAST_PAGE_OFFSET: usize = 1028;
+const BAD_PAGE_COUNT_OFFSET: usize = 1032;
+const BAD_PAGES_OFFSET: usize = 1536;
+
+/// How often the page-out pass looks at the watermarks, and how much it writes out each time
+const PAGE_OUT_INTERVAL_NS: u64 = 100_000_000;
+const PAGE_OUT_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoDevice,
+    /// The device was not formatted by mkswap, or its blocks do not fit in a page
+    NotSwap,
+    /// A device is already swapped to
+    AlreadyOn,
+    Io(block::Error),
+}
+
+impl From<block::Error> for Error {
+    fn from(error: block::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// The device pages are swapped to, with a bit set for each slot that holds a page
+struct Device {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    slots: usize,
+    free: usize,
+    used: Vec<u64>,
+}
+
+impl Device {
+    fn is_used(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn set_used(&mut self, slot: usize, used: bool) {
+        if used {
+            self.used[slot / 64] |= 1 << (slot % 64);
+        } else {
+            self.used[slot / 64] &= !(1 << (slot % 64));
+        }
+    }
+
+    fn allocate(&mut self) -> Option<usize> {
+        let slot = (1..self.slots).find(|&slot| !self.is_used(slot))?;
+
+        self.set_used(slot, true);
+        self.free -= 1;
+
+        Some(slot)
+    }
+
+    fn release(&mut self, slot: usize) {
+        assert!(self.is_used(slot), "swap: releasing a free slot");
+
+        self.set_used(slot, false);
+        self.free += 1;
+    }
+
+    fn lba(&self, slot: usize) -> u64 {
+        slot as u64 * PAGE_SIZE / self.device.block_size() as u64
+    }
+
+    fn write(&self, slot: usize, phys: u64) -> Result<(), block::Error> {
+        let data = unsafe {
+            core::slice::from_raw_parts(
+                paging::virt_from_phys(phys) as *const u8,
+                PAGE_SIZE as usize,
+            )
+        };
+
+        self.device.write_blocks(self.lba(slot), data)
+    }
+
+    fn read(&self, slot: usize, phys: u64) -> Result<(), block::Error> {
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(
+                paging::virt_from_phys(phys) as *mut u8,
+                PAGE_SIZE as usize,
+            )
+        };
+
+        self.device.read_blocks(self.lba(slot), buffer)
+    }
+}
+
+/// Memory from [`map_anonymous`]
+struct Region {
+    start: u64,
+    len: u64,
+}
+
+struct Anonymous {
+    regions: Vec<Region>,
+    next: u64,
+    /// The pages that have a frame, oldest first, which the page-out pass goes through
+    resident: VecDeque<u64>,
+    swapped: usize,
+}
+
+static DEVICE: Mutex<Option<Device>> = Mutex::new(None);
+
+static ANONYMOUS: Mutex<Anonymous> = Mutex::new(Anonymous {
+    regions: Vec::new(),
+    next: ANONYMOUS_BASE,
+    resident: VecDeque::new(),
+    swapped: 0,
+});
+
+static LAST_PAGE_OUT: AtomicU64 = AtomicU64::new(0);
+static PAGE_OUTS: AtomicU64 = AtomicU64::new(0);
+static PAGE_INS: AtomicU64 = AtomicU64::new(0);
+
+fn swap_entry(slot: usize) -> u64 {
+    (slot as u64) << SLOT_SHIFT | SWAP_ENTRY
+}
+
+/// The slot in `entry`, if it is a swap entry
+fn slot_of(entry: u64) -> Option<usize> {
+    let flags = PageTableFlags::from_bits_retain(entry);
+
+    (!flags.contains(PageTableFlags::PRESENT) && entry & SWAP_ENTRY != 0)
+        .then_some((entry >> SLOT_SHIFT) as usize)
+}
+
+/// Swap to the block device called `name`
+pub fn swapon(name: &str) -> Result<(), Error> {
+    let device = block::find(name).ok_or(Error::NoDevice)?;
+    let block_size = device.block_size();
+
+    if block_size == 0 || !(PAGE_SIZE as usize).is_multiple_of(block_size) {
+        return Err(Error::NotSwap);
+    }
+
+    let mut header = alloc::vec![0; PAGE_SIZE as usize];
+
+    device.read_blocks(0, &mut header)?;
+
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap()) as usize;
+
+    if header[MAGIC_OFFSET..] != *MAGIC || read_u32(VERSION_OFFSET) != 1 {
+        return Err(Error::NotSwap);
+    }
+
+    let pages = (device.block_count() * block_size as u64 / PAGE_SIZE) as usize;
+    let slots = (read_u32(LAST_PAGE_OFFSET) + 1).min(pages);
+
+    let mut swap = Device {
+        name: String::from(name),
+        device,
+        slots,
+        free: slots,
+        used: alloc::vec![0; slots.div_ceil(64)],
+    };
+
+    // The first page is the header, and mkswap may have found pages that do not work
+    let bad_pages = read_u32(BAD_PAGE_COUNT_OFFSET).min((MAGIC_OFFSET - BAD_PAGES_OFFSET) / 4);
+    let unusable = (0..bad_pages).map(|index| read_u32(BAD_PAGES_OFFSET + index * 4));
+
+    for slot in core::iter::once(0).chain(unusable) {
+        if slot < slots && !swap.is_used(slot) {
+            swap.set_used(slot, true);
+            swap.free -= 1;
+        }
+    }
+
+    let mut current = DEVICE.lock();
+
+    if current.is_some() {
+        return Err(Error::AlreadyOn);
+    }
+
+    println!(
+        "swap: {}: {} MiB in {} slots",
+        name,
+        swap.free as u64 * PAGE_SIZE / (1024 * 1024),
+        swap.free
+    );
+
+    *current = Some(swap);
+
+    Ok(())
+}
+
+/// Reserve `len` bytes of memory that is only given frames once touched and may be swapped out,
+/// readable and writable but not executable
+pub fn map_anonymous(len: usize) -> Option<u64> {
+    let len = (len as u64).next_multiple_of(PAGE_SIZE);
+    let mut anonymous = ANONYMOUS.lock();
+
+    let start = anonymous.next;
+
+    // A page is left unmapped after each region to catch overflows
+    let end = start.checked_add(len)?.checked_add(PAGE_SIZE)?;
+
+    if len == 0 || end > ANONYMOUS_BASE + ANONYMOUS_SIZE {
+        return None;
+    }
+
+    anonymous.next = end;
+    anonymous.regions.push(Region { start, len });
+
+    Some(start)
+}
+
+/// Give back memory from [`map_anonymous`], with whatever frames and slots it has
+pub fn unmap_anonymous(start: u64) {
+    let mut anonymous = ANONYMOUS.lock();
+
+    let Some(index) = anonymous
+        .regions
+        .iter()
+        .position(|region| region.start == start)
+    else {
+        return;
+    };
+
+    let region = anonymous.regions.swap_remove(index);
+    let range = region.start..region.start + region.len;
+
+    for virt in range.clone().step_by(PAGE_SIZE as usize) {
+        let Some(entry) = paging::read_entry(virt) else {
+            continue;
+        };
+
+        if let Some(slot) = slot_of(entry) {
+            if let Some(device) = DEVICE.lock().as_mut() {
+                device.release(slot);
+            }
+
+            anonymous.swapped -= 1;
+            paging::write_entry(virt, 0);
+        } else if let Some(phys) = paging::translate(virt) {
+            paging::unmap(virt, PAGE_SIZE as usize);
+            frame::put(phys);
+        }
+    }
+
+    anonymous.resident.retain(|virt| !range.contains(virt));
+}
+
+/// Write out a page that was not accessed lately, returns false if there is none or nowhere to
+/// write it to
+fn page_out(anonymous: &mut Anonymous) -> bool {
+    let mut device = DEVICE.lock();
+
+    let Some(device) = device.as_mut() else {
+        return false;
+    };
+
+    // Pages that were accessed since they were last looked at get another round
+    for _ in 0..anonymous.resident.len() * 2 {
+        let Some(virt) = anonymous.resident.pop_front() else {
+            return false;
+        };
+
+        let Some(entry) = paging::read_entry(virt) else {
+            continue;
+        };
+
+        let flags = PageTableFlags::from_bits_retain(entry);
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        if flags.contains(PageTableFlags::ACCESSED) {
+            paging::write_entry(virt, entry & !PageTableFlags::ACCESSED.bits());
+            anonymous.resident.push_back(virt);
+
+            continue;
+        }
+
+        let Some(phys) = paging::translate(virt) else {
+            continue;
+        };
+
+        let Some(slot) = device.allocate() else {
+            anonymous.resident.push_front(virt);
+
+            return false;
+        };
+
+        // Unmapped first so the page can not change while it is written out
+        paging::write_entry(virt, swap_entry(slot));
+
+        if let Err(error) = device.write(slot, phys) {
+            println!("swap: {}: writing slot {}: {:?}", device.name, slot, error);
+
+            paging::write_entry(virt, entry);
+            device.release(slot);
+            anonymous.resident.push_back(virt);
+
+            return false;
+        }
+
+        frame::put(phys);
+
+        anonymous.swapped += 1;
+        PAGE_OUTS.fetch_add(1, Ordering::Relaxed);
+
+        return true;
+    }
+
+    false
+}
+
+/// A frame for an anonymous page, paging another one out if there is none
+fn alloc_frame(anonymous: &mut Anonymous) -> Option<u64> {
+    frame::alloc(1).or_else(|| page_out(anonymous).then(|| frame::alloc(1)).flatten())
+}
+
+/// Give the anonymous page at `address` a frame, reading it back if it was swapped out, returns
+/// false if the address is not anonymous memory or the page could not be brought in
+pub fn handle_fault(address: u64) -> bool {
+    if !(ANONYMOUS_BASE..ANONYMOUS_BASE + ANONYMOUS_SIZE).contains(&address) {
+        return false;
+    }
+
+    let virt = address & !(PAGE_SIZE - 1);
+
+    // Touching anonymous memory while paging would otherwise wait on ourselves forever
+    let Some(mut anonymous) = ANONYMOUS.try_lock() else {
+        return false;
+    };
+
+    if !anonymous
+        .regions
+        .iter()
+        .any(|region| (region.start..region.start + region.len).contains(&virt))
+    {
+        return false;
+    }
+
+    let entry = paging::read_entry(virt).unwrap_or(0);
+
+    // The page is there, so this is a fault the page tables can not fix
+    if PageTableFlags::from_bits_retain(entry).contains(PageTableFlags::PRESENT) {
+        return false;
+    }
+
+    let Some(phys) = alloc_frame(&mut anonymous) else {
+        // Whatever gets killed may give its anonymous memory back
+        drop(anonymous);
+
+        return oom::out_of_memory("an anonymous page", 1) && handle_fault(address);
+    };
+
+    match slot_of(entry) {
+        Some(slot) => {
+            let mut device = DEVICE.lock();
+            let device = device.as_mut().expect("swap: swapped out without a device");
+
+            if let Err(error) = device.read(slot, phys) {
+                println!("swap: {}: reading slot {}: {:?}", device.name, slot, error);
+
+                frame::free(phys, 1);
+
+                return false;
+            }
+
+            device.release(slot);
+
+            anonymous.swapped -= 1;
+            PAGE_INS.fetch_add(1, Ordering::Relaxed);
+        }
+        None => unsafe {
+            (paging::virt_from_phys(phys) as *mut u8).write_bytes(0, PAGE_SIZE as usize);
+        },
+    }
+
+    if let Some(page) = page::from_phys(phys) {
+        page.set_owner(Owner::Anonymous);
+    }
+
+    paging::map(
+        virt,
+        phys,
+        PAGE_SIZE as usize,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    );
+
+    anonymous.resident.push_back(virt);
+
+    true
+}
+
+/// Move the anonymous page in the frame at `from` to the frame at `to`, returns false if no
+/// anonymous page is in it or anonymous memory is busy
+pub fn migrate(from: u64, to: u64) -> bool {
+    // Compaction can be asked for with anything held, even this
+    let Some(anonymous) = ANONYMOUS.try_lock() else {
+        return false;
+    };
+
+    let Some(&virt) = anonymous
+        .resident
+        .iter()
+        .find(|&&virt| paging::translate(virt) == Some(from))
+    else {
+        return false;
+    };
+
+    let Some(entry) = paging::read_entry(virt) else {
+        return false;
+    };
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            paging::virt_from_phys(from) as *const u8,
+            paging::virt_from_phys(to) as *mut u8,
+            PAGE_SIZE as usize,
+        );
+    }
+
+    if let Some(page) = page::from_phys(to) {
+        page.set_owner(Owner::Anonymous);
+    }
+
+    // The address is all of the entry that is not its flags, and the flags stay as they were
+    paging::write_entry(virt, entry ^ from | to);
+
+    frame::put(from);
+
+    true
+}
+
+/// The page-out pass, which writes pages out while a zone is below its low watermark
+pub fn poll() {
+    let now = time::monotonic_ns();
+
+    if now - LAST_PAGE_OUT.load(Ordering::Relaxed) < PAGE_OUT_INTERVAL_NS {
+        return;
+    }
+
+    LAST_PAGE_OUT.store(now, Ordering::Relaxed);
+
+    if !frame::is_short_of_memory() {
+        return;
+    }
+
+    // Polling the swap device while paging comes back here
+    let Some(mut anonymous) = ANONYMOUS.try_lock() else {
+        return;
+    };
+
+    for _ in 0..PAGE_OUT_BATCH {
+        if !frame::is_short_of_memory() || !page_out(&mut anonymous) {
+            break;
+        }
+    }
+}
+
+pub fn dump() {
+    let Some(anonymous) = ANONYMOUS.try_lock() else {
+        println!("swap: busy, try again later");
+        return;
+    };
+
+    match DEVICE.try_lock().as_ref().map(|device| device.as_ref()) {
+        Some(Some(device)) => println!(
+            "swap: {}: {} of {} slots free",
+            device.name, device.free, device.slots
+        ),
+        Some(None) => println!("swap: no device"),
+        None => println!("swap: device busy"),
+    }
+
+    println!(
+        "swap: {} anonymous pages resident, {} swapped out, {} page outs, {} page ins",
+        anonymous.resident.len(),
+        anonymous.swapped,
+        PAGE_OUTS.load(Ordering::Relaxed),
+        PAGE_INS.load(Ordering::Relaxed)
+    );
+}
+
+/// Swap to the device given as `swap=<name>`, once the block devices are found
+pub fn init() {
+    let Some(name) = cmdline::value("swap") else {
+        return;
+    };
+
+    if let Err(error) = swapon(name) {
+        println!("swap: {}: could not swap to it: {:?}", name, error);
+    }
+}
@@ -0,0 +1,158 @@
+//! The kernel's functions that modules can call, listed with [`export!`] next to where they are
+//! defined
+//!
+//! The linker gathers them in one table, which is sorted by name at boot so they can be looked up
+//! quickly. Each one carries a hash of its name and signature, and the hash of all of them is the
+//! version of the interface modules are built against, which changes with any change to it
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::paging;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut index = 0;
+
+    while index < bytes.len() {
+        hash ^= bytes[index] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        index += 1;
+    }
+
+    hash
+}
+
+/// A function exported with [`export!`], laid out the same in the kernel and in every module
+#[derive(Debug)]
+#[repr(C)]
+pub struct Symbol {
+    name: *const u8,
+    name_len: usize,
+    address: *const (),
+    version: u64,
+}
+
+unsafe impl Sync for Symbol {}
+
+impl Symbol {
+    pub const fn new(name: &'static str, signature: &'static str, address: *const ()) -> Self {
+        Self {
+            name: name.as_ptr(),
+            name_len: name.len(),
+            address,
+            version: fnv1a(
+                fnv1a(FNV_OFFSET_BASIS, name.as_bytes()),
+                signature.as_bytes(),
+            ),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        unsafe {
+            core::str::from_utf8_unchecked(core::slice::from_raw_parts(self.name, self.name_len))
+        }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address as u64
+    }
+
+    /// The hash of the name and the signature
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+/// Let modules call `function`, which must have the type `signature`
+#[macro_export]
+macro_rules! export {
+    ($function:ident: $signature:ty) => {
+        const _: () = {
+            const FUNCTION: $signature = $function;
+
+            #[used]
+            #[unsafe(link_section = ".symbols")]
+            static mut SYMBOL: $crate::symbols::Symbol = $crate::symbols::Symbol::new(
+                stringify!($function),
+                stringify!($signature),
+                FUNCTION as *const (),
+            );
+        };
+    };
+}
+
+unsafe extern "C" {
+    static mut __symbols_start: Symbol;
+    static mut __symbols_end: Symbol;
+    static __kernel_end: u8;
+}
+
+static SORTED: AtomicBool = AtomicBool::new(false);
+
+/// Every exported symbol, sorted by name once [`init`] ran
+pub fn all() -> &'static [Symbol] {
+    let start = &raw const __symbols_start;
+    let end = &raw const __symbols_end;
+
+    unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+}
+
+/// The address of the exported function called `name`
+pub fn resolve(name: &str) -> Option<u64> {
+    let symbols = all();
+
+    let symbol = if SORTED.load(Ordering::Acquire) {
+        symbols
+            .binary_search_by(|symbol| symbol.name().cmp(name))
+            .ok()
+            .map(|index| &symbols[index])
+    } else {
+        symbols.iter().find(|symbol| symbol.name() == name)
+    };
+
+    symbol.map(Symbol::address)
+}
+
+/// The exported function `address` is in, and how far into it
+///
+/// Only exported functions are known, so an address in any other function is attributed to the
+/// closest exported one before it
+pub fn find(address: u64) -> Option<(&'static Symbol, u64)> {
+    if !(paging::kernel_virt_base()..(&raw const __kernel_end) as u64).contains(&address) {
+        return None;
+    }
+
+    all()
+        .iter()
+        .filter(|symbol| symbol.address() <= address)
+        .max_by_key(|symbol| symbol.address())
+        .map(|symbol| (symbol, address - symbol.address()))
+}
+
+/// The version of the interface, which modules must be built against
+pub fn abi_version() -> u64 {
+    all().iter().fold(FNV_OFFSET_BASIS, |hash, symbol| {
+        fnv1a(hash, &symbol.version().to_le_bytes())
+    })
+}
+
+/// Sort the table, in place so nothing needs to be allocated
+pub fn init() {
+    let start = &raw mut __symbols_start;
+    let end = &raw mut __symbols_end;
+
+    let symbols =
+        unsafe { core::slice::from_raw_parts_mut(start, end.offset_from(start) as usize) };
+
+    symbols.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+    SORTED.store(true, Ordering::Release);
+
+    println!(
+        "symbols: {} exported, abi version {:016x}",
+        symbols.len(),
+        abi_version()
+    );
+}
@@ -0,0 +1,87 @@
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
+#[cfg(feature = "lockdep")]
+use crate::lockdep;
+
+/// A spinning mutex, which reports lock ordering inversions when the `lockdep` feature is enabled
+///
+/// Every mutex created at the same place in the source code belongs to the same lock class
+pub struct Mutex<T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    class: &'static Location<'static>,
+    inner: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    #[cfg(feature = "lockdep")]
+    class: &'static Location<'static>,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "lockdep")]
+            class: Location::caller(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.class, Location::caller(), false);
+
+        MutexGuard {
+            #[cfg(feature = "lockdep")]
+            class: self.class,
+            inner: self.inner.lock(),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+
+        // A failed attempt can not deadlock, so only successful ones are tracked
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.class, Location::caller(), true);
+
+        Some(MutexGuard {
+            #[cfg(feature = "lockdep")]
+            class: self.class,
+            inner,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.class);
+    }
+}
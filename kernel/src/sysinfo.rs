@@ -0,0 +1,472 @@
+//! What the firmware says about the machine, from the SMBIOS (DMI) tables
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use lazy_static::lazy_static;
+
+use crate::{mmio::Mmio, paging, requests::SMBIOS_REQUEST};
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BOARD: u8 = 2;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+/// Bigger tables than this are cut short
+const MAX_TABLE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct Bios {
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub date: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct System {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub uuid: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Board {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Processor {
+    pub socket: Option<String>,
+    pub manufacturer: Option<String>,
+    pub version: Option<String>,
+    /// In MHz, zero when unknown
+    pub max_speed: u16,
+    pub current_speed: u16,
+    /// Zero when unknown
+    pub cores: u16,
+    pub threads: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDevice {
+    pub locator: Option<String>,
+    pub bank: Option<String>,
+    pub manufacturer: Option<String>,
+    pub part_number: Option<String>,
+    pub kind: u8,
+    /// In MiB, `None` when unknown
+    pub size: Option<u64>,
+    /// In MT/s, zero when unknown
+    pub speed: u16,
+}
+
+impl MemoryDevice {
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            0x03 => "DRAM",
+            0x07 => "RAM",
+            0x0F => "SDRAM",
+            0x12 => "DDR",
+            0x13 => "DDR2",
+            0x18 => "DDR3",
+            0x1A => "DDR4",
+            0x1B => "LPDDR",
+            0x1C => "LPDDR2",
+            0x1D => "LPDDR3",
+            0x1E => "LPDDR4",
+            0x22 => "DDR5",
+            0x23 => "LPDDR5",
+            _ => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemInfo {
+    /// The SMBIOS version as major and minor
+    pub version: (u8, u8),
+    pub bios: Option<Bios>,
+    pub system: Option<System>,
+    pub board: Option<Board>,
+    pub processors: Vec<Processor>,
+    /// Only the slots that have a module in them
+    pub memory: Vec<MemoryDevice>,
+}
+
+impl SystemInfo {
+    /// How much memory the firmware says is installed, in MiB
+    pub fn total_memory(&self) -> u64 {
+        self.memory.iter().filter_map(|device| device.size).sum()
+    }
+}
+
+/// One structure of the table, the formatted part and the strings after it
+struct Structure<'a> {
+    kind: u8,
+    formatted: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl Structure<'_> {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    fn word(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(
+            self.formatted.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+
+    fn dword(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(
+            self.formatted.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    }
+
+    /// The string whose index is at `offset`, strings are numbered from 1 and 0 means none
+    fn string(&self, offset: usize) -> Option<String> {
+        let index = self.byte(offset)? as usize;
+
+        if index == 0 {
+            return None;
+        }
+
+        let string = self.strings.split(|&byte| byte == 0).nth(index - 1)?;
+        let string = String::from_utf8_lossy(string);
+        let string = string.trim();
+
+        (!string.is_empty()).then(|| String::from(string))
+    }
+}
+
+/// Split the table into its structures, stopping at the end marker or anything malformed
+fn structures(table: &[u8]) -> impl Iterator<Item = Structure<'_>> {
+    let mut rest = table;
+
+    core::iter::from_fn(move || {
+        let &[kind, len, ..] = rest else {
+            return None;
+        };
+
+        let len = len as usize;
+
+        if len < 4 || len > rest.len() || kind == TYPE_END {
+            return None;
+        }
+
+        let (formatted, after) = rest.split_at(len);
+
+        // The strings end with two zeros, which are still there when there are no strings
+        let end = after.windows(2).position(|pair| pair == [0, 0])?;
+
+        rest = &after[end + 2..];
+
+        Some(Structure {
+            kind,
+            formatted,
+            strings: &after[..end],
+        })
+    })
+}
+
+fn parse_processor(structure: &Structure) -> Processor {
+    let mut cores = structure.byte(0x23).unwrap_or(0) as u16;
+    let mut threads = structure.byte(0x25).unwrap_or(0) as u16;
+
+    // Counts that do not fit in a byte are given again as words since SMBIOS 3.0
+    if cores == 0xFF {
+        cores = structure.word(0x2A).unwrap_or(0);
+    }
+
+    if threads == 0xFF {
+        threads = structure.word(0x2E).unwrap_or(0);
+    }
+
+    Processor {
+        socket: structure.string(0x04),
+        manufacturer: structure.string(0x07),
+        version: structure.string(0x10),
+        max_speed: structure.word(0x14).unwrap_or(0),
+        current_speed: structure.word(0x16).unwrap_or(0),
+        cores,
+        threads,
+    }
+}
+
+/// Returns `None` for empty slots
+fn parse_memory_device(structure: &Structure) -> Option<MemoryDevice> {
+    let size = match structure.word(0x0C)? {
+        0 => return None,
+        0xFFFF => None,
+        0x7FFF => structure
+            .dword(0x1C)
+            .map(|size| (size & 0x7FFF_FFFF) as u64),
+        // Given in KiB when the top bit is set
+        size if size & 0x8000 != 0 => Some((size & 0x7FFF) as u64 / 1024),
+        size => Some(size as u64),
+    };
+
+    Some(MemoryDevice {
+        locator: structure.string(0x10),
+        bank: structure.string(0x11),
+        manufacturer: structure.string(0x17),
+        part_number: structure.string(0x1A),
+        kind: structure.byte(0x12).unwrap_or(0),
+        size,
+        speed: structure.word(0x15).unwrap_or(0),
+    })
+}
+
+fn parse_table(version: (u8, u8), table: &[u8]) -> SystemInfo {
+    let mut info = SystemInfo {
+        version,
+        ..SystemInfo::default()
+    };
+
+    for structure in structures(table) {
+        match structure.kind {
+            TYPE_BIOS => {
+                info.bios = Some(Bios {
+                    vendor: structure.string(0x04),
+                    version: structure.string(0x05),
+                    date: structure.string(0x08),
+                })
+            }
+            TYPE_SYSTEM => {
+                info.system = Some(System {
+                    manufacturer: structure.string(0x04),
+                    product: structure.string(0x05),
+                    version: structure.string(0x06),
+                    serial: structure.string(0x07),
+                    uuid: structure
+                        .formatted
+                        .get(0x08..0x18)
+                        .and_then(|uuid| uuid.try_into().ok())
+                        // All zeros and all ones both mean there is no UUID
+                        .filter(|uuid: &[u8; 16]| {
+                            uuid.iter().any(|&byte| byte != 0)
+                                && uuid.iter().any(|&byte| byte != 0xFF)
+                        }),
+                })
+            }
+            TYPE_BOARD => {
+                info.board = Some(Board {
+                    manufacturer: structure.string(0x04),
+                    product: structure.string(0x05),
+                    version: structure.string(0x06),
+                    serial: structure.string(0x07),
+                })
+            }
+            TYPE_PROCESSOR => info.processors.push(parse_processor(&structure)),
+            TYPE_MEMORY_DEVICE => info.memory.extend(parse_memory_device(&structure)),
+            _ => {}
+        }
+    }
+
+    info
+}
+
+fn checksum_ok(entry: &Mmio, len: usize) -> bool {
+    (0..len).fold(0u8, |sum, offset| {
+        sum.wrapping_add(entry.read::<u8>(offset))
+    }) == 0
+}
+
+/// Find the structure table from an entry point, as the version, physical address and length
+fn entry_point() -> Option<((u8, u8), u64, usize)> {
+    let response = SMBIOS_REQUEST.get_response()?;
+
+    // The 64 bit entry point is preferred as it is the only one that can point above 4 GiB
+    if let Some(address) = response.entry_64() {
+        let address = paging::phys_from_bootloader(address.get() as u64);
+        let entry = Mmio::map(address, 0x18);
+
+        if entry.read::<[u8; 5]>(0) == *b"_SM3_" {
+            let len = (entry.read::<u8>(0x06) as usize).clamp(0x18, 0x20);
+
+            let entry = Mmio::map(address, len);
+
+            if checksum_ok(&entry, len) {
+                return Some((
+                    (entry.read(0x07), entry.read(0x08)),
+                    entry.read::<u64>(0x10),
+                    entry.read::<u32>(0x0C) as usize,
+                ));
+            }
+        }
+    }
+
+    let address = paging::phys_from_bootloader(response.entry_32()?.get() as u64);
+    let entry = Mmio::map(address, 0x1F);
+
+    if entry.read::<[u8; 4]>(0) != *b"_SM_"
+        || !checksum_ok(&entry, entry.read::<u8>(0x05).min(0x1F) as usize)
+    {
+        return None;
+    }
+
+    Some((
+        (entry.read(0x06), entry.read(0x07)),
+        entry.read::<u32>(0x18) as u64,
+        entry.read::<u16>(0x16) as usize,
+    ))
+}
+
+fn parse() -> Option<SystemInfo> {
+    let (version, address, len) = entry_point()?;
+
+    // The 64 bit entry point only gives the most the table can be
+    let len = len.min(MAX_TABLE_SIZE);
+
+    if len == 0 {
+        return None;
+    }
+
+    let mapping = Mmio::map(address, len);
+    let table = (0..len)
+        .map(|offset| mapping.read::<u8>(offset))
+        .collect::<Vec<_>>();
+
+    Some(parse_table(version, &table))
+}
+
+lazy_static! {
+    static ref INFO: Option<SystemInfo> = parse();
+}
+
+/// What the SMBIOS tables say, if the firmware has any
+pub fn get() -> Option<&'static SystemInfo> {
+    INFO.as_ref()
+}
+
+struct Field<'a>(&'a Option<String>);
+
+impl fmt::Display for Field<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.as_deref().unwrap_or("unknown"))
+    }
+}
+
+/// One `key: value` per line, meant to be read by people and by scripts alike
+impl fmt::Display for SystemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "smbios: {}.{}", self.version.0, self.version.1)?;
+
+        if let Some(bios) = &self.bios {
+            writeln!(f, "bios_vendor: {}", Field(&bios.vendor))?;
+            writeln!(f, "bios_version: {}", Field(&bios.version))?;
+            writeln!(f, "bios_date: {}", Field(&bios.date))?;
+        }
+
+        if let Some(system) = &self.system {
+            writeln!(f, "sys_vendor: {}", Field(&system.manufacturer))?;
+            writeln!(f, "product_name: {}", Field(&system.product))?;
+            writeln!(f, "product_version: {}", Field(&system.version))?;
+            writeln!(f, "product_serial: {}", Field(&system.serial))?;
+
+            if let Some(uuid) = system.uuid {
+                // The first three fields are little endian
+                writeln!(
+                    f,
+                    "product_uuid: {:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                    u32::from_le_bytes([uuid[0], uuid[1], uuid[2], uuid[3]]),
+                    u16::from_le_bytes([uuid[4], uuid[5]]),
+                    u16::from_le_bytes([uuid[6], uuid[7]]),
+                    uuid[8],
+                    uuid[9],
+                    uuid[10],
+                    uuid[11],
+                    uuid[12],
+                    uuid[13],
+                    uuid[14],
+                    uuid[15],
+                )?;
+            }
+        }
+
+        if let Some(board) = &self.board {
+            writeln!(f, "board_vendor: {}", Field(&board.manufacturer))?;
+            writeln!(f, "board_name: {}", Field(&board.product))?;
+            writeln!(f, "board_version: {}", Field(&board.version))?;
+            writeln!(f, "board_serial: {}", Field(&board.serial))?;
+        }
+
+        for (i, processor) in self.processors.iter().enumerate() {
+            writeln!(
+                f,
+                "cpu{}: {}, {} {}, {} cores, {} threads, {}/{} MHz",
+                i,
+                Field(&processor.socket),
+                Field(&processor.manufacturer),
+                Field(&processor.version),
+                processor.cores,
+                processor.threads,
+                processor.current_speed,
+                processor.max_speed
+            )?;
+        }
+
+        for (i, device) in self.memory.iter().enumerate() {
+            write!(f, "memory{}: {}, ", i, Field(&device.locator))?;
+
+            match device.size {
+                Some(size) => write!(f, "{} MiB", size)?,
+                None => write!(f, "unknown size")?,
+            }
+
+            writeln!(
+                f,
+                " {} at {} MT/s, {} {}",
+                device.kind_name(),
+                device.speed,
+                Field(&device.manufacturer),
+                Field(&device.part_number)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn init() {
+    let Some(info) = get() else {
+        println!("sysinfo: no smbios tables");
+        return;
+    };
+
+    let (vendor, product) = info.system.as_ref().map_or((&None, &None), |system| {
+        (&system.manufacturer, &system.product)
+    });
+
+    println!(
+        "sysinfo: smbios {}.{}, {} {}",
+        info.version.0,
+        info.version.1,
+        Field(vendor),
+        Field(product)
+    );
+
+    if let Some(bios) = &info.bios {
+        println!(
+            "sysinfo: bios {} {} {}",
+            Field(&bios.vendor),
+            Field(&bios.version),
+            Field(&bios.date)
+        );
+    }
+
+    println!(
+        "sysinfo: {} processors, {} MiB in {} memory modules",
+        info.processors.len(),
+        info.total_memory(),
+        info.memory.len()
+    );
+}
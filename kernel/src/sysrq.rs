@@ -0,0 +1,126 @@
+use crate::{
+    arch, cma, compaction, console, frame, idle, irq, kdb, kexec, meminfo, mmap, module, net, oom,
+    page, pci, stack, suspend, swap, thermal,
+};
+
+pub struct Action {
+    pub key: u8,
+    pub description: &'static str,
+    pub handler: fn(),
+}
+
+pub static ACTIONS: &[Action] = &[
+    Action {
+        key: b'b',
+        description: "reboot immediately without syncing",
+        handler: || arch::reboot(),
+    },
+    Action {
+        key: b'c',
+        description: "crash the kernel for testing",
+        handler: || panic!("crash triggered by sysrq"),
+    },
+    Action {
+        key: b'd',
+        description: "write the text on the screen to the serial port",
+        handler: console::mirror_to_serial,
+    },
+    Action {
+        key: b'g',
+        description: "enter the debug shell",
+        handler: kdb::request,
+    },
+    Action {
+        key: b'h',
+        description: "show this help",
+        handler: show_help,
+    },
+    Action {
+        key: b'i',
+        description: "dump idle state usage and residency",
+        handler: idle::dump,
+    },
+    Action {
+        key: b'k',
+        description: "boot the kernel loaded for kexec",
+        handler: || println!("kexec: could not start: {:?}", kexec::exec()),
+    },
+    Action {
+        key: b'l',
+        description: "list loaded modules",
+        handler: module::dump,
+    },
+    Action {
+        key: b'm',
+        description: "dump memory statistics",
+        handler: dump_memory_stats,
+    },
+    Action {
+        key: b'n',
+        description: "show network interfaces, routes and neighbors",
+        handler: net::dump,
+    },
+    Action {
+        key: b'p',
+        description: "write captured packets to the serial port as pcap",
+        handler: net::capture::dump,
+    },
+    Action {
+        key: b'q',
+        description: "dump interrupt counts and problems",
+        handler: irq::dump,
+    },
+    Action {
+        key: b's',
+        description: "dump kernel stack usage",
+        handler: stack::dump,
+    },
+    Action {
+        key: b't',
+        description: "show processor temperatures",
+        handler: thermal::dump,
+    },
+    Action {
+        key: b'v',
+        description: "dump pci configuration headers",
+        handler: pci::dump,
+    },
+    Action {
+        key: b'z',
+        description: "suspend to ram",
+        handler: || {
+            if let Err(error) = suspend::suspend_to_ram() {
+                println!("suspend: could not suspend: {:?}", error);
+            }
+        },
+    },
+];
+
+/// Run the action bound to `key`, either from Alt+SysRq or from a serial break followed by a key
+pub fn handle(key: u8) {
+    match ACTIONS.iter().find(|action| action.key == key) {
+        Some(action) => (action.handler)(),
+        None => show_help(),
+    }
+}
+
+fn show_help() {
+    println!("sysrq: available actions:");
+
+    for action in ACTIONS {
+        println!("    {}: {}", action.key as char, action.description);
+    }
+}
+
+fn dump_memory_stats() {
+    // The allocators may be locked by whatever got interrupted, they are not waited for as that
+    // would wedge the system even more
+    meminfo::dump();
+    frame::dump();
+    compaction::dump();
+    cma::dump();
+    page::dump();
+    swap::dump();
+    mmap::dump();
+    oom::dump();
+}
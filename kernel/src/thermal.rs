@@ -0,0 +1,208 @@
+//! Watching the digital thermal sensor, and slowing down when it gets too hot
+//!
+//! Only the processor the kernel runs on is read, which is the only one running
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::cpu,
+    cmdline,
+    cpufreq::{self, Governor},
+    idle,
+    sync::Mutex,
+    timer,
+};
+
+const MSR_THERM_STATUS: u32 = 0x19C;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+const MSR_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+
+const THERM_STATUS_VALID: u64 = 1 << 31;
+
+/// What is assumed when the processor does not say how hot it may get
+const DEFAULT_TJ_MAX: u8 = 100;
+
+/// How far below the maximum the default trip point is
+const DEFAULT_TRIP_MARGIN: u8 = 10;
+
+/// The temperature has to drop this far below the trip point before throttling stops
+const HYSTERESIS: u8 = 5;
+
+const POLL_INTERVAL_NS: u64 = 1_000_000_000;
+
+/// How much of each interval is spent idle while throttling without frequency scaling
+const INJECTED_IDLE_NS: u64 = 250_000_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Temperatures {
+    /// In degrees celsius
+    pub core: Option<u8>,
+    pub package: Option<u8>,
+    pub tj_max: u8,
+    pub trip: u8,
+    pub throttling: bool,
+}
+
+struct Sensor {
+    tj_max: u8,
+    trip: u8,
+    package: bool,
+    /// The governor to go back to once cool again, when throttling through cpufreq
+    previous_governor: Option<Governor>,
+}
+
+static SENSOR: Mutex<Option<Sensor>> = Mutex::new(None);
+static THROTTLING: AtomicBool = AtomicBool::new(false);
+
+/// The temperature from a thermal status register, which counts down to the maximum
+fn read(msr: u32, tj_max: u8) -> Option<u8> {
+    let status = cpu::rdmsr(msr);
+
+    (status & THERM_STATUS_VALID != 0).then(|| tj_max.saturating_sub((status >> 16) as u8 & 0x7F))
+}
+
+pub fn temperatures() -> Option<Temperatures> {
+    let sensor = SENSOR.lock();
+    let sensor = sensor.as_ref()?;
+
+    Some(Temperatures {
+        core: read(MSR_THERM_STATUS, sensor.tj_max),
+        package: sensor
+            .package
+            .then(|| read(MSR_PACKAGE_THERM_STATUS, sensor.tj_max))
+            .flatten(),
+        tj_max: sensor.tj_max,
+        trip: sensor.trip,
+        throttling: THROTTLING.load(Ordering::Relaxed),
+    })
+}
+
+fn start_throttling(sensor: &mut Sensor, temperature: u8) {
+    println!(
+        "thermal: {} C is past the trip point of {} C, throttling",
+        temperature, sensor.trip
+    );
+
+    THROTTLING.store(true, Ordering::Relaxed);
+
+    if let Some(governor) = cpufreq::governor()
+        && cpufreq::set_governor(Governor::Powersave).is_ok()
+    {
+        sensor.previous_governor = Some(governor);
+    }
+}
+
+fn stop_throttling(sensor: &mut Sensor, temperature: u8) {
+    println!("thermal: cooled down to {} C", temperature);
+
+    THROTTLING.store(false, Ordering::Relaxed);
+
+    if let Some(governor) = sensor.previous_governor.take() {
+        let _ = cpufreq::set_governor(governor);
+    }
+}
+
+fn poll() {
+    let inject = {
+        let mut sensor = SENSOR.lock();
+
+        let Some(sensor) = sensor.as_mut() else {
+            return;
+        };
+
+        let hottest = read(MSR_THERM_STATUS, sensor.tj_max).max(
+            sensor
+                .package
+                .then(|| read(MSR_PACKAGE_THERM_STATUS, sensor.tj_max))
+                .flatten(),
+        );
+
+        if let Some(temperature) = hottest {
+            let throttling = THROTTLING.load(Ordering::Relaxed);
+
+            if !throttling && temperature >= sensor.trip {
+                start_throttling(sensor, temperature);
+            } else if throttling && temperature + HYSTERESIS <= sensor.trip {
+                stop_throttling(sensor, temperature);
+            }
+        }
+
+        // Without a governor to lower the frequency, make the processor rest instead
+        THROTTLING.load(Ordering::Relaxed) && sensor.previous_governor.is_none()
+    };
+
+    if inject {
+        idle::inject(INJECTED_IDLE_NS);
+    }
+
+    timer::add(POLL_INTERVAL_NS, poll);
+}
+
+struct Celsius(Option<u8>);
+
+impl fmt::Display for Celsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(temperature) => write!(f, "{} C", temperature),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+pub fn dump() {
+    let Some(temperatures) = temperatures() else {
+        println!("thermal: no sensor");
+        return;
+    };
+
+    println!(
+        "thermal: core {}, package {}, trip {} C, maximum {} C{}",
+        Celsius(temperatures.core),
+        Celsius(temperatures.package),
+        temperatures.trip,
+        temperatures.tj_max,
+        if temperatures.throttling {
+            ", throttling"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Start watching the sensor, the trip point is `thermal.trip=<celsius>` or a little below the
+/// processor's maximum
+pub fn init() {
+    if !cpu::is_intel() || cpu::max_leaf() < 6 || cpu::cpuid(6, 0).eax & 1 == 0 {
+        return;
+    }
+
+    let package = cpu::cpuid(6, 0).eax & (1 << 6) != 0;
+
+    // The maximum is only in the temperature target register on family 6, and hypervisors often
+    // do not have the register at all
+    let tj_max = match cpu::family() {
+        6 => match cpu::rdmsr_safe(MSR_TEMPERATURE_TARGET).map(|target| (target >> 16) as u8) {
+            None | Some(0) => DEFAULT_TJ_MAX,
+            Some(tj_max) => tj_max,
+        },
+        _ => DEFAULT_TJ_MAX,
+    };
+
+    let trip = cmdline::value("thermal.trip")
+        .and_then(|trip| trip.parse::<u8>().ok())
+        .unwrap_or(tj_max.saturating_sub(DEFAULT_TRIP_MARGIN));
+
+    *SENSOR.lock() = Some(Sensor {
+        tj_max,
+        trip,
+        package,
+        previous_governor: None,
+    });
+
+    timer::add(POLL_INTERVAL_NS, poll);
+
+    dump();
+}
@@ -0,0 +1,284 @@
+//! Time since boot, counted by the time stamp counter, and the wall clock on top of it
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    arch::{cpu, pit},
+    hpet,
+    sync::Mutex,
+    timer, vdso,
+};
+
+pub const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// How often the frequency is measured again when it may change or is not known exactly
+const RECALIBRATE_INTERVAL_NS: u64 = NANOSECONDS_PER_SECOND;
+
+/// The most the clock is sped up or slowed down to make up for drift, a part of the interval
+const MAX_SLEW_NS: i64 = (RECALIBRATE_INTERVAL_NS / 100) as i64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// From the crystal frequency in cpuid, exact
+    Cpuid,
+    /// From the base frequency in cpuid, to the nearest megahertz
+    BaseFrequency,
+    /// Measured against the programmable interval timer
+    Pit,
+}
+
+/// The clock is a line through (`BASE_TSC`, `BASE_NS`) with a slope of `FREQUENCY`, which is
+/// moved whenever the frequency changes, so readers go by `SEQUENCE` which is odd while it moves
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref SOURCE: Source = calibrate();
+}
+
+fn calibrate() -> Source {
+    let (frequency, source) = match cpu::tsc_frequency() {
+        Some((frequency, true)) => (frequency, Source::Cpuid),
+        Some((frequency, false)) => (frequency, Source::BaseFrequency),
+        None => (pit::tsc_frequency(), Source::Pit),
+    };
+
+    rebase(cpu::rdtsc(), 0, frequency);
+
+    source
+}
+
+fn rebase(tsc: u64, ns: u64, frequency: u64) {
+    SEQUENCE.fetch_add(1, Ordering::Acquire);
+
+    BASE_TSC.store(tsc, Ordering::Relaxed);
+    BASE_NS.store(ns, Ordering::Relaxed);
+    FREQUENCY.store(frequency.max(1), Ordering::Relaxed);
+
+    SEQUENCE.fetch_add(1, Ordering::Release);
+
+    vdso::publish();
+}
+
+/// Whether the frequency was found yet, reading the clock before finds it if it was not
+pub fn is_calibrated() -> bool {
+    SEQUENCE.load(Ordering::Acquire) != 0
+}
+
+/// The time stamp the clock was last moved at, the clock then and the frequency since, for code
+/// that works the clock out by itself like the vDSO's
+pub fn clock() -> (u64, u64, u64) {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+
+        if sequence & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+
+        let clock = (
+            BASE_TSC.load(Ordering::Relaxed),
+            BASE_NS.load(Ordering::Relaxed),
+            FREQUENCY.load(Ordering::Relaxed),
+        );
+
+        if SEQUENCE.load(Ordering::Acquire) == sequence {
+            return clock;
+        }
+    }
+}
+
+/// The clock at the time stamp `tsc`
+fn ns_at(tsc: u64) -> u64 {
+    lazy_static::initialize(&SOURCE);
+
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+
+        if sequence & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+
+        let base_tsc = BASE_TSC.load(Ordering::Relaxed);
+        let base_ns = BASE_NS.load(Ordering::Relaxed);
+        let frequency = FREQUENCY.load(Ordering::Relaxed);
+
+        if SEQUENCE.load(Ordering::Acquire) != sequence {
+            continue;
+        }
+
+        let ticks = tsc.saturating_sub(base_tsc);
+
+        return base_ns
+            + (ticks as u128 * NANOSECONDS_PER_SECOND as u128 / frequency as u128) as u64;
+    }
+}
+
+pub fn tsc_frequency() -> u64 {
+    lazy_static::initialize(&SOURCE);
+
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn source() -> Source {
+    *SOURCE
+}
+
+/// Nanoseconds since the clock was initialized
+pub fn monotonic_ns() -> u64 {
+    ns_at(cpu::rdtsc())
+}
+
+crate::export!(monotonic_ns: fn() -> u64);
+
+/// Where the high precision event timer was last read, and what the clock should have been then
+struct Reference {
+    tsc: u64,
+    counter: u64,
+    ns: u64,
+}
+
+static REFERENCE: Mutex<Option<Reference>> = Mutex::new(None);
+
+/// Measure the frequency over the last interval, and aim to be back on the high precision event
+/// timer's time at the end of the next one
+fn recalibrate() {
+    let Some(hpet) = hpet::get() else {
+        return;
+    };
+
+    let mut reference = REFERENCE.lock();
+
+    let Some(reference) = reference.as_mut() else {
+        return;
+    };
+
+    let tsc = cpu::rdtsc();
+    let counter = hpet.counter();
+
+    let ticks = tsc.saturating_sub(reference.tsc);
+    let elapsed_ns = hpet.ticks_to_ns(hpet.elapsed(reference.counter, counter));
+
+    reference.tsc = tsc;
+    reference.counter = counter;
+    reference.ns += elapsed_ns;
+
+    if ticks != 0 && elapsed_ns != 0 {
+        let measured = ticks as i128 * NANOSECONDS_PER_SECOND as i128 / elapsed_ns as i128;
+
+        let now = ns_at(tsc);
+        let ahead = (now as i64 - reference.ns as i64).clamp(-MAX_SLEW_NS, MAX_SLEW_NS);
+
+        // Running faster than the counter makes the clock slower
+        let frequency = measured * RECALIBRATE_INTERVAL_NS as i128
+            / (RECALIBRATE_INTERVAL_NS as i64 - ahead) as i128;
+
+        rebase(tsc, now, frequency as u64);
+    }
+
+    timer::add(RECALIBRATE_INTERVAL_NS, recalibrate);
+}
+
+pub fn monotonic_ms() -> u64 {
+    monotonic_ns() / 1_000_000
+}
+
+/// Nanoseconds since the unix epoch at boot, zero until the firmware tells the time
+static BOOT_REALTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds since the unix epoch, if the time is known
+pub fn realtime_ns() -> Option<u64> {
+    match BOOT_REALTIME_NS.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(boot + monotonic_ns()),
+    }
+}
+
+/// The wall clock when the clock was zero, zero if the time is not known
+pub fn boot_realtime_ns() -> u64 {
+    BOOT_REALTIME_NS.load(Ordering::Relaxed)
+}
+
+pub fn set_realtime_ns(now: u64) {
+    BOOT_REALTIME_NS.store(now.saturating_sub(monotonic_ns()).max(1), Ordering::Relaxed);
+
+    vdso::publish();
+}
+
+/// The clock when the system went to sleep, the time stamp counter starts over on wake up
+static SUSPENDED_NS: AtomicU64 = AtomicU64::new(0);
+
+pub fn suspend() {
+    SUSPENDED_NS.store(monotonic_ns(), Ordering::Relaxed);
+}
+
+/// Carry on from where the clock stopped, the time asleep is left out like it is on other systems
+pub fn resume() {
+    let tsc = cpu::rdtsc();
+    let ns = SUSPENDED_NS.load(Ordering::Relaxed);
+
+    rebase(tsc, ns, tsc_frequency());
+
+    if let Some(reference) = REFERENCE.lock().as_mut()
+        && let Some(hpet) = hpet::get()
+    {
+        *reference = Reference {
+            tsc,
+            counter: hpet.counter(),
+            ns,
+        };
+    }
+}
+
+/// Busy wait for at least `ns` nanoseconds
+pub fn delay_ns(ns: u64) {
+    let end = monotonic_ns() + ns;
+
+    while monotonic_ns() < end {
+        core::hint::spin_loop();
+    }
+}
+
+crate::export!(delay_ns: fn(u64));
+
+pub fn init() {
+    let invariant = cpu::has_invariant_tsc();
+    let source = source();
+
+    println!(
+        "time: tsc runs at {} khz from {:?}{}",
+        tsc_frequency() / 1000,
+        source,
+        if invariant { ", invariant" } else { "" }
+    );
+
+    // An exact frequency that never changes needs nothing more
+    if invariant && source == Source::Cpuid {
+        return;
+    }
+
+    let Some(hpet) = hpet::get() else {
+        println!("time: no hpet to correct the tsc against");
+        return;
+    };
+
+    let tsc = cpu::rdtsc();
+
+    *REFERENCE.lock() = Some(Reference {
+        tsc,
+        counter: hpet.counter(),
+        ns: ns_at(tsc),
+    });
+
+    timer::add(RECALIBRATE_INTERVAL_NS, recalibrate);
+
+    println!(
+        "time: correcting the tsc against the hpet at {} khz",
+        hpet.frequency() / 1000
+    );
+}
@@ -0,0 +1,146 @@
+//! A hashed timer wheel for callbacks that run after a delay
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    softirq::{self, Softirq},
+    sync::Mutex,
+    time,
+};
+
+/// How coarse the wheel is, timers fire on the first tick after they expire
+pub const TICK_NS: u64 = 10_000_000;
+
+const SLOTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    expires_at_tick: u64,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+struct Wheel {
+    /// Timers hash into the slot of the tick they expire at, those further than a whole turn away
+    /// stay in their slot until their tick comes around
+    slots: Vec<Vec<Timer>>,
+    current_tick: u64,
+    next_id: u64,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: Vec::new(),
+    current_tick: 0,
+    next_id: 0,
+});
+
+/// The newest tick the softirq was raised for
+static RAISED_TICK: AtomicU64 = AtomicU64::new(0);
+
+fn now_tick() -> u64 {
+    time::monotonic_ns() / TICK_NS
+}
+
+/// Run `callback` after at least `delay_ns`, from the timer softirq
+pub fn add(delay_ns: u64, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let mut wheel = WHEEL.lock();
+
+    if wheel.slots.is_empty() {
+        wheel.slots.resize_with(SLOTS, Vec::new);
+        wheel.current_tick = now_tick();
+    }
+
+    let id = TimerId(wheel.next_id);
+    wheel.next_id += 1;
+
+    let expires_at_tick = now_tick().max(wheel.current_tick) + delay_ns.div_ceil(TICK_NS).max(1);
+
+    wheel.slots[expires_at_tick as usize % SLOTS].push(Timer {
+        id,
+        expires_at_tick,
+        callback: Box::new(callback),
+    });
+
+    id
+}
+
+/// Stop a timer from firing, returns false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    let mut wheel = WHEEL.lock();
+
+    for slot in &mut wheel.slots {
+        if let Some(index) = slot.iter().position(|timer| timer.id == id) {
+            slot.swap_remove(index);
+
+            return true;
+        }
+    }
+
+    false
+}
+
+/// When the first timer expires in nanoseconds since boot, if there is one
+pub fn next_expiry_ns() -> Option<u64> {
+    let wheel = WHEEL.lock();
+
+    wheel
+        .slots
+        .iter()
+        .flatten()
+        .map(|timer| timer.expires_at_tick * TICK_NS)
+        .min()
+}
+
+/// Raise the timer softirq when a tick passed, called from the main loop
+pub fn poll() {
+    let tick = now_tick();
+
+    if RAISED_TICK.fetch_max(tick, Ordering::AcqRel) < tick {
+        softirq::raise(Softirq::Timer);
+    }
+}
+
+fn run() {
+    let tick = now_tick();
+    let mut expired = Vec::new();
+
+    {
+        let mut wheel = WHEEL.lock();
+
+        if wheel.slots.is_empty() {
+            return;
+        }
+
+        // Falling more than a turn behind only means visiting every slot once
+        let first = wheel
+            .current_tick
+            .max(tick.saturating_sub(SLOTS as u64 - 1));
+
+        for current in first..=tick {
+            let slot = &mut wheel.slots[current as usize % SLOTS];
+            let mut i = 0;
+
+            while i < slot.len() {
+                if slot[i].expires_at_tick <= tick {
+                    expired.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        wheel.current_tick = tick + 1;
+    }
+
+    // The callbacks may add timers, so the wheel must not be locked while they run
+    for timer in expired {
+        (timer.callback)();
+    }
+}
+
+pub fn init() {
+    softirq::register(Softirq::Timer, run);
+}
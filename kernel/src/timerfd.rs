@@ -0,0 +1,208 @@
+//! Timerfd, a file that becomes readable when a timer on the timer wheel expires
+
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+
+use crate::{
+    file::{Error, Events, File},
+    sync::Mutex,
+    time,
+    timer::{self, TimerId},
+    wait::{self, WaitQueue},
+};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct TimerFdFlags: u32 {
+        const NONBLOCK = 0o4000;
+        const CLOEXEC = 0o2000000;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct SetTimeFlags: u32 {
+        /// The expiry is a point on the monotonic clock instead of a delay
+        const ABSOLUTE = 1 << 0;
+    }
+}
+
+/// When a timer expires next and how often after that, zero means never
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimerSpec {
+    pub value_ns: u64,
+    pub interval_ns: u64,
+}
+
+#[derive(Default)]
+struct State {
+    /// Expirations since the last read
+    expirations: u64,
+    /// Monotonic time of the next expiry, zero while disarmed
+    expires_at: u64,
+    interval_ns: u64,
+    timer: Option<TimerId>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    wait_queue: WaitQueue,
+}
+
+/// Put the timer on the wheel for the next expiry
+fn arm(inner: &Arc<Inner>, state: &mut State) {
+    let inner = Arc::downgrade(inner);
+    let delay = state.expires_at.saturating_sub(time::monotonic_ns());
+
+    state.timer = Some(timer::add(delay, move || expire(inner)));
+}
+
+fn expire(inner: Weak<Inner>) {
+    // The file was closed, the timer only missed being cancelled
+    let Some(inner) = inner.upgrade() else {
+        return;
+    };
+
+    {
+        let mut state = inner.state.lock();
+
+        state.timer = None;
+
+        if state.expires_at == 0 {
+            return;
+        }
+
+        let now = time::monotonic_ns();
+
+        // Catch up on the expirations the wheel was too coarse to fire one by one
+        if let Some(missed) = now
+            .saturating_sub(state.expires_at)
+            .checked_div(state.interval_ns)
+        {
+            state.expirations += 1 + missed;
+            state.expires_at += (1 + missed) * state.interval_ns;
+
+            arm(&inner, &mut state);
+        } else {
+            state.expirations += 1;
+            state.expires_at = 0;
+        }
+    }
+
+    inner.wait_queue.wake_all();
+}
+
+pub struct TimerFd {
+    inner: Arc<Inner>,
+    nonblocking: AtomicBool,
+}
+
+impl TimerFd {
+    /// A disarmed timer on the monotonic clock, the only clock there is yet
+    pub fn new(flags: TimerFdFlags) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::default()),
+                wait_queue: WaitQueue::new(),
+            }),
+            nonblocking: AtomicBool::new(flags.contains(TimerFdFlags::NONBLOCK)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    fn current(state: &State) -> TimerSpec {
+        TimerSpec {
+            value_ns: if state.expires_at == 0 {
+                0
+            } else {
+                state.expires_at.saturating_sub(time::monotonic_ns()).max(1)
+            },
+            interval_ns: state.interval_ns,
+        }
+    }
+
+    /// Arm the timer, or disarm it with a zero value, returning what it was set to before
+    pub fn set_time(&self, spec: TimerSpec, flags: SetTimeFlags) -> TimerSpec {
+        let mut state = self.inner.state.lock();
+
+        let old = Self::current(&state);
+
+        if let Some(timer) = state.timer.take() {
+            timer::cancel(timer);
+        }
+
+        state.expirations = 0;
+        state.interval_ns = spec.interval_ns;
+        state.expires_at = match spec.value_ns {
+            0 => 0,
+            value if flags.contains(SetTimeFlags::ABSOLUTE) => value,
+            value => time::monotonic_ns() + value,
+        };
+
+        if state.expires_at != 0 {
+            arm(&self.inner, &mut state);
+        }
+
+        old
+    }
+
+    /// How long until the next expiry and the interval, `timerfd_gettime`
+    pub fn time(&self) -> TimerSpec {
+        Self::current(&self.inner.state.lock())
+    }
+}
+
+impl File for TimerFd {
+    /// Read the expirations since the last read as 8 native endian bytes, waiting for one
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let buffer: &mut [u8; 8] = buffer
+            .get_mut(..8)
+            .and_then(|buffer| buffer.try_into().ok())
+            .ok_or(Error::InvalidArgument)?;
+
+        loop {
+            let mut state = self.inner.state.lock();
+
+            if state.expirations != 0 {
+                *buffer = core::mem::take(&mut state.expirations).to_ne_bytes();
+
+                return Ok(8);
+            }
+
+            drop(state);
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(Error::WouldBlock);
+            }
+
+            wait::until(None, || {
+                (self.inner.state.lock().expirations != 0).then_some(())
+            });
+        }
+    }
+
+    fn poll(&self) -> Events {
+        if self.inner.state.lock().expirations != 0 {
+            Events::READABLE
+        } else {
+            Events::empty()
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.inner.wait_queue)
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        if let Some(timer) = self.inner.state.lock().timer.take() {
+            timer::cancel(timer);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Terminals, which put job control in front of a character device like a console port
+//!
+//! A terminal is the controlling terminal of at most one session, with one of the session's
+//! process groups in the foreground. Reading from it in the background sends the reader's group
+//! `SIGTTIN`, and so does writing `SIGTTOU` when background writes are stopped, failing with
+//! [`Error::Interrupted`] as the signal would interrupt the syscall. The interrupt, quit and
+//! suspend characters are not read but send their signal to the foreground group
+//!
+//! There is no line editing or echo, what the device gives is read as it is
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{
+    file::{Error, Events, File},
+    process::{self, Pid},
+    signal::{self, Signal},
+    wait::WaitQueue,
+};
+
+/// Ctrl+C
+const INTERRUPT: u8 = 0x03;
+/// Ctrl+\
+const QUIT: u8 = 0x1C;
+/// Ctrl+Z
+const SUSPEND: u8 = 0x1A;
+
+pub struct Tty {
+    device: Arc<dyn File>,
+    /// The session it is the controlling terminal of and its foreground process group, zero for
+    /// none
+    session: AtomicU32,
+    foreground: AtomicU32,
+    /// `TOSTOP`, without it anyone in the session can write
+    stop_background_writes: AtomicBool,
+}
+
+impl Tty {
+    pub fn new(device: Arc<dyn File>) -> Arc<Self> {
+        Arc::new(Self {
+            device,
+            session: AtomicU32::new(0),
+            foreground: AtomicU32::new(0),
+            stop_background_writes: AtomicBool::new(false),
+        })
+    }
+
+    pub fn session(&self) -> Pid {
+        self.session.load(Ordering::Relaxed)
+    }
+
+    /// Make it the controlling terminal of the current process's session, which it has to lead
+    /// without having one yet
+    pub fn set_controlling(self: &Arc<Self>) -> Result<(), process::Error> {
+        let current = process::current().ok_or(process::Error::NoThread)?;
+        let process = current.process();
+
+        if process.sid() != process.pid() || process.tty().is_some() {
+            return Err(process::Error::PermissionDenied);
+        }
+
+        self.session
+            .compare_exchange(0, process.sid(), Ordering::Relaxed, Ordering::Relaxed)
+            .map_err(|_| process::Error::PermissionDenied)?;
+
+        self.foreground.store(process.pgid(), Ordering::Relaxed);
+        process.set_tty(Some(self.clone()));
+
+        Ok(())
+    }
+
+    /// The foreground process group, `tcgetpgrp`
+    pub fn foreground(&self) -> Pid {
+        self.foreground.load(Ordering::Relaxed)
+    }
+
+    /// Put the group `pgid` of the session in the foreground, `tcsetpgrp`
+    pub fn set_foreground(&self, pgid: Pid) -> Result<(), process::Error> {
+        let current = process::current().ok_or(process::Error::NoThread)?;
+        let process = current.process();
+
+        if self.session() == 0 || process.sid() != self.session() {
+            return Err(process::Error::NotFound);
+        }
+
+        // Only the foreground can hand the terminal over
+        if process.pgid() != self.foreground() {
+            signal::send_group(process.pgid(), Signal::TerminalOutput);
+
+            return Err(process::Error::Interrupted);
+        }
+
+        let in_session = process::all()
+            .iter()
+            .any(|process| process.pgid() == pgid && process.sid() == self.session());
+
+        if !in_session {
+            return Err(process::Error::PermissionDenied);
+        }
+
+        self.foreground.store(pgid, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn set_stop_background_writes(&self, stop: bool) {
+        self.stop_background_writes.store(stop, Ordering::Relaxed);
+    }
+
+    /// The session is gone, tell its foreground and let the terminal be another's
+    pub fn hangup(&self) {
+        let foreground = self.foreground.swap(0, Ordering::Relaxed);
+
+        self.session.store(0, Ordering::Relaxed);
+
+        if foreground != 0 {
+            signal::send_group(foreground, Signal::Hangup);
+            signal::send_group(foreground, Signal::Continue);
+        }
+    }
+
+    /// Send the group of the current process `signal` if it is in the session but not in the
+    /// foreground
+    fn check_background(&self, signal: Signal) -> Result<(), Error> {
+        let Some(current) = process::current() else {
+            return Ok(());
+        };
+
+        let process = current.process();
+
+        if self.session() == 0
+            || process.sid() != self.session()
+            || process.pgid() == self.foreground()
+        {
+            return Ok(());
+        }
+
+        signal::send_group(process.pgid(), signal);
+
+        Err(Error::Interrupted)
+    }
+}
+
+impl File for Tty {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.check_background(Signal::TerminalInput)?;
+
+        let read = self.device.read(buffer)?;
+
+        let mut kept = 0;
+        let mut signalled = false;
+
+        for index in 0..read {
+            let signal = match buffer[index] {
+                INTERRUPT => Signal::Interrupt,
+                QUIT => Signal::Quit,
+                SUSPEND => Signal::TerminalStop,
+                byte => {
+                    buffer[kept] = byte;
+                    kept += 1;
+                    continue;
+                }
+            };
+
+            if self.foreground() != 0 {
+                signal::send_group(self.foreground(), signal);
+            }
+
+            signalled = true;
+        }
+
+        // Only control characters came in, whose signals interrupt the read
+        if kept == 0 && signalled {
+            return Err(Error::Interrupted);
+        }
+
+        Ok(kept)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        if self.stop_background_writes.load(Ordering::Relaxed) {
+            self.check_background(Signal::TerminalOutput)?;
+        }
+
+        self.device.write(data)
+    }
+
+    fn poll(&self) -> Events {
+        self.device.poll()
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        self.device.wait_queue()
+    }
+}
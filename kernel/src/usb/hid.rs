@@ -0,0 +1,269 @@
+//! USB HID keyboards and mice, in the boot protocol so there is no report descriptor to parse
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    input::{self, BTN_EXTRA, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, BTN_SIDE, Event},
+    sync::Mutex,
+    usb::{
+        Device, Direction, EndpointDescriptor, Error, Interface, REQUEST_RECIPIENT_INTERFACE,
+        REQUEST_TYPE_CLASS, SetupPacket, Transfer, TransferType,
+    },
+};
+
+pub const CLASS_HID: u8 = 3;
+
+const SUBCLASS_BOOT: u8 = 1;
+
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+
+const PROTOCOL_BOOT: u16 = 0;
+
+/// Modifiers, a reserved byte and six keys
+const KEYBOARD_REPORT_SIZE: usize = 8;
+
+/// Keyboards put this in every key of the report when more keys are down than it has room for
+const USAGE_ROLLOVER: u8 = 0x01;
+
+/// The key codes of the keyboard usages, zero where there is none
+const KEY_CODES: [u8; 116] = [
+    0, 0, 0, 0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, // 0x00
+    50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44, 2, 3, // 0x10
+    4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26, // 0x20
+    27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64, // 0x30
+    65, 66, 67, 68, 87, 88, 99, 70, 119, 110, 102, 104, 111, 107, 109, 106, // 0x40
+    105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71, // 0x50
+    72, 73, 82, 83, 86, 127, 116, 117, 183, 184, 185, 186, 187, 188, 189, 190, // 0x60
+    191, 192, 193, 194, // 0x70
+];
+
+/// The left control, shift, alt and meta keys, then the right ones, as the modifier bits go
+const MODIFIER_KEY_CODES: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+const MOUSE_BUTTONS: [u16; 5] = [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE, BTN_SIDE, BTN_EXTRA];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Keyboard,
+    Mouse,
+}
+
+struct Hid {
+    device: Arc<Device>,
+    kind: Kind,
+    /// The report being waited for, gone after an error
+    transfer: Option<Transfer>,
+    /// Keys and buttons are reported where they changed from the last report
+    previous: [u8; KEYBOARD_REPORT_SIZE],
+}
+
+static DEVICES: Mutex<Vec<Hid>> = Mutex::new(Vec::new());
+
+fn report_keys(previous: &mut [u8; KEYBOARD_REPORT_SIZE], report: &[u8]) {
+    let Ok(report) =
+        <[u8; KEYBOARD_REPORT_SIZE]>::try_from(&report[..KEYBOARD_REPORT_SIZE.min(report.len())])
+    else {
+        return;
+    };
+
+    for (bit, &code) in MODIFIER_KEY_CODES.iter().enumerate() {
+        if (previous[0] ^ report[0]) & 1 << bit != 0 {
+            input::report(Event::Key {
+                code,
+                pressed: report[0] & 1 << bit != 0,
+            });
+        }
+    }
+
+    previous[0] = report[0];
+
+    // Which keys are down is unknown, the ones we know of stay down until it is known again
+    if report[2..].contains(&USAGE_ROLLOVER) {
+        return;
+    }
+
+    let key = |usage: u8, pressed: bool| {
+        if let Some(&code) = KEY_CODES.get(usage as usize).filter(|&&code| code != 0) {
+            input::report(Event::Key {
+                code: code as u16,
+                pressed,
+            });
+        }
+    };
+
+    for &usage in &previous[2..] {
+        if !report[2..].contains(&usage) {
+            key(usage, false);
+        }
+    }
+
+    for &usage in &report[2..] {
+        if !previous[2..].contains(&usage) {
+            key(usage, true);
+        }
+    }
+
+    *previous = report;
+}
+
+/// Buttons, then the motion as signed bytes, with the wheel after it on most mice
+fn report_motion(previous: &mut [u8; KEYBOARD_REPORT_SIZE], report: &[u8]) {
+    let Some(&buttons) = report.first() else {
+        return;
+    };
+
+    for (bit, &code) in MOUSE_BUTTONS.iter().enumerate() {
+        if (previous[0] ^ buttons) & 1 << bit != 0 {
+            input::report(Event::Key {
+                code,
+                pressed: buttons & 1 << bit != 0,
+            });
+        }
+    }
+
+    previous[0] = buttons;
+
+    let axis = |index: usize| report.get(index).map_or(0, |&value| value as i8 as i32);
+
+    let (dx, dy, wheel) = (axis(1), axis(2), axis(3));
+
+    if dx != 0 || dy != 0 || wheel != 0 {
+        input::report(Event::Motion { dx, dy, wheel });
+    }
+}
+
+/// Drive a boot keyboard or mouse interface, other HID interfaces need a report parser
+pub fn probe(device: &Arc<Device>, interface: &Interface) {
+    if interface.subclass != SUBCLASS_BOOT {
+        return;
+    }
+
+    let kind = match interface.protocol {
+        PROTOCOL_KEYBOARD => Kind::Keyboard,
+        PROTOCOL_MOUSE => Kind::Mouse,
+        _ => return,
+    };
+
+    let Some(endpoint) = interface
+        .endpoints
+        .iter()
+        .find(|endpoint| {
+            endpoint.transfer_type() == TransferType::Interrupt
+                && endpoint.direction() == Direction::In
+        })
+        .copied()
+    else {
+        return;
+    };
+
+    let request = |request: u8, value: u16| {
+        device.control_out(
+            SetupPacket {
+                request_type: REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+                request,
+                value,
+                index: interface.number as u16,
+            },
+            &[],
+        )
+    };
+
+    if let Err(error) = request(REQUEST_SET_PROTOCOL, PROTOCOL_BOOT) {
+        crate::log_warn!(
+            "hid",
+            "port {}: setting the boot protocol: {:?}",
+            device.port(),
+            error
+        );
+        return;
+    }
+
+    // Only report on changes, some devices refuse and report on changes anyway
+    let _ = request(REQUEST_SET_IDLE, 0);
+
+    let transfer = match submit(device, &endpoint) {
+        Ok(transfer) => transfer,
+        Err(error) => {
+            crate::log_error!("hid", "port {}: {:?}", device.port(), error);
+            return;
+        }
+    };
+
+    crate::log_ok!(
+        "hid",
+        "port {}: {:04x}:{:04x} {}",
+        device.port(),
+        device.descriptor().vendor_id,
+        device.descriptor().product_id,
+        if kind == Kind::Keyboard {
+            "keyboard"
+        } else {
+            "mouse"
+        }
+    );
+
+    DEVICES.lock().push(Hid {
+        device: device.clone(),
+        kind,
+        transfer: Some(transfer),
+        previous: [0; KEYBOARD_REPORT_SIZE],
+    });
+}
+
+fn submit(device: &Device, endpoint: &EndpointDescriptor) -> Result<Transfer, Error> {
+    device.submit_in(
+        endpoint,
+        (endpoint.packet_size() as usize).max(KEYBOARD_REPORT_SIZE),
+    )
+}
+
+/// Turn the reports that came into input events and wait for the next ones
+pub fn poll() {
+    // Recovering from an error waits, which polls again
+    let Some(mut devices) = DEVICES.try_lock() else {
+        return;
+    };
+
+    devices.retain_mut(|hid| {
+        if !hid.device.is_connected() {
+            if let Some(transfer) = hid.transfer.take() {
+                hid.device.cancel(transfer);
+            }
+
+            return false;
+        }
+
+        let Some(transfer) = hid.transfer.as_mut() else {
+            return true;
+        };
+
+        let Some(result) = hid.device.poll_transfer(transfer) else {
+            return true;
+        };
+
+        if let Err(error) = result {
+            crate::log_error!("hid", "port {}: {:?}", hid.device.port(), error);
+
+            hid.transfer = None;
+
+            return true;
+        }
+
+        match hid.kind {
+            Kind::Keyboard => report_keys(&mut hid.previous, transfer.data()),
+            Kind::Mouse => report_motion(&mut hid.previous, transfer.data()),
+        }
+
+        if let Err(error) = hid.device.resubmit(transfer) {
+            crate::log_error!("hid", "port {}: {:?}", hid.device.port(), error);
+
+            hid.transfer = None;
+        }
+
+        true
+    });
+}
@@ -0,0 +1,514 @@
+//! USB, the devices behind the host controllers and the descriptors they describe themselves with
+
+pub mod hid;
+pub mod storage;
+pub mod xhci;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::sync::Mutex;
+
+pub use xhci::Transfer;
+
+use xhci::Controller;
+
+pub const REQUEST_CLEAR_FEATURE: u8 = 1;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+
+/// The `bmRequestType` bits, which way the data goes, what defines the request and what it is for
+pub const REQUEST_DEVICE_TO_HOST: u8 = 1 << 7;
+pub const REQUEST_TYPE_CLASS: u8 = 1 << 5;
+pub const REQUEST_TYPE_VENDOR: u8 = 2 << 5;
+pub const REQUEST_RECIPIENT_INTERFACE: u8 = 1;
+pub const REQUEST_RECIPIENT_ENDPOINT: u8 = 2;
+
+/// The feature of an endpoint that is cleared to take it out of a stall
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_STRING: u8 = 3;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Register bar 0 is not a memory bar
+    MissingBar,
+    ResetTimedOut,
+    OutOfMemory,
+    /// The device refused the request or halted the endpoint
+    Stall,
+    Timeout,
+    /// The controller failed the command or transfer with this completion code
+    Failed(u8),
+    InvalidDescriptor,
+    /// The endpoint is of a kind we do not drive, or the transfer too large for its ring
+    Unsupported,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+    SuperPlus,
+}
+
+impl Speed {
+    /// What the control endpoint is limited to until the device descriptor says otherwise
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full => 8,
+            Speed::High => 64,
+            Speed::Super | Speed::SuperPlus => 512,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Speed::Low => "low",
+            Speed::Full => "full",
+            Speed::High => "high",
+            Speed::Super => "super",
+            Speed::SuperPlus => "super+",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+/// A request on the control endpoint, the length comes from the buffer it is sent with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const SIZE: usize = 18;
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE || data[1] != DESCRIPTOR_DEVICE {
+            return None;
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+        Some(Self {
+            usb_version: u16_at(2),
+            class: data[4],
+            subclass: data[5],
+            protocol: data[6],
+            max_packet_size: data[7],
+            vendor_id: u16_at(8),
+            product_id: u16_at(10),
+            device_version: u16_at(12),
+            configurations: data[17],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0xF
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.address & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x3 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+
+    /// The packet size without the high bandwidth bits
+    pub fn packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7FF
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Configuration {
+    pub value: u8,
+    pub interfaces: Vec<Interface>,
+}
+
+impl Configuration {
+    /// Parse the configuration descriptor and the interface and endpoint descriptors after it,
+    /// skipping the class specific ones
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 9 || data[1] != DESCRIPTOR_CONFIGURATION {
+            return None;
+        }
+
+        let mut configuration = Self {
+            value: data[5],
+            interfaces: Vec::new(),
+        };
+
+        let mut rest = data;
+
+        while rest.len() >= 2 {
+            let len = rest[0] as usize;
+
+            if len < 2 || len > rest.len() {
+                return None;
+            }
+
+            let descriptor = &rest[..len];
+
+            match descriptor[1] {
+                DESCRIPTOR_INTERFACE if len >= 9 => configuration.interfaces.push(Interface {
+                    number: descriptor[2],
+                    alternate: descriptor[3],
+                    class: descriptor[5],
+                    subclass: descriptor[6],
+                    protocol: descriptor[7],
+                    endpoints: Vec::new(),
+                }),
+                DESCRIPTOR_ENDPOINT if len >= 7 => {
+                    if let Some(interface) = configuration.interfaces.last_mut() {
+                        interface.endpoints.push(EndpointDescriptor {
+                            address: descriptor[2],
+                            attributes: descriptor[3],
+                            max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]),
+                            interval: descriptor[6],
+                        });
+                    }
+                }
+                _ => {}
+            }
+
+            rest = &rest[len..];
+        }
+
+        Some(configuration)
+    }
+}
+
+/// A device addressed on a root hub port, its slot is given back when the last user drops it
+pub struct Device {
+    controller: Arc<Controller>,
+    slot: u8,
+    port: u8,
+    speed: Speed,
+    descriptor: DeviceDescriptor,
+    configuration: Configuration,
+    connected: AtomicBool,
+}
+
+impl Device {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    /// The configuration that was set when the device was enumerated
+    pub fn configuration(&self) -> &Configuration {
+        &self.configuration
+    }
+
+    pub fn control_in(&self, setup: SetupPacket, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.controller.control_in(self.slot, setup, buffer)
+    }
+
+    pub fn control_out(&self, setup: SetupPacket, data: &[u8]) -> Result<(), Error> {
+        self.controller.control_out(self.slot, setup, data)
+    }
+
+    pub fn get_descriptor(&self, kind: u8, index: u8, buffer: &mut [u8]) -> Result<usize, Error> {
+        get_descriptor(&self.controller, self.slot, kind, index, buffer)
+    }
+
+    /// Receive from a bulk or interrupt IN endpoint, returning how much came
+    pub fn transfer_in(
+        &self,
+        endpoint: &EndpointDescriptor,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.controller.transfer_in(self.slot, endpoint, buffer)
+    }
+
+    /// Send on a bulk or interrupt OUT endpoint
+    pub fn transfer_out(&self, endpoint: &EndpointDescriptor, data: &[u8]) -> Result<(), Error> {
+        self.controller.transfer_out(self.slot, endpoint, data)
+    }
+
+    /// Start receiving up to `len` bytes from an IN endpoint, for `poll_transfer` to pick up
+    pub fn submit_in(&self, endpoint: &EndpointDescriptor, len: usize) -> Result<Transfer, Error> {
+        self.controller.submit_in(self.slot, endpoint, len)
+    }
+
+    pub fn poll_transfer(&self, transfer: &mut Transfer) -> Option<Result<usize, Error>> {
+        self.controller.poll_transfer(transfer)
+    }
+
+    pub fn resubmit(&self, transfer: &mut Transfer) -> Result<(), Error> {
+        self.controller.resubmit(transfer)
+    }
+
+    pub fn cancel(&self, transfer: Transfer) {
+        self.controller.cancel(transfer)
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.controller.disable_slot(self.slot);
+    }
+}
+
+fn get_descriptor(
+    controller: &Controller,
+    slot: u8,
+    kind: u8,
+    index: u8,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let setup = SetupPacket {
+        request_type: REQUEST_DEVICE_TO_HOST,
+        request: REQUEST_GET_DESCRIPTOR,
+        value: (kind as u16) << 8 | index as u16,
+        index: 0,
+    };
+
+    controller.control_in(slot, setup, buffer)
+}
+
+static DEVICES: Mutex<Vec<Arc<Device>>> = Mutex::new(Vec::new());
+
+pub fn devices() -> Vec<Arc<Device>> {
+    DEVICES.lock().clone()
+}
+
+/// Read the descriptors of a device that was just addressed and set its first configuration
+fn configure(
+    controller: &Arc<Controller>,
+    slot: u8,
+    speed: Speed,
+) -> Result<(DeviceDescriptor, Configuration), Error> {
+    let mut header = [0; 8];
+
+    get_descriptor(controller, slot, DESCRIPTOR_DEVICE, 0, &mut header)?;
+
+    // Super speed devices give the size as a power of two, which is always 512
+    if !matches!(speed, Speed::Super | Speed::SuperPlus)
+        && header[7] as u16 != speed.default_max_packet_size()
+    {
+        controller.set_max_packet_size(slot, header[7] as u16)?;
+    }
+
+    let mut data = [0; DeviceDescriptor::SIZE];
+
+    get_descriptor(controller, slot, DESCRIPTOR_DEVICE, 0, &mut data)?;
+
+    let descriptor = DeviceDescriptor::parse(&data).ok_or(Error::InvalidDescriptor)?;
+
+    let mut header = [0; 9];
+
+    get_descriptor(controller, slot, DESCRIPTOR_CONFIGURATION, 0, &mut header)?;
+
+    let mut data = vec![0; u16::from_le_bytes([header[2], header[3]]) as usize];
+    let len = get_descriptor(controller, slot, DESCRIPTOR_CONFIGURATION, 0, &mut data)?;
+
+    let configuration = Configuration::parse(&data[..len]).ok_or(Error::InvalidDescriptor)?;
+
+    controller.control_out(
+        slot,
+        SetupPacket {
+            request_type: 0,
+            request: REQUEST_SET_CONFIGURATION,
+            value: configuration.value as u16,
+            index: 0,
+        },
+        &[],
+    )?;
+
+    let endpoints: Vec<EndpointDescriptor> = configuration
+        .interfaces
+        .iter()
+        .filter(|interface| interface.alternate == 0)
+        .flat_map(|interface| interface.endpoints.iter().copied())
+        .filter(|endpoint| {
+            matches!(
+                endpoint.transfer_type(),
+                TransferType::Bulk | TransferType::Interrupt
+            )
+        })
+        .collect();
+
+    controller.configure_endpoints(slot, &endpoints)?;
+
+    Ok((descriptor, configuration))
+}
+
+/// Address and configure the device that was just connected to `port`
+fn enumerate(controller: &Arc<Controller>, port: u8, speed: Speed) -> Result<(), Error> {
+    let slot = controller.address_device(port, speed)?;
+
+    let (descriptor, configuration) = match configure(controller, slot, speed) {
+        Ok(descriptors) => descriptors,
+        Err(error) => {
+            controller.disable_slot(slot);
+
+            return Err(error);
+        }
+    };
+
+    crate::log_ok!(
+        "usb",
+        "{} port {}: {:04x}:{:04x} {} speed, {} interfaces",
+        controller.address,
+        port,
+        descriptor.vendor_id,
+        descriptor.product_id,
+        speed,
+        configuration.interfaces.len()
+    );
+
+    let device = Arc::new(Device {
+        controller: controller.clone(),
+        slot,
+        port,
+        speed,
+        descriptor,
+        configuration,
+        connected: AtomicBool::new(true),
+    });
+
+    DEVICES.lock().push(device.clone());
+
+    bind(&device);
+
+    Ok(())
+}
+
+/// Hand the interfaces of a new device to the class drivers
+fn bind(device: &Arc<Device>) {
+    for interface in &device.configuration.interfaces {
+        if interface.alternate != 0 {
+            continue;
+        }
+
+        match interface.class {
+            hid::CLASS_HID => hid::probe(device, interface),
+            storage::CLASS_MASS_STORAGE => storage::probe(device, interface),
+            _ => {}
+        }
+    }
+}
+
+/// Forget the device on `port`, its slot goes once the class drivers noticed and let go of it
+fn disconnect(controller: &Arc<Controller>, port: u8) {
+    let mut devices = DEVICES.lock();
+
+    let Some(index) = devices
+        .iter()
+        .position(|device| Arc::ptr_eq(&device.controller, controller) && device.port == port)
+    else {
+        return;
+    };
+
+    let device = devices.remove(index);
+
+    drop(devices);
+
+    crate::log_info!("usb", "{} port {}: disconnected", controller.address, port);
+
+    device.connected.store(false, Ordering::Relaxed);
+
+    drop(device);
+}
+
+fn is_attached(controller: &Arc<Controller>, port: u8) -> bool {
+    DEVICES
+        .lock()
+        .iter()
+        .any(|device| Arc::ptr_eq(&device.controller, controller) && device.port == port)
+}
+
+pub fn init() {
+    xhci::init();
+}
+
+/// Handle the events of the host controllers, like devices coming and going
+pub fn poll() {
+    xhci::poll();
+    hid::poll();
+    storage::poll();
+}
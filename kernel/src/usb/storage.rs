@@ -0,0 +1,421 @@
+//! USB mass storage, SCSI commands over the bulk-only transport
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    block::{self, BlockDevice},
+    sync::Mutex,
+    usb::{
+        self, Device, Direction, EndpointDescriptor, FEATURE_ENDPOINT_HALT, Interface,
+        REQUEST_CLEAR_FEATURE, REQUEST_RECIPIENT_ENDPOINT, REQUEST_RECIPIENT_INTERFACE,
+        REQUEST_TYPE_CLASS, SetupPacket, TransferType,
+    },
+};
+
+pub const CLASS_MASS_STORAGE: u8 = 8;
+
+const SUBCLASS_SCSI: u8 = 6;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const REQUEST_BULK_ONLY_RESET: u8 = 0xFF;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_SIZE: usize = 31;
+const CBW_FLAG_IN: u8 = 1 << 7;
+
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_SIZE: usize = 13;
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+
+const INQUIRY_SIZE: usize = 36;
+const SENSE_SIZE: usize = 18;
+
+/// The most one READ or WRITE moves
+const MAX_TRANSFER_LEN: usize = 64 * 1024;
+
+/// Devices often report a unit attention after they were reset, or take a while to spin up
+const READY_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Usb(usb::Error),
+    /// The device failed the command, the sense key says why
+    CommandFailed(u8),
+    /// The status did not make sense, the device was reset
+    PhaseError,
+    /// Over 2 TiB, which needs the 16 byte commands
+    TooLarge,
+}
+
+impl From<usb::Error> for Error {
+    fn from(error: usb::Error) -> Self {
+        Error::Usb(error)
+    }
+}
+
+enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+pub struct Storage {
+    device: Arc<Device>,
+    interface: u8,
+    bulk_in: EndpointDescriptor,
+    bulk_out: EndpointDescriptor,
+    /// The tag of the next command, locked for the whole of a command
+    tag: Mutex<u32>,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl Storage {
+    fn clear_halt(&self, endpoint: &EndpointDescriptor) {
+        let _ = self.device.control_out(
+            SetupPacket {
+                request_type: REQUEST_RECIPIENT_ENDPOINT,
+                request: REQUEST_CLEAR_FEATURE,
+                value: FEATURE_ENDPOINT_HALT,
+                index: endpoint.address as u16,
+            },
+            &[],
+        );
+    }
+
+    /// The reset recovery of the bulk-only transport, for when the device lost track
+    fn reset(&self) {
+        let _ = self.device.control_out(
+            SetupPacket {
+                request_type: REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+                request: REQUEST_BULK_ONLY_RESET,
+                value: 0,
+                index: self.interface as u16,
+            },
+            &[],
+        );
+
+        self.clear_halt(&self.bulk_in);
+        self.clear_halt(&self.bulk_out);
+    }
+
+    /// Receive the status, a stalled endpoint gets cleared and one more try
+    fn receive_status(&self, status: &mut [u8; CSW_SIZE]) -> Result<usize, Error> {
+        match self.device.transfer_in(&self.bulk_in, status) {
+            Err(usb::Error::Stall) => {
+                self.clear_halt(&self.bulk_in);
+
+                Ok(self.device.transfer_in(&self.bulk_in, status)?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Run a SCSI command, returning how much data moved
+    fn command(&self, command: &[u8], data: Data) -> Result<usize, Error> {
+        let mut tag = self.tag.lock();
+
+        *tag = tag.wrapping_add(1);
+
+        let (len, flags) = match &data {
+            Data::None => (0, 0),
+            Data::In(buffer) => (buffer.len(), CBW_FLAG_IN),
+            Data::Out(data) => (data.len(), 0),
+        };
+
+        let mut wrapper = [0; CBW_SIZE];
+
+        wrapper[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        wrapper[4..8].copy_from_slice(&tag.to_le_bytes());
+        wrapper[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        wrapper[12] = flags;
+        // Only the first logical unit is driven
+        wrapper[13] = 0;
+        wrapper[14] = command.len() as u8;
+        wrapper[15..15 + command.len()].copy_from_slice(command);
+
+        if let Err(error) = self.device.transfer_out(&self.bulk_out, &wrapper) {
+            self.reset();
+
+            return Err(error.into());
+        }
+
+        // A stalled data stage still ends with a status
+        let transferred = match data {
+            Data::None => Ok(0),
+            Data::In(buffer) => self.device.transfer_in(&self.bulk_in, buffer),
+            Data::Out(data) => self
+                .device
+                .transfer_out(&self.bulk_out, data)
+                .map(|()| data.len()),
+        };
+
+        let transferred = match transferred {
+            Ok(transferred) => transferred,
+            Err(usb::Error::Stall) => {
+                self.clear_halt(if flags == CBW_FLAG_IN {
+                    &self.bulk_in
+                } else {
+                    &self.bulk_out
+                });
+
+                0
+            }
+            Err(error) => {
+                self.reset();
+
+                return Err(error.into());
+            }
+        };
+
+        let mut status = [0; CSW_SIZE];
+
+        let valid = self.receive_status(&mut status)? == CSW_SIZE
+            && status[0..4] == CSW_SIGNATURE.to_le_bytes()
+            && status[4..8] == tag.to_le_bytes();
+
+        drop(tag);
+
+        match status[12] {
+            CSW_STATUS_PASSED if valid => Ok(transferred),
+            CSW_STATUS_FAILED if valid => Err(Error::CommandFailed(self.sense_key())),
+            _ => {
+                self.reset();
+
+                Err(Error::PhaseError)
+            }
+        }
+    }
+
+    /// Why the last command failed
+    fn sense_key(&self) -> u8 {
+        let mut sense = [0; SENSE_SIZE];
+
+        match self.command(
+            &[SCSI_REQUEST_SENSE, 0, 0, 0, SENSE_SIZE as u8, 0],
+            Data::In(&mut sense),
+        ) {
+            Ok(len) if len >= 3 => sense[2] & 0xF,
+            _ => 0,
+        }
+    }
+
+    fn scsi_read_write(
+        &self,
+        operation: u8,
+        lba: u64,
+        blocks: u16,
+        data: Data,
+    ) -> Result<(), Error> {
+        let [_, _, _, _, l0, l1, l2, l3] = lba.to_be_bytes();
+        let [b0, b1] = blocks.to_be_bytes();
+
+        self.command(&[operation, 0, l0, l1, l2, l3, 0, b0, b1, 0], data)?;
+
+        Ok(())
+    }
+
+    fn new(device: &Arc<Device>, interface: &Interface) -> Result<Option<Self>, Error> {
+        let endpoint = |direction| {
+            interface
+                .endpoints
+                .iter()
+                .find(|endpoint| {
+                    endpoint.transfer_type() == TransferType::Bulk
+                        && endpoint.direction() == direction
+                })
+                .copied()
+        };
+
+        let (Some(bulk_in), Some(bulk_out)) = (endpoint(Direction::In), endpoint(Direction::Out))
+        else {
+            return Ok(None);
+        };
+
+        let mut storage = Self {
+            device: device.clone(),
+            interface: interface.number,
+            bulk_in,
+            bulk_out,
+            tag: Mutex::new(0),
+            block_size: 0,
+            block_count: 0,
+        };
+
+        let mut inquiry = [0; INQUIRY_SIZE];
+
+        storage.command(
+            &[SCSI_INQUIRY, 0, 0, 0, INQUIRY_SIZE as u8, 0],
+            Data::In(&mut inquiry),
+        )?;
+
+        let mut attempts = 0;
+
+        loop {
+            match storage.command(&[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None) {
+                Ok(_) => break,
+                Err(Error::CommandFailed(_)) if attempts < READY_ATTEMPTS => attempts += 1,
+                Err(error) => return Err(error),
+            }
+        }
+
+        let mut capacity = [0; 8];
+
+        storage.command(
+            &[SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Data::In(&mut capacity),
+        )?;
+
+        let last_lba = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+
+        if last_lba == u32::MAX {
+            return Err(Error::TooLarge);
+        }
+
+        storage.block_count = last_lba as u64 + 1;
+        storage.block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap()) as usize;
+
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim().into();
+        let (vendor, product): (String, String) = (text(&inquiry[8..16]), text(&inquiry[16..32]));
+
+        crate::log_ok!("storage", "port {}: {} {}", device.port(), vendor, product);
+
+        Ok(Some(storage))
+    }
+
+    fn blocks_per_command(&self) -> usize {
+        (MAX_TRANSFER_LEN / self.block_size).clamp(1, u16::MAX as usize)
+    }
+}
+
+impl BlockDevice for Storage {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        block::check(self, lba, buffer.len())?;
+
+        let chunk_len = self.blocks_per_command() * self.block_size;
+
+        for (i, chunk) in buffer.chunks_mut(chunk_len).enumerate() {
+            let blocks = (chunk.len() / self.block_size) as u16;
+            let lba = lba + (i * self.blocks_per_command()) as u64;
+
+            self.scsi_read_write(SCSI_READ_10, lba, blocks, Data::In(chunk))
+                .map_err(|error| {
+                    crate::log_error!(
+                        "storage",
+                        "port {}: reading {}: {:?}",
+                        self.device.port(),
+                        lba,
+                        error
+                    );
+
+                    block::Error::Io
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, data: &[u8]) -> Result<(), block::Error> {
+        block::check(self, lba, data.len())?;
+
+        let chunk_len = self.blocks_per_command() * self.block_size;
+
+        for (i, chunk) in data.chunks(chunk_len).enumerate() {
+            let blocks = (chunk.len() / self.block_size) as u16;
+            let lba = lba + (i * self.blocks_per_command()) as u64;
+
+            self.scsi_read_write(SCSI_WRITE_10, lba, blocks, Data::Out(chunk))
+                .map_err(|error| {
+                    crate::log_error!(
+                        "storage",
+                        "port {}: writing {}: {:?}",
+                        self.device.port(),
+                        lba,
+                        error
+                    );
+
+                    block::Error::Io
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), block::Error> {
+        match self.command(
+            &[SCSI_SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Data::None,
+        ) {
+            // Sticks without a cache may not know the command
+            Ok(_) | Err(Error::CommandFailed(_)) => Ok(()),
+            Err(_) => Err(block::Error::Io),
+        }
+    }
+}
+
+/// The block devices of the sticks, by their name
+static DEVICES: Mutex<Vec<(String, Arc<Storage>)>> = Mutex::new(Vec::new());
+
+/// The first of `sda`, `sdb` and on that is free
+fn next_name() -> String {
+    (b'a'..=b'z')
+        .map(|letter| format!("sd{}", letter as char))
+        .find(|name| block::find(name).is_none())
+        .unwrap_or_else(|| "sd?".into())
+}
+
+/// Drive a SCSI interface on the bulk-only transport, registering it as a block device
+pub fn probe(device: &Arc<Device>, interface: &Interface) {
+    if interface.subclass != SUBCLASS_SCSI || interface.protocol != PROTOCOL_BULK_ONLY {
+        return;
+    }
+
+    let storage = match Storage::new(device, interface) {
+        Ok(Some(storage)) => Arc::new(storage),
+        Ok(None) => return,
+        Err(error) => {
+            crate::log_error!("storage", "port {}: {:?}", device.port(), error);
+            return;
+        }
+    };
+
+    let name = next_name();
+
+    block::register(name.clone(), storage.clone());
+
+    DEVICES.lock().push((name, storage));
+}
+
+/// Unregister the block devices of the sticks that were pulled out
+pub fn poll() {
+    let Some(mut devices) = DEVICES.try_lock() else {
+        return;
+    };
+
+    devices.retain(|(name, storage)| {
+        if storage.device.is_connected() {
+            return true;
+        }
+
+        block::unregister(name);
+
+        false
+    });
+}
@@ -0,0 +1,1469 @@
+//! The xHCI USB host controller, which drives every speed of USB through rings of TRBs
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering, fence};
+
+use crate::{
+    dma::{self, CoherentBuffer, Constraints},
+    mmio::Mmio,
+    pci::{self, Bar},
+    sync::Mutex,
+    time,
+    usb::{self, Direction, EndpointDescriptor, Error, SetupPacket, Speed, TransferType},
+    wait,
+};
+
+const CLASS_SERIAL_BUS: u8 = 0x0C;
+const SUBCLASS_USB: u8 = 0x03;
+const PROG_IF_XHCI: u8 = 0x30;
+
+const CAP_CAPLENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+const HCCPARAMS1_AC64: u32 = 1 << 0;
+/// Contexts are 64 bytes instead of 32
+const HCCPARAMS1_CSZ: u32 = 1 << 2;
+
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_PAGESIZE: usize = 0x08;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTS: usize = 0x400;
+
+const USBCMD_RS: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_CNR: u32 = 1 << 11;
+
+const CRCR_RCS: u64 = 1 << 0;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+const PORTSC_CHANGES: u32 = 0x00FE_0000;
+/// Writing one to these clears them, or for port enabled disables the port
+const PORTSC_RW1C: u32 = PORTSC_PED | PORTSC_CHANGES;
+
+const RUNTIME_INTERRUPTER: usize = 0x20;
+
+const IR_ERSTSZ: usize = 0x08;
+const IR_ERSTBA: usize = 0x10;
+const IR_ERDP: usize = 0x18;
+
+/// Writing one clears the event handler busy bit
+const ERDP_EHB: u64 = 1 << 3;
+
+const EXTENDED_CAPABILITY_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// The SMI enables of the legacy control register
+const LEGACY_SMI_ENABLES: u32 = 0xE011;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+const TRB_CYCLE: u32 = 1 << 0;
+/// On a link TRB, toggle the cycle bit when following it
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+/// Interrupt on a short packet, so the residue of the TRB is reported
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+/// The setup packet is in the parameter instead of pointed to
+const TRB_IDT: u32 = 1 << 6;
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+
+const SETUP_DATA_OUT: u32 = 2 << 16;
+const SETUP_DATA_IN: u32 = 3 << 16;
+
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_STALL: u8 = 6;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+const ENDPOINT_TYPE_BULK_OUT: u32 = 2;
+const ENDPOINT_TYPE_INTERRUPT_OUT: u32 = 3;
+const ENDPOINT_TYPE_CONTROL: u32 = 4;
+const ENDPOINT_TYPE_BULK_IN: u32 = 6;
+const ENDPOINT_TYPE_INTERRUPT_IN: u32 = 7;
+
+/// The device context index of the control endpoint
+const CONTROL_ENDPOINT: usize = 1;
+
+const RING_SIZE: usize = 256;
+const TRB_SIZE: usize = 16;
+/// A TRB can not move more than this, nor cross a multiple of it
+const TRB_MAX_LEN: usize = 64 * 1024;
+/// One transfer at a time is on a ring, this leaves room for the link TRB
+const MAX_TRANSFER_LEN: usize = (RING_SIZE / 2) * TRB_MAX_LEN;
+
+const RESET_TIMEOUT_NS: u64 = 1_000_000_000;
+const COMMAND_TIMEOUT_NS: u64 = 1_000_000_000;
+const TRANSFER_TIMEOUT_NS: u64 = 5_000_000_000;
+const PORT_RESET_TIMEOUT_NS: u64 = 500_000_000;
+/// How long the spec gives a device to recover from a port reset
+const PORT_RESET_RECOVERY_NS: u64 = 10_000_000;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(kind: u32, parameter: u64, status: u32, control: u32) -> Self {
+        Self {
+            parameter,
+            status,
+            control: control | kind << 10,
+        }
+    }
+
+    /// A command or a transfer TRB on the endpoint of a slot
+    fn for_endpoint(kind: u32, parameter: u64, slot: u8, dci: usize) -> Self {
+        Self::new(kind, parameter, 0, (slot as u32) << 24 | (dci as u32) << 16)
+    }
+
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// What was left of the transfer of a transfer event
+    fn residue(&self) -> u32 {
+        self.status & 0xFF_FFFF
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+}
+
+fn read_trb(buffer: &CoherentBuffer, index: usize) -> Trb {
+    let trb = unsafe { (buffer.as_ptr() as *const Trb).add(index) };
+
+    unsafe {
+        let control = (&raw const (*trb).control).read_volatile();
+
+        fence(Ordering::Acquire);
+
+        Trb {
+            parameter: (&raw const (*trb).parameter).read_volatile(),
+            status: (&raw const (*trb).status).read_volatile(),
+            control,
+        }
+    }
+}
+
+/// Write the control word last, its cycle bit hands the TRB to the controller
+fn write_trb(buffer: &CoherentBuffer, index: usize, value: Trb) {
+    let trb = unsafe { (buffer.as_ptr() as *mut Trb).add(index) };
+
+    unsafe {
+        (&raw mut (*trb).parameter).write_volatile(value.parameter);
+        (&raw mut (*trb).status).write_volatile(value.status);
+
+        fence(Ordering::Release);
+
+        (&raw mut (*trb).control).write_volatile(value.control);
+    }
+}
+
+/// A ring we produce TRBs on, for commands or for the transfers of an endpoint
+struct Ring {
+    trbs: CoherentBuffer,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new(constraints: Constraints) -> Result<Self, Error> {
+        let trbs = dma::alloc_coherent(RING_SIZE * TRB_SIZE, constraints)
+            .map_err(|_| Error::OutOfMemory)?;
+
+        let link = Trb::new(TRB_LINK, trbs.bus_address(), 0, TRB_TOGGLE_CYCLE);
+
+        write_trb(&trbs, RING_SIZE - 1, link);
+
+        Ok(Self {
+            trbs,
+            enqueue: 0,
+            cycle: true,
+        })
+    }
+
+    fn address(&self) -> u64 {
+        self.trbs.bus_address() + (self.enqueue * TRB_SIZE) as u64
+    }
+
+    /// The dequeue pointer that makes the controller continue where we enqueue next
+    fn dequeue_pointer(&self) -> u64 {
+        self.address() | self.cycle as u64
+    }
+
+    /// Hand a TRB to the controller, returning its address for matching up the event about it
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        let address = self.address();
+
+        trb.control = (trb.control & !TRB_CYCLE) | self.cycle as u32;
+
+        write_trb(&self.trbs, self.enqueue, trb);
+
+        self.enqueue += 1;
+
+        if self.enqueue == RING_SIZE - 1 {
+            let mut link = read_trb(&self.trbs, RING_SIZE - 1);
+
+            // A transfer chained across the end of the ring stays chained through the link
+            link.control = (link.control & !(TRB_CYCLE | TRB_CHAIN))
+                | (trb.control & TRB_CHAIN)
+                | self.cycle as u32;
+
+            write_trb(&self.trbs, RING_SIZE - 1, link);
+
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        address
+    }
+}
+
+/// The ring the controller produces events on, a single segment
+struct EventRing {
+    trbs: CoherentBuffer,
+    /// The segment table, with the one segment
+    table: CoherentBuffer,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn new(constraints: Constraints) -> Result<Self, Error> {
+        let trbs = dma::alloc_coherent(RING_SIZE * TRB_SIZE, constraints)
+            .map_err(|_| Error::OutOfMemory)?;
+        let mut table = dma::alloc_coherent(16, constraints).map_err(|_| Error::OutOfMemory)?;
+
+        table.as_mut_slice()[..8].copy_from_slice(&trbs.bus_address().to_le_bytes());
+        table.as_mut_slice()[8..12].copy_from_slice(&(RING_SIZE as u32).to_le_bytes());
+
+        Ok(Self {
+            trbs,
+            table,
+            dequeue: 0,
+            cycle: true,
+        })
+    }
+
+    fn dequeue_address(&self) -> u64 {
+        self.trbs.bus_address() + (self.dequeue * TRB_SIZE) as u64
+    }
+
+    fn next(&mut self) -> Option<Trb> {
+        let trb = read_trb(&self.trbs, self.dequeue);
+
+        if (trb.control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+
+        self.dequeue += 1;
+
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        Some(trb)
+    }
+}
+
+/// Write the first eight dwords of a context, the rest are reserved
+fn write_context(buffer: &CoherentBuffer, context_size: usize, index: usize, dwords: [u32; 8]) {
+    let context = unsafe { buffer.as_ptr().add(index * context_size) as *mut u32 };
+
+    for (i, dword) in dwords.into_iter().enumerate() {
+        unsafe { context.add(i).write_volatile(dword) };
+    }
+}
+
+fn read_context(buffer: &CoherentBuffer, context_size: usize, index: usize) -> [u32; 8] {
+    let context = unsafe { buffer.as_ptr().add(index * context_size) as *const u32 };
+
+    core::array::from_fn(|i| unsafe { context.add(i).read_volatile() })
+}
+
+fn endpoint_context(
+    endpoint_type: u32,
+    max_packet_size: u16,
+    interval: u8,
+    dequeue: u64,
+    average_trb_len: u16,
+) -> [u32; 8] {
+    // Interrupt endpoints reserve a packet per interval, one is all boot devices need
+    let max_esit_payload = if matches!(
+        endpoint_type,
+        ENDPOINT_TYPE_INTERRUPT_IN | ENDPOINT_TYPE_INTERRUPT_OUT
+    ) {
+        max_packet_size as u32
+    } else {
+        0
+    };
+
+    [
+        (interval as u32) << 16,
+        // Retry three times on errors
+        3 << 1 | endpoint_type << 3 | (max_packet_size as u32) << 16,
+        dequeue as u32,
+        (dequeue >> 32) as u32,
+        average_trb_len as u32 | max_esit_payload << 16,
+        0,
+        0,
+        0,
+    ]
+}
+
+/// The device context index of an endpoint, the control endpoint is 1
+fn device_context_index(endpoint: &EndpointDescriptor) -> usize {
+    endpoint.number() as usize * 2 + (endpoint.direction() == Direction::In) as usize
+}
+
+/// The interval as the exponent of 125 µs periods, which full and low speed give in frames
+fn interval(speed: Speed, endpoint: &EndpointDescriptor) -> u8 {
+    match (endpoint.transfer_type(), speed) {
+        (TransferType::Interrupt, Speed::Low | Speed::Full) => {
+            ((endpoint.interval.max(1) as u32 * 8).ilog2() as u8).clamp(3, 10)
+        }
+        (TransferType::Interrupt, _) => endpoint.interval.clamp(1, 16) - 1,
+        _ => 0,
+    }
+}
+
+fn speed_from_id(id: u32) -> Option<Speed> {
+    match id {
+        1 => Some(Speed::Full),
+        2 => Some(Speed::Low),
+        3 => Some(Speed::High),
+        4 => Some(Speed::Super),
+        5 => Some(Speed::SuperPlus),
+        _ => None,
+    }
+}
+
+fn speed_id(speed: Speed) -> u32 {
+    match speed {
+        Speed::Full => 1,
+        Speed::Low => 2,
+        Speed::High => 3,
+        Speed::Super => 4,
+        Speed::SuperPlus => 5,
+    }
+}
+
+struct Slot {
+    speed: Speed,
+    /// The device context, which the controller owns and updates
+    output: CoherentBuffer,
+    input: CoherentBuffer,
+    /// The transfer rings by device context index
+    rings: [Option<Ring>; 32],
+}
+
+struct Inner {
+    operational: Mmio,
+    interrupter: Mmio,
+    doorbells: Mmio,
+    context_size: usize,
+    constraints: Constraints,
+    /// The device context base address array, entry 0 points at the scratchpad buffers
+    dcbaa: CoherentBuffer,
+    /// The array of scratchpad buffer addresses and the buffers
+    scratchpad: (CoherentBuffer, Vec<CoherentBuffer>),
+    commands: Ring,
+    events: EventRing,
+    /// Command and transfer events by the address of their TRB, until they are picked up
+    completions: BTreeMap<u64, Trb>,
+    slots: BTreeMap<u8, Slot>,
+    /// Ports with a status change to look at
+    changed_ports: BTreeSet<u8>,
+}
+
+impl Inner {
+    fn process_events(&mut self) {
+        let mut processed = false;
+
+        while let Some(trb) = self.events.next() {
+            processed = true;
+
+            match trb.kind() {
+                TRB_COMMAND_COMPLETION | TRB_TRANSFER_EVENT => {
+                    self.completions.insert(trb.parameter, trb);
+                }
+                TRB_PORT_STATUS_CHANGE => {
+                    self.changed_ports.insert((trb.parameter >> 24) as u8);
+                }
+                _ => {}
+            }
+        }
+
+        if processed {
+            self.interrupter
+                .write(IR_ERDP, self.events.dequeue_address() | ERDP_EHB);
+        }
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: usize) {
+        fence(Ordering::SeqCst);
+
+        self.doorbells.write(slot as usize * 4, target as u32);
+    }
+
+    fn ring(&mut self, slot: u8, dci: usize) -> Result<&mut Ring, Error> {
+        self.slots
+            .get_mut(&slot)
+            .and_then(|slot| slot.rings[dci].as_mut())
+            .ok_or(Error::Disconnected)
+    }
+
+    fn port_status(&self, port: u8) -> u32 {
+        self.operational.read(OP_PORTS + (port as usize - 1) * 0x10)
+    }
+
+    /// Set bits of the port status without clearing the changes or disabling the port
+    fn set_port_status(&self, port: u8, bits: u32) {
+        let status = self.port_status(port) & !PORTSC_RW1C;
+
+        self.operational
+            .write(OP_PORTS + (port as usize - 1) * 0x10, status | bits);
+    }
+
+    fn clear_port_changes(&self, port: u8) {
+        self.set_port_status(port, self.port_status(port) & PORTSC_CHANGES);
+    }
+
+    fn set_dcbaa(&self, index: usize, address: u64) {
+        unsafe {
+            (self.dcbaa.as_ptr() as *mut u64)
+                .add(index)
+                .write_volatile(address)
+        };
+    }
+}
+
+/// A transfer left running, for interrupt endpoints that only answer when they have something
+/// to say, its buffer belongs to the controller until it completed or was cancelled
+pub struct Transfer {
+    slot: u8,
+    dci: usize,
+    buffer: CoherentBuffer,
+    /// The TRBs on the ring and their lengths, empty once completed
+    data: Vec<(u64, u32)>,
+    transferred: usize,
+}
+
+impl Transfer {
+    /// What came with the last completion
+    pub fn data(&self) -> &[u8] {
+        &self.buffer.as_slice()[..self.transferred]
+    }
+}
+
+pub struct Controller {
+    pub address: pci::Address,
+    inner: Mutex<Inner>,
+    ports: u8,
+    /// Enumerating waits, which polls the controller again, and must not enumerate under itself
+    enumerating: AtomicBool,
+}
+
+/// Take the controller from the firmware, which may be using it for legacy keyboard emulation
+fn take_ownership(registers: &Mmio, hccparams1: u32) {
+    let mut offset = ((hccparams1 >> 16) << 2) as usize;
+
+    while offset != 0 {
+        let capability = registers.read::<u32>(offset);
+
+        if capability & 0xFF == EXTENDED_CAPABILITY_LEGACY {
+            registers.write::<u8>(offset + 3, 1);
+
+            let deadline = time::monotonic_ns() + RESET_TIMEOUT_NS;
+
+            while registers.read::<u32>(offset) & (LEGACY_BIOS_OWNED | LEGACY_OS_OWNED)
+                != LEGACY_OS_OWNED
+            {
+                if time::monotonic_ns() >= deadline {
+                    crate::log_warn!("xhci", "firmware did not let go of the controller");
+                    break;
+                }
+
+                core::hint::spin_loop();
+            }
+
+            let control = registers.read::<u32>(offset + 4);
+
+            registers.write(offset + 4, control & !LEGACY_SMI_ENABLES);
+
+            return;
+        }
+
+        offset = match (capability >> 8) & 0xFF {
+            0 => 0,
+            next => offset + ((next as usize) << 2),
+        };
+    }
+}
+
+fn wait_for(registers: &Mmio, offset: usize, mask: u32, value: u32) -> Result<(), Error> {
+    let deadline = time::monotonic_ns() + RESET_TIMEOUT_NS;
+
+    while registers.read::<u32>(offset) & mask != value {
+        if time::monotonic_ns() >= deadline {
+            return Err(Error::ResetTimedOut);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+impl Controller {
+    pub fn new(device: &pci::Device) -> Result<Self, Error> {
+        let Some(Bar::Memory { address, size, .. }) = device.bar(0) else {
+            return Err(Error::MissingBar);
+        };
+
+        device.enable_bus_master();
+
+        let registers = Mmio::map(address, size as usize);
+
+        let caplength = registers.read::<u8>(CAP_CAPLENGTH) as usize;
+        let hcsparams1 = registers.read::<u32>(CAP_HCSPARAMS1);
+        let hcsparams2 = registers.read::<u32>(CAP_HCSPARAMS2);
+        let hccparams1 = registers.read::<u32>(CAP_HCCPARAMS1);
+
+        let max_slots = hcsparams1 & 0xFF;
+        let ports = (hcsparams1 >> 24) as u8;
+
+        let operational = registers.subrange(caplength, OP_PORTS + ports as usize * 0x10);
+        let interrupter = registers.subrange(
+            (registers.read::<u32>(CAP_RTSOFF) & !0x1F) as usize + RUNTIME_INTERRUPTER,
+            0x20,
+        );
+        let doorbells = registers.subrange(
+            (registers.read::<u32>(CAP_DBOFF) & !0x3) as usize,
+            (max_slots as usize + 1) * 4,
+        );
+
+        take_ownership(&registers, hccparams1);
+
+        operational.write(OP_USBCMD, operational.read::<u32>(OP_USBCMD) & !USBCMD_RS);
+        wait_for(&operational, OP_USBSTS, USBSTS_HCH, USBSTS_HCH)?;
+
+        operational.write(OP_USBCMD, USBCMD_HCRST);
+        wait_for(&operational, OP_USBCMD, USBCMD_HCRST, 0)?;
+        wait_for(&operational, OP_USBSTS, USBSTS_CNR, 0)?;
+
+        let constraints = if hccparams1 & HCCPARAMS1_AC64 != 0 {
+            Constraints::new()
+        } else {
+            Constraints::new().below_4gib()
+        };
+
+        let context_size = if hccparams1 & HCCPARAMS1_CSZ != 0 {
+            64
+        } else {
+            32
+        };
+
+        operational.write(OP_CONFIG, max_slots);
+
+        let dcbaa = dma::alloc_coherent((max_slots as usize + 1) * 8, constraints)
+            .map_err(|_| Error::OutOfMemory)?;
+
+        // Memory the controller keeps its own state in, in pages of its page size
+        let scratchpad_count =
+            ((hcsparams2 >> 27) & 0x1F | ((hcsparams2 >> 21) & 0x1F) << 5) as usize;
+        let page_size = 4096 << operational.read::<u32>(OP_PAGESIZE).trailing_zeros();
+
+        let mut scratchpad_array = dma::alloc_coherent(scratchpad_count.max(1) * 8, constraints)
+            .map_err(|_| Error::OutOfMemory)?;
+        let mut scratchpad_pages = Vec::with_capacity(scratchpad_count);
+
+        for i in 0..scratchpad_count {
+            let page = dma::alloc_coherent(page_size, constraints.aligned(page_size))
+                .map_err(|_| Error::OutOfMemory)?;
+
+            scratchpad_array.as_mut_slice()[i * 8..(i + 1) * 8]
+                .copy_from_slice(&page.bus_address().to_le_bytes());
+            scratchpad_pages.push(page);
+        }
+
+        let commands = Ring::new(constraints)?;
+        let events = EventRing::new(constraints)?;
+
+        let inner = Inner {
+            operational,
+            interrupter,
+            doorbells,
+            context_size,
+            constraints,
+            dcbaa,
+            scratchpad: (scratchpad_array, scratchpad_pages),
+            commands,
+            events,
+            completions: BTreeMap::new(),
+            slots: BTreeMap::new(),
+            // Look at every port once, for what was connected before we started
+            changed_ports: (1..=ports).collect(),
+        };
+
+        if scratchpad_count != 0 {
+            inner.set_dcbaa(0, inner.scratchpad.0.bus_address());
+        }
+
+        operational.write(OP_DCBAAP, inner.dcbaa.bus_address());
+        operational.write(OP_CRCR, inner.commands.address() | CRCR_RCS);
+
+        // Interrupts are not routed yet, the event ring is polled
+        interrupter.write(IR_ERSTSZ, 1u32);
+        interrupter.write(IR_ERDP, inner.events.dequeue_address());
+        interrupter.write(IR_ERSTBA, inner.events.table.bus_address());
+
+        operational.write(OP_USBCMD, USBCMD_RS);
+        wait_for(&operational, OP_USBSTS, USBSTS_HCH, 0)?;
+
+        for port in 1..=ports {
+            if inner.port_status(port) & PORTSC_PP == 0 {
+                inner.set_port_status(port, PORTSC_PP);
+            }
+        }
+
+        Ok(Self {
+            address: device.address,
+            inner: Mutex::new(inner),
+            ports,
+            enumerating: AtomicBool::new(false),
+        })
+    }
+
+    pub fn ports(&self) -> u8 {
+        self.ports
+    }
+
+    /// Wait for the event about one of the TRBs, taking it
+    fn wait_event(&self, trbs: &[u64], timeout_ns: u64) -> Option<(u64, Trb)> {
+        let deadline = time::monotonic_ns() + timeout_ns;
+
+        wait::until(Some(deadline), || {
+            let mut inner = self.inner.lock();
+
+            inner.process_events();
+
+            trbs.iter()
+                .find_map(|&trb| inner.completions.remove(&trb).map(|event| (trb, event)))
+        })
+    }
+
+    fn command(&self, trb: Trb) -> Result<Trb, Error> {
+        let address = {
+            let mut inner = self.inner.lock();
+
+            let address = inner.commands.push(trb);
+
+            inner.ring_doorbell(0, 0);
+
+            address
+        };
+
+        let (_, event) = self
+            .wait_event(&[address], COMMAND_TIMEOUT_NS)
+            .ok_or(Error::Timeout)?;
+
+        match event.completion_code() {
+            COMPLETION_SUCCESS => Ok(event),
+            code => Err(Error::Failed(code)),
+        }
+    }
+
+    /// Get a slot for the device on `port` and give it an address
+    pub fn address_device(&self, port: u8, speed: Speed) -> Result<u8, Error> {
+        let slot = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?.slot();
+
+        self.setup_slot(slot, port, speed)
+            .inspect_err(|_| self.disable_slot(slot))?;
+
+        Ok(slot)
+    }
+
+    fn setup_slot(&self, slot: u8, port: u8, speed: Speed) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+
+        let (constraints, context_size) = (inner.constraints, inner.context_size);
+
+        let output =
+            dma::alloc_coherent(context_size * 32, constraints).map_err(|_| Error::OutOfMemory)?;
+        let input =
+            dma::alloc_coherent(context_size * 33, constraints).map_err(|_| Error::OutOfMemory)?;
+        let ring = Ring::new(constraints)?;
+
+        // Add the slot and the control endpoint
+        write_context(&input, context_size, 0, [0, 0b11, 0, 0, 0, 0, 0, 0]);
+        write_context(
+            &input,
+            context_size,
+            1,
+            [
+                speed_id(speed) << 20 | 1 << 27,
+                (port as u32) << 16,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        );
+        write_context(
+            &input,
+            context_size,
+            1 + CONTROL_ENDPOINT,
+            endpoint_context(
+                ENDPOINT_TYPE_CONTROL,
+                speed.default_max_packet_size(),
+                0,
+                ring.dequeue_pointer(),
+                8,
+            ),
+        );
+
+        inner.set_dcbaa(slot as usize, output.bus_address());
+
+        let input_address = input.bus_address();
+
+        let mut rings = core::array::from_fn(|_| None);
+        rings[CONTROL_ENDPOINT] = Some(ring);
+
+        inner.slots.insert(
+            slot,
+            Slot {
+                speed,
+                output,
+                input,
+                rings,
+            },
+        );
+
+        drop(inner);
+
+        self.command(Trb::for_endpoint(
+            TRB_ADDRESS_DEVICE,
+            input_address,
+            slot,
+            0,
+        ))?;
+
+        Ok(())
+    }
+
+    /// Give the slot back, once the controller let go of it its memory is freed
+    pub fn disable_slot(&self, slot: u8) {
+        if let Err(error) = self.command(Trb::for_endpoint(TRB_DISABLE_SLOT, 0, slot, 0)) {
+            crate::log_error!(
+                "xhci",
+                "{} disabling slot {}: {:?}",
+                self.address,
+                slot,
+                error
+            );
+        }
+
+        let mut inner = self.inner.lock();
+
+        inner.set_dcbaa(slot as usize, 0);
+        inner.slots.remove(&slot);
+    }
+
+    /// Tell the controller the real packet size of the control endpoint
+    pub fn set_max_packet_size(&self, slot: u8, max_packet_size: u16) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+
+        let context_size = inner.context_size;
+
+        let dequeue = inner.ring(slot, CONTROL_ENDPOINT)?.dequeue_pointer();
+        let slot_state = inner.slots.get(&slot).ok_or(Error::Disconnected)?;
+
+        write_context(
+            &slot_state.input,
+            context_size,
+            0,
+            [0, 1 << 1, 0, 0, 0, 0, 0, 0],
+        );
+        write_context(
+            &slot_state.input,
+            context_size,
+            1 + CONTROL_ENDPOINT,
+            endpoint_context(ENDPOINT_TYPE_CONTROL, max_packet_size, 0, dequeue, 8),
+        );
+
+        let input_address = slot_state.input.bus_address();
+
+        drop(inner);
+
+        self.command(Trb::for_endpoint(
+            TRB_EVALUATE_CONTEXT,
+            input_address,
+            slot,
+            0,
+        ))?;
+
+        Ok(())
+    }
+
+    /// Give the bulk and interrupt endpoints of the configuration that was set rings
+    pub fn configure_endpoints(
+        &self,
+        slot: u8,
+        endpoints: &[EndpointDescriptor],
+    ) -> Result<(), Error> {
+        if endpoints.is_empty() {
+            return Ok(());
+        }
+
+        let mut inner = self.inner.lock();
+
+        let (constraints, context_size) = (inner.constraints, inner.context_size);
+
+        let slot_state = inner.slots.get_mut(&slot).ok_or(Error::Disconnected)?;
+
+        let mut add = 1;
+        let mut last = read_context(&slot_state.output, context_size, 0)[0] >> 27;
+
+        for endpoint in endpoints {
+            let dci = device_context_index(endpoint);
+
+            let endpoint_type = match (endpoint.transfer_type(), endpoint.direction()) {
+                (TransferType::Bulk, Direction::Out) => ENDPOINT_TYPE_BULK_OUT,
+                (TransferType::Bulk, Direction::In) => ENDPOINT_TYPE_BULK_IN,
+                (TransferType::Interrupt, Direction::Out) => ENDPOINT_TYPE_INTERRUPT_OUT,
+                (TransferType::Interrupt, Direction::In) => ENDPOINT_TYPE_INTERRUPT_IN,
+                _ => return Err(Error::Unsupported),
+            };
+
+            let ring = Ring::new(constraints)?;
+
+            let average_trb_len = if endpoint.transfer_type() == TransferType::Bulk {
+                3072
+            } else {
+                endpoint.packet_size()
+            };
+
+            write_context(
+                &slot_state.input,
+                context_size,
+                1 + dci,
+                endpoint_context(
+                    endpoint_type,
+                    endpoint.packet_size(),
+                    interval(slot_state.speed, endpoint),
+                    ring.dequeue_pointer(),
+                    average_trb_len,
+                ),
+            );
+
+            slot_state.rings[dci] = Some(ring);
+
+            add |= 1 << dci;
+            last = last.max(dci as u32);
+        }
+
+        let mut slot_context = read_context(&slot_state.output, context_size, 0);
+
+        slot_context[0] = (slot_context[0] & !(0x1F << 27)) | last << 27;
+        // The slot state and device address the controller keeps are not input
+        slot_context[3] = 0;
+
+        write_context(
+            &slot_state.input,
+            context_size,
+            0,
+            [0, add, 0, 0, 0, 0, 0, 0],
+        );
+        write_context(&slot_state.input, context_size, 1, slot_context);
+
+        let input_address = slot_state.input.bus_address();
+
+        drop(inner);
+
+        self.command(Trb::for_endpoint(
+            TRB_CONFIGURE_ENDPOINT,
+            input_address,
+            slot,
+            0,
+        ))?;
+
+        Ok(())
+    }
+
+    /// Get an endpoint going again after an error or a timeout, skipping what was left on its
+    /// ring
+    fn recover(&self, slot: u8, dci: usize, halted: bool) {
+        let kind = if halted {
+            TRB_RESET_ENDPOINT
+        } else {
+            TRB_STOP_ENDPOINT
+        };
+
+        let _ = self.command(Trb::for_endpoint(kind, 0, slot, dci));
+
+        let Ok(dequeue) = self
+            .inner
+            .lock()
+            .ring(slot, dci)
+            .map(|ring| ring.dequeue_pointer())
+        else {
+            return;
+        };
+
+        let _ = self.command(Trb::for_endpoint(TRB_SET_DEQUEUE, dequeue, slot, dci));
+    }
+
+    /// Wait for a transfer made of the `data` TRBs with their lengths, done when `last` is,
+    /// returning how much was moved
+    fn finish(
+        &self,
+        slot: u8,
+        dci: usize,
+        data: &[(u64, u32)],
+        last: u64,
+        short_ends: bool,
+    ) -> Result<usize, Error> {
+        let mut trbs: Vec<u64> = data.iter().map(|&(trb, _)| trb).collect();
+        trbs.push(last);
+
+        let mut transferred = total_len(data);
+
+        let result = loop {
+            let Some((trb, event)) = self.wait_event(&trbs, TRANSFER_TIMEOUT_NS) else {
+                self.recover(slot, dci, false);
+
+                break Err(Error::Timeout);
+            };
+
+            if let Some(result) =
+                transfer_result(data, last, short_ends, trb, &event, &mut transferred)
+            {
+                if result.is_err() {
+                    self.recover(slot, dci, true);
+                }
+
+                break result;
+            }
+        };
+
+        self.forget_events(&trbs);
+
+        result
+    }
+
+    /// Events for the TRBs of a transfer that came after we stopped looking must not match
+    /// later transfers
+    fn forget_events(&self, trbs: &[u64]) {
+        let mut inner = self.inner.lock();
+
+        for trb in trbs {
+            inner.completions.remove(trb);
+        }
+    }
+
+    fn bounce_buffer(&self, len: usize) -> Result<CoherentBuffer, Error> {
+        if len > MAX_TRANSFER_LEN {
+            return Err(Error::Unsupported);
+        }
+
+        let constraints = self.inner.lock().constraints;
+
+        dma::alloc_coherent(len, constraints).map_err(|_| Error::OutOfMemory)
+    }
+
+    /// Run a control transfer, with a data stage if there is a buffer
+    fn control(
+        &self,
+        slot: u8,
+        setup: SetupPacket,
+        direction: Direction,
+        buffer: Option<&CoherentBuffer>,
+    ) -> Result<usize, Error> {
+        let len = buffer.map_or(0, |buffer| buffer.len());
+
+        let parameter = setup.request_type as u64
+            | (setup.request as u64) << 8
+            | (setup.value as u64) << 16
+            | (setup.index as u64) << 32
+            | (len as u64) << 48;
+
+        let direction_in = if direction == Direction::In {
+            TRB_DIRECTION_IN
+        } else {
+            0
+        };
+
+        let (data, last) = {
+            let mut inner = self.inner.lock();
+
+            let ring = inner.ring(slot, CONTROL_ENDPOINT)?;
+
+            let transfer_type = match (buffer, direction) {
+                (None, _) => 0,
+                (Some(_), Direction::In) => SETUP_DATA_IN,
+                (Some(_), Direction::Out) => SETUP_DATA_OUT,
+            };
+
+            ring.push(Trb::new(TRB_SETUP, parameter, 8, TRB_IDT | transfer_type));
+
+            let data = buffer
+                .map(|buffer| {
+                    push_data(
+                        ring,
+                        TRB_DATA,
+                        direction_in,
+                        buffer.bus_address(),
+                        buffer.len(),
+                        0,
+                    )
+                })
+                .unwrap_or_default();
+
+            // The status stage goes the other way, or in when there was no data
+            let status_in = if buffer.is_none() || direction == Direction::Out {
+                TRB_DIRECTION_IN
+            } else {
+                0
+            };
+
+            let last = ring.push(Trb::new(TRB_STATUS, 0, 0, TRB_IOC | status_in));
+
+            inner.ring_doorbell(slot, CONTROL_ENDPOINT);
+
+            (data, last)
+        };
+
+        self.finish(slot, CONTROL_ENDPOINT, &data, last, false)
+    }
+
+    pub fn control_in(
+        &self,
+        slot: u8,
+        setup: SetupPacket,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        if buffer.is_empty() {
+            return self.control(slot, setup, Direction::In, None);
+        }
+
+        let bounce = self.bounce_buffer(buffer.len())?;
+
+        let len = self.control(slot, setup, Direction::In, Some(&bounce))?;
+
+        buffer[..len].copy_from_slice(&bounce.as_slice()[..len]);
+
+        Ok(len)
+    }
+
+    pub fn control_out(&self, slot: u8, setup: SetupPacket, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return self.control(slot, setup, Direction::Out, None).map(|_| ());
+        }
+
+        let mut bounce = self.bounce_buffer(data.len())?;
+
+        bounce.as_mut_slice().copy_from_slice(data);
+
+        self.control(slot, setup, Direction::Out, Some(&bounce))
+            .map(|_| ())
+    }
+
+    /// Run a bulk or interrupt transfer on an endpoint given a ring by `configure_endpoints`
+    fn transfer(&self, slot: u8, dci: usize, buffer: &CoherentBuffer) -> Result<usize, Error> {
+        let data = {
+            let mut inner = self.inner.lock();
+
+            let ring = inner.ring(slot, dci)?;
+
+            let data = push_data(
+                ring,
+                TRB_NORMAL,
+                0,
+                buffer.bus_address(),
+                buffer.len(),
+                TRB_IOC,
+            );
+
+            inner.ring_doorbell(slot, dci);
+
+            data
+        };
+
+        let last = data.last().unwrap().0;
+
+        self.finish(slot, dci, &data, last, true)
+    }
+
+    pub fn transfer_in(
+        &self,
+        slot: u8,
+        endpoint: &EndpointDescriptor,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        let bounce = self.bounce_buffer(buffer.len())?;
+
+        let len = self.transfer(slot, device_context_index(endpoint), &bounce)?;
+
+        buffer[..len].copy_from_slice(&bounce.as_slice()[..len]);
+
+        Ok(len)
+    }
+
+    pub fn transfer_out(
+        &self,
+        slot: u8,
+        endpoint: &EndpointDescriptor,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let mut bounce = self.bounce_buffer(data.len())?;
+
+        bounce.as_mut_slice().copy_from_slice(data);
+
+        self.transfer(slot, device_context_index(endpoint), &bounce)
+            .map(|_| ())
+    }
+
+    fn queue(&self, transfer: &mut Transfer) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+
+        let ring = inner.ring(transfer.slot, transfer.dci)?;
+
+        transfer.data = push_data(
+            ring,
+            TRB_NORMAL,
+            0,
+            transfer.buffer.bus_address(),
+            transfer.buffer.len(),
+            TRB_IOC,
+        );
+        transfer.transferred = total_len(&transfer.data);
+
+        inner.ring_doorbell(transfer.slot, transfer.dci);
+
+        Ok(())
+    }
+
+    /// Start receiving up to `len` bytes from an IN endpoint without waiting for them
+    pub fn submit_in(
+        &self,
+        slot: u8,
+        endpoint: &EndpointDescriptor,
+        len: usize,
+    ) -> Result<Transfer, Error> {
+        let mut transfer = Transfer {
+            slot,
+            dci: device_context_index(endpoint),
+            buffer: self.bounce_buffer(len)?,
+            data: Vec::new(),
+            transferred: 0,
+        };
+
+        self.queue(&mut transfer)?;
+
+        Ok(transfer)
+    }
+
+    /// Check on a submitted transfer, returning how much came once it completed
+    pub fn poll_transfer(&self, transfer: &mut Transfer) -> Option<Result<usize, Error>> {
+        let last = transfer.data.last()?.0;
+
+        let (trb, event) = {
+            let mut inner = self.inner.lock();
+
+            inner.process_events();
+
+            transfer
+                .data
+                .iter()
+                .find_map(|&(trb, _)| inner.completions.remove(&trb).map(|event| (trb, event)))
+        }?;
+
+        let result = transfer_result(
+            &transfer.data,
+            last,
+            true,
+            trb,
+            &event,
+            &mut transfer.transferred,
+        )?;
+
+        if result.is_err() {
+            self.recover(transfer.slot, transfer.dci, true);
+        }
+
+        let trbs: Vec<u64> = transfer.data.drain(..).map(|(trb, _)| trb).collect();
+
+        self.forget_events(&trbs);
+
+        Some(result)
+    }
+
+    /// Queue a completed transfer again, into the same buffer
+    pub fn resubmit(&self, transfer: &mut Transfer) -> Result<(), Error> {
+        self.queue(transfer)
+    }
+
+    /// Stop a transfer that has not completed yet, so its buffer can go
+    pub fn cancel(&self, mut transfer: Transfer) {
+        if transfer.data.is_empty() {
+            return;
+        }
+
+        self.recover(transfer.slot, transfer.dci, false);
+
+        let trbs: Vec<u64> = transfer.data.drain(..).map(|(trb, _)| trb).collect();
+
+        self.forget_events(&trbs);
+    }
+
+    /// Reset a port that has something connected, USB 3 ports reset themselves on connect
+    fn reset_port(&self, port: u8) -> bool {
+        let inner = self.inner.lock();
+
+        if inner.port_status(port) & PORTSC_PED != 0 {
+            return true;
+        }
+
+        inner.set_port_status(port, PORTSC_PR);
+
+        drop(inner);
+
+        let deadline = time::monotonic_ns() + PORT_RESET_TIMEOUT_NS;
+
+        let reset = wait::until(Some(deadline), || {
+            let status = self.inner.lock().port_status(port);
+
+            (status & PORTSC_PRC != 0).then_some(status)
+        });
+
+        self.inner.lock().clear_port_changes(port);
+
+        time::delay_ns(PORT_RESET_RECOVERY_NS);
+
+        reset.is_some_and(|status| status & PORTSC_PED != 0)
+    }
+
+    fn port_changed(self: &Arc<Self>, port: u8) {
+        let status = {
+            let inner = self.inner.lock();
+
+            let status = inner.port_status(port);
+
+            inner.clear_port_changes(port);
+
+            status
+        };
+
+        let connected = status & PORTSC_CCS != 0;
+
+        // A new device may have replaced the old one between two looks
+        if usb::is_attached(self, port) {
+            if connected && status & PORTSC_CSC == 0 {
+                return;
+            }
+
+            usb::disconnect(self, port);
+        }
+
+        if !connected {
+            return;
+        }
+
+        if !self.reset_port(port) {
+            crate::log_error!("xhci", "{} port {}: reset failed", self.address, port);
+            return;
+        }
+
+        let status = self.inner.lock().port_status(port);
+
+        let Some(speed) = speed_from_id((status >> 10) & 0xF) else {
+            crate::log_error!("xhci", "{} port {}: unknown speed", self.address, port);
+            return;
+        };
+
+        if let Err(error) = usb::enumerate(self, port, speed) {
+            crate::log_error!("usb", "{} port {}: {:?}", self.address, port, error);
+        }
+    }
+
+    /// Handle the events, and the ports that changed unless already enumerating
+    pub fn poll(self: &Arc<Self>) {
+        let Some(mut inner) = self.inner.try_lock() else {
+            return;
+        };
+
+        inner.process_events();
+
+        if inner.changed_ports.is_empty() || self.enumerating.swap(true, Ordering::Acquire) {
+            return;
+        }
+
+        let ports = core::mem::take(&mut inner.changed_ports);
+
+        drop(inner);
+
+        for port in ports {
+            self.port_changed(port);
+        }
+
+        self.enumerating.store(false, Ordering::Release);
+    }
+}
+
+fn total_len(data: &[(u64, u32)]) -> usize {
+    data.iter().map(|&(_, len)| len as usize).sum()
+}
+
+/// What the event about one of its TRBs means for a transfer made of the `data` TRBs and done
+/// when `last` is, `None` while it goes on
+///
+/// A short packet ends bulk and interrupt transfers, control transfers still have their status
+/// stage to finish
+fn transfer_result(
+    data: &[(u64, u32)],
+    last: u64,
+    short_ends: bool,
+    trb: u64,
+    event: &Trb,
+    transferred: &mut usize,
+) -> Option<Result<usize, Error>> {
+    match event.completion_code() {
+        COMPLETION_SUCCESS => (trb == last).then_some(Ok(*transferred)),
+        COMPLETION_SHORT_PACKET => {
+            if let Some(index) = data.iter().position(|&(data, _)| data == trb) {
+                *transferred = total_len(&data[..index])
+                    + data[index].1.saturating_sub(event.residue()) as usize;
+            }
+
+            (trb == last || short_ends).then_some(Ok(*transferred))
+        }
+        COMPLETION_STALL => Some(Err(Error::Stall)),
+        code => Some(Err(Error::Failed(code))),
+    }
+}
+
+/// Queue TRBs moving `len` bytes at `address`, split where a TRB can not reach across, the first
+/// is of `kind` with `kind_flags` and the rest normal TRBs chained to it, the last gets
+/// `last_flags`, returning the address and length of each
+fn push_data(
+    ring: &mut Ring,
+    kind: u32,
+    kind_flags: u32,
+    address: u64,
+    len: usize,
+    last_flags: u32,
+) -> Vec<(u64, u32)> {
+    let mut trbs = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let start = address + offset as u64;
+        let chunk = (len - offset).min(TRB_MAX_LEN - start as usize % TRB_MAX_LEN);
+
+        let done = offset + chunk == len;
+
+        let (kind, flags) = if trbs.is_empty() {
+            (kind, kind_flags)
+        } else {
+            (TRB_NORMAL, 0)
+        };
+
+        let control = TRB_ISP | flags | if done { last_flags } else { TRB_CHAIN };
+
+        trbs.push((
+            ring.push(Trb::new(kind, start, chunk as u32, control)),
+            chunk as u32,
+        ));
+
+        offset += chunk;
+
+        if done {
+            return trbs;
+        }
+    }
+}
+
+static CONTROLLERS: Mutex<Vec<Arc<Controller>>> = Mutex::new(Vec::new());
+
+pub fn init() {
+    for device in pci::devices() {
+        if (device.class, device.subclass, device.prog_if)
+            != (CLASS_SERIAL_BUS, SUBCLASS_USB, PROG_IF_XHCI)
+        {
+            continue;
+        }
+
+        match Controller::new(device) {
+            Ok(controller) => {
+                crate::log_ok!("xhci", "{} with {} ports", device.address, controller.ports);
+
+                let controller = Arc::new(controller);
+
+                CONTROLLERS.lock().push(controller.clone());
+
+                // Enumerate what is connected already
+                controller.poll();
+            }
+            Err(error) => crate::log_error!("xhci", "{}: {:?}", device.address, error),
+        }
+    }
+}
+
+pub fn poll() {
+    let Some(controllers) = CONTROLLERS
+        .try_lock()
+        .map(|controllers| controllers.clone())
+    else {
+        return;
+    };
+
+    for controller in controllers {
+        controller.poll();
+    }
+}
@@ -0,0 +1,281 @@
+//! The vDSO, a page of code mapped into programs so they can read the clock without asking the
+//! kernel, after a page the kernel keeps the clock's parameters in
+//!
+//! The code comes from the architecture, and is wrapped here in the ELF shared object programs look
+//! for at `AT_SYSINFO_EHDR`, exporting every function it has both as itself and with `__vdso_` in
+//! front. The parameters are published again whenever the clock moves, behind a sequence that is
+//! odd while they change like the clock's own
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Once;
+
+use crate::{
+    arch::{
+        paging::PAGE_SIZE,
+        vdso::{self, MACHINE, TEXT_OFFSET},
+    },
+    dma::{self, CoherentBuffer, Constraints},
+    file::Error,
+    mmap::{self, Mappable, Protection, Sharing},
+    sync::Mutex,
+    time,
+};
+
+const PROGRAM_HEADERS_OFFSET: usize = 0x40;
+const DYNAMIC_OFFSET: usize = 0x100;
+const HASH_OFFSET: usize = 0x180;
+const SYMBOLS_OFFSET: usize = 0x200;
+const STRINGS_OFFSET: usize = 0x400;
+
+const SYMBOL_SIZE: usize = 24;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const DYNAMIC_NULL: u64 = 0;
+const DYNAMIC_HASH: u64 = 4;
+const DYNAMIC_STRINGS: u64 = 5;
+const DYNAMIC_SYMBOLS: u64 = 6;
+const DYNAMIC_STRINGS_SIZE: u64 = 10;
+const DYNAMIC_SYMBOL_SIZE: u64 = 11;
+
+/// A global function
+const SYMBOL_INFO: u8 = 1 << 4 | 2;
+
+/// What the code reads, at the offsets it reads them at
+#[repr(C)]
+struct Data {
+    sequence: AtomicU64,
+    base_tsc: AtomicU64,
+    base_ns: AtomicU64,
+    frequency: AtomicU64,
+    boot_realtime_ns: AtomicU64,
+}
+
+struct Vdso {
+    data: CoherentBuffer,
+    text: CoherentBuffer,
+}
+
+impl Vdso {
+    fn data(&self) -> &Data {
+        unsafe { &*(self.data.as_ptr() as *const Data) }
+    }
+}
+
+impl Mappable for Vdso {
+    fn size(&self) -> Result<u64, Error> {
+        Ok(2 * PAGE_SIZE)
+    }
+
+    fn read_page(&self, index: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let page = match index {
+            0 => &self.data,
+            _ => &self.text,
+        };
+
+        buffer.copy_from_slice(page.as_slice());
+
+        Ok(())
+    }
+
+    fn write_page(&self, _index: u64, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn frame(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(self.data.bus_address()),
+            1 => Some(self.text.bus_address()),
+            _ => None,
+        }
+    }
+}
+
+static VDSO: Once<Arc<Vdso>> = Once::new();
+
+/// Taken while the parameters are published, as the clock can move from more than one place
+static PUBLISHING: Mutex<()> = Mutex::new(());
+
+/// Set when the clock moved while someone else was publishing, who publishes again
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Copy the clock's parameters where the code reads them
+///
+/// The clock can move from an interrupt that came in the middle of this, so it never waits
+pub fn publish() {
+    let Some(vdso) = VDSO.get() else {
+        return;
+    };
+
+    PENDING.store(true, Ordering::Release);
+
+    while PENDING.load(Ordering::Acquire) {
+        let Some(_guard) = PUBLISHING.try_lock() else {
+            return;
+        };
+
+        PENDING.store(false, Ordering::Relaxed);
+
+        let data = vdso.data();
+        let (base_tsc, base_ns, frequency) = time::clock();
+
+        data.sequence.fetch_add(1, Ordering::Acquire);
+
+        data.base_tsc.store(base_tsc, Ordering::Relaxed);
+        data.base_ns.store(base_ns, Ordering::Relaxed);
+        data.frequency.store(frequency, Ordering::Relaxed);
+        data.boot_realtime_ns
+            .store(time::boot_realtime_ns(), Ordering::Relaxed);
+
+        data.sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Map the vDSO after its data, returns where the data starts to be given back with
+/// [`mmap::munmap`], and the vDSO's ELF header is the page after it
+pub fn map() -> Result<u64, Error> {
+    let vdso: Arc<dyn Mappable> = VDSO.get().ok_or(Error::NotSupported)?.clone();
+
+    let start = mmap::reserve(2 * PAGE_SIZE as usize)?;
+
+    let mapped = mmap::mmap_fixed(
+        start,
+        vdso.clone(),
+        0,
+        PAGE_SIZE as usize,
+        Protection::READ,
+        Sharing::Private,
+    )
+    .and_then(|_| {
+        mmap::mmap_fixed(
+            start + PAGE_SIZE,
+            vdso,
+            PAGE_SIZE,
+            PAGE_SIZE as usize,
+            Protection::READ | Protection::EXECUTE,
+            Sharing::Private,
+        )
+    });
+
+    if let Err(error) = mapped {
+        mmap::munmap(start);
+
+        return Err(error);
+    }
+
+    Ok(start)
+}
+
+fn put(page: &mut [u8], offset: usize, bytes: &[u8]) {
+    page[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// The shared object around the architecture's code, linked to start at zero
+fn build(page: &mut [u8]) {
+    let text = vdso::text();
+
+    assert!(
+        TEXT_OFFSET as usize + text.len() <= page.len(),
+        "vdso: the code does not fit in a page"
+    );
+
+    put(page, TEXT_OFFSET as usize, text);
+
+    let mut strings = vec![0u8];
+    let mut symbols = Vec::new();
+
+    for (name, offset) in vdso::symbols() {
+        for name in [format!("__vdso_{}", name), String::from(name)] {
+            symbols.push((strings.len() as u32, TEXT_OFFSET + offset));
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+    }
+
+    put(page, STRINGS_OFFSET, &strings);
+
+    // The first symbol is the undefined one. There are no section headers, so the section a
+    // symbol is in only has to be something other than undefined
+    for (index, &(name, value)) in symbols.iter().enumerate() {
+        let symbol = SYMBOLS_OFFSET + (index + 1) * SYMBOL_SIZE;
+
+        put(page, symbol, &name.to_le_bytes());
+        page[symbol + 4] = SYMBOL_INFO;
+        put(page, symbol + 6, &1u16.to_le_bytes());
+        put(page, symbol + 8, &value.to_le_bytes());
+    }
+
+    // One bucket that chains every symbol
+    let count = symbols.len() as u32 + 1;
+    let mut hash = vec![1, count, 1, 0];
+
+    hash.extend(2..count);
+    hash.push(0);
+
+    for (index, word) in hash.iter().enumerate() {
+        put(page, HASH_OFFSET + index * 4, &word.to_le_bytes());
+    }
+
+    let dynamic = [
+        (DYNAMIC_HASH, HASH_OFFSET as u64),
+        (DYNAMIC_STRINGS, STRINGS_OFFSET as u64),
+        (DYNAMIC_SYMBOLS, SYMBOLS_OFFSET as u64),
+        (DYNAMIC_STRINGS_SIZE, strings.len() as u64),
+        (DYNAMIC_SYMBOL_SIZE, SYMBOL_SIZE as u64),
+        (DYNAMIC_NULL, 0),
+    ];
+
+    for (index, (tag, value)) in dynamic.iter().enumerate() {
+        put(page, DYNAMIC_OFFSET + index * 16, &tag.to_le_bytes());
+        put(page, DYNAMIC_OFFSET + index * 16 + 8, &value.to_le_bytes());
+    }
+
+    // A loadable segment with everything, readable and executable, and the dynamic one
+    let program_headers: [(u32, u32, usize, usize, u64); 2] = [
+        (1, 5, 0, PAGE_SIZE as usize, PAGE_SIZE),
+        (2, 4, DYNAMIC_OFFSET, dynamic.len() * 16, 8),
+    ];
+
+    for (index, &(kind, flags, offset, size, align)) in program_headers.iter().enumerate() {
+        let header = PROGRAM_HEADERS_OFFSET + index * PROGRAM_HEADER_SIZE;
+
+        put(page, header, &kind.to_le_bytes());
+        put(page, header + 4, &flags.to_le_bytes());
+
+        for field in [8, 16, 24] {
+            put(page, header + field, &(offset as u64).to_le_bytes());
+        }
+
+        put(page, header + 32, &(size as u64).to_le_bytes());
+        put(page, header + 40, &(size as u64).to_le_bytes());
+        put(page, header + 48, &align.to_le_bytes());
+    }
+
+    put(page, 0, b"\x7FELF\x02\x01\x01");
+    put(page, 16, &3u16.to_le_bytes());
+    put(page, 18, &MACHINE.to_le_bytes());
+    put(page, 20, &1u32.to_le_bytes());
+    put(page, 32, &(PROGRAM_HEADERS_OFFSET as u64).to_le_bytes());
+    put(page, 52, &64u16.to_le_bytes());
+    put(page, 54, &(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    put(page, 56, &(program_headers.len() as u16).to_le_bytes());
+    put(page, 58, &64u16.to_le_bytes());
+}
+
+pub fn init() {
+    let alloc = || dma::alloc_coherent(PAGE_SIZE as usize, Constraints::new());
+
+    let (Ok(data), Ok(mut text)) = (alloc(), alloc()) else {
+        println!("vdso: out of memory, programs will ask the kernel for the time");
+        return;
+    };
+
+    build(text.as_mut_slice());
+
+    VDSO.call_once(|| Arc::new(Vdso { data, text }));
+
+    publish();
+
+    println!("vdso: {} bytes of code", vdso::text().len());
+}
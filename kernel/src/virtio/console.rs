@@ -0,0 +1,642 @@
+//! The virtio console device, ports to the host that carry the kernel log and byte streams
+//!
+//! Without multiport the device has a single port, which is the console. With it, the host adds
+//! ports over the control queues, names them, and says which one is the console
+
+use alloc::{
+    collections::VecDeque,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{DEVICE_CONSOLE, Error, Transport, Virtqueue, queue::Segment};
+use crate::{
+    dma::{self, CoherentBuffer, Constraints},
+    file::{self, Events, File, OpenFlags},
+    log,
+    sync::Mutex,
+    wait::{self, WaitQueue},
+};
+
+/// The size of the console is in the configuration
+pub const FEATURE_SIZE: u64 = 1 << 0;
+pub const FEATURE_MULTIPORT: u64 = 1 << 1;
+
+const CONFIG_COLS: usize = 0;
+const CONFIG_ROWS: usize = 2;
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+const CONTROL_RECEIVE_QUEUE: u16 = 2;
+const CONTROL_TRANSMIT_QUEUE: u16 = 3;
+
+const CONTROL_DEVICE_READY: u16 = 0;
+const CONTROL_DEVICE_ADD: u16 = 1;
+const CONTROL_DEVICE_REMOVE: u16 = 2;
+const CONTROL_PORT_READY: u16 = 3;
+const CONTROL_CONSOLE_PORT: u16 = 4;
+const CONTROL_RESIZE: u16 = 5;
+const CONTROL_PORT_OPEN: u16 = 6;
+const CONTROL_PORT_NAME: u16 = 7;
+
+/// The port, the event and its value, some events have more after them
+const CONTROL_HEADER_SIZE: usize = 8;
+
+const QUEUE_SIZE: u16 = 32;
+const BUFFER_SIZE: usize = 512;
+
+/// Input past this many unread bytes is dropped
+const INPUT_LIMIT: usize = 4096;
+
+/// Port 0 has the first two queues, the control queues come before the ones of the other ports
+fn receive_queue(port: u32) -> u16 {
+    if port == 0 { 0 } else { (port * 2 + 2) as u16 }
+}
+
+struct Queue {
+    queue: Virtqueue,
+    /// One buffer per descriptor
+    buffers: CoherentBuffer,
+}
+
+impl Queue {
+    fn new(transport: &Transport, index: u16) -> Result<Self, Error> {
+        let queue = transport.setup_queue(index, QUEUE_SIZE)?;
+        let buffers = dma::alloc_coherent(queue.size() as usize * BUFFER_SIZE, Constraints::new())
+            .map_err(|_| Error::OutOfMemory)?;
+
+        Ok(Self { queue, buffers })
+    }
+
+    fn buffer_address(&self, descriptor: u16) -> u64 {
+        self.buffers.bus_address() + (descriptor as usize * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&mut self, descriptor: u16) -> &mut [u8] {
+        let start = descriptor as usize * BUFFER_SIZE;
+
+        &mut self.buffers.as_mut_slice()[start..start + BUFFER_SIZE]
+    }
+
+    /// Give every descriptor a buffer for the device to fill
+    fn fill(&mut self, transport: &Transport) -> Result<(), Error> {
+        while self.queue.free_descriptors() != 0 {
+            let address = self.buffer_address(self.queue.free_head());
+
+            self.queue
+                .add(&[Segment::writable(address, BUFFER_SIZE as u32)])?;
+        }
+
+        transport.notify(&self.queue);
+
+        Ok(())
+    }
+
+    /// Hand what the device wrote to `f`, then give the buffers back
+    fn receive(&mut self, transport: &Transport, mut f: impl FnMut(&[u8])) {
+        let mut received = false;
+
+        while let Some((descriptor, len)) = self.queue.pop_used() {
+            f(&self.buffer(descriptor)[..(len as usize).min(BUFFER_SIZE)]);
+
+            let address = self.buffer_address(descriptor);
+
+            // The descriptor that was just freed
+            let _ = self
+                .queue
+                .add(&[Segment::writable(address, BUFFER_SIZE as u32)]);
+
+            received = true;
+        }
+
+        if received {
+            transport.notify(&self.queue);
+        }
+    }
+
+    /// Send as much of `data` as fits in one buffer, zero when every buffer is still in flight
+    fn send(&mut self, transport: &Transport, data: &[u8]) -> usize {
+        // Reclaim the buffers the device already took
+        while self.queue.pop_used().is_some() {}
+
+        if self.queue.free_descriptors() == 0 || data.is_empty() {
+            return 0;
+        }
+
+        let len = data.len().min(BUFFER_SIZE);
+        let descriptor = self.queue.free_head();
+
+        self.buffer(descriptor)[..len].copy_from_slice(&data[..len]);
+
+        let address = self.buffer_address(descriptor);
+
+        if self
+            .queue
+            .add(&[Segment::readable(address, len as u32)])
+            .is_err()
+        {
+            return 0;
+        }
+
+        transport.notify(&self.queue);
+
+        len
+    }
+}
+
+pub struct Port {
+    id: u32,
+    /// The name the host gave the port, only with multiport
+    name: Mutex<Option<String>>,
+    input: Mutex<VecDeque<u8>>,
+    /// Columns and rows, when the host says
+    size: Mutex<Option<(u16, u16)>>,
+    /// Whether a program on the host has the port open
+    host_connected: AtomicBool,
+    removed: AtomicBool,
+    /// How many files have the port open
+    opened: AtomicUsize,
+    wait_queue: WaitQueue,
+}
+
+impl Port {
+    fn new(id: u32) -> Self {
+        Self {
+            id,
+            name: Mutex::new(None),
+            input: Mutex::new(VecDeque::new()),
+            size: Mutex::new(None),
+            host_connected: AtomicBool::new(false),
+            removed: AtomicBool::new(false),
+            opened: AtomicUsize::new(0),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.name.lock().clone()
+    }
+
+    pub fn size(&self) -> Option<(u16, u16)> {
+        *self.size.lock()
+    }
+
+    pub fn is_host_connected(&self) -> bool {
+        self.host_connected.load(Ordering::Relaxed)
+    }
+}
+
+struct PortQueues {
+    port: Arc<Port>,
+    receive: Queue,
+    transmit: Queue,
+}
+
+struct Console {
+    transport: Transport,
+    /// The receive and transmit control queues, only with multiport
+    control: Option<(Queue, Queue)>,
+    ports: Vec<PortQueues>,
+    max_ports: u32,
+    /// The port the kernel log goes to
+    console_port: Option<u32>,
+    /// Where in the log the next write to the console port starts
+    log_position: u64,
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+impl Console {
+    fn port(&mut self, id: u32) -> Option<&mut PortQueues> {
+        self.ports.iter_mut().find(|queues| queues.port.id == id)
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let Some((_, transmit)) = self.control.as_mut() else {
+            return;
+        };
+
+        let mut message = [0; CONTROL_HEADER_SIZE];
+
+        message[0..4].copy_from_slice(&id.to_le_bytes());
+        message[4..6].copy_from_slice(&event.to_le_bytes());
+        message[6..8].copy_from_slice(&value.to_le_bytes());
+
+        transmit.send(&self.transport, &message);
+    }
+
+    fn add_port(&mut self, id: u32) -> Result<(), Error> {
+        if id >= self.max_ports || self.port(id).is_some() {
+            return Ok(());
+        }
+
+        let mut receive = Queue::new(&self.transport, receive_queue(id))?;
+        let transmit = Queue::new(&self.transport, receive_queue(id) + 1)?;
+
+        receive.fill(&self.transport)?;
+
+        self.ports.push(PortQueues {
+            port: Arc::new(Port::new(id)),
+            receive,
+            transmit,
+        });
+
+        self.send_control(id, CONTROL_PORT_READY, 1);
+
+        Ok(())
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < CONTROL_HEADER_SIZE {
+            return;
+        }
+
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        let extra = &message[CONTROL_HEADER_SIZE..];
+
+        match event {
+            CONTROL_DEVICE_ADD => {
+                if let Err(error) = self.add_port(id) {
+                    crate::log_error!("virtio-console", "port {}: {:?}", id, error);
+                }
+            }
+            CONTROL_DEVICE_REMOVE => {
+                if let Some(index) = self.ports.iter().position(|queues| queues.port.id == id) {
+                    let queues = self.ports.remove(index);
+
+                    queues.port.removed.store(true, Ordering::Relaxed);
+                    queues.port.wait_queue.wake_all();
+                }
+
+                if self.console_port == Some(id) {
+                    self.console_port = None;
+                }
+            }
+            CONTROL_CONSOLE_PORT => {
+                if self.port(id).is_some() {
+                    self.console_port = Some(id);
+                    self.send_control(id, CONTROL_PORT_OPEN, 1);
+                }
+            }
+            CONTROL_RESIZE if extra.len() >= 4 => {
+                if let Some(queues) = self.port(id) {
+                    let rows = u16::from_le_bytes(extra[0..2].try_into().unwrap());
+                    let cols = u16::from_le_bytes(extra[2..4].try_into().unwrap());
+
+                    *queues.port.size.lock() = Some((cols, rows));
+                }
+            }
+            CONTROL_PORT_OPEN => {
+                if let Some(queues) = self.port(id) {
+                    queues
+                        .port
+                        .host_connected
+                        .store(value != 0, Ordering::Relaxed);
+                    queues.port.wait_queue.wake_all();
+                }
+            }
+            CONTROL_PORT_NAME => {
+                if let Some(queues) = self.port(id) {
+                    let name = extra.split(|&byte| byte == 0).next().unwrap_or(&[]);
+
+                    *queues.port.name.lock() = Some(String::from_utf8_lossy(name).into());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Send what was logged since the last time to the console port
+    fn flush_log(&mut self) {
+        let Some(id) = self.console_port else {
+            return;
+        };
+
+        let position = self.log_position;
+        let transport = &self.transport;
+
+        let Some(queues) = self.ports.iter_mut().find(|queues| queues.port.id == id) else {
+            return;
+        };
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut position = position;
+
+        loop {
+            let (start, len) = log::read(position, &mut buffer);
+
+            if len == 0 {
+                break;
+            }
+
+            let sent = queues.transmit.send(transport, &buffer[..len]);
+
+            if sent == 0 {
+                break;
+            }
+
+            position = start + sent as u64;
+        }
+
+        self.log_position = position;
+    }
+
+    fn poll(&mut self) {
+        let mut messages = Vec::new();
+
+        if let Some((receive, _)) = self.control.as_mut() {
+            receive.receive(&self.transport, |message| messages.push(message.to_vec()));
+        }
+
+        for message in messages {
+            self.handle_control(&message);
+        }
+
+        for queues in &mut self.ports {
+            let mut received = false;
+
+            queues.receive.receive(&self.transport, |data| {
+                let mut input = queues.port.input.lock();
+
+                let room = INPUT_LIMIT - input.len();
+
+                input.extend(&data[..data.len().min(room)]);
+
+                received = true;
+            });
+
+            if received {
+                queues.port.wait_queue.wake_all();
+            }
+        }
+
+        self.flush_log();
+    }
+}
+
+/// Handle the control messages, take in what the ports received and send out the log
+pub fn poll() {
+    // Printing while polling must not come back here
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+
+    if let Some(console) = console.as_mut() {
+        console.poll();
+    }
+}
+
+/// Every port the host added
+pub fn ports() -> Vec<Arc<Port>> {
+    CONSOLE.lock().as_ref().map_or(Vec::new(), |console| {
+        console
+            .ports
+            .iter()
+            .map(|queues| queues.port.clone())
+            .collect()
+    })
+}
+
+/// The file name of a port, `hvc0` for the console and `vport0p<id>` for the others
+pub fn port_name(port: &Port) -> String {
+    let console_port = CONSOLE
+        .lock()
+        .as_ref()
+        .and_then(|console| console.console_port);
+
+    if console_port == Some(port.id) {
+        "hvc0".to_string()
+    } else {
+        format!("vport0p{}", port.id)
+    }
+}
+
+/// Open a port by its file name or the name the host gave it
+pub fn open(name: &str, flags: OpenFlags) -> Result<PortFile, file::Error> {
+    let port = ports()
+        .into_iter()
+        .find(|port| port_name(port) == name || port.name().as_deref() == Some(name))
+        .ok_or(file::Error::NotFound)?;
+
+    if port.opened.fetch_add(1, Ordering::Relaxed) == 0
+        && let Some(console) = CONSOLE.lock().as_mut()
+    {
+        console.send_control(port.id, CONTROL_PORT_OPEN, 1);
+    }
+
+    Ok(PortFile {
+        port,
+        nonblocking: AtomicBool::new(flags.contains(OpenFlags::NONBLOCK)),
+    })
+}
+
+pub struct PortFile {
+    port: Arc<Port>,
+    nonblocking: AtomicBool,
+}
+
+impl PortFile {
+    pub fn port(&self) -> &Arc<Port> {
+        &self.port
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    /// Send as much as fits in one buffer
+    fn send(&self, data: &[u8]) -> Result<usize, file::Error> {
+        let mut console = CONSOLE.lock();
+
+        let console = console.as_mut().ok_or(file::Error::BrokenPipe)?;
+        let transport = &console.transport;
+
+        let queues = console
+            .ports
+            .iter_mut()
+            .find(|queues| queues.port.id == self.port.id)
+            .ok_or(file::Error::BrokenPipe)?;
+
+        Ok(queues.transmit.send(transport, data))
+    }
+}
+
+impl File for PortFile {
+    /// Block until something came from the host, zero once the port is removed
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, file::Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            {
+                let mut input = self.port.input.lock();
+
+                if !input.is_empty() {
+                    let len = buffer.len().min(input.len());
+
+                    for (byte, input) in buffer.iter_mut().zip(input.drain(..len)) {
+                        *byte = input;
+                    }
+
+                    return Ok(len);
+                }
+            }
+
+            if self.port.removed.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(file::Error::WouldBlock);
+            }
+
+            wait::until(None, || (!self.poll().is_empty()).then_some(()));
+        }
+    }
+
+    /// Block until everything is handed to the device
+    fn write(&self, data: &[u8]) -> Result<usize, file::Error> {
+        let mut written = 0;
+
+        while written < data.len() {
+            let sent = self.send(&data[written..])?;
+
+            written += sent;
+
+            if sent != 0 {
+                continue;
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return if written == 0 {
+                    Err(file::Error::WouldBlock)
+                } else {
+                    Ok(written)
+                };
+            }
+
+            wait::poll();
+        }
+
+        Ok(written)
+    }
+
+    fn poll(&self) -> Events {
+        let mut events = Events::WRITABLE;
+
+        if !self.port.input.lock().is_empty() {
+            events |= Events::READABLE;
+        }
+
+        if self.port.removed.load(Ordering::Relaxed) {
+            events |= Events::HANGUP;
+        }
+
+        events
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.port.wait_queue)
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        if self.port.opened.fetch_sub(1, Ordering::Relaxed) == 1
+            && let Some(console) = CONSOLE.lock().as_mut()
+        {
+            console.send_control(self.port.id, CONTROL_PORT_OPEN, 0);
+        }
+    }
+}
+
+fn probe(transport: Transport) -> Result<Console, Error> {
+    let features = transport.negotiate(FEATURE_SIZE | FEATURE_MULTIPORT)?;
+
+    let multiport = features & FEATURE_MULTIPORT != 0;
+
+    let max_ports = if multiport {
+        transport
+            .read_config::<u32>(CONFIG_MAX_NR_PORTS)
+            .unwrap_or(1)
+    } else {
+        1
+    };
+
+    let control = if multiport {
+        Some((
+            Queue::new(&transport, CONTROL_RECEIVE_QUEUE)?,
+            Queue::new(&transport, CONTROL_TRANSMIT_QUEUE)?,
+        ))
+    } else {
+        None
+    };
+
+    transport.finish_init();
+
+    let mut console = Console {
+        transport,
+        control,
+        ports: Vec::new(),
+        max_ports,
+        console_port: None,
+        log_position: 0,
+    };
+
+    if let Some((receive, _)) = console.control.as_mut() {
+        receive.fill(&console.transport)?;
+
+        // The host adds the ports once it knows we are ready for them
+        console.send_control(0, CONTROL_DEVICE_READY, 1);
+    } else {
+        console.add_port(0)?;
+        console.console_port = Some(0);
+
+        if features & FEATURE_SIZE != 0 {
+            let cols = console.transport.read_config::<u16>(CONFIG_COLS);
+            let rows = console.transport.read_config::<u16>(CONFIG_ROWS);
+
+            if let (Some(cols), Some(rows)) = (cols, rows) {
+                *console.ports[0].port.size.lock() = Some((cols, rows));
+            }
+        }
+    }
+
+    Ok(console)
+}
+
+/// Drive the first virtio console, the kernel log goes to its console port from the start
+pub fn init() {
+    let Some(device) = super::devices(DEVICE_CONSOLE).next() else {
+        return;
+    };
+
+    match Transport::new(device).and_then(probe) {
+        Ok(console) => {
+            crate::log_ok!(
+                "virtio-console",
+                "{} with {}",
+                device.address,
+                if console.control.is_some() {
+                    format!("up to {} ports", console.max_ports)
+                } else {
+                    "one port".to_string()
+                }
+            );
+
+            *CONSOLE.lock() = Some(console);
+
+            poll();
+        }
+        Err(error) => crate::log_error!("virtio-console", "{}: {:?}", device.address, error),
+    }
+}
@@ -0,0 +1,418 @@
+//! The virtio GPU device, driven in 2D as a framebuffer we draw into and transfer to the host
+
+use alloc::vec::Vec;
+
+use super::{DEVICE_GPU, Transport, Virtqueue, queue::Segment};
+use crate::{
+    cmdline,
+    console::CONSOLE,
+    dma::{self, CoherentBuffer, Constraints},
+    screen::{self, Color},
+    sync::Mutex,
+    time,
+};
+
+const CONFIG_EVENTS_READ: usize = 0;
+const CONFIG_EVENTS_CLEAR: usize = 4;
+const CONFIG_NUM_SCANOUTS: usize = 8;
+
+/// The host changed the displays, like when its window was resized
+const EVENT_DISPLAY: u32 = 1;
+
+const CONTROL_QUEUE: u16 = 0;
+
+const QUEUE_SIZE: u16 = 16;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Blue, green, red and an unused byte, the layout of [`Color`]
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+/// The type, flags, fence, context and ring of every request and response
+const HEADER_SIZE: usize = 24;
+
+const MAX_SCANOUTS: usize = 16;
+/// A rectangle, whether the scanout is enabled and its flags
+const DISPLAY_SIZE: usize = 24;
+const DISPLAY_INFO_SIZE: usize = HEADER_SIZE + MAX_SCANOUTS * DISPLAY_SIZE;
+
+/// Requests go at the start of the buffer, the response after them
+const RESPONSE_OFFSET: usize = 2048;
+
+/// Only the first scanout is driven
+const SCANOUT: u32 = 0;
+
+/// For displays that do not say what they prefer
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+const COMMAND_TIMEOUT_NS: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Virtio(super::Error),
+    NoDevice,
+    /// The device did not answer a request in time
+    Timeout,
+    /// The device answered a request with an error
+    Response(u32),
+}
+
+impl From<super::Error> for Error {
+    fn from(error: super::Error) -> Self {
+        Error::Virtio(error)
+    }
+}
+
+/// The resource shown on the scanout, and the memory backing it
+struct Scanout {
+    resource: u32,
+    buffer: CoherentBuffer,
+    width: u32,
+    height: u32,
+}
+
+struct Gpu {
+    transport: Transport,
+    control: Virtqueue,
+    /// Holds one request and its response at a time
+    buffer: CoherentBuffer,
+    next_resource: u32,
+    scanout: Option<Scanout>,
+}
+
+static GPU: Mutex<Option<Gpu>> = Mutex::new(None);
+
+fn request(ty: u32, fields: &[u32]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(HEADER_SIZE + fields.len() * 4);
+
+    request.extend_from_slice(&ty.to_le_bytes());
+    request.resize(HEADER_SIZE, 0);
+
+    for field in fields {
+        request.extend_from_slice(&field.to_le_bytes());
+    }
+
+    request
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Gpu {
+    /// Send a request and wait for the response, which is expected to be of type `expected`
+    fn command(
+        &mut self,
+        request: &[u8],
+        response_len: usize,
+        expected: u32,
+    ) -> Result<&[u8], Error> {
+        // Whatever a timed out request left behind
+        while self.control.pop_used().is_some() {}
+
+        let address = self.buffer.bus_address();
+
+        self.buffer.as_mut_slice()[..request.len()].copy_from_slice(request);
+        self.buffer.as_mut_slice()[RESPONSE_OFFSET..RESPONSE_OFFSET + response_len].fill(0);
+
+        self.control.add(&[
+            Segment::readable(address, request.len() as u32),
+            Segment::writable(address + RESPONSE_OFFSET as u64, response_len as u32),
+        ])?;
+
+        self.transport.notify(&self.control);
+
+        // Nothing else is polled, this is called while printing
+        let deadline = time::monotonic_ns() + COMMAND_TIMEOUT_NS;
+
+        while self.control.pop_used().is_none() {
+            if time::monotonic_ns() >= deadline {
+                return Err(Error::Timeout);
+            }
+
+            core::hint::spin_loop();
+        }
+
+        let response = &self.buffer.as_slice()[RESPONSE_OFFSET..RESPONSE_OFFSET + response_len];
+
+        match read_u32(response, 0) {
+            ty if ty == expected => Ok(response),
+            ty => Err(Error::Response(ty)),
+        }
+    }
+
+    fn command_nodata(&mut self, ty: u32, fields: &[u32]) -> Result<(), Error> {
+        self.command(&request(ty, fields), HEADER_SIZE, RESP_OK_NODATA)?;
+
+        Ok(())
+    }
+
+    /// The size the host would like the scanout to be, if it is enabled
+    fn preferred_mode(&mut self) -> Result<Option<(u32, u32)>, Error> {
+        let response = self.command(
+            &request(CMD_GET_DISPLAY_INFO, &[]),
+            DISPLAY_INFO_SIZE,
+            RESP_OK_DISPLAY_INFO,
+        )?;
+
+        let display = &response[HEADER_SIZE + SCANOUT as usize * DISPLAY_SIZE..];
+        let (width, height, enabled) = (
+            read_u32(display, 8),
+            read_u32(display, 12),
+            read_u32(display, 16),
+        );
+
+        Ok((enabled != 0 && width != 0 && height != 0).then_some((width, height)))
+    }
+
+    /// Transfer the whole framebuffer to the host and show it
+    fn flush(&mut self) -> Result<(), Error> {
+        let Some(Scanout {
+            resource,
+            width,
+            height,
+            ..
+        }) = self.scanout
+        else {
+            return Ok(());
+        };
+
+        self.command_nodata(
+            CMD_TRANSFER_TO_HOST_2D,
+            &[0, 0, width, height, 0, 0, resource, 0],
+        )?;
+
+        self.command_nodata(CMD_RESOURCE_FLUSH, &[0, 0, width, height, resource, 0])
+    }
+}
+
+fn flush() {
+    // Printing while the device is busy, like while it prints itself, shows up on the next flush
+    let Some(mut gpu) = GPU.try_lock() else {
+        return;
+    };
+
+    if let Some(gpu) = gpu.as_mut() {
+        let _ = gpu.flush();
+    }
+}
+
+/// The size of the display
+pub fn mode() -> Option<(u32, u32)> {
+    GPU.lock()
+        .as_ref()?
+        .scanout
+        .as_ref()
+        .map(|scanout| (scanout.width, scanout.height))
+}
+
+/// Show a new framebuffer of the given size, and move the console onto it
+pub fn set_mode(width: u32, height: u32) -> Result<(), Error> {
+    let buffer = dma::alloc_contiguous(
+        width as usize * height as usize * size_of::<Color>(),
+        Constraints::new(),
+    )
+    .map_err(|_| Error::Virtio(super::Error::OutOfMemory))?;
+
+    let (address, len) = (buffer.bus_address(), buffer.len() as u32);
+
+    let mut guard = GPU.lock();
+    let gpu = guard.as_mut().ok_or(Error::NoDevice)?;
+
+    let resource = gpu.next_resource;
+    gpu.next_resource += 1;
+
+    gpu.command_nodata(
+        CMD_RESOURCE_CREATE_2D,
+        &[resource, FORMAT_B8G8R8X8_UNORM, width, height],
+    )?;
+
+    let attached = gpu
+        .command_nodata(
+            CMD_RESOURCE_ATTACH_BACKING,
+            &[resource, 1, address as u32, (address >> 32) as u32, len, 0],
+        )
+        .and_then(|()| {
+            gpu.command_nodata(CMD_SET_SCANOUT, &[0, 0, width, height, SCANOUT, resource])
+        });
+
+    if let Err(error) = attached {
+        let _ = gpu.command_nodata(CMD_RESOURCE_UNREF, &[resource, 0]);
+
+        return Err(error);
+    }
+
+    let previous = gpu.scanout.replace(Scanout {
+        resource,
+        buffer,
+        width,
+        height,
+    });
+
+    let colors = gpu
+        .scanout
+        .as_ref()
+        .map_or(core::ptr::null_mut(), |scanout| scanout.buffer.as_ptr())
+        .cast::<Color>();
+
+    // Printing flushes, which takes the device
+    drop(guard);
+
+    {
+        let mut console = CONSOLE.lock();
+
+        // The buffer stays until the next mode replaces it
+        unsafe { screen::set_framebuffer(colors, width as usize, height as usize, flush) };
+
+        console.resize();
+
+        screen::flush();
+    }
+
+    if let Some(previous) = previous {
+        if let Some(gpu) = GPU.lock().as_mut() {
+            // Unreferencing also detaches the backing
+            let _ = gpu.command_nodata(CMD_RESOURCE_UNREF, &[previous.resource, 0]);
+        }
+
+        drop(previous);
+    }
+
+    Ok(())
+}
+
+/// The size asked for with `video=<width>x<height>`, the host's displays are followed without one
+fn cmdline_mode() -> Option<(u32, u32)> {
+    let (width, height) = cmdline::value("video")?.split_once('x')?;
+
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Follow the host's display when it changes size
+pub fn poll() {
+    if cmdline_mode().is_some() {
+        return;
+    }
+
+    let preferred = {
+        let Some(mut guard) = GPU.try_lock() else {
+            return;
+        };
+
+        let Some(gpu) = guard.as_mut() else {
+            return;
+        };
+
+        let events = gpu
+            .transport
+            .read_config::<u32>(CONFIG_EVENTS_READ)
+            .unwrap_or(0);
+
+        if events & EVENT_DISPLAY == 0 {
+            return;
+        }
+
+        gpu.transport
+            .write_config(CONFIG_EVENTS_CLEAR, EVENT_DISPLAY);
+
+        match gpu.preferred_mode() {
+            Ok(Some(preferred)) => preferred,
+            _ => return,
+        }
+    };
+
+    if mode() == Some(preferred) {
+        return;
+    }
+
+    match set_mode(preferred.0, preferred.1) {
+        Ok(()) => crate::log_info!("virtio-gpu", "resized to {}x{}", preferred.0, preferred.1),
+        Err(error) => crate::log_error!(
+            "virtio-gpu",
+            "resizing to {}x{}: {:?}",
+            preferred.0,
+            preferred.1,
+            error
+        ),
+    }
+}
+
+fn probe(transport: Transport) -> Result<(u32, (u32, u32)), Error> {
+    transport.negotiate(0)?;
+
+    let control = transport.setup_queue(CONTROL_QUEUE, QUEUE_SIZE)?;
+
+    transport.finish_init();
+
+    let buffer = dma::alloc_coherent(RESPONSE_OFFSET + DISPLAY_INFO_SIZE, Constraints::new())
+        .map_err(|_| super::Error::OutOfMemory)?;
+
+    let scanouts = transport
+        .read_config::<u32>(CONFIG_NUM_SCANOUTS)
+        .unwrap_or(0);
+
+    let mut gpu = Gpu {
+        transport,
+        control,
+        buffer,
+        next_resource: 1,
+        scanout: None,
+    };
+
+    let mode = match cmdline_mode() {
+        Some(mode) => mode,
+        None => gpu.preferred_mode()?.unwrap_or(DEFAULT_MODE),
+    };
+
+    *GPU.lock() = Some(gpu);
+
+    Ok((scanouts, mode))
+}
+
+/// Take over the display from the bootloader's framebuffer, with the first virtio GPU
+pub fn init() {
+    let Some(device) = super::devices(DEVICE_GPU).next() else {
+        return;
+    };
+
+    let (scanouts, (width, height)) =
+        match Transport::new(device).map_err(Error::from).and_then(probe) {
+            Ok(probed) => probed,
+            Err(error) => {
+                crate::log_error!("virtio-gpu", "{}: {:?}", device.address, error);
+                return;
+            }
+        };
+
+    if let Err(error) = set_mode(width, height) {
+        *GPU.lock() = None;
+
+        crate::log_error!(
+            "virtio-gpu",
+            "{}: {}x{}: {:?}",
+            device.address,
+            width,
+            height,
+            error
+        );
+        return;
+    }
+
+    crate::log_ok!(
+        "virtio-gpu",
+        "{} with {} scanouts, {}x{}",
+        device.address,
+        scanouts,
+        width,
+        height
+    );
+}
@@ -0,0 +1,336 @@
+//! The shared layer of the virtio drivers, a modern PCI transport and split virtqueues
+
+pub mod console;
+pub mod gpu;
+pub mod net;
+pub mod ninep;
+pub mod queue;
+pub mod rng;
+
+use crate::{
+    mmio::Mmio,
+    pci::{self, Bar, CAPABILITY_VENDOR, Device},
+};
+
+pub use queue::Virtqueue;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+pub const DEVICE_NET: u16 = 1;
+pub const DEVICE_BLOCK: u16 = 2;
+pub const DEVICE_CONSOLE: u16 = 3;
+pub const DEVICE_ENTROPY: u16 = 4;
+pub const DEVICE_9P: u16 = 9;
+pub const DEVICE_GPU: u16 = 16;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const FEATURE_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const FEATURE_RING_EVENT_IDX: u64 = 1 << 29;
+/// Set by devices that follow the virtio 1.0 specification, which is the only kind we drive
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const CONFIG_COMMON: u8 = 1;
+const CONFIG_NOTIFY: u8 = 2;
+const CONFIG_ISR: u8 = 3;
+const CONFIG_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const ISR_QUEUE: u8 = 1;
+const ISR_CONFIG: u8 = 2;
+
+const NO_VECTOR: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The device lacks one of the capabilities of the modern PCI transport
+    MissingCapability,
+    /// The device did not accept the features we asked for
+    FeaturesRejected,
+    QueueUnavailable,
+    QueueFull,
+    OutOfMemory,
+}
+
+/// The virtio device type of a PCI device, if it is a virtio device at all
+pub fn device_type(device: &Device) -> Option<u16> {
+    if device.vendor_id != VENDOR_ID {
+        return None;
+    }
+
+    match device.device_id {
+        0x1040..=0x107F => Some(device.device_id - 0x1040),
+        // Transitional devices
+        0x1000 => Some(DEVICE_NET),
+        0x1001 => Some(DEVICE_BLOCK),
+        0x1003 => Some(DEVICE_CONSOLE),
+        0x1005 => Some(DEVICE_ENTROPY),
+        0x1009 => Some(DEVICE_9P),
+        _ => None,
+    }
+}
+
+/// Every virtio device of the given type
+pub fn devices(ty: u16) -> impl Iterator<Item = &'static Device> {
+    pci::devices()
+        .iter()
+        .filter(move |device| device_type(device) == Some(ty))
+}
+
+pub fn init() {
+    // First, so the randomness of the other drivers is seeded from it
+    rng::init();
+    console::init();
+    net::init();
+    gpu::init();
+    ninep::init();
+}
+
+/// The devices without a poll of their own, network devices are polled by the network stack
+pub fn poll() {
+    rng::poll();
+    console::poll();
+    gpu::poll();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterruptStatus {
+    pub queue: bool,
+    pub config: bool,
+}
+
+pub struct Transport {
+    device: Device,
+    common: Mmio,
+    notify: Mmio,
+    notify_multiplier: u32,
+    isr: Mmio,
+    config: Option<Mmio>,
+}
+
+impl Transport {
+    /// Find the configuration structures of the device and reset it
+    pub fn new(device: &Device) -> Result<Self, Error> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut config = None;
+
+        for (id, offset) in device.capabilities() {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+
+            let address = device.address;
+            let cfg_type = address.read_u8(offset + 3);
+            let bar = address.read_u8(offset + 4);
+            let bar_offset = address.read_u32(offset + 8) as u64;
+            let length = address.read_u32(offset + 12) as usize;
+
+            let Some(Bar::Memory { address: base, .. }) = device.bar(bar) else {
+                continue;
+            };
+
+            let region = || Mmio::map(base + bar_offset, length);
+
+            match cfg_type {
+                CONFIG_COMMON if common.is_none() => common = Some(region()),
+                CONFIG_NOTIFY if notify.is_none() => {
+                    notify = Some((region(), address.read_u32(offset + 16)))
+                }
+                CONFIG_ISR if isr.is_none() => isr = Some(region()),
+                CONFIG_DEVICE if config.is_none() => config = Some(region()),
+                _ => {}
+            }
+        }
+
+        let (Some(common), Some((notify, notify_multiplier)), Some(isr)) = (common, notify, isr)
+        else {
+            return Err(Error::MissingCapability);
+        };
+
+        device.enable_bus_master();
+
+        let transport = Self {
+            device: *device,
+            common,
+            notify,
+            notify_multiplier,
+            isr,
+            config,
+        };
+
+        transport.reset();
+
+        Ok(transport)
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn reset(&self) {
+        self.common.write::<u8>(COMMON_DEVICE_STATUS, 0);
+
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.common.read(COMMON_DEVICE_STATUS)
+    }
+
+    pub fn add_status(&self, status: u8) {
+        self.common
+            .write(COMMON_DEVICE_STATUS, self.status() | status);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+
+        self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+
+        high << 32 | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.common
+            .write::<u32>(COMMON_DRIVER_FEATURE, features as u32);
+
+        self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.common
+            .write::<u32>(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    /// Accept the features both sides support and return them, the queues must be set up next
+    pub fn negotiate(&self, supported: u64) -> Result<u64, Error> {
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let features = self.device_features() & (supported | FEATURE_VERSION_1);
+
+        if features & FEATURE_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+
+            return Err(Error::FeaturesRejected);
+        }
+
+        self.set_driver_features(features);
+        self.add_status(STATUS_FEATURES_OK);
+
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+
+            return Err(Error::FeaturesRejected);
+        }
+
+        Ok(features)
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        self.common.read(COMMON_NUM_QUEUES)
+    }
+
+    /// The largest size the device supports for a queue, zero if the queue does not exist
+    pub fn max_queue_size(&self, index: u16) -> u16 {
+        self.common.write(COMMON_QUEUE_SELECT, index);
+        self.common.read(COMMON_QUEUE_SIZE)
+    }
+
+    /// Allocate a queue and hand it to the device
+    pub fn setup_queue(&self, index: u16, size: u16) -> Result<Virtqueue, Error> {
+        let max = if index < self.num_queues() {
+            self.max_queue_size(index)
+        } else {
+            0
+        };
+
+        if max == 0 {
+            return Err(Error::QueueUnavailable);
+        }
+
+        let size = size.min(max);
+        let size = 1 << size.ilog2();
+
+        let notify_off = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize;
+        let queue = Virtqueue::new(index, size, notify_off * self.notify_multiplier as usize)?;
+
+        self.common.write(COMMON_QUEUE_SELECT, index);
+        self.common.write(COMMON_QUEUE_SIZE, size);
+        self.common.write(COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+        self.common
+            .write(COMMON_QUEUE_DESC, queue.descriptor_area());
+        self.common.write(COMMON_QUEUE_DRIVER, queue.driver_area());
+        self.common.write(COMMON_QUEUE_DEVICE, queue.device_area());
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+
+        Ok(queue)
+    }
+
+    /// Tell the device to look at new buffers in the queue
+    pub fn notify(&self, queue: &Virtqueue) {
+        self.notify.write(queue.notify_offset(), queue.index());
+    }
+
+    /// Let the device start working, after the queues are set up
+    pub fn finish_init(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Read and acknowledge the reason of the interrupt, reading clears it
+    pub fn interrupt_status(&self) -> InterruptStatus {
+        let isr = self.isr.read::<u8>(0);
+
+        InterruptStatus {
+            queue: isr & ISR_QUEUE != 0,
+            config: isr & ISR_CONFIG != 0,
+        }
+    }
+
+    /// Acknowledge an interrupt and run the callbacks of the queues with finished buffers
+    pub fn handle_interrupt(&self, queues: &mut [&mut Virtqueue]) -> InterruptStatus {
+        let status = self.interrupt_status();
+
+        if status.queue {
+            for queue in queues {
+                queue.run_callback();
+            }
+        }
+
+        status
+    }
+
+    /// Read a field from the device specific configuration
+    pub fn read_config<T: Copy>(&self, offset: usize) -> Option<T> {
+        self.config.map(|config| config.read(offset))
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        if let Some(config) = self.config {
+            config.write(offset, value);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! The virtio network device
+
+use alloc::sync::Arc;
+
+use super::{DEVICE_NET, Error, Transport, Virtqueue, queue::Segment};
+use crate::{
+    dma::{self, CoherentBuffer, Constraints},
+    net::{self, MacAddress, NetDevice, PacketBuffer},
+    sync::Mutex,
+};
+
+/// The device completes partial checksums of frames we send
+pub const FEATURE_CSUM: u64 = 1 << 0;
+/// We accept received frames with partial checksums
+pub const FEATURE_GUEST_CSUM: u64 = 1 << 1;
+pub const FEATURE_MTU: u64 = 1 << 3;
+pub const FEATURE_MAC: u64 = 1 << 5;
+pub const FEATURE_STATUS: u64 = 1 << 16;
+
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const CONFIG_MTU: usize = 10;
+
+const STATUS_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+const QUEUE_SIZE: u16 = 128;
+
+/// The header in front of every frame, `num_buffers` is always there with `VERSION_1`
+const HEADER_SIZE: usize = 12;
+const ETHERNET_HEADER_SIZE: usize = 14;
+const DEFAULT_MTU: usize = 1500;
+
+/// Set in the header of a received frame whose checksum was not calculated
+pub const HEADER_NEEDS_CSUM: u8 = 1;
+/// Set in the header of a received frame whose checksum was already checked
+pub const HEADER_DATA_VALID: u8 = 2;
+
+struct Queue {
+    queue: Virtqueue,
+    /// One buffer per descriptor, big enough for a whole frame and its header
+    buffers: CoherentBuffer,
+    buffer_size: usize,
+}
+
+impl Queue {
+    fn new(transport: &Transport, index: u16, buffer_size: usize) -> Result<Self, Error> {
+        let queue = transport.setup_queue(index, QUEUE_SIZE)?;
+        let buffers = dma::alloc_coherent(queue.size() as usize * buffer_size, Constraints::new())
+            .map_err(|_| Error::OutOfMemory)?;
+
+        Ok(Self {
+            queue,
+            buffers,
+            buffer_size,
+        })
+    }
+
+    fn buffer_address(&self, descriptor: u16) -> u64 {
+        self.buffers.bus_address() + (descriptor as usize * self.buffer_size) as u64
+    }
+
+    fn buffer(&mut self, descriptor: u16) -> &mut [u8] {
+        let start = descriptor as usize * self.buffer_size;
+
+        &mut self.buffers.as_mut_slice()[start..start + self.buffer_size]
+    }
+}
+
+struct Inner {
+    transport: Transport,
+    receive: Queue,
+    transmit: Queue,
+}
+
+pub struct VirtioNet {
+    inner: Mutex<Inner>,
+    mac: MacAddress,
+    mtu: usize,
+    features: u64,
+}
+
+impl VirtioNet {
+    pub fn new(transport: Transport) -> Result<Self, Error> {
+        let features = transport.negotiate(
+            FEATURE_CSUM | FEATURE_GUEST_CSUM | FEATURE_MTU | FEATURE_MAC | FEATURE_STATUS,
+        )?;
+
+        let mac = if features & FEATURE_MAC != 0 {
+            let mut mac = [0; 6];
+
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.read_config(CONFIG_MAC + i).unwrap_or(0);
+            }
+
+            MacAddress(mac)
+        } else {
+            // A locally administered address, the device did not give us one
+            let mut mac = [0; 6];
+            crate::rand::fill(&mut mac);
+            mac[0] = (mac[0] & !1) | 2;
+
+            MacAddress(mac)
+        };
+
+        let mtu = if features & FEATURE_MTU != 0 {
+            transport
+                .read_config::<u16>(CONFIG_MTU)
+                .map_or(DEFAULT_MTU, |mtu| mtu as usize)
+        } else {
+            DEFAULT_MTU
+        };
+
+        let buffer_size = HEADER_SIZE + ETHERNET_HEADER_SIZE + mtu;
+
+        let mut receive = Queue::new(&transport, RECEIVE_QUEUE, buffer_size)?;
+        let transmit = Queue::new(&transport, TRANSMIT_QUEUE, buffer_size)?;
+
+        transport.finish_init();
+
+        // Every receive descriptor holds a buffer for the device to fill
+        for _ in 0..receive.queue.size() {
+            let descriptor = receive.queue.free_head();
+            let address = receive.buffer_address(descriptor);
+
+            receive
+                .queue
+                .add(&[Segment::writable(address, buffer_size as u32)])?;
+        }
+
+        transport.notify(&receive.queue);
+
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                transport,
+                receive,
+                transmit,
+            }),
+            mac,
+            mtu,
+            features,
+        })
+    }
+
+    /// Whether the device calculates checksums of frames we send
+    pub fn checksum_offload(&self) -> bool {
+        self.features & FEATURE_CSUM != 0
+    }
+
+    pub fn link_up(&self) -> bool {
+        if self.features & FEATURE_STATUS == 0 {
+            return true;
+        }
+
+        self.inner
+            .lock()
+            .transport
+            .read_config::<u16>(CONFIG_STATUS)
+            .is_some_and(|status| status & STATUS_LINK_UP != 0)
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn transmit(&self, packet: &PacketBuffer) -> Result<(), net::Error> {
+        let frame = packet.data();
+
+        let mut inner = self.inner.lock();
+        let Inner {
+            transport,
+            transmit,
+            ..
+        } = &mut *inner;
+
+        if HEADER_SIZE + frame.len() > transmit.buffer_size {
+            return Err(net::Error::TooLarge);
+        }
+
+        // Reclaim the buffers the device already sent
+        while transmit.queue.pop_used().is_some() {}
+
+        if transmit.queue.free_descriptors() == 0 {
+            return Err(net::Error::Busy);
+        }
+
+        let descriptor = transmit.queue.free_head();
+        let buffer = transmit.buffer(descriptor);
+
+        buffer[..HEADER_SIZE].fill(0);
+        buffer[HEADER_SIZE..HEADER_SIZE + frame.len()].copy_from_slice(frame);
+
+        let address = transmit.buffer_address(descriptor);
+
+        transmit
+            .queue
+            .add(&[Segment::readable(
+                address,
+                (HEADER_SIZE + frame.len()) as u32,
+            )])
+            .map_err(|_| net::Error::Busy)?;
+
+        transport.notify(&transmit.queue);
+
+        Ok(())
+    }
+
+    fn poll(&self, rx: &mut dyn FnMut(PacketBuffer)) {
+        let mut inner = self.inner.lock();
+        let Inner {
+            transport, receive, ..
+        } = &mut *inner;
+
+        let mut received = false;
+
+        while let Some((descriptor, len)) = receive.queue.pop_used() {
+            let len = (len as usize).min(receive.buffer_size);
+
+            if len > HEADER_SIZE {
+                rx(PacketBuffer::from_slice(
+                    &receive.buffer(descriptor)[HEADER_SIZE..len],
+                ));
+            }
+
+            let address = receive.buffer_address(descriptor);
+            let size = receive.buffer_size as u32;
+
+            // Give the buffer back, it is the descriptor that was just freed
+            let _ = receive.queue.add(&[Segment::writable(address, size)]);
+
+            received = true;
+        }
+
+        if received {
+            transport.notify(&receive.queue);
+        }
+    }
+}
+
+pub fn init() {
+    for device in super::devices(DEVICE_NET) {
+        let driver = Transport::new(device).and_then(VirtioNet::new);
+
+        match driver {
+            Ok(driver) => {
+                crate::log_ok!(
+                    "virtio-net",
+                    "{} with mac {}, checksum offload {}, link {}",
+                    device.address,
+                    driver.mac,
+                    if driver.checksum_offload() {
+                        "on"
+                    } else {
+                        "off"
+                    },
+                    if driver.link_up() { "up" } else { "down" }
+                );
+
+                net::register(Arc::new(driver));
+            }
+            Err(error) => crate::log_error!("virtio-net", "{}: {:?}", device.address, error),
+        }
+    }
+}
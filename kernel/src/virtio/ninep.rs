@@ -0,0 +1,843 @@
+//! A 9P2000.L client over the virtio 9P transport, for directories the host shares with us
+//!
+//! Every request waits for its reply before the next one is sent, so one tag is enough
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{DEVICE_9P, Transport, Virtqueue, queue::Segment};
+use crate::{
+    cmdline,
+    cred::{self, Access},
+    dma::{self, CoherentBuffer, Constraints},
+    file::{self, Events, File, OpenFlags},
+    mmap::Mappable,
+    process,
+    sync::Mutex,
+    time, wait,
+};
+
+/// The device has a tag that names what it shares
+pub const FEATURE_MOUNT_TAG: u64 = 1 << 0;
+
+const CONFIG_TAG_LEN: usize = 0;
+const CONFIG_TAG: usize = 2;
+
+const REQUEST_QUEUE: u16 = 0;
+
+const VERSION: &str = "9P2000.L";
+
+/// The largest message either side sends
+const MSIZE: u32 = 64 * 1024;
+
+/// The size, type and tag in front of every message
+const HEADER_SIZE: usize = 7;
+
+/// The header, fid, offset and count of a read or write, what is left of a message is data
+const IO_HEADER_SIZE: u32 = 24;
+
+const NOTAG: u16 = 0xFFFF;
+const NOFID: u32 = u32::MAX;
+const TAG: u16 = 0;
+
+/// The most names a walk can have
+const MAX_WALK_NAMES: usize = 16;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const GETATTR_BASIC: u64 = 0x7FF;
+
+/// The directory bit of the type of a qid
+const QID_DIRECTORY: u8 = 0x80;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+const ENOENT: u32 = 2;
+const ENOMEM: u32 = 12;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const EINVAL: u32 = 22;
+const ENAMETOOLONG: u32 = 36;
+const EOPNOTSUPP: u32 = 95;
+
+const TIMEOUT_NS: u64 = 5_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Virtio(super::Error),
+    /// No device shares under that tag
+    NoDevice,
+    /// The server answered with an error number
+    Errno(u32),
+    /// The reply did not make sense
+    Protocol,
+    /// The server did not answer, the device is not used again
+    Timeout,
+}
+
+impl From<super::Error> for Error {
+    fn from(error: super::Error) -> Self {
+        Error::Virtio(error)
+    }
+}
+
+impl From<Error> for file::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Errno(ENOENT) => file::Error::NotFound,
+            Error::Errno(ENOMEM) => file::Error::OutOfMemory,
+            Error::Errno(EACCES) => file::Error::PermissionDenied,
+            Error::Errno(EEXIST) => file::Error::AlreadyExists,
+            Error::Errno(EINVAL) => file::Error::InvalidArgument,
+            Error::Errno(ENAMETOOLONG) => file::Error::NameTooLong,
+            Error::Errno(EOPNOTSUPP) => file::Error::NotSupported,
+            Error::Timeout => file::Error::TimedOut,
+            _ => file::Error::Io,
+        }
+    }
+}
+
+/// What the server uses to tell files apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    pub ty: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn is_directory(&self) -> bool {
+        self.ty & QID_DIRECTORY != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub qid: Qid,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub size: u64,
+    pub mtime_sec: u64,
+    pub mtime_nsec: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub qid: Qid,
+    pub name: String,
+}
+
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(ty: u8, tag: u16) -> Self {
+        let mut message = Self(Vec::new());
+
+        message.u32(0).u8(ty).u16(tag);
+
+        message
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        let len = self.0.len() as u32;
+
+        self.0[..4].copy_from_slice(&len.to_le_bytes());
+
+        &self.0
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Protocol);
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, Error> {
+        let len = self.u16()? as usize;
+
+        Ok(String::from_utf8_lossy(self.take(len)?).into())
+    }
+
+    fn qid(&mut self) -> Result<Qid, Error> {
+        Ok(Qid {
+            ty: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+}
+
+struct Inner {
+    transport: Transport,
+    queue: Virtqueue,
+    request: CoherentBuffer,
+    reply: CoherentBuffer,
+    /// The size both sides agreed on, zero until the version was negotiated
+    msize: u32,
+    /// A request timed out and may still be in flight
+    broken: bool,
+}
+
+pub struct Client {
+    tag: String,
+    inner: Mutex<Inner>,
+    next_fid: AtomicU32,
+}
+
+impl Client {
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    fn allocate_fid(&self) -> u32 {
+        self.next_fid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Send a request and return the body of its reply, which must be of the type after the
+    /// request's
+    fn rpc(&self, message: &mut Message) -> Result<Vec<u8>, Error> {
+        let mut inner = self.inner.lock();
+
+        if inner.broken {
+            return Err(Error::Timeout);
+        }
+
+        let message = message.finish();
+        let ty = message[4];
+
+        if message.len() > MSIZE as usize {
+            return Err(Error::Errno(EINVAL));
+        }
+
+        inner.request.as_mut_slice()[..message.len()].copy_from_slice(message);
+
+        let (request, reply) = (inner.request.bus_address(), inner.reply.bus_address());
+
+        inner.queue.add(&[
+            Segment::readable(request, message.len() as u32),
+            Segment::writable(reply, MSIZE),
+        ])?;
+
+        inner.transport.notify(&inner.queue);
+
+        let used = wait::until(Some(time::monotonic_ns() + TIMEOUT_NS), || {
+            inner.queue.pop_used()
+        });
+
+        let Some((_, len)) = used else {
+            inner.broken = true;
+
+            return Err(Error::Timeout);
+        };
+
+        let reply = &inner.reply.as_slice()[..(len as usize).min(MSIZE as usize)];
+
+        let mut reader = Reader(reply);
+        let size = reader.u32()? as usize;
+        let reply_ty = reader.u8()?;
+        let _tag = reader.u16()?;
+
+        let body = reply.get(HEADER_SIZE..size).ok_or(Error::Protocol)?;
+
+        match reply_ty {
+            RLERROR => Err(Error::Errno(Reader(body).u32()?)),
+            reply_ty if reply_ty == ty + 1 => Ok(body.to_vec()),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    fn negotiate_version(&self) -> Result<(), Error> {
+        if self.inner.lock().msize != 0 {
+            return Ok(());
+        }
+
+        let body = self.rpc(Message::new(TVERSION, NOTAG).u32(MSIZE).str(VERSION))?;
+
+        let mut reader = Reader(&body);
+        let msize = reader.u32()?;
+
+        if reader.str()? != VERSION {
+            return Err(Error::Errno(EOPNOTSUPP));
+        }
+
+        self.inner.lock().msize = msize.min(MSIZE);
+
+        Ok(())
+    }
+
+    /// The most data one read or write moves
+    fn io_size(&self, iounit: u32) -> usize {
+        let most = self.inner.lock().msize - IO_HEADER_SIZE;
+
+        (if iounit == 0 { most } else { iounit.min(most) }) as usize
+    }
+
+    fn clunk(&self, fid: u32) {
+        let _ = self.rpc(Message::new(TCLUNK, TAG).u32(fid));
+    }
+
+    /// Attach to the root of what the server shares, `aname` picks which export on servers with
+    /// more than one
+    pub fn attach(self: &Arc<Self>, aname: &str) -> Result<Node, Error> {
+        self.negotiate_version()?;
+
+        let fid = self.allocate_fid();
+
+        let body = self.rpc(
+            Message::new(TATTACH, TAG)
+                .u32(fid)
+                .u32(NOFID)
+                .str("root")
+                .str(aname)
+                .u32(0),
+        )?;
+
+        Ok(Node {
+            client: self.clone(),
+            fid,
+            qid: Reader(&body).qid()?,
+        })
+    }
+}
+
+/// A file or directory on the server, walked to but not opened
+pub struct Node {
+    client: Arc<Client>,
+    fid: u32,
+    qid: Qid,
+}
+
+impl Node {
+    pub fn qid(&self) -> Qid {
+        self.qid
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.qid.is_directory()
+    }
+
+    /// Walk to `path` from here, an empty path gives another node for the same file
+    pub fn walk(&self, path: &str) -> Result<Node, Error> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+
+        let mut node = self.walk_names(&[])?;
+
+        for names in names.chunks(MAX_WALK_NAMES) {
+            node = node.walk_names(names)?;
+        }
+
+        Ok(node)
+    }
+
+    fn walk_names(&self, names: &[&str]) -> Result<Node, Error> {
+        let fid = self.client.allocate_fid();
+
+        let mut message = Message::new(TWALK, TAG);
+
+        message.u32(self.fid).u32(fid).u16(names.len() as u16);
+
+        for name in names {
+            message.str(name);
+        }
+
+        let body = self.client.rpc(&mut message)?;
+
+        let mut reader = Reader(&body);
+        let count = reader.u16()? as usize;
+
+        // Walking stopped early, so the new fid was not made
+        if count != names.len() {
+            return Err(Error::Errno(ENOENT));
+        }
+
+        let mut qid = self.qid;
+
+        for _ in 0..count {
+            qid = reader.qid()?;
+        }
+
+        Ok(Node {
+            client: self.client.clone(),
+            fid,
+            qid,
+        })
+    }
+
+    pub fn attributes(&self) -> Result<Attributes, Error> {
+        let body = self
+            .client
+            .rpc(Message::new(TGETATTR, TAG).u32(self.fid).u64(GETATTR_BASIC))?;
+
+        let mut reader = Reader(&body);
+
+        let _valid = reader.u64()?;
+        let qid = reader.qid()?;
+        let mode = reader.u32()?;
+        let uid = reader.u32()?;
+        let gid = reader.u32()?;
+        let nlink = reader.u64()?;
+        let _rdev = reader.u64()?;
+        let size = reader.u64()?;
+        let _blksize = reader.u64()?;
+        let _blocks = reader.u64()?;
+        let _atime = (reader.u64()?, reader.u64()?);
+
+        Ok(Attributes {
+            qid,
+            mode,
+            uid,
+            gid,
+            nlink,
+            size,
+            mtime_sec: reader.u64()?,
+            mtime_nsec: reader.u64()?,
+        })
+    }
+
+    /// The group new files are created in, the effective one of the current process
+    fn group() -> u32 {
+        process::current().map_or(0, |current| current.process().credentials().egid)
+    }
+
+    /// Whether the current process may do `access` to the file, by the mode the server gives it
+    fn check_access(&self, access: Access) -> Result<(), Error> {
+        let attributes = self.attributes()?;
+
+        match cred::may_access(attributes.mode, attributes.uid, attributes.gid, access) {
+            true => Ok(()),
+            false => Err(Error::Errno(EACCES)),
+        }
+    }
+
+    fn open_flags(flags: OpenFlags) -> u32 {
+        (flags - OpenFlags::CLOEXEC - OpenFlags::NONBLOCK).bits()
+    }
+
+    pub fn open(self, flags: OpenFlags) -> Result<NinepFile, Error> {
+        let access = match flags & (OpenFlags::WRITE_ONLY | OpenFlags::READ_WRITE) {
+            OpenFlags::WRITE_ONLY => Access::WRITE,
+            OpenFlags::READ_WRITE => Access::READ | Access::WRITE,
+            _ if flags.contains(OpenFlags::TRUNCATE) => Access::READ | Access::WRITE,
+            _ => Access::READ,
+        };
+
+        self.check_access(access)?;
+
+        let body = self.client.rpc(
+            Message::new(TLOPEN, TAG)
+                .u32(self.fid)
+                .u32(Self::open_flags(flags)),
+        )?;
+
+        let mut reader = Reader(&body);
+        let _qid = reader.qid()?;
+        let iounit = reader.u32()?;
+
+        Ok(NinepFile {
+            node: self,
+            iounit,
+            position: Mutex::new(0),
+        })
+    }
+
+    /// Create `name` in this directory and open it
+    pub fn create(&self, name: &str, flags: OpenFlags, mode: u32) -> Result<NinepFile, Error> {
+        self.check_access(Access::WRITE | Access::EXECUTE)?;
+
+        // Creating turns the fid into the one of the new file, so use another for it
+        let directory = self.walk("")?;
+
+        let body = directory.client.rpc(
+            Message::new(TLCREATE, TAG)
+                .u32(directory.fid)
+                .str(name)
+                .u32(Self::open_flags(flags))
+                .u32(mode)
+                .u32(Self::group()),
+        )?;
+
+        let mut reader = Reader(&body);
+        let qid = reader.qid()?;
+        let iounit = reader.u32()?;
+
+        let mut node = directory;
+        node.qid = qid;
+
+        Ok(NinepFile {
+            node,
+            iounit,
+            position: Mutex::new(0),
+        })
+    }
+
+    pub fn mkdir(&self, name: &str, mode: u32) -> Result<Qid, Error> {
+        self.check_access(Access::WRITE | Access::EXECUTE)?;
+
+        let body = self.client.rpc(
+            Message::new(TMKDIR, TAG)
+                .u32(self.fid)
+                .str(name)
+                .u32(mode)
+                .u32(Self::group()),
+        )?;
+
+        Reader(&body).qid()
+    }
+
+    /// Remove `name` from this directory, which must be a directory itself with `directory`
+    pub fn unlink(&self, name: &str, directory: bool) -> Result<(), Error> {
+        self.check_access(Access::WRITE | Access::EXECUTE)?;
+
+        self.client.rpc(
+            Message::new(TUNLINKAT, TAG)
+                .u32(self.fid)
+                .str(name)
+                .u32(if directory { AT_REMOVEDIR } else { 0 }),
+        )?;
+
+        Ok(())
+    }
+
+    /// Every entry of this directory, including `.` and `..`
+    pub fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        let directory = self.walk("")?.open(OpenFlags::empty())?;
+        let client = &directory.node.client;
+
+        let count = client.io_size(directory.iounit) as u32;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let body = client.rpc(
+                Message::new(TREADDIR, TAG)
+                    .u32(directory.node.fid)
+                    .u64(offset)
+                    .u32(count),
+            )?;
+
+            let mut reader = Reader(&body);
+            let len = reader.u32()? as usize;
+            let mut reader = Reader(reader.take(len)?);
+
+            if len == 0 {
+                break;
+            }
+
+            while !reader.0.is_empty() {
+                let qid = reader.qid()?;
+                offset = reader.u64()?;
+                let _ty = reader.u8()?;
+                let name = reader.str()?;
+
+                entries.push(DirEntry { qid, name });
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.client.clunk(self.fid);
+    }
+}
+
+/// An opened file on the server, reads and writes go from where the last one stopped
+pub struct NinepFile {
+    node: Node,
+    iounit: u32,
+    position: Mutex<u64>,
+}
+
+impl NinepFile {
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let client = &self.node.client;
+        let count = buffer.len().min(client.io_size(self.iounit));
+
+        let body = client.rpc(
+            Message::new(TREAD, TAG)
+                .u32(self.node.fid)
+                .u64(offset)
+                .u32(count as u32),
+        )?;
+
+        let mut reader = Reader(&body);
+        let len = (reader.u32()? as usize).min(count);
+
+        buffer[..len].copy_from_slice(reader.take(len)?);
+
+        Ok(len)
+    }
+
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let client = &self.node.client;
+        let data = &data[..data.len().min(client.io_size(self.iounit))];
+
+        let body = client.rpc(
+            Message::new(TWRITE, TAG)
+                .u32(self.node.fid)
+                .u64(offset)
+                .u32(data.len() as u32)
+                .bytes(data),
+        )?;
+
+        Ok((Reader(&body).u32()? as usize).min(data.len()))
+    }
+
+    /// Get what was written onto the host's disk
+    pub fn sync(&self) -> Result<(), Error> {
+        self.node
+            .client
+            .rpc(Message::new(TFSYNC, TAG).u32(self.node.fid).u32(0))?;
+
+        Ok(())
+    }
+}
+
+impl File for NinepFile {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, file::Error> {
+        let mut position = self.position.lock();
+
+        let len = self.read_at(*position, buffer)?;
+
+        *position += len as u64;
+
+        Ok(len)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, file::Error> {
+        let mut position = self.position.lock();
+        let mut written = 0;
+
+        while written < data.len() {
+            let len = self.write_at(*position, &data[written..])?;
+
+            if len == 0 {
+                break;
+            }
+
+            *position += len as u64;
+            written += len;
+        }
+
+        Ok(written)
+    }
+
+    fn poll(&self) -> Events {
+        Events::READABLE | Events::WRITABLE
+    }
+}
+
+impl Mappable for NinepFile {
+    fn size(&self) -> Result<u64, file::Error> {
+        Ok(self.node.attributes()?.size)
+    }
+
+    fn read_page(&self, index: u64, buffer: &mut [u8]) -> Result<(), file::Error> {
+        let offset = index * dma::PAGE_SIZE as u64;
+        let mut read = 0;
+
+        while read < buffer.len() {
+            let len = self.read_at(offset + read as u64, &mut buffer[read..])?;
+
+            if len == 0 {
+                break;
+            }
+
+            read += len;
+        }
+
+        buffer[read..].fill(0);
+
+        Ok(())
+    }
+
+    fn write_page(&self, index: u64, data: &[u8]) -> Result<(), file::Error> {
+        let offset = index * dma::PAGE_SIZE as u64;
+        let mut written = 0;
+
+        while written < data.len() {
+            let len = self.write_at(offset + written as u64, &data[written..])?;
+
+            if len == 0 {
+                return Err(file::Error::Io);
+            }
+
+            written += len;
+        }
+
+        Ok(())
+    }
+}
+
+static CLIENTS: Mutex<Vec<Arc<Client>>> = Mutex::new(Vec::new());
+
+/// The root of what is shared under `tag`, given another fid every time
+pub fn mount(tag: &str) -> Result<Node, Error> {
+    let client = CLIENTS
+        .lock()
+        .iter()
+        .find(|client| client.tag == tag)
+        .cloned()
+        .ok_or(Error::NoDevice)?;
+
+    client.attach("")
+}
+
+/// Every tag a device shares under
+pub fn tags() -> Vec<String> {
+    CLIENTS
+        .lock()
+        .iter()
+        .map(|client| client.tag.clone())
+        .collect()
+}
+
+fn probe(transport: Transport) -> Result<Client, super::Error> {
+    let features = transport.negotiate(FEATURE_MOUNT_TAG)?;
+
+    let tag = if features & FEATURE_MOUNT_TAG != 0 {
+        let len = transport.read_config::<u16>(CONFIG_TAG_LEN).unwrap_or(0) as usize;
+
+        let tag: Vec<u8> = (0..len)
+            .map(|i| transport.read_config::<u8>(CONFIG_TAG + i).unwrap_or(0))
+            .collect();
+
+        String::from_utf8_lossy(&tag).into()
+    } else {
+        String::new()
+    };
+
+    let queue = transport.setup_queue(REQUEST_QUEUE, 2)?;
+
+    transport.finish_init();
+
+    let alloc = || {
+        dma::alloc_coherent(MSIZE as usize, Constraints::new())
+            .map_err(|_| super::Error::OutOfMemory)
+    };
+
+    Ok(Client {
+        tag,
+        inner: Mutex::new(Inner {
+            transport,
+            queue,
+            request: alloc()?,
+            reply: alloc()?,
+            msize: 0,
+            broken: false,
+        }),
+        next_fid: AtomicU32::new(0),
+    })
+}
+
+/// Find the shared directories, `9p=<tag>` on the command line checks the one under that tag can
+/// be mounted by listing it
+pub fn init() {
+    for device in super::devices(DEVICE_9P) {
+        match Transport::new(device).and_then(probe) {
+            Ok(client) => {
+                crate::log_ok!("9p", "{} shares {}", device.address, client.tag);
+
+                CLIENTS.lock().push(Arc::new(client));
+            }
+            Err(error) => crate::log_error!("9p", "{}: {:?}", device.address, error),
+        }
+    }
+
+    let Some(tag) = cmdline::value("9p") else {
+        return;
+    };
+
+    match mount(tag).and_then(|root| root.read_dir()) {
+        Ok(entries) => crate::log_info!(
+            "9p",
+            "{}: {}",
+            tag,
+            entries
+                .iter()
+                .map(|entry| entry.name.as_str())
+                .filter(|name| *name != "." && *name != "..")
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        Err(error) => crate::log_error!("9p", "{}: {:?}", tag, error),
+    }
+}
@@ -0,0 +1,283 @@
+use core::sync::atomic::{Ordering, fence};
+
+use super::Error;
+use crate::dma::{self, CoherentBuffer, Constraints};
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// Memory handed to the device as a part of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u64,
+    pub len: u32,
+    /// Whether the device writes this memory instead of reading it
+    pub writable: bool,
+}
+
+impl Segment {
+    pub fn readable(address: u64, len: u32) -> Self {
+        Self {
+            address,
+            len,
+            writable: false,
+        }
+    }
+
+    pub fn writable(address: u64, len: u32) -> Self {
+        Self {
+            address,
+            len,
+            writable: true,
+        }
+    }
+}
+
+/// Called with the head descriptor of a finished request and the amount of bytes the device wrote
+pub type Callback = fn(head: u16, len: u32);
+
+/// A split virtqueue, made of the descriptor table, the available ring we write and the used ring
+/// the device writes
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    notify_offset: usize,
+    descriptors: CoherentBuffer,
+    available: CoherentBuffer,
+    used: CoherentBuffer,
+    free_head: u16,
+    free_count: u16,
+    available_index: u16,
+    last_used_index: u16,
+    callback: Option<Callback>,
+}
+
+impl Virtqueue {
+    pub(super) fn new(index: u16, size: u16, notify_offset: usize) -> Result<Self, Error> {
+        let size_usize = size as usize;
+
+        let alloc = |len, align| {
+            dma::alloc_coherent(len, Constraints::new().aligned(align))
+                .map_err(|_| Error::OutOfMemory)
+        };
+
+        let descriptors = alloc(size_of::<Descriptor>() * size_usize, 16)?;
+        let available = alloc(6 + 2 * size_usize, 2)?;
+        let used = alloc(6 + size_of::<UsedElement>() * size_usize, 4)?;
+
+        let queue = Self {
+            index,
+            size,
+            notify_offset,
+            descriptors,
+            available,
+            used,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            last_used_index: 0,
+            callback: None,
+        };
+
+        for i in 0..size {
+            queue.write_descriptor(
+                i,
+                Descriptor {
+                    next: (i + 1) % size,
+                    ..Default::default()
+                },
+            );
+        }
+
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// How many more descriptors can be added
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    /// The descriptor the next request will start at
+    pub fn free_head(&self) -> u16 {
+        self.free_head
+    }
+
+    pub(super) fn notify_offset(&self) -> usize {
+        self.notify_offset
+    }
+
+    pub(super) fn descriptor_area(&self) -> u64 {
+        self.descriptors.bus_address()
+    }
+
+    pub(super) fn driver_area(&self) -> u64 {
+        self.available.bus_address()
+    }
+
+    pub(super) fn device_area(&self) -> u64 {
+        self.used.bus_address()
+    }
+
+    pub fn set_callback(&mut self, callback: Callback) {
+        self.callback = Some(callback);
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        self.descriptors
+            .as_ptr()
+            .cast::<Descriptor>()
+            .wrapping_add(index as usize)
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        unsafe { self.descriptor(index).read_volatile() }
+    }
+
+    fn write_descriptor(&self, index: u16, descriptor: Descriptor) {
+        unsafe { self.descriptor(index).write_volatile(descriptor) }
+    }
+
+    fn available_field(&self, offset: usize) -> *mut u16 {
+        self.available.as_ptr().wrapping_add(offset).cast()
+    }
+
+    fn used_field(&self, offset: usize) -> *mut u8 {
+        self.used.as_ptr().wrapping_add(offset)
+    }
+
+    /// Chain the segments into a request and make it available to the device, returning the head
+    /// descriptor that identifies it once it is used, the device still has to be notified
+    pub fn add(&mut self, segments: &[Segment]) -> Result<u16, Error> {
+        if segments.is_empty() || segments.len() > self.free_count as usize {
+            return Err(Error::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+
+        for (i, segment) in segments.iter().enumerate() {
+            let next = self.read_descriptor(current).next;
+            let last = i == segments.len() - 1;
+
+            let mut flags = 0;
+
+            if segment.writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+
+            if !last {
+                flags |= DESCRIPTOR_NEXT;
+            }
+
+            self.write_descriptor(
+                current,
+                Descriptor {
+                    address: segment.address,
+                    len: segment.len,
+                    flags,
+                    next,
+                },
+            );
+
+            if last {
+                self.free_head = next;
+            } else {
+                current = next;
+            }
+        }
+
+        self.free_count -= segments.len() as u16;
+
+        let slot = 4 + 2 * (self.available_index % self.size) as usize;
+
+        unsafe { self.available_field(slot).write_volatile(head) };
+
+        self.available_index = self.available_index.wrapping_add(1);
+
+        // The device must see the descriptors and the ring entry before the new index
+        fence(Ordering::SeqCst);
+
+        unsafe { self.available_field(2).write_volatile(self.available_index) };
+
+        fence(Ordering::SeqCst);
+
+        Ok(head)
+    }
+
+    /// Take a finished request off the used ring and free its descriptors
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { self.used_field(2).cast::<u16>().read_volatile() };
+
+        if used_index == self.last_used_index {
+            return None;
+        }
+
+        // The element must not be read before the index that published it
+        fence(Ordering::SeqCst);
+
+        let slot = 4 + size_of::<UsedElement>() * (self.last_used_index % self.size) as usize;
+        let element = unsafe { self.used_field(slot).cast::<UsedElement>().read_volatile() };
+
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut current = head;
+
+        loop {
+            let descriptor = self.read_descriptor(current);
+
+            self.free_count += 1;
+
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                self.write_descriptor(
+                    current,
+                    Descriptor {
+                        next: self.free_head,
+                        ..Default::default()
+                    },
+                );
+
+                break;
+            }
+
+            current = descriptor.next;
+        }
+
+        self.free_head = head;
+
+        Some((head, element.len))
+    }
+
+    /// Pop every finished request, handing each to the callback
+    pub fn run_callback(&mut self) {
+        while let Some((head, len)) = self.pop_used() {
+            if let Some(callback) = self.callback {
+                callback(head, len);
+            }
+        }
+    }
+}
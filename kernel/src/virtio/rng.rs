@@ -0,0 +1,128 @@
+//! The virtio entropy device, the host's random number generator
+
+use super::{DEVICE_ENTROPY, Error, Transport, Virtqueue, queue::Segment};
+use crate::{
+    dma::{self, CoherentBuffer, Constraints},
+    rand,
+    sync::Mutex,
+    time, timer, wait,
+};
+
+const REQUEST_QUEUE: u16 = 0;
+
+/// A whole key of the generator and then some
+const REQUEST_SIZE: usize = 64;
+
+/// How often the pool is topped up after the first request
+const REFILL_INTERVAL_NS: u64 = 60_000_000_000;
+
+/// How long to wait for the first bytes before going on without them
+const INIT_TIMEOUT_NS: u64 = 100_000_000;
+
+struct Rng {
+    transport: Transport,
+    queue: Virtqueue,
+    buffer: CoherentBuffer,
+    /// Whether the device holds the buffer
+    pending: bool,
+}
+
+static RNG: Mutex<Option<Rng>> = Mutex::new(None);
+
+/// Hand the buffer to the device, unless it already has it
+fn request() {
+    let mut rng = RNG.lock();
+
+    let Some(rng) = rng.as_mut().filter(|rng| !rng.pending) else {
+        return;
+    };
+
+    let address = rng.buffer.bus_address();
+
+    if rng
+        .queue
+        .add(&[Segment::writable(address, REQUEST_SIZE as u32)])
+        .is_ok()
+    {
+        rng.transport.notify(&rng.queue);
+        rng.pending = true;
+    }
+}
+
+/// Credit what the device wrote to the entropy pool and ask again later
+pub fn poll() {
+    let Some(mut rng) = RNG.try_lock() else {
+        return;
+    };
+
+    let Some(rng) = rng.as_mut() else {
+        return;
+    };
+
+    let Some((_, len)) = rng.queue.pop_used() else {
+        return;
+    };
+
+    rng.pending = false;
+
+    let len = (len as usize).min(REQUEST_SIZE);
+
+    rand::add_hardware_entropy(&rng.buffer.as_slice()[..len]);
+
+    rng.buffer.as_mut_slice().fill(0);
+
+    timer::add(REFILL_INTERVAL_NS, request);
+}
+
+fn probe(transport: Transport) -> Result<Rng, Error> {
+    transport.negotiate(0)?;
+
+    let queue = transport.setup_queue(REQUEST_QUEUE, 1)?;
+
+    transport.finish_init();
+
+    let buffer =
+        dma::alloc_coherent(REQUEST_SIZE, Constraints::new()).map_err(|_| Error::OutOfMemory)?;
+
+    Ok(Rng {
+        transport,
+        queue,
+        buffer,
+        pending: false,
+    })
+}
+
+/// Seed the entropy pool from the first virtio entropy device, before anything needs randomness
+pub fn init() {
+    let Some(device) = super::devices(DEVICE_ENTROPY).next() else {
+        return;
+    };
+
+    match Transport::new(device).and_then(probe) {
+        Ok(rng) => *RNG.lock() = Some(rng),
+        Err(error) => {
+            crate::log_error!("virtio-rng", "{}: {:?}", device.address, error);
+            return;
+        }
+    }
+
+    request();
+
+    let seeded = wait::until(Some(time::monotonic_ns() + INIT_TIMEOUT_NS), || {
+        RNG.lock()
+            .as_ref()
+            .is_some_and(|rng| !rng.pending)
+            .then_some(())
+    });
+
+    crate::log_ok!(
+        "virtio-rng",
+        "{}{}",
+        device.address,
+        if seeded.is_some() {
+            ""
+        } else {
+            ", no entropy yet"
+        }
+    );
+}
@@ -0,0 +1,89 @@
+//! Waiting for something to happen
+//!
+//! There is no scheduler yet, so whoever waits keeps the system going by polling the devices,
+//! the timers and the softirqs until what it waits for happened
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{kdb, net, serial_input, softirq, sound, swap, sync::Mutex, time, timer, usb, virtio};
+
+/// Do one round of the work that interrupts would otherwise kick off
+pub fn poll() {
+    kdb::poll();
+    serial_input::poll();
+    net::poll();
+    usb::poll();
+    virtio::poll();
+    sound::poll();
+    timer::poll();
+    softirq::run_pending();
+    swap::poll();
+}
+
+/// Keep the system going until `f` returns something or the deadline passes
+pub fn until<T>(deadline: Option<u64>, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    loop {
+        if let Some(value) = f() {
+            return Some(value);
+        }
+
+        if deadline.is_some_and(|deadline| time::monotonic_ns() >= deadline) {
+            return None;
+        }
+
+        poll();
+
+        core::hint::spin_loop();
+    }
+}
+
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+/// Who to tell when something changed, so waiters only look again at what may be ready
+pub struct WaitQueue {
+    wakers: Mutex<Vec<(u64, Waker)>>,
+}
+
+static NEXT_WAKER_ID: AtomicU64 = AtomicU64::new(0);
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Call `waker` on every wake until unsubscribed with the id returned
+    pub fn subscribe(&self, waker: Waker) -> u64 {
+        let id = NEXT_WAKER_ID.fetch_add(1, Ordering::Relaxed);
+
+        self.wakers.lock().push((id, waker));
+
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        self.wakers.lock().retain(|(waker_id, _)| *waker_id != id);
+    }
+
+    /// Must not be called with locks a waker could take
+    pub fn wake_all(&self) {
+        let wakers: Vec<Waker> = self
+            .wakers
+            .lock()
+            .iter()
+            .map(|(_, waker)| waker.clone())
+            .collect();
+
+        for waker in wakers {
+            waker();
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}